@@ -0,0 +1,61 @@
+use std::net::Ipv4Addr;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use loramesh::stack::{BroadcastMessage, FrameBuilder, MessageType, ToFromFrame, Frame};
+
+const PAYLOAD_SIZES: [usize; 3] = [10, 100, 240];
+
+fn sample_frame(size: usize) -> Frame {
+    FrameBuilder::new()
+        .frameid(7)
+        .message_type(MessageType::IPPacket)
+        .sender(3)
+        .route(&[3, 5, 9])
+        .payload(vec![0xa5u8; size])
+        .build()
+        .expect("Invalid benchmark frame")
+}
+
+fn sample_broadcast() -> BroadcastMessage {
+    BroadcastMessage {
+        header: None,
+        isgateway: false,
+        ipOffset: 4,
+        ipaddr: Some(Ipv4Addr::new(172, 16, 0, 3)),
+        ipv6addr: None,
+        custom_tlv: Vec::new()
+    }
+}
+
+fn frame_to_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_to_bytes");
+    for size in PAYLOAD_SIZES.iter() {
+        let mut frame = sample_frame(*size);
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, _| b.iter(|| black_box(&mut frame).to_bytes()));
+    }
+    group.finish();
+}
+
+fn frame_from_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_from_bytes");
+    for size in PAYLOAD_SIZES.iter() {
+        let bytes = sample_frame(*size).to_bytes();
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, _| b.iter(|| Frame::from_bytes(black_box(&bytes))));
+    }
+    group.finish();
+}
+
+fn broadcast_roundtrip(c: &mut Criterion) {
+    let msg = sample_broadcast();
+    let bytes = msg.to_frame(1, 3, vec![3]).unwrap().to_bytes();
+
+    c.bench_function("broadcast_to_bytes", |b| b.iter(|| black_box(&msg).to_frame(1, 3, vec![3]).unwrap().to_bytes()));
+    c.bench_function("broadcast_from_bytes", |b| b.iter(|| {
+        let mut frame = Frame::from_bytes(black_box(&bytes)).unwrap();
+        BroadcastMessage::from_frame(&mut frame).unwrap()
+    }));
+}
+
+criterion_group!(benches, frame_to_bytes, frame_from_bytes, broadcast_roundtrip);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use loramesh::stack::{Frame, FrameHeader};
+
+/// the byte-indexed parsing FrameHeader used before the wire layout was
+/// declared, kept as a baseline
+fn indexed_parse(bytes: &[u8]) -> Option<(u8, u8, u8, u8, Vec<u8>)> {
+    let txflag = *bytes.first()?;
+    let frameid = *bytes.get(1)?;
+    let msgtype = *bytes.get(2)?;
+    let sender = *bytes.get(3)?;
+    let routeoffset = *bytes.get(4)?;
+    let route = bytes.get(5..(5+routeoffset as usize))?;
+    Some((txflag, frameid, msgtype, sender, Vec::from(route)))
+}
+
+fn header_parse(c: &mut Criterion) {
+    let bytes = vec![0u8, 12, 9, 3, 4, 3, 7, 9, 1, 0x45, 0x00, 0x00, 0x42];
+
+    let mut group = c.benchmark_group("header_parse");
+    group.bench_function("zerocopy", |b| b.iter(|| FrameHeader::from_bytes(black_box(&bytes))));
+    group.bench_function("indexed", |b| b.iter(|| indexed_parse(black_box(&bytes))));
+    group.bench_function("frame", |b| b.iter(|| Frame::from_bytes(black_box(&bytes))));
+    group.finish();
+}
+
+criterion_group!(benches, header_parse);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+//! Listens to the mesh from a tokio runtime and sends a heartbeat every
+//! few seconds, using the radio set up in the usual config file.
+use std::io;
+use std::time::Duration;
+use loramesh::hardware::{AsyncLoStik, LoStik};
+use loramesh::settings::Settings;
+use loramesh::stack::{Frame, HeartbeatMessage, ToFromFrame};
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let opt = Settings::new().expect("Error loading settings");
+    let mut radio = LoStik::new(opt.clone());
+    radio.init(opt.radiocfg.clone())?;
+    let mut radio = AsyncLoStik::new(radio);
+
+    let mut heartbeat = tokio::time::interval(Duration::from_millis(opt.heartbeatinterval));
+    let mut frameid: u8 = 0;
+    loop {
+        tokio::select! {
+            packet = radio.rx() => {
+                let packet = packet?;
+                match Frame::from_bytes(&packet.data) {
+                    Err(e) => println!("invalid frame: {}", e),
+                    Ok(mut frame) => println!("{:?} from node {}, RSSI {:?}",
+                        frame.msgtype(), frame.sender(), packet.quality.rssi),
+                }
+            },
+            _ = heartbeat.tick() => {
+                frameid = frameid.wrapping_add(1);
+                let mut frame = HeartbeatMessage::new(0).to_frame(frameid, opt.nodeid, vec![opt.nodeid])?;
+                radio.tx(frame.to_bytes()).await?;
+            },
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Turns a frame and the message it carries into JSON and back.
+//! Run with `cargo run --example json_frame --features serde-support`.
+use std::net::Ipv4Addr;
+use loramesh::stack::{BroadcastMessage, DataMessage, Frame, ToFromFrame};
+
+fn main() {
+    // payloads show up as base64 strings
+    let data = DataMessage::new(7, 3, vec![0xde, 0xad, 0xbe, 0xef]).expect("Invalid data");
+    let frame = data.to_frame(1, 2, vec![5, 7]).expect("Invalid data frame");
+    let json = serde_json::to_string_pretty(&frame).expect("Frames are always serializable");
+    println!("{}", json);
+
+    let mut restored: Frame = serde_json::from_str(&json).expect("Invalid frame JSON");
+    assert_eq!(restored.to_bytes(), frame.clone().to_bytes());
+    let message = DataMessage::from_frame(&mut restored).expect("Invalid data frame");
+    println!("port {} payload {:?}", message.port, message.payload);
+
+    // messages can be serialized on their own, with their header once received
+    let broadcast = BroadcastMessage {
+        header: None,
+        isgateway: false,
+        ipOffset: 4,
+        ipaddr: Some(Ipv4Addr::new(172, 16, 0, 4)),
+        ipv6addr: None,
+        custom_tlv: vec![(1, b"roof".to_vec())],
+    };
+    let json = serde_json::to_string(&broadcast).expect("Messages are always serializable");
+    println!("{}", json);
+    let restored: BroadcastMessage = serde_json::from_str(&json).expect("Invalid broadcast JSON");
+    assert_eq!(restored.custom_tlv, broadcast.custom_tlv);
+}
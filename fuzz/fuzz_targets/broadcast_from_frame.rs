@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use loramesh::stack::{BroadcastMessage, FrameBuilder, MessageType, ToFromFrame};
+
+// any payload in an otherwise valid broadcast frame
+fuzz_target!(|data: &[u8]| {
+    let frame = FrameBuilder::new()
+        .message_type(MessageType::Broadcast)
+        .sender(1)
+        .route(&[1])
+        .payload(data.to_vec())
+        .build();
+    if let Ok(mut frame) = frame {
+        if let Ok(msg) = BroadcastMessage::from_frame(&mut frame) {
+            // what was accepted can be sent on
+            msg.to_frame(1, 2, vec![2]).unwrap();
+        }
+    }
+});
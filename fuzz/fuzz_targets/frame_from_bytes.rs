@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use loramesh::stack::{Frame, FrameHeader};
+
+// anything heard on the air goes through here first
+fuzz_target!(|data: &[u8]| {
+    let bytes = data.to_vec();
+    if let Ok(mut frame) = Frame::from_bytes(&bytes) {
+        // a frame that parsed has a valid header, and goes back out unchanged
+        frame.header();
+        assert_eq!(frame.to_bytes(), bytes);
+        assert!(FrameHeader::from_bytes(data).is_ok());
+    }
+});
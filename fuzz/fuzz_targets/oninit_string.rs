@@ -0,0 +1,25 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use loramesh::hardware::{parse_hweui, parse_init_line, parse_radio_config, parse_rx_line, parse_version};
+
+// init file lines and the radio's answers, the serial reader only passes on valid UTF-8
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    for line in text.lines() {
+        if let Some((command, expected)) = parse_init_line(line) {
+            assert_eq!(command, command.trim());
+            assert_eq!(expected, expected.trim());
+        }
+        if let Some(Ok(packet)) = parse_rx_line(line) {
+            assert!(!packet.is_empty());
+        }
+        let _ = parse_version(line);
+        let _ = parse_hweui(line);
+    }
+    // the answers to a config dump, one per line
+    let responses: Vec<String> = text.lines().map(String::from).collect();
+    let _ = parse_radio_config(&responses);
+});
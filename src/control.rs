@@ -0,0 +1,399 @@
+use log::*;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use crossbeam_channel;
+use crossbeam_channel::{Receiver, Sender};
+use crate::hardware::lostik::mkerror;
+use crate::stack::NodeId;
+use crate::stack::message::RemoteCommand;
+
+/// Pings sent when the count is not given
+pub const DEFAULT_PING_COUNT: u16 = 4;
+
+/// A command for the running node, sent over its control socket
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlCommand {
+    /// ping a node `count` times
+    Ping { dest: NodeId, count: u16 },
+    /// show the path to a node
+    Traceroute { dest: NodeId },
+    /// send a text and wait for its delivery receipt
+    SendText { dest: NodeId, text: String },
+    /// print incoming texts until disconnected
+    ListenText,
+    /// flood an emergency beacon until the all-clear
+    Emergency { text: String },
+    /// cancel our emergency
+    AllClear,
+    /// print active emergencies, then new emergency events until disconnected
+    ListenEmergency,
+    /// silence our radio at once, nothing is sent until `RadioReset`
+    RadioStop,
+    /// wake our radio after `RadioStop` and send again
+    RadioReset,
+    /// send a file and wait until it is delivered
+    SendFile { dest: NodeId, path: PathBuf },
+    /// list the last known position of every node
+    Positions,
+    /// list the gateways heard from, best first
+    Gateways,
+    /// list the candidate routes to every node with their metrics
+    Routes,
+    /// list the direct neighbors with the quality of their links both ways
+    Neighbors,
+    /// list the transmit queue depth and bytes sent for every destination
+    Queues,
+    /// list the tunnel filter rules with the packets each dropped
+    Filters,
+    /// run a command on another node and wait for its result
+    Remote { dest: NodeId, command: RemoteCommand },
+    /// change a setting on another node with a signed config message
+    Config { dest: NodeId, key: String, value: String },
+    /// list the name, hardware and software of every node
+    Nodes,
+    /// list the node holding every mesh address we know, and the addresses being resolved
+    Addresses,
+    /// dump the latest telemetry of every node as JSON
+    Telemetry,
+    /// show the mesh graph used for routing, as a list of links or to be drawn
+    Topology { format: TopologyFormat },
+}
+
+/// How the `topology` command prints the mesh
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TopologyFormat {
+    /// one link with its cost per line
+    Text,
+    /// Graphviz DOT, for `dot -Tsvg`
+    Dot,
+    Json,
+}
+
+fn parse_topology_format(arg: Option<&str>) -> io::Result<TopologyFormat> {
+    match arg {
+        None => Ok(TopologyFormat::Text),
+        Some("dot") => Ok(TopologyFormat::Dot),
+        Some("json") => Ok(TopologyFormat::Json),
+        Some(format) => Err(mkerror(&format!("unknown topology format: {}, use dot or json", format))),
+    }
+}
+
+/// A command along with the channel for its output lines
+/* The connection is closed once the node drops the reply sender. */
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub reply: Sender<String>,
+}
+
+fn parse_nodeid(arg: Option<&str>) -> io::Result<NodeId> {
+    let arg = arg.ok_or(mkerror("missing node ID"))?;
+    arg.parse::<NodeId>().map_err(|_| mkerror(&format!("invalid node ID: {}", arg)))
+}
+
+fn parse_text(text: &str) -> io::Result<String> {
+    if text.is_empty() {
+        return Err(mkerror("missing text"));
+    }
+    // the control protocol is line based
+    if text.contains('\n') {
+        return Err(mkerror("text must be a single line"));
+    }
+    Ok(String::from(text))
+}
+
+fn parse_count(arg: Option<&str>) -> io::Result<u16> {
+    let arg = arg.ok_or(mkerror("missing count"))?;
+    match arg.parse::<u16>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(mkerror(&format!("invalid count: {}", arg))),
+    }
+}
+
+impl ControlCommand {
+    /// Parse command line arguments, such as `ping 5 --count 3`
+    pub fn from_args(args: &[String]) -> io::Result<Self> {
+        let mut args = args.iter().map(|a| a.as_str());
+        match args.next() {
+            Some("ping") => {
+                let mut dest = None;
+                let mut count = DEFAULT_PING_COUNT;
+                while let Some(arg) = args.next() {
+                    match arg {
+                        "-c" | "--count" => count = parse_count(args.next())?,
+                        _ => dest = Some(parse_nodeid(Some(arg))?),
+                    }
+                }
+                let dest = dest.ok_or(mkerror("usage: ping <node-id> [--count N]"))?;
+                Ok(ControlCommand::Ping { dest, count })
+            },
+            Some("traceroute") => {
+                let dest = parse_nodeid(args.next())?;
+                Ok(ControlCommand::Traceroute { dest })
+            },
+            Some("send-text") => {
+                let dest = parse_nodeid(args.next())?;
+                let text = parse_text(&args.collect::<Vec<&str>>().join(" "))?;
+                Ok(ControlCommand::SendText { dest, text })
+            },
+            Some("listen-text") => Ok(ControlCommand::ListenText),
+            Some("emergency") => {
+                let text = parse_text(&args.collect::<Vec<&str>>().join(" "))?;
+                Ok(ControlCommand::Emergency { text })
+            },
+            Some("all-clear") => Ok(ControlCommand::AllClear),
+            Some("listen-emergency") => Ok(ControlCommand::ListenEmergency),
+            Some("radio-stop") => Ok(ControlCommand::RadioStop),
+            Some("radio-reset") => Ok(ControlCommand::RadioReset),
+            Some("remote") => {
+                let dest = parse_nodeid(args.next())?;
+                let command = RemoteCommand::parse(&args.collect::<Vec<&str>>().join(" "))?;
+                Ok(ControlCommand::Remote { dest, command })
+            },
+            Some("config") => {
+                let dest = parse_nodeid(args.next())?;
+                let key = args.next().ok_or(mkerror("usage: config <node-id> <setting> <value>"))?;
+                let value = args.next().ok_or(mkerror("usage: config <node-id> <setting> <value>"))?;
+                Ok(ControlCommand::Config { dest, key: String::from(key), value: String::from(value) })
+            },
+            Some("send-file") => {
+                let dest = parse_nodeid(args.next())?;
+                let path = args.next().ok_or(mkerror("usage: send-file <node-id> <path>"))?;
+                // the node may run in another directory
+                let path = fs::canonicalize(path)?;
+                Ok(ControlCommand::SendFile { dest, path })
+            },
+            Some("positions") => Ok(ControlCommand::Positions),
+            Some("nodes") => Ok(ControlCommand::Nodes),
+            Some("addresses") => Ok(ControlCommand::Addresses),
+            Some("gateways") => Ok(ControlCommand::Gateways),
+            Some("neighbors") => Ok(ControlCommand::Neighbors),
+            Some("routes") => Ok(ControlCommand::Routes),
+            Some("queues") => Ok(ControlCommand::Queues),
+            Some("filters") => Ok(ControlCommand::Filters),
+            Some("telemetry") => Ok(ControlCommand::Telemetry),
+            Some("topology") => Ok(ControlCommand::Topology { format: parse_topology_format(args.next())? }),
+            Some(cmd) => Err(mkerror(&format!("unknown command: {}", cmd))),
+            None => Err(mkerror("missing command")),
+        }
+    }
+
+    /// Parse a line received on the control socket
+    pub fn parse(line: &str) -> io::Result<Self> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("ping") => {
+                let dest = parse_nodeid(words.next())?;
+                let count = parse_count(words.next())?;
+                Ok(ControlCommand::Ping { dest, count })
+            },
+            Some("traceroute") => {
+                let dest = parse_nodeid(words.next())?;
+                Ok(ControlCommand::Traceroute { dest })
+            },
+            Some("send-text") => {
+                // the text is everything after the node ID, spacing included
+                let mut parts = line.splitn(3, ' ').skip(1);
+                let dest = parse_nodeid(parts.next())?;
+                let text = parse_text(parts.next().unwrap_or(""))?;
+                Ok(ControlCommand::SendText { dest, text })
+            },
+            Some("listen-text") => Ok(ControlCommand::ListenText),
+            Some("emergency") => {
+                // the text is everything after the command, spacing included
+                let text = parse_text(line.split_once(' ').map_or("", |(_, text)| text))?;
+                Ok(ControlCommand::Emergency { text })
+            },
+            Some("all-clear") => Ok(ControlCommand::AllClear),
+            Some("listen-emergency") => Ok(ControlCommand::ListenEmergency),
+            Some("radio-stop") => Ok(ControlCommand::RadioStop),
+            Some("radio-reset") => Ok(ControlCommand::RadioReset),
+            Some("remote") => {
+                let mut parts = line.splitn(3, ' ').skip(1);
+                let dest = parse_nodeid(parts.next())?;
+                let command = RemoteCommand::parse(parts.next().unwrap_or(""))?;
+                Ok(ControlCommand::Remote { dest, command })
+            },
+            Some("config") => {
+                let dest = parse_nodeid(words.next())?;
+                let key = words.next().ok_or(mkerror("missing setting"))?;
+                let value = words.next().ok_or(mkerror("missing value"))?;
+                Ok(ControlCommand::Config { dest, key: String::from(key), value: String::from(value) })
+            },
+            Some("send-file") => {
+                // the path is everything after the node ID
+                let mut parts = line.splitn(3, ' ').skip(1);
+                let dest = parse_nodeid(parts.next())?;
+                match parts.next() {
+                    Some(path) if !path.is_empty() => Ok(ControlCommand::SendFile { dest, path: PathBuf::from(path) }),
+                    _ => Err(mkerror("missing path")),
+                }
+            },
+            Some("positions") => Ok(ControlCommand::Positions),
+            Some("nodes") => Ok(ControlCommand::Nodes),
+            Some("addresses") => Ok(ControlCommand::Addresses),
+            Some("gateways") => Ok(ControlCommand::Gateways),
+            Some("neighbors") => Ok(ControlCommand::Neighbors),
+            Some("routes") => Ok(ControlCommand::Routes),
+            Some("queues") => Ok(ControlCommand::Queues),
+            Some("filters") => Ok(ControlCommand::Filters),
+            Some("telemetry") => Ok(ControlCommand::Telemetry),
+            Some("topology") => Ok(ControlCommand::Topology { format: parse_topology_format(words.next())? }),
+            Some(cmd) => Err(mkerror(&format!("unknown command: {}", cmd))),
+            None => Err(mkerror("empty command")),
+        }
+    }
+
+    /// The line sent over the control socket for this command
+    pub fn to_line(&self) -> String {
+        match self {
+            ControlCommand::Ping { dest, count } => format!("ping {} {}", dest, count),
+            ControlCommand::Traceroute { dest } => format!("traceroute {}", dest),
+            ControlCommand::SendText { dest, text } => format!("send-text {} {}", dest, text),
+            ControlCommand::ListenText => String::from("listen-text"),
+            ControlCommand::Emergency { text } => format!("emergency {}", text),
+            ControlCommand::AllClear => String::from("all-clear"),
+            ControlCommand::ListenEmergency => String::from("listen-emergency"),
+            ControlCommand::RadioStop => String::from("radio-stop"),
+            ControlCommand::RadioReset => String::from("radio-reset"),
+            ControlCommand::SendFile { dest, path } => format!("send-file {} {}", dest, path.display()),
+            ControlCommand::Positions => String::from("positions"),
+            ControlCommand::Nodes => String::from("nodes"),
+            ControlCommand::Addresses => String::from("addresses"),
+            ControlCommand::Gateways => String::from("gateways"),
+            ControlCommand::Neighbors => String::from("neighbors"),
+            ControlCommand::Routes => String::from("routes"),
+            ControlCommand::Queues => String::from("queues"),
+            ControlCommand::Filters => String::from("filters"),
+            ControlCommand::Remote { dest, command } => format!("remote {} {}", dest, command.to_line()),
+            ControlCommand::Config { dest, key, value } => format!("config {} {} {}", dest, key, value),
+            ControlCommand::Telemetry => String::from("telemetry"),
+            ControlCommand::Topology { format: TopologyFormat::Text } => String::from("topology"),
+            ControlCommand::Topology { format: TopologyFormat::Dot } => String::from("topology dot"),
+            ControlCommand::Topology { format: TopologyFormat::Json } => String::from("topology json"),
+        }
+    }
+}
+
+/// Listen for commands on a unix socket
+/* Each connection carries one command line, the output of the command is
+streamed back one line at a time. */
+pub fn listen(path: &Path) -> io::Result<Receiver<ControlRequest>> {
+    // a stale socket from a previous run would make bind fail
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let socketpath = PathBuf::from(path);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Err(e) => error!("Control socket {:?} failed: {}", socketpath, e),
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_client(stream, sender) {
+                            debug!("Control connection closed: {}", e);
+                        }
+                    });
+                }
+            }
+        }
+    });
+    info!("Control socket listening on {:?}", path);
+    Ok(receiver)
+}
+
+fn handle_client(mut stream: UnixStream, sender: Sender<ControlRequest>) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut line)?;
+
+    let command = match ControlCommand::parse(&line) {
+        Err(e) => return writeln!(stream, "error: {}", e),
+        Ok(command) => command,
+    };
+    let (reply, replies) = crossbeam_channel::unbounded();
+    sender.send(ControlRequest { command, reply })
+        .map_err(|_| mkerror("node is not accepting commands"))?;
+    for line in replies.iter() {
+        writeln!(stream, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Send a command to a running node and print its output
+pub fn request(path: &Path, command: &ControlCommand) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command.to_line())?;
+    for line in BufReader::new(stream).lines() {
+        println!("{}", line?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn control_command_parse() {
+    let args: Vec<String> = ["ping", "5", "--count", "3"].iter().map(|a| a.to_string()).collect();
+    let cmd = ControlCommand::from_args(&args).unwrap();
+    assert_eq!(cmd, ControlCommand::Ping { dest: 5, count: 3 });
+    assert_eq!(ControlCommand::parse(&cmd.to_line()).unwrap(), cmd);
+
+    let args: Vec<String> = ["ping", "7"].iter().map(|a| a.to_string()).collect();
+    assert_eq!(ControlCommand::from_args(&args).unwrap(), ControlCommand::Ping { dest: 7, count: DEFAULT_PING_COUNT });
+
+    let args: Vec<String> = ["traceroute", "4"].iter().map(|a| a.to_string()).collect();
+    let cmd = ControlCommand::from_args(&args).unwrap();
+    assert_eq!(ControlCommand::parse(&cmd.to_line()).unwrap(), ControlCommand::Traceroute { dest: 4 });
+
+    let args: Vec<String> = ["send-text", "2", "hello  there", "bob"].iter().map(|a| a.to_string()).collect();
+    let cmd = ControlCommand::from_args(&args).unwrap();
+    assert_eq!(cmd, ControlCommand::SendText { dest: 2, text: String::from("hello  there bob") });
+    assert_eq!(ControlCommand::parse(&format!("{}\n", cmd.to_line())).unwrap(), cmd);
+    assert!(ControlCommand::parse("send-text 2").is_err());
+    assert!(ControlCommand::from_args(&["send-text".to_string(), "2".to_string(), "a\nb".to_string()]).is_err());
+
+    let cmd = ControlCommand::parse("send-file 3 /tmp/sensor log.csv\n").unwrap();
+    assert_eq!(cmd, ControlCommand::SendFile { dest: 3, path: PathBuf::from("/tmp/sensor log.csv") });
+    assert_eq!(ControlCommand::parse(&cmd.to_line()).unwrap(), cmd);
+    assert!(ControlCommand::parse("send-file 3").is_err());
+
+    assert!(ControlCommand::parse("ping 300 1").is_err());
+    assert!(ControlCommand::parse("ping 3 0").is_err());
+    assert_eq!(ControlCommand::parse("telemetry").unwrap(), ControlCommand::Telemetry);
+    assert_eq!(ControlCommand::parse("topology").unwrap(), ControlCommand::Topology { format: TopologyFormat::Text });
+    let cmd = ControlCommand::from_args(&["topology".to_string(), "dot".to_string()]).unwrap();
+    assert_eq!(cmd, ControlCommand::Topology { format: TopologyFormat::Dot });
+    assert_eq!(ControlCommand::parse(&cmd.to_line()).unwrap(), cmd);
+    assert_eq!(ControlCommand::parse("topology json").unwrap(), ControlCommand::Topology { format: TopologyFormat::Json });
+    assert!(ControlCommand::parse("topology svg").is_err());
+    assert_eq!(ControlCommand::parse("nodes").unwrap(), ControlCommand::Nodes);
+    assert_eq!(ControlCommand::parse("addresses").unwrap(), ControlCommand::Addresses);
+    assert_eq!(ControlCommand::parse("gateways").unwrap(), ControlCommand::Gateways);
+    assert_eq!(ControlCommand::parse("neighbors").unwrap(), ControlCommand::Neighbors);
+    assert_eq!(ControlCommand::parse("routes").unwrap(), ControlCommand::Routes);
+    assert_eq!(ControlCommand::parse("queues").unwrap(), ControlCommand::Queues);
+    assert_eq!(ControlCommand::parse("filters").unwrap(), ControlCommand::Filters);
+    let cmd = ControlCommand::parse("emergency need  help").unwrap();
+    assert_eq!(cmd, ControlCommand::Emergency { text: String::from("need  help") });
+    assert_eq!(ControlCommand::parse(&cmd.to_line()).unwrap(), cmd);
+    assert!(ControlCommand::parse("emergency").is_err());
+    assert_eq!(ControlCommand::parse("all-clear").unwrap(), ControlCommand::AllClear);
+    assert_eq!(ControlCommand::parse("radio-stop").unwrap(), ControlCommand::RadioStop);
+    assert_eq!(ControlCommand::parse(&ControlCommand::RadioReset.to_line()).unwrap(), ControlCommand::RadioReset);
+    let cmd = ControlCommand::parse("config 4 hellointerval 30000").unwrap();
+    assert_eq!(cmd, ControlCommand::Config { dest: 4, key: String::from("hellointerval"), value: String::from("30000") });
+    assert_eq!(ControlCommand::parse(&cmd.to_line()).unwrap(), cmd);
+    assert!(ControlCommand::parse("config 4 hellointerval").is_err());
+    let cmd = ControlCommand::parse("remote 4 set hellointerval 30000").unwrap();
+    assert_eq!(cmd, ControlCommand::Remote { dest: 4, command: RemoteCommand::Set(String::from("hellointerval"), String::from("30000")) });
+    assert_eq!(ControlCommand::parse(&cmd.to_line()).unwrap(), cmd);
+    assert!(ControlCommand::parse("remote 4 reboot").is_err());
+    assert!(ControlCommand::parse("reboot").is_err());
+    assert!(ControlCommand::from_args(&["ping".to_string()]).is_err());
+}
@@ -0,0 +1,117 @@
+use log::*;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::thread;
+use std::time::Duration;
+use crossbeam_channel::{Receiver, RecvTimeoutError, TrySendError};
+use tokio::sync::mpsc;
+use crate::hardware::lostik::{mkerror, Radio, RxPacket};
+use crate::stack::qos::{TxPriority, TxQueueSender};
+
+/// Frames waiting between the radio and async code
+const ASYNC_CHANNEL_SIZE: usize = 64;
+
+/// How often the receive bridge checks whether the async side is gone
+const BRIDGE_POLL: Duration = Duration::from_millis(100);
+
+/// A running `LoStik` for use from `tokio`
+/* The radio loop keeps running on its own thread. Frames to send go
+straight into its transmit queue, which never blocks, and a thread of
+our own moves received frames to a tokio channel. That thread is not a
+tokio blocking task, so it never holds up the runtime shutting down, and
+it stops once the `AsyncLoStik` is dropped. */
+pub struct AsyncLoStik {
+    txsender: TxQueueSender,
+    rxreader: mpsc::Receiver<RxPacket>,
+}
+
+impl AsyncLoStik {
+    /// Start the radio loop of an initialized radio
+    pub fn new<R: Radio>(radio: R) -> Self {
+        let (rxreader, txsender) = radio.run();
+        AsyncLoStik::bridge(rxreader, txsender)
+    }
+
+    pub(crate) fn bridge(rxreader: Receiver<RxPacket>, txsender: TxQueueSender) -> Self {
+        let (asyncrx, rxout) = mpsc::channel(ASYNC_CHANNEL_SIZE);
+        thread::spawn(move || {
+            while !asyncrx.is_closed() {
+                match rxreader.recv_timeout(BRIDGE_POLL) {
+                    Ok(packet) => if asyncrx.blocking_send(packet).is_err() {
+                        break;
+                    },
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            debug!("Async radio receive bridge stopped");
+        });
+        AsyncLoStik { txsender, rxreader: rxout }
+    }
+
+    /// Queue frame bytes for transmission at normal priority
+    pub async fn tx(&self, data: Vec<u8>) -> io::Result<()> {
+        self.tx_with_priority(data, TxPriority::Normal).await
+    }
+
+    /// Queue frame bytes for transmission in the given priority tier
+    /// fails with `WouldBlock` if the tier is full and the frame was dropped
+    pub async fn tx_with_priority(&self, data: Vec<u8>, priority: TxPriority) -> io::Result<()> {
+        self.txsender.send(data, priority).map_err(|e| match e {
+            TrySendError::Full(_) => Error::new(ErrorKind::WouldBlock, format!("Transmit queue for {:?} priority is full", priority)),
+            TrySendError::Disconnected(_) => mkerror("Radio thread is not running"),
+        })
+    }
+
+    /// Wait for the next packet from the radio
+    pub async fn rx(&mut self) -> io::Result<RxPacket> {
+        self.rxreader.recv().await
+            .ok_or_else(|| mkerror("Radio thread is not running"))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn async_bridge() {
+    use crate::hardware::LinkQuality;
+    use crate::stack::qos::tx_queue;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+    let (rxsender, rxreader) = crossbeam_channel::unbounded();
+    let (txsender, txreader) = tx_queue(4);
+    let queued = txsender.clone();
+    runtime.block_on(async move {
+        let mut radio = AsyncLoStik::bridge(rxreader, txsender);
+        rxsender.send(RxPacket { data: vec![1, 2, 3], quality: LinkQuality::default() }).unwrap();
+        assert_eq!(radio.rx().await.unwrap().data, vec![1, 2, 3]);
+
+        radio.tx_with_priority(vec![4], TxPriority::Low).await.unwrap();
+        radio.tx_with_priority(vec![5], TxPriority::High).await.unwrap();
+        // the radio takes high priority frames first
+        assert_eq!(queued.len(), 2);
+        assert_eq!(txreader.try_recv().unwrap(), vec![5]);
+        assert_eq!(txreader.try_recv().unwrap(), vec![4]);
+
+        // a frame dropped for a full queue is reported
+        for _ in 0..4 {
+            radio.tx(vec![6]).await.unwrap();
+        }
+        assert_eq!(radio.tx(vec![7]).await.unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        // the radio thread went away
+        drop(rxsender);
+        assert!(radio.rx().await.is_err());
+    });
+
+    // the receive bridge stops with the async side, and does not hold up shutting down
+    let (rxsender, rxreader) = crossbeam_channel::unbounded();
+    let (txsender, _txreader) = tx_queue(4);
+    let radio = runtime.block_on(async move { AsyncLoStik::bridge(rxreader, txsender) });
+    drop(radio);
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    let started = std::time::Instant::now();
+    while rxsender.send(RxPacket { data: vec![1], quality: LinkQuality::default() }).is_ok() {
+        assert!(started.elapsed() < Duration::from_secs(5), "receive bridge still running");
+        thread::sleep(BRIDGE_POLL);
+    }
+}
@@ -0,0 +1,206 @@
+use log::*;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use crossbeam_channel;
+use crossbeam_channel::Receiver;
+use crate::hardware::serial::SerialIO;
+
+/// meters per second in a knot
+const KNOTS_TO_MS: f32 = 0.514_444;
+
+/// A sentence from a GPS receiver we know how to use
+#[derive(Clone, Debug, PartialEq)]
+pub enum NmeaSentence {
+    /// `$GPGGA`, position and fix quality
+    Gga { lat: Option<f64>, lon: Option<f64>, quality: u8, altitude: Option<f32> },
+    /// `$GPRMC`, position and speed
+    Rmc { valid: bool, lat: Option<f64>, lon: Option<f64>, speed: Option<f32> },
+}
+
+/// Position reported by the GPS receiver
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpsFix {
+    /// degrees, north is positive
+    pub lat: f64,
+    /// degrees, east is positive
+    pub lon: f64,
+    /// meters above mean sea level
+    pub altitude: f32,
+    /// ground speed in meters per second
+    pub speed: f32,
+    /// GGA fix quality, 1 is GPS and 2 is differential GPS
+    pub quality: u8,
+}
+
+/// true if the sentence has no checksum or a correct one
+fn checksum_ok(body: &str, checksum: Option<&str>) -> bool {
+    match checksum {
+        None => true,
+        Some(hex) => {
+            let sum = body.bytes().fold(0u8, |acc, b| acc ^ b);
+            u8::from_str_radix(hex.trim(), 16).map_or(false, |expected| expected == sum)
+        }
+    }
+}
+
+/// parse `ddmm.mmmm` or `dddmm.mmmm` with its hemisphere into degrees
+fn parse_coordinate(value: &str, hemisphere: &str) -> Option<f64> {
+    let dot = value.find('.').unwrap_or(value.len());
+    if dot < 2 {
+        return None;
+    }
+    let degrees: f64 = value[..dot-2].parse().ok()?;
+    let minutes: f64 = value[dot-2..].parse().ok()?;
+    let coordinate = degrees + minutes / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(coordinate),
+        "S" | "W" => Some(-coordinate),
+        _ => None,
+    }
+}
+
+/// Parse a GGA or RMC sentence, None for anything else
+/* Receivers print plenty of sentences we don't use, and a noisy serial
+line corrupts some of them. Anything that does not parse cleanly is
+dropped, the next sentence is only a second away. */
+pub fn parse_nmea(line: &str) -> Option<NmeaSentence> {
+    let line = line.trim();
+    if !line.starts_with('$') {
+        return None;
+    }
+    let mut parts = line[1..].splitn(2, '*');
+    let body = parts.next()?;
+    if !checksum_ok(body, parts.next()) {
+        debug!("Dropping NMEA sentence with bad checksum: {}", line);
+        return None;
+    }
+
+    let fields: Vec<&str> = body.split(',').collect();
+    // the first two letters are the talker, such as GP or GN
+    let kind = fields[0].get(2..)?;
+    let field = |i: usize| fields.get(i).cloned().unwrap_or("");
+    match kind {
+        "GGA" => Some(NmeaSentence::Gga {
+            lat: parse_coordinate(field(2), field(3)),
+            lon: parse_coordinate(field(4), field(5)),
+            quality: field(6).parse().unwrap_or(0),
+            altitude: field(9).parse().ok(),
+        }),
+        "RMC" => Some(NmeaSentence::Rmc {
+            valid: field(2) == "A",
+            lat: parse_coordinate(field(3), field(4)),
+            lon: parse_coordinate(field(5), field(6)),
+            speed: field(7).parse::<f32>().ok().map(|knots| knots * KNOTS_TO_MS),
+        }),
+        _ => None,
+    }
+}
+
+/// Combines GGA and RMC sentences into fixes
+#[derive(Clone, Debug, Default)]
+pub struct GpsState {
+    speed: f32,
+}
+
+impl GpsState {
+    /// Feed a sentence, returning a fix when a GGA sentence has a position
+    pub fn update(&mut self, sentence: NmeaSentence) -> Option<GpsFix> {
+        match sentence {
+            NmeaSentence::Rmc { valid, speed, .. } => {
+                if valid {
+                    self.speed = speed.unwrap_or(0.0);
+                }
+                None
+            },
+            NmeaSentence::Gga { lat, lon, quality, altitude } => {
+                if quality == 0 {
+                    return None;
+                }
+                Some(GpsFix {
+                    lat: lat?,
+                    lon: lon?,
+                    altitude: altitude.unwrap_or(0.0),
+                    speed: self.speed,
+                    quality,
+                })
+            }
+        }
+    }
+}
+
+/// Read fixes from a GPS receiver on a serial port
+pub fn gps_reader(port: PathBuf, baud: u32) -> io::Result<Receiver<GpsFix>> {
+    let mut ser = SerialIO::with_baud(port, baud, Duration::from_secs(60))?;
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    thread::spawn(move || {
+        info!("GPS reader started on {:?}", ser.portname);
+        let mut state = GpsState::default();
+        loop {
+            match ser.readln() {
+                Err(e) => {
+                    // usually a read timeout while the receiver is quiet
+                    debug!("{:?}: {}", ser.portname, e);
+                },
+                Ok(None) => thread::sleep(Duration::from_millis(100)),
+                Ok(Some(line)) => {
+                    if let Some(fix) = parse_nmea(&line).and_then(|s| state.update(s)) {
+                        if sender.send(fix).is_err() {
+                            return;
+                        }
+                    }
+                },
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+#[cfg(test)]
+#[test]
+fn nmea_parse() {
+    let gga = parse_nmea("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47").unwrap();
+    match gga {
+        NmeaSentence::Gga { lat, lon, quality, altitude } => {
+            assert!((lat.unwrap() - 48.1173).abs() < 1e-6);
+            assert!((lon.unwrap() - 11.516_666).abs() < 1e-5);
+            assert_eq!(quality, 1);
+            assert_eq!(altitude, Some(545.4));
+        },
+        _ => panic!("expected GGA"),
+    }
+
+    let rmc = parse_nmea("$GNRMC,123519,A,4807.038,S,01131.000,W,022.4,084.4,230394,003.1,W*7B").unwrap();
+    match rmc {
+        NmeaSentence::Rmc { valid, lat, lon, speed } => {
+            assert!(valid);
+            assert!(lat.unwrap() < 0.0 && lon.unwrap() < 0.0);
+            assert!((speed.unwrap() - 11.523).abs() < 0.01);
+        },
+        _ => panic!("expected RMC"),
+    }
+
+    // corrupted checksum, unknown sentences and garbage are dropped
+    assert_eq!(parse_nmea("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*48"), None);
+    assert_eq!(parse_nmea("$GPGSV,2,1,08,01,40,083,46*4E"), None);
+    assert_eq!(parse_nmea("\u{0}$G,,"), None);
+    assert_eq!(parse_nmea("$"), None);
+}
+
+#[test]
+fn gps_missing_fix() {
+    let mut state = GpsState::default();
+    // receiver still searching for satellites
+    let nofix = parse_nmea("$GPGGA,123519,,,,,0,00,,,M,,M,,").unwrap();
+    assert_eq!(state.update(nofix), None);
+    let void = parse_nmea("$GPRMC,123519,V,,,,,,,230394,,").unwrap();
+    assert_eq!(state.update(void), None);
+
+    let rmc = parse_nmea("$GPRMC,123520,A,4807.038,N,01131.000,E,010.0,084.4,230394,003.1,W").unwrap();
+    state.update(rmc);
+    let gga = parse_nmea("$GPGGA,123520,4807.038,N,01131.000,E,1,08,0.9,,M,46.9,M,,").unwrap();
+    let fix = state.update(gga).unwrap();
+    assert_eq!(fix.altitude, 0.0);
+    assert!((fix.speed - 5.144).abs() < 0.01);
+}
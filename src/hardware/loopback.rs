@@ -0,0 +1,455 @@
+use log::*;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crate::hardware::lostik::{LinkQuality, Radio, RxPacket};
+use crate::stack::qos::{tx_queue, TxQueueReceiver, TxQueueSender};
+
+/// Frames a loopback radio's transmit queue holds per priority tier
+const LOOPBACK_QUEUE_SIZE: usize = 64;
+
+/// How long the transmit thread waits when its queue is empty
+const LOOPBACK_POLL: Duration = Duration::from_millis(1);
+
+/// A link between two loopback radios, with the loss and quality it overrides the sender's with
+#[derive(Clone, Copy, Debug, Default)]
+struct Link {
+    loss: Option<f64>,
+    quality: Option<LinkQuality>,
+}
+
+/// A frame on its way through the air to one receiver
+struct Reception {
+    id: u64,
+    to: usize,
+    end: Instant,
+    collided: bool,
+}
+
+/// Who hears whom, and what is on the air
+struct Medium {
+    receivers: Vec<Sender<RxPacket>>,
+    /// by transmitting and receiving radio
+    links: HashMap<(usize, usize), Link>,
+    down: Vec<bool>,
+    transmissions: Vec<u64>,
+    collisions: Vec<u64>,
+    /// until when each radio is transmitting
+    transmitting: Vec<Option<Instant>>,
+    /// time on air of a byte, frames only collide if it is not zero
+    airtime: Duration,
+    onair: Vec<Reception>,
+    nextid: u64,
+}
+
+impl Medium {
+    fn new() -> Self {
+        Medium {
+            receivers: Vec::new(),
+            links: HashMap::new(),
+            down: Vec::new(),
+            transmissions: Vec::new(),
+            collisions: Vec::new(),
+            transmitting: Vec::new(),
+            airtime: Duration::from_millis(0),
+            onair: Vec::new(),
+            nextid: 0,
+        }
+    }
+
+    /// add a radio hearing nobody, returning its index
+    fn attach(&mut self, receiver: Sender<RxPacket>) -> usize {
+        self.receivers.push(receiver);
+        self.down.push(false);
+        self.transmissions.push(0);
+        self.collisions.push(0);
+        self.transmitting.push(None);
+        self.receivers.len() - 1
+    }
+
+    /// Put a frame on the air, returning the receptions that are not lost
+    /* A frame starting while its receiver hears another one, or
+    transmits itself, collides with it and neither is received. */
+    fn transmit(&mut self, from: usize, len: usize, loss: f64, quality: LinkQuality, rng: &mut StdRng) -> Vec<(u64, usize, LinkQuality)> {
+        if self.down[from] {
+            return Vec::new();
+        }
+        self.transmissions[from] += 1;
+        let now = Instant::now();
+        let end = now + self.airtime * len as u32;
+        self.transmitting[from] = Some(end);
+        for reception in self.onair.iter_mut().filter(|reception| reception.to == from && reception.end > now) {
+            reception.collided = true;
+        }
+        let mut receptions = Vec::new();
+        for to in 0..self.receivers.len() {
+            let link = match self.links.get(&(from, to)) {
+                Some(link) if !self.down[to] => *link,
+                _ => continue,
+            };
+            if rng.gen::<f64>() < link.loss.unwrap_or(loss) {
+                trace!("Loopback lost a frame of {} bytes", len);
+                continue;
+            }
+            self.nextid += 1;
+            let mut collided = self.transmitting[to].iter().any(|until| *until > now);
+            if self.airtime > Duration::from_millis(0) {
+                for other in self.onair.iter_mut().filter(|other| other.to == to && other.end > now) {
+                    other.collided = true;
+                    collided = true;
+                }
+                self.onair.push(Reception { id: self.nextid, to, end, collided });
+            }
+            receptions.push((self.nextid, to, link.quality.unwrap_or(quality)));
+        }
+        receptions
+    }
+
+    /// Take the receptions of a frame off the air, true for those that did not collide
+    fn land(&mut self, receptions: &[(u64, usize, LinkQuality)]) -> Vec<bool> {
+        let collided: Vec<bool> = receptions.iter()
+            .map(|(id, _, _)| self.onair.iter().any(|reception| reception.id == *id && reception.collided))
+            .collect();
+        for ((_, to, _), &collided) in receptions.iter().zip(collided.iter()) {
+            if collided {
+                self.collisions[*to] += 1;
+            }
+        }
+        self.onair.retain(|reception| !receptions.iter().any(|(id, _, _)| *id == reception.id));
+        collided.into_iter().map(|collided| !collided).collect()
+    }
+}
+
+/// The medium shared by loopback radios, to change who hears whom while they run
+/* Radios are numbered in the order they were made. Links go both ways,
+and a link's own loss and quality take the place of those its sender
+was made with. */
+#[derive(Clone)]
+pub struct LoopbackMedium {
+    medium: Arc<Mutex<Medium>>,
+}
+
+impl LoopbackMedium {
+    /// put two radios in range of each other
+    pub fn connect(&self, a: usize, b: usize) {
+        let mut medium = self.medium.lock().unwrap();
+        medium.links.entry((a, b)).or_default();
+        medium.links.entry((b, a)).or_default();
+    }
+
+    /// take two radios out of range of each other
+    pub fn disconnect(&self, a: usize, b: usize) {
+        let mut medium = self.medium.lock().unwrap();
+        medium.links.remove(&(a, b));
+        medium.links.remove(&(b, a));
+    }
+
+    /// chance that a frame between two radios is lost, between 0 and 1
+    pub fn set_loss(&self, a: usize, b: usize, loss: f64) {
+        self.connect(a, b);
+        let mut medium = self.medium.lock().unwrap();
+        for link in [(a, b), (b, a)].iter() {
+            medium.links.get_mut(link).unwrap().loss = Some(loss.clamp(0.0, 1.0));
+        }
+    }
+
+    /// RSSI in dBm and SNR in dB two radios hear each other with
+    pub fn set_quality(&self, a: usize, b: usize, rssi: Option<i16>, snr: Option<i8>) {
+        self.connect(a, b);
+        let mut medium = self.medium.lock().unwrap();
+        for link in [(a, b), (b, a)].iter() {
+            medium.links.get_mut(link).unwrap().quality = Some(LinkQuality { rssi, snr });
+        }
+    }
+
+    /// time each byte of a frame is on the air
+    pub fn set_airtime(&self, per_byte: Duration) {
+        self.medium.lock().unwrap().airtime = per_byte;
+    }
+
+    /// switch a radio off, it neither sends nor hears anything until switched on again
+    pub fn set_down(&self, radio: usize, down: bool) {
+        self.medium.lock().unwrap().down[radio] = down;
+    }
+
+    /// frames a radio put on the air
+    pub fn transmissions(&self, radio: usize) -> u64 {
+        self.medium.lock().unwrap().transmissions[radio]
+    }
+
+    /// frames a radio received that were not read yet
+    pub fn unread(&self, radio: usize) -> usize {
+        self.medium.lock().unwrap().receivers[radio].len()
+    }
+
+    /// frames a radio missed because they collided with others
+    pub fn collisions(&self, radio: usize) -> u64 {
+        self.medium.lock().unwrap().collisions[radio]
+    }
+}
+
+/// A radio without hardware, for tests
+/* Every frame queued for transmission is received by the radios it is
+connected to: itself when made with `new`, every other one of a
+`PairedLoopback`. Frames arrive after `delay`, each receiver loses one
+with probability `loss`, and all are heard with the same signal
+quality, unless the medium says otherwise for a link. */
+pub struct LoopbackRadio {
+    index: usize,
+    medium: Arc<Mutex<Medium>>,
+    delay: Duration,
+    loss: f64,
+    quality: LinkQuality,
+    seed: Option<u64>,
+    rxreader: Receiver<RxPacket>,
+    txsender: TxQueueSender,
+    txreader: TxQueueReceiver,
+}
+
+impl LoopbackRadio {
+    /// A radio that receives whatever it transmits
+    pub fn new() -> Self {
+        let radio = LoopbackRadio::attached(Arc::new(Mutex::new(Medium::new())));
+        radio.medium().connect(radio.index, radio.index);
+        radio
+    }
+
+    fn attached(medium: Arc<Mutex<Medium>>) -> Self {
+        let (rxsender, rxreader) = crossbeam_channel::unbounded();
+        let index = medium.lock().unwrap().attach(rxsender);
+        let (txsender, txreader) = tx_queue(LOOPBACK_QUEUE_SIZE);
+        LoopbackRadio { index, medium, delay: Duration::from_millis(0), loss: 0.0, quality: LinkQuality::default(), seed: None, rxreader, txsender, txreader }
+    }
+
+    /// time between a frame being queued and its reception
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// chance that a receiver misses a frame, between 0 and 1
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss.max(0.0).min(1.0);
+        self
+    }
+
+    /// RSSI in dBm and SNR in dB the frames are received with
+    pub fn with_quality(mut self, rssi: Option<i16>, snr: Option<i8>) -> Self {
+        self.quality = LinkQuality { rssi, snr };
+        self
+    }
+
+    /// lose the same frames on every run
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// the medium this radio is on
+    pub fn medium(&self) -> LoopbackMedium {
+        LoopbackMedium { medium: self.medium.clone() }
+    }
+}
+
+impl Default for LoopbackRadio {
+    fn default() -> Self {
+        LoopbackRadio::new()
+    }
+}
+
+impl Radio for LoopbackRadio {
+    /// Start the transmit thread, it ends once every sender of its queue is dropped
+    fn run(&self) -> (Receiver<RxPacket>, TxQueueSender) {
+        let rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let (index, medium, txreader) = (self.index, self.medium.clone(), self.txreader.clone());
+        let (delay, loss, quality) = (self.delay, self.loss, self.quality);
+        thread::spawn(move || loopback_loop(index, medium, txreader, delay, loss, quality, rng));
+        (self.rxreader.clone(), self.txsender.clone())
+    }
+
+    fn txsender(&self) -> &TxQueueSender {
+        &self.txsender
+    }
+
+    fn airtime(&self, len: usize) -> Duration {
+        self.medium.lock().unwrap().airtime * len as u32
+    }
+
+    /// Refuse frames and drop the queued ones, like a radio put to sleep
+    fn emergency_stop(&mut self) -> io::Result<()> {
+        self.txsender.halt();
+        self.txreader.drain();
+        Ok(())
+    }
+}
+
+fn loopback_loop(index: usize, medium: Arc<Mutex<Medium>>, txreader: TxQueueReceiver, delay: Duration, loss: f64, quality: LinkQuality, mut rng: StdRng) {
+    loop {
+        let data = match txreader.try_recv() {
+            Ok(data) => data,
+            Err(TryRecvError::Empty) => {
+                thread::sleep(LOOPBACK_POLL);
+                continue;
+            },
+            Err(TryRecvError::Disconnected) => break,
+        };
+        thread::sleep(delay);
+        let (receptions, airtime) = {
+            let mut medium = medium.lock().unwrap();
+            (medium.transmit(index, data.len(), loss, quality, &mut rng), medium.airtime * data.len() as u32)
+        };
+        // the radio is busy until the frame is off the air
+        thread::sleep(airtime);
+        let mut medium = medium.lock().unwrap();
+        let received = medium.land(&receptions);
+        for ((_, to, quality), received) in receptions.into_iter().zip(received) {
+            if received {
+                // a receiver that went away just doesn't hear it
+                medium.receivers[to].send(RxPacket { data: data.clone(), quality }).ok();
+            }
+        }
+    }
+    debug!("Loopback radio stopped");
+}
+
+/// Radios sharing one medium, each hears what any other transmits
+pub struct PairedLoopback {
+    radios: Vec<LoopbackRadio>,
+}
+
+impl PairedLoopback {
+    pub fn new(n: usize) -> Self {
+        let paired = PairedLoopback::isolated(n);
+        let medium = paired.medium();
+        for a in 0..n {
+            for b in (a + 1)..n {
+                medium.connect(a, b);
+            }
+        }
+        paired
+    }
+
+    /// radios on one medium that are out of range of each other, until connected
+    pub fn isolated(n: usize) -> Self {
+        let medium = Arc::new(Mutex::new(Medium::new()));
+        PairedLoopback { radios: (0..n).map(|_| LoopbackRadio::attached(medium.clone())).collect() }
+    }
+
+    /// every radio's frames arrive after `delay`
+    pub fn with_delay(self, delay: Duration) -> Self {
+        self.map(|radio| radio.with_delay(delay))
+    }
+
+    /// every receiver misses a frame with probability `loss`
+    pub fn with_loss(self, loss: f64) -> Self {
+        self.map(|radio| radio.with_loss(loss))
+    }
+
+    /// every frame is received with this RSSI and SNR
+    pub fn with_quality(self, rssi: Option<i16>, snr: Option<i8>) -> Self {
+        self.map(|radio| radio.with_quality(rssi, snr))
+    }
+
+    /// the radios lose the same frames on every run
+    pub fn with_seed(self, seed: u64) -> Self {
+        let radios = self.radios.into_iter().enumerate()
+            .map(|(i, radio)| radio.with_seed(seed.wrapping_add(i as u64)))
+            .collect();
+        PairedLoopback { radios }
+    }
+
+    /// frames take `per_byte` on the air for each of their bytes, and those overlapping at a receiver collide
+    pub fn with_airtime(self, per_byte: Duration) -> Self {
+        self.medium().medium.lock().unwrap().airtime = per_byte;
+        self
+    }
+
+    fn map<F: Fn(LoopbackRadio) -> LoopbackRadio>(self, f: F) -> Self {
+        PairedLoopback { radios: self.radios.into_iter().map(f).collect() }
+    }
+
+    /// the medium the radios share
+    pub fn medium(&self) -> LoopbackMedium {
+        LoopbackMedium { medium: self.radios.first().map_or_else(|| Arc::new(Mutex::new(Medium::new())), |radio| radio.medium.clone()) }
+    }
+
+    pub fn into_radios(self) -> Vec<LoopbackRadio> {
+        self.radios
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn loopback_radio() {
+    use crate::stack::qos::TxPriority;
+
+    let timeout = Duration::from_secs(5);
+    let (rx, tx) = LoopbackRadio::new().with_quality(Some(-80), Some(5)).run();
+    tx.send(vec![1, 2, 3], TxPriority::Normal).unwrap();
+    let packet = rx.recv_timeout(timeout).unwrap();
+    assert_eq!(packet.data, vec![1, 2, 3]);
+    assert_eq!(packet.quality, LinkQuality { rssi: Some(-80), snr: Some(5) });
+
+    // three radios on one medium, each frame reaches the other two only
+    let radios: Vec<(Receiver<RxPacket>, TxQueueSender)> = PairedLoopback::new(3)
+        .with_delay(Duration::from_millis(10))
+        .into_radios().iter().map(|radio| radio.run()).collect();
+    radios[1].1.send(vec![7], TxPriority::High).unwrap();
+    assert_eq!(radios[0].0.recv_timeout(timeout).unwrap().data, vec![7]);
+    assert_eq!(radios[2].0.recv_timeout(timeout).unwrap().data, vec![7]);
+    assert!(radios[1].0.recv_timeout(Duration::from_millis(100)).is_err());
+
+    // a lossy medium drops some frames, the same ones for the same seed
+    let heard = |seed: u64| {
+        let radios: Vec<(Receiver<RxPacket>, TxQueueSender)> = PairedLoopback::new(2)
+            .with_loss(0.5)
+            .with_seed(seed)
+            .into_radios().iter().map(|radio| radio.run()).collect();
+        for i in 0..40u8 {
+            radios[0].1.send(vec![i], TxPriority::Normal).unwrap();
+        }
+        thread::sleep(Duration::from_millis(200));
+        radios[1].0.try_iter().map(|packet| packet.data[0]).collect::<Vec<u8>>()
+    };
+    let first = heard(1);
+    assert!(first.len() > 5 && first.len() < 35, "{} of 40 frames heard", first.len());
+    assert_eq!(heard(1), first);
+}
+
+#[test]
+fn loopback_relay() {
+    use crate::stack::{route_step, Frame, RouteStep};
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::TextMessage;
+    use crate::stack::qos::TxPriority;
+
+    // node 1 sends to node 3 by way of node 2
+    let radios: Vec<(Receiver<RxPacket>, TxQueueSender)> = PairedLoopback::new(3)
+        .into_radios().iter().map(|radio| radio.run()).collect();
+    let mut frame = TextMessage::new(1, "over two hops").unwrap().to_frame(9, 1, vec![2, 3]).unwrap();
+    radios[0].1.send(frame.to_bytes(), TxPriority::Normal).unwrap();
+
+    let timeout = Duration::from_secs(5);
+    let mut relayed = Frame::from_bytes(&radios[1].0.recv_timeout(timeout).unwrap().data).unwrap();
+    match route_step(&mut relayed, 2) {
+        RouteStep::Forward(3) => radios[1].1.send(relayed.to_bytes(), TxPriority::Normal).unwrap(),
+        _ => panic!("node 2 should forward to node 3"),
+    }
+    // node 3 is in range of node 1 as well and takes the first copy
+    let mut received = Frame::from_bytes(&radios[2].0.recv_timeout(timeout).unwrap().data).unwrap();
+    assert!(match route_step(&mut received, 3) { RouteStep::Deliver => true, _ => false });
+    assert_eq!(TextMessage::from_frame(&mut received).unwrap().text, "over two hops");
+
+    // node 1 overhears the relay, which tells it the first hop went through
+    let mut overheard = Frame::from_bytes(&radios[0].0.recv_timeout(timeout).unwrap().data).unwrap();
+    assert_eq!((overheard.sender(), overheard.route()), (1, vec![3]));
+    assert!(match route_step(&mut overheard, 1) { RouteStep::Overheard => true, _ => false });
+}
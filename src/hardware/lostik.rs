@@ -3,20 +3,259 @@ use std::fs;
 use std::io::{BufRead, BufReader, Error, ErrorKind};
 use std::io;
 use crossbeam_channel;
-use crossbeam_channel::{Sender, Receiver};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use hex;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use format_escape_default::format_escape_default;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use ratelimit_meter::{DirectRateLimiter, LeakyBucket};
+use crate::hardware::modulation::TxEncoding;
 use crate::hardware::serial::SerialIO;
+use crate::hardware::usb_detect::resolve_radioport;
+use crate::hardware::watchdog::WatchdogTimer;
 use crate::settings::Settings;
+use crate::stack::qos::{tx_queue_fair, TxQueueSender, TxQueueReceiver};
+use crate::stack::frame::{max_payload_for, parse_frame_key, MAX_FRAME_LEN};
+use crate::stack::timesync::airtime;
+use crate::stack::util::{fnv1a, unix_millis};
 
 pub fn mkerror(msg: &str) -> Error {
     Error::new(ErrorKind::Other, msg)
 }
 
+/// The error for a serial channel that was cut off, as a restart does to the loop it replaces
+fn serial_closed() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "Radio serial channel closed")
+}
+
+/// The failure of a radio loop operation if it ends the loop
+/* Only a serial channel that was cut off does, the radio recovers from
+anything else, or the failure count and the watchdog take care of it. */
+fn serial_failure(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// How long `LoStik::query` waits for the radio thread to answer
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the self-test waits for the radio to answer over serial
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the module takes to reboot after a factory reset, everything it sends meanwhile is read
+const FACTORY_RESET_WAIT: Duration = Duration::from_secs(5);
+
+/// Longest the module sleeps for, an emergency stop sleeps until it is woken
+const EMERGENCY_SLEEP_MS: u32 = 4294967295;
+
+/// Packets sent from one radio to the other by the self-test
+const LOOPBACK_PACKETS: u16 = 3;
+
+/// Size of the self-test packets, enough to tell them from noise
+const LOOPBACK_PACKET_LEN: usize = 16;
+
+/// Size of the frames sent at each level of a power ramp
+const RAMP_PACKET_LEN: usize = 16;
+
+/// Failed transmissions or receiver restarts in a row after which the radio configuration is dumped
+const PERSISTENT_FAILURES: u32 = 3;
+
+/// Listen before talk looks at a busy channel again after this, longer each time
+const LBT_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Times listen before talk finds the channel busy before a frame goes out anyway
+const LBT_MAX_LOOKS: u32 = 8;
+
+/// Longest listen before talk holds a frame back
+const LBT_MAX_DEFER: Duration = Duration::from_secs(2);
+
+/// Parameters read by `LoStik::read_config_dump`, each with `radio get`, in order
+const RADIO_CONFIG_PARAMS: [&str; 11] = ["mod", "freq", "pwr", "sf", "bw", "cr", "wdt", "sync", "prlen", "crc", "iqi"];
+
+/// A command for the radio thread and the channel for its response
+type RadioQuery = (String, Sender<io::Result<String>>);
+
+/// Snapshot of radio health readings
+#[derive(Clone, Debug, Default)]
+pub struct RadioStats {
+    /// internal module temperature in Celsius
+    pub temperature: Option<f32>,
+    /// module supply voltage in volts
+    pub vdd: Option<f32>,
+}
+
+/// Signal quality of a received packet, as reported by the radio
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct LinkQuality {
+    /// received signal strength in dBm
+    pub rssi: Option<i16>,
+    /// signal to noise ratio in dB
+    pub snr: Option<i8>,
+}
+
+/// Minimum, maximum, mean and standard deviation of signal readings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SignalStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub stddev: f32,
+}
+
+impl SignalStats {
+    /// statistics of the readings, None if there are none
+    pub fn from_samples(samples: &[f32]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let count = samples.len() as f32;
+        let mean = samples.iter().sum::<f32>() / count;
+        let variance = samples.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / count;
+        Some(SignalStats {
+            min: samples.iter().cloned().fold(f32::INFINITY, f32::min),
+            max: samples.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+            mean,
+            stddev: variance.sqrt(),
+        })
+    }
+}
+
+/// Results of a continuous receive test
+#[derive(Clone, Debug, PartialEq)]
+pub struct RxTestReport {
+    /// how long the radio was listening
+    pub duration: Duration,
+    /// packets received
+    pub packets: u32,
+    /// `radio_err` events, the radio gives up receiving after its watchdog time
+    pub errors: u32,
+    /// RSSI in dBm and SNR in dB of the received packets
+    pub rssi: Option<SignalStats>,
+    pub snr: Option<SignalStats>,
+}
+
+/// Results of a transmit stress test
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxTestReport {
+    /// packets the radio confirmed sending
+    pub sent: u32,
+    /// packets the radio refused or failed to send
+    pub errors: u32,
+    /// estimated time on air of the sent packets
+    pub total_airtime_ms: u64,
+    /// mean time from handing a packet to the radio until it confirmed sending it
+    pub average_tx_ms: u64,
+}
+
+/// Results of `LoStik::self_test`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfTestReport {
+    /// the module and its firmware, as reported by `sys get ver`
+    pub device: DeviceInfo,
+    /// firmware version number, such as (1, 0, 5)
+    pub firmware: (u8, u8, u8),
+    /// module supply voltage in volts
+    pub vdd: f32,
+    /// test packets sent to the second radio and received by it, if one was given
+    pub loopback: Option<(u32, u32)>,
+}
+
+/// Every radio parameter as read back from the module
+#[derive(Clone, Debug, PartialEq)]
+pub struct RadioConfig {
+    /// "lora" or "fsk"
+    pub mod_type: String,
+    pub freq_hz: u64,
+    pub power_dbm: i8,
+    /// spreading factor, 7 to 12
+    pub sf: u8,
+    pub bw_khz: u16,
+    /// coding rate, such as "4/5"
+    pub cr: String,
+    /// time the radio waits for a reception or transmission to end
+    pub wdt_ms: u32,
+    pub sync_word: u8,
+    /// preamble length in symbols
+    pub preamble_len: u16,
+    pub crc_enabled: bool,
+    /// inverted IQ
+    pub iqi_enabled: bool,
+}
+
+/// Packet `seq` of a transmit test, the sequence number followed by filler bytes
+pub fn test_packet(seq: u16, size: usize) -> Vec<u8> {
+    let mut packet = vec![0u8; size.max(2)];
+    packet[..2].copy_from_slice(&seq.to_be_bytes());
+    for (i, byte) in packet.iter_mut().enumerate().skip(2) {
+        *byte = i as u8;
+    }
+    packet
+}
+
+/// Transmit powers from `start_dbm` to `end_dbm` `step` dB apart, counting down if the end is lower
+pub fn power_levels(start_dbm: i8, end_dbm: i8, step: u8) -> Vec<i8> {
+    let step = if end_dbm < start_dbm { -(step.max(1) as i16) } else { step.max(1) as i16 };
+    let mut levels = Vec::new();
+    let mut dbm = start_dbm as i16;
+    while (step > 0 && dbm <= end_dbm as i16) || (step < 0 && dbm >= end_dbm as i16) {
+        levels.push(dbm as i8);
+        dbm += step;
+    }
+    levels
+}
+
+/// A packet received by the radio
+#[derive(Clone, Debug)]
+pub struct RxPacket {
+    pub data: Vec<u8>,
+    pub quality: LinkQuality,
+}
+
+/// Something frames are sent and received through
+pub trait Radio {
+    /// Start moving frames, returning where received packets arrive and the queue to transmit from
+    fn run(&self) -> (Receiver<RxPacket>, TxQueueSender);
+
+    /// The queue frames are transmitted from, the one `run` returns
+    fn txsender(&self) -> &TxQueueSender;
+
+    /// time on air of a frame of `len` bytes
+    fn airtime(&self, len: usize) -> Duration;
+
+    /// Send a command to the radio and return its answer
+    /* Radios without firmware to ask answer nothing. */
+    fn query(&self, cmd: &str) -> io::Result<String> {
+        Err(mkerror(&format!("Radio does not answer \"{}\"", cmd)))
+    }
+
+    /// Put the radio to sleep, it wakes up by itself after `duration`
+    fn sleep(&self, _duration: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Replace a stalled radio loop with a freshly initialized one
+    fn restart(&mut self) {}
+
+    /// Silence the radio at once, every frame is refused until `emergency_reset`
+    /* Radios without a module to put to sleep only stop taking frames. */
+    fn emergency_stop(&mut self) -> io::Result<()> {
+        self.txsender().halt();
+        Ok(())
+    }
+
+    /// Take frames again after `emergency_stop`
+    fn emergency_reset(&mut self) -> io::Result<()> {
+        self.txsender().resume();
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct LoStik {
     // Application options
@@ -24,27 +263,43 @@ pub struct LoStik {
     
     ser: SerialIO,
 
-    // serial messages coming from the radio
+    // serial messages coming from the radio, the serial thread sends to
+    // whichever sender is current so a stalled radio loop can be cut off
     readerlinesrx: crossbeam_channel::Receiver<String>,
+    readerlinestx: Arc<Mutex<crossbeam_channel::Sender<String>>>,
+
+    // cleared to tell a radio loop that it was replaced
+    running: Arc<AtomicBool>,
+
+    // disconnected once the current radio loop returned
+    loopdone: Arc<Mutex<Receiver<()>>>,
+
+    // fed by the radio loop whenever a frame goes in or out
+    heartbeat: Sender<()>,
+    heartbeatrx: Receiver<()>,
 
     // channels for receiving radio packets
-    rxsender: crossbeam_channel::Sender<Vec<u8>>,
-    rxreader: crossbeam_channel::Receiver<Vec<u8>>,
+    rxsender: crossbeam_channel::Sender<RxPacket>,
+    rxreader: crossbeam_channel::Receiver<RxPacket>,
+
+    // prioritized queue for transmitting radio packets
+    pub txsender: TxQueueSender,
+    txreader: TxQueueReceiver,
 
-    // channels for transmitting radio packets
-    pub txsender: crossbeam_channel::Sender<Vec<u8>>,
-    txreader: crossbeam_channel::Receiver<Vec<u8>>,
+    // commands run by the radio thread on behalf of other threads
+    cmdsender: Sender<RadioQuery>,
+    cmdreader: Receiver<RadioQuery>,
 }
 
 /// Reads the lines from the radio and sends them down the channel to
 /// the processing bits.
-fn serialloop(mut ser: SerialIO, rxsender: crossbeam_channel::Sender<String>) -> io::Result<()> {
+fn serialloop(mut ser: SerialIO, rxsender: Arc<Mutex<crossbeam_channel::Sender<String>>>) -> io::Result<()> {
     info!("Device serial IO started");
 
     loop {
         let line = ser.readln().expect("Error reading line");
         if let Some(l) = line {
-            rxsender.send(l).expect("Error sending message");
+            rxsender.lock().unwrap().send(l).expect("Error sending message");
         } else {
             debug!("{:?}: EOF", ser.portname);
             continue;
@@ -64,17 +319,238 @@ pub fn assert_response(resp: String, expected: String) -> io::Result<()> {
     }
 }
 
+/// Identity of a radio found on a serial port
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    /// full response to `sys get ver`
+    pub version: String,
+    /// true if the module is a Microchip RN2483/RN2903 as used by the LoStik
+    pub is_lostik: bool,
+}
+
+/// Parse the response of `sys get ver`, such as `RN2483 1.0.5 Oct 31 2018 15:06:52`
+pub fn parse_version(resp: &str) -> io::Result<DeviceInfo> {
+    let version = resp.trim();
+    if version.is_empty() || !version.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        return Err(mkerror(&format!("Unrecognized version response: {:?}", resp)));
+    }
+    Ok(DeviceInfo {
+        version: String::from(version),
+        is_lostik: version.starts_with("RN2483") || version.starts_with("RN2903"),
+    })
+}
+
+/// The firmware version number in a `sys get ver` response, such as (1, 0, 5)
+pub fn parse_firmware_version(version: &str) -> Option<(u8, u8, u8)> {
+    let mut numbers = version.split_whitespace().nth(1)?.split('.').map(|n| n.parse::<u8>());
+    match (numbers.next(), numbers.next(), numbers.next(), numbers.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => Some((major, minor, patch)),
+        _ => None,
+    }
+}
+
+/// Expected response that accepts anything but `invalid_param`
+pub const ANY_RESPONSE: &str = "*";
+
+/// Parse a line of an init file, `command|expected` or just `command`
+/// which expects `ok`. Blank lines and lines starting with `#` are skipped.
+pub fn parse_init_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let mut parts = line.splitn(2, '|');
+    let command = parts.next().unwrap_or("").trim();
+    let expected = parts.next().map_or("ok", |e| e.trim());
+    Some((String::from(command), String::from(expected)))
+}
+
+/// The power of a `radio set pwr <dbm>` command, None for any other command
+pub fn parse_power_command(command: &str) -> Option<i8> {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next(), words.next(), words.next()) {
+        (Some("radio"), Some("set"), Some("pwr"), Some(dbm), None) => dbm.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The packet in a `radio_rx  <hex>` line from the radio, None for any other line
+pub fn parse_rx_line(line: &str) -> Option<io::Result<Vec<u8>>> {
+    if !line.starts_with("radio_rx ") {
+        return None;
+    }
+    Some(match hex::decode(line["radio_rx ".len()..].trim()) {
+        Ok(data) if data.is_empty() => Err(mkerror("Received an empty packet")),
+        Ok(data) => Ok(data),
+        Err(_) => Err(mkerror("Error with hex decoding")),
+    })
+}
+
+/// Sync words reserved for LoRaWAN, 0x34 is the public network one
+pub const RESERVED_SYNC_WORDS: [u8; 2] = [0x00, 0x34];
+
+/// Sync word for a network ID, the lowest byte of its FNV-1a hash
+/* A reserved word is skipped for the next byte of the hash up. */
+pub fn network_id_to_sync_word(network_id: u32) -> u8 {
+    fnv1a(&network_id.to_be_bytes()).to_le_bytes().iter().cloned()
+        .find(|word| !RESERVED_SYNC_WORDS.contains(word))
+        .unwrap_or(0x12)
+}
+
+/// Parse the decimal response of `sys get temp`, such as `25.0`
+pub fn parse_temperature(resp: &str) -> io::Result<f32> {
+    resp.trim().parse::<f32>()
+        .map_err(|_| mkerror(&format!("Invalid temperature response: {}", resp)))
+}
+
+/// Parse the response of `radio get snr`, a signed integer from -128 to 127
+pub fn parse_snr(resp: &str) -> io::Result<i8> {
+    resp.trim().parse::<i8>()
+        .map_err(|_| mkerror(&format!("Invalid SNR response: {}", resp)))
+}
+
+/// Parse the response of `radio get rssi`, such as `-87`
+pub fn parse_rssi(resp: &str) -> io::Result<i16> {
+    resp.trim().parse::<i16>()
+        .map_err(|_| mkerror(&format!("Invalid RSSI response: {}", resp)))
+}
+
+/// Parse the response of `sys get vdd`, the supply voltage in millivolts
+pub fn parse_vdd(resp: &str) -> io::Result<u16> {
+    resp.trim().parse::<u16>()
+        .map_err(|_| mkerror(&format!("Invalid supply voltage response: {}", resp)))
+}
+
+/// Parse the response of `sys get hweui`, 16 hex digits such as `0004A30B001A2B3C`
+pub fn parse_hweui(resp: &str) -> io::Result<[u8; 8]> {
+    let hex = resp.trim();
+    if hex.len() != 16 || !hex.is_ascii() {
+        return Err(mkerror(&format!("Invalid hardware EUI response: {}", resp)));
+    }
+    let mut eui = [0u8; 8];
+    for (i, byte) in eui.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| mkerror(&format!("Invalid hardware EUI response: {}", resp)))?;
+    }
+    Ok(eui)
+}
+
+/// Parse the response of `radio get freq`, the frequency in Hz such as `868100000`
+pub fn parse_frequency(resp: &str) -> io::Result<u64> {
+    resp.trim().parse::<u64>()
+        .map_err(|_| mkerror(&format!("Invalid frequency response: {}", resp)))
+}
+
+/// Parse the responses to `radio get` for each of `RADIO_CONFIG_PARAMS`, in order
+pub fn parse_radio_config(responses: &[String]) -> io::Result<RadioConfig> {
+    if responses.len() != RADIO_CONFIG_PARAMS.len() {
+        return Err(mkerror(&format!("Expected {} radio parameters, got {}", RADIO_CONFIG_PARAMS.len(), responses.len())));
+    }
+    let value = |i: usize| responses[i].trim();
+    let invalid = |i: usize| mkerror(&format!("Invalid response to \"radio get {}\": {}", RADIO_CONFIG_PARAMS[i], value(i)));
+    let on_off = |i: usize| match value(i) {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(invalid(i)),
+    };
+    if value(0) != "lora" && value(0) != "fsk" {
+        return Err(invalid(0));
+    }
+    if !value(5).starts_with("4/") {
+        return Err(invalid(5));
+    }
+    Ok(RadioConfig {
+        mod_type: String::from(value(0)),
+        freq_hz: parse_frequency(value(1))?,
+        power_dbm: value(2).parse().map_err(|_| invalid(2))?,
+        sf: value(3).trim_start_matches("sf").parse().map_err(|_| invalid(3))?,
+        bw_khz: value(4).parse().map_err(|_| invalid(4))?,
+        cr: String::from(value(5)),
+        wdt_ms: value(6).parse().map_err(|_| invalid(6))?,
+        sync_word: u8::from_str_radix(value(7), 16).map_err(|_| invalid(7))?,
+        preamble_len: value(8).parse().map_err(|_| invalid(8))?,
+        crc_enabled: on_off(9)?,
+        iqi_enabled: on_off(10)?,
+    })
+}
+
+/// Largest crystal error accepted, 1 kHz at 868 MHz
+pub const MAX_CRYSTAL_ERROR_PPM: f32 = 1000.0 / 868.0;
+
+/// deviation of a measured frequency from the one it should be, in parts per million
+/// positive when the measured frequency is high
+pub fn ppm_error(measured_hz: u64, reference_hz: u64) -> f32 {
+    ((measured_hz as f64 - reference_hz as f64) / reference_hz as f64 * 1e6) as f32
+}
+
+/// frequency to set so a crystal off by `ppm`, as `ppm_error` gives it, lands on `freq_hz`
+pub fn corrected_frequency(freq_hz: u64, ppm: f32) -> u64 {
+    (freq_hz as f64 / (1.0 + ppm as f64 / 1e6)).round() as u64
+}
+
+/// A frame listen before talk held back for a busy channel
+/* The channel is looked at again after a backoff that grows with every
+look, not on every pass of the radio loop, and only a clear channel
+takes a rate limiter token. After `LBT_MAX_LOOKS` looks or
+`LBT_MAX_DEFER` the frame goes out anyway, a channel that never clears
+must not hold up the queue for good. */
+#[derive(Debug)]
+struct LbtDeferral {
+    since: Instant,
+    looks: u32,
+    next: Instant,
+}
+
+impl LbtDeferral {
+    /// the channel was found busy at `now`
+    fn new(now: Instant) -> Self {
+        LbtDeferral { since: now, looks: 1, next: now + LBT_BACKOFF }
+    }
+
+    /// true once it is time to look at the channel again
+    fn due(&self, now: Instant) -> bool {
+        now >= self.next
+    }
+
+    /// the channel was busy again, false once the frame waited long enough to go out anyway
+    fn busy(&mut self, now: Instant) -> bool {
+        if self.looks >= LBT_MAX_LOOKS || now.duration_since(self.since) >= LBT_MAX_DEFER {
+            warn!("LBT: channel busy {} times in {}ms, sending anyway", self.looks, now.duration_since(self.since).as_millis());
+            return false;
+        }
+        self.looks += 1;
+        self.next = now + LBT_BACKOFF * self.looks;
+        true
+    }
+
+    /// the channel may be used but the rate limiter holds the frame, look again after a backoff
+    fn postpone(&mut self, now: Instant) {
+        self.next = now + LBT_BACKOFF;
+    }
+}
+
 /// Loop for sending and receiving radio data
 /// Uses the Token Bucket algorithm to limit the transmission slot so
 /// we can ensure we have a healthy amount of time to receive
 pub fn radioloop(mut radio: LoStik) {
+    if let Err(e) = radio_passes(&mut radio) {
+        warn!("Radio loop exiting: {}", e);
+    }
+}
+
+/// The passes of `radioloop`, until it is told to stop or its serial channel is cut off
+fn radio_passes(radio: &mut LoStik) -> io::Result<()> {
     let duration = Duration::from_millis(radio.opt.txslot.clone());
     let mut limiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(3u32), duration);
 
     // flag if radio is transmitting or not
-    radio.rxstart();
+    serial_failure(radio.rxstart())?;
     let mut isrx = true;
     let mut extratx: Option<Vec<u8>> = None;
+    // set while `extratx` waits for a busy channel rather than the rate limiter
+    let mut deferral: Option<LbtDeferral> = None;
+    // transmissions and receiver restarts failed in a row
+    let mut failures = 0;
 
     info!("LoStik radio started");
 
@@ -83,6 +559,27 @@ pub fn radioloop(mut radio: LoStik) {
     // strategy is to always transmit within allowed rate limit
     // otherwise we ensure the radio is in receiving mode
     loop {
+        // the watchdog replaced this loop with a new one
+        if !radio.running.load(Ordering::SeqCst) {
+            warn!("Abandoned radio loop exiting");
+            return Ok(());
+        }
+        // stopped for an emergency, `emergency_reset` starts a new loop
+        if radio.txsender.is_halted() {
+            warn!("Radio loop exiting for an emergency stop");
+            return Ok(());
+        }
+
+        // commands from other threads, the receiver is stopped while they run
+        if let Ok((cmd, reply)) = radio.cmdreader.try_recv() {
+            if isrx {
+                serial_failure(radio.rxstop())?;
+            }
+            reply.send(radio.command(&cmd)).ok();
+            serial_failure(radio.rxstart())?;
+            isrx = true;
+        }
+
         // no extra data from last loop, let's pull from queue
         if extratx.is_none() {
             let next = radio.txreader.try_recv();
@@ -90,7 +587,7 @@ pub fn radioloop(mut radio: LoStik) {
             // nothing to transmit, put in receiving mode
             if next.is_err() {
                 if !isrx {
-                    radio.rxstart();
+                    serial_failure(radio.rxstart())?;
                     isrx = true;
                 }
             }
@@ -98,61 +595,106 @@ pub fn radioloop(mut radio: LoStik) {
             if limiter.check().is_ok() && next.is_ok() {
                 debug!("Something to transmit");
                 if isrx {
-                    radio.rxstop(); // we're okay to transmit, stop receiver
+                    serial_failure(radio.rxstop())?; // we're okay to transmit, stop receiver
                     isrx = false;
                 }
-                let send = next.clone();
-                radio.tx(&send.unwrap()); // grab the next frame and transmit
+                let send = next.clone().unwrap(); // grab the next frame and transmit
+                if radio.channel_busy() {
+                    extratx = Some(send);
+                    deferral = Some(LbtDeferral::new(Instant::now()));
+                } else {
+                    let sent = radio.tx(&send);
+                    radio.track_failure(sent, &mut failures)?;
+                }
 
                 // keep transmitting until rate limited
-                while limiter.check().is_ok() {
+                while extratx.is_none() && limiter.check().is_ok() {
                     let next = radio.txreader.try_recv();
-                    if next.is_ok() {
-                        let send = next.clone();
-                        radio.tx(&send.unwrap());
+                    if let Ok(send) = next {
+                        if radio.channel_busy() {
+                            extratx = Some(send);
+                            deferral = Some(LbtDeferral::new(Instant::now()));
+                            break;
+                        }
+                        let sent = radio.tx(&send);
+                        radio.track_failure(sent, &mut failures)?;
                     }
                 }
 
-                radio.rxstart(); // TODO not sure why but something blocks thread, so start right away
+                serial_failure(radio.rxstart())?; // TODO not sure why but something blocks thread, so start right away
                 isrx = true;
             }
             // we've been rate limited, save to next loop
-            if limiter.check().is_err() && next.is_ok() {
+            if extratx.is_none() && limiter.check().is_err() && next.is_ok() {
                 debug!("Rate limiting transmission");
                 if next.is_ok() { // we were rate limited, save the extra frame
                     extratx = Some(next.unwrap());
                 }
                 if !isrx {
-                    radio.rxstart(); // we're okay to receive again
+                    serial_failure(radio.rxstart())?; // we're okay to receive again
                     isrx = true;
                 }
             }
             // rate limited but nothing to send, start receiver
             else {
                 if !isrx {
-                    radio.rxstart(); // we're okay to receive again
+                    serial_failure(radio.rxstart())?; // we're okay to receive again
+                    isrx = true;
+                }
+            }
+        }
+        // we have extra data to transmit, held back for a busy channel
+        else if let Some(lbt) = deferral.as_mut() {
+            let now = Instant::now();
+            if lbt.due(now) {
+                if isrx {
+                    serial_failure(radio.rxstop())?;
+                    isrx = false;
+                }
+                // only a clear channel takes a token
+                let busy = radio.channel_busy() && lbt.busy(now);
+                if !busy && limiter.check().is_ok() {
+                    debug!("Transmitting deferred packet");
+                    let sent = radio.tx(&extratx.take().unwrap());
+                    radio.track_failure(sent, &mut failures)?;
+                    deferral = None;
+                } else {
+                    if !busy {
+                        lbt.postpone(now);
+                    }
+                    // listen while the frame waits for the next look
+                    serial_failure(radio.rxstart())?;
                     isrx = true;
                 }
             }
         }
-        // we have extra data to transmit, check rate limiter
+        // or by the rate limiter
         else {
             if limiter.check().is_ok() {
                 debug!("Transmitting rate limited packet");
                 if isrx {
-                    radio.rxstop(); // we're okay to transmit, stop receiver
+                    serial_failure(radio.rxstop())?; // we're okay to transmit, stop receiver
                     isrx = false;
                 }
-                radio.tx(&extratx.unwrap());
-                extratx = None;
+                // busy, listen while the frame waits for the next look
+                if radio.channel_busy() {
+                    deferral = Some(LbtDeferral::new(Instant::now()));
+                    serial_failure(radio.rxstart())?;
+                    isrx = true;
+                } else {
+                    let sent = radio.tx(&extratx.unwrap());
+                    radio.track_failure(sent, &mut failures)?;
+                    extratx = None;
+                }
             }
         }
         // check serial buffer for incoming radio packets
         if isrx {
             match radio.readerlinesrx.try_recv() {
                 Ok(msg) => {
-                    radio.onrx(msg);
-                    radio.rxstart();
+                    serial_failure(radio.onrx(msg, true))?;
+                    let started = radio.rxstart();
+                    radio.track_failure(started, &mut failures)?;
                 },
                 _ => continue
             }
@@ -160,36 +702,178 @@ pub fn radioloop(mut radio: LoStik) {
     }
 }
 
+impl Radio for LoStik {
+    fn run(&self) -> (Receiver<RxPacket>, TxQueueSender) {
+        LoStik::run(self)
+    }
+
+    fn txsender(&self) -> &TxQueueSender {
+        &self.txsender
+    }
+
+    fn airtime(&self, len: usize) -> Duration {
+        LoStik::airtime(self, len)
+    }
+
+    fn query(&self, cmd: &str) -> io::Result<String> {
+        LoStik::query(self, cmd)
+    }
+
+    fn sleep(&self, duration: Duration) -> io::Result<()> {
+        LoStik::sleep(self, duration)
+    }
+
+    fn restart(&mut self) {
+        LoStik::restart(self)
+    }
+
+    fn emergency_stop(&mut self) -> io::Result<()> {
+        LoStik::emergency_stop(self)
+    }
+
+    fn emergency_reset(&mut self) -> io::Result<()> {
+        LoStik::emergency_reset(self)
+    }
+}
+
 impl LoStik {
     pub fn new(opt: Settings) -> LoStik {
+        LoStik::open(opt).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Connect to the radio on the configured port, failing if it cannot be opened
+    pub fn open(opt: Settings) -> io::Result<LoStik> {
+        // an empty radioport means use the first LoStik plugged in
+        let port = resolve_radioport(&opt.radioport)?;
+        let ser = SerialIO::new(port.clone())
+            .map_err(|e| mkerror(&format!("Failed to initialize serial port {}: {}", port.display(), e)))?;
+        Ok(LoStik::with_serial(opt, ser))
+    }
+
+    /// A radio on a serial port that is already open
+    pub(crate) fn with_serial(opt: Settings, ser: SerialIO) -> LoStik {
         // set up channels for serial command IO
         let (readerlinestx, readerlinesrx) = crossbeam_channel::unbounded();
+        let readerlinestx = Arc::new(Mutex::new(readerlinestx));
+        // one pending heartbeat is enough to prove the loop is alive
+        let (heartbeat, heartbeatrx) = crossbeam_channel::bounded(1);
         // set up channels for radio packet IO
         let (rxsender, rxreader) = crossbeam_channel::unbounded();
-        let (txsender, txreader) = crossbeam_channel::unbounded();
+        // the settings were validated, the key parses
+        let framekey = opt.framekey.as_ref().and_then(|key| parse_frame_key(key).ok());
+        // frames wait a whole sleep for the next window
+        let capacity = if opt.dutycycle { opt.txqueuesize.max(opt.dutycyclequeue) } else { opt.txqueuesize };
+        let (txsender, txreader) = tx_queue_fair(capacity, opt.txdestdepth);
+        let mut txsender = txsender.with_frame_key(framekey);
+        if opt.encryption {
+            // the configured spreading factor is the highest, with the smallest packets
+            let maxframe = match opt.modulation {
+                TxEncoding::Lora => max_payload_for(opt.radiosf, opt.radiobw, opt.radiocr).min(MAX_FRAME_LEN),
+                _ => MAX_FRAME_LEN,
+            };
+            txsender = txsender.with_encryption(if opt.padding { opt.padbucket } else { 1 }, maxframe);
+        }
+        let (cmdsender, cmdreader) = crossbeam_channel::unbounded();
+        // no radio loop runs yet
+        let (_, loopdone) = crossbeam_channel::bounded(0);
 
-        let ser = SerialIO::new(opt.radioport.clone()).expect("Failed to initialize serial port");
         let ser2 = ser.clone();
-        thread::spawn(move || serialloop(ser2, readerlinestx).expect("Serial IO crashed"));
+        let serialtx = readerlinestx.clone();
+        thread::spawn(move || serialloop(ser2, serialtx).expect("Serial IO crashed"));
 
-        return LoStik {
+        LoStik {
             opt,
             ser,
             readerlinesrx,
+            readerlinestx,
+            running: Arc::new(AtomicBool::new(true)),
+            loopdone: Arc::new(Mutex::new(loopdone)),
+            heartbeat,
+            heartbeatrx,
             rxsender,
             rxreader,
             txsender,
-            txreader
-        };
+            txreader,
+            cmdsender,
+            cmdreader
+        }
     }
 
-    pub fn run(&self) -> (Receiver<Vec<u8>>, Sender<Vec<u8>>) {
-        let ls2 = self.clone();
-        thread::spawn(move || radioloop(ls2));
+    /// Check for a radio on a port without initializing it
+    /* Opens the port, asks for the firmware version and closes it again. */
+    pub fn probe_device(port: &str) -> io::Result<DeviceInfo> {
+        let mut ser = SerialIO::with_timeout(PathBuf::from(port), Duration::from_millis(500))?;
+        ser.writeln(String::from("sys get ver"))?;
+        match ser.readln()? {
+            None => Err(mkerror(&format!("No response from {}", port))),
+            Some(line) => parse_version(&line),
+        }
+    }
+
+    pub fn run(&self) -> (Receiver<RxPacket>, TxQueueSender) {
+        self.spawn_loop();
+
+        if self.opt.watchdogtimeout > 0 {
+            let mut radio = self.clone();
+            let mut watchdog = WatchdogTimer::new(Duration::from_millis(self.opt.watchdogtimeout), self.heartbeatrx.clone());
+            thread::spawn(move || watchdog.watch(|| radio.restart()));
+        }
 
         return (self.rxreader.clone(), self.txsender.clone());
     }
 
+    /// Replace a stalled radio loop with a freshly initialized one
+    /* Threads cannot be killed, so the old loop is told to exit and its
+    serial channel is disconnected. If it is stuck waiting on the radio
+    that wakes it up with an error, and it returns. */
+    pub fn restart(&mut self) {
+        // a silenced radio sends no heartbeats, the watchdog must not wake it
+        if self.txsender.is_halted() {
+            warn!("Radio is stopped for an emergency, not restarting it");
+            return;
+        }
+        self.running.store(false, Ordering::SeqCst);
+        self.running = Arc::new(AtomicBool::new(true));
+
+        let (readerlinestx, readerlinesrx) = crossbeam_channel::unbounded();
+        *self.readerlinestx.lock().unwrap() = readerlinestx;
+        self.readerlinesrx = readerlinesrx;
+
+        if let Err(e) = self.init(self.opt.radiocfg.clone()) {
+            error!("Could not reinitialize radio after restart: {}", e);
+            self.log_config_dump();
+        }
+        self.spawn_loop();
+    }
+
+    /// Start a radio loop on a clone of this radio, the one `stop_loop` waits for
+    fn spawn_loop(&self) {
+        let (done, loopdone) = crossbeam_channel::bounded::<()>(0);
+        *self.loopdone.lock().unwrap() = loopdone;
+        let ls2 = self.clone();
+        thread::spawn(move || {
+            radioloop(ls2);
+            drop(done);
+        });
+    }
+
+    /// Wait for the radio loop to return once it was told to, so serial is ours
+    /* A loop waiting on the radio only sees it was told to stop after
+    the answer, its serial channel is cut off if that takes too long. */
+    fn stop_loop(&mut self) -> io::Result<()> {
+        let loopdone = self.loopdone.lock().unwrap().clone();
+        let waited = loopdone.recv_timeout(SELF_TEST_TIMEOUT);
+
+        let (readerlinestx, readerlinesrx) = crossbeam_channel::unbounded();
+        *self.readerlinestx.lock().unwrap() = readerlinestx;
+        self.readerlinesrx = readerlinesrx;
+
+        if waited == Err(RecvTimeoutError::Timeout) && loopdone.recv_timeout(SELF_TEST_TIMEOUT) == Err(RecvTimeoutError::Timeout) {
+            return Err(mkerror("Radio loop did not stop"));
+        }
+        Ok(())
+    }
+
     /// apply radio settings using init file
     pub fn init(&mut self, initfile: Option<PathBuf>) -> io::Result<()> {
         // First, send it an invalid command.  Then, consume everything it sends back
@@ -204,17 +888,16 @@ impl LoStik {
 
         debug!("Configuring radio");
         let default = vec![
-            "sys get ver",
-            "mac reset",
-            "mac pause",
-            "radio get mod",
-            "radio get freq",
-            "radio get pwr",
-            "radio get sf",
-            "radio get bw",
-            "radio get cr",
-            "radio get wdt",
-            "radio set pwr 22",/// 22dbm + 7.9999999...Gain = 30dbm
+            "sys get ver|*",
+            "mac reset|*",
+            "mac pause|*",
+            "radio get mod|*",
+            "radio get freq|*",
+            "radio get pwr|*",
+            "radio get sf|*",
+            "radio get bw|*",
+            "radio get cr|*",
+            "radio get wdt|*",
             "radio set sf sf12",
             "radio set bw 125",
             "radio set cr 4/5",
@@ -223,65 +906,607 @@ impl LoStik {
         let initlines: Vec<String> = if let Some(file) = initfile {
             let f = fs::File::open(file)?;
             let reader = BufReader::new(f);
-            reader.lines().map(|l| l.unwrap()).collect()
+            reader.lines().collect::<io::Result<Vec<String>>>()?
         } else {
             default.iter().map(|l| String::from(*l)).collect()
         };
 
-        for line in initlines {
-            if line.len() > 0 {
-                self.ser.writeln(line)?;
-                self.oninit()?;
-            }
+        self.batch_init(initlines.iter().filter_map(|l| parse_init_line(l)).collect())?;
+        // takes the place of a sync word from the init file
+        if let Some(network_id) = self.get_network_id() {
+            self.set_sync_word_from_network_id(network_id)?;
+        }
+        if self.opt.lbtenabled {
+            self.set_listen_before_talk(true, self.opt.lbtthresholddbm)?;
+        }
+        // the radio is in LoRa mode after the mac reset
+        if self.opt.modulation != TxEncoding::Lora {
+            self.set_tx_encoding(self.opt.modulation)?;
+        }
+
+        // a nearly dead battery browns the module out mid transmission
+        let vdd = self.get_vdd()?;
+        if vdd < self.opt.vddminthreshold {
+            return Err(mkerror(&format!("Radio supply is {:.2}V, below the minimum of {:.2}V", vdd, self.opt.vddminthreshold)));
         }
         debug!("Radio initialized");
         Ok(())
     }
 
-    fn oninit(&mut self) -> io::Result<()> {
-        let line = self.readerlinesrx.recv().unwrap();
-        if line == "invalid_param" {
-            Err(mkerror("Bad response from radio during initialization"))
-        } else {
-            Ok(())
+    /// Send each command and check the radio answers with the expected response
+    /* An expected response of `*` accepts anything but `invalid_param`,
+    for commands such as `radio get` whose answer depends on the radio.
+    Transmit powers go through `set_output_power`, so no init file gets
+    past the region's limit. */
+    pub fn batch_init(&mut self, commands: Vec<(String, String)>) -> io::Result<()> {
+        for (command, expected) in commands {
+            if let Some(dbm) = parse_power_command(&command) {
+                self.set_output_power(dbm)
+                    .map_err(|e| mkerror(&format!("Bad response from radio to \"{}\": {}", command, e)))?;
+                continue;
+            }
+            let resp = self.command(&command)?;
+            let result = if expected == ANY_RESPONSE {
+                if resp == "invalid_param" { Err(mkerror("invalid_param")) } else { Ok(()) }
+            } else {
+                assert_response(resp, expected)
+            };
+            result.map_err(|e| mkerror(&format!("Bad response from radio to \"{}\": {}", command, e)))?;
         }
+        Ok(())
+    }
+
+    /// handle a line from the radio while receiving, `readquality` asks the
+    /// radio for the link quality of the packet before passing it on
+    fn onrx(&mut self, msg: String, readquality: bool) -> io::Result<()> {
+        if let Some(packet) = self.rx_packet(&msg, readquality)? {
+            self.rxsender.send(packet).unwrap();
+        }
+        // Might get radio_err here.  That's harmless.
+        Ok(())
+    }
+
+    /// the packet in a line from the radio, None for any other line
+    fn rx_packet(&mut self, msg: &str, readquality: bool) -> io::Result<Option<RxPacket>> {
+        let decoded = match parse_rx_line(msg) {
+            None => return Ok(None),
+            Some(decoded) => decoded?,
+        };
+        trace!("DECODED: {}", format_escape_default(&decoded));
+        let quality = if readquality { self.link_quality() } else { LinkQuality::default() };
+        self.heartbeat.try_send(()).ok();
+        Ok(Some(RxPacket { data: decoded, quality }))
+    }
+
+    /// Wait at most `timeout` for a packet, None if none came
+    /* For reading packets without `run`, while nothing else uses the
+    radio. The receiver must be started with `rxstart`, and started again
+    after every packet or `radio_err`, which also gives None. */
+    pub fn read_rx_packet_timeout(&mut self, timeout: Duration) -> io::Result<Option<RxPacket>> {
+        match self.readerlinesrx.recv_timeout(timeout) {
+            Ok(line) => self.rx_packet(&line, true),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(serial_closed()),
+        }
+    }
+
+    /// send a single command and return the response line
+    fn command(&mut self, cmd: &str) -> io::Result<String> {
+        self.ser.writeln(String::from(cmd))?;
+        self.recv_line()
+    }
+
+    /// the next line from the radio, an error once the serial channel was cut off
+    fn recv_line(&mut self) -> io::Result<String> {
+        self.readerlinesrx.recv().map_err(|_| serial_closed())
+    }
+
+    /// Run a command on the radio thread and wait for the response
+    /* Once `run` is called the radio thread owns the serial port, other
+    threads go through here instead of `command`. */
+    pub fn query(&self, cmd: &str) -> io::Result<String> {
+        let (reply, response) = crossbeam_channel::bounded(1);
+        self.cmdsender.send((String::from(cmd), reply))
+            .map_err(|_| mkerror("Radio thread is not running"))?;
+        response.recv_timeout(QUERY_TIMEOUT)
+            .map_err(|_| mkerror(&format!("No response from radio to \"{}\"", cmd)))?
+    }
+
+    /// send a single command and wait at most `timeout` for the response line
+    fn command_timeout(&mut self, cmd: &str, timeout: Duration) -> io::Result<String> {
+        self.ser.writeln(String::from(cmd))?;
+        match self.readerlinesrx.recv_timeout(timeout) {
+            Ok(resp) => Ok(resp),
+            Err(RecvTimeoutError::Timeout) => Err(Error::new(ErrorKind::TimedOut, format!(
+                "No response from the radio on {} to \"{}\" within {}s, check the port and the USB connection",
+                self.opt.radioport.display(), cmd, timeout.as_secs()))),
+            Err(RecvTimeoutError::Disconnected) => Err(serial_closed()),
+        }
+    }
+
+    /// Check the radio answers over serial with a valid version string
+    /* For a pre-flight check, run before `run` while nothing else uses the
+    radio. */
+    pub fn serial_loopback_test(&mut self) -> io::Result<()> {
+        self.read_version().map(|_| ())
+    }
+
+    fn read_version(&mut self) -> io::Result<DeviceInfo> {
+        // lines left over from before would be taken for the answer
+        while self.readerlinesrx.try_recv().is_ok() {}
+        let resp = self.command_timeout("sys get ver", SELF_TEST_TIMEOUT)?;
+        parse_version(&resp)
+            .map_err(|e| mkerror(&format!("The radio on {} is not answering like a LoStik, is the baud rate right? {}", self.opt.radioport.display(), e)))
     }
 
-    fn onrx(&mut self, msg: String) -> io::Result<()> {
-        if msg.starts_with("radio_rx ") {
-            if let Ok(decoded) = hex::decode(&msg.as_bytes()[10..]) {
-                trace!("DECODED: {}", format_escape_default(&decoded));
-                self.rxsender.send(decoded).unwrap();
+    /// Check the radio is ready for a deployment
+    /* Runs the serial check, makes sure the module and firmware are known
+    and the supply voltage is above the minimum. Given the port of a second
+    radio in range, both are initialized and a few packets are sent from
+    this one to the other. Stops at the first check that fails. */
+    pub fn self_test(&mut self, peerport: Option<&Path>) -> io::Result<SelfTestReport> {
+        let device = self.read_version()?;
+        if !device.is_lostik {
+            return Err(mkerror(&format!("{} is not a Microchip RN2483 or RN2903 module", device.version)));
+        }
+        let firmware = parse_firmware_version(&device.version)
+            .ok_or_else(|| mkerror(&format!("Unrecognized firmware version in {:?}", device.version)))?;
+
+        let vdd = parse_vdd(&self.command_timeout("sys get vdd", SELF_TEST_TIMEOUT)?)? as f32 / 1000.0;
+        if vdd < self.opt.vddminthreshold {
+            return Err(mkerror(&format!("Radio supply is {:.2}V, below the minimum of {:.2}V", vdd, self.opt.vddminthreshold)));
+        }
+
+        let loopback = match peerport {
+            None => None,
+            Some(port) => Some(self.rf_loopback(port)?),
+        };
+        Ok(SelfTestReport { device, firmware, vdd, loopback })
+    }
+
+    /// send test packets to the radio on `peerport`, returning how many were sent and received
+    fn rf_loopback(&mut self, peerport: &Path) -> io::Result<(u32, u32)> {
+        let mut opt = self.opt.clone();
+        opt.radioport = PathBuf::from(peerport);
+        let mut peer = LoStik::open(opt)?;
+        peer.init(self.opt.radiocfg.clone())
+            .map_err(|e| mkerror(&format!("Could not set up the radio on {}: {}", peerport.display(), e)))?;
+        self.init(self.opt.radiocfg.clone())?;
+
+        // listen until all packets could have gone out, with some time to spare
+        let listen = self.airtime(LOOPBACK_PACKET_LEN) * (LOOPBACK_PACKETS as u32 + 1) * 2 + SELF_TEST_TIMEOUT;
+        let receiver = thread::spawn(move || peer.continuous_rx_test(listen));
+        let mut sent = 0;
+        for seq in 0..LOOPBACK_PACKETS {
+            self.tx(&test_packet(seq, LOOPBACK_PACKET_LEN))?;
+            sent += 1;
+        }
+        let report = receiver.join().map_err(|_| mkerror("Receiving radio thread crashed"))??;
+        if report.packets == 0 {
+            return Err(mkerror(&format!("None of {} test packets reached the radio on {}, check the antennas and radio settings",
+                sent, peerport.display())));
+        }
+        Ok((sent, report.packets))
+    }
+
+    /// read the signal quality of the last received packet
+    fn link_quality(&mut self) -> LinkQuality {
+        let snr = self.command("radio get snr").and_then(|r| parse_snr(&r));
+        let rssi = self.command("radio get rssi").and_then(|r| parse_rssi(&r));
+        LinkQuality { rssi: rssi.ok(), snr: snr.ok() }
+    }
+
+    /// Listen for `duration`, counting the packets heard and their signal quality
+    /* For measuring receive sensitivity, run before `run` while nothing
+    else uses the radio. The receiver is restarted after every packet or
+    error, the packets themselves are discarded. */
+    pub fn continuous_rx_test(&mut self, duration: Duration) -> io::Result<RxTestReport> {
+        let started = Instant::now();
+        let mut packets = 0;
+        let mut errors = 0;
+        let mut rssi = Vec::new();
+        let mut snr = Vec::new();
+
+        self.rxstart()?;
+        while started.elapsed() < duration {
+            let line = match self.readerlinesrx.recv_timeout(duration - started.elapsed().min(duration)) {
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Err(serial_closed()),
+                Ok(line) => line,
+            };
+            if line.starts_with("radio_rx ") {
+                packets += 1;
+                let quality = self.link_quality();
+                if let Some(r) = quality.rssi {
+                    rssi.push(r as f32);
+                }
+                if let Some(s) = quality.snr {
+                    snr.push(s as f32);
+                }
+            } else if line == "radio_err" {
+                errors += 1;
             } else {
-                return Err(mkerror("Error with hex decoding"));
+                debug!("Unexpected line during receive test: {}", line);
+                continue;
             }
+            self.rxstart()?;
         }
-        // Might get radio_err here.  That's harmless.
+        self.rxstop()?;
+
+        Ok(RxTestReport {
+            duration: started.elapsed(),
+            packets,
+            errors,
+            rssi: SignalStats::from_samples(&rssi),
+            snr: SignalStats::from_samples(&snr),
+        })
+    }
+
+    /// Send `n` test packets of `payload_size` bytes back to back, as fast as the transmission slot allows
+    /* For qualifying hardware, run before `run` while nothing else uses the
+    radio. The sequence number in the first two bytes wraps after 65535. */
+    pub fn tx_test_packet(&mut self, n: u32, payload_size: usize) -> io::Result<TxTestReport> {
+        if payload_size < 2 || payload_size > MAX_FRAME_LEN {
+            return Err(mkerror(&format!("Test packets must be 2 to {} bytes, not {}", MAX_FRAME_LEN, payload_size)));
+        }
+        // the same limit the radio loop transmits under
+        let mut limiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(3u32), Duration::from_millis(self.opt.txslot));
+        let mut sent = 0;
+        let mut errors = 0;
+        let mut txtime = Duration::from_secs(0);
+
+        for seq in 0..n {
+            while limiter.check().is_err() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            let packet = test_packet(seq as u16, payload_size);
+            let started = Instant::now();
+            match self.tx(&packet) {
+                Err(e) => {
+                    debug!("Test packet {} failed: {}", seq, e);
+                    errors += 1;
+                },
+                Ok(()) => {
+                    txtime += started.elapsed();
+                    sent += 1;
+                },
+            }
+        }
+
+        let airtime = self.airtime(payload_size).as_millis() as u64;
+        Ok(TxTestReport {
+            sent,
+            errors,
+            total_airtime_ms: airtime * sent as u64,
+            average_tx_ms: if sent > 0 { txtime.as_millis() as u64 / sent as u64 } else { 0 },
+        })
+    }
+
+    /// Send a test frame at every power from `start_dbm` to `end_dbm`, `step` dB apart, with how each went
+    /* For finding where an amplifier saturates, run before `run` while
+    nothing else uses the radio. A level fails when the radio refuses the
+    power or reports radio_err for the frame, the ramp goes on either way
+    and the power is put back afterwards. Powers over the region's limit
+    are clamped to it. */
+    pub fn tx_power_ramp_test(&mut self, start_dbm: i8, end_dbm: i8, step: u8) -> io::Result<Vec<(i8, io::Result<()>)>> {
+        if step == 0 {
+            return Err(mkerror("Power ramp step must be at least 1 dB"));
+        }
+        let resp = self.command("radio get pwr")?;
+        let power: i8 = resp.trim().parse()
+            .map_err(|_| mkerror(&format!("Bad transmit power response from radio: {:?}", resp)))?;
+
+        let mut results = Vec::new();
+        for (seq, dbm) in power_levels(start_dbm, end_dbm, step).into_iter().enumerate() {
+            let packet = test_packet(seq as u16, RAMP_PACKET_LEN);
+            let result = self.set_output_power(dbm).and_then(|_| self.tx_confirmed(&packet));
+            if let Err(e) = &result {
+                debug!("Test frame at {} dBm failed: {}", dbm, e);
+            }
+            results.push((dbm, result));
+        }
+        self.set_output_power(power)?;
+        Ok(results)
+    }
+
+    /// set the transmit power, limited to the maximum of the configured region
+    pub fn set_output_power(&mut self, dbm: i8) -> io::Result<()> {
+        let power = self.opt.region.clamp_power(dbm);
+        if power != dbm {
+            warn!("Transmit power {} dBm exceeds the {:?} limit, using {} dBm", dbm, self.opt.region, power);
+        }
+        let resp = self.command(&format!("radio set pwr {}", power))?;
+        assert_response(resp, String::from("ok"))
+    }
+
+    /// change the spreading factor, for adaptive data rate
+    /* Airtime estimates from `airtime` follow the new setting. */
+    pub fn set_spreading_factor(&mut self, sf: u8) -> io::Result<()> {
+        if sf < 7 || sf > 12 {
+            return Err(mkerror(&format!("Spreading factor {} is not between 7 and 12", sf)));
+        }
+        let resp = self.command(&format!("radio set sf sf{}", sf))?;
+        assert_response(resp, String::from("ok"))?;
+        self.opt.radiosf = sf;
+        Ok(())
+    }
+
+    /// switch between LoRa and FSK modulation
+    /* Airtime estimates from `airtime` follow the new setting. */
+    pub fn set_tx_encoding(&mut self, encoding: TxEncoding) -> io::Result<()> {
+        encoding.validate()?;
+        for command in encoding.commands() {
+            let resp = self.command(&command)?;
+            assert_response(resp, String::from("ok"))
+                .map_err(|e| mkerror(&format!("Bad response from radio to \"{}\": {}", command, e)))?;
+        }
+        self.opt.modulation = encoding;
+        Ok(())
+    }
+
+    /// time on air of a frame with the current modulation, spreading factor and bandwidth
+    pub fn airtime(&self, len: usize) -> Duration {
+        self.opt.modulation.fsk_airtime(len)
+            .unwrap_or_else(|| airtime(len, self.opt.radiosf, self.opt.radiobw))
+    }
+
+    /// Compare the radio's carrier to the frequency it is set to, returning the error in ppm
+    /* `measured_hz` is the frequency a reference receiver measured our
+    carrier at. The error is positive when the crystal runs fast, and is
+    what `apply_ppm_correction` takes. */
+    pub fn measure_crystal_error(&mut self, measured_hz: u64) -> io::Result<f32> {
+        if measured_hz == 0 {
+            return Err(mkerror("Measured frequency must not be 0"));
+        }
+        let resp = self.command("radio get freq")?;
+        let freq = parse_frequency(&resp)?;
+        let ppm = ppm_error(measured_hz, freq);
+        if ppm.abs() > MAX_CRYSTAL_ERROR_PPM {
+            warn!("Radio carrier measured at {} Hz is {:.2} ppm off its frequency {} Hz", measured_hz, ppm, freq);
+        }
+        Ok(ppm)
+    }
+
+    /// retune the radio to make up for a crystal error from `measure_crystal_error`
+    pub fn apply_ppm_correction(&mut self, ppm: f32) -> io::Result<()> {
+        let resp = self.command("radio get freq")?;
+        let freq = corrected_frequency(parse_frequency(&resp)?, ppm);
+        let resp = self.command(&format!("radio set freq {}", freq))?;
+        assert_response(resp, String::from("ok"))
+    }
+
+    /// Put the radio to sleep, it wakes up by itself after `duration`
+    /* Runs on the radio thread like `query`, without waiting for the
+    answer, which only comes once the radio is awake again. Frames queued
+    in the meantime go out after that. */
+    pub fn sleep(&self, duration: Duration) -> io::Result<()> {
+        // a sleeping radio sends and receives nothing, that is not a stall
+        self.heartbeat.try_send(()).ok();
+        let (reply, _) = crossbeam_channel::bounded(1);
+        let cmd = format!("sys sleep {}", duration.as_millis().max(100));
+        self.cmdsender.send((cmd, reply)).map_err(|_| mkerror("Radio thread is not running"))
+    }
+
+    /// turn listen before talk on or off, frames wait while the RSSI is above `threshold_dbm`
+    /* Checks the radio can read the RSSI before turning it on. */
+    pub fn set_listen_before_talk(&mut self, enabled: bool, threshold_dbm: i16) -> io::Result<()> {
+        if enabled {
+            let resp = self.command("radio get rssi")?;
+            parse_rssi(&resp)?;
+        }
+        self.opt.lbtenabled = enabled;
+        self.opt.lbtthresholddbm = threshold_dbm;
+        Ok(())
+    }
+
+    /// true if listen before talk is on and the channel is in use
+    /* A radio that cannot tell is trusted to be clear, a failed reading
+    must not hold back every frame. The RN2483 has no reading of the
+    channel as it is: with the receiver stopped `radio get rssi` gives the
+    RSSI of the last packet received. So the channel counts as busy after a
+    strong packet until a weaker one arrives, and a transmission that
+    started since goes unnoticed. `LbtDeferral` keeps a stale reading
+    from holding frames back for long. */
+    fn channel_busy(&mut self) -> bool {
+        if !self.opt.lbtenabled {
+            return false;
+        }
+        match self.command("radio get rssi").and_then(|resp| parse_rssi(&resp)) {
+            Ok(rssi) if rssi > self.opt.lbtthresholddbm => {
+                debug!("LBT: channel busy, deferring tx, rssi={}", rssi);
+                true
+            },
+            Ok(_) => false,
+            Err(e) => {
+                warn!("LBT: could not read the RSSI, sending anyway: {}", e);
+                false
+            },
+        }
+    }
+
+    /// change the sync word, radios only hear frames sent with the same one
+    pub fn set_sync_word(&mut self, word: u8) -> io::Result<()> {
+        let resp = self.command(&format!("radio set sync {:02x}", word))?;
+        assert_response(resp, String::from("ok"))
+    }
+
+    /// set the sync word derived from a network ID, so networks sharing a channel don't hear each other
+    pub fn set_sync_word_from_network_id(&mut self, network_id: u32) -> io::Result<()> {
+        let word = network_id_to_sync_word(network_id);
+        debug!("Network {} uses sync word {:02x}", network_id, word);
+        self.set_sync_word(word)
+    }
+
+    /// the network ID from the settings, if one was given
+    pub fn get_network_id(&self) -> Option<u32> {
+        self.opt.networkid
+    }
+
+    /// read the EUI burned into the module, unique to every radio
+    pub fn get_device_eui(&mut self) -> io::Result<[u8; 8]> {
+        let resp = self.command("sys get hweui")?;
+        parse_hweui(&resp)
+    }
+
+    /// read the internal temperature sensor in Celsius
+    pub fn get_temperature(&mut self) -> io::Result<f32> {
+        let resp = self.command("sys get temp")?;
+        parse_temperature(&resp)
+    }
+
+    /// read the module supply voltage in volts
+    pub fn get_vdd(&mut self) -> io::Result<f32> {
+        let resp = self.command("sys get vdd")?;
+        Ok(parse_vdd(&resp)? as f32 / 1000.0)
+    }
+
+    /// Send a LoRaWAN MAC command, such as `get deveui`, and return the response line
+    /* Like `command` it reads the answer straight off the serial port, so
+    only call it before `run` or from the radio thread. While `radioloop`
+    is running, its lines and ours get mixed up; use `query` there. */
+    pub fn send_mac_command(&mut self, cmd: &str) -> io::Result<String> {
+        let command = format!("mac {}", cmd);
+        let resp = self.command(&command)?;
+        if resp == "invalid_param" {
+            return Err(mkerror(&format!("Bad response from radio to \"{}\": invalid_param", command)));
+        }
+        Ok(resp)
+    }
+
+    /// Restore every setting of the module to its factory default, then initialize it again
+    /* This erases the LoRaWAN keys stored in the module and clears its
+    join state, whatever was provisioned has to be again. Nothing is done
+    unless `confirmed`. Only call it before `run`, as it reads the serial
+    port itself. */
+    pub fn factory_reset(&mut self, confirmed: bool) -> io::Result<()> {
+        if !confirmed {
+            return Err(Error::new(ErrorKind::InvalidInput, "A factory reset erases the radio's LoRaWAN keys and must be confirmed"));
+        }
+        while self.readerlinesrx.try_recv().is_ok() {}
+        self.ser.writeln(String::from("sys factoryRESET"))?;
+
+        // the module answers with its boot banner once it rebooted
+        let started = Instant::now();
+        let mut banner = None;
+        while let Some(left) = FACTORY_RESET_WAIT.checked_sub(started.elapsed()) {
+            match self.readerlinesrx.recv_timeout(left) {
+                Ok(line) => match parse_version(&line) {
+                    Ok(device) if device.is_lostik && banner.is_none() => banner = Some(device),
+                    _ => debug!("Radio said during factory reset: {}", line),
+                },
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Err(serial_closed()),
+            }
+        }
+        let device = banner.ok_or_else(|| mkerror("Radio did not show its boot banner after the factory reset"))?;
+        info!("Radio reset to factory defaults: {}", device.version);
+        self.init(self.opt.radiocfg.clone())
+    }
+
+    /// Silence the radio at once: stop receiving, drop every queued frame and put the module to sleep
+    /* Frames are refused until `emergency_reset`, so every send of the
+    node fails meanwhile. The radio loop is waited for before anything is
+    sent to the module, so no command of the loop is cut in half. The
+    module sleeps for as long as it can and only a break wakes it earlier. */
+    pub fn emergency_stop(&mut self) -> io::Result<()> {
+        error!("Emergency stop of the radio at {} ms since the epoch", unix_millis());
+        self.txsender.halt();
+        let dropped = self.txreader.drain();
+        warn!("Dropped {} frames queued for transmission", dropped);
+        self.stop_loop()?;
+
+        // the answer may be a packet caught on its way in, the module sleeps either way
+        if let Err(e) = self.command_timeout("radio rxstop", SELF_TEST_TIMEOUT) {
+            warn!("Radio did not answer rxstop: {}", e);
+        }
+        self.ser.writeln(format!("sys sleep {}", EMERGENCY_SLEEP_MS))
+    }
+
+    /// Wake the radio after `emergency_stop`, initialize it again and start a new radio loop
+    /* Frames are accepted again only once the radio is initialized, it
+    stays stopped if that fails. */
+    pub fn emergency_reset(&mut self) -> io::Result<()> {
+        if !self.txsender.is_halted() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Radio was not stopped"));
+        }
+        self.ser.send_break()?;
+        self.init(self.opt.radiocfg.clone())?;
+        self.txsender.resume();
+        warn!("Radio resumed after an emergency stop");
+        self.spawn_loop();
         Ok(())
     }
 
+    /// Read back every radio parameter, one `radio get` after the other
+    /* Only from the radio thread or before `run`, with the receiver
+    stopped. Lines left over from before are dropped first so each
+    answer lines up with its command. */
+    pub fn read_config_dump(&mut self) -> io::Result<RadioConfig> {
+        while self.readerlinesrx.try_recv().is_ok() {}
+        let responses = RADIO_CONFIG_PARAMS.iter()
+            .map(|param| self.command(&format!("radio get {}", param)))
+            .collect::<io::Result<Vec<String>>>()?;
+        parse_radio_config(&responses)
+    }
+
+    /// Log the radio configuration, to see what state it was in when it failed
+    fn log_config_dump(&mut self) {
+        match self.read_config_dump() {
+            Ok(config) => error!("Radio configuration: {:?}", config),
+            Err(e) => error!("Could not read the radio configuration: {}", e),
+        }
+    }
+
+    /// Count a failed transmission or receiver restart, dumping the radio configuration once failures persist
+    /// returns the failure if it ends the radio loop
+    fn track_failure(&mut self, result: io::Result<()>, failures: &mut u32) -> io::Result<()> {
+        let e = match result {
+            Ok(()) => {
+                *failures = 0;
+                return Ok(());
+            },
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => return Err(e),
+            Err(e) => e,
+        };
+        *failures += 1;
+        debug!("Radio operation failed: {}", e);
+        if *failures == PERSISTENT_FAILURES {
+            error!("Radio failed {} times in a row, last with: {}", failures, e);
+            self.log_config_dump();
+        }
+        Ok(())
+    }
+
+    /// take a snapshot of the radio health readings
+    pub fn stats(&mut self) -> RadioStats {
+        RadioStats {
+            temperature: self.get_temperature().ok(),
+            vdd: self.get_vdd().ok(),
+        }
+    }
+
     /// turn on the red LED light
-    fn redledon(&mut self) {
-        self.ser.writeln(String::from("sys set pindig GPIO10 1"));
-        self.readerlinesrx.recv();
+    fn redledon(&mut self) -> io::Result<()> {
+        self.ser.writeln(String::from("sys set pindig GPIO10 1"))?;
+        self.recv_line().map(|_| ())
     }
 
     /// turn off the red LED light
-    fn redledoff(&mut self) {
-        self.ser.writeln(String::from("sys set pindig GPIO10 0"));
-        self.readerlinesrx.recv();
+    fn redledoff(&mut self) -> io::Result<()> {
+        self.ser.writeln(String::from("sys set pindig GPIO10 0"))?;
+        self.recv_line().map(|_| ())
     }
 
     /// turn on the blue LED light
-    fn blueledon(&mut self) {
-        self.ser.writeln(String::from("sys set pindig GPIO11 1"));
-        self.readerlinesrx.recv();
+    fn blueledon(&mut self) -> io::Result<()> {
+        self.ser.writeln(String::from("sys set pindig GPIO11 1"))?;
+        self.recv_line().map(|_| ())
     }
 
     /// turn off the blue LED light
-    fn blueledoff(&mut self) {
-        self.ser.writeln(String::from("sys set pindig GPIO11 0"));
-        self.readerlinesrx.recv();
+    fn blueledoff(&mut self) -> io::Result<()> {
+        self.ser.writeln(String::from("sys set pindig GPIO11 0"))?;
+        self.recv_line().map(|_| ())
     }
 
     /// starts radio receiver
@@ -289,55 +1514,354 @@ impl LoStik {
         // Enter read mode
 
         self.ser.writeln(String::from("radio rx 0"))?;
-        let mut response = self.readerlinesrx.recv().unwrap();
+        let mut response = self.recv_line()?;
 
         // For some reason, sometimes we get a radio_err here, then an OK.  Ignore it.
         if response == String::from("radio_err") {
-            response = self.readerlinesrx.recv().unwrap();
+            response = self.recv_line()?;
         }
         assert_response(response, String::from("ok"))?;
-        self.blueledon();
-        Ok(())
+        self.blueledon()
     }
 
     /// stops radio receiver so can transmit
     pub fn rxstop(&mut self) -> io::Result<()> {
         self.ser.writeln(String::from("radio rxstop"))?;
-        let checkresp = self.readerlinesrx.recv().unwrap();
+        let checkresp = self.recv_line()?;
         if checkresp.starts_with("radio_rx ") {
             // We had a race.  A packet was coming in.  Decode and deal with it,
             // then look for the 'ok' from rxstop.  We can't try to read the quality in
             // this scenario.
-            self.onrx(checkresp)?;
-            self.readerlinesrx.recv().unwrap();  // used to pop this into checkresp, but no need now.
+            self.onrx(checkresp, false)?;
+            self.recv_line()?;  // used to pop this into checkresp, but no need now.
         }
 
         // Now, checkresp should hold 'ok'.
         //  It might not be; I sometimes see radio_err here.  it's OK too.
         // assert_response(checkresp, String::from("ok"))?;
-        self.blueledoff();
-        Ok(())
+        self.blueledoff()
+    }
+
+    /// transmits a frame at a given time, for time slotted protocols
+    /* A send time already past is transmitted right away with a warning,
+    unless it is later than the jitter tolerance allows. */
+    pub fn tx_at_time(&mut self, data: &[u8], send_at: Instant) -> io::Result<()> {
+        let now = Instant::now();
+        if now > send_at {
+            let late = now.duration_since(send_at);
+            let tolerance = Duration::from_millis(self.opt.txjittertolerance);
+            if tolerance.as_millis() > 0 && late > tolerance {
+                return Err(mkerror(&format!("Transmission is {}ms late, tolerance is {}ms", late.as_millis(), tolerance.as_millis())));
+            }
+            warn!("Transmitting {}ms late", late.as_millis());
+        } else {
+            thread::sleep(send_at.saturating_duration_since(now));
+        }
+        self.tx(data)
     }
 
     /// transmits a frame, do not call this directly
     /// or you could have collisions
     pub fn tx(&mut self, data: &[u8]) -> io::Result<()> {
-        self.redledon();
+        self.tx_ack(data).map(|_| ())
+    }
+
+    /// transmits a frame, failing if the radio reports radio_err rather than radio_tx_ok
+    fn tx_confirmed(&mut self, data: &[u8]) -> io::Result<()> {
+        let ack = self.tx_ack(data)?;
+        assert_response(ack, String::from("radio_tx_ok"))
+    }
+
+    /// transmits a frame, returning the radio's final answer
+    fn tx_ack(&mut self, data: &[u8]) -> io::Result<String> {
+        self.redledon()?;
         // hex encode and send to radio device for transmission
         let txstr = format!("radio tx {}", hex::encode(data));
         self.ser.writeln(txstr)?;
 
         // We get two responses from this.... though sometimes a lingering radio_err also.
-        let mut resp = self.readerlinesrx.recv().unwrap();
+        let mut resp = self.recv_line()?;
         if resp == String::from("radio_err") {
-            resp = self.readerlinesrx.recv().unwrap();
+            resp = self.recv_line()?;
         }
         assert_response(resp, String::from("ok"))?;
 
         // pull radio ack message
-        self.readerlinesrx.recv().unwrap();  // normally radio_tx_ok
-        self.redledoff();
-        Ok(())
+        let ack = self.recv_line()?;  // normally radio_tx_ok
+        self.redledoff()?;
+        self.heartbeat.try_send(()).ok();
+        Ok(ack)
+    }
+
+}
+
+#[cfg(test)]
+#[test]
+fn version_parse() {
+    let info = parse_version("RN2483 1.0.5 Oct 31 2018 15:06:52").unwrap();
+    assert_eq!(info.version, "RN2483 1.0.5 Oct 31 2018 15:06:52");
+    assert!(info.is_lostik);
+
+    assert!(!parse_version("SOMETHING ELSE 2.0").unwrap().is_lostik);
+    assert!(parse_version("").is_err());
+    assert!(parse_version("\u{0}\u{fffd}x").is_err());
+
+    assert_eq!(parse_firmware_version(&info.version), Some((1, 0, 5)));
+    assert_eq!(parse_firmware_version("RN2903 1.0.5"), Some((1, 0, 5)));
+    assert_eq!(parse_firmware_version("RN2483 1.0"), None);
+    assert_eq!(parse_firmware_version("RN2483 v1.0.5"), None);
+    assert_eq!(parse_firmware_version("RN2483"), None);
+}
+
+#[test]
+fn sync_word_from_network_id() {
+    assert_eq!(network_id_to_sync_word(0), fnv1a(&[0, 0, 0, 0]) as u8);
+    assert_eq!(network_id_to_sync_word(42), network_id_to_sync_word(42));
+    // reserved words are never handed out, and different networks spread over the rest
+    let words: Vec<u8> = (0..10000).map(network_id_to_sync_word).collect();
+    assert!(words.iter().all(|word| !RESERVED_SYNC_WORDS.contains(word)));
+    let mut distinct = words.clone();
+    distinct.sort();
+    distinct.dedup();
+    assert_eq!(distinct.len(), 254);
+}
+
+#[test]
+fn temperature_parse() {
+    assert_eq!(parse_temperature("25.0").unwrap(), 25.0f32);
+    assert_eq!(parse_temperature("-4.5\r").unwrap(), -4.5f32);
+    assert!(parse_temperature("invalid_param").is_err());
+}
+
+#[test]
+fn init_line_parse() {
+    assert_eq!(parse_init_line("radio set sf sf12"), Some((String::from("radio set sf sf12"), String::from("ok"))));
+    assert_eq!(parse_init_line(" sys get ver | * "), Some((String::from("sys get ver"), String::from("*"))));
+    assert_eq!(parse_init_line("mac pause|4294967245"), Some((String::from("mac pause"), String::from("4294967245"))));
+    assert_eq!(parse_init_line(""), None);
+    assert_eq!(parse_init_line("# comment"), None);
+
+    assert_eq!(parse_power_command("radio set pwr 22"), Some(22));
+    assert_eq!(parse_power_command("radio  set pwr -3"), Some(-3));
+    assert_eq!(parse_power_command("radio set pwr"), None);
+    assert_eq!(parse_power_command("radio set pwr high"), None);
+    assert_eq!(parse_power_command("radio get pwr"), None);
+}
+
+#[test]
+fn init_power_limit() {
+    use crate::hardware::mock::MockModule;
+
+    let powers = |module: &MockModule| -> Vec<i8> {
+        module.commands().iter().filter_map(|command| parse_power_command(command)).collect()
+    };
+    // the defaults set no power, an EU868 radio keeps under 14 dBm whatever its init file asks for
+    let module = MockModule::new();
+    let opt = Settings::new().unwrap();
+    assert_eq!(opt.region, crate::hardware::LoraRegion::EU868);
+    let mut radio = LoStik::with_serial(opt.clone(), module.serial());
+    radio.init(None).unwrap();
+    assert!(powers(&module).iter().all(|&dbm| dbm <= 14));
+
+    let initfile = std::env::temp_dir().join(format!("loramesh-init-{}.txt", std::process::id()));
+    fs::write(&initfile, "sys get ver|*\nradio set pwr 22\nradio set pwr 10|ok\nradio set sf sf12\n").unwrap();
+    let module = MockModule::new();
+    let mut radio = LoStik::with_serial(opt, module.serial());
+    let result = radio.init(Some(initfile.clone()));
+    fs::remove_file(&initfile).ok();
+    result.unwrap();
+    assert_eq!(powers(&module), vec![14, 10]);
+}
+
+#[test]
+fn rx_line_parse() {
+    assert_eq!(parse_rx_line("radio_rx  0a0b").unwrap().unwrap(), vec![0x0a, 0x0b]);
+    assert_eq!(parse_rx_line("radio_rx 0a0b").unwrap().unwrap(), vec![0x0a, 0x0b]);
+    assert!(parse_rx_line("radio_err").is_none());
+    // truncated and garbled lines are errors
+    assert!(parse_rx_line("radio_rx ").unwrap().is_err());
+    assert!(parse_rx_line("radio_rx  0a0").unwrap().is_err());
+    assert!(parse_rx_line("radio_rx \u{0}\u{e9}").unwrap().is_err());
+}
+
+#[test]
+fn link_quality_parse() {
+    assert_eq!(parse_snr("7").unwrap(), 7);
+    assert_eq!(parse_snr("-12\r").unwrap(), -12);
+    assert!(parse_snr("200").is_err());
+    assert_eq!(parse_rssi("-87").unwrap(), -87);
+    assert!(parse_rssi("invalid_param").is_err());
+}
+
+#[test]
+fn vdd_parse() {
+    assert_eq!(parse_vdd("3312").unwrap(), 3312);
+    assert_eq!(parse_vdd("2950\r").unwrap(), 2950);
+    assert!(parse_vdd("invalid_param").is_err());
+}
+
+#[test]
+fn hweui_parse() {
+    assert_eq!(parse_hweui("0004A30B001A2B3C\r").unwrap(), [0x00, 0x04, 0xA3, 0x0B, 0x00, 0x1A, 0x2B, 0x3C]);
+    assert_eq!(parse_hweui("0004a30b001a2b3c").unwrap()[7], 0x3C);
+    assert!(parse_hweui("0004A30B001A2B").is_err());
+    assert!(parse_hweui("0004A30B001A2BXY").is_err());
+    assert!(parse_hweui("invalid_param").is_err());
+}
+
+#[test]
+fn crystal_error() {
+    use crate::hardware::mock::MockModule;
+
+    assert_eq!(parse_frequency("868100000\r").unwrap(), 868_100_000);
+    assert!(parse_frequency("invalid_param").is_err());
+
+    // 1 kHz high at 868 MHz is just about acceptable
+    let ppm = ppm_error(868_001_000, 868_000_000);
+    assert!((ppm - 1.152).abs() < 0.001);
+    assert!(ppm_error(868_001_100, 868_000_000) > MAX_CRYSTAL_ERROR_PPM);
+    assert!(ppm_error(867_998_000, 868_000_000) < -MAX_CRYSTAL_ERROR_PPM);
+    assert_eq!(corrected_frequency(868_100_000, 0.0), 868_100_000);
+    assert_eq!(corrected_frequency(868_000_000, ppm), 867_999_000);
+
+    // a crystal 1 ppm fast is measured as such, and the corrected carrier lands where the radio was set
+    let module = MockModule::new();
+    let mut radio = LoStik::with_serial(Settings::new().unwrap(), module.serial());
+    let carrier = |freq: u64| (freq as f64 * (1.0 + 1e-6)).round() as u64;
+    let ppm = radio.measure_crystal_error(carrier(868_100_000)).unwrap();
+    assert!((ppm - 1.0).abs() < 0.01);
+    radio.apply_ppm_correction(ppm).unwrap();
+    let freq = parse_frequency(&radio.command("radio get freq").unwrap()).unwrap();
+    assert!(freq < 868_100_000);
+    assert_eq!(carrier(freq), 868_100_000);
+}
+
+#[test]
+fn restart_stuck_loop() {
+    use crate::hardware::mock::MockModule;
+
+    let module = MockModule::new();
+    let mut radio = LoStik::with_serial(Settings::new().unwrap(), module.serial());
+    radio.init(None).unwrap();
+
+    // the module hangs as the loop starts the receiver
+    module.set_mute(true);
+    let (stuck, stopped) = crossbeam_channel::bounded(1);
+    let ls2 = radio.clone();
+    thread::spawn(move || {
+        radioloop(ls2);
+        stuck.send(()).unwrap();
+    });
+    assert!(stopped.recv_timeout(Duration::from_millis(200)).is_err());
+
+    // once it answers again the restart wakes the stuck loop, which returns rather than panics
+    module.set_mute(false);
+    radio.restart();
+    stopped.recv_timeout(Duration::from_secs(5)).unwrap();
+    // and the new loop runs the radio
+    assert_eq!(radio.query("radio get sf").unwrap(), "sf12");
+    radio.running.store(false, Ordering::SeqCst);
+}
+
+#[test]
+fn emergency_stop_sleeps() {
+    use crate::hardware::mock::MockModule;
+    use crate::stack::qos::TxPriority;
+    use crossbeam_channel::TryRecvError;
+
+    let module = MockModule::new();
+    let mut radio = LoStik::with_serial(Settings::new().unwrap(), module.serial());
+    radio.init(None).unwrap();
+    let (_, txsender) = radio.run();
+    assert_eq!(radio.query("radio get sf").unwrap(), "sf12");
+
+    // the loop returns before the module is told anything, the sleep comes last
+    radio.emergency_stop().unwrap();
+    assert_eq!(radio.loopdone.lock().unwrap().try_recv(), Err(TryRecvError::Disconnected));
+    let commands = module.commands();
+    assert_eq!(commands[commands.len() - 2..], [String::from("radio rxstop"), format!("sys sleep {}", EMERGENCY_SLEEP_MS)]);
+    assert!(txsender.send(vec![1, 2, 3], TxPriority::Normal).is_err());
+
+    // a break wakes it, and a new loop runs the radio
+    radio.emergency_reset().unwrap();
+    assert!(txsender.send(vec![1, 2, 3], TxPriority::Normal).is_ok());
+    assert_eq!(radio.query("radio get sf").unwrap(), "sf12");
+    radio.running.store(false, Ordering::SeqCst);
+}
+
+#[test]
+fn radio_config_parse() {
+    let responses = |values: &[&str]| values.iter().map(|v| format!("{}\r", v)).collect::<Vec<String>>();
+    let config = parse_radio_config(&responses(&["lora", "868100000", "14", "sf12", "125", "4/5", "15000", "12", "8", "on", "off"])).unwrap();
+    assert_eq!(config, RadioConfig {
+        mod_type: String::from("lora"),
+        freq_hz: 868_100_000,
+        power_dbm: 14,
+        sf: 12,
+        bw_khz: 125,
+        cr: String::from("4/5"),
+        wdt_ms: 15000,
+        sync_word: 0x12,
+        preamble_len: 8,
+        crc_enabled: true,
+        iqi_enabled: false,
+    });
+    assert_eq!(parse_radio_config(&responses(&["lora", "868100000", "-3", "sf7", "500", "4/8", "0", "34", "12", "off", "on"])).unwrap().power_dbm, -3);
+
+    // any parameter the radio refused spoils the dump
+    assert!(parse_radio_config(&responses(&["lora", "868100000", "14", "invalid_param", "125", "4/5", "15000", "12", "8", "on", "off"])).is_err());
+    assert!(parse_radio_config(&responses(&["busy", "868100000", "14", "sf12", "125", "4/5", "15000", "12", "8", "on", "off"])).is_err());
+    assert!(parse_radio_config(&responses(&["lora", "868100000"])).is_err());
+}
+
+#[test]
+fn tx_test_packets() {
+    let packet = test_packet(258, 6);
+    assert_eq!(packet, vec![1, 2, 2, 3, 4, 5]);
+    assert_eq!(test_packet(0, 0).len(), 2);
+
+    assert_eq!(power_levels(2, 14, 4), vec![2, 6, 10, 14]);
+    assert_eq!(power_levels(2, 13, 4), vec![2, 6, 10]);
+    assert_eq!(power_levels(14, -3, 5), vec![14, 9, 4, -1]);
+    assert_eq!(power_levels(5, 5, 1), vec![5]);
+}
+
+#[test]
+fn signal_stats() {
+    assert_eq!(SignalStats::from_samples(&[]), None);
+    let stats = SignalStats::from_samples(&[-90.0, -80.0, -100.0, -90.0]).unwrap();
+    assert_eq!((stats.min, stats.max, stats.mean), (-100.0, -80.0, -90.0));
+    assert!((stats.stddev - 50f32.sqrt()).abs() < 0.001);
+}
+
+#[test]
+fn lbt_deferral() {
+    // a deferred frame is not looked at again on every pass of the loop
+    let now = Instant::now();
+    let mut lbt = LbtDeferral::new(now);
+    assert!(!lbt.due(now));
+    assert!(lbt.due(now + LBT_BACKOFF));
+
+    // each look backs off longer, until the frame goes out anyway
+    let mut at = now + LBT_BACKOFF;
+    let mut looks = 1;
+    while lbt.busy(at) {
+        looks += 1;
+        assert!(!lbt.due(at + LBT_BACKOFF * (looks - 1)));
+        at += LBT_BACKOFF * looks;
+        assert!(lbt.due(at));
     }
+    assert_eq!(looks, LBT_MAX_LOOKS);
+    assert!(at.duration_since(now) < LBT_MAX_DEFER);
+
+    // or once it waited long enough
+    let mut lbt = LbtDeferral::new(now);
+    assert!(lbt.busy(now + LBT_BACKOFF));
+    assert!(!lbt.busy(now + LBT_MAX_DEFER));
 
+    // a clear channel the rate limiter holds the frame on is looked at again after a backoff
+    let mut lbt = LbtDeferral::new(now);
+    lbt.postpone(now + LBT_BACKOFF);
+    assert!(!lbt.due(now + LBT_BACKOFF));
+    assert!(lbt.due(now + LBT_BACKOFF * 2));
 }
@@ -0,0 +1,289 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use serialport::prelude::*;
+use crate::hardware::serial::SerialIO;
+
+/// Baud rate `SerialIO::send_break` sends its zero byte at
+const BREAK_BAUD_RATE: u32 = 1200;
+
+/// What the module was told and the state it is in
+struct Module {
+    /// every command written, in order
+    commands: Vec<String>,
+    /// bytes of the command being written
+    line: Vec<u8>,
+    baud_rate: u32,
+    power: i8,
+    freq: u64,
+    asleep: bool,
+    mute: bool,
+}
+
+impl Module {
+    /// The lines the module answers a command with
+    fn answer(&mut self, command: &str) -> Vec<String> {
+        let mut words = command.split_whitespace();
+        let answer = match (words.next(), words.next(), words.next(), words.next()) {
+            (Some("sys"), Some("get"), Some("ver"), None) => "RN2483 1.0.5 Oct 31 2018 15:06:52".to_string(),
+            (Some("sys"), Some("get"), Some("vdd"), None) => "3300".to_string(),
+            (Some("sys"), Some("get"), Some("temp"), None) => "25.0".to_string(),
+            (Some("sys"), Some("get"), Some("hweui"), None) => "0004A30B001A2B3C".to_string(),
+            (Some("sys"), Some("set"), Some("pindig"), Some(_)) => "ok".to_string(),
+            (Some("sys"), Some("sleep"), Some(_), None) => {
+                self.asleep = true;
+                return Vec::new();
+            },
+            (Some("mac"), Some("pause"), None, None) => "4294967245".to_string(),
+            (Some("mac"), Some(_), _, _) => "ok".to_string(),
+            (Some("radio"), Some("get"), Some(param), None) => match param {
+                "mod" => "lora".to_string(),
+                "freq" => self.freq.to_string(),
+                "pwr" => self.power.to_string(),
+                "sf" => "sf12".to_string(),
+                "bw" => "125".to_string(),
+                "cr" => "4/5".to_string(),
+                "wdt" => "15000".to_string(),
+                "sync" => "12".to_string(),
+                "prlen" => "8".to_string(),
+                "crc" => "on".to_string(),
+                "iqi" => "off".to_string(),
+                "rssi" => "-100".to_string(),
+                "snr" => "5".to_string(),
+                _ => "invalid_param".to_string(),
+            },
+            // the RN2903 goes up to 20 dBm, the RN2483 only to 15
+            (Some("radio"), Some("set"), Some("pwr"), Some(dbm)) => match dbm.parse::<i8>() {
+                Ok(dbm) if (-3..=20).contains(&dbm) => {
+                    self.power = dbm;
+                    "ok".to_string()
+                },
+                _ => "invalid_param".to_string(),
+            },
+            (Some("radio"), Some("set"), Some("freq"), Some(hz)) => match hz.parse::<u64>() {
+                Ok(hz) => {
+                    self.freq = hz;
+                    "ok".to_string()
+                },
+                Err(_) => "invalid_param".to_string(),
+            },
+            (Some("radio"), Some("set"), Some(_), Some(_)) => "ok".to_string(),
+            (Some("radio"), Some("rx"), Some(_), None) => "ok".to_string(),
+            (Some("radio"), Some("rxstop"), None, None) => "ok".to_string(),
+            (Some("radio"), Some("tx"), Some(_), None) => return vec!["ok".to_string(), "radio_tx_ok".to_string()],
+            _ => "invalid_param".to_string(),
+        };
+        vec![answer]
+    }
+}
+
+/// A serial port with a simulated RN2483 behind it, for tests
+/* Commands are answered at once, the way the module answers them, and
+kept so a test can see what a radio was told. A module put to sleep
+answers nothing until a break wakes it, a muted one nothing at all. */
+#[derive(Clone)]
+pub struct MockModule {
+    module: Arc<Mutex<Module>>,
+    /// what the module sends, and what of it was not read yet
+    output: Sender<Vec<u8>>,
+    input: Receiver<Vec<u8>>,
+    unread: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MockModule {
+    pub fn new() -> Self {
+        let (output, input) = unbounded();
+        MockModule {
+            module: Arc::new(Mutex::new(Module {
+                commands: Vec::new(),
+                line: Vec::new(),
+                baud_rate: 57600,
+                power: 1,
+                freq: 868_100_000,
+                asleep: false,
+                mute: false,
+            })),
+            output,
+            input,
+            unread: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// A serial port to the module
+    pub fn serial(&self) -> SerialIO {
+        SerialIO::from_port(Box::new(self.clone()), PathBuf::from("/dev/mock")).unwrap()
+    }
+
+    /// Every command the module was sent so far
+    pub fn commands(&self) -> Vec<String> {
+        self.module.lock().unwrap().commands.clone()
+    }
+
+    /// Stop answering, like a module that hangs, or start again
+    pub fn set_mute(&self, mute: bool) {
+        self.module.lock().unwrap().mute = mute;
+    }
+
+    fn send(&self, line: &str) {
+        self.output.send(format!("{}\r\n", line).into_bytes()).unwrap();
+    }
+}
+
+impl io::Read for MockModule {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut unread = self.unread.lock().unwrap();
+        if unread.is_empty() {
+            // the module's output never closes, each clone holds a sender
+            unread.extend(self.input.recv().unwrap());
+        }
+        let len = buf.len().min(unread.len());
+        buf[..len].copy_from_slice(&unread[..len]);
+        unread.drain(..len);
+        Ok(len)
+    }
+}
+
+impl io::Write for MockModule {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut answers = Vec::new();
+        {
+            let mut module = self.module.lock().unwrap();
+            for &byte in buf {
+                // a break wakes a sleeping module, which says so, the 0x55 after it sets the baud rate
+                if module.baud_rate == BREAK_BAUD_RATE && byte == 0 {
+                    if module.asleep {
+                        module.asleep = false;
+                        answers.push("ok".to_string());
+                    }
+                    continue;
+                }
+                if byte == 0x55 && module.line.is_empty() {
+                    continue;
+                }
+                if byte != b'\n' {
+                    module.line.push(byte);
+                    continue;
+                }
+                let command = String::from_utf8_lossy(&module.line).trim().to_string();
+                module.line.clear();
+                module.commands.push(command.clone());
+                if !module.asleep && !module.mute {
+                    answers.extend(module.answer(&command));
+                }
+            }
+        }
+        for answer in answers {
+            self.send(&answer);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MockModule {
+    fn name(&self) -> Option<String> {
+        Some(String::from("/dev/mock"))
+    }
+
+    fn settings(&self) -> SerialPortSettings {
+        SerialPortSettings::default()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.module.lock().unwrap().baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    fn set_all(&mut self, settings: &SerialPortSettings) -> serialport::Result<()> {
+        self.set_baud_rate(settings.baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.module.lock().unwrap().baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, _: Duration) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.unread.lock().unwrap().len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+}
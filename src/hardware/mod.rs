@@ -1,4 +1,29 @@
 pub(crate) mod serial;
 
+pub(crate) mod async_lostik;
+pub use async_lostik::AsyncLoStik;
+
 pub(crate) mod lostik;
-pub(crate) use lostik::LoStik;
\ No newline at end of file
+pub use lostik::{LoStik, LinkQuality, Radio, RadioConfig, RxPacket, RxTestReport, SelfTestReport, SignalStats, TxTestReport};
+pub use lostik::{network_id_to_sync_word, parse_hweui, parse_init_line, parse_radio_config, parse_rx_line, parse_version};
+
+pub(crate) mod loopback;
+pub use loopback::{LoopbackMedium, LoopbackRadio, PairedLoopback};
+
+#[cfg(test)]
+pub(crate) mod mock;
+
+pub(crate) mod region;
+pub use region::LoraRegion;
+
+pub(crate) mod modulation;
+pub use modulation::TxEncoding;
+
+pub(crate) mod gps;
+pub use gps::{gps_reader, GpsFix};
+
+pub(crate) mod watchdog;
+pub use watchdog::WatchdogTimer;
+
+pub(crate) mod usb_detect;
+pub use usb_detect::{enumerate_lostik_ports, DetectedPort};
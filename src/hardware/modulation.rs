@@ -0,0 +1,77 @@
+use std::io;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::hardware::lostik::mkerror;
+
+/// Bytes an FSK frame carries besides its payload: preamble, sync word, length and CRC
+const FSK_OVERHEAD: usize = 11;
+
+/// Modulation the radio transmits and receives with
+/* Every node of a mesh must use the same one, a LoRa radio does not hear
+FSK and the other way around. FSK is much faster over short distances. */
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum TxEncoding {
+    Lora,
+    /// bit rate in bit/s and frequency deviation in Hz
+    Fsk { bitrate: u32, frequency_deviation: u32 },
+}
+
+impl Default for TxEncoding {
+    fn default() -> Self {
+        TxEncoding::Lora
+    }
+}
+
+impl TxEncoding {
+    /// Check the FSK parameters are within what the RN2483 supports
+    pub fn validate(&self) -> io::Result<()> {
+        if let TxEncoding::Fsk { bitrate, frequency_deviation } = *self {
+            if bitrate == 0 || bitrate > 300_000 {
+                return Err(mkerror(&format!("FSK bit rate {} is not between 1 and 300000", bitrate)));
+            }
+            if frequency_deviation > 200_000 {
+                return Err(mkerror(&format!("FSK frequency deviation {} is above 200000", frequency_deviation)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Radio commands that switch to this modulation
+    pub fn commands(&self) -> Vec<String> {
+        match self {
+            TxEncoding::Lora => vec![String::from("radio set mod lora")],
+            TxEncoding::Fsk { bitrate, frequency_deviation } => vec![
+                String::from("radio set mod fsk"),
+                format!("radio set bitrate {}", bitrate),
+                format!("radio set fdev {}", frequency_deviation),
+            ],
+        }
+    }
+
+    /// time on air of an FSK frame, None for LoRa whose airtime depends on the spreading factor
+    pub fn fsk_airtime(&self, len: usize) -> Option<Duration> {
+        match self {
+            TxEncoding::Lora => None,
+            TxEncoding::Fsk { bitrate, .. } => {
+                let bits = 8 * (len + FSK_OVERHEAD) as u64;
+                Some(Duration::from_micros(bits * 1_000_000 / *bitrate as u64))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn tx_encoding_commands() {
+    assert_eq!(TxEncoding::default(), TxEncoding::Lora);
+    assert_eq!(TxEncoding::Lora.commands(), vec!["radio set mod lora"]);
+    let fsk = TxEncoding::Fsk { bitrate: 50000, frequency_deviation: 25000 };
+    assert_eq!(fsk.commands(), vec!["radio set mod fsk", "radio set bitrate 50000", "radio set fdev 25000"]);
+    assert!(fsk.validate().is_ok());
+    assert!(TxEncoding::Fsk { bitrate: 0, frequency_deviation: 25000 }.validate().is_err());
+    assert!(TxEncoding::Fsk { bitrate: 50000, frequency_deviation: 250000 }.validate().is_err());
+
+    // 100 bytes and the overhead at 50 kbit/s
+    assert_eq!(fsk.fsk_airtime(100), Some(Duration::from_micros(17760)));
+    assert_eq!(TxEncoding::Lora.fsk_airtime(100), None);
+}
@@ -0,0 +1,52 @@
+use serde::Deserialize;
+
+/// Regulatory region the radio operates in
+/* Power limits are the maximum allowed output for the band, assuming an
+antenna with no gain. Check your local regulations when using a high gain
+antenna. */
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum LoraRegion {
+    /// Europe 863-870 MHz, 14 dBm ERP
+    EU868,
+    /// Europe 433 MHz, 10 dBm ERP
+    EU433,
+    /// United States 902-928 MHz, 30 dBm conducted
+    US915,
+    /// Australia 915-928 MHz, 30 dBm EIRP
+    AU915,
+    /// Asia 923 MHz, 16 dBm EIRP
+    AS923,
+    /// South Korea 920-923 MHz, 14 dBm EIRP
+    KR920,
+    /// India 865-867 MHz, 30 dBm EIRP
+    IN865,
+}
+
+impl LoraRegion {
+    /// Maximum transmit power allowed in the region, in dBm
+    pub fn max_power(&self) -> i8 {
+        match self {
+            LoraRegion::EU868 => 14,
+            LoraRegion::EU433 => 10,
+            LoraRegion::US915 => 30,
+            LoraRegion::AU915 => 30,
+            LoraRegion::AS923 => 16,
+            LoraRegion::KR920 => 14,
+            LoraRegion::IN865 => 30,
+        }
+    }
+
+    /// Limit a transmit power to the region's maximum
+    pub fn clamp_power(&self, dbm: i8) -> i8 {
+        if dbm > self.max_power() { self.max_power() } else { dbm }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn region_power_clamp() {
+    assert_eq!(LoraRegion::EU868.clamp_power(20), 14);
+    assert_eq!(LoraRegion::EU868.clamp_power(10), 10);
+    assert_eq!(LoraRegion::US915.clamp_power(22), 22);
+    assert_eq!(LoraRegion::EU433.clamp_power(-3), -3);
+}
@@ -6,6 +6,9 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::path::PathBuf;
 
+/// Baud rate a break is sent at, a zero byte then holds the line low for 7.5ms
+const BREAK_BAUD_RATE: u32 = 1200;
+
 #[derive(Clone)]
 pub struct SerialIO {
     // BufReader can't be cloned.  Sigh.
@@ -18,20 +21,35 @@ impl SerialIO {
 
     /// Initialize the serial system, configuring the port.
     pub fn new(portname: PathBuf) -> io::Result<SerialIO> {
+        SerialIO::with_timeout(portname, Duration::new(60 * 60 * 24 * 365 * 20, 0))
+    }
+
+    /// Initialize the serial system with a read timeout
+    pub fn with_timeout(portname: PathBuf, timeout: Duration) -> io::Result<SerialIO> {
+        SerialIO::with_baud(portname, 57600, timeout)
+    }
+
+    /// Initialize the serial system with a baud rate and read timeout
+    pub fn with_baud(portname: PathBuf, baud_rate: u32, timeout: Duration) -> io::Result<SerialIO> {
         let settings = SerialPortSettings {
-            baud_rate: 57600,
+            baud_rate,
             data_bits: DataBits::Eight,
             flow_control: FlowControl::None,
             parity: Parity::None,
             stop_bits: StopBits::One,
-            timeout: Duration::new(60 * 60 * 24 * 365 * 20, 0),
+            timeout,
         };
         let readport = serialport::open_with_settings(&portname, &settings)?;
+        SerialIO::from_port(readport, portname)
+    }
+
+    /// Use a port that is already open
+    pub fn from_port(readport: Box<dyn SerialPort>, portname: PathBuf) -> io::Result<SerialIO> {
         let writeport = readport.try_clone()?;
         
         Ok(SerialIO {br: Arc::new(Mutex::new(BufReader::new(readport))),
                     swrite: Arc::new(Mutex::new(writeport)),
-                    portname: portname})
+                    portname})
     }
 
     /// Read a line from the port.  Return it with EOL characters removed.
@@ -39,7 +57,7 @@ impl SerialIO {
     pub fn readln(&mut self) -> io::Result<Option<String>> {
         let mut buf = String::new();
         let mut lock = self.br.lock().unwrap();
-        let size = lock.read_line(&mut buf).expect("Error reading line");
+        let size = lock.read_line(&mut buf)?;
         drop(lock);
         if size == 0 {
             debug!("{:?}: Received EOF from serial port", self.portname);
@@ -59,6 +77,22 @@ impl SerialIO {
         self.swrite.lock().unwrap().write_all(data.as_bytes())?;
         self.swrite.lock().unwrap().flush()
     }
+
+    /// Send a break followed by 0x55, which wakes a sleeping module
+    /* The port cannot send a break itself. A zero byte at a slow baud rate
+    holds the line low long enough, and the module measures the baud rate
+    again from the 0x55 that follows. */
+    pub fn send_break(&mut self) -> io::Result<()> {
+        trace!("{:?} SEROUT: <break>", self.portname);
+        let mut port = self.swrite.lock().unwrap();
+        let baud_rate = port.baud_rate()?;
+        port.set_baud_rate(BREAK_BAUD_RATE)?;
+        port.write_all(&[0])?;
+        port.flush()?;
+        port.set_baud_rate(baud_rate)?;
+        port.write_all(&[0x55])?;
+        port.flush()
+    }
 }
 
 
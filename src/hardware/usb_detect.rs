@@ -0,0 +1,82 @@
+use log::*;
+use std::io;
+use std::path::PathBuf;
+use serialport::{SerialPortInfo, SerialPortType};
+use crate::hardware::lostik::mkerror;
+
+/// USB vendor ID of the FTDI serial chip on the LoStik
+pub const LOSTIK_VID: u16 = 0x0403;
+/// USB product ID of the FTDI FT232R
+pub const LOSTIK_PID: u16 = 0x6001;
+
+/// A serial port that looks like a LoStik
+#[derive(Clone, Debug, PartialEq)]
+pub struct DetectedPort {
+    pub path: PathBuf,
+    pub serial_number: Option<String>,
+}
+
+/// keep only the ports with the LoStik's USB IDs
+fn lostik_ports(ports: Vec<SerialPortInfo>) -> Vec<DetectedPort> {
+    ports.into_iter().filter_map(|port| {
+        match port.port_type {
+            SerialPortType::UsbPort(usb) if usb.vid == LOSTIK_VID && usb.pid == LOSTIK_PID => {
+                Some(DetectedPort { path: PathBuf::from(port.port_name), serial_number: usb.serial_number })
+            },
+            _ => None,
+        }
+    }).collect()
+}
+
+/// List the serial ports of connected LoStiks
+/* Other FTDI based adapters share these IDs, so a detected port is not
+guaranteed to be a radio. `LoStik::probe_device` can tell them apart. */
+pub fn enumerate_lostik_ports() -> Vec<DetectedPort> {
+    match serialport::available_ports() {
+        Err(e) => {
+            warn!("Could not list serial ports: {}", e);
+            Vec::new()
+        },
+        Ok(ports) => lostik_ports(ports),
+    }
+}
+
+/// The configured radio port, or the first detected LoStik if none is configured
+pub fn resolve_radioport(radioport: &PathBuf) -> io::Result<PathBuf> {
+    if !radioport.as_os_str().is_empty() {
+        return Ok(radioport.clone());
+    }
+    match enumerate_lostik_ports().into_iter().next() {
+        None => Err(mkerror("No LoStik found: no USB serial device with ID 0403:6001 is connected. \
+            Plug in the radio or set radioport in /etc/loramesh/conf.yml")),
+        Some(port) => {
+            info!("Detected LoStik on {:?} (serial {})", port.path, port.serial_number.as_ref().map_or("unknown", |s| s.as_str()));
+            Ok(port.path)
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn lostik_port_filter() {
+    use serialport::UsbPortInfo;
+
+    let usb = |vid, pid, serial: &str| SerialPortType::UsbPort(UsbPortInfo {
+        vid,
+        pid,
+        serial_number: Some(String::from(serial)),
+        manufacturer: None,
+        product: None,
+    });
+    let ports = vec![
+        SerialPortInfo { port_name: String::from("/dev/ttyS0"), port_type: SerialPortType::Unknown },
+        SerialPortInfo { port_name: String::from("/dev/ttyACM0"), port_type: usb(0x2341, 0x0043, "arduino") },
+        SerialPortInfo { port_name: String::from("/dev/ttyUSB1"), port_type: usb(LOSTIK_VID, LOSTIK_PID, "A9EPKZ2B") },
+    ];
+    assert_eq!(lostik_ports(ports), vec![DetectedPort {
+        path: PathBuf::from("/dev/ttyUSB1"),
+        serial_number: Some(String::from("A9EPKZ2B")),
+    }]);
+
+    assert_eq!(resolve_radioport(&PathBuf::from("/dev/ttyUSB0")).unwrap(), PathBuf::from("/dev/ttyUSB0"));
+}
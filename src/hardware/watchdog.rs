@@ -0,0 +1,72 @@
+use log::*;
+use std::time::Duration;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+
+/// Restarts the radio loop when it stops making progress
+/* The radio loop sends a heartbeat after every frame it transmits or
+receives. Nodes transmit a heartbeat frame of their own every few seconds,
+so a quiet channel still keeps the watchdog fed as long as the timeout is
+well above the heartbeat interval. */
+pub struct WatchdogTimer {
+    timeout: Duration,
+    heartbeat: Receiver<()>,
+    restarts: u64,
+}
+
+impl WatchdogTimer {
+    pub fn new(timeout: Duration, heartbeat: Receiver<()>) -> Self {
+        WatchdogTimer { timeout, heartbeat, restarts: 0 }
+    }
+
+    /// Wait for the next heartbeat, false if the timeout ran out first
+    /// None once every heartbeat sender is gone
+    pub fn wait(&self) -> Option<bool> {
+        match self.heartbeat.recv_timeout(self.timeout) {
+            Ok(()) => Some(true),
+            Err(RecvTimeoutError::Timeout) => Some(false),
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// Number of restarts so far
+    pub fn restarts(&self) -> u64 {
+        self.restarts
+    }
+
+    /// Call `restart` every time a heartbeat is missed, until the senders are gone
+    pub fn watch<F: FnMut()>(&mut self, mut restart: F) {
+        while let Some(alive) = self.wait() {
+            if !alive {
+                self.restarts += 1;
+                error!("Radio loop stalled for {:?}, restarting it ({} restarts so far)", self.timeout, self.restarts);
+                restart();
+            }
+        }
+        debug!("Radio heartbeats stopped, watchdog exiting");
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn watchdog_restarts() {
+    use std::thread;
+
+    let (beat, heartbeat) = crossbeam_channel::unbounded();
+    let mut watchdog = WatchdogTimer::new(Duration::from_millis(50), heartbeat);
+
+    // healthy loop, then a stall, then the loop is dropped
+    let worker = thread::spawn(move || {
+        for _ in 0..5 {
+            beat.send(()).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(120));
+        beat.send(()).unwrap();
+    });
+
+    let mut restarted = 0;
+    watchdog.watch(|| restarted += 1);
+    worker.join().unwrap();
+    assert!((1..=2).contains(&restarted), "restarted {} times", restarted);
+    assert_eq!(watchdog.restarts(), restarted);
+}
@@ -0,0 +1,14 @@
+#[macro_use]
+extern crate nonzero_ext;
+extern crate packet;
+extern crate rand;
+extern crate config;
+
+pub mod control;
+pub mod hardware;
+pub mod stack;
+pub mod node;
+pub mod settings;
+
+#[cfg(test)]
+mod simulation;
@@ -1,33 +1,59 @@
 use simplelog::*;
+use std::env;
 use std::io;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 use log::*;
 
-mod hardware;
-mod stack;
-mod node;
-mod settings;
-
-use crate::settings::*;
-use crate::hardware::*;
-use crate::node::*;
-use crate::stack::*;
+use loramesh::control;
+use loramesh::control::ControlCommand;
+use loramesh::settings::*;
+use loramesh::hardware::*;
+use loramesh::node::*;
+use loramesh::stack::*;
 
 
 
 use tun_tap::{Iface, Mode};
 use std::sync::Arc;
 
-#[macro_use]
-extern crate nonzero_ext;
-extern crate packet;
-extern crate rand;
-extern crate config;
-
 const MESH_MAX_MESSAGE_LEN: usize = 200;
 const TUN_DEFAULT_PREFIX: &str = "loratun%d";
 
+/// set by SIGINT and SIGTERM
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
 fn main() {
-    let opt: Settings = Settings::new().expect("Error loading settings");
+    let mut opt: Settings = Settings::new().expect("Error loading settings");
+
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // leaves forwarding on the host as it is
+    if args.iter().any(|a| a == "--no-nat") {
+        args.retain(|a| a != "--no-nat");
+        opt.nat = false;
+    }
+    // the self-test needs the radio to itself, it runs instead of the node
+    if args.first().map(|a| a.as_str()) == Some("self-test") {
+        if let Err(e) = self_test(&opt, args.get(1).map(Path::new)) {
+            eprintln!("loramesh: self-test failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // any arguments are a command for an already running node
+    if !args.is_empty() {
+        let result = ControlCommand::from_args(&args)
+            .and_then(|command| control::request(&opt.controlsocket, &command));
+        if let Err(e) = result {
+            eprintln!("loramesh: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
     if opt.debug {
         WriteLogger::init(LevelFilter::Trace, Config::default(), io::stderr()).expect("Failed to init log");
@@ -39,17 +65,96 @@ fn main() {
     //this part is not needed because opt.nodeid's limit is already 255
     //assert!(opt.nodeid <= 255, "Invalid node ID specified, it must be 255 or less.");
 
-    info!("Node ID is {}", opt.nodeid);
     let iface = Arc::new(Iface::new(TUN_DEFAULT_PREFIX, Mode::Tun).unwrap());
-    let tun = NetworkTunnel::new(iface);
+    let tun = NetworkTunnel::new(iface, opt.tunmtu);
 
     let mut ls: LoStik = LoStik::new(opt.clone());
     let initfile = opt.radiocfg.clone();
-    ls.init(initfile);
+    // init refuses a radio on a low supply
+    if let Err(e) = ls.init(initfile) {
+        error!("Could not initialize the radio: {}", e);
+        process::exit(1);
+    }
 
+    if opt.usehardwareeui {
+        match ls.get_device_eui() {
+            Ok(eui) => {
+                info!("Radio hardware EUI is {}", eui.iter().map(|b| format!("{:02X}", b)).collect::<String>());
+                opt.nodeid = derive_node_id(&eui, 0);
+            },
+            Err(e) => error!("Could not read hardware EUI, keeping the configured node ID: {}", e),
+        }
+    }
+    info!("Node ID is {}", opt.nodeid);
 
-    let mut node: MeshNode = node::MeshNode::new(opt.nodeid, tun, ls, opt.clone());
+
+    let mut node: MeshNode = MeshNode::new(opt.nodeid, tun, ls, opt.clone());
+    if let Some(gpsport) = opt.gpsport.clone() {
+        match gps_reader(gpsport, opt.gpsbaud) {
+            Ok(gps) => node.attach_gps(gps),
+            Err(e) => error!("Could not open GPS receiver: {}", e),
+        }
+    }
+    match control::listen(&opt.controlsocket) {
+        Ok(control) => node.attach_control(control),
+        Err(e) => warn!("Control socket unavailable, commands disabled: {}", e),
+    }
+
+    // give the mesh the gateway's internet access
+    let masquerade = match (opt.isgateway && opt.nat, opt.uplink.as_ref()) {
+        (false, _) => None,
+        (true, None) => {
+            info!("No uplink set, not masquerading the mesh");
+            None
+        },
+        (true, Some(uplink)) => match parse_subnet(&opt.meshsubnet).and_then(|subnet| Masquerade::install(subnet, uplink)) {
+            Ok(masquerade) => Some(masquerade),
+            Err(e) => {
+                error!("Could not set up NAT, pass --no-nat to configure it by hand: {}", e);
+                None
+            },
+        },
+    };
+    if let Some(masquerade) = masquerade {
+        on_shutdown(move || drop(masquerade));
+    }
 
     debug!("Running full network stack");
     node.run();
 }
+
+extern "C" fn request_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Run `cleanup` and exit once SIGINT or SIGTERM arrives
+/* The handler only sets a flag, the cleanup runs on a thread of its own
+where it may do anything. */
+fn on_shutdown<F: FnOnce() + Send + 'static>(cleanup: F) {
+    unsafe {
+        libc::signal(libc::SIGINT, request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    thread::spawn(move || {
+        while !SHUTDOWN.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        info!("Shutting down");
+        cleanup();
+        process::exit(0);
+    });
+}
+
+/// Check the radio before a deployment, sending to a second radio on `peerport` if given
+fn self_test(opt: &Settings, peerport: Option<&Path>) -> io::Result<()> {
+    let mut radio = LoStik::open(opt.clone())?;
+    let report = radio.self_test(peerport)?;
+    let (major, minor, patch) = report.firmware;
+    println!("radio: {}", report.device.version);
+    println!("firmware: {}.{}.{}", major, minor, patch);
+    println!("supply: {:.2}V", report.vdd);
+    if let Some((sent, received)) = report.loopback {
+        println!("loopback: {} of {} packets received", received, sent);
+    }
+    Ok(())
+}
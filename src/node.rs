@@ -1,79 +1,580 @@
 use log::*;
-use std::time::Duration;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
 use crate::stack::{NetworkTunnel, Frame};
-use crate::hardware::LoStik;
+use crate::hardware::{LoStik, LinkQuality, Radio, RxPacket, GpsFix, TxEncoding};
+use crate::hardware::lostik::{assert_response, mkerror, parse_hweui, parse_vdd, parse_version};
+use crate::control::{ControlCommand, ControlRequest, TopologyFormat};
 use crate::stack::*;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::fs;
 use packet::ip::v4::Packet;
 use ratelimit_meter::{DirectRateLimiter, LeakyBucket};
-use crossbeam_channel::Sender;
 use std::borrow::{BorrowMut};
 
 
 use std::collections::HashMap;
 use crate::stack::frame::recombine_chunks;
+use crate::stack::dedup::dedup_key;
 
+use crossbeam_channel::{unbounded, Receiver, Sender, TrySendError};
 use rand::{thread_rng, Rng};
-use rand::prelude::ThreadRng;
-use util::composite_key;
+use util::{composite_key, unix_millis};
+use health::{mem_available_kb, uplink_up};
 
 use crate::settings::Settings;
 
+/// How long to listen for another node using an ID before taking it
+const NODEID_CLAIM_WAIT: Duration = Duration::from_secs(5);
 
-pub struct MeshNode {
+/// How often the gateway looks at its uplink, to announce a change
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why a send failed while the radio is stopped for an emergency
+fn radio_stopped() -> Error {
+    Error::new(ErrorKind::NotConnected, "Radio is stopped for an emergency, nothing is sent")
+}
+
+/// Channels and timers of a running node
+struct NodeIo {
+    tunreader: Receiver<IpPacket>,
+    rxreader: Receiver<RxPacket>,
+    txsender: TxQueueSender,
+    periodic: PeriodicScheduler,
+    mstlimiter: DirectRateLimiter<LeakyBucket>,
+    /// incomplete chunks by sender and frame ID
+    rxchunks: HashMap<String, Vec<Frame>>,
+}
+
+/// A traceroute waiting for its reply
+struct PendingTrace {
+    sequence: u16,
+    dest: u8,
+    started: Instant,
+    /// control connection to report to, if started from the control socket
+    reply: Option<Sender<String>>,
+}
+
+/// A text waiting for its delivery receipt
+struct PendingText {
+    dest: u8,
+    msgid: u16,
+    sent: Instant,
+    reply: Option<Sender<String>>,
+}
+
+pub struct MeshNode<R: Radio = LoStik> {
     /// The ID of this node
     id: u8,
     /// IP address of this node's tunnel
     ipaddr: Option<Ipv4Addr>,
+    /// IPv6 address of this node, in the mesh's prefix
+    ipv6addr: Option<Ipv6Addr>,
     /// LoRa device for communication
-    radio: LoStik,
+    radio: R,
     /// Local network interface for IP
     networktunnel: NetworkTunnel,
     /// Router instance
     router: MeshRouter,
+    /// Recently seen frames, to drop flooded duplicates
+    dedup: DedupCache,
+    /// floods heard, waiting to be sent on unless neighbors already did
+    floods: FloodControl,
+    /// acks we owe other nodes, sent a few at a time
+    acks: AckCoalescer,
+    /// our frames waiting for their ack
+    acktracker: AckTracker,
+    /// unicast frames until the next hop sends them on, and data waiting for a route
+    delivery: DeliveryTracker,
+    /// Frame IDs for frames we originate
+    frameids: FrameIdAllocator,
+    /// Commands from the control socket, if one is attached
+    control: Option<Receiver<ControlRequest>>,
+    /// Running ping commands and where to report their results
+    pings: Vec<(PingSession, Sender<String>)>,
+    /// Traceroutes waiting for a reply
+    traces: Vec<PendingTrace>,
+    /// Finished traceroutes not started from the control socket
+    traceresults: HashMap<u16, TracerouteResult>,
+    /// Sequence number of the last traceroute we sent
+    traceseq: u16,
+    /// Texts waiting for a delivery receipt
+    texts: Vec<PendingText>,
+    /// ID of the last text we sent
+    textseq: u16,
+    /// Control connections printing incoming texts
+    textlisteners: Vec<Sender<String>>,
+    /// Addresses assigned to other nodes, only kept on the gateway
+    leases: Option<LeaseTable>,
+    /// when our address lease is due for renewal, None if it never expires
+    leaserenew: Option<Instant>,
+    /// when we last asked the gateway for an address
+    iprequested: Option<Instant>,
+    /// Files being sent or received
+    files: FileTransfers,
+    /// control connections waiting for their file to be delivered
+    filereplies: HashMap<(u8, u16), Sender<String>>,
+    /// when we last announced ourselves as the gateway
+    gatewaysent: Option<Instant>,
+    /// when we last announced whether we reach the internet, and what, as the gateway
+    networksent: Option<(Instant, bool)>,
+    /// when we last looked at our uplink, as the gateway
+    networkchecked: Option<Instant>,
+    /// the gateway we last picked, to notice failovers
+    bestgateway: Option<u8>,
+    /// IP packets recently sent through the gateway, sent again through the next on a failover
+    gatewaybacklog: GatewayBacklog,
+    /// Our clock corrected to the gateway's
+    clock: MeshClock,
+    /// when we last announced our time as the gateway, or asked for it otherwise
+    timesyncsent: Option<Instant>,
+    /// Receivers of application data, by port
+    ports: HashMap<u8, Vec<Sender<(u8, Vec<u8>)>>>,
+    /// Receivers of datagrams, by port
+    datagramports: HashMap<u16, Vec<Sender<(u8, Vec<u8>)>>>,
+    /// Fixes from our GPS receiver, if one is attached
+    gps: Option<Receiver<GpsFix>>,
+    /// Decides when our position is announced
+    positiontracker: PositionTracker,
+    /// Last known position of other nodes
+    positions: PositionTable,
+    /// what we are, sent to other nodes
+    nodeinfo: NodeInfo,
+    /// what other nodes told us they are
+    nodeinfos: NodeInfoTable,
+    /// limits node info requests per node
+    inforequests: KeyedLimiter<u8>,
+    /// latest health report of every node, filled in on the gateway
+    telemetry: TelemetryTable,
+    /// health metrics we report
+    telemetryfields: Vec<TelemetryField>,
+    /// limits route errors per broken link
+    routeerrors: KeyedLimiter<(u8, u8)>,
+    /// limits error reports per node and error code
+    mesherrors: KeyedLimiter<(u8, u8)>,
+    /// receivers of the errors other nodes report to us
+    errorsubscribers: Vec<Sender<ErrorMessage>>,
+    /// limits route discoveries per destination
+    discoveries: KeyedLimiter<u8>,
+    /// cheapest copy of every route discovery heard
+    discoverycosts: DiscoveryCosts,
+    /// routes from before a restart to confirm with a ping
+    staleroutes: Vec<(u8, Vec<u8>)>,
+    /// when the mesh state was last saved
+    statesaved: Instant,
+    /// delivery ratio of each neighbor, advertised in link-state mode
+    links: LinkEstimator,
+    /// picks the spreading factor from the neighbors' SNR, if enabled
+    adr: Option<AdaptiveDataRate>,
+    /// spreading factor announced to the neighbors, and when the radio moves to it
+    adrpending: Option<(u8, Instant)>,
+    /// source routes, in DSR mode
+    dsr: Option<DsrRouter>,
+    /// the sleep schedule, if the radio is duty cycled
+    dutycycle: Option<DutyCycle>,
+    /// channel reservations for large bursts, if enabled
+    reservations: Option<Reservations>,
+    /// nodes we hear from, to notice the mesh splitting
+    partitions: PartitionDetector,
+    /// when our last neighbor hello was sent
+    hellosent: Instant,
+    /// true if neighbors appeared or expired since the last poll
+    topologychanged: bool,
+    /// drops frames going round in loops, and counts them
+    loops: LoopGuard,
+    /// frame IDs seen from every sender, to drop replayed frames
+    antireplay: AntiReplayWindow,
+    /// node ID we are about to take, listening for another node using it
+    claimwatch: Option<u8>,
+    /// true if a frame from the watched node ID was heard
+    claimheard: bool,
+    /// our instance token, and the other nodes heard using our ID
+    collisions: CollisionDetector,
+    /// messages held for unreachable nodes, if we store and forward
+    store: Option<MessageStore>,
+    /// limits held message notices per node
+    heldnotices: KeyedLimiter<u8>,
+    /// decides which remote commands from other nodes we run
+    remote: RemoteGuard,
+    /// decides which config changes from other nodes we apply
+    config: ConfigGuard,
+    /// key we sign our own config changes with
+    configkey: Option<ConfigKey>,
+    /// key frames from the mesh are checked against
+    framekey: Option<FrameKey>,
+    /// drop frames that are not signed
+    requiresigned: bool,
+    /// nonce of the last remote command or config change we sent
+    remotenonce: u64,
+    /// control connections waiting for the result of their remote command or config change, by node and nonce
+    remotereplies: HashMap<(u8, u64), (Instant, Sender<String>)>,
+    /// sequence number of our last link-state advertisement
+    lsaseq: u16,
+    /// when our next link-state advertisement is due
+    lsanext: Instant,
+    /// when our last health report was sent
+    telemetrysent: Instant,
+    /// frames received from the radio, and those that could not be parsed
+    rxframes: u64,
+    rxdropped: u64,
+    /// when the last frame was received
+    lastrx: Instant,
+    /// sequence number of our last heartbeat
+    heartbeatseq: u16,
+    /// supply voltage read at the last broadcast, sent in heartbeats
+    vddmv: Option<u16>,
+    /// IP packets sent and received over the mesh, ICMP apart
+    iptraffic: IpTraffic,
+    /// contexts of the IP headers compressed to and from other nodes
+    headercomp: HeaderCompression,
+    /// rules for the packets crossing the tunnel
+    filter: PacketFilter,
+    /// DNS proxy for the mesh, on a gateway that runs one
+    dns: Option<DnsProxy>,
+    /// the addresses other nodes announce, to catch two using the same
+    addressclaims: AddressClaims,
+    /// subnet of the mesh's addresses, resolved to nodes with address queries
+    meshsubnet: (Ipv4Addr, u8),
+    /// the node holding every mesh address we know, and packets waiting for one
+    resolver: AddressResolver,
+    started: Instant,
+    /// whether we warned that nothing was heard since the start
+    silencewarned: bool,
+    /// Tunnel and radio i/o, once started
+    io: Option<NodeIo>,
+    /// every node heard directly, with its link statistics
+    neighbors: NeighborTable,
+    /// candidate routes to every node with their metrics
+    routes: RoutingTable,
+    /// our own emergency beacon while one is raised
+    beacon: Option<Beacon>,
+    /// emergencies raised by other nodes
+    emergencies: EmergencyTable,
+    /// beacon frames that may still jump the transmit queue
+    emergencyallowance: EmergencyAllowance,
+    /// control clients waiting for emergency events
+    emergencylisteners: Vec<Sender<String>>,
+    /// latest GPS position, sent along with our beacon
+    lastfix: Option<(f64, f64)>,
     /// Options
     opt: Settings
 }
 
-impl MeshNode {
+impl<R: Radio> MeshNode<R> {
 
-    pub fn new(id: u8, mut networktunnel: NetworkTunnel, radio: LoStik, opt: Settings) -> Self {
-        // If this node is a gateway, assign an IP address of 172.16.0.<id>.
-        // Otherwise, we will wait for DHCP from a network gateway and
-        // assign a default address.
+    pub fn new(id: u8, mut networktunnel: NetworkTunnel, radio: R, opt: Settings) -> Self {
+        // A static IP from the settings comes first. Otherwise, if this node
+        // is a gateway, assign an IP address of 172.16.0.<id>, or we will
+        // wait for DHCP from a network gateway and assign a default address.
         let mut ipaddr = None;
-        if opt.isgateway {
+        if let Some(staticip) = opt.staticip {
+            ipaddr = Some(staticip);
+            networktunnel.assignipnet(&staticip, opt.staticprefix);
+            info!("Using static IP {}/{}", staticip, opt.staticprefix);
+        } else if opt.isgateway {
             ipaddr = Some(Ipv4Addr::new(172,16,0, id));
             networktunnel.assignipaddr(&ipaddr.unwrap());
             networktunnel.routeipaddr(&ipaddr.unwrap(), &networktunnel.tunip.unwrap());
             info!("Network gateway detected, added route to {}", ipaddr.unwrap().to_string());
         }
-        let router =
+        for ((dest, prefix), via) in parse_ip_routes(&opt.staticiproutes).expect("Invalid static IP routes") {
+            networktunnel.routesubnet(&dest, prefix, &via);
+        }
+        // the proxy answers over UDP and IPv4 in a single packet
+        let dns = match opt.isgateway && opt.dnsproxy {
+            false => None,
+            true => match UdpUpstream::from_resolv_conf(Path::new(RESOLV_CONF)) {
+                Err(e) => {
+                    error!("Not proxying DNS, could not read the host's resolvers: {}", e);
+                    None
+                },
+                Ok(upstream) => Some(DnsProxy::new(Box::new(upstream), opt.dnscachesize, opt.tunmtu - 28)),
+            },
+        };
+        let mut router =
             MeshRouter::new(
                 id,
                 None,
                 opt.maxhops.clone(),
                 Duration::from_millis(opt.chunktimeout.clone()),
                 opt.isgateway.clone());
+        // IPv6 addresses need no gateway, every node takes its own
+        let ipv6addr = opt.ipv6prefix.map(|prefix| ipv6_node_addr(prefix, id));
+        if let Some(ipv6addr) = ipv6addr {
+            networktunnel.assignip6addr(&ipv6addr);
+            router.ip6_add(id, ipv6addr);
+            info!("Added IPv6 address {}", ipv6addr);
+        }
+        let meshsubnet = parse_subnet(&opt.meshsubnet).expect("Invalid mesh subnet");
+        let mut resolver = AddressResolver::new(Duration::from_millis(opt.addressttl),
+            Duration::from_millis(opt.addresswait), opt.addressqueuelen);
+        // the gateway hands out addresses from its pool, keeping its own
+        let mut leases = None;
+        if opt.isgateway {
+            let mut table = LeaseTable::new(opt.ippoolstart, opt.ippoolend, Duration::from_millis(opt.leasetime));
+            table.reserve(ipaddr.unwrap());
+            if let Err(e) = table.load(&opt.leasefile) {
+                error!("Could not read IP leases from {}: {}", opt.leasefile.display(), e);
+            }
+            for (nodeid, lease) in table.iter() {
+                router.ip_add(*nodeid, lease.ipaddr);
+                resolver.learn(lease.ipaddr, *nodeid);
+                networktunnel.routeipaddr(&lease.ipaddr, &ipaddr.unwrap());
+            }
+            info!("Loaded {} IP leases", table.len());
+            leases = Some(table);
+        }
+        let mut store = None;
+        if opt.storeforward {
+            let mut held = MessageStore::new(opt.storemaxmessages, opt.storemaxbytes, Duration::from_millis(opt.storemaxage));
+            if let Err(e) = held.load(&opt.storefile) {
+                error!("Could not read held messages from {}: {}", opt.storefile.display(), e);
+            }
+            info!("Holding {} messages for unreachable nodes", held.len());
+            store = Some(held);
+        }
+        // missed announcements or failed frames fail over to another gateway
+        if opt.gatewayinterval > 0 {
+            router.track_gateways(Duration::from_millis(opt.gatewayinterval * opt.gatewaymissed as u64), opt.gatewayfailures);
+        }
+        if opt.networkinterval > 0 {
+            router.track_networks(Duration::from_millis(opt.networkinterval * opt.gatewaymissed as u64));
+        }
+        // what we knew before a restart, none of it trusted yet
+        let mut staleroutes = Vec::new();
+        let mut saved = None;
+        if opt.stateinterval > 0 {
+            match MeshState::load(&opt.statefile) {
+                Err(e) if e.kind() == ErrorKind::NotFound => {},
+                Err(e) => warn!("Discarding mesh state from {}: {}", opt.statefile.display(), e),
+                Ok(state) => {
+                    staleroutes = router.restore(state.clone());
+                    info!("Restored {} routes from before the restart", staleroutes.len());
+                    saved = Some(state);
+                }
+            }
+        }
+        let mut routes = RoutingTable::new(Duration::from_millis(opt.routemaxage));
+        for (dest, path) in parse_static_routes(&opt.staticroutes).expect("Invalid static routes") {
+            routes.add_static(dest, path);
+        }
+        if opt.stateinterval > 0 {
+            match routes.load(&opt.routefile) {
+                Err(e) => error!("Could not read routes from {}: {}", opt.routefile.display(), e),
+                Ok(restored) => info!("Restored {} routes to revalidate", restored),
+            }
+        }
+        let dedup = DedupCache::new(opt.dedupsize, opt.dedupfprate)
+            .with_ttl(Duration::from_millis(opt.dedupttl));
+        let positiontracker = PositionTracker::new(Duration::from_millis(opt.positioninterval), opt.positiondistance);
+        if opt.routing == RoutingMode::LinkState {
+            router.enable_linkstate(Duration::from_millis(opt.lsamaxage));
+        }
+        // heartbeats are periodic, ten of them make up the estimation window
+        let heartbeatinterval = Duration::from_millis(opt.heartbeatinterval);
+        let links = LinkEstimator::new(heartbeatinterval, heartbeatinterval * 10);
+        // a file chunk and its header fit in a single frame
+        let chunksize = opt.maxpacketsize.saturating_sub(FRAME_HEADER_LEN + opt.maxhops as usize + 4);
+        let files = FileTransfers::new(opt.filedir.clone(), opt.maxfilesize, chunksize, Duration::from_millis(opt.filetimeout));
+        let telemetryfields = TelemetryField::parse_list(&opt.telemetryfields).expect("Invalid telemetry fields");
+        // the radio firmware is filled in once the radio runs
+        let nodeinfo = NodeInfo {
+            name: opt.nodename.clone(),
+            hardware: HardwareModel::Unknown,
+            firmware: String::new(),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            gateway: opt.isgateway,
+            encryption: opt.encryption,
+            routing: opt.routing,
+            headercompression: opt.headercompression,
+        };
+        let framekey = opt.framekey.as_ref().and_then(|key| parse_frame_key(key).ok());
+        // remote commands are only run from authenticated frames
+        let authenticated = nodeinfo.encryption || (framekey.is_some() && opt.requiresigned);
+        let remote = RemoteGuard::new(authenticated, Duration::from_millis(opt.remoteinterval));
+        // the settings were validated, the key parses
+        let configkey = opt.configkey.as_ref().and_then(|key| parse_config_key(key).ok());
+        let config = ConfigGuard::new(configkey, opt.mutableconfigkeys.clone());
 
-        MeshNode{
+        let mut node = MeshNode{
             id,
             ipaddr,
+            ipv6addr,
             radio,
             networktunnel,
             router,
+            dedup,
+            floods: FloodControl::new(opt.floodthreshold, Duration::from_millis(opt.floodwindow)).with_delay(opt.flooddelay),
+            acks: AckCoalescer::new(Duration::from_millis(opt.ackdelay)),
+            acktracker: AckTracker::new(Duration::from_millis(opt.acktimeout)),
+            delivery: DeliveryTracker::new(
+                Duration::from_millis(opt.hoptimeout),
+                opt.hopretries,
+                Duration::from_millis(opt.routewait)),
+            frameids: FrameIdAllocator::new(),
+            control: None,
+            pings: Vec::new(),
+            traces: Vec::new(),
+            traceresults: HashMap::new(),
+            traceseq: 0,
+            texts: Vec::new(),
+            textseq: 0,
+            textlisteners: Vec::new(),
+            ports: HashMap::new(),
+            datagramports: HashMap::new(),
+            leases,
+            leaserenew: None,
+            files,
+            filereplies: HashMap::new(),
+            gatewaysent: None,
+            networksent: None,
+            networkchecked: None,
+            bestgateway: None,
+            // a failed hop takes all retries to show, and as long again to come back from further away
+            gatewaybacklog: GatewayBacklog::new(Duration::from_millis(opt.hoptimeout * (opt.hopretries as u64 + 1) * 2)),
+            clock: MeshClock::new(Duration::from_millis(opt.timesyncinterval * 2)),
+            timesyncsent: None,
+            iprequested: None,
+            gps: None,
+            positiontracker,
+            positions: PositionTable::new(),
+            nodeinfo,
+            nodeinfos: NodeInfoTable::new(),
+            inforequests: KeyedLimiter::new(heartbeatinterval * 10),
+            telemetry: TelemetryTable::new(),
+            links,
+            adr: if opt.adaptivedatarate {
+                Some(AdaptiveDataRate::new(opt.adrminsf, opt.radiosf, opt.adrsnrmargin, opt.adrhysteresis))
+            } else {
+                None
+            },
+            adrpending: None,
+            dutycycle: if opt.dutycycle {
+                Some(DutyCycle::new(opt.dutycycleperiod, opt.dutycycleawake, opt.dutycycleguard,
+                    Duration::from_millis(opt.dutycycleresync)))
+            } else {
+                None
+            },
+            reservations: if opt.reservation {
+                Some(Reservations::new(id, opt.reservationthreshold,
+                    Duration::from_millis(opt.reservationmax), Duration::from_millis(opt.reservationwait)))
+            } else {
+                None
+            },
+            // copies of a request stop coming in as soon as duplicate frames do
+            dsr: if opt.routing == RoutingMode::Dsr {
+                Some(DsrRouter::new(id, opt.maxhops, opt.dsrcachesize, Duration::from_millis(opt.dedupttl)))
+            } else {
+                None
+            },
+            partitions: PartitionDetector::new(
+                Duration::from_millis(opt.partitionwindow),
+                opt.partitionthreshold,
+                Duration::from_millis(opt.partitionboost)),
+            hellosent: Instant::now(),
+            topologychanged: false,
+            // loops the duplicate filter missed are caught as long as it should have caught them
+            loops: LoopGuard::new(Duration::from_millis(opt.dedupttl)),
+            // a sender silent that long may have restarted without us hearing it
+            antireplay: AntiReplayWindow::new(Duration::from_millis(opt.neighbortimeout)),
+            claimwatch: None,
+            claimheard: false,
+            collisions: CollisionDetector::new(),
+            remote,
+            config,
+            configkey,
+            framekey,
+            requiresigned: opt.requiresigned,
+            store,
+            heldnotices: KeyedLimiter::new(Duration::from_millis(opt.heartbeatinterval)),
+            remotenonce: 0,
+            remotereplies: HashMap::new(),
+            staleroutes,
+            statesaved: Instant::now(),
+            lsaseq: 0,
+            lsanext: Instant::now(),
+            routeerrors: KeyedLimiter::new(Duration::from_millis(opt.routeerrorinterval)),
+            mesherrors: KeyedLimiter::new(Duration::from_millis(opt.routeerrorinterval)),
+            errorsubscribers: Vec::new(),
+            discoveries: KeyedLimiter::new(Duration::from_millis(opt.routeerrorinterval)),
+            discoverycosts: DiscoveryCosts::new(Duration::from_millis(opt.dedupttl)),
+            telemetryfields,
+            telemetrysent: Instant::now(),
+            rxframes: 0,
+            rxdropped: 0,
+            lastrx: Instant::now(),
+            heartbeatseq: 0,
+            vddmv: None,
+            iptraffic: IpTraffic::default(),
+            headercomp: HeaderCompression::new(),
+            // the settings were validated, the rules parse
+            filter: PacketFilter::parse(&opt.tunfilter).expect("Invalid tunnel filter"),
+            dns,
+            addressclaims: AddressClaims::new(Duration::from_millis(opt.broadcastinterval.max(opt.broadcastmaxinterval) * 3)),
+            meshsubnet,
+            resolver,
+            started: Instant::now(),
+            silencewarned: false,
+            io: None,
+            neighbors: NeighborTable::new(Duration::from_millis(opt.neighbortimeout)),
+            routes,
+            beacon: None,
+            emergencies: EmergencyTable::new(),
+            emergencyallowance: EmergencyAllowance::new(opt.emergencyallowance),
+            emergencylisteners: Vec::new(),
+            lastfix: None,
             opt,
+        };
+        if let Some(state) = saved {
+            node.restore_state(state);
         }
+        node
+    }
+
+    /// Accept commands from a control socket while running
+    pub fn attach_control(&mut self, control: Receiver<ControlRequest>) {
+        self.control = Some(control);
+    }
+
+    /// Announce positions read from a GPS receiver
+    pub fn attach_gps(&mut self, gps: Receiver<GpsFix>) {
+        self.gps = Some(gps);
+    }
+
+    /// Receive the sender and payload of data sent to a port of this node
+    pub fn recv_on_port(&mut self, port: u8) -> Receiver<(u8, Vec<u8>)> {
+        let (sender, receiver) = unbounded();
+        self.ports.entry(port).or_insert_with(Vec::new).push(sender);
+        receiver
+    }
+
+    /// Receive the sender and payload of datagrams sent to a port of this node
+    pub fn recv_datagrams(&mut self, port: u16) -> Receiver<(u8, Vec<u8>)> {
+        let (sender, receiver) = unbounded();
+        self.datagramports.entry(port).or_insert_with(Vec::new).push(sender);
+        receiver
     }
 
     /// Main loop, discover network and send/receive packets
     pub fn run(&mut self) {
-        // random number generator for frame IDs
-        let mut rng = thread_rng();
+        self.start();
+        loop {
+            self.poll();
+        }
+    }
 
-        // update the router if we are a gateway
-        if self.opt.isgateway {
+    /// Start tunnel and radio i/o, does nothing if already started
+    pub fn start(&mut self) {
+        if self.io.is_some() {
+            return;
+        }
+        // update the router if we are a gateway or have our address already
+        if self.opt.isgateway || self.opt.staticip.is_some() {
             self.router.handle_ip_assignment(&self.ipaddr.unwrap());
+        }
+        if self.opt.isgateway {
             self.router.handle_gateway_assignment(&self.ipaddr.unwrap());
         }
 
@@ -82,345 +583,3923 @@ impl MeshNode {
         // start radio i/o
         let (rxreader, txsender) = self.radio.run();
         // rate limiters for different tasks
-        let mut broadcastlimiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(1u32), Duration::from_secs(rng.gen_range(40, 80)));
-        let mut mstlimiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(1u32), Duration::from_secs(240));
+        let periodic = PeriodicScheduler::new(
+            Duration::from_millis(self.opt.broadcastinterval),
+            Duration::from_millis(self.opt.heartbeatinterval),
+            Duration::from_millis(self.opt.txslot))
+            .with_jitter(self.opt.broadcastjitter)
+            .with_start_delay(Duration::from_millis(self.opt.broadcastdelay))
+            // 0 is below the interval and keeps it fixed
+            .with_max_interval(Duration::from_millis(self.opt.broadcastmaxinterval));
+        let mstlimiter = DirectRateLimiter::<LeakyBucket>::new(nonzero!(1u32), Duration::from_secs(240));
 
-        // hashmap for storing incomplete chunks
-        let mut rxchunks: HashMap<String, Vec<Frame>> = HashMap::new();
+        self.io = Some(NodeIo {
+            tunreader,
+            rxreader,
+            txsender,
+            periodic,
+            mstlimiter,
+            rxchunks: HashMap::new(),
+        });
 
-        loop {
-            // handle packets coming from tunnel
-            // pull the next packet from the receiver, process it, and determine if we
-            // need to forward it to the radio
-            let r = tunreader.try_recv();
-            match r {
-                Err(e) => {
-                    if e.is_disconnected() {
-                        r.unwrap(); // other threads crashed
-                        panic!("Network tunnel crashed: {}", e);
-                    }
-                    // Otherwise - nothing to write, go on through.
-                },
-                Ok(data) => {
-                    // apply routing logic
-                    // if it cannot be routed, drop it
-                    self.handle_tun_ip(rng, data, &txsender);
-                },
+        // routes from before the restart are only used once a ping gets through
+        let timestamp = unix_millis();
+        let mut unconfirmed = std::mem::replace(&mut self.staleroutes, Vec::new());
+        unconfirmed.extend(self.routes.unconfirmed());
+        for (dest, route) in unconfirmed {
+            trace!("Checking route {:?} to {} from before the restart", &route, &dest);
+            match PingMessage::new(0, timestamp).to_frame(self.frameids.allocate(Some(dest)), self.id, route) {
+                Ok(mut ping) => self.tx_logged(ping.to_bytes(), TxPriority::High),
+                Err(e) => error!("Could not build PingMessage: {}", e),
             }
+        }
 
-            // now handle packets coming from radio
-            // parse the frame, and match against message type to
-            // determine if it goes to our tunnel
-            // or if it is routed to another node
-            let r = rxreader.try_recv();
-            match r {
-                Err(e) => {
-                    if e.is_disconnected() {
-                        r.unwrap(); // other threads crashed
-                        panic!("Network tunnel crashed: {}", e);
-                    }
-                    // Otherwise - nothing to write, go on through.
-                },
-                Ok(data) => {
-                    match Frame::from_bytes(&data) {
-                        Err(e) => {
-                            debug!("Dropping radio frame {}", e);
-                        },
-                        Ok(mut frame) => {
-                            trace!("Received frame txflag {} frameid {} sender {} routes {}", &frame.txflag().to_u8(), &frame.frameid(), &frame.sender(), &frame.routeoffset());
-                            let sender = frame.sender();
-                            let frameid = frame.frameid();
-                            // if this is a chunked packet, save the chunk
-                            // in the hashmap and come back to it
-                            if frame.txflag().more_chunks() {
-                                match rxchunks.get_mut(&composite_key(&sender,&frameid)) {
-                                    None => {
-                                        let mut chunks = Vec::new();
-                                        chunks.push(frame);
-                                        rxchunks.insert(composite_key(&sender,&frameid), chunks);
-                                    },
-                                    Some(chunks) => {
-                                        chunks.push(frame);
-                                    }
+        // tell the mesh who we are
+        match self.radio.query("sys get ver").and_then(|resp| parse_version(&resp)) {
+            Err(e) => warn!("Could not read the radio firmware version: {}", e),
+            Ok(device) => {
+                self.nodeinfo.hardware = HardwareModel::from_version(&device.version);
+                self.nodeinfo.firmware = device.version;
+            }
+        }
+        let mut msg = NodeInfoMessage::new(self.nodeinfo.clone());
+        msg.announce = true;
+        match msg.to_frame(self.frameids.allocate(None), self.id, vec![self.id]) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::for_message(&MessageType::NodeInfo)),
+            Err(e) => error!("Could not build NodeInfoMessage: {}", e),
+        }
+    }
+
+    /// Run one pass of the main loop
+    pub fn poll(&mut self) {
+        // taken out while polling so handlers can borrow the node
+        let mut io = self.io.take().expect("Node must be started before polling");
+
+        // handle packets coming from tunnel
+        // pull the next packet from the receiver, process it, and determine if we
+        // need to forward it to the radio
+        let r = io.tunreader.try_recv();
+        match r {
+            Err(e) => {
+                if e.is_disconnected() {
+                    r.unwrap(); // other threads crashed
+                    panic!("Network tunnel crashed: {}", e);
+                }
+                // Otherwise - nothing to write, go on through.
+            },
+            Ok(data) => {
+                // apply routing logic
+                // if it cannot be routed, drop it
+                self.handle_tun_ip(data);
+            },
+        }
+
+        // now handle packets coming from radio
+        // parse the frame, and match against message type to
+        // determine if it goes to our tunnel
+        // or if it is routed to another node
+        let r = io.rxreader.try_recv();
+        match r {
+            Err(e) => {
+                if e.is_disconnected() {
+                    r.unwrap(); // other threads crashed
+                    panic!("Network tunnel crashed: {}", e);
+                }
+                // Otherwise - nothing to write, go on through.
+            },
+            Ok(packet) => {
+                self.rxframes += 1;
+                self.lastrx = Instant::now();
+                match self.accept_frame(&packet.data) {
+                    Err(e) => {
+                        debug!("Dropping radio frame {}", e);
+                        self.rxdropped += 1;
+                    },
+                    Ok(mut frame) => {
+                        trace!("Received frame txflag {} frameid {} sender {} routes {}", &frame.txflag().to_u8(), &frame.frameid(), &frame.sender(), &frame.routeoffset());
+                        let sender = frame.sender();
+                        let frameid = frame.frameid();
+                        if self.claimwatch == Some(sender) {
+                            self.claimheard = true;
+                        }
+                        // if this is a chunked packet, save the chunk
+                        // in the hashmap and come back to it
+                        if frame.txflag().more_chunks() {
+                            match io.rxchunks.get_mut(&composite_key(&sender,&frameid)) {
+                                None => {
+                                    let mut chunks = Vec::new();
+                                    chunks.push(frame);
+                                    io.rxchunks.insert(composite_key(&sender,&frameid), chunks);
+                                },
+                                Some(chunks) => {
+                                    chunks.push(frame);
                                 }
-                            } else {
-                                // do we need to recombine previous chunks?
-                                match rxchunks.remove(&composite_key(&sender,&frameid)) {
-                                    None => {},
-                                    Some(mut chunks) => {
-                                        trace!("Recombining {} chunks", &chunks.len()+1);
-                                        let header = frame.header();
-                                        chunks.push(frame); // push final frame
-                                        trace!("First chunk flag {}", &chunks[0].txflag().to_u8());
-                                        frame = recombine_chunks(chunks, header);
-                                    }
+                            }
+                        } else {
+                            // do we need to recombine previous chunks?
+                            match io.rxchunks.remove(&composite_key(&sender,&frameid)) {
+                                None => {},
+                                Some(mut chunks) => {
+                                    trace!("Recombining {} chunks", &chunks.len()+1);
+                                    let header = frame.header();
+                                    chunks.push(frame); // push final frame
+                                    trace!("First chunk flag {}", &chunks[0].txflag().to_u8());
+                                    frame = recombine_chunks(chunks, header);
                                 }
-                                // TODO some things here depend if node is gateway
-                                match frame.msgtype() {
-                                    // received IP packet, handle it
-                                    MessageType::IPPacket => {
-                                        debug!("Recieved IP packet from {}", &frame.sender());
-                                        match IPPacketMessage::from_frame(&mut frame) {
-                                            Err(e) => { error!("Dropping invalid IPv4 packet message {}", e); },
-                                            Ok(msg) => {
-                                                let packet = msg.packet();
-                                                self.handle_radio_ip(packet, frame, &txsender);
-                                            }
-                                        }
-                                    },
-                                    // process another node's broadcast
-                                    MessageType::Broadcast => {
-                                        match BroadcastMessage::from_frame(frame.borrow_mut()) {
-                                            Err(e) => error!("Could not parse BroadcastMessage: {}", e),
-                                            Ok(broadcast) => {
-                                                debug!("Received broadcast from {} {:?}", &frame.sender(), broadcast.clone().ipaddr);
-                                                // we aren't a gateway, we should rebroadcast this
-                                                if !self.opt.isgateway && !frame.route().contains(&self.id) {
-                                                    frame.route_unshift(self.id.clone());
-                                                    txsender.send(frame.to_bytes());
-                                                }
-                                                // we need an IP to operate properly
-                                                if self.ipaddr.is_some() {
-                                                    // add route to IP if new observation and we aren't a gateway
-                                                    if &frame.sender() != &self.id && !self.opt.isgateway {
-                                                        if broadcast.ipaddr.is_some() {
-                                                            let ip = broadcast.ipaddr.unwrap().clone();
-                                                            match self.router.node_observe_get(&frame.sender()) {
-                                                                Some(_) => {},
-                                                                None => {
-                                                                    info!("Broadcast received from node {}, routing IP {}", &frame.sender(), &ip.to_string());
-                                                                    self.networktunnel.routeipaddr(&ip, &self.ipaddr.unwrap());
-                                                                    // TODO should we put broadcast handler here and refactor gateway logic?
-                                                                }
-                                                            }
-                                                        }
-                                                    };
-                                                    // let our router handle the broadcast and add route to IP if we are a gateway
-                                                    match self.router.handle_broadcast(broadcast, frame.route()) {
-                                                        Err(e) => {
-                                                            error!("Failed to assign IP to broadcast from {}", &frame.sender());
-                                                            // ip address assignment failed, notify the source
-                                                            let mut route: Vec<u8> = Vec::new();
-                                                            if frame.route().len() > 0 {
-                                                                route = frame.route().clone(); // this was multi-hop, send it back
-                                                            } else {
-                                                                route.push(frame.sender());
-                                                            }
-                                                            let bytes = e.to_frame(rng.gen_range(1u8, 244u8), self.id, route).to_bytes();
-                                                            txsender.send(bytes);
-                                                        },
-                                                        Ok(ip) => {
-                                                            match ip {
-                                                                None => (), // no response, we know this node already
-                                                                Some((ipaddr, isnew)) => {
-                                                                    info!("Sending IP {} to node {}", ipaddr.to_string(), frame.sender());
-
-                                                                    // tell the node of their new IP address
-                                                                    let mut route: Vec<u8> = Vec::new();
-                                                                    if frame.route().len() > 0 {
-                                                                        route = frame.route().clone(); // this was multi-hop, send it back
-                                                                    } else {
-                                                                        route.push(frame.sender());
-                                                                    }
-                                                                    let bits = IPAssignSuccessMessage::new(ipaddr).to_frame(rng.gen_range(1u8, 244u8), self.id, route).to_bytes();
-                                                                    txsender.send(bits);
-
-                                                                    // since we are a gateway, we must route the IP locally
-                                                                    if isnew {
-                                                                        info!("Broadcast received from node {}, assigned new IP {}", &frame.sender(), &ipaddr.to_string());
-                                                                        self.networktunnel.routeipaddr(&ipaddr, &self.ipaddr.unwrap());
-                                                                    }
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    },
-                                    // we were successfully assigned an IP
-                                    MessageType::IPAssignSuccess => {
-                                        match frame.route_shift() {
-                                            None => error!("Received invalid IP message with no destination"),
-                                            Some(nexthop) => {
-                                                if nexthop == self.id { // is it for us? drop if not
-                                                    if frame.route().len() == 0 {
-                                                        match IPAssignSuccessMessage::from_frame(frame.borrow_mut()) {
-                                                            Err(e) => error!("Could not parse IPAssignSuccessMessage: {}", e),
-                                                            Ok(message) => {
-                                                                info!("Received new IP address {} from gateway {}", &message.ipaddr.to_string(), &frame.sender());
-                                                                self.handle_ip_assignment(message.ipaddr);
-                                                            }
-                                                        }
-                                                    }
-                                                    if frame.route().len() > 0 { // retransmit to next hop
-                                                        txsender.send(frame.to_bytes());
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    },
-                                    // we sent a broadcast without IP, but got a failure
-                                    MessageType::IPAssignFailure => {
-                                        match frame.route_shift() {
-                                            None => error!("Received invalid IP message with no destination"),
-                                            Some(nexthop) => {
-                                                if nexthop == self.id { // is it for us? drop if not
-                                                    if frame.route().len() == 0 {
-                                                        match IPAssignFailureMessage::from_frame(frame.borrow_mut()) {
-                                                            Err(e) => error!("Could not parse IPAssignFailureMessage: {}", e),
-                                                            Ok(message) => error!("Failed to be assigned IP: {}", message.reason)
-                                                        }
-                                                    }
-                                                    if frame.route().len() > 0 { // retransmit to next hop
-                                                        txsender.send(frame.to_bytes());
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    },
-                                    // handle route discovery
-                                    // TODO: refactor out old message architecture
-                                    MessageType::RouteDiscovery => {},
-                                    MessageType::RouteSuccess => {},
-                                    MessageType::RouteFailure => {},
-                                    MessageType::TransmitRequest => {},
-                                    MessageType::TransmitConfirm => {},
+                            }
+                            // duplicates are acked again, the first ack may have been lost
+                            self.queue_ack(&mut frame, packet.quality);
+                            self.neighbor_heard(&mut frame, packet.quality);
+                            self.instance_heard(&mut frame);
+                            if self.opt.partitionwindow > 0 && sender != self.id {
+                                self.partitions.heard(sender);
+                            }
+                            if self.delivery.overheard(&mut frame) {
+                                trace!("Next hop sent frame {} from {} on", &frameid, &sender);
+                            }
+                            // floods end with their sender, anything else with its destination
+                            let dest = frame.route().last().cloned().filter(|dest| *dest != sender);
+                            // a later copy of a route discovery may have come a cheaper way
+                            let duplicate = self.dedup.is_duplicate(frame.sender(), dest, frame.frameid(), frame.msgtype().to_u8());
+                            if duplicate && frame.msgtype() != MessageType::RouteDiscovery {
+                                trace!("Dropping duplicate frame {} from {}", &frameid, &sender);
+                                if self.floods.overheard(&dedup_key(&mut frame)) {
+                                    trace!("Frame {} from {} is covered, not sending it on", &frameid, &sender);
                                 }
+                            } else if !duplicate && !self.antireplay.check_and_advance(sender, dest, frameid) {
+                                debug!("Dropping replayed frame {} from {}", &frameid, &sender);
+                                self.rxdropped += 1;
+                            } else {
+                                self.handle_frame(frame, packet.quality, &io.txsender);
                             }
                         }
                     }
                 }
             }
+        }
 
-            // now handle any protocol tasks
-            // such as broadcasts or route discovery
-            if broadcastlimiter.check().is_ok() {
+        // now handle any protocol tasks
+        // such as broadcasts or route discovery
+        if self.partition_tick() {
+            let interval = Duration::from_millis(self.opt.broadcastinterval) / PARTITION_SPEEDUP;
+            io.periodic.accelerate(interval, Duration::from_millis(self.opt.partitionboost));
+        }
+        if std::mem::take(&mut self.topologychanged) {
+            io.periodic.topology_changed();
+        }
+        // while asleep periodic tasks wait, they come due in the next window
+        let awake = self.duty_tick();
+        match if awake { io.periodic.next_task() } else { None } {
+            Some(PeriodicTask::Broadcast) => {
                 debug!("Sending broadcast to nearby nodes");
                 self.broadcast();
-            }
+                // asking the radio interrupts receiving, too often to do for every heartbeat
+                self.vddmv = self.radio.query("sys get vdd").and_then(|resp| parse_vdd(&resp)).ok();
+            },
+            Some(PeriodicTask::Heartbeat) => {
+                trace!("Sending heartbeat to nearby nodes");
+                self.heartbeat();
+                self.adr_tick();
+                let mut silent = self.router.expire_neighbors(Duration::from_millis(self.opt.neighbortimeout));
+                for nodeid in &silent {
+                    info!("Neighbor {} expired, nothing heard from it", nodeid);
+                }
+                silent.extend(self.neighbors.expire());
+                silent.sort();
+                silent.dedup();
+                self.topologychanged |= !silent.is_empty();
+                for nodeid in silent {
+                    if let Some(adr) = self.adr.as_mut() {
+                        adr.forget(nodeid);
+                    }
+                    for dest in self.routes.remove_via(nodeid) {
+                        debug!("Lost the last route to {} with neighbor {}, rediscovering", dest, nodeid);
+                        self.rediscover(dest);
+                    }
+                }
+            },
+            None => {},
+        }
 
-            // clean up the mesh graph to optimize
-            // routing and performance
-            if mstlimiter.check().is_ok() {
-                debug!("Applying minimum spanning tree to mesh router");
-                self.router.min_spanning_tree();
-            }
+        // commands from the control socket
+        self.handle_control();
+        self.emergency_tick();
+        self.ping_tick(&io.txsender);
+        self.trace_tick();
+        self.text_tick();
+        self.remote_tick();
+        self.store_tick();
+        self.gps_tick();
+        self.ip_tick();
+        self.dns_tick();
+        self.resolve_tick();
+        self.file_tick();
+        self.ack_tick();
+        self.delivery_tick();
+        self.reservation_tick();
+        // routing maintenance only happens while the others are awake to hear it
+        if awake {
+            self.gateway_tick();
+            self.network_tick();
+            self.hello_tick();
+            self.timesync_tick();
+            self.telemetry_tick();
+            self.lsa_tick();
         }
+        self.flood_tick();
+        self.route_tick();
+        self.state_tick();
+        self.silence_tick();
+
+        // clean up the mesh graph to optimize
+        // routing and performance
+        if io.mstlimiter.check().is_ok() {
+            debug!("Applying minimum spanning tree to mesh router");
+            self.router.min_spanning_tree();
+        }
+
+        self.io = Some(io);
     }
 
-    /// Handle an IP assignment
-    /// ensures a new local route is set up and node
-    /// accepts new IP
-    // TODO recover if new IP is different than old
-    fn handle_ip_assignment(&mut self, ipaddr: Ipv4Addr) {
-        if self.ipaddr.is_none() {
-            self.ipaddr = Some(ipaddr);
-            self.networktunnel.assignipaddr(&ipaddr);
-            self.networktunnel.routeipaddr(&ipaddr, &self.networktunnel.tunip.unwrap());
-            self.router.handle_ip_assignment(&ipaddr);
+    /// Parse and authenticate a frame from the radio, telling its sender when it is refused
+    fn accept_frame(&mut self, data: &Vec<u8>) -> io::Result<Frame> {
+        if let Some((sender, frameid, error)) = frame_error(data) {
+            self.send_error(sender, frameid, error);
+            return Err(Error::new(ErrorKind::InvalidData, error.to_string()));
         }
+        let mut frame = Frame::from_bytes(data)?;
+        let (sender, frameid, msgtype) = (frame.sender(), frame.frameid(), frame.msgtype());
+        let authenticated = self.authenticate(frame);
+        // two nodes with different keys would answer each other's errors forever
+        if authenticated.is_err() && msgtype != MessageType::Error {
+            self.send_error(sender, frameid, MeshError::DecryptionFailed);
+        }
+        authenticated
     }
 
-    /// Handle routing of a tunnel packet
-    /// checks if packet was destinated for this node or if
-    /// routing logic should be applied and forwarding necessary
-    fn handle_tun_ip(&mut self, mut framerng: ThreadRng, packet: Packet<Vec<u8>>, txsender: &Sender<Vec<u8>>) {
-        // apply routing logic
-        // if it cannot be routed, drop it
-        if self.ipaddr.is_some() {
-            if packet.destination().eq(&self.ipaddr.unwrap()) {
-                debug!("Received packet from {}", packet.source());
-                if !self.opt.debug {
-                    // TODO route to tunnel during debug
-                    self.networktunnel.send(packet);
+    /// Check the tag of a frame from the radio and take it off, then decrypt it
+    /* Chunks are signed and encrypted one by one, so they are checked
+    before they are recombined. Without a framekey signed frames are taken
+    as they are. An encrypted frame must be signed, the tag is what
+    authenticates it. */
+    fn authenticate(&self, frame: Frame) -> io::Result<Frame> {
+        let frame = match (&self.framekey, frame.signed()) {
+            (Some(key), true) => frame.verify(key).map(|_| frame.without_signature())?,
+            (_, false) if self.requiresigned || self.opt.encryption || frame.encrypted() =>
+                return Err(Error::new(ErrorKind::PermissionDenied, "frame is not signed")),
+            _ => frame.without_signature(),
+        };
+        match (&self.framekey, frame.encrypted()) {
+            (Some(key), true) => frame.decrypt(key),
+            (None, true) => Err(Error::new(ErrorKind::PermissionDenied, "frame is encrypted and we have no framekey")),
+            (_, false) if self.opt.encryption => Err(Error::new(ErrorKind::PermissionDenied, "frame is not encrypted")),
+            _ => Ok(frame),
+        }
+    }
+
+    /// Handle a complete frame from the radio
+    /// matches against the message type to determine if it goes
+    /// to our tunnel or if it is routed to another node
+    fn handle_frame(&mut self, mut frame: Frame, quality: LinkQuality, txsender: &TxQueueSender) {
+        // TODO some things here depend if node is gateway
+        match frame.msgtype() {
+            // received IP packet, handle it
+            MessageType::IPPacket => {
+                debug!("Recieved IP packet from {}", &frame.sender());
+                if self.accept_routed(&mut frame, txsender, TxPriority::Low) {
+                    match IPPacketMessage::from_frame(&mut frame) {
+                        Err(e) => { error!("Dropping invalid IP packet message {}", e); },
+                        Ok(msg) => self.handle_radio_ip(frame.sender(), msg.packet()),
+                    }
                 }
-            }
-            else {
-                // look up a route for this destination IP
-                // then send it in chunks if necessary
-                match self.router.packet_route(&packet) {
-                    None => {
-                        trace!("Dropping packet to: {}", packet.destination());
-                        drop(packet);
+            },
+            // received IP packet with compressed headers, put it back together
+            MessageType::CompressedIPPacket => {
+                debug!("Recieved compressed IP packet from {}", &frame.sender());
+                if self.accept_routed(&mut frame, txsender, TxPriority::Low) {
+                    match CompressedIPPacketMessage::from_frame(&mut frame) {
+                        Err(e) => error!("Dropping invalid compressed IP packet message {}", e),
+                        Ok(msg) => self.handle_compressed_ip(&mut frame, *msg),
+                    }
+                }
+            },
+            // process another node's broadcast
+            MessageType::Broadcast => {
+                match BroadcastMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse BroadcastMessage: {}", e),
+                    Ok(broadcast) => {
+                        debug!("Received broadcast from {} {:?}", &frame.sender(), broadcast.clone().ipaddr);
+                        // the first hop in the route is the neighbor we heard it from
+                        let lasthop = frame.route().first().cloned().unwrap_or(frame.sender());
+                        self.router.neighbor_seen(lasthop);
+                        // broadcasts stand in for a heartbeat when heard first hand
+                        if frame.route().len() <= 1 {
+                            self.links.record(frame.sender());
+                            if let Some(rssi) = quality.rssi {
+                                self.links.record_rssi(frame.sender(), rssi);
+                            }
+                        }
+                        // the way it came leads back to its sender
+                        let route = frame.route();
+                        if frame.sender() != self.id && self.loops.path_ok(&route, self.id) {
+                            let cost = self.path_cost(&route);
+                            self.routes.add(frame.sender(), route, cost, RouteSource::Learned);
+                        }
+                        // we aren't a gateway, we should rebroadcast this
+                        if !self.opt.isgateway && !frame.route().contains(&self.id) && frame.route_unshift(self.id.clone()).is_ok() {
+                            self.relay_flood(&mut frame, TxPriority::Normal, quality.rssi);
+                        }
+                        if let (Some(ip), true) = (broadcast.ipaddr, frame.sender() != self.id) {
+                            self.claim_address(frame.sender(), ip);
+                            self.learn_address(ip, frame.sender());
+                        }
+                        // add route to IP if new observation and we aren't a gateway
+                        if self.ipaddr.is_some() && &frame.sender() != &self.id && !self.opt.isgateway {
+                            if broadcast.ipaddr.is_some() {
+                                let ip = broadcast.ipaddr.unwrap().clone();
+                                match self.router.node_observe_get(&frame.sender()) {
+                                    Some(_) => {},
+                                    None => {
+                                        info!("Broadcast received from node {}, routing IP {}", &frame.sender(), &ip.to_string());
+                                        self.networktunnel.routeipaddr(&ip, &self.ipaddr.unwrap());
+                                    }
+                                }
+                            }
+                        };
+                        // let our router learn the route, and the gateway even before we have an IP
+                        self.router.handle_broadcast(broadcast, frame.route());
+                        self.notify_held(frame.sender());
+                    }
+                }
+            },
+            // we were successfully assigned an IP
+            MessageType::IPAssignSuccess => {
+                match frame.route_shift() {
+                    None => error!("Received invalid IP message with no destination"),
+                    Some(nexthop) => {
+                        if nexthop == self.id { // is it for us? drop if not
+                            if frame.route().len() == 0 {
+                                match IPAssignSuccessMessage::from_frame(frame.borrow_mut()) {
+                                    Err(e) => error!("Could not parse IPAssignSuccessMessage: {}", e),
+                                    Ok(message) => {
+                                        info!("Received IP address {} from gateway {}", &message.ipaddr.to_string(), &frame.sender());
+                                        self.handle_ip_assignment(message.ipaddr, message.leasetime, message.resolver);
+                                    }
+                                }
+                            }
+                            if frame.route().len() > 0 { // retransmit to next hop
+                                txsender.send_logged(frame.to_bytes(), TxPriority::Normal);
+                            }
+                        }
+                    }
+                }
+            },
+            // a node looking for the holder of a mesh address
+            MessageType::AddressQuery => {
+                match AddressQueryMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse AddressQueryMessage: {}", e),
+                    Ok(query) => self.handle_address_query(&mut frame, *query, quality, txsender),
+                }
+            },
+            // the holder of a mesh address, learned by every node it passes
+            MessageType::AddressAnswer => {
+                match AddressAnswerMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse AddressAnswerMessage: {}", e),
+                    Ok(answer) => {
+                        if frame.sender() != self.id {
+                            self.learn_address(answer.ipaddr, frame.sender());
+                        }
+                        self.accept_routed(&mut frame, txsender, TxPriority::Normal);
                     },
-                    Some(route) => {
-                        let message = IPPacketMessage::new(packet);
-                        let chunks = message.to_frame(framerng.gen_range(1, 244) as u8, self.id.clone(), route).chunked(&self.opt.maxpacketsize);
-                        for chunk in chunks {
-                            trace!("Sending chunk");
-                            txsender.send(chunk);
+                }
+            },
+            // a node asking us for an address, if we are the gateway
+            MessageType::IPRequest => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                    match IPRequestMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse IPRequestMessage: {}", e),
+                        Ok(request) => self.handle_ip_request(frame.sender(), request.ipaddr, txsender),
+                    }
+                }
+            },
+            // a file transfer to or from us
+            MessageType::FileOffer | MessageType::FileChunk | MessageType::FileAck | MessageType::FileComplete => {
+                let priority = TxPriority::for_message(&frame.msgtype());
+                if self.accept_routed(&mut frame, txsender, priority) {
+                    match FileMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse file transfer message: {}", e),
+                        Ok(msg) => {
+                            let replies = self.files.handle(frame.sender(), msg, Instant::now());
+                            self.send_file_messages(replies);
+                            self.handle_file_events();
                         }
                     }
                 }
-            }
-        }
-    }
-
-    /// Handle routing of an IP packet from radio
-    /// checks if packet was destined for this node or if
-    /// it should be passed to the next hop
-    fn handle_radio_ip(&mut self, packet: Packet<Vec<u8>>, frame: Frame, txsender: &Sender<Vec<u8>>) {
-        // apply routing logic
-        // was this packet meant for us? if not, drop
-        match self.ipaddr {
-            None => {
-                self.handle_ip_nexthop(packet, frame, txsender);
             },
-            Some(ipaddr) => {
-                if packet.destination().eq(&ipaddr) {
-                    trace!("Forwarding IP packet from {} to local network", packet.source());
-                    self.networktunnel.send(packet);
-                } else {
-                    trace!("Forwarding IP packet from {} to next hop", packet.source());
-                    self.handle_ip_nexthop(packet, frame, txsender);
+            // confirmation of frames we asked an ack for
+            MessageType::Ack => {
+                match AckMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse AckMessage: {}", e),
+                    Ok(ack) => {
+                        // passing through, it confirms the last hop of the frames it names
+                        let frameids: Vec<u8> = ack.acks.iter().map(|entry| entry.frameid).collect();
+                        self.delivery.acked(frame.sender(), ack.origin, &frameids);
+                        if self.accept_routed(&mut frame, txsender, TxPriority::High) {
+                            let acked = self.acktracker.handle(frame.sender(), &ack);
+                            for frameid in &acked {
+                                trace!("Frame {} acked by {}", frameid, &frame.sender());
+                                self.frameids.acknowledge(Some(frame.sender()), *frameid);
+                                self.delivery.publish(DeliveryEvent::Delivered { dest: frame.sender(), frameid: *frameid });
+                            }
+                            // the route the frames took still works
+                            if !acked.is_empty() {
+                                self.routes.refresh(frame.sender());
+                            }
+                        }
+                    }
                 }
-            }
-        }
-    }
-
-    /// retransmit or drop an IP packet bound for another destination
-    fn handle_ip_nexthop(&mut self, packet: Packet<Vec<u8>>, mut frame: Frame, txsender: &Sender<Vec<u8>>) {
-        match frame.route_shift() {
-            // there wasn't a next hop, something's wrong
-            None => error!("Received an IP packet from {} with no route", &frame.sender()),
-            Some(nexthop) => {
-                if nexthop == self.id { panic!("Tried to transmit packet with local node destination"); }
-
-                // we can still forward it to another node id
-                if frame.route().len() > 0 {
-                    // chunk it
-                    let chunks = frame.chunked(&self.opt.maxpacketsize);
-                    for chunk in chunks {
-                        txsender.send(chunk);
+            },
+            // a gateway's uplink and load, flooded periodically
+            MessageType::GatewayAnnounce => {
+                // cost of the hop we heard it on
+                let lasthop = frame.route().first().cloned().unwrap_or(frame.sender());
+                let hop = self.link_cost(lasthop);
+                match GatewayAnnounceMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse GatewayAnnounceMessage: {}", e),
+                    Ok(_) if frame.sender() == self.id => {},
+                    Ok(mut msg) => {
+                        let cost = msg.cost as f32 / 100.0 + hop;
+                        if let Some(gateways) = self.router.gateways() {
+                            gateways.update(frame.sender(), msg.uplink, msg.load, cost);
+                        }
+                        // flood it on, adding the cost of the hop it took to us
+                        let route = frame.route();
+                        if !route.contains(&self.id) && (route.len() as u8) < self.opt.maxhops {
+                            msg.cost = (cost * 100.0).round().min(u16::MAX as f32) as u16;
+                            match msg.to_frame(frame.frameid(), frame.sender(), route) {
+                                Ok(mut relayed) => if relayed.route_unshift(self.id.clone()).is_ok() {
+                                    self.relay_flood(&mut relayed, TxPriority::Normal, quality.rssi);
+                                },
+                                Err(e) => error!("Could not relay GatewayAnnounceMessage: {}", e),
+                            }
+                        }
                     }
-                } else {
-                    error!("Dropping IP packet from {} to {}: no route available", &packet.source(), &packet.destination());
                 }
-            }
-        }
-    }
-
-    /// Send a broadcast packet to nearby nodes
-    fn broadcast(&mut self) {
-        // prepare broadcast
-        if self.radio.txsender.is_empty() {
-            let mut ipOffset = 0;
-            if self.ipaddr.is_some() {
-                ipOffset = 4;
-            }
-            let msg = BroadcastMessage {
-                header: None,
-                isgateway: self.opt.isgateway.clone(),
-                ipOffset,
-                ipaddr: self.ipaddr
-            };
-            let mut route: Vec<u8> = Vec::new();
-            route.push(self.id.clone());
-            let mut frame = msg.to_frame(1u8, self.id, route);
-            // dump
-            self.radio.txsender.send(frame.to_bytes());
-        }
-    }
-
-}
\ No newline at end of file
+            },
+            // a gateway telling us whether it reaches the internet
+            MessageType::NetworkAnnounce => {
+                // cost of the hop we heard it on
+                let lasthop = frame.route().first().cloned().unwrap_or(frame.sender());
+                let hop = self.link_cost(lasthop);
+                match NetworkAnnounceMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse NetworkAnnounceMessage: {}", e),
+                    Ok(_) if frame.sender() == self.id => {},
+                    Ok(mut msg) => {
+                        let metric = msg.metric.saturating_add((hop * 100.0).round() as u16);
+                        self.router.handle_network_announce(msg.gateway_id, msg.internet_reachable, metric);
+                        // flood it on, adding the cost of the hop it took to us
+                        let route = frame.route();
+                        if !route.contains(&self.id) && (route.len() as u8) < self.opt.maxhops {
+                            msg.metric = metric;
+                            match msg.to_frame(frame.frameid(), frame.sender(), route) {
+                                Ok(mut relayed) => if relayed.route_unshift(self.id).is_ok() {
+                                    self.relay_flood(&mut relayed, TxPriority::Normal, quality.rssi);
+                                },
+                                Err(e) => error!("Could not relay NetworkAnnounceMessage: {}", e),
+                            }
+                        }
+                    }
+                }
+            },
+            // the gateway's time, or a node asking the gateway for it
+            MessageType::TimeSync => {
+                // airtime of the hop we heard it on
+                let received = unix_millis();
+                let hop = airtime(frame.to_bytes().len(), self.opt.radiosf, self.opt.radiobw).as_millis() as u64;
+                match TimeSyncMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse TimeSyncMessage: {}", e),
+                    Ok(msg) if msg.request => {
+                        if self.accept_routed(&mut frame, txsender, TxPriority::Normal) && self.opt.isgateway {
+                            self.handle_timesync_request(frame.sender());
+                        }
+                    },
+                    // the gateway keeps its own time
+                    Ok(_) if frame.sender() == self.id || self.opt.isgateway => {},
+                    Ok(mut msg) => {
+                        let route = frame.route();
+                        self.handle_timesync(sync_offset(msg.time, msg.delay as u64 + hop, received), route.len() as u8);
+                        // flood it on, adding the delay of the hop it took to us
+                        if !route.contains(&self.id) && (route.len() as u8) < self.opt.maxhops {
+                            let held = unix_millis().saturating_sub(received);
+                            msg.delay = msg.delay.saturating_add((hop + held) as u32);
+                            match msg.to_frame(frame.frameid(), frame.sender(), route) {
+                                Ok(mut relayed) => if relayed.route_unshift(self.id.clone()).is_ok() {
+                                    txsender.send_logged(relayed.to_bytes(), TxPriority::High);
+                                },
+                                Err(e) => error!("Could not relay TimeSyncMessage: {}", e),
+                            }
+                        }
+                    }
+                }
+            },
+            // we asked the gateway for an IP, but got a failure
+            MessageType::IPAssignFailure => {
+                match frame.route_shift() {
+                    None => error!("Received invalid IP message with no destination"),
+                    Some(nexthop) => {
+                        if nexthop == self.id { // is it for us? drop if not
+                            if frame.route().len() == 0 {
+                                match IPAssignFailureMessage::from_frame(frame.borrow_mut()) {
+                                    Err(e) => error!("Could not parse IPAssignFailureMessage: {}", e),
+                                    Ok(message) => error!("Failed to be assigned IP, retrying: {}", message.reason)
+                                }
+                            }
+                            if frame.route().len() > 0 { // retransmit to next hop
+                                txsender.send_logged(frame.to_bytes(), TxPriority::Normal);
+                            }
+                        }
+                    }
+                }
+            },
+            // how well a neighbor hears us, only meant for direct neighbors
+            MessageType::NeighborHello => {
+                if frame.route() == vec![frame.sender()] {
+                    match NeighborHelloMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse NeighborHelloMessage: {}", e),
+                        Ok(hello) => {
+                            trace!("Received hello from {} with {} neighbors", &frame.sender(), hello.neighbors.len());
+                            self.router.neighbor_seen(frame.sender());
+                            if let Some(rssi) = quality.rssi {
+                                self.links.record_rssi(frame.sender(), rssi);
+                            }
+                            self.links.handle_hello(frame.sender(), self.id, &hello.neighbors);
+                        }
+                    }
+                }
+            },
+            // a neighbor proving it is still alive
+            MessageType::Heartbeat => {
+                trace!("Received heartbeat from {}", &frame.sender());
+                self.router.neighbor_seen(frame.sender());
+                if let Ok(msg) = HeartbeatMessage::from_frame(frame.borrow_mut()) {
+                    self.nodeinfos.heartbeat(frame.sender(), &msg);
+                    self.sf_announced(frame.sender(), msg.sf);
+                }
+                self.links.record(frame.sender());
+                if let Some(rssi) = quality.rssi {
+                    self.links.record_rssi(frame.sender(), rssi);
+                }
+                // ask neighbors we know nothing about who they are
+                if !self.nodeinfos.contains(frame.sender()) && self.inforequests.allow(frame.sender()) {
+                    self.send_nodeinfo(frame.sender(), true, txsender);
+                }
+            },
+            // who another node is, flooded after it booted or sent on request
+            MessageType::NodeInfo => {
+                match NodeInfoMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse NodeInfoMessage: {}", e),
+                    Ok(msg) if msg.announce => {
+                        if frame.sender() != self.id {
+                            self.handle_nodeinfo(frame.sender(), msg.info);
+                        }
+                        // flood it on until the hop limit
+                        let route = frame.route();
+                        if !route.contains(&self.id) && (route.len() as u8) < self.opt.maxhops && frame.route_unshift(self.id.clone()).is_ok() {
+                            self.relay_flood(&mut frame, TxPriority::Normal, quality.rssi);
+                        }
+                    },
+                    Ok(msg) => {
+                        if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                            self.handle_nodeinfo(frame.sender(), msg.info);
+                            if msg.request {
+                                self.send_nodeinfo(frame.sender(), false, txsender);
+                            }
+                        }
+                    }
+                }
+            },
+            // answer pings addressed to us
+            MessageType::Ping => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::High) {
+                    match PingMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse PingMessage: {}", e),
+                        Ok(ping) => {
+                            trace!("Ping {} from {}, answering", &ping.seq, &frame.sender());
+                            let route = self.route_to(frame.sender());
+                            match ping.pong().to_frame(self.frameids.allocate(Some(frame.sender())), self.id, route) {
+                                Ok(mut pong) => txsender.send_logged(pong.to_bytes(), TxPriority::High),
+                                Err(e) => error!("Could not build PongMessage: {}", e),
+                            }
+                        }
+                    }
+                }
+            },
+            // answer to one of our pings
+            MessageType::Pong => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::High) {
+                    match PongMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse PongMessage: {}", e),
+                        Ok(pong) => {
+                            if self.router.confirm_stale(frame.sender()) {
+                                debug!("Route to {} from before the restart still works", &frame.sender());
+                            }
+                            self.routes.confirm(frame.sender());
+                            self.handle_pong(frame.sender(), pong.seq, quality);
+                        },
+                    }
+                }
+            },
+            // record ourselves in a traceroute passing through
+            MessageType::Traceroute => {
+                match trace_hop(&mut frame, self.id, quality.rssi, self.clock.now()) {
+                    Err(e) => error!("Could not parse TracerouteMessage: {}", e),
+                    Ok(TraceAction::Ignore) => {},
+                    Ok(TraceAction::Forward(mut next)) => {
+                        txsender.send_logged(next.to_bytes(), TxPriority::High);
+                    },
+                    Ok(TraceAction::Reply { reply, route }) => {
+                        debug!("Answering traceroute from {}", &frame.sender());
+                        match reply.to_frame(self.frameids.allocate(Some(frame.sender())), self.id, route) {
+                            Ok(mut reply) => txsender.send_logged(reply.to_bytes(), TxPriority::High),
+                            Err(e) => error!("Could not build TracerouteMessage: {}", e),
+                        }
+                    },
+                }
+            },
+            // path recorded by one of our traceroutes
+            MessageType::TracerouteReply => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::High) {
+                    match TracerouteReplyMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse TracerouteReplyMessage: {}", e),
+                        Ok(reply) => self.handle_trace_reply(*reply),
+                    }
+                }
+            },
+            // a text for the user of this node
+            MessageType::Text => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Low) {
+                    match TextMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse TextMessage: {}", e),
+                        Ok(text) => {
+                            if text.receipt {
+                                let route = self.route_to(frame.sender());
+                                match TextReceiptMessage::new(text.msgid).to_frame(self.frameids.allocate(Some(frame.sender())), self.id, route) {
+                                    Ok(mut receipt) => txsender.send_logged(receipt.to_bytes(), TxPriority::High),
+                                    Err(e) => error!("Could not build TextReceiptMessage: {}", e),
+                                }
+                            }
+                            self.handle_text(frame.sender(), *text);
+                        }
+                    }
+                }
+            },
+            // application data for one of our ports
+            MessageType::Data => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Low) {
+                    match DataMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse DataMessage: {}", e),
+                        Ok(data) => self.handle_data(frame.sender(), *data),
+                    }
+                }
+            },
+            // a datagram for one of our ports
+            MessageType::Datagram => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Low) {
+                    match DatagramMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse DatagramMessage: {}", e),
+                        Ok(datagram) => self.handle_datagram(frame.sender(), *datagram),
+                    }
+                }
+            },
+            // one of our texts was delivered
+            MessageType::TextReceipt => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::High) {
+                    match TextReceiptMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse TextReceiptMessage: {}", e),
+                        Ok(receipt) => self.handle_text_receipt(frame.sender(), receipt.msgid),
+                    }
+                }
+            },
+            // a command from another node, only run with frame authentication
+            MessageType::RemoteCommand => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                    match RemoteCommandMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse RemoteCommandMessage: {}", e),
+                        Ok(msg) => self.handle_remote_command(frame.sender(), *msg),
+                    }
+                }
+            },
+            // the result of a command we sent
+            MessageType::RemoteCommandResult => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                    match RemoteCommandResultMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse RemoteCommandResultMessage: {}", e),
+                        Ok(result) => {
+                            if let Some((_, reply)) = self.remotereplies.remove(&(frame.sender(), result.nonce)) {
+                                let status = if result.ok { "ok" } else { "error" };
+                                reply.send(format!("{}: {}", status, result.output)).ok();
+                            }
+                        }
+                    }
+                }
+            },
+            // a signed change of one of our settings
+            MessageType::Config => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                    match ConfigMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse ConfigMessage: {}", e),
+                        Ok(msg) => self.handle_config(frame.sender(), *msg),
+                    }
+                }
+            },
+            // whether a config change we sent was applied
+            MessageType::ConfigAck => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                    match ConfigAckMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse ConfigAckMessage: {}", e),
+                        Ok(ack) => {
+                            if let Some((_, reply)) = self.remotereplies.remove(&(frame.sender(), ack.nonce)) {
+                                let status = if ack.ok { "ok" } else { "error" };
+                                reply.send(format!("{}: {}", status, ack.output)).ok();
+                            }
+                        }
+                    }
+                }
+            },
+            // a store-and-forward node holds messages for us
+            MessageType::HeldNotice => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                    match HeldNoticeMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse HeldNoticeMessage: {}", e),
+                        Ok(notice) => {
+                            info!("Node {} holds {} messages for us, asking for them", &frame.sender(), &notice.count);
+                            let sender = frame.sender();
+                            let route = self.route_to(sender);
+                            match HeldRequestMessage::new().to_frame(self.frameids.allocate(Some(sender)), self.id, route) {
+                                Ok(mut request) => txsender.send_logged(request.to_bytes(), TxPriority::Normal),
+                                Err(e) => error!("Could not build HeldRequestMessage: {}", e),
+                            }
+                        }
+                    }
+                }
+            },
+            // a node that came back asks for what we held for it
+            MessageType::HeldRequest => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                    self.send_held(frame.sender());
+                }
+            },
+            // a call for help, relayed by every node whatever the routes
+            MessageType::EmergencyBeacon => {
+                match EmergencyBeaconMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse EmergencyBeaconMessage: {}", e),
+                    Ok(beacon) => {
+                        if frame.sender() != self.id {
+                            self.handle_emergency(frame.sender(), *beacon);
+                        }
+                        if should_relay(&frame.route(), self.id) && frame.route_unshift(self.id.clone()).is_ok() {
+                            let priority = self.emergency_priority();
+                            txsender.send_logged(frame.to_bytes(), priority);
+                        }
+                    }
+                }
+            },
+            // another node announced its position
+            MessageType::Position => {
+                match PositionMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse PositionMessage: {}", e),
+                    Ok(position) => {
+                        trace!("Position of {} is {}, {}", &frame.sender(), position.lat_degrees(), position.lon_degrees());
+                        if frame.sender() != self.id {
+                            self.positions.update(frame.sender(), *position);
+                        }
+                        // flood it on until the hop limit
+                        let route = frame.route();
+                        if !route.contains(&self.id) && (route.len() as u8) < self.opt.maxhops && frame.route_unshift(self.id.clone()).is_ok() {
+                            self.relay_flood(&mut frame, TxPriority::Low, quality.rssi);
+                        }
+                    }
+                }
+            },
+            // health report from a node, kept for the status interface
+            MessageType::Telemetry => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::Low) {
+                    match TelemetryMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse TelemetryMessage: {}", e),
+                        Ok(msg) => {
+                            trace!("Telemetry from {}: {:?}", &frame.sender(), &msg.sample);
+                            self.telemetry.update(frame.sender(), msg.sample, self.clock.now());
+                        }
+                    }
+                }
+            },
+            // a node's links, flooded through the mesh in link-state mode
+            MessageType::LinkState => {
+                match LinkStateMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse LinkStateMessage: {}", e),
+                    Ok(lsa) => {
+                        let sender = frame.sender();
+                        let fresh = match self.router.linkstate() {
+                            Some(db) if sender != self.id => db.update(sender, lsa.seq, lsa.links),
+                            _ => false,
+                        };
+                        // only new advertisements are flooded on
+                        let route = frame.route();
+                        if fresh && !route.contains(&self.id) && (route.len() as u8) < self.opt.maxhops && frame.route_unshift(self.id.clone()).is_ok() {
+                            self.relay_flood(&mut frame, TxPriority::Normal, quality.rssi);
+                        }
+                    }
+                }
+            },
+            // a relay could not reach the next hop of a route
+            MessageType::RouteError => {
+                match RouteErrorMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse RouteErrorMessage: {}", e),
+                    Ok(error) => {
+                        // every node the error passes through stops using the link
+                        if frame.route().contains(&self.id) {
+                            self.router.link_remove(error.from, error.to);
+                        }
+                        if self.accept_routed(&mut frame, txsender, TxPriority::High) {
+                            info!("Route to {} broken between {} and {}, rediscovering", error.dest, error.from, error.to);
+                            self.gateway_failed(error.dest);
+                            for dest in self.routes.remove_link(error.from, error.to) {
+                                if dest != error.dest {
+                                    self.rediscover(dest);
+                                }
+                            }
+                            self.rediscover(error.dest);
+                        }
+                    }
+                }
+            },
+            // someone is looking for a route, answer if it's us or flood it on
+            MessageType::RouteDiscovery => {
+                let lasthop = frame.route().first().cloned().unwrap_or(frame.sender());
+                let hop = self.link_cost(lasthop);
+                match discovery_hop(&mut frame, self.id, self.opt.maxhops, hop, &mut self.discoverycosts) {
+                    Err(e) => error!("Could not parse RouteDiscoveryMessage: {}", e),
+                    // a copy no cheaper than one we had counts towards covering our rebroadcast
+                    Ok(DiscoveryAction::Ignore) => {
+                        self.floods.overheard(&dedup_key(&mut frame));
+                    },
+                    Ok(DiscoveryAction::Forward(mut next)) => {
+                        self.relay_flood(&mut next, TxPriority::Normal, quality.rssi);
+                    },
+                    Ok(DiscoveryAction::Reply { reply, route }) => {
+                        debug!("Answering route discovery from {}", &frame.sender());
+                        self.router.path_add(&reply.path);
+                        self.learn_path(&reply.path, RouteSource::Discovered, Some(reply.cost));
+                        match reply.to_frame(self.frameids.allocate(Some(frame.sender())), self.id, route) {
+                            Ok(mut reply) => txsender.send_logged(reply.to_bytes(), TxPriority::Normal),
+                            Err(e) => error!("Could not build RouteSuccessMessage: {}", e),
+                        }
+                    },
+                }
+            },
+            // answer to a route discovery, every node on the way learns the path
+            MessageType::RouteSuccess => {
+                match RouteSuccessMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse RouteSuccessMessage: {}", e),
+                    Ok(success) => {
+                        if frame.route().contains(&self.id) {
+                            self.router.path_add(&success.path);
+                            self.learn_path(&success.path, RouteSource::Discovered, Some(success.cost));
+                        }
+                        if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                            info!("Discovered route {:?}", &success.path);
+                        }
+                    }
+                }
+            },
+            // someone is looking for a source route, answer if we know one or flood it on
+            MessageType::DsrRequest => {
+                match DsrRequestMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse DsrRequestMessage: {}", e),
+                    Ok(request) => {
+                        let action = match self.dsr.as_mut() {
+                            Some(dsr) => dsr.handle_request(&request),
+                            None => DsrAction::Ignore,
+                        };
+                        match action {
+                            DsrAction::Ignore => {
+                                self.floods.overheard(&dedup_key(&mut frame));
+                            },
+                            DsrAction::Forward(next) => {
+                                match next.to_frame(frame.frameid(), frame.sender(), frame.route()) {
+                                    Ok(mut next) => self.relay_flood(&mut next, TxPriority::Normal, quality.rssi),
+                                    Err(e) => error!("Could not relay DsrRequestMessage: {}", e),
+                                }
+                            },
+                            DsrAction::Reply { reply, route } => {
+                                debug!("Answering DSR request {} from {} with {:?}", request.id, &frame.sender(), &reply.path);
+                                match reply.to_frame(self.frameids.allocate(Some(frame.sender())), self.id, route) {
+                                    Ok(mut reply) => txsender.send_logged(reply.to_bytes(), TxPriority::Normal),
+                                    Err(e) => error!("Could not build DsrReplyMessage: {}", e),
+                                }
+                            },
+                        }
+                    }
+                }
+            },
+            // answer to a DSR request, every node on the way caches the path
+            MessageType::DsrReply => {
+                match DsrReplyMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse DsrReplyMessage: {}", e),
+                    Ok(reply) => {
+                        if frame.route().contains(&self.id) {
+                            if let Some(dsr) = self.dsr.as_mut() {
+                                dsr.handle_reply(&reply);
+                            }
+                        }
+                        if self.accept_routed(&mut frame, txsender, TxPriority::Normal) {
+                            info!("Discovered source route {:?}", &reply.path);
+                        }
+                    }
+                }
+            },
+            // a relay could not reach the next hop of a source route
+            MessageType::DsrError => {
+                match DsrErrorMessage::from_frame(frame.borrow_mut()) {
+                    Err(e) => error!("Could not parse DsrErrorMessage: {}", e),
+                    Ok(error) => {
+                        let mut lost = Vec::new();
+                        if frame.route().contains(&self.id) {
+                            self.router.link_remove(error.from, error.to);
+                            if let Some(dsr) = self.dsr.as_mut() {
+                                lost = dsr.handle_error(error.from, error.to);
+                            }
+                        }
+                        if self.accept_routed(&mut frame, txsender, TxPriority::High) {
+                            info!("Source routes broken between {} and {}, rediscovering {:?}", error.from, error.to, &lost);
+                            for dest in lost {
+                                self.gateway_failed(dest);
+                                self.rediscover(dest);
+                            }
+                        }
+                    }
+                }
+            },
+            // a neighbor asking for the channel for a burst
+            MessageType::TransmitRequest => {
+                if frame.route() == vec![frame.sender()] {
+                    match TransmitRequestMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse TransmitRequestMessage: {}", e),
+                        Ok(request) => self.handle_transmit_request(frame.sender(), *request),
+                    }
+                }
+            },
+            // a neighbor granting the channel for a burst
+            MessageType::TransmitConfirm => {
+                if frame.route() == vec![frame.sender()] {
+                    match TransmitConfirmMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse TransmitConfirmMessage: {}", e),
+                        Ok(confirm) => self.handle_transmit_confirm(frame.sender(), *confirm),
+                    }
+                }
+            },
+            // a node could not handle one of our frames
+            MessageType::Error => {
+                if self.accept_routed(&mut frame, txsender, TxPriority::High) {
+                    match ErrorMessage::from_frame(frame.borrow_mut()) {
+                        Err(e) => error!("Could not parse ErrorMessage: {}", e),
+                        Ok(msg) => self.handle_error(frame.sender(), *msg),
+                    }
+                }
+            },
+            // TODO: refactor out old message architecture
+            MessageType::RouteFailure => {},
+        }
+    }
+
+    /// Follow the source route of a frame meant for a single node
+    /// returns true if the frame is for us, retransmits it if we are only a hop
+    fn accept_routed(&mut self, frame: &mut Frame, txsender: &TxQueueSender, priority: TxPriority) -> bool {
+        match route_step(frame, self.id) {
+            RouteStep::Empty => {
+                error!("Received {:?} from {} with no destination", frame.msgtype(), &frame.sender());
+                false
+            },
+            RouteStep::Overheard => {
+                // not for us, pass it on only if we relay everything we hear
+                if self.opt.autoretransmit && !self.loops.looping(frame, self.id) && overheard_forward(frame, self.id, self.opt.maxhops) {
+                    trace!("Retransmitting overheard {:?} from {}", frame.msgtype(), &frame.sender());
+                    for chunk in self.chunks(frame) {
+                        txsender.send_logged(chunk, priority);
+                    }
+                }
+                false
+            },
+            RouteStep::Forward(next) => { // retransmit to next hop
+                if self.loops.looping(frame, self.id) {
+                    debug!("Dropping {:?} {} from {}, it came back to us in a loop", frame.msgtype(), frame.frameid(), frame.sender());
+                    return false;
+                }
+                if self.router.neighbor_lost(next) {
+                    let held = self.hold(frame);
+                    let dest = frame.route().last().cloned().unwrap_or(next);
+                    warn!("Cannot reach {} to forward {:?} from {} to {}", next, frame.msgtype(), frame.sender(), dest);
+                    self.route_error(frame.sender(), next, dest);
+                    if !held {
+                        self.send_error(frame.sender(), frame.frameid(), MeshError::RouteNotFound);
+                    }
+                    return false;
+                }
+                let chunks = self.chunks(frame);
+                for chunk in chunks.iter() {
+                    txsender.send_logged(chunk.clone(), priority);
+                }
+                self.delivery.sent(frame, next, chunks, priority);
+                false
+            },
+            RouteStep::Deliver => true,
+        }
+    }
+
+    /// Tell the sender of a frame why we could not handle it
+    fn send_error(&mut self, origin: u8, frameid: u8, error: MeshError) {
+        if origin == self.id || !self.mesherrors.allow((origin, error.code())) {
+            return;
+        }
+        debug!("Telling {} about frame {}: {}", &origin, &frameid, &error);
+        let route = self.route_to(origin);
+        let msg = ErrorMessage::new(error, frameid);
+        match msg.to_frame(self.frameids.allocate(Some(origin)), self.id, route) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::High),
+            Err(e) => error!("Could not build ErrorMessage: {}", e),
+        }
+    }
+
+    /// Pass on the error another node reported about one of our frames
+    fn handle_error(&mut self, sender: u8, msg: ErrorMessage) {
+        warn!("{} could not handle our frame {}: {}", &sender, &msg.original_frame_id, &msg.error_code);
+        if let MeshError::HeaderContextLost(context) = msg.error_code {
+            self.headercomp.context_lost(sender, context);
+        }
+        // subscribers that hung up are dropped
+        self.errorsubscribers.retain(|subscriber| subscriber.send(msg.clone()).is_ok());
+    }
+
+    /// Receive the errors other nodes report about our frames
+    pub fn mesh_errors(&mut self) -> Receiver<ErrorMessage> {
+        let (sender, receiver) = unbounded();
+        self.errorsubscribers.push(sender);
+        receiver
+    }
+
+    /// Tell the origin of a frame for `dest` that we could not reach its next hop
+    fn route_error(&mut self, origin: u8, nexthop: u8, dest: u8) {
+        self.router.link_remove(self.id, nexthop);
+        if let Some(dsr) = self.dsr.as_mut() {
+            dsr.handle_error(self.id, nexthop);
+        }
+        if !self.routeerrors.allow((self.id, nexthop)) {
+            return;
+        }
+        let route = self.route_to(origin);
+        let frameid = self.frameids.allocate(Some(origin));
+        // in DSR mode the origin finds out which of its routes used the link
+        let frame = match self.dsr {
+            Some(_) => DsrErrorMessage::new(self.id, nexthop).to_frame(frameid, self.id, route),
+            None => RouteErrorMessage::new(self.id, nexthop, dest).to_frame(frameid, self.id, route),
+        };
+        match frame {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::High),
+            Err(e) => error!("Could not build route error: {}", e),
+        }
+    }
+
+    /// Send a flood on after a random delay, unless neighbors cover it first
+    /* Time syncs and emergency beacons are sent on right away instead, the
+    first would be late and the second must get through. */
+    fn relay_flood(&mut self, frame: &mut Frame, priority: TxPriority, rssi: Option<i16>) {
+        if self.opt.floodrelay {
+            let key = dedup_key(frame);
+            self.floods.schedule(key, frame.to_bytes(), priority, rssi);
+        }
+    }
+
+    /// Send the floods whose rebroadcast delay is over
+    fn flood_tick(&mut self) {
+        for (bytes, priority) in self.floods.due() {
+            self.tx_logged(bytes, priority);
+        }
+    }
+
+    /// A frame to a node failed on the way, count it against the node if it is a gateway
+    fn gateway_failed(&mut self, nodeid: u8) {
+        let maxfailures = self.opt.gatewayfailures;
+        if self.router.gateways().map_or(false, |gateways| gateways.failed(nodeid)) {
+            warn!("Gateway {} demoted after {} failed frames", &nodeid, &maxfailures);
+            self.router.default_gateway_failed(nodeid);
+        }
+    }
+
+    /// Flood a route discovery for a node we lost the route to
+    fn rediscover(&mut self, dest: u8) {
+        if self.discoveries.allow(dest) {
+            let frameid = self.frameids.allocate(None);
+            let frame = match self.dsr.as_mut() {
+                Some(dsr) => dsr.request(dest).to_frame(frameid, self.id, vec![self.id]),
+                None => RouteDiscoveryMessage::new(dest).to_frame(frameid, self.id, vec![self.id]),
+            };
+            match frame {
+                Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+                Err(e) => error!("Could not build route discovery: {}", e),
+            }
+        }
+    }
+
+    /// Start commands received on the control socket
+    fn handle_control(&mut self) {
+        let request = match &self.control {
+            None => return,
+            Some(control) => control.try_recv(),
+        };
+        if let Ok(request) = request {
+            match request.command {
+                ControlCommand::Ping { dest, count } => {
+                    request.reply.send(format!("PING node {}: {} pings", dest, count)).ok();
+                    let session = PingSession::new(
+                        dest,
+                        count,
+                        Duration::from_millis(self.opt.pinginterval),
+                        Duration::from_millis(self.opt.pingtimeout));
+                    self.pings.push((session, request.reply));
+                },
+                ControlCommand::Traceroute { dest } => {
+                    request.reply.send(format!("traceroute to node {}, {} hops max", dest, self.opt.maxhops)).ok();
+                    self.traceroute_start(dest, Some(request.reply));
+                },
+                ControlCommand::SendText { dest, text } => {
+                    match self.send_text_report(dest, &text, Some(request.reply.clone())) {
+                        Err(e) => { request.reply.send(format!("error: {}", e)).ok(); },
+                        Ok(msgid) => { request.reply.send(format!("sent text {} to node {}", msgid, dest)).ok(); },
+                    }
+                },
+                ControlCommand::SendFile { dest, path } => {
+                    match self.send_file_report(dest, &path, Some(request.reply.clone())) {
+                        Err(e) => { request.reply.send(format!("error: {}", e)).ok(); },
+                        Ok(transferid) => { request.reply.send(format!("offered file {} to node {}", transferid, dest)).ok(); },
+                    }
+                },
+                ControlCommand::Remote { dest, command } => {
+                    match self.send_remote_command(dest, command) {
+                        Err(e) => { request.reply.send(format!("error: {}", e)).ok(); },
+                        Ok(nonce) => { self.remotereplies.insert((dest, nonce), (Instant::now(), request.reply)); },
+                    }
+                },
+                ControlCommand::Config { dest, key, value } => {
+                    match self.send_config(dest, &key, &value) {
+                        Err(reason) => { request.reply.send(format!("error: {}", reason)).ok(); },
+                        Ok(nonce) => { self.remotereplies.insert((dest, nonce), (Instant::now(), request.reply)); },
+                    }
+                },
+                ControlCommand::ListenText => {
+                    self.textlisteners.push(request.reply);
+                },
+                ControlCommand::Emergency { text } => {
+                    self.raise_emergency(&text);
+                    request.reply.send(format!("emergency raised, beacon every {}s until all-clear", self.opt.emergencyinterval / 1000)).ok();
+                },
+                ControlCommand::AllClear => {
+                    let reply = if self.cancel_emergency() { "all-clear sent" } else { "no emergency raised" };
+                    request.reply.send(String::from(reply)).ok();
+                },
+                ControlCommand::RadioStop => {
+                    let reply = match self.emergency_stop() {
+                        Ok(()) => String::from("radio stopped, nothing is sent until radio-reset"),
+                        Err(e) => format!("error: {}", e),
+                    };
+                    request.reply.send(reply).ok();
+                },
+                ControlCommand::RadioReset => {
+                    let reply = match self.emergency_reset() {
+                        Ok(()) => String::from("radio reset, sending again"),
+                        Err(e) => format!("error: {}", e),
+                    };
+                    request.reply.send(reply).ok();
+                },
+                ControlCommand::ListenEmergency => {
+                    for (nodeid, beacon, received) in self.emergencies.all() {
+                        request.reply.send(format!("[emergency] node {}: {}  ({}s ago)",
+                            nodeid, beacon.text, received.elapsed().as_secs())).ok();
+                    }
+                    self.emergencylisteners.push(request.reply);
+                },
+                ControlCommand::Routes => {
+                    let now = Instant::now();
+                    let ago = |time: Option<Instant>| time.map_or(String::from("never"), |t| format!("{}s ago", now.duration_since(t).as_secs()));
+                    for (dest, entry) in self.routes.entries() {
+                        let path: Vec<String> = entry.path.iter().map(|hop| hop.to_string()).collect();
+                        request.reply.send(format!("node {} via {}  hops {}  cost {:.2}  {:?}  confirmed {}  used {}",
+                            dest, path.join(","), entry.hops(), entry.cost, entry.source,
+                            if entry.confirmed.is_some() { ago(entry.confirmed) } else { String::from("pending") },
+                            ago(entry.lastused))).ok();
+                    }
+                },
+                ControlCommand::Queues => {
+                    for (dest, stats) in self.radio.txsender().destinations() {
+                        request.reply.send(format!("queue {}: {} waiting  {} frames  {} bytes sent",
+                            dest.map_or(String::from("?"), |nodeid| nodeid.to_string()),
+                            stats.depth, stats.frames, stats.bytes)).ok();
+                    }
+                    if self.opt.padding {
+                        let padding = self.radio.txsender().padding();
+                        request.reply.send(format!("padding: {} frames  {} payload bytes  {} padding bytes  {:.0}% of the payload airtime",
+                            padding.frames, padding.payloadbytes, padding.paddingbytes, padding.overhead() * 100.0)).ok();
+                    }
+                },
+                ControlCommand::Filters => {
+                    for (rule, drops) in self.filter.rules() {
+                        request.reply.send(format!("{}  ({} dropped)", rule.text, drops)).ok();
+                    }
+                },
+                ControlCommand::Topology { format: TopologyFormat::Text } => {
+                    for (from, to, cost) in self.router.topology() {
+                        request.reply.send(format!("{} -> {}  cost {:.2}", from, to, cost)).ok();
+                    }
+                },
+                ControlCommand::Topology { format: TopologyFormat::Dot } => {
+                    request.reply.send(self.mesh_graph().to_dot()).ok();
+                },
+                ControlCommand::Topology { format: TopologyFormat::Json } => {
+                    request.reply.send(self.mesh_graph().to_json()).ok();
+                },
+                ControlCommand::Telemetry => {
+                    request.reply.send(self.telemetry.to_json()).ok();
+                },
+                ControlCommand::Nodes => {
+                    let interval = Duration::from_millis(self.opt.heartbeatinterval);
+                    for (nodeid, info, received) in self.nodeinfos.all() {
+                        let mut flags = vec![match info.routing {
+                            RoutingMode::Aodv => "aodv",
+                            RoutingMode::LinkState => "linkstate",
+                            RoutingMode::Dsr => "dsr",
+                        }];
+                        if info.gateway { flags.push("gateway"); }
+                        if info.encryption { flags.push("encrypted"); }
+                        if self.nodeinfos.dead(nodeid, interval) { flags.push("dead"); }
+                        let health = self.nodeinfos.last_heartbeat(nodeid).map_or(String::new(), |hb| format!(
+                            "  vdd {}  up {}s", hb.battery_mv.map_or(String::from("?"), |mv| format!("{}mV", mv)), hb.uptime_s));
+                        let seen = self.nodeinfos.last_seen(nodeid).unwrap_or(received);
+                        request.reply.send(format!("node {}: {}  {:?} {}  loramesh {}  {}{}  ({}s ago)",
+                            nodeid, info.name.as_deref().unwrap_or("-"), info.hardware, info.firmware,
+                            info.version, flags.join(","), health, seen.elapsed().as_secs())).ok();
+                    }
+                },
+                ControlCommand::Addresses => {
+                    for (ipaddr, nodeid, age) in self.resolver.entries() {
+                        request.reply.send(format!("{}: node {}  ({}s ago)", ipaddr, nodeid, age.as_secs())).ok();
+                    }
+                    for (ipaddr, packets) in self.resolver.pending() {
+                        request.reply.send(format!("{}: resolving, {} packets waiting", ipaddr, packets)).ok();
+                    }
+                },
+                ControlCommand::Neighbors => {
+                    let dbm = |rssi: Option<f32>| rssi.map_or(String::from("?"), |r| format!("{:.0} dBm", r));
+                    let now = Instant::now();
+                    for stats in self.neighbors.neighbors() {
+                        let link = self.links.neighbor_at(stats.nodeid, now);
+                        let outbound = link.outbound.map_or(String::from("?"), |q| format!("{:.2}", q));
+                        request.reply.send(format!("neighbor {}: in/out {:.2}/{}  rssi {}/{}  snr {}  loss {}  etx {}  {} frames  ({}s ago)",
+                            stats.nodeid, link.inbound, outbound, dbm(stats.rssi), dbm(link.rssiout.map(|r| r as f32)),
+                            stats.snr.map_or(String::from("?"), |snr| format!("{:.1} dB", snr)),
+                            stats.loss.map_or(String::from("?"), |loss| format!("{:.0}%", loss * 100.0)),
+                            link.etx().map_or(String::from("?"), |etx| format!("{:.2}", etx)),
+                            stats.packets, now.duration_since(stats.lastseen).as_secs())).ok();
+                    }
+                    for nodeid in self.neighbors.stale() {
+                        request.reply.send(format!("neighbor {}: not heard since the restart", nodeid)).ok();
+                    }
+                },
+                ControlCommand::Gateways => {
+                    if let Some(gateway) = self.router.default_gateway() {
+                        request.reply.send(format!("default gateway {}: metric {:.2}  ({}s ago)",
+                            gateway.gateway_id, gateway.metric as f32 / 100.0, gateway.last_announced.elapsed().as_secs())).ok();
+                    }
+                    if let Some(gateways) = self.router.gateways() {
+                        for (nodeid, entry) in gateways.ranked() {
+                            request.reply.send(format!("gateway {}: uplink {}  load {}%  cost {:.2}  ({}s ago)",
+                                nodeid, if entry.uplink { "up" } else { "down" }, entry.load, entry.cost,
+                                entry.heard.elapsed().as_secs())).ok();
+                        }
+                    }
+                },
+                ControlCommand::Positions => {
+                    for (nodeid, position, received) in self.positions.all() {
+                        request.reply.send(format!("node {}: {:.7}, {:.7}  alt {}m  speed {:.1}m/s  fix {}  ({}s ago)",
+                            nodeid, position.lat_degrees(), position.lon_degrees(), position.altitude,
+                            position.speed as f32 / 100.0, position.fix, received.elapsed().as_secs())).ok();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send due pings, expire lost ones and report finished ping commands
+    fn ping_tick(&mut self, txsender: &TxQueueSender) {
+        let now = Instant::now();
+        let timestamp = unix_millis();
+
+        // taken out so routes can be looked up while going through them
+        let mut pings = std::mem::replace(&mut self.pings, Vec::new());
+        for (session, reply) in pings.iter_mut() {
+            if let Some(seq) = session.poll_send(now) {
+                // unknown nodes may still be direct neighbors, try them directly
+                let route = self.route_to(session.dest);
+                let frameid = self.frameids.allocate(Some(session.dest));
+                match PingMessage::new(seq, timestamp).to_frame(frameid, self.id, route) {
+                    Ok(mut ping) => txsender.send_logged(ping.to_bytes(), TxPriority::High),
+                    Err(e) => error!("Could not build PingMessage: {}", e),
+                }
+            }
+            for seq in session.expire(now) {
+                reply.send(format!("request timeout for seq={}", seq)).ok();
+            }
+        }
+        self.pings = pings;
+
+        // dropping the reply sender closes the control connection
+        self.pings.retain(|(session, reply)| {
+            if !session.is_done() {
+                return true;
+            }
+            let summary = session.summary();
+            let ms = |d: Option<Duration>| d.map_or(String::from("-"), |d| d.as_millis().to_string());
+            reply.send(format!("--- node {} ping statistics ---", session.dest)).ok();
+            reply.send(format!("{} sent, {} received, {:.1}% loss, rtt min/avg/max = {}/{}/{} ms",
+                summary.sent, summary.received, summary.loss, ms(summary.min), ms(summary.avg), ms(summary.max))).ok();
+            false
+        });
+    }
+
+    /// Take another node ID, unless a node already uses it
+    /* Listens for a while and asks the node with that ID for its info, any
+    frame from it means the ID is taken. Routes learned under the old ID
+    are dropped and the mesh hears the new one right away. Keeps the node
+    running while waiting, so it must not be called from a handler. */
+    pub fn set_node_id(&mut self, id: u8) -> io::Result<()> {
+        if id == self.id {
+            return Ok(());
+        }
+        self.start();
+        let claimed = |id| Error::new(ErrorKind::AddrInUse, format!("node ID {} is already claimed by another node", id));
+        if self.neighbors.get(id).is_some() {
+            return Err(claimed(id));
+        }
+
+        self.claimwatch = Some(id);
+        self.claimheard = false;
+        let route = self.route_to(id);
+        let mut msg = NodeInfoMessage::new(self.nodeinfo.clone());
+        msg.request = true;
+        match msg.to_frame(self.frameids.allocate(Some(id)), self.id, route) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::for_message(&MessageType::NodeInfo)),
+            Err(e) => error!("Could not build NodeInfoMessage: {}", e),
+        }
+        let started = Instant::now();
+        while !self.claimheard && started.elapsed() < NODEID_CLAIM_WAIT {
+            self.poll();
+        }
+        self.claimwatch = None;
+        if self.claimheard {
+            return Err(claimed(id));
+        }
+
+        self.take_node_id(id);
+        Ok(())
+    }
+
+    /// Change our node ID and tell the mesh
+    fn take_node_id(&mut self, id: u8) {
+        info!("Node ID changed from {} to {}", self.id, id);
+        self.id = id;
+        self.opt.nodeid = id;
+        // nodes still routing to the old ID must not bring it back until their routes expire
+        self.router.set_nodeid(id, Duration::from_millis(self.opt.routemaxage));
+        self.routes.clear();
+        if self.dsr.is_some() {
+            self.dsr = Some(DsrRouter::new(id, self.opt.maxhops, self.opt.dsrcachesize, Duration::from_millis(self.opt.dedupttl)));
+        }
+        self.collisions.reset();
+        self.send_broadcast();
+    }
+
+    /// Snapshot of the mesh as this node knows it, for drawing
+    /* In link-state mode that is every advertised link, otherwise the links
+    seen in broadcasts and discoveries. Our own links carry what we measured
+    on them. */
+    pub fn mesh_graph(&mut self) -> MeshGraph {
+        let now = Instant::now();
+        let linkstate = self.opt.routing == RoutingMode::LinkState;
+        let mut graph = MeshGraph::new(self.id, linkstate);
+        graph.node(self.id).name = self.nodeinfo.name.clone();
+        graph.node(self.id).gateway = self.opt.isgateway;
+        for (from, to, cost) in self.router.topology() {
+            // outside link-state mode the cost is a hop count
+            graph.edge(from, to).etx = if linkstate { Some(cost) } else { None };
+        }
+        for stats in self.neighbors.neighbors() {
+            let link = self.links.neighbor_at(stats.nodeid, now);
+            graph.node(stats.nodeid).lastseen = Some(now.duration_since(stats.lastseen).as_secs());
+            let edge = graph.edge(self.id, stats.nodeid);
+            edge.etx = edge.etx.or(link.etx());
+            edge.rssi = stats.rssi.map(|rssi| rssi.round() as i16);
+        }
+        for (nodeid, info, received) in self.nodeinfos.all() {
+            let seen = self.nodeinfos.last_seen(nodeid).unwrap_or(received);
+            let node = graph.node(nodeid);
+            node.name = info.name.clone();
+            node.gateway |= info.gateway;
+            let secs = now.duration_since(seen).as_secs();
+            node.lastseen = Some(node.lastseen.map_or(secs, |seen| seen.min(secs)));
+        }
+        if let Some(gateways) = self.router.gateways() {
+            for (nodeid, _) in gateways.ranked() {
+                graph.node(nodeid).gateway = true;
+            }
+        }
+        graph.sorted()
+    }
+
+    /// The loop defenses, with the frames and paths each one refused
+    pub fn loops(&self) -> &LoopGuard {
+        &self.loops
+    }
+
+    /// The recently seen frames, with the duplicates dropped
+    pub fn dedup(&self) -> &DedupCache {
+        &self.dedup
+    }
+
+    /// The floods waiting to be sent on, with the rebroadcasts sent and cancelled
+    pub fn floods(&self) -> &FloodControl {
+        &self.floods
+    }
+
+    /// The routes learned so far, by destination
+    pub fn routes(&self) -> &RoutingTable {
+        &self.routes
+    }
+
+    /// Trace the path to a node, blocking until the reply arrives
+    /* Keeps the node running while waiting, so it must not be called
+    from a handler. */
+    pub fn traceroute(&mut self, destination: u8) -> io::Result<TracerouteResult> {
+        self.start();
+        let sequence = self.traceroute_start(destination, None);
+        loop {
+            self.poll();
+            if let Some(result) = self.traceresults.remove(&sequence) {
+                return Ok(result);
+            }
+            if !self.traces.iter().any(|t| t.sequence == sequence) {
+                return Err(Error::new(ErrorKind::TimedOut, format!("no answer from node {}", destination)));
+            }
+        }
+    }
+
+    /// Trace the route to every known node at once and combine the paths
+    /* Each traceroute gives up after the trace timeout, nodes whose
+    traceroute did not come back are reported as unreachable. */
+    pub fn discover_topology(&mut self) -> io::Result<TopologyReport> {
+        self.start();
+        let mut known: Vec<u8> = self.router.state().nodes.keys().cloned()
+            .chain(self.nodeinfos.all().into_iter().map(|(nodeid, _, _)| nodeid))
+            .filter(|nodeid| *nodeid != self.id)
+            .collect();
+        known.sort();
+        known.dedup();
+        let pending: Vec<(u8, u16)> = known.iter().map(|dest| (*dest, self.traceroute_start(*dest, None))).collect();
+
+        while pending.iter().any(|(_, sequence)| self.traces.iter().any(|t| t.sequence == *sequence)) {
+            self.poll();
+        }
+        let mut traces = Vec::new();
+        let mut unreachable = Vec::new();
+        for (dest, sequence) in pending {
+            match self.traceresults.remove(&sequence) {
+                Some(result) if result.complete => traces.push(result),
+                Some(result) => {
+                    unreachable.push(dest);
+                    traces.push(result);
+                },
+                None => unreachable.push(dest),
+            }
+        }
+        Ok(TopologyReport::from_traces(self.id, &traces, unreachable))
+    }
+
+    /// Send a traceroute towards a node, returning its sequence number
+    fn traceroute_start(&mut self, dest: u8, reply: Option<Sender<String>>) -> u16 {
+        self.traceseq = self.traceseq.wrapping_add(1);
+        let sequence = self.traceseq;
+
+        // unknown nodes may still be direct neighbors, try them directly
+        let route = self.route_to(dest);
+        let frameid = self.frameids.allocate(Some(dest));
+        let msg = TracerouteMessage::new(sequence, dest, self.opt.maxhops, self.clock.now());
+        match msg.to_frame(frameid, self.id, route) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::High),
+            Err(e) => error!("Could not build TracerouteMessage: {}", e),
+        }
+
+        self.traces.push(PendingTrace { sequence, dest, started: Instant::now(), reply });
+        return sequence;
+    }
+
+    /// Give up on traceroutes that were never answered
+    fn trace_tick(&mut self) {
+        let timeout = Duration::from_millis(self.opt.tracetimeout);
+        self.traces.retain(|t| {
+            if t.started.elapsed() < timeout {
+                return true;
+            }
+            if let Some(reply) = &t.reply {
+                reply.send(format!("no answer from node {}", t.dest)).ok();
+            }
+            false
+        });
+    }
+
+    /// Report the path recorded by one of our traceroutes
+    fn handle_trace_reply(&mut self, reply: TracerouteReplyMessage) {
+        let pending = match self.traces.iter().position(|t| t.sequence == reply.sequence) {
+            None => {
+                trace!("Dropping unexpected traceroute reply {}", &reply.sequence);
+                return;
+            },
+            Some(i) => self.traces.remove(i),
+        };
+        let result = TracerouteResult::new(&reply, pending.started.elapsed());
+        match pending.reply {
+            None => { self.traceresults.insert(pending.sequence, result); },
+            Some(sender) => {
+                for (n, hop) in result.hops.iter().enumerate() {
+                    let rssi = hop.rssi.map_or(String::from("?"), |r| r.to_string());
+                    sender.send(format!("{:2}  node {}  {}ms  rssi={}dBm",
+                        n + 1, hop.nodeid, result.hop_latency[n].as_millis(), rssi)).ok();
+                }
+                if !result.complete {
+                    sender.send(format!("hop limit reached before node {}", result.dest)).ok();
+                }
+                sender.send(format!("round trip {}ms", result.rtt.as_millis())).ok();
+            }
+        }
+    }
+
+    /// Send our node info to a node, asking for its own if `request`
+    fn send_nodeinfo(&mut self, dest: u8, request: bool, txsender: &TxQueueSender) {
+        let route = self.route_to(dest);
+        let mut msg = NodeInfoMessage::new(self.nodeinfo.clone());
+        msg.request = request;
+        match msg.to_frame(self.frameids.allocate(Some(dest)), self.id, route) {
+            Ok(mut frame) => txsender.send_logged(frame.to_bytes(), TxPriority::for_message(&MessageType::NodeInfo)),
+            Err(e) => error!("Could not build NodeInfoMessage: {}", e),
+        }
+    }
+
+    /// Remember another node's info, pointing out settings we disagree on
+    fn handle_nodeinfo(&mut self, sender: u8, info: NodeInfo) {
+        if !self.nodeinfos.update(sender, info.clone()) {
+            return;
+        }
+        info!("Node {} is {:?}, {:?} running loramesh {}", &sender, info.name.as_deref().unwrap_or("unnamed"), &info.hardware, &info.version);
+        for mismatch in mismatches(&self.nodeinfo, &info) {
+            warn!("Node {} does not match our settings: {}", &sender, mismatch);
+        }
+    }
+
+    /// Probe routes in use before they expire, and rediscover the ones that did
+    /* A pong along the probed route confirms it like any other ping. */
+    fn route_tick(&mut self) {
+        for dest in self.routes.expire() {
+            debug!("Last route to {} expired, rediscovering", dest);
+            self.rediscover(dest);
+        }
+        if self.opt.routeprobebefore == 0 {
+            return;
+        }
+        let timestamp = unix_millis();
+        for (dest, route) in self.routes.probes_due(Duration::from_millis(self.opt.routeprobebefore)) {
+            trace!("Probing route {:?} to {} before it expires", &route, &dest);
+            match PingMessage::new(0, timestamp).to_frame(self.frameids.allocate(Some(dest)), self.id, route) {
+                Ok(mut ping) => self.tx_logged(ping.to_bytes(), TxPriority::Low),
+                Err(e) => error!("Could not build PingMessage: {}", e),
+            }
+        }
+    }
+
+    /// Warn once if not a single frame was heard for a neighbor timeout after the start
+    /* Radios on another modulation, or LoRa settings, hear nothing at all
+    rather than garbled frames. */
+    fn silence_tick(&mut self) {
+        if self.silencewarned || self.rxframes > 0 || self.started.elapsed() < Duration::from_millis(self.opt.neighbortimeout) {
+            return;
+        }
+        self.silencewarned = true;
+        warn!("Nothing heard for {}s, check that the modulation {:?} matches the other nodes",
+            self.opt.neighbortimeout / 1000, self.opt.modulation);
+    }
+
+    /// Drop unconfirmed routes from before a restart, and save the mesh state when due
+    fn state_tick(&mut self) {
+        for nodeid in self.router.expire_stale() {
+            debug!("Route to {} from before the restart did not answer", nodeid);
+        }
+        let interval = Duration::from_millis(self.opt.stateinterval);
+        if interval.as_millis() == 0 || self.statesaved.elapsed() < interval {
+            return;
+        }
+        self.save_state();
+    }
+
+    /// What we know about the mesh, to be restored after a restart
+    pub fn mesh_state(&self) -> MeshState {
+        let mut state = self.router.state();
+        state.neighbors = self.neighbors.neighbors().iter().map(|stats| stats.nodeid).collect();
+        state.neighbors.extend(self.neighbors.stale());
+        state.neighbors.sort();
+        // a gateway's own address comes from its node ID, a static one from the settings
+        if !self.opt.isgateway && self.opt.staticip.is_none() {
+            state.ipaddr = self.ipaddr;
+        }
+        state.frameids = self.frameids.last();
+        state.replay = self.antireplay.highest();
+        state
+    }
+
+    /// Take back what we knew before a restart, each part stale until the mesh confirms it
+    fn restore_state(&mut self, state: MeshState) {
+        for nodeid in state.neighbors.iter() {
+            self.neighbors.restore(*nodeid);
+        }
+        self.frameids.resume(&state.frameids);
+        self.antireplay.restore(&state.replay);
+        if let (Some(ipaddr), false, None) = (state.ipaddr, self.opt.isgateway, self.opt.staticip) {
+            info!("Using IP {} from before the restart until the gateway renews it", ipaddr);
+            self.handle_ip_assignment(ipaddr, None, None);
+            // the lease may have run out while we were down
+            self.leaserenew = Some(Instant::now());
+        }
+    }
+
+    /// Save the mesh state and routing table now
+    pub fn save_state(&mut self) {
+        self.statesaved = Instant::now();
+        if let Err(e) = self.mesh_state().save(&self.opt.statefile) {
+            error!("Could not save mesh state to {}: {}", self.opt.statefile.display(), e);
+        }
+        if let Err(e) = self.routes.save() {
+            error!("Could not save routes to {}: {}", self.opt.routefile.display(), e);
+        }
+    }
+
+    /// Announce our position when the tracker says it's time
+    fn gps_tick(&mut self) {
+        let fix = match &self.gps {
+            None => return,
+            // only the newest fix matters
+            Some(gps) => gps.try_iter().last(),
+        };
+        if let Some(fix) = fix {
+            self.lastfix = Some((fix.lat, fix.lon));
+            if self.positiontracker.should_send(fix.lat, fix.lon) {
+                debug!("Announcing position {}, {}", fix.lat, fix.lon);
+                let msg = PositionMessage::new(fix.lat, fix.lon, fix.altitude, fix.speed, fix.quality, self.clock.now());
+                match msg.to_frame(self.frameids.allocate(None), self.id, vec![self.id]) {
+                    Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Low),
+                    Err(e) => error!("Could not build PositionMessage: {}", e),
+                }
+            }
+        }
+    }
+
+    /// Advertise our links when due, in link-state mode
+    fn lsa_tick(&mut self) {
+        let now = Instant::now();
+        if now < self.lsanext {
+            return;
+        }
+        let links = self.links.links_at(now);
+        let db = match self.router.linkstate() {
+            None => return,
+            Some(db) => db,
+        };
+        for nodeid in db.expire() {
+            debug!("Link-state advertisement from {} aged out", nodeid);
+        }
+        self.lsaseq = self.lsaseq.wrapping_add(1);
+        db.update(self.id, self.lsaseq, links.clone());
+
+        let jitter = thread_rng().gen_range(0, self.opt.lsajitter + 1);
+        self.lsanext = now + Duration::from_millis(self.opt.lsainterval + jitter);
+        trace!("Advertising {} links", links.len());
+        match LinkStateMessage::new(self.lsaseq, links).to_frame(self.frameids.allocate(None), self.id, vec![self.id]) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+            Err(e) => error!("Could not build LinkStateMessage: {}", e),
+        }
+    }
+
+    /// Report our health to the gateway once the telemetry interval passed
+    /* The gateway files its own reports straight into the table. */
+    fn telemetry_tick(&mut self) {
+        let interval = Duration::from_millis(self.opt.telemetryinterval);
+        if interval.as_millis() == 0 || self.telemetrysent.elapsed() < interval {
+            return;
+        }
+        self.telemetrysent = Instant::now();
+
+        let sample = self.telemetry_sample();
+        if self.opt.isgateway {
+            self.telemetry.update(self.id, sample, self.clock.now());
+            return;
+        }
+        match self.router.gateway_id() {
+            None => debug!("No gateway known yet, skipping telemetry"),
+            Some(gateway) => {
+                let route = self.route_to(gateway);
+                let frameid = self.frameids.allocate(Some(gateway));
+                match TelemetryMessage::new(sample).to_frame(frameid, self.id, route) {
+                    Ok(mut frame) => for chunk in self.chunks(&mut frame) {
+                        self.tx_logged(chunk, TxPriority::for_message(&MessageType::Telemetry));
+                    },
+                    Err(e) => error!("Could not build TelemetryMessage: {}", e),
+                }
+            }
+        }
+    }
+
+    /// Read the health metrics selected in the settings
+    fn telemetry_sample(&self) -> TelemetrySample {
+        let fields = &self.telemetryfields;
+        let count = |n: u64| n.min(u32::MAX as u64) as u32;
+        let txqueue = &self.radio.txsender();
+
+        // asking the radio interrupts receiving, so only do it when reported
+        let vdd_mv = if fields.contains(&TelemetryField::Vdd) {
+            match self.radio.query("sys get vdd").and_then(|resp| parse_vdd(&resp)) {
+                Err(e) => { warn!("Could not read supply voltage: {}", e); None },
+                Ok(mv) => Some(mv),
+            }
+        } else {
+            None
+        };
+
+        let mut sample = TelemetrySample {
+            vdd_mv,
+            uptime_s: Some(count(self.started.elapsed().as_secs())),
+            tx_frames: Some(count(txqueue.sent())),
+            rx_frames: Some(count(self.rxframes)),
+            dropped: Some(count(txqueue.dropped() + self.rxdropped)),
+            queue_depth: Some(txqueue.len().min(u16::MAX as usize) as u16),
+            free_memory_kb: mem_available_kb().map(count),
+            icmp_packets: Some(count(self.iptraffic.icmp_packets)),
+        };
+        sample.retain(fields);
+        sample
+    }
+
+    /// Send a text to a node, asking for a delivery receipt
+    /// returns the ID the receipt will carry
+    pub fn send_text(&mut self, dest: u8, text: &str) -> io::Result<u16> {
+        self.send_text_report(dest, text, None)
+    }
+
+    fn send_text_report(&mut self, dest: u8, text: &str, reply: Option<Sender<String>>) -> io::Result<u16> {
+        self.textseq = self.textseq.wrapping_add(1);
+        let mut msg = TextMessage::new(self.textseq, text)?;
+        msg.receipt = true;
+        msg.name = self.opt.nodename.clone();
+
+        let route = self.route_to(dest);
+        let frameid = self.frameids.allocate(Some(dest));
+        let mut frame = msg.to_frame(frameid, self.id, route)?;
+        if !(self.unreachable(dest) && self.hold(&mut frame)) {
+            for chunk in self.chunks(&mut frame) {
+                self.tx_with_priority(chunk, TxPriority::Low)?;
+            }
+        }
+        self.texts.push(PendingText { dest, msgid: msg.msgid, sent: Instant::now(), reply });
+        Ok(msg.msgid)
+    }
+
+    /// Send a file to another node, returns the transfer ID
+    pub fn send_file(&mut self, dest: u8, path: &Path) -> io::Result<u16> {
+        self.send_file_report(dest, path, None)
+    }
+
+    fn send_file_report(&mut self, dest: u8, path: &Path, reply: Option<Sender<String>>) -> io::Result<u16> {
+        // a transfer started now would be offered again and again to no avail
+        if self.radio.txsender().is_halted() {
+            return Err(radio_stopped());
+        }
+        let (transferid, offer) = self.files.send(dest, path, Instant::now())?;
+        info!("Offering file {:?} to node {}", path, &dest);
+        self.send_file_messages(vec![(dest, offer)]);
+        if let Some(reply) = reply {
+            self.filereplies.insert((dest, transferid), reply);
+        }
+        Ok(transferid)
+    }
+
+    fn send_file_messages(&mut self, msgs: Vec<(u8, FileMessage)>) {
+        for (dest, msg) in msgs {
+            let route = self.route_to(dest);
+            match msg.to_frame(self.frameids.allocate(Some(dest)), self.id, route) {
+                Ok(mut frame) => {
+                    let priority = TxPriority::for_message(&frame.msgtype());
+                    self.tx_logged(frame.to_bytes(), priority);
+                },
+                Err(e) => error!("Could not build file transfer message: {}", e),
+            }
+        }
+    }
+
+    /// Report finished file transfers to the control connections that started them
+    fn handle_file_events(&mut self) {
+        for event in self.files.take_events() {
+            let (key, line) = match event {
+                FileEvent::Sent { dest, transferid } => ((dest, transferid), format!("delivered to node {}", dest)),
+                FileEvent::Failed { peer, transferid, reason } => ((peer, transferid), format!("failed: {}", reason)),
+                FileEvent::Received { .. } => continue,
+            };
+            if let Some(reply) = self.filereplies.remove(&key) {
+                reply.send(line).ok();
+            }
+        }
+    }
+
+    /// Repeat unanswered file transfer messages
+    fn file_tick(&mut self) {
+        let msgs = self.files.poll(Instant::now());
+        self.send_file_messages(msgs);
+        self.handle_file_events();
+    }
+
+    /// Pass a received text on to everyone listening for texts
+    fn handle_text(&mut self, sender: u8, text: TextMessage) {
+        let from = match &text.name {
+            None => format!("node {}", sender),
+            Some(name) => format!("node {} ({})", sender, name),
+        };
+        info!("Text from {}: {}", from, text.text);
+        let line = format!("[{}] {}", from, text.text);
+        // listeners that hung up are dropped
+        self.textlisteners.retain(|listener| listener.send(line.clone()).is_ok());
+    }
+
+    /// Raise an emergency, flooding a beacon now and at every emergency interval
+    pub fn raise_emergency(&mut self, text: &str) {
+        warn!("Raising emergency: {}", text);
+        self.beacon = Some(Beacon::new(text, Duration::from_millis(self.opt.emergencyinterval)));
+    }
+
+    /// Stop our beacon and tell the mesh the emergency is over, false if none was raised
+    pub fn cancel_emergency(&mut self) -> bool {
+        match self.beacon.take() {
+            None => false,
+            Some(beacon) => {
+                info!("Emergency cancelled, sending all-clear");
+                self.send_beacon(EmergencyBeaconMessage::all_clear(beacon.clear_seq()));
+                true
+            }
+        }
+    }
+
+    /// Repeat our beacon when due
+    fn emergency_tick(&mut self) {
+        let (seq, text) = match self.beacon.as_mut() {
+            None => return,
+            Some(beacon) => match beacon.due() {
+                None => return,
+                Some(seq) => (seq, beacon.text.clone()),
+            },
+        };
+        self.send_beacon(EmergencyBeaconMessage::new(seq, self.lastfix, &text));
+    }
+
+    fn send_beacon(&mut self, beacon: EmergencyBeaconMessage) {
+        let mut frame = match beacon.to_frame(self.frameids.allocate(None), self.id, vec![self.id]) {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Could not build EmergencyBeaconMessage: {}", e);
+                return;
+            },
+        };
+        // relays drop it as a duplicate, as they do for every flood
+        self.dedup.is_duplicate(self.id, None, frame.frameid(), frame.msgtype().to_u8());
+        let priority = self.emergency_priority();
+        for chunk in self.chunks(&mut frame) {
+            self.tx_logged(chunk, priority);
+        }
+    }
+
+    /// Emergency priority while the allowance lasts, high priority after that
+    fn emergency_priority(&mut self) -> TxPriority {
+        if self.emergencyallowance.allow() {
+            TxPriority::Emergency
+        } else {
+            warn!("Emergency allowance used up, queueing beacon as high priority");
+            TxPriority::High
+        }
+    }
+
+    fn handle_emergency(&mut self, sender: u8, beacon: EmergencyBeaconMessage) {
+        let position = beacon.position_degrees()
+            .map_or(String::new(), |(lat, lon)| format!(" at {:.7}, {:.7}", lat, lon));
+        let line = match self.emergencies.handle(sender, beacon.clone()) {
+            None => return,
+            Some(EmergencyEvent::Raised) => {
+                error!("EMERGENCY from node {}{}: {}", sender, position, beacon.text);
+                format!("[emergency] node {}{}: {}", sender, position, beacon.text)
+            },
+            Some(EmergencyEvent::Repeated) => {
+                format!("[emergency {}] node {}{}: {}", beacon.seq, sender, position, beacon.text)
+            },
+            Some(EmergencyEvent::Cleared) => {
+                info!("All clear from node {}", sender);
+                format!("[all-clear] node {}", sender)
+            },
+        };
+        // listeners that hung up are dropped
+        self.emergencylisteners.retain(|listener| listener.send(line.clone()).is_ok());
+    }
+
+    /// Send application data to a port on another node
+    /// fails if the payload is over `MAX_DATA_LEN`
+    pub fn send_data(&mut self, dest: u8, port: u8, payload: Vec<u8>) -> io::Result<()> {
+        self.submit_data(dest, port, payload, false).map(|_| ())
+    }
+
+    /// Send application data asking the destination for a receipt,
+    /// reported with `delivery_events` under the returned frame ID
+    /// returns None if the data waits for a route, its ID is reported once sent
+    pub fn send_data_with_receipt(&mut self, dest: u8, port: u8, payload: Vec<u8>) -> io::Result<Option<u8>> {
+        self.submit_data(dest, port, payload, true)
+    }
+
+    /// Receive an event whenever data sent with a receipt is delivered or fails
+    pub fn delivery_events(&mut self) -> Receiver<DeliveryEvent> {
+        self.delivery.subscribe()
+    }
+
+    /// Send a datagram to a port on another node, without waiting for a route or a receipt
+    /* Datagrams do not wait in the delivery queue like data does: with no
+    route to `dest` one is looked for and the datagram is refused. They
+    must fit a single frame so nodes without reassembly receive them. */
+    pub fn send_datagram(&mut self, dest: u8, port: u16, payload: Vec<u8>) -> io::Result<()> {
+        let msg = DatagramMessage::new(dest, port, payload)?;
+        if self.unreachable(dest) {
+            self.rediscover(dest);
+            return Err(Error::new(ErrorKind::NotConnected, format!("No route to {}, looking for one", dest)));
+        }
+        let route = self.route_to(dest);
+        let mut frame = msg.to_frame(self.frameids.allocate(Some(dest)), self.id, route)?;
+        if frame.payload().len() > self.opt.maxpacketsize {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Datagram does not fit a frame of {} bytes", self.opt.maxpacketsize)));
+        }
+        self.radio.txsender().send(frame.to_bytes(), TxPriority::Low)
+            .map_err(|e| mkerror(&format!("Could not queue datagram: {}", e)))
+    }
+
+    /// Send data on its route, or look for one first
+    fn submit_data(&mut self, dest: u8, port: u8, payload: Vec<u8>, receipt: bool) -> io::Result<Option<u8>> {
+        // data too large is refused before it waits for a route
+        let msg = DataMessage::new(dest, port, payload)?;
+        if self.unreachable(dest) {
+            debug!("No route to {}, data waits for discovery", &dest);
+            self.delivery.queue(dest, port, msg.payload, receipt);
+            self.rediscover(dest);
+            return Ok(None);
+        }
+        self.transmit_data(dest, port, msg.payload, receipt).map(Some)
+    }
+
+    fn transmit_data(&mut self, dest: u8, port: u8, payload: Vec<u8>, receipt: bool) -> io::Result<u8> {
+        let route = self.route_to(dest);
+        // an ID waiting on its ack is not handed out again until the ack comes or times out
+        let frameid = if receipt {
+            self.frameids.allocate_with_ack(Some(dest), Duration::from_millis(self.opt.acktimeout))
+                .ok_or_else(|| Error::new(ErrorKind::WouldBlock, format!("Every frame ID to {} is waiting on an ack", dest)))?
+        } else {
+            self.frameids.allocate(Some(dest))
+        };
+        let mut frame = DataMessage::new(dest, port, payload)?
+            .to_frame(frameid, self.id, route.clone())?
+            .with_ack_requested(receipt);
+        let chunks = self.chunks(&mut frame);
+        let queued = match self.reserve(route[0], &chunks) {
+            Ok(true) => Ok(()),
+            Ok(false) => chunks.iter().try_for_each(|chunk| self.tx_with_priority(chunk.clone(), TxPriority::Low)),
+            Err(e) => Err(e),
+        };
+        // a refused frame waits on no ack and no delivery
+        if let Err(e) = queued {
+            if receipt {
+                self.frameids.acknowledge(Some(dest), frameid);
+            }
+            return Err(e);
+        }
+        if receipt {
+            self.acktracker.expect(dest, frameid);
+        }
+        self.delivery.sent(&mut frame, route[0], chunks, TxPriority::Low);
+        Ok(frameid)
+    }
+
+    /// Hold a large burst back and ask its next hop to reserve the channel for it
+    /// returns false if the burst can go out right away
+    fn reserve(&mut self, nexthop: u8, frames: &[Vec<u8>]) -> io::Result<bool> {
+        let bytes: usize = frames.iter().map(|frame| frame.len()).sum();
+        if !self.reservations.as_ref().map_or(false, |res| res.needed(bytes)) {
+            return Ok(false);
+        }
+        // a held burst would go out once the radio is reset
+        if self.radio.txsender().is_halted() {
+            return Err(radio_stopped());
+        }
+        // the radio sends three frames per transmit slot at most
+        let airtime: Duration = frames.iter().map(|frame| self.radio.airtime(frame.len())).sum();
+        let duration = airtime.max(Duration::from_millis(self.opt.txslot) * (frames.len() as u32 / 3));
+        let request = self.reservations.as_mut().and_then(|res| res.request(nexthop, frames.to_vec(), duration));
+        if let Some(request) = request {
+            debug!("Asking {} for the channel for {}ms", &nexthop, &request.duration);
+            let frameid = self.frameids.allocate(None);
+            match request.to_frame(frameid, self.id, vec![self.id]) {
+                Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::High),
+                Err(e) => error!("Could not build TransmitRequestMessage: {}", e),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Grant a neighbor the channel, or hold back while another one has it
+    fn handle_transmit_request(&mut self, sender: u8, request: TransmitRequestMessage) {
+        let confirm = match self.reservations.as_mut() {
+            None => return,
+            Some(res) => res.handle_request(sender, &request),
+        };
+        if let Some(confirm) = confirm {
+            debug!("Granting {} the channel for {}ms", &sender, &confirm.duration);
+            let frameid = self.frameids.allocate(None);
+            match confirm.to_frame(frameid, self.id, vec![self.id]) {
+                Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::High),
+                Err(e) => error!("Could not build TransmitConfirmMessage: {}", e),
+            }
+        }
+        self.reservation_defer();
+    }
+
+    /// Send the burst a neighbor granted us the channel for, or hold back while another one has it
+    fn handle_transmit_confirm(&mut self, sender: u8, confirm: TransmitConfirmMessage) {
+        let frames = match self.reservations.as_mut() {
+            None => return,
+            Some(res) => res.handle_confirm(sender, &confirm),
+        };
+        if !frames.is_empty() {
+            debug!("Channel granted by {}, sending {} frames", &sender, frames.len());
+        }
+        for frame in frames {
+            self.tx_logged(frame, TxPriority::Low);
+        }
+        self.reservation_defer();
+    }
+
+    /// Have the radio hold back our frames while others have the channel
+    fn reservation_defer(&self) {
+        if let Some(until) = self.reservations.as_ref().and_then(|res| res.deferred_until()) {
+            self.radio.txsender().defer_until(until);
+        }
+    }
+
+    /// Send the bursts whose reservation was never granted
+    fn reservation_tick(&mut self) {
+        let frames = match self.reservations.as_mut() {
+            None => return,
+            Some(res) => res.expired(),
+        };
+        if !frames.is_empty() {
+            debug!("No reservation granted, sending {} frames anyway", frames.len());
+        }
+        for frame in frames {
+            self.tx_logged(frame, TxPriority::Low);
+        }
+    }
+
+    /// Send data whose route was found, repeat frames next hops did not send on,
+    /// and give up on data and hops that took too long
+    /* A relay tells the origin with a route error when its next hop fails.
+    At the origin the failed hop is our own first one, so we drop the routes
+    over it and look for new ones, the missing ack then fails the data. */
+    fn delivery_tick(&mut self) {
+        for dest in self.delivery.waiting() {
+            if self.unreachable(dest) {
+                continue;
+            }
+            for queued in self.delivery.take(dest) {
+                match self.transmit_data(queued.dest, queued.port, queued.payload, queued.receipt) {
+                    Ok(frameid) => trace!("Sent frame {} to {} once its route was found", &frameid, &dest),
+                    Err(e) => error!("Could not send data to {}: {}", &dest, e),
+                }
+            }
+        }
+        for queued in self.delivery.expire_queued() {
+            let frameid = self.frameids.allocate(Some(queued.dest));
+            let frame = DataMessage::new(queued.dest, queued.port, queued.payload)
+                .and_then(|msg| msg.to_frame(frameid, self.id, vec![msg.destination]));
+            let held = match frame {
+                Ok(frame) => self.hold(&mut frame.with_ack_requested(queued.receipt)),
+                Err(e) => {
+                    error!("Could not build DataMessage: {}", e);
+                    false
+                },
+            };
+            if !held {
+                warn!("No route to {} found, dropping data for port {}", &queued.dest, &queued.port);
+                if queued.receipt {
+                    self.delivery.publish(DeliveryEvent::Failed { dest: queued.dest, frameid: None });
+                }
+            }
+        }
+        let (resend, failures) = self.delivery.due();
+        for (bytes, priority) in resend {
+            self.tx_logged(bytes, priority);
+        }
+        for failure in failures {
+            if failure.origin != self.id {
+                warn!("{} did not send on frame {} from {} to {}", failure.nexthop, failure.frameid, failure.origin, failure.dest);
+                self.route_error(failure.origin, failure.nexthop, failure.dest);
+                continue;
+            }
+            warn!("{} did not send on our frame {} to {}, rediscovering", failure.nexthop, failure.frameid, failure.dest);
+            self.gateway_failed(failure.dest);
+            self.router.link_remove(self.id, failure.nexthop);
+            for dest in self.routes.remove_via(failure.nexthop) {
+                if dest != failure.dest {
+                    self.rediscover(dest);
+                }
+            }
+            self.rediscover(failure.dest);
+        }
+    }
+
+    /// true if we know a way to a node, data for it is sent without looking for a route first
+    pub fn reachable(&self, dest: u8) -> bool {
+        !self.unreachable(dest)
+    }
+
+    /// true if we know no way to a node
+    fn unreachable(&self, dest: u8) -> bool {
+        self.dsr.as_ref().and_then(|dsr| dsr.route(dest)).is_none()
+            && self.routes.best(dest).is_none() && self.router.node_route(dest).is_none() && !self.router.neighbors().contains(&dest)
+    }
+
+    /// Hops to a node, from the DSR cache, the routing table or else the mesh graph
+    /* Unknown nodes may still be direct neighbors, they are tried directly. */
+    fn route_to(&mut self, dest: u8) -> Vec<u8> {
+        self.dsr.as_ref().and_then(|dsr| dsr.route(dest))
+            .or_else(|| self.routes.route(dest))
+            .or_else(|| self.router.node_route(dest))
+            .unwrap_or(vec![dest])
+    }
+
+    /// Add the routes to both ends of a path we are on
+    /// `cost` is the ETX of the whole path in hundredths, if it came with it
+    fn learn_path(&mut self, path: &[u8], source: RouteSource, cost: Option<u16>) {
+        let at = match path.iter().position(|nodeid| *nodeid == self.id) {
+            None => return,
+            Some(at) => at,
+        };
+        let forward = path[at + 1..].to_vec();
+        let mut backward: Vec<u8> = path[..at].to_vec();
+        backward.reverse();
+        for route in vec![forward, backward] {
+            if !self.loops.path_ok(&route, self.id) {
+                debug!("Not taking route {:?}, it loops", &route);
+                continue;
+            }
+            if let Some(dest) = route.last().cloned() {
+                // at either end of the path its cost is ours, elsewhere it is estimated
+                let cost = match cost {
+                    Some(cost) if route.len() + 1 == path.len() => cost as f32 / 100.0,
+                    _ => self.path_cost(&route),
+                };
+                self.routes.add(dest, route, cost, source);
+            }
+        }
+    }
+
+    /// ETX of a path, the first link from our estimate and the others taken as perfect
+    fn path_cost(&mut self, path: &[u8]) -> f32 {
+        let first = match path.first() {
+            None => return 0.0,
+            Some(first) => self.link_cost(*first),
+        };
+        first + (path.len() - 1) as f32 * hop_cost(1.0)
+    }
+
+    /// ETX of the link with a neighbor, from its heartbeats, frame IDs and hellos
+    /* A link heard too little to tell counts as a single hop. */
+    fn link_cost(&mut self, nodeid: u8) -> f32 {
+        let loss = self.neighbors.get(nodeid).and_then(|stats| stats.loss);
+        self.links.neighbor_at(nodeid, Instant::now()).with_loss(loss).route_cost(self.opt.etxminsamples)
+    }
+
+    /// Hold a frame for its destination if we store and forward
+    fn hold(&mut self, frame: &mut Frame) -> bool {
+        let dest = match frame.route().last() {
+            Some(dest) if holdable(&frame.msgtype()) => *dest,
+            _ => return false,
+        };
+        let store = match self.store.as_mut() {
+            None => return false,
+            Some(store) => store,
+        };
+        let held = store.hold(HeldMessage::from_frame(frame, dest, unix_millis()));
+        if held {
+            info!("Holding {:?} from {} for unreachable node {}", frame.msgtype(), frame.sender(), dest);
+        }
+        held
+    }
+
+    /// Tell a node we just heard that messages are waiting for it
+    fn notify_held(&mut self, nodeid: u8) {
+        let count = match &self.store {
+            None => return,
+            Some(store) => store.count(nodeid),
+        };
+        if count == 0 || !self.heldnotices.allow(nodeid) {
+            return;
+        }
+        let route = self.route_to(nodeid);
+        match HeldNoticeMessage::new(count.min(u16::MAX as usize) as u16).to_frame(self.frameids.allocate(Some(nodeid)), self.id, route) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+            Err(e) => error!("Could not build HeldNoticeMessage: {}", e),
+        }
+    }
+
+    /// Send a node everything held for it, oldest first
+    fn send_held(&mut self, nodeid: u8) {
+        let held = match self.store.as_mut() {
+            None => return,
+            Some(store) => store.take(nodeid),
+        };
+        info!("Sending {} held messages to node {}", held.len(), nodeid);
+        let route = self.route_to(nodeid);
+        for msg in held {
+            match msg.to_frame(route.clone()) {
+                Err(e) => error!("Dropping held message for {}: {}", nodeid, e),
+                Ok(mut frame) => {
+                    for chunk in self.chunks(&mut frame) {
+                        self.tx_logged(chunk, TxPriority::Low);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Give up on held messages past their max age
+    fn store_tick(&mut self) {
+        if let Some(store) = self.store.as_mut() {
+            let expired = store.expire(unix_millis());
+            if expired > 0 {
+                info!("Gave up on {} held messages", expired);
+            }
+        }
+    }
+
+    /// Pass received data on to the receivers of its port
+    fn handle_data(&mut self, sender: u8, data: DataMessage) {
+        if data.destination != self.id {
+            debug!("Dropping data for {} delivered to us", &data.destination);
+            return;
+        }
+        // listening on the probe port answers bandwidth probes
+        if data.port == BANDWIDTH_PROBE_PORT && self.ports.contains_key(&data.port) {
+            if let Some(echo) = probe_echo(&data.payload) {
+                if let Err(e) = self.send_data(sender, BANDWIDTH_PROBE_PORT, echo) {
+                    debug!("Could not answer bandwidth probe from {}: {}", &sender, e);
+                }
+                return;
+            }
+        }
+        match self.ports.get_mut(&data.port) {
+            None => trace!("Dropping data from {} for closed port {}", &sender, &data.port),
+            Some(receivers) => {
+                // receivers that hung up are dropped
+                receivers.retain(|receiver| receiver.send((sender, data.payload.clone())).is_ok());
+                if receivers.is_empty() {
+                    self.ports.remove(&data.port);
+                }
+            }
+        }
+    }
+
+    /// Pass a received datagram on to the receivers of its port
+    fn handle_datagram(&mut self, sender: u8, datagram: DatagramMessage) {
+        if datagram.destination != self.id {
+            debug!("Dropping datagram for {} delivered to us", &datagram.destination);
+            return;
+        }
+        match self.datagramports.get_mut(&datagram.port) {
+            None => trace!("Dropping datagram from {} for closed port {}", &sender, &datagram.port),
+            Some(receivers) => {
+                // receivers that hung up are dropped
+                receivers.retain(|receiver| receiver.send((sender, datagram.payload.clone())).is_ok());
+                if receivers.is_empty() {
+                    self.datagramports.remove(&datagram.port);
+                }
+            }
+        }
+    }
+
+    /// Report the delivery of one of our texts
+    fn handle_text_receipt(&mut self, sender: u8, msgid: u16) {
+        match self.texts.iter().position(|t| t.dest == sender && t.msgid == msgid) {
+            None => trace!("Dropping unexpected receipt {} from {}", &msgid, &sender),
+            Some(i) => {
+                let pending = self.texts.remove(i);
+                debug!("Text {} delivered to {}", &msgid, &sender);
+                if let Some(reply) = pending.reply {
+                    reply.send(format!("delivered to node {} after {}ms", sender, pending.sent.elapsed().as_millis())).ok();
+                }
+            }
+        }
+    }
+
+    /// Give up on texts that were never confirmed
+    fn text_tick(&mut self) {
+        let timeout = Duration::from_millis(self.opt.receipttimeout);
+        self.texts.retain(|t| {
+            if t.sent.elapsed() < timeout {
+                return true;
+            }
+            warn!("No delivery receipt for text {} to node {}", &t.msgid, &t.dest);
+            if let Some(reply) = &t.reply {
+                reply.send(format!("no delivery receipt from node {}", t.dest)).ok();
+            }
+            false
+        });
+    }
+
+    /// Send a command for another node to run, returning its nonce
+    /* Nonces are milliseconds since the epoch, so they keep growing
+    across restarts. */
+    pub fn send_remote_command(&mut self, dest: u8, command: RemoteCommand) -> io::Result<u64> {
+        self.remotenonce = unix_millis().max(self.remotenonce + 1);
+        let msg = RemoteCommandMessage { header: None, nonce: self.remotenonce, command };
+        let route = self.route_to(dest);
+        let frameid = self.frameids.allocate(Some(dest));
+        match msg.to_frame(frameid, self.id, route) {
+            Ok(mut frame) => for chunk in self.chunks(&mut frame) {
+                self.tx_with_priority(chunk, TxPriority::Normal)?;
+            },
+            Err(e) => error!("Could not build RemoteCommandMessage: {}", e),
+        }
+        Ok(self.remotenonce)
+    }
+
+    /// Run a command from another node if allowed, and send back the result
+    fn handle_remote_command(&mut self, sender: u8, msg: RemoteCommandMessage) {
+        let result = match self.remote.check(sender, &msg) {
+            Err(reason) => {
+                warn!("Rejected remote command \"{}\" from node {}: {}", msg.command.to_line(), sender, reason);
+                Err(reason)
+            },
+            Ok(()) => {
+                info!("Running remote command \"{}\" from node {}", msg.command.to_line(), sender);
+                self.run_remote_command(msg.command)
+            },
+        };
+        let reply = match result {
+            Ok(output) => RemoteCommandResultMessage::new(msg.nonce, true, &output),
+            Err(reason) => RemoteCommandResultMessage::new(msg.nonce, false, &reason),
+        };
+        let route = self.route_to(sender);
+        let frameid = self.frameids.allocate(Some(sender));
+        match reply.to_frame(frameid, self.id, route) {
+            Ok(mut frame) => for chunk in self.chunks(&mut frame) {
+                self.tx_logged(chunk, TxPriority::Normal);
+            },
+            Err(e) => error!("Could not build RemoteCommandResultMessage: {}", e),
+        }
+    }
+
+    /// Sign a change of a setting on another node and send it, returning its nonce
+    pub fn send_config(&mut self, dest: u8, key: &str, value: &str) -> Result<u64, String> {
+        let secret = self.configkey.ok_or(String::from("no configkey is set"))?;
+        self.remotenonce = unix_millis().max(self.remotenonce + 1);
+        let msg = ConfigMessage {
+            header: None,
+            key: String::from(key),
+            value: String::from(value),
+            requester_id: self.id,
+            signature: sign_config(&secret, self.remotenonce, key, value, self.id),
+        };
+        let route = self.route_to(dest);
+        let frameid = self.frameids.allocate(Some(dest));
+        match msg.to_frame(frameid, self.id, route) {
+            Ok(mut frame) => for chunk in self.chunks(&mut frame) {
+                self.tx_with_priority(chunk, TxPriority::Normal).map_err(|e| e.to_string())?;
+            },
+            Err(e) => error!("Could not build ConfigMessage: {}", e),
+        }
+        Ok(self.remotenonce)
+    }
+
+    /// Apply a config change from another node if its signature holds, and ack it
+    fn handle_config(&mut self, sender: u8, msg: ConfigMessage) {
+        let result = self.config.check(sender, &msg)
+            .and_then(|()| remote_set(&mut self.opt, &msg.key, &msg.value));
+        let output = match &result {
+            Ok(()) => {
+                info!("Node {} set {} to {}", sender, msg.key, msg.value);
+                format!("{} set to {}", msg.key, msg.value)
+            },
+            Err(reason) => {
+                warn!("Rejected config change of {} from node {}: {}", msg.key, sender, reason);
+                reason.clone()
+            },
+        };
+        let ack = ConfigAckMessage { header: None, nonce: config_nonce(&msg.signature), ok: result.is_ok(), output };
+        let route = self.route_to(sender);
+        let frameid = self.frameids.allocate(Some(sender));
+        match ack.to_frame(frameid, self.id, route) {
+            Ok(mut frame) => for chunk in self.chunks(&mut frame) {
+                self.tx_logged(chunk, TxPriority::Normal);
+            },
+            Err(e) => error!("Could not build ConfigAckMessage: {}", e),
+        }
+    }
+
+    fn run_remote_command(&mut self, command: RemoteCommand) -> Result<String, String> {
+        match command {
+            RemoteCommand::Get(key) => remote_get(&self.opt, &key),
+            RemoteCommand::Set(key, value) => {
+                remote_set(&mut self.opt, &key, &value)?;
+                Ok(format!("{} set to {}", key, value))
+            },
+            RemoteCommand::RestartRadio => {
+                self.radio.restart();
+                Ok(String::from("radio restarted"))
+            },
+            RemoteCommand::SelfTest => {
+                let version = self.radio.query("sys get ver").map_err(|e| format!("radio does not answer: {}", e))?;
+                let vdd = self.radio.query("sys get vdd").and_then(|resp| parse_vdd(&resp))
+                    .map_err(|e| format!("could not read supply voltage: {}", e))?;
+                Ok(format!("radio {}  vdd {}mV  tx queue {}", version, vdd, self.radio.txsender().len()))
+            },
+            RemoteCommand::Stats => {
+                serde_json::to_string(&self.telemetry_sample()).map_err(|e| e.to_string())
+            },
+        }
+    }
+
+    /// Give up on remote commands whose result never came
+    fn remote_tick(&mut self) {
+        let timeout = Duration::from_millis(self.opt.pingtimeout);
+        self.remotereplies.retain(|(dest, _), (sent, reply)| {
+            if sent.elapsed() < timeout {
+                return true;
+            }
+            reply.send(format!("no result from node {}", dest)).ok();
+            false
+        });
+    }
+
+    /// Match a pong to a running ping command and report it
+    fn handle_pong(&mut self, sender: u8, seq: u16, quality: LinkQuality) {
+        let now = Instant::now();
+        for (session, reply) in self.pings.iter_mut().filter(|(s, _)| s.dest == sender) {
+            if let Some(rtt) = session.on_pong(seq, now) {
+                let rssi = quality.rssi.map_or(String::from("?"), |r| r.to_string());
+                let snr = quality.snr.map_or(String::from("?"), |s| s.to_string());
+                reply.send(format!("reply from {}: seq={} rtt={}ms rssi={}dBm snr={}dB",
+                    sender, seq, rtt.as_millis(), rssi, snr)).ok();
+                return;
+            }
+        }
+        trace!("Dropping unexpected pong {} from {}", &seq, &sender);
+    }
+
+    /// Handle an IP assignment
+    /// ensures a new local route is set up and node
+    /// accepts new IP
+    fn handle_ip_assignment(&mut self, ipaddr: Ipv4Addr, leasetime: Option<Duration>, resolver: Option<Ipv4Addr>) {
+        let taken = assignment(self.opt.staticip, self.ipaddr, ipaddr);
+        if let Assignment::KeepStatic(staticip) = taken {
+            warn!("Ignoring IP {} from the gateway, keeping static IP {}", ipaddr, staticip);
+            return;
+        }
+        // renew halfway through the lease
+        self.leaserenew = leasetime.map(|leasetime| Instant::now() + leasetime / 2);
+        self.iprequested = None;
+        if let (Some(resolver), Some(resolvfile)) = (resolver, self.opt.resolvfile.as_ref()) {
+            match fs::write(resolvfile, format!("nameserver {}\n", resolver)) {
+                Ok(()) => debug!("Using {} as resolver", resolver),
+                Err(e) => error!("Could not write resolver to {}: {}", resolvfile.display(), e),
+            }
+        }
+        if taken == Assignment::Renew {
+            return;
+        }
+        if let Some(old) = self.ipaddr {
+            warn!("Gateway moved us from IP {} to {}", old, ipaddr);
+        }
+        self.ipaddr = Some(ipaddr);
+        self.networktunnel.assignipaddr(&ipaddr);
+        self.networktunnel.routeipaddr(&ipaddr, &self.networktunnel.tunip.unwrap());
+        self.router.handle_ip_assignment(&ipaddr);
+    }
+
+    /// Note the address a node announced, reporting it if another node announces the same
+    /* A gateway keeps addresses it did not lease, static ones, out of its
+    pool. */
+    fn claim_address(&mut self, nodeid: u8, ipaddr: Ipv4Addr) {
+        match self.addressclaims.claim(nodeid, ipaddr) {
+            Some(other) if other == self.id => error!("Node {} announces IP {}, which is ours! Two nodes are using the same address", nodeid, ipaddr),
+            Some(other) => error!("Nodes {} and {} both announce IP {}! Two nodes are using the same address", other, nodeid, ipaddr),
+            None => {},
+        }
+        if let Some(leases) = self.leases.as_mut() {
+            if !leases.iter().any(|(_, lease)| lease.ipaddr == ipaddr) && self.ipaddr != Some(ipaddr) {
+                leases.reserve(ipaddr);
+            }
+        }
+    }
+
+    /// Lease an address to a node, answering with the address or the reason it failed
+    fn handle_ip_request(&mut self, sender: u8, requested: Option<Ipv4Addr>, txsender: &TxQueueSender) {
+        let route = self.route_to(sender);
+        let leases = match self.leases.as_mut() {
+            None => {
+                debug!("Dropping IP request from {}, we are not a gateway", &sender);
+                return;
+            },
+            Some(leases) => leases,
+        };
+        let leasetime = leases.leasetime();
+        let frameid = self.frameids.allocate(Some(sender));
+        let frame = match leases.assign(sender, requested, self.clock.now()) {
+            Err(failure) => {
+                warn!("Could not assign an IP to node {}: {}", &sender, &failure.reason);
+                failure.to_frame(frameid, self.id, route)
+            },
+            Ok((ipaddr, isnew)) => {
+                // since we are a gateway, we must route the IP locally
+                self.router.ip_add(sender, ipaddr);
+                self.learn_address(ipaddr, sender);
+                if isnew {
+                    info!("Leased new IP {} to node {}", &ipaddr.to_string(), &sender);
+                    self.networktunnel.routeipaddr(&ipaddr, &self.ipaddr.unwrap());
+                } else {
+                    debug!("Renewed lease of IP {} for node {}", &ipaddr.to_string(), &sender);
+                }
+                let mut msg = IPAssignSuccessMessage::new(ipaddr, Some(leasetime));
+                // we are the resolver, when we proxy DNS
+                msg.resolver = self.dns.as_ref().and(self.ipaddr);
+                msg.to_frame(frameid, self.id, route)
+            }
+        };
+        match frame {
+            Ok(mut frame) => txsender.send_logged(frame.to_bytes(), TxPriority::Normal),
+            Err(e) => error!("Could not build IP assignment: {}", e),
+        }
+    }
+
+    /// Correct mesh time by the offset of a sync that travelled `hops`
+    fn handle_timesync(&mut self, offset: i64, hops: u8) {
+        // even a sync we don't take shows the schedule is still the gateway's
+        if let Some(duty) = self.dutycycle.as_mut() {
+            duty.synced();
+        }
+        if !self.clock.update(offset, hops) {
+            trace!("Ignoring time sync from {} hops, a closer one is current", &hops);
+            return;
+        }
+        debug!("Mesh time offset is {}ms from a sync {} hops away", &offset, &hops);
+        if self.opt.clockstep && offset.abs() > CLOCK_STEP_THRESHOLD {
+            if let Err(e) = self.clock.step_system_clock() {
+                error!("Could not step the system clock, keeping the offset: {}", e);
+            }
+        }
+    }
+
+    /// Follow the sleep schedule, sleeping the radio between windows
+    /// returns false while it sleeps
+    /* The gateway keeps the mesh time, so it is always in sync. */
+    fn duty_tick(&mut self) -> bool {
+        let meshtime = self.clock.now();
+        let duty = match self.dutycycle.as_mut() {
+            None => return true,
+            Some(duty) => duty,
+        };
+        if self.opt.isgateway {
+            duty.synced();
+        }
+        match duty.tick(meshtime) {
+            Some(DutyState::Asleep) => {
+                let sleep = duty.wake_in(meshtime);
+                debug!("Sleeping the radio for {}ms until the next window", sleep.as_millis());
+                if let Err(e) = self.radio.sleep(sleep) {
+                    error!("Could not put the radio to sleep: {}", e);
+                }
+            },
+            Some(DutyState::Awake) => debug!("Awake window opened"),
+            Some(DutyState::AlwaysOn) => warn!("No time sync for {}s, listening until the next one", self.opt.dutycycleresync / 1000),
+            None => {},
+        }
+        duty.is_awake()
+    }
+
+    /// Announce our time early for a node that asked, at most once per heartbeat interval
+    fn handle_timesync_request(&mut self, sender: u8) {
+        let interval = Duration::from_millis(self.opt.heartbeatinterval);
+        if self.timesyncsent.map_or(false, |sent| sent.elapsed() < interval) {
+            trace!("Time sync requested by {}, announced recently", &sender);
+            return;
+        }
+        debug!("Time sync requested by {}", &sender);
+        self.timesyncsent = None;
+    }
+
+    /// Announce our uplink and load as a gateway, or notice the best gateway changed
+    /* On a failover the packets that may have been lost with the old
+    gateway are sent again through the new one. */
+    fn gateway_tick(&mut self) {
+        if !self.opt.isgateway {
+            let best = self.router.gateway_id();
+            if best != self.bestgateway {
+                match (self.bestgateway, best) {
+                    (_, None) => warn!("No gateway heard from anymore"),
+                    (Some(old), Some(gateway)) => {
+                        warn!("Gateway {} failed, switching to gateway {}", &old, &gateway);
+                        for bytes in self.gatewaybacklog.requeue(old) {
+                            if let Ok(packet) = IpPacket::new(bytes) {
+                                self.send_ip(gateway, packet);
+                            }
+                        }
+                    },
+                    (None, Some(gateway)) => info!("Using gateway {}", &gateway),
+                }
+                self.bestgateway = best;
+            }
+            return;
+        }
+        let interval = Duration::from_millis(self.opt.gatewayinterval);
+        if interval.as_millis() == 0 || self.gatewaysent.map_or(false, |sent| sent.elapsed() < interval) {
+            return;
+        }
+        self.gatewaysent = Some(Instant::now());
+        let uplink = uplink_up(&self.networktunnel.tunname);
+        let load = (self.radio.txsender().len() * 100 / self.opt.txqueuesize.max(1)).min(100) as u8;
+        trace!("Announcing gateway, uplink {} and load {}%", uplink, load);
+        match GatewayAnnounceMessage::new(uplink, load).to_frame(self.frameids.allocate(None), self.id, vec![self.id]) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+            Err(e) => error!("Could not build GatewayAnnounceMessage: {}", e),
+        }
+    }
+
+    /// Announce whether we reach the internet as the gateway, at once when it changes
+    fn network_tick(&mut self) {
+        let interval = Duration::from_millis(self.opt.networkinterval);
+        if !self.opt.isgateway || interval.as_millis() == 0 || self.networkchecked.map_or(false, |checked| checked.elapsed() < NETWORK_CHECK_INTERVAL) {
+            return;
+        }
+        self.networkchecked = Some(Instant::now());
+        let reachable = uplink_up(&self.networktunnel.tunname);
+        match self.networksent {
+            Some((sent, announced)) if announced == reachable && sent.elapsed() < interval => return,
+            Some((_, announced)) if announced != reachable => {
+                info!("Uplink {}, telling the mesh", if reachable { "up" } else { "down" });
+            },
+            _ => {},
+        }
+        self.networksent = Some((Instant::now(), reachable));
+        trace!("Announcing internet reachable {}", reachable);
+        match NetworkAnnounceMessage::new(reachable, self.id).to_frame(self.frameids.allocate(None), self.id, vec![self.id]) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+            Err(e) => error!("Could not build NetworkAnnounceMessage: {}", e),
+        }
+    }
+
+    /// Tell our neighbors how well we hear them, once the hello interval passed
+    fn hello_tick(&mut self) {
+        let mut interval = Duration::from_millis(self.opt.hellointerval);
+        if self.partitions.boosted() {
+            interval /= PARTITION_SPEEDUP;
+        }
+        if interval.as_millis() == 0 || self.hellosent.elapsed() < interval {
+            return;
+        }
+        let now = Instant::now();
+        self.hellosent = now;
+        let neighbors = self.links.hello_at(now);
+        if neighbors.is_empty() {
+            return;
+        }
+        trace!("Sending hello with {} neighbors", neighbors.len());
+        match NeighborHelloMessage::new(neighbors).to_frame(self.frameids.allocate(None), self.id, vec![self.id]) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+            Err(e) => error!("Could not build NeighborHelloMessage: {}", e),
+        }
+    }
+
+    /// Every node heard directly, with its link statistics
+    pub fn neighbors(&self) -> Vec<NeighborStats> {
+        self.neighbors.neighbors()
+    }
+
+    /// Receive an event whenever a neighbor appears or expires
+    pub fn neighbor_events(&mut self) -> Receiver<NeighborEvent> {
+        self.neighbors.subscribe()
+    }
+
+    /// Receive an event whenever the mesh seems to split or comes back together
+    pub fn partition_events(&mut self) -> Receiver<PartitionEvent> {
+        self.partitions.subscribe()
+    }
+
+    /// The nodes heard lately and those lost in a partition
+    pub fn partitions(&self) -> &PartitionDetector {
+        &self.partitions
+    }
+
+    /// Act on nodes going silent together or coming back, true if broadcasts should speed up
+    /* Routes into a lost region only hold frames up until they fail, so
+    they go right away. Nodes coming back are rediscovered rather than
+    left to whatever route their first frame brought along. */
+    fn partition_tick(&mut self) -> bool {
+        if self.opt.partitionwindow == 0 {
+            return false;
+        }
+        let events = self.partitions.tick();
+        for event in events.iter() {
+            match event {
+                PartitionEvent::Suspected(lost) => {
+                    warn!("Partition suspected, {} nodes went silent together: {:?}", lost.len(), lost);
+                    self.routes.remove_through(lost);
+                    for nodeid in lost {
+                        self.router.node_remove(*nodeid);
+                    }
+                },
+                PartitionEvent::Rejoined(nodes) => {
+                    info!("Nodes {:?} are reachable again, revalidating their routes", nodes);
+                    for nodeid in nodes {
+                        self.rediscover(*nodeid);
+                    }
+                    self.topologychanged = true;
+                },
+            }
+        }
+        !events.is_empty()
+    }
+
+    /// Receive an event whenever another node turns out to use our ID
+    pub fn collision_events(&mut self) -> Receiver<CollisionEvent> {
+        self.collisions.subscribe()
+    }
+
+    /// Look for another node using our ID, or two using a neighbor's, in the token of a heartbeat
+    /* Heartbeats are never relayed, one with our ID was not sent by us. */
+    fn instance_heard(&mut self, frame: &mut Frame) {
+        if frame.msgtype() != MessageType::Heartbeat || !frame.route().is_empty() {
+            return;
+        }
+        let token = match HeartbeatMessage::from_frame(frame).map(|msg| msg.token) {
+            Ok(Some(token)) => token,
+            // older nodes send no token
+            _ => return,
+        };
+        let sender = frame.sender();
+        if sender != self.id {
+            if self.neighbors.token_heard(sender, token) {
+                error!("Two nodes are using ID {}, check their settings", sender);
+                self.send_error(sender, frame.frameid(), MeshError::DuplicateNodeId);
+            }
+            return;
+        }
+        let event = match self.collisions.own_id_heard(sender, token) {
+            None => return,
+            Some(event) => event,
+        };
+        error!("Another node is using our ID {} (instance {:08x}, ours {:08x}), check for cloned settings",
+            sender, event.theirs, event.ours);
+        if self.opt.resolveidcollisions && self.collisions.yields(&event) {
+            self.resolve_collision();
+        }
+    }
+
+    /// Take a new ID derived from the radio's hardware EUI, one no node we know of uses
+    fn resolve_collision(&mut self) {
+        let eui = match self.radio.query("sys get hweui").and_then(|resp| parse_hweui(&resp)) {
+            Err(e) => {
+                error!("Could not read the hardware EUI to take another node ID: {}", e);
+                return;
+            },
+            Ok(eui) => eui,
+        };
+        let free = (1..=u8::MAX)
+            .map(|attempt| derive_node_id(&eui, attempt))
+            .find(|id| *id != self.id && self.neighbors.get(*id).is_none()
+                && !self.nodeinfos.contains(*id) && self.routes.best(*id).is_none());
+        match free {
+            None => error!("No free node ID left to resolve the collision"),
+            Some(id) => {
+                warn!("Taking node ID {} to resolve the collision", id);
+                self.take_node_id(id);
+            },
+        }
+    }
+
+    /// Update the neighbor table from a frame its sender sent us itself
+    fn neighbor_heard(&mut self, frame: &mut Frame, quality: LinkQuality) {
+        let sender = frame.sender();
+        let route = frame.route();
+        if sender == self.id || !(route.is_empty() || route == vec![sender]) {
+            return;
+        }
+        if self.neighbors.heard(sender, frame.frameid(), quality) {
+            debug!("Neighbor {} appeared", sender);
+            self.topologychanged = true;
+            // it may have been away while messages for it came in
+            self.notify_held(sender);
+        }
+        self.adaptive_data_rate(sender, quality);
+    }
+
+    /// Move the radio to the spreading factor the neighbors' SNR allows
+    fn adaptive_data_rate(&mut self, nodeid: u8, quality: LinkQuality) {
+        let event = match (self.adr.as_mut(), quality.snr) {
+            (Some(adr), Some(snr)) => adr.record(nodeid, snr),
+            _ => None,
+        };
+        if let Some(AdrEvent::SetSF(sf)) = event {
+            self.announce_sf(sf);
+        }
+    }
+
+    /// Tell the neighbors the spreading factor we move to a heartbeat interval from now
+    /* Heartbeats carry it until then, so neighbors that hear one of them
+    move along instead of losing us. */
+    fn announce_sf(&mut self, sf: u8) {
+        info!("Moving from spreading factor {} to {}", self.opt.radiosf, sf);
+        let due = Instant::now() + Duration::from_millis(self.opt.heartbeatinterval);
+        self.adrpending = Some((sf, due));
+        self.heartbeat();
+    }
+
+    /// A neighbor's heartbeat carried the spreading factor it is on or moving to
+    fn sf_announced(&mut self, nodeid: u8, sf: u8) {
+        // while our own change is pending, our heartbeats are the ones to follow
+        if sf == 0 || self.adrpending.is_some() || sf == self.opt.radiosf {
+            return;
+        }
+        if let Some(AdrEvent::SetSF(sf)) = self.adr.as_mut().and_then(|adr| adr.follow(sf)) {
+            debug!("Neighbor {} announced spreading factor {}, following it", nodeid, sf);
+            self.announce_sf(sf);
+        }
+    }
+
+    /// Move the radio to an announced spreading factor once due, and back to the highest when nobody is heard
+    fn adr_tick(&mut self) {
+        let silent = self.neighbors.neighbors().is_empty()
+            || self.lastrx.elapsed() >= Duration::from_millis(self.opt.neighbortimeout);
+        if silent {
+            if let Some(AdrEvent::SetSF(sf)) = self.adr.as_mut().and_then(|adr| adr.fall_back()) {
+                warn!("No neighbor heard, going back to spreading factor {}", sf);
+                self.adrpending = None;
+                self.set_radio_sf(sf);
+                return;
+            }
+        }
+        match self.adrpending {
+            Some((sf, due)) if due <= Instant::now() => {
+                self.adrpending = None;
+                self.set_radio_sf(sf);
+            },
+            _ => {},
+        }
+    }
+
+    /// Put the radio on a spreading factor the adaptive data rate picked
+    fn set_radio_sf(&mut self, sf: u8) {
+        if sf == self.opt.radiosf {
+            return;
+        }
+        match self.radio.query(&format!("radio set sf sf{}", sf)).and_then(|resp| assert_response(resp, String::from("ok"))) {
+            Ok(()) => {
+                info!("Spreading factor changed from {} to {}", self.opt.radiosf, sf);
+                self.opt.radiosf = sf;
+            },
+            Err(e) => {
+                warn!("Could not set spreading factor {}: {}", sf, e);
+                if let Some(adr) = self.adr.as_mut() {
+                    adr.set_sf(self.opt.radiosf);
+                }
+            },
+        }
+    }
+
+    /// Owe an ack for a frame that asked for one and was meant for us
+    fn queue_ack(&mut self, frame: &mut Frame, quality: LinkQuality) {
+        if frame.ack_requested() && frame.msgtype() != MessageType::Ack && frame.route().last() == Some(&self.id) {
+            self.acks.push(frame.sender(), frame.frameid(), quality);
+        }
+    }
+
+    /// Send the acks that are due, and forget frames whose ack never came
+    fn ack_tick(&mut self) {
+        for (dest, ack) in self.acks.poll() {
+            let route = self.route_to(dest);
+            let frameid = self.frameids.allocate(Some(dest));
+            match ack.to_frame(frameid, self.id, route) {
+                Ok(mut frame) => for chunk in self.chunks(&mut frame) {
+                    self.tx_logged(chunk, TxPriority::High);
+                },
+                Err(e) => error!("Could not build AckMessage: {}", e),
+            }
+        }
+        for (dest, frameid) in self.acktracker.expire() {
+            debug!("No ack from {} for frame {}", &dest, &frameid);
+            self.frameids.acknowledge(Some(dest), frameid);
+            self.delivery.publish(DeliveryEvent::Failed { dest, frameid: Some(frameid) });
+        }
+    }
+
+    /// Send a frame asking its destination to ack it
+    pub fn send_with_ack(&mut self, frame: Frame, priority: TxPriority) -> io::Result<()> {
+        let mut frame = frame.with_ack_requested(true);
+        for chunk in self.chunks(&mut frame) {
+            self.tx_with_priority(chunk, priority)?;
+        }
+        if let Some(dest) = frame.route().last().cloned() {
+            self.acktracker.expect(dest, frame.frameid());
+        }
+        Ok(())
+    }
+
+    /// Announce our time as the gateway, or ask for the gateway's until we have it
+    /* Without a sync yet, a node asks once per heartbeat interval. */
+    fn timesync_tick(&mut self) {
+        let interval = Duration::from_millis(self.opt.timesyncinterval);
+        if self.opt.isgateway {
+            if interval.as_millis() == 0 || self.timesyncsent.map_or(false, |sent| sent.elapsed() < interval) {
+                return;
+            }
+            self.timesyncsent = Some(Instant::now());
+            match TimeSyncMessage::new(unix_millis()).to_frame(self.frameids.allocate(None), self.id, vec![self.id]) {
+                Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::High),
+                Err(e) => error!("Could not build TimeSyncMessage: {}", e),
+            }
+            return;
+        }
+
+        let retry = Duration::from_millis(self.opt.heartbeatinterval);
+        if self.clock.is_synced() || self.timesyncsent.map_or(false, |sent| sent.elapsed() < retry) {
+            return;
+        }
+        if let Some(gateway) = self.router.gateway_id() {
+            self.timesyncsent = Some(Instant::now());
+            debug!("Asking gateway {} for the time", &gateway);
+            let route = self.route_to(gateway);
+            match TimeSyncMessage::request().to_frame(self.frameids.allocate(Some(gateway)), self.id, route) {
+                Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+                Err(e) => error!("Could not build TimeSyncMessage: {}", e),
+            }
+        }
+    }
+
+    /// Ask the gateway for an address until we have one, and renew its lease
+    fn ip_tick(&mut self) {
+        let due = match (self.ipaddr, self.leaserenew) {
+            _ if self.opt.isgateway || self.opt.staticip.is_some() => false,
+            (None, _) => true,
+            (Some(_), Some(renew)) => Instant::now() >= renew,
+            // assigned without a lease, it never expires
+            (Some(_), None) => false,
+        };
+        let interval = Duration::from_millis(self.opt.iprequestinterval);
+        if !due || self.iprequested.map_or(false, |sent| sent.elapsed() < interval) {
+            return;
+        }
+        self.iprequested = Some(Instant::now());
+
+        match self.router.gateway_id() {
+            None => warn!("No gateway known yet, waiting for its broadcast to request an IP"),
+            Some(gateway) => {
+                debug!("Requesting IP from gateway {}", &gateway);
+                let route = self.route_to(gateway);
+                match IPRequestMessage::new(self.ipaddr).to_frame(self.frameids.allocate(Some(gateway)), self.id, route) {
+                    Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+                    Err(e) => error!("Could not build IPRequestMessage: {}", e),
+                }
+            }
+        }
+    }
+
+    /// Handle routing of a tunnel packet
+    /// checks if packet was destinated for this node or if
+    /// routing logic should be applied and forwarding necessary
+    fn handle_tun_ip(&mut self, packet: IpPacket) {
+        // neighbor discovery and the rest of the link's traffic stays here
+        if let IpPacket::V6(ipv6) = &packet {
+            match classify_ipv6(ipv6) {
+                Ipv6Traffic::Unicast(_) => {},
+                Ipv6Traffic::NeighborSolicitation(target) => {
+                    self.handle_neighbor_solicitation(ipv6, target);
+                    return;
+                },
+                traffic => {
+                    trace!("Not carrying {:?} over the mesh", traffic);
+                    return;
+                },
+            }
+        }
+        // apply routing logic
+        // if it cannot be routed, drop it
+        let ours = match &packet {
+            IpPacket::V4(ipv4) => self.ipaddr.map(|ipaddr| ipv4.destination() == ipaddr),
+            IpPacket::V6(ipv6) => self.ipv6addr.map(|ipaddr| ipv6.destination() == ipaddr),
+        };
+        match ours {
+            // no address of this version yet
+            None => {},
+            Some(true) => {
+                debug!("Received packet from {}", packet.source());
+                if !self.opt.debug {
+                    // TODO route to tunnel during debug
+                    self.networktunnel.send(packet);
+                }
+            },
+            // the node with the address, or the gateway for everything outside the mesh
+            Some(false) if !self.filter.allow(FilterDirection::Out, &packet) => {
+                trace!("Filtered packet to {} from the tunnel", packet.destination());
+            },
+            Some(false) => self.send_ip_to(packet),
+        }
+    }
+
+    /// Send a packet to the node with its destination address, or the gateway for everything outside the mesh
+    /* Mesh addresses are resolved with a query if we don't know their
+    node, the packet waits for the answer. */
+    fn send_ip_to(&mut self, packet: IpPacket) {
+        let meshaddr = match &packet {
+            IpPacket::V4(ipv4) if resolvable(ipv4.destination(), self.meshsubnet) => Some(ipv4.destination()),
+            _ => None,
+        };
+        let dest = match meshaddr {
+            None => self.router.packet_dest(&packet),
+            Some(ipaddr) => match self.resolver.lookup(ipaddr) {
+                Some(dest) => Some(dest).filter(|dest| *dest != self.id),
+                None => {
+                    match self.resolver.hold(ipaddr, packet) {
+                        Held::Query => self.send_address_query(ipaddr),
+                        Held::Queued => trace!("Packet to {} waits for its node to be found", ipaddr),
+                        Held::Full => debug!("Dropping packet to {}, too many wait for its node to be found", ipaddr),
+                    }
+                    return;
+                },
+            },
+        };
+        match dest {
+            None => {
+                trace!("Dropping packet to: {}", packet.destination());
+                drop(packet);
+            },
+            Some(dest) if packet.as_ref().len() > self.opt.tunmtu => self.send_oversized_ip(dest, packet),
+            Some(dest) => self.send_ip(dest, packet),
+        }
+    }
+
+    /// Note the node holding a mesh address, sending the packets that waited for it
+    fn learn_address(&mut self, ipaddr: Ipv4Addr, nodeid: u8) {
+        let waiting = self.resolver.learn(ipaddr, nodeid);
+        if !waiting.is_empty() {
+            debug!("IP {} is node {}, sending {} waiting packets", ipaddr, nodeid, waiting.len());
+        }
+        for packet in waiting {
+            self.send_ip_to(packet);
+        }
+    }
+
+    /// Flood a query for the node holding a mesh address, as far as a route may go
+    fn send_address_query(&mut self, ipaddr: Ipv4Addr) {
+        debug!("Asking the mesh which node holds IP {}", ipaddr);
+        let msg = AddressQueryMessage::new(ipaddr, self.opt.maxhops);
+        match msg.to_frame(self.frameids.allocate(None), self.id, vec![self.id]) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+            Err(e) => error!("Could not build AddressQueryMessage: {}", e),
+        }
+    }
+
+    /// Query again for addresses nobody answered for yet, and give up on those that waited long enough
+    fn resolve_tick(&mut self) {
+        let (requery, expired) = self.resolver.tick();
+        for ipaddr in requery {
+            self.send_address_query(ipaddr);
+        }
+        for (ipaddr, dropped) in expired {
+            debug!("No node answered for IP {}, dropping {} packets", ipaddr, dropped);
+        }
+    }
+
+    /// Answer a query for our address, and send it on if it may go further
+    fn handle_address_query(&mut self, frame: &mut Frame, query: AddressQueryMessage, quality: LinkQuality, txsender: &TxQueueSender) {
+        if frame.sender() == self.id {
+            return;
+        }
+        if self.ipaddr == Some(query.ipaddr) {
+            debug!("Answering node {} asking for our IP {}", frame.sender(), query.ipaddr);
+            match AddressAnswerMessage::new(query.ipaddr).to_frame(self.frameids.allocate(Some(frame.sender())), self.id, frame.route()) {
+                Ok(mut answer) => txsender.send_logged(answer.to_bytes(), TxPriority::Normal),
+                Err(e) => error!("Could not build AddressAnswerMessage: {}", e),
+            }
+            return;
+        }
+        // the TTL is rewritten, the frame keeps the querier's sender and frame ID
+        if let Some(next) = relay_query(&query).filter(|_| !frame.route().contains(&self.id)) {
+            match next.to_frame(frame.frameid(), frame.sender(), frame.route()) {
+                Ok(mut relayed) => if relayed.route_unshift(self.id).is_ok() {
+                    self.relay_flood(&mut relayed, TxPriority::Normal, quality.rssi);
+                },
+                Err(e) => error!("Could not relay AddressQueryMessage: {}", e),
+            }
+        }
+    }
+
+    /// Answer the kernel asking for the address of a node in the mesh
+    /* Addresses no node announced go unanswered, as do our own. */
+    fn handle_neighbor_solicitation(&mut self, solicitation: &Ipv6Packet, target: Ipv6Addr) {
+        match self.router.ip6_node(&target) {
+            Some(nodeid) if nodeid != self.id => {
+                trace!("Answering neighbor solicitation for {} of node {}", target, nodeid);
+                self.networktunnel.send(neighbor_advertisement(solicitation, target));
+            },
+            _ => trace!("Not answering neighbor solicitation for {}", target),
+        }
+    }
+
+    /// Send an IP packet larger than the tunnel MTU, in IP fragments that fit
+    /* The frames of a packet of tunmtu bytes are bounded, larger ones
+    would take the channel for longer than any other traffic expects.
+    A packet that may not be fragmented is answered with ICMP
+    Fragmentation Needed, as a router would. IPv6 is only ever fragmented
+    by its sender, which is told with Packet Too Big. */
+    fn send_oversized_ip(&mut self, dest: u8, packet: IpPacket) {
+        let mtu = self.opt.tunmtu;
+        let packet = match packet {
+            IpPacket::V4(packet) => packet,
+            IpPacket::V6(packet) => {
+                debug!("Packet of {} bytes to {} does not fit MTU {}", packet.as_ref().len(), packet.destination(), mtu);
+                if let Some(source) = self.ipv6addr {
+                    self.networktunnel.send(icmpv6_packet_too_big(&packet, mtu, source));
+                }
+                return;
+            },
+        };
+        if dont_fragment(packet.as_ref()) {
+            debug!("Packet of {} bytes to {} does not fit MTU {}", packet.as_ref().len(), packet.destination(), mtu);
+            let source = self.ipaddr.unwrap_or_else(|| packet.destination());
+            if let Ok(reply) = Packet::new(icmp_frag_needed(packet.as_ref(), mtu, source)) {
+                self.networktunnel.send(reply);
+            }
+            return;
+        }
+        for fragment in fragment_ipv4(packet.as_ref(), mtu) {
+            match Packet::new(fragment) {
+                Ok(fragment) => self.send_ip(dest, fragment.into()),
+                Err(e) => error!("Could not fragment packet to {}: {}", packet.destination(), e),
+            }
+        }
+    }
+
+    /// Send an IP packet to a node on its route, in chunks if necessary
+    fn send_ip(&mut self, dest: u8, packet: IpPacket) {
+        self.iptraffic.record(&packet);
+        if Some(dest) == self.router.gateway_id() {
+            self.gatewaybacklog.push(dest, packet.as_ref().to_vec());
+        }
+        let route = self.route_to(dest);
+        let frameid = self.frameids.allocate(Some(dest));
+        // nodes that take compressed headers get them
+        let compressed = if self.opt.headercompression && self.nodeinfos.get(dest).map_or(false, |info| info.headercompression) {
+            self.headercomp.compress(dest, packet.as_ref())
+        } else {
+            None
+        };
+        let frame = match compressed {
+            Some((context, data)) => CompressedIPPacketMessage::new(context, data).to_frame(frameid, self.id, route.clone()),
+            None => IPPacketMessage::new(packet).to_frame(frameid, self.id, route.clone()),
+        };
+        let mut frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Could not build IP packet frame: {}", e);
+                return;
+            },
+        };
+        let chunks = self.chunks(&mut frame);
+        for chunk in chunks.iter() {
+            trace!("Sending chunk");
+            self.tx_logged(chunk.clone(), TxPriority::Low);
+        }
+        self.delivery.sent(&mut frame, route[0], chunks, TxPriority::Low);
+    }
+
+    /// Handle an IP packet from radio that was routed to us
+    /// it goes to our tunnel if it is ours, or leaves the mesh there if we are the gateway
+    fn handle_radio_ip(&mut self, sender: u8, packet: IpPacket) {
+        if !self.filter.allow(FilterDirection::In, &packet) {
+            trace!("Filtered packet from {} to {} from the radio", packet.source(), packet.destination());
+            return;
+        }
+        self.iptraffic.record(&packet);
+        // a mesh address sending us a packet is held by its sender
+        if let IpPacket::V4(ipv4) = &packet {
+            if resolvable(ipv4.source(), self.meshsubnet) && sender != self.id {
+                self.learn_address(ipv4.source(), sender);
+            }
+        }
+        let ours = match &packet {
+            IpPacket::V4(ipv4) => self.ipaddr == Some(ipv4.destination()),
+            IpPacket::V6(ipv6) => self.ipv6addr == Some(ipv6.destination()),
+        };
+        // pings are answered here, however the network above the tunnel is set up
+        if ours && self.opt.icmpechoreply {
+            if let Some(reply) = echo_reply(&packet) {
+                trace!("Answering ping from {}", packet.source());
+                self.send_ip_to(reply);
+                return;
+            }
+        }
+        if let (true, Some(dns)) = (ours, self.dns.as_mut()) {
+            if let Some((client, query)) = dns_query(packet.as_ref()) {
+                trace!("DNS query from {}", client.addr);
+                if let Some(response) = dns.query(client, query) {
+                    self.send_dns_response(client, response);
+                }
+                return;
+            }
+        }
+        if ours || self.opt.isgateway {
+            trace!("Forwarding IP packet from {} to local network", packet.source());
+            self.networktunnel.send(packet);
+        } else {
+            debug!("Dropping IP packet from {} to {} routed to us", &packet.source(), &packet.destination());
+        }
+    }
+
+    /// Send the answers the upstream resolvers gave to the nodes that asked
+    fn dns_tick(&mut self) {
+        let answers = match self.dns.as_mut() {
+            None => return,
+            Some(dns) => dns.answers(),
+        };
+        for (client, response) in answers {
+            self.send_dns_response(client, response);
+        }
+    }
+
+    /// Answer a DNS query from a node
+    fn send_dns_response(&mut self, client: DnsClient, response: Vec<u8>) {
+        let packet = match IpPacket::new(dns_response_packet(&client, &response)) {
+            Err(e) => {
+                error!("Could not build DNS response to {}: {}", client.addr, e);
+                return;
+            },
+            Ok(packet) => packet,
+        };
+        self.send_ip_to(packet);
+    }
+
+    /// Put the headers of an IP packet from the radio back, then handle it like any other
+    /* A packet that can't be is dropped, its sender is told to send the
+    flow's full headers again. */
+    fn handle_compressed_ip(&mut self, frame: &mut Frame, msg: CompressedIPPacketMessage) {
+        let sender = frame.sender();
+        let packet = match self.headercomp.decompress(sender, msg.context, &msg.data) {
+            Err(e) => {
+                debug!("Dropping compressed IP packet: {}", e);
+                self.send_error(sender, frame.frameid(), MeshError::HeaderContextLost(msg.context));
+                return;
+            },
+            Ok(packet) => packet,
+        };
+        match IpPacket::new(packet) {
+            Err(e) => error!("Dropping invalid decompressed IP packet from {}: {}", sender, e),
+            Ok(packet) => self.handle_radio_ip(sender, packet),
+        }
+    }
+
+    /// Send a broadcast packet to nearby nodes
+    fn broadcast(&mut self) {
+        // prepare broadcast
+        if self.radio.txsender().is_empty() {
+            self.send_broadcast();
+        }
+    }
+
+    /// Send a broadcast packet now, even if other frames are waiting
+    fn send_broadcast(&mut self) {
+        let mut ipOffset = 0;
+        if self.ipaddr.is_some() {
+            ipOffset = 4;
+        }
+        // our own claim, another node announcing it is a conflict
+        if let Some(ipaddr) = self.ipaddr {
+            self.addressclaims.claim(self.id, ipaddr);
+        }
+        let msg = BroadcastMessage {
+            header: None,
+            isgateway: self.opt.isgateway.clone(),
+            ipOffset,
+            ipaddr: self.ipaddr,
+            ipv6addr: self.ipv6addr,
+            custom_tlv: Vec::new()
+        };
+        let mut route: Vec<u8> = Vec::new();
+        route.push(self.id.clone());
+        match msg.to_frame(self.frameids.allocate(None), self.id, route) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+            Err(e) => error!("Could not build BroadcastMessage: {}", e),
+        }
+    }
+
+    /// Send a heartbeat to direct neighbors
+    fn heartbeat(&mut self) {
+        let mut status = 0u8;
+        if self.opt.isgateway {
+            status |= HEARTBEAT_GATEWAY;
+        }
+        let mut msg = HeartbeatMessage::new(status);
+        msg.token = Some(self.collisions.token());
+        self.heartbeatseq = self.heartbeatseq.wrapping_add(1);
+        msg.seq = self.heartbeatseq;
+        msg.battery_mv = self.vddmv.unwrap_or(0);
+        msg.uptime_s = self.started.elapsed().as_secs().min(u32::MAX as u64) as u32;
+        if self.adr.is_some() {
+            msg.sf = self.adrpending.map_or(self.opt.radiosf, |(sf, _)| sf);
+        }
+        match msg.to_frame(self.frameids.allocate(None), self.id, Vec::new()) {
+            Ok(mut frame) => self.tx_logged(frame.to_bytes(), TxPriority::Normal),
+            Err(e) => error!("Could not build HeartbeatMessage: {}", e),
+        }
+    }
+
+    /// Split a frame into chunks the radio sends in one packet each at its current settings
+    /* An empty list, and nothing sent, if the settings leave no room for
+    the frame's route. */
+    fn chunks(&self, frame: &mut Frame) -> Vec<Vec<u8>> {
+        if self.opt.modulation != TxEncoding::Lora {
+            return frame.chunked(&self.opt.maxpacketsize);
+        }
+        frame.radio_chunked(self.opt.maxpacketsize, self.opt.radiosf, self.opt.radiobw, self.opt.radiocr)
+            .unwrap_or_else(|e| {
+                error!("Could not chunk {:?} for the radio: {}", frame.msgtype(), e);
+                Vec::new()
+            })
+    }
+
+    /// Frames waiting in the transmit queue for the radio
+    pub fn tx_backlog(&self) -> usize {
+        self.radio.txsender().len()
+    }
+
+    /// Queue raw frame bytes for transmission in the given priority tier
+    /// fails if the radio is stopped for an emergency, a frame dropped for a full tier is only logged
+    pub fn tx_with_priority(&self, data: Vec<u8>, priority: TxPriority) -> io::Result<()> {
+        match self.radio.txsender().send(data, priority) {
+            Err(TrySendError::Disconnected(_)) => Err(radio_stopped()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Queue frame bytes the node sends on its own, a refused frame is only logged
+    fn tx_logged(&self, data: Vec<u8>, priority: TxPriority) {
+        self.radio.txsender().send_logged(data, priority);
+    }
+
+    /// Silence the radio at once, every send fails until `emergency_reset`
+    /* Frames already queued are dropped. Nothing the node sends on its
+    own goes out meanwhile, beacons and heartbeats included. */
+    pub fn emergency_stop(&mut self) -> io::Result<()> {
+        self.radio.emergency_stop()
+    }
+
+    /// Let the radio transmit again after `emergency_stop`
+    pub fn emergency_reset(&mut self) -> io::Result<()> {
+        self.radio.emergency_reset()
+    }
+
+}
+impl<R: Radio> Drop for MeshNode<R> {
+    /// A node that ran keeps what it learned for the next start
+    fn drop(&mut self) {
+        if self.io.is_some() && self.opt.stateinterval > 0 {
+            self.save_state();
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn datagram_ports() {
+    use crate::simulation::SimulatedMesh;
+    use crate::stack::message::MAX_DATAGRAM_LEN;
+
+    let mut mesh = SimulatedMesh::new(2);
+    mesh.link(1, 2);
+    let timeout = Duration::from_secs(5);
+    assert!(mesh.run_until(timeout, |mesh| mesh.node(1).reachable(2) && mesh.node(2).reachable(1)));
+    let (chat, sensors) = (mesh.node(2).recv_datagrams(5000), mesh.node(2).recv_datagrams(5683));
+    let replies = mesh.node(1).recv_datagrams(5000);
+
+    // each port gets its own datagrams, in order
+    mesh.node(1).send_datagram(2, 5683, vec![21, 5]).unwrap();
+    mesh.node(1).send_datagram(2, 5000, b"hello".to_vec()).unwrap();
+    mesh.node(1).send_datagram(2, 5683, vec![21, 6]).unwrap();
+    assert!(mesh.run_until(timeout, |_| chat.len() == 1 && sensors.len() == 2));
+    assert_eq!(chat.try_recv().unwrap(), (1, b"hello".to_vec()));
+    assert_eq!(sensors.try_iter().collect::<Vec<_>>(), vec![(1, vec![21, 5]), (1, vec![21, 6])]);
+
+    // replies come back the same way
+    mesh.node(2).send_datagram(1, 5000, b"hi".to_vec()).unwrap();
+    assert!(mesh.run_until(timeout, |_| !replies.is_empty()));
+    assert_eq!(replies.try_recv().unwrap(), (2, b"hi".to_vec()));
+
+    // closed ports drop datagrams, too large ones are refused
+    mesh.node(1).send_datagram(2, 9, vec![1]).unwrap();
+    assert!(mesh.node(1).send_datagram(2, 5000, vec![0; MAX_DATAGRAM_LEN + 1]).is_err());
+    mesh.node(1).send_datagram(2, 5000, vec![7; 100]).unwrap();
+    assert!(mesh.run_until(timeout, |_| !chat.is_empty()));
+    assert_eq!(chat.try_recv().unwrap(), (1, vec![7; 100]));
+    mesh.run_for(Duration::from_millis(200));
+    assert!(chat.is_empty() && sensors.is_empty());
+
+    // a node nobody heard of is looked for, the datagram is not sent
+    assert!(mesh.node(1).send_datagram(9, 5000, vec![1]).is_err());
+}
+
+#[test]
+fn emergency_stop_refuses_sends() {
+    use crate::simulation::SimulatedMesh;
+
+    let mut mesh = SimulatedMesh::with_settings(2, |_, opt| opt.acktimeout = 300);
+    mesh.link(1, 2);
+    let timeout = Duration::from_secs(5);
+    assert!(mesh.run_until(timeout, |mesh| mesh.node(1).reachable(2) && mesh.node(2).reachable(1)));
+    let events = mesh.node(1).delivery_events();
+
+    // while stopped every send fails and nothing waits on an ack or a receipt
+    let replies = mesh.control(1, ControlCommand::RadioStop);
+    assert!(mesh.run_until(timeout, |_| !replies.is_empty()));
+    assert!(replies.try_recv().unwrap().starts_with("radio stopped"));
+    let sent = mesh.transmissions(1);
+    assert_eq!(mesh.node(1).send_data(2, 1, vec![1]).unwrap_err().kind(), ErrorKind::NotConnected);
+    assert!(mesh.node(1).send_data_with_receipt(2, 1, vec![2]).is_err());
+    assert!(mesh.node(1).send_text(2, "hello").is_err());
+    assert!(mesh.node(1).texts.is_empty());
+    mesh.run_for(Duration::from_millis(600));
+    assert_eq!(mesh.transmissions(1), sent);
+    assert!(events.try_recv().is_err());
+
+    // after a reset data goes out and is delivered again
+    let replies = mesh.control(1, ControlCommand::RadioReset);
+    assert!(mesh.run_until(timeout, |_| !replies.is_empty()));
+    let frameid = mesh.node(1).send_data_with_receipt(2, 1, vec![3]).unwrap().unwrap();
+    assert!(mesh.run_until(timeout, |_| !events.is_empty()));
+    assert_eq!(events.try_recv().unwrap(), DeliveryEvent::Delivered { dest: 2, frameid });
+}
+
+#[test]
+fn broadcasts_handled_once() {
+    use crate::control::ControlCommand;
+    use crate::simulation::SimulatedMesh;
+
+    // three nodes in range of each other, each hears the beacon from 1 and its rebroadcasts
+    let mut mesh = SimulatedMesh::with_settings(3, |_, opt| opt.emergencyinterval = 60000);
+    mesh.full();
+    let timeout = Duration::from_secs(5);
+    assert!(mesh.run_until(timeout, |mesh| mesh.node(1).reachable(2) && mesh.node(1).reachable(3)));
+    let events: Vec<Receiver<String>> = (1..=3).map(|id| mesh.control(id, ControlCommand::ListenEmergency)).collect();
+    let before: Vec<u64> = (1..=3).map(|id| mesh.node(id).dedup().duplicates()).collect();
+    mesh.node(1).raise_emergency("broken leg");
+    assert!(mesh.run_until(timeout, |_| !events[1].is_empty() && !events[2].is_empty()));
+    // give the rebroadcasts time to arrive
+    mesh.run_for(Duration::from_millis(500));
+
+    for (i, events) in events.iter().enumerate() {
+        let lines: Vec<String> = events.try_iter().collect();
+        let expected = if i == 0 { 0 } else { 1 };
+        assert_eq!(lines.len(), expected, "node {} got {:?}", i + 1, lines);
+        assert!(lines.iter().all(|line| line == "[emergency] node 1: broken leg"));
+    }
+    // the copies were dropped, the originator's too
+    for id in 1..=3 {
+        assert!(mesh.node(id).dedup().duplicates() > before[id as usize - 1], "node {} dropped no copies", id);
+    }
+}
+
+#[test]
+fn route_error_recovery() {
+    use crate::simulation::SimulatedMesh;
+
+    // 1 - 2 - 3 - 4, node 5 comes in range of 2 and 4 later
+    let mut mesh = SimulatedMesh::new(5);
+    mesh.line(&[1, 2, 3, 4]);
+    let timeout = Duration::from_secs(10);
+    assert!(mesh.run_until(timeout, |mesh| mesh.node(1).reachable(4)));
+    let received = mesh.node(4).recv_on_port(7);
+    mesh.node(1).send_data(4, 7, b"before".to_vec()).unwrap();
+    assert!(mesh.run_until(timeout, |_| !received.is_empty()));
+    assert_eq!(received.try_recv().unwrap(), (1, b"before".to_vec()));
+
+    // node 3 dies, the data keeps coming until some gets around it
+    mesh.line(&[2, 5, 4]);
+    mesh.kill(3);
+    let mut sent = Instant::now() - Duration::from_secs(1);
+    let recovered = mesh.run_until(timeout, |mesh| {
+        if sent.elapsed() >= Duration::from_millis(500) {
+            sent = Instant::now();
+            mesh.node(1).send_data(4, 7, b"after".to_vec()).unwrap();
+        }
+        !received.is_empty()
+    });
+    assert!(recovered, "no data got to 4 after 3 died");
+    assert_eq!(received.try_recv().unwrap(), (1, b"after".to_vec()));
+}
+
+#[test]
+fn emergency_flood() {
+    use crate::control::ControlCommand;
+    use crate::simulation::SimulatedMesh;
+
+    // two clusters joined only through 3 and 4, 7 hangs off the far end
+    let mut mesh = SimulatedMesh::new(7);
+    for &(a, b) in [(1, 2), (1, 3), (2, 3), (3, 4), (4, 5), (4, 6), (5, 6), (6, 7)].iter() {
+        mesh.link(a, b);
+    }
+    mesh.run_for(Duration::from_millis(300));
+    let events: Vec<Receiver<String>> = (1..=7).map(|id| mesh.control(id, ControlCommand::ListenEmergency)).collect();
+    let mut lines: Vec<Vec<String>> = vec![Vec::new(); 7];
+    let timeout = Duration::from_secs(5);
+    let mut heard = |mesh: &mut SimulatedMesh, line: &str| mesh.run_until(timeout, |_| {
+        for (events, lines) in events.iter().zip(lines.iter_mut()) {
+            lines.extend(events.try_iter());
+        }
+        lines[1..].iter().all(|lines| lines.iter().any(|l| l == line))
+    });
+
+    // every other node hears the beacon and its repeat, each once
+    mesh.node(1).raise_emergency("avalanche");
+    assert!(heard(&mut mesh, "[emergency] node 1: avalanche"));
+    assert!(heard(&mut mesh, "[emergency 1] node 1: avalanche"));
+    mesh.node(1).cancel_emergency();
+    assert!(heard(&mut mesh, "[all-clear] node 1"));
+    mesh.run_for(Duration::from_millis(500));
+    for (events, lines) in events.iter().zip(lines.iter_mut()) {
+        lines.extend(events.try_iter());
+    }
+    assert!(lines[0].is_empty());
+    for (i, lines) in lines.iter().enumerate().skip(1) {
+        assert_eq!(lines, &vec![String::from("[emergency] node 1: avalanche"), String::from("[emergency 1] node 1: avalanche"),
+            String::from("[all-clear] node 1")], "node {}", i + 1);
+    }
+    // the copies were caught as duplicates
+    assert!(mesh.node(4).dedup().duplicates() > 0);
+}
+
+#[test]
+fn flood_suppression() {
+    use crate::simulation::SimulatedMesh;
+
+    // a 4x4 grid where every node hears the nodes up to two steps away, diagonals included
+    let side = 4u8;
+    // every node floods its broadcasts for a while, returns who knows of everyone and the rebroadcasts
+    let flood = |threshold: usize| {
+        let mut mesh = SimulatedMesh::with_settings((side * side) as usize, |_, opt| opt.floodthreshold = threshold);
+        for a in 0..side * side {
+            for b in (a + 1)..side * side {
+                if ((a % side) as i16 - (b % side) as i16).abs() <= 2 && ((a / side) as i16 - (b / side) as i16).abs() <= 2 {
+                    mesh.link(a + 1, b + 1);
+                }
+            }
+        }
+        mesh.run_for(Duration::from_secs(3));
+        let everyone = (1..=side * side).filter(|&id| mesh.node(id).mesh_state().nodes.len() >= (side * side) as usize - 1).count();
+        let relayed: u64 = (1..=side * side).map(|id| mesh.node(id).floods().sent).sum();
+        (everyone, relayed)
+    };
+
+    // every node hears of every other one either way, with far fewer rebroadcasts
+    let (everyone, everything) = flood(1000);
+    assert_eq!(everyone, (side * side) as usize);
+    let (everyone, suppressed) = flood(1);
+    assert_eq!(everyone, (side * side) as usize);
+    assert!(suppressed * 2 < everything, "{} rebroadcasts with suppression, {} without", suppressed, everything);
+}
+
+#[test]
+fn flood_rssi_delay() {
+    use crate::simulation::SimulatedMesh;
+    use crate::stack::FloodDelay;
+
+    // a chain of nodes a step apart, each hears the nodes up to three steps away, weaker the farther
+    let count = 10u8;
+    // only the node at the end of the chain broadcasts, the others wait weeks for their first broadcast
+    let flood = |policy: FloodDelay| {
+        let mut mesh = SimulatedMesh::with_settings(count as usize, |id, opt| {
+            opt.flooddelay = policy;
+            opt.floodthreshold = 0;
+            opt.floodwindow = 200;
+            if id == 1 {
+                opt.broadcastinterval = 100;
+            } else {
+                opt.broadcastdelay = u64::from(u32::MAX);
+            }
+        });
+        for a in 1..=count {
+            for b in (a + 1)..=count.min(a + 3) {
+                mesh.set_rssi(a, b, -60 - 12 * i16::from(b - a));
+            }
+        }
+        // the floods every node sends once it booted are over before counting starts
+        mesh.run_for(Duration::from_secs(1));
+        // node 2 hears every broadcast first hand and either sends it on or not
+        let counts = |mesh: &mut SimulatedMesh| {
+            let relayed: u64 = (1..=count).map(|id| mesh.node(id).floods().sent).sum();
+            let floods = mesh.node(2).floods();
+            (relayed, floods.sent + floods.suppressed)
+        };
+        let (relayed, floods) = counts(&mut mesh);
+        mesh.run_for(Duration::from_secs(4));
+        let reached = (2..=count).filter(|&id| mesh.node(id).reachable(1)).count();
+        let (allrelayed, allfloods) = counts(&mut mesh);
+        (reached, (allrelayed - relayed) as f64 / (allfloods - floods) as f64)
+    };
+
+    // the far nodes go first and the ones in between stay quiet
+    let (reached, random) = flood(FloodDelay::Random);
+    assert_eq!(reached, count as usize - 1);
+    let (reached, weighted) = flood(FloodDelay::Rssi);
+    assert_eq!(reached, count as usize - 1);
+    assert!(weighted < random, "{:.2} rebroadcasts per flood with RSSI delays, {:.2} with random ones", weighted, random);
+}
+
+#[test]
+fn line_delivery() {
+    use crate::simulation::SimulatedMesh;
+    use crate::stack::DeliveryEvent;
+
+    // nodes 1 to 5 in a line, each only hears the nodes next to it
+    let mut mesh = SimulatedMesh::new(5);
+    mesh.line(&[1, 2, 3, 4, 5]);
+    let received = mesh.node(5).recv_on_port(7);
+    let events = mesh.node(1).delivery_events();
+    let timeout = Duration::from_secs(10);
+
+    // node 1 knows no route to node 5 yet, the data waits until discovery finds one
+    assert!(!mesh.node(1).reachable(5));
+    mesh.node(1).send_data(5, 7, b"hello".to_vec()).unwrap();
+    assert!(mesh.run_until(timeout, |_| !received.is_empty()));
+    assert_eq!(received.try_recv().unwrap(), (1, b"hello".to_vec()));
+
+    // the link from 3 to 4 loses everything for a moment, 3 sends the data again once it is back
+    // and node 5 returns a receipt the way it knows back
+    assert!(mesh.run_until(timeout, |mesh| mesh.node(5).reachable(1)));
+    mesh.set_loss(3, 4, 1.0);
+    let frameid = mesh.node(1).send_data_with_receipt(5, 7, b"again".to_vec()).unwrap().unwrap();
+    mesh.run_for(Duration::from_millis(100));
+    mesh.set_loss(3, 4, 0.0);
+    assert!(mesh.run_until(timeout, |_| !events.is_empty()));
+    assert_eq!(events.try_recv().unwrap(), DeliveryEvent::Delivered { dest: 5, frameid });
+    assert_eq!(received.try_iter().collect::<Vec<_>>(), vec![(1, b"again".to_vec())]);
+
+    // with node 5 gone, node 4 gives up and the error flows back to node 1, which stops using the link
+    let linked = |mesh: &mut SimulatedMesh| mesh.node(1).mesh_graph().edges.iter()
+        .any(|edge| (edge.from, edge.to) == (4, 5) || (edge.from, edge.to) == (5, 4));
+    assert!(linked(&mut mesh));
+    mesh.kill(5);
+    let frameid = mesh.node(1).send_data_with_receipt(5, 7, b"anyone?".to_vec()).unwrap().unwrap();
+    assert!(mesh.run_until(timeout, |mesh| !linked(mesh)), "node 1 still uses the link from 4 to 5");
+    assert!(mesh.run_until(timeout, |_| !events.is_empty()));
+    assert_eq!(events.try_recv().unwrap(), DeliveryEvent::Failed { dest: 5, frameid: Some(frameid) });
+}
+
+#[test]
+fn gateway_failover() {
+    use std::net::Ipv4Addr;
+    use crate::simulation::SimulatedMesh;
+
+    // node 1 hears the gateways 2 and 3, which don't hear each other
+    let mut mesh = SimulatedMesh::with_settings(3, |id, opt| match id {
+        1 => opt.staticip = Some(Ipv4Addr::new(172, 16, 0, 10)),
+        _ => opt.isgateway = true,
+    });
+    mesh.link(1, 2);
+    mesh.link(1, 3);
+    let interval = Duration::from_millis(300);
+    // node 1 sends a packet to the internet every 100ms until one leaves the mesh, returns through which gateway
+    let mut sent = 0u8;
+    let mut leaves = |mesh: &mut SimulatedMesh| {
+        let mut gateway = None;
+        let mut last = Instant::now() - interval;
+        mesh.run_until(Duration::from_secs(10), |mesh| {
+            if last.elapsed() >= Duration::from_millis(100) {
+                last = Instant::now();
+                sent = sent.wrapping_add(1);
+                mesh.inject(1, IpPacket::new(vec![0x45, 0, 0, 20, 0, sent, 0x40, 0, 64, 17, 0, 0, 172, 16, 0, 10, 8, 8, 8, 8]).unwrap());
+            }
+            gateway = [2, 3].iter().cloned().find(|&id| mesh.tunnel(id).try_iter().count() > 0);
+            gateway.is_some()
+        });
+        gateway
+    };
+
+    // the gateway in use dies, traffic goes through the other one within a few of its announcements
+    let first = leaves(&mut mesh).expect("no packet left the mesh");
+    mesh.kill(first);
+    let died = Instant::now();
+    let second = leaves(&mut mesh).expect("traffic never resumed");
+    assert_eq!(second, 5 - first);
+    assert!(died.elapsed() < interval * 5, "resumed after {:?}", died.elapsed());
+}
+
+#[test]
+fn loop_dies_early() {
+    use crate::simulation::SimulatedMesh;
+
+    // 1, 2 and 3 hear each other and 4 only hears 3, node 1 has a static route to 4 that leads back to itself
+    let mut mesh = SimulatedMesh::with_settings(4, |id, opt| if id == 1 {
+        opt.staticroutes = String::from("4:2,3,1,4");
+    });
+    mesh.line(&[1, 2, 3, 1]);
+    mesh.link(3, 4);
+    let received = mesh.node(4).recv_on_port(7);
+    let timeout = Duration::from_secs(5);
+
+    // node 1 finds its own frame back after one lap and drops it, the data still gets through on another route
+    mesh.node(1).send_data(4, 7, b"round".to_vec()).unwrap();
+    assert!(mesh.run_until(timeout, |mesh| mesh.node(1).loops().routeloops > 0));
+    assert!(mesh.run_until(timeout, |_| !received.is_empty()));
+    assert_eq!(received.try_recv().unwrap(), (1, b"round".to_vec()));
+    let loops = |mesh: &mut SimulatedMesh, id| {
+        let loops = mesh.node(id).loops();
+        (loops.routeloops, loops.pathloops, loops.pingpongs)
+    };
+    assert_eq!(loops(&mut mesh, 1), (1, 0, 0));
+    assert_eq!(loops(&mut mesh, 2), (0, 0, 0));
+    assert_eq!(loops(&mut mesh, 3), (0, 0, 0));
+}
+
+#[test]
+fn etx_routing() {
+    use crate::simulation::SimulatedMesh;
+
+    // 1 - 2 - 5 is short over a lossy link, 1 - 3 - 4 - 5 long over clean ones, nobody broadcasts
+    let route = |minsamples: usize| {
+        let mut mesh = SimulatedMesh::with_settings(5, |_, opt| {
+            opt.etxminsamples = minsamples;
+            opt.broadcastdelay = u64::from(u32::MAX);
+        });
+        mesh.line(&[1, 2, 5]);
+        mesh.line(&[1, 3, 4, 5]);
+        mesh.set_loss(1, 2, 0.6);
+        mesh.run_for(Duration::from_secs(3));
+        assert!(!mesh.node(1).reachable(5));
+        // the loss is remembered, the discovery itself goes both ways
+        mesh.set_loss(1, 2, 0.0);
+        mesh.node(1).send_data(5, 7, b"etx".to_vec()).unwrap();
+        assert!(mesh.run_until(Duration::from_secs(5), |mesh| mesh.node(1).reachable(5)));
+        mesh.run_for(Duration::from_millis(300));
+        mesh.node(1).routes().best(5).map(|entry| entry.path.clone())
+    };
+
+    // too little heard to go by, the fewest hops win
+    assert_eq!(route(1000), Some(vec![2, 5]));
+    // once the links are known the clean path wins despite its extra hop
+    assert_eq!(route(3), Some(vec![3, 4, 5]));
+}
+
+#[test]
+fn partition_bridge_cut() {
+    use crate::simulation::SimulatedMesh;
+    use std::time::Instant;
+
+    // 1, 2 and 3 on one side, 5, 6 and 7 on the other, bridged by 4 between 3 and 5
+    // broadcasts stretch to 1.6s while nothing changes
+    let rejoin = |threshold: u8| {
+        let mut mesh = SimulatedMesh::with_settings(7, |_, opt| {
+            opt.broadcastinterval = 100;
+            opt.broadcastmaxinterval = 1600;
+            opt.partitionwindow = 2000;
+            opt.partitionthreshold = threshold;
+            opt.partitionboost = 2000;
+        });
+        mesh.line(&[1, 2, 3, 1]);
+        mesh.line(&[5, 6, 7, 5]);
+        mesh.line(&[3, 4, 5]);
+        let events = mesh.node(1).partition_events();
+        let timeout = Duration::from_secs(10);
+        mesh.run_for(Duration::from_secs(4));
+        assert!(events.try_recv().is_err());
+
+        mesh.kill(4);
+        if threshold < 100 {
+            // each side loses the bridge and everything behind it, together
+            assert!(mesh.run_until(timeout, |mesh| [1, 2, 3, 5, 6, 7].iter().all(|id| mesh.node(*id).partitions().lost().len() == 4)));
+            assert!(matches!(events.try_recv(), Ok(PartitionEvent::Suspected(_))));
+            assert_eq!(mesh.node(1).partitions().lost(), vec![4, 5, 6, 7]);
+            assert_eq!(mesh.node(7).partitions().lost(), vec![1, 2, 3, 4]);
+        } else {
+            mesh.run_for(Duration::from_secs(3));
+        }
+
+        // time from the bridge coming back until every node heard every other
+        mesh.revive(4);
+        let restored = Instant::now();
+        assert!(mesh.run_until(timeout, |mesh| (1..=7).all(|id| mesh.node(id).partitions().reachable().len() == 6)));
+        restored.elapsed()
+    };
+
+    // today the far nodes stay on their long interval, the boost brings them in within a few short ones
+    let (today, aware) = (rejoin(100), rejoin(30));
+    assert!(aware * 2 < today, "rejoined in {:?} with partition detection, {:?} without", aware, today);
+}
+
+#[test]
+fn fair_destinations() {
+    use crate::simulation::SimulatedMesh;
+    use crate::stack::DeliveryEvent;
+
+    // 2 and 3 both hear 1, a frame is on the air for about 10ms
+    let mut mesh = SimulatedMesh::new(3);
+    mesh.line(&[2, 1, 3]);
+    mesh.medium().set_airtime(Duration::from_micros(50));
+    let (bulk, interactive) = (mesh.node(2).recv_on_port(7), mesh.node(3).recv_on_port(7));
+    let receipts = mesh.node(1).delivery_events();
+    let timeout = Duration::from_secs(10);
+    assert!(mesh.run_until(timeout, |mesh| mesh.node(1).reachable(2) && mesh.node(1).reachable(3)));
+
+    // a bulk transfer to 2 fills node 1's queue, data for 3 does not wait for it
+    for _ in 0..40 {
+        let chunk: Vec<u8> = (0..150).map(|_| thread_rng().gen()).collect();
+        mesh.node(1).send_data(2, 7, chunk).unwrap();
+    }
+    let frameid = mesh.node(1).send_data_with_receipt(3, 7, b"interactive".to_vec()).unwrap().unwrap();
+    assert!(mesh.run_until(timeout, |_| !interactive.is_empty()));
+    assert_eq!(interactive.try_recv().unwrap(), (1, b"interactive".to_vec()));
+    assert!(bulk.len() < 5, "{} bulk frames were sent first", bulk.len());
+
+    // the transfer goes on and the receipt comes back
+    assert!(mesh.run_until(timeout, |_| !receipts.is_empty()));
+    assert_eq!(receipts.try_recv().unwrap(), DeliveryEvent::Delivered { dest: 3, frameid });
+    assert!(mesh.run_until(timeout, |_| bulk.len() > 20));
+}
+
+#[test]
+fn reservation_hidden_terminal() {
+    use crate::simulation::SimulatedMesh;
+
+    // 1 and 3 both send to 2 without hearing each other, a burst is on the air for about 20ms
+    let burst = |reservation: bool| {
+        let mut mesh = SimulatedMesh::with_settings(3, |_, opt| {
+            // few frames besides the bursts
+            opt.heartbeatinterval = 2000;
+            opt.neighbortimeout = 8000;
+            opt.txslot = 60;
+            opt.broadcastinterval = 5000;
+            opt.reservation = reservation;
+            opt.reservationwait = 1000;
+        });
+        mesh.line(&[1, 2, 3]);
+        mesh.medium().set_airtime(Duration::from_micros(20));
+        let received = mesh.node(2).recv_on_port(7);
+        let timeout = Duration::from_secs(10);
+        assert!(mesh.run_until(timeout, |mesh| mesh.node(1).reachable(2) && mesh.node(3).reachable(2)));
+
+        // 3 starts its burst once the one of 1 is on the air
+        let collisions = mesh.medium().collisions(1);
+        for _ in 0..5 {
+            let sent = mesh.transmissions(1);
+            let data: Vec<u8> = (0..800).map(|_| thread_rng().gen()).collect();
+            mesh.node(1).send_data(2, 7, data).unwrap();
+            assert!(mesh.run_until(timeout, |mesh| mesh.transmissions(1) > sent + 1));
+            let data: Vec<u8> = (0..800).map(|_| thread_rng().gen()).collect();
+            mesh.node(3).send_data(2, 7, data).unwrap();
+            mesh.run_for(Duration::from_millis(300));
+        }
+        mesh.run_until(Duration::from_secs(3), |_| received.len() == 10);
+        (received.len(), mesh.medium().collisions(1) - collisions)
+    };
+
+    // without reservations the bursts run into each other at 2, with them each has the channel to itself
+    // though a frame of 2's own may still cut into one now and then
+    let (plain, plaincollisions) = burst(false);
+    let (reserved, collisions) = burst(true);
+    assert!(reserved >= 8 && plain < 5 && collisions < plaincollisions,
+        "{} bursts arrived with reservations and {} collisions, {} and {} without", reserved, collisions, plain, plaincollisions);
+}
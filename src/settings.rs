@@ -1,6 +1,10 @@
 use config::{ConfigError, File};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
 use serde::Deserialize;
+use crate::hardware::{LoraRegion, TxEncoding};
+use crate::stack::message::{TelemetryField, HEARTBEAT_MISSES};
+use crate::stack::{chunk_overhead, parse_config_key, PacketFilter, DEFAULT_TUN_FILTER, parse_frame_key, in_subnet, parse_ip_routes, parse_static_routes, parse_subnet, FloodDelay, RoutingMode, IPV6_MIN_MTU, max_payload_for, MAX_FRAME_LEN, MAX_PAYLOAD_LEN, MAX_ROUTE_LEN, MIN_TUN_MTU, REMOTE_SETTINGS};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
@@ -10,48 +14,633 @@ pub struct Settings {
     you set the gateway as 1. */
     pub nodeid: u8,
 
+    /// Take the node ID from the lowest byte of the radio's hardware EUI instead
+    /* Saves configuring every node by hand, but two radios whose EUIs
+    end in the same byte will clash. */
+    pub usehardwareeui: bool,
+
+    /// When another node turns out to use our ID, take a new one derived from the hardware EUI
+    /* Of the two nodes, the one with the larger instance token gives way,
+    the collision is reported either way. */
+    pub resolveidcollisions: bool,
+
     /// Activate debug mode
     // short and long flags (-d, --debug) will be deduced from the field's name
     pub debug: bool,
 
+    /// Display name sent along with our texts and node info
+    pub nodename: Option<String>,
+
     /// Set if node is a gateway to internet
     /* Turning this on will enable special networking features, including a
     DHCP server and will assign IP addresses to other nodes in the mesh. */
     pub isgateway: bool,
 
     /// Local device port for radio
+    /* Leave empty to use the first LoStik found on USB */
     pub radioport: PathBuf,
 
+    /// Unix socket the node listens on for commands such as ping
+    pub controlsocket: PathBuf,
+
+    /// Regulatory region, limits the transmit power
+    pub region: LoraRegion,
+
+    /// Listen before talk, required in some regulatory domains
+    /* The radio reads the RSSI before every transmission and holds the
+    frame back while it is above lbtthresholddbm, for two seconds at most.
+    The RN2483 only reports the RSSI of the last packet it received, not
+    the energy on the channel right now. */
+    pub lbtenabled: bool,
+
+    /// RSSI (dBm) above which the channel counts as busy
+    pub lbtthresholddbm: i16,
+
+    /// Serial port of a GPS receiver, positions are only sent if set
+    pub gpsport: Option<PathBuf>,
+
+    /// Baud rate of the GPS receiver
+    pub gpsbaud: u32,
+
+    /// Interval (ms) between position announcements
+    pub positioninterval: u64,
+
+    /// Distance (m) moved that triggers a position announcement before the interval
+    pub positiondistance: f64,
+
+    /// Interval (ms) between telemetry reports to the gateway, 0 disables them
+    pub telemetryinterval: u64,
+
+    /// Comma separated telemetry fields to report
+    /* Any of vdd, uptime, tx, rx, dropped, queue, memory and icmp. Fields
+    left out are not sent at all, which saves airtime. */
+    pub telemetryfields: String,
+
+    /// First and last address the gateway assigns to nodes
+    pub ippoolstart: Ipv4Addr,
+    pub ippoolend: Ipv4Addr,
+
+    /// /64 prefix of the mesh's IPv6 addresses, such as fd6c:6d00::
+    /* Every node takes the address with its node ID as the interface ID
+    and announces it in its broadcasts. Must be the same on every node. */
+    pub ipv6prefix: Option<Ipv6Addr>,
+
+    /// Answer pings to our addresses ourselves instead of passing them to the tunnel
+    /* A node then answers as long as the mesh reaches it, even if the
+    network set up above the tunnel is broken. */
+    pub icmpechoreply: bool,
+
+    /// Compress the headers of IPv4 TCP and UDP packets to nodes that take them
+    /* Only used with nodes announcing it in their info, others get the
+    packets as they are. */
+    pub headercompression: bool,
+
+    /// Rules for packets crossing the tunnel, written as `allow|deny [in|out] [tcp|udp|icmp|proto N] [to addr/len] [port N[-M]]` separated by `;`
+    /* The first matching rule decides, packets no rule matches are let
+    through. `out` is from the tunnel to the radio, `in` the other way. The
+    default keeps LAN multicast and broadcast noise such as mDNS, SSDP and
+    syncthing discovery off the air, an empty list lets everything through. */
+    pub tunfilter: String,
+
+    /// Mesh address of this node, instead of one assigned by the gateway
+    /* Must be in `meshsubnet`. It is announced in broadcasts like any
+    other, assignments from the gateway are ignored. */
+    pub staticip: Option<Ipv4Addr>,
+
+    /// Prefix length the static address is given on the tunnel interface
+    pub staticprefix: u8,
+
+    /// Routes added through the tunnel at startup, written as `dest/len via addr` separated by `;`
+    /* The address they go via must be in `meshsubnet`, such as
+    `0.0.0.0/0 via 172.16.0.1` for a default route through the gateway. */
+    pub staticiproutes: String,
+
+    /// Time (ms) an assigned address is valid for, nodes renew halfway through
+    pub leasetime: u64,
+
+    /// File the gateway keeps its address leases in across restarts
+    pub leasefile: PathBuf,
+
+    /// Answer DNS queries from the mesh at the gateway, using the host's resolvers
+    /* The gateway hands itself out as resolver along with the addresses
+    it leases. Answers are cached for their TTL and cut down to fit the
+    tunnel MTU. */
+    pub dnsproxy: bool,
+
+    /// Responses the gateway's DNS proxy keeps
+    pub dnscachesize: usize,
+
+    /// File the resolver handed out by the gateway is written to, such as /etc/resolv.conf
+    pub resolvfile: Option<PathBuf>,
+
+    /// Masquerade the mesh's traffic out of `uplink` at the gateway, `--no-nat` turns it off
+    /* IPv4 forwarding is switched on and an nftables rule is installed,
+    both undone on shutdown. Nothing is done while `uplink` is unset. */
+    pub nat: bool,
+
+    /// Subnet of the mesh's addresses, masqueraded at the gateway
+    pub meshsubnet: String,
+
+    /// Time (ms) the node holding a mesh address is remembered without hearing from it
+    /* Broadcasts, packets from the node and answers to address queries
+    all confirm it. */
+    pub addressttl: u64,
+
+    /// Time (ms) packets to an unknown mesh address wait for its node to answer a query
+    pub addresswait: u64,
+
+    /// Packets kept for every mesh address being resolved, more are dropped
+    pub addressqueuelen: usize,
+
+    /// Interface the gateway reaches the internet through, such as eth0
+    pub uplink: Option<String>,
+
+    /// File the known nodes, links, neighbors, gateways, frame IDs and our address are kept in across restarts
+    pub statefile: PathBuf,
+
+    /// Interval (ms) between saves of the mesh state, 0 disables keeping it
+    pub stateinterval: u64,
+
+    /// File the routing table is kept in across restarts, saved along with the mesh state
+    pub routefile: PathBuf,
+
+    /// Time (ms) a route is kept without being confirmed
+    pub routemaxage: u64,
+
+    /// Time (ms) before expiry a route in use is probed to keep it alive, 0 disables probing
+    pub routeprobebefore: u64,
+
+    /// Frames heard from a neighbor within the link window before its link is costed by ETX
+    /* Until then the link counts as a single hop, so routes are compared
+    by hop count. 0 trusts the estimate from the first frame. */
+    pub etxminsamples: usize,
+
+    /// Routes that are always used, written as `dest:hop,hop,...` separated by `;`
+    /* The path leaves out this node and ends at the destination. */
+    pub staticroutes: String,
+
+    /// Hold messages for nodes that can't be reached until they are heard again
+    /* Meant for the gateway, or any node that stays in range of most others. */
+    pub storeforward: bool,
+
+    /// File held messages are kept in across restarts
+    pub storefile: PathBuf,
+
+    /// Most messages held, and most payload bytes, the oldest make room
+    pub storemaxmessages: usize,
+    pub storemaxbytes: usize,
+
+    /// Time (ms) a message is held before it is given up on
+    pub storemaxage: u64,
+
+    /// Interval (ms) between requests for an address while the gateway doesn't answer
+    pub iprequestinterval: u64,
+
+    /// Interval (ms) between the gateway's announcements of its uplink and load
+    /* Nodes pick the best gateway from these, and fail over to another
+    when `gatewaymissed` in a row are missed. 0 disables them. */
+    pub gatewayinterval: u64,
+
+    /// Announcements in a row a gateway may miss before another takes over
+    pub gatewaymissed: u32,
+
+    /// Frames to a gateway that fail on the way before another takes over, 0 never
+    /* A demoted gateway stays behind the others for `gatewaymissed`
+    announcement intervals. */
+    pub gatewayfailures: u32,
+
+    /// Interval (ms) between the gateway's announcements of whether it reaches the internet
+    /* A change is announced at once. Nodes send traffic with no route of
+    its own to the reachable gateway with the lowest metric, and forget one
+    that missed `gatewaymissed` in a row. 0 disables them. */
+    pub networkinterval: u64,
+
+    /// Time (ms) a node has to wait between remote commands it sends us
+    pub remoteinterval: u64,
+
+    /// Key shared by the nodes of the mesh to sign config changes, 64 hex digits
+    /* Without it config changes from other nodes are rejected. */
+    pub configkey: Option<String>,
+
+    /// Key shared by the nodes of the mesh to sign every frame, 64 hex digits
+    /* The HMAC tag only authenticates frames, unlike encryption it does not
+    hide them. */
+    pub framekey: Option<String>,
+
+    /// Drop frames that are not signed with the framekey
+    pub requiresigned: bool,
+
+    /// Encrypt the payload of every frame with the framekey, and drop frames that are not
+    /* ChaCha20 under a key derived from the framekey, before the frame is
+    signed. Like the signature it is taken off and put back on by every
+    hop, so relays read what they pass on. */
+    pub encryption: bool,
+
+    /// Settings other nodes may change with a signed config message
+    pub mutableconfigkeys: Vec<String>,
+
+    /// Lowest radio supply voltage (V) the node starts with
+    /* Below it the module browns out while transmitting. */
+    pub vddminthreshold: f32,
+
+    /// Interval (ms) between repeats of our emergency beacon until it is cancelled
+    pub emergencyinterval: u64,
+
+    /// Beacon frames per hour that may jump ahead of all other traffic
+    /* Ours and those we relay. Beyond it beacons still go out, queued
+    as high priority. */
+    pub emergencyallowance: usize,
+
+    /// Interval (ms) between hellos telling neighbors how well we hear them, 0 disables them
+    pub hellointerval: u64,
+
+    /// Interval (ms) between the gateway's time announcements, 0 disables them
+    pub timesyncinterval: u64,
+
+    /// Sleep the radio between windows every node is awake for together
+    /* The windows are kept in mesh time, so they need the gateway's time
+    syncs. A node that has not heard one for `dutycycleresync` listens
+    all the time until it does. */
+    pub dutycycle: bool,
+
+    /// Length (ms) of a duty cycle, one awake window and the sleep after it
+    pub dutycycleperiod: u64,
+
+    /// Time (ms) every node is awake at the start of each duty cycle
+    pub dutycycleawake: u64,
+
+    /// Time (ms) the awake window is opened early and closed late, for clocks that are a little apart
+    pub dutycycleguard: u64,
+
+    /// Time (ms) without a time sync after which the sleep schedule is no longer trusted
+    pub dutycycleresync: u64,
+
+    /// Number of frames each transmit priority tier can hold while duty cycling
+    /* Everything sent while the radio sleeps waits for the next window,
+    this takes the place of `txqueuesize` when it is larger. */
+    pub dutycyclequeue: usize,
+
+    /// Step the system clock to the gateway's time instead of only correcting mesh time
+    /* Needs root privileges. Meant for nodes without a real-time clock. */
+    pub clockstep: bool,
+
+    /// Spreading factor, bandwidth (kHz) and coding rate numerator (4/5 to 4/8) the radio is set up with
+    /* Used to estimate airtime and to keep chunks within what the radio
+    sends in one packet, they must match the radio initialization
+    commands. */
+    pub radiosf: u8,
+    pub radiobw: u32,
+    pub radiocr: u8,
+
+    /// Lower the spreading factor, down to `adrminsf`, while every neighbor is heard well
+    /* A radio only hears frames sent with its own spreading factor, so a
+    node announces a change in its heartbeats a heartbeat interval before
+    making it and neighbors that hear one follow. `radiosf` is where it
+    starts, the highest it goes back up to, and where it returns when no
+    neighbor is heard for `neighbortimeout`. */
+    pub adaptivedatarate: bool,
+    pub adrminsf: u8,
+
+    /// SNR margin (dB) over what the spreading factor needs, and the extra margin before lowering it
+    pub adrsnrmargin: i8,
+    pub adrhysteresis: u8,
+
+    /// Modulation of the radio, `Lora` or `Fsk` with its `bitrate` and `frequency_deviation`
+    /* All nodes must use the same. A node that hears nothing for
+    `neighbortimeout` after starting warns it may not match the others. */
+    pub modulation: TxEncoding,
+
+    /// Directory files sent to us are saved in
+    pub filedir: PathBuf,
+
+    /// Largest file (bytes) sent or accepted
+    pub maxfilesize: u64,
+
+    /// Time (ms) without progress before a file transfer asks again
+    pub filetimeout: u64,
+
     /// Radio initialization command file
     pub radiocfg: Option<PathBuf>,
 
+    /// ID of the mesh, the radio's sync word is derived from it
+    /* Meshes with different IDs on the same channel don't hear each other,
+    mostly. There are only 254 sync words to go around. */
+    pub networkid: Option<u32>,
+
     /// Maximum frame size sent to radio [10..250] (valid only for ping and kiss)
     pub maxpacketsize: usize,
 
+    /// MTU of the tun interface [68..1500]
+    /* Larger packets from the kernel are fragmented before they are sent,
+    or answered with ICMP Fragmentation Needed when they may not be. */
+    pub tunmtu: usize,
+
     /// The size of the transmission slot, in milliseconds, used for transmission
     /// rate limiting
     /* The smaller the transmission slot, the more frequently transmissions will occur */
     pub txslot: u64,
 
+    /// Time (ms) without a frame sent or received before the radio loop is restarted, 0 disables it
+    /* Must be well above the heartbeat interval, heartbeats keep a quiet
+    channel from looking stalled. */
+    pub watchdogtimeout: u64,
+
+    /// Time (ms) a scheduled transmission may be late before it fails, 0 only warns
+    pub txjittertolerance: u64,
+
+    /// Number of frames each transmit priority tier can hold
+    pub txqueuesize: usize,
+
+    /// Number of low priority frames that can wait for any one destination
+    /* Application data is shared out between destinations in turns, this
+    keeps a bulk transfer from filling the whole of its tier. */
+    pub txdestdepth: usize,
+
+    /// Reserve the channel with the next hop before large bursts of data
+    /* Against hidden terminals, two nodes sending to the same receiver
+    without hearing each other. Nodes overhearing a reservation hold back
+    their own frames while it lasts. */
+    pub reservation: bool,
+
+    /// Size (bytes) of a data burst above which the channel is reserved for it
+    pub reservationthreshold: usize,
+
+    /// Longest time (ms) a reservation holds the channel, whatever it asks for
+    pub reservationmax: u64,
+
+    /// Time (ms) a burst waits for its reservation to be granted before it is sent anyway
+    pub reservationwait: u64,
+
+    /// Interval (ms) between broadcasts announcing us to nearby nodes
+    pub broadcastinterval: u64,
+
+    /// Percentage of the interval each broadcast is moved by at random, either way
+    /* Nodes that started together would otherwise keep broadcasting at the
+    same time and collide every time. */
+    pub broadcastjitter: u32,
+
+    /// Longest random delay (ms) before the first broadcast after startup
+    pub broadcastdelay: u64,
+
+    /// Longest interval (ms) broadcasts stretch to while the neighbors stay the same, 0 keeps it fixed
+    /* The interval doubles after each broadcast without a change and goes
+    back to broadcastinterval as soon as a neighbor appears or expires. */
+    pub broadcastmaxinterval: u64,
+
+    /// Interval (ms) between heartbeats to nearby nodes
+    /* Heartbeats are much smaller than broadcasts and should be sent more
+    often. They carry the sender's supply voltage and uptime. */
+    pub heartbeatinterval: u64,
+
+    /// Time (ms) without a heartbeat or broadcast before a neighbor is expired
+    /* At least three heartbeat intervals, a neighbor is not given up on
+    for a heartbeat or two lost in collisions. */
+    pub neighbortimeout: u64,
+
+    /// Time (ms) without hearing from a node, first hand or flooded, before
+    /// it counts as unreachable for partition detection, 0 disables it
+    /* Must be longer than the broadcast interval, a node broadcasting on
+    its longest interval would otherwise look gone between broadcasts. */
+    pub partitionwindow: u64,
+
+    /// Percentage of the reachable nodes that must go silent together for a partition to be suspected
+    pub partitionthreshold: u8,
+
+    /// Time (ms) broadcasts and hellos go faster after a partition is suspected or heals
+    pub partitionboost: u64,
+
+    /// Interval (ms) between pings sent by the ping command
+    pub pinginterval: u64,
+
+    /// Time (ms) to wait for a pong before a ping is counted as lost
+    pub pingtimeout: u64,
+
+    /// Time (ms) to wait for the answer to a traceroute
+    pub tracetimeout: u64,
+
+    /// Time (ms) to wait for the delivery receipt of a text
+    pub receipttimeout: u64,
+
+    /// How routes are found, "aodv" on demand, "linkstate" proactively, or
+    /// "dsr" on demand as source routes kept by each origin
+    pub routing: RoutingMode,
+
+    /// Paths kept in the DSR route cache
+    pub dsrcachesize: usize,
+
+    /// Interval (ms) between link-state advertisements
+    pub lsainterval: u64,
+
+    /// Random delay (ms) of up to this much added to each advertisement
+    /* Keeps neighbors that started together from advertising at the same time */
+    pub lsajitter: u64,
+
+    /// Time (ms) after which a node's advertisement is dropped if not refreshed
+    pub lsamaxage: u64,
+
+    /// Minimum time (ms) between route errors for the same link, and between
+    /// route discoveries for the same node
+    pub routeerrorinterval: u64,
+
     /// Timeout (ms) to drop incomplete packet chunks
     pub chunktimeout: u64,
 
+    /// Retransmit frames overheard on their way to other nodes
+    /* Helps frames past weak links at the cost of airtime, each node
+    passes a frame on once at most and never beyond maxhops. */
+    pub autoretransmit: bool,
+
     /// Maximum number of hops a packet should travel
     pub maxhops: u8,
+
+    /// Send floods from other nodes on, a node that does not only receives them
+    pub floodrelay: bool,
+
+    /// Copies of a flood overheard from other nodes after which we don't send it on
+    pub floodthreshold: usize,
+
+    /// Longest random delay (ms) before sending a flood on
+    /* Spreads the rebroadcasts of neighbors out, and gives each time to
+    overhear the others. */
+    pub floodwindow: u64,
+
+    /// How the delay within `floodwindow` is chosen, `rssi` or `random`
+    /* With `rssi` floods heard weakly, from far away, are sent on first as
+    they cover the most new ground. Frames without an RSSI get a random
+    delay either way. */
+    pub flooddelay: FloodDelay,
+
+    /// Number of frames expected per half `dedupttl`, sizes the duplicate filter
+    pub dedupsize: usize,
+
+    /// Share of new frames the duplicate filter may mistake for duplicates
+    /* A mistaken frame is dropped like a duplicate, a real duplicate
+    is never let through. */
+    pub dedupfprate: f64,
+
+    /// Pad outgoing payloads to hide their length
+    /* Padding happens before encryption, so it cannot be enabled without it.
+    The airtime it costs is listed with the transmit queues. */
+    pub padding: bool,
+
+    /// Payloads are padded to a multiple of this size, 0 pads to the maximum frame size
+    pub padbucket: usize,
+
+    /// Time (ms) an ack waits for more frames from the same node to confirm at once
+    pub ackdelay: u64,
+
+    /// Time (ms) we wait for the ack of a frame that asked for one
+    pub acktimeout: u64,
+
+    /// Time (ms) to wait to overhear the next hop send a frame on before sending it again
+    pub hoptimeout: u64,
+
+    /// Times a frame is sent again to a silent next hop before the hop is given up on
+    pub hopretries: u8,
+
+    /// Time (ms) data for a node without a known route waits for route discovery
+    pub routewait: u64,
+
+    /// Time (ms) a seen frame is remembered for duplicate suppression
+    /* This should be shorter than the time it takes frame IDs to wrap around */
+    pub dedupttl: u64,
 }
 
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
         let mut settings = config::Config::default();
         settings.set_default("nodeid", 0);
+        settings.set_default("usehardwareeui", false);
+        settings.set_default("resolveidcollisions", false);
         settings.set_default("debug", false);
+        settings.set_default::<Option<&str>>("nodename", None);
         settings.set_default("isgateway", false);
         settings.set_default("radioport", "/dev/ttyUSB0");
+        settings.set_default("controlsocket", "/var/run/loramesh.sock");
+        settings.set_default("region", "EU868");
+        settings.set_default("lbtenabled", false);
+        settings.set_default("lbtthresholddbm", -80);
+        settings.set_default("telemetryinterval", 600000);
+        settings.set_default("telemetryfields", "vdd,uptime,tx,rx,dropped,queue,memory,icmp");
+        settings.set_default("ippoolstart", "172.16.0.2");
+        settings.set_default("ippoolend", "172.16.0.254");
+        settings.set_default::<Option<&str>>("ipv6prefix", None);
+        settings.set_default("icmpechoreply", false);
+        settings.set_default("headercompression", true);
+        settings.set_default("tunfilter", DEFAULT_TUN_FILTER);
+        settings.set_default("leasetime", 86400000);
+        settings.set_default("leasefile", "/var/lib/loramesh/leases.json");
+        settings.set_default("dnsproxy", false);
+        settings.set_default("dnscachesize", 256);
+        settings.set_default::<Option<&str>>("resolvfile", None);
+        settings.set_default("nat", true);
+        settings.set_default("meshsubnet", "172.16.0.0/24");
+        settings.set_default("addressttl", 300000);
+        settings.set_default("addresswait", 10000);
+        settings.set_default("addressqueuelen", 8);
+        settings.set_default::<Option<&str>>("staticip", None);
+        settings.set_default("staticprefix", 24);
+        settings.set_default("staticiproutes", "");
+        settings.set_default::<Option<&str>>("uplink", None);
+        settings.set_default("statefile", "/var/lib/loramesh/mesh.json");
+        settings.set_default("stateinterval", 300000);
+        settings.set_default("routefile", "/var/lib/loramesh/routes.json");
+        settings.set_default("routemaxage", 600000);
+        settings.set_default("routeprobebefore", 60000);
+        settings.set_default("etxminsamples", 5);
+        settings.set_default("staticroutes", "");
+        settings.set_default("storeforward", false);
+        settings.set_default("storefile", "/var/lib/loramesh/held.json");
+        settings.set_default("storemaxmessages", 100);
+        settings.set_default("storemaxbytes", 65536);
+        settings.set_default("storemaxage", 86400000);
+        settings.set_default("iprequestinterval", 30000);
+        settings.set_default("gatewayinterval", 60000);
+        settings.set_default("gatewaymissed", 3);
+        settings.set_default("gatewayfailures", 3);
+        settings.set_default("networkinterval", 60000);
+        settings.set_default("hellointerval", 60000);
+        settings.set_default("vddminthreshold", 2.7);
+        settings.set_default("emergencyinterval", 30000);
+        settings.set_default("emergencyallowance", 60);
+        settings.set_default("remoteinterval", 5000);
+        settings.set_default::<Option<&str>>("configkey", None);
+        settings.set_default("mutableconfigkeys", Vec::<String>::new());
+        settings.set_default::<Option<&str>>("framekey", None);
+        settings.set_default("requiresigned", false);
+        settings.set_default("encryption", false);
+        settings.set_default("timesyncinterval", 600000);
+        settings.set_default("dutycycle", false);
+        settings.set_default("dutycycleperiod", 120000);
+        settings.set_default("dutycycleawake", 10000);
+        settings.set_default("dutycycleguard", 500);
+        settings.set_default("dutycycleresync", 1800000);
+        settings.set_default("dutycyclequeue", 256);
+        settings.set_default("clockstep", false);
+        settings.set_default("radiosf", 12);
+        settings.set_default("radiobw", 125);
+        settings.set_default("radiocr", 5);
+        settings.set_default("adaptivedatarate", false);
+        settings.set_default("adrminsf", 7);
+        settings.set_default("adrsnrmargin", 10);
+        settings.set_default("adrhysteresis", 3);
+        settings.set_default("modulation", "Lora");
+        settings.set_default("filedir", "/var/lib/loramesh/files");
+        settings.set_default("maxfilesize", 65536);
+        settings.set_default("filetimeout", 20000);
         settings.set_default::<Option<&str>>("radiocfg", None);
+        settings.set_default::<Option<&str>>("networkid", None);
+        settings.set_default::<Option<&str>>("gpsport", None);
+        settings.set_default("gpsbaud", 9600);
+        settings.set_default("positioninterval", 300000);
+        settings.set_default("positiondistance", 50.0);
         settings.set_default("maxpacketsize", 200);
+        settings.set_default("tunmtu", 1500);
         settings.set_default("txslot", 1000);
+        settings.set_default("txqueuesize", 64);
+        settings.set_default("txdestdepth", 16);
+        settings.set_default("reservation", false);
+        settings.set_default("reservationthreshold", 600);
+        settings.set_default("reservationmax", 30000);
+        settings.set_default("reservationwait", 5000);
+        settings.set_default("txjittertolerance", 0);
+        settings.set_default("watchdogtimeout", 120000);
+        settings.set_default("broadcastinterval", 60000);
+        settings.set_default("broadcastjitter", 20);
+        settings.set_default("broadcastdelay", 20000);
+        settings.set_default("broadcastmaxinterval", 0);
+        settings.set_default("heartbeatinterval", 5000);
+        settings.set_default("partitionwindow", 180000);
+        settings.set_default("partitionthreshold", 30);
+        settings.set_default("partitionboost", 600000);
+        settings.set_default("neighbortimeout", 200000);
+        settings.set_default("pinginterval", 5000);
+        settings.set_default("pingtimeout", 30000);
+        settings.set_default("tracetimeout", 60000);
+        settings.set_default("receipttimeout", 60000);
+        settings.set_default("routing", "aodv");
+        settings.set_default("dsrcachesize", 32);
+        settings.set_default("lsainterval", 60000);
+        settings.set_default("lsajitter", 5000);
+        settings.set_default("lsamaxage", 300000);
+        settings.set_default("routeerrorinterval", 30000);
         settings.set_default("chunktimeout", 10000);
         settings.set_default("maxhops", 2);
+        settings.set_default("autoretransmit", false);
+        settings.set_default("floodrelay", true);
+        settings.set_default("floodthreshold", 3);
+        settings.set_default("floodwindow", 500);
+        settings.set_default("flooddelay", "rssi");
+        settings.set_default("dedupsize", 256);
+        settings.set_default("dedupfprate", 0.01);
+        settings.set_default("dedupttl", 30000);
+        settings.set_default("ackdelay", 250);
+        settings.set_default("acktimeout", 10000);
+        settings.set_default("hoptimeout", 5000);
+        settings.set_default("hopretries", 2);
+        settings.set_default("routewait", 30000);
+        settings.set_default("padding", false);
+        settings.set_default("padbucket", 32);
 
 
         // local user settings file
@@ -60,7 +649,163 @@ impl Settings {
         // Add in settings from the environment (with a prefix of APP)
         settings.merge(config::Environment::with_prefix("LOMESH")).unwrap();
 
-        settings.try_into()
+        let opt: Settings = settings.try_into()?;
+        opt.validate()?;
+        Ok(opt)
+    }
+
+    /// Reject combinations of settings that cannot work together
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // padded plaintext still leaks the true length
+        if self.padding && !self.encryption {
+            return Err(ConfigError::Message(String::from("padding requires frame encryption, which is not enabled")));
+        }
+        // our own heartbeats are what keeps an idle radio loop from looking stalled
+        if self.watchdogtimeout > 0 && self.watchdogtimeout <= self.heartbeatinterval {
+            return Err(ConfigError::Message(String::from("watchdogtimeout must be longer than heartbeatinterval")));
+        }
+        if self.neighbortimeout < self.heartbeatinterval * HEARTBEAT_MISSES as u64 {
+            return Err(ConfigError::Message(format!("neighbortimeout must be at least {} heartbeat intervals", HEARTBEAT_MISSES)));
+        }
+        if self.broadcastinterval == 0 {
+            return Err(ConfigError::Message(String::from("broadcastinterval must be greater than 0")));
+        }
+        // a jitter of the whole interval could broadcast without pause
+        if self.broadcastjitter >= 100 {
+            return Err(ConfigError::Message(String::from("broadcastjitter must be below 100 percent")));
+        }
+        if self.broadcastmaxinterval > 0 && self.broadcastmaxinterval < self.broadcastinterval {
+            return Err(ConfigError::Message(String::from("broadcastmaxinterval must not be shorter than broadcastinterval")));
+        }
+        if self.dutycycle {
+            if self.dutycycleawake == 0 || self.dutycycleawake + 2 * self.dutycycleguard >= self.dutycycleperiod {
+                return Err(ConfigError::Message(String::from("dutycycleawake and twice dutycycleguard must fit in dutycycleperiod")));
+            }
+            if self.timesyncinterval == 0 || self.dutycycleresync <= self.timesyncinterval {
+                return Err(ConfigError::Message(String::from("dutycycle needs time syncs more often than dutycycleresync")));
+            }
+            // a sleeping radio is quiet, neither the watchdog nor the neighbor table may take that for a failure
+            let sleep = self.dutycycleperiod - self.dutycycleawake - 2 * self.dutycycleguard;
+            if self.watchdogtimeout > 0 && self.watchdogtimeout <= sleep {
+                return Err(ConfigError::Message(String::from("watchdogtimeout must be longer than the duty cycle sleep")));
+            }
+            if self.neighbortimeout <= self.dutycycleperiod {
+                return Err(ConfigError::Message(String::from("neighbortimeout must be longer than dutycycleperiod")));
+            }
+        }
+        if self.partitionwindow > 0 && self.partitionwindow <= self.broadcastinterval.max(self.broadcastmaxinterval) {
+            return Err(ConfigError::Message(String::from("partitionwindow must be longer than the broadcast interval")));
+        }
+        let meshsubnet = parse_subnet(&self.meshsubnet)
+            .map_err(|e| ConfigError::Message(format!("meshsubnet: {}", e)))?;
+        if let Some(staticip) = self.staticip {
+            if !in_subnet(staticip, meshsubnet) {
+                return Err(ConfigError::Message(format!("staticip {} is outside meshsubnet {}", staticip, self.meshsubnet)));
+            }
+        }
+        // broadcasts alone keep the address of a quiet node known
+        if self.addressttl <= self.broadcastinterval.max(self.broadcastmaxinterval) {
+            return Err(ConfigError::Message(String::from("addressttl must be longer than the broadcast interval")));
+        }
+        if self.addresswait == 0 || self.addressqueuelen == 0 {
+            return Err(ConfigError::Message(String::from("addresswait and addressqueuelen must be greater than 0")));
+        }
+        if self.staticprefix == 0 || self.staticprefix > 32 {
+            return Err(ConfigError::Message(String::from("staticprefix must be between 1 and 32")));
+        }
+        let iproutes = parse_ip_routes(&self.staticiproutes)
+            .map_err(|e| ConfigError::Message(format!("staticiproutes: {}", e)))?;
+        if let Some((_, via)) = iproutes.iter().find(|(_, via)| !in_subnet(*via, meshsubnet)) {
+            return Err(ConfigError::Message(format!("staticiproutes go via {}, which is outside meshsubnet {}", via, self.meshsubnet)));
+        }
+        if self.maxhops as usize > MAX_ROUTE_LEN {
+            return Err(ConfigError::Message(format!("maxhops must be at most {}", MAX_ROUTE_LEN)));
+        }
+        // a signed chunk over the longest route must still fit a frame
+        if self.maxpacketsize == 0 || chunk_overhead(self.maxhops) + self.maxpacketsize > MAX_FRAME_LEN {
+            return Err(ConfigError::Message(format!("maxpacketsize must be between 1 and {} with maxhops {}",
+                MAX_FRAME_LEN.saturating_sub(chunk_overhead(self.maxhops)), self.maxhops)));
+        }
+        if self.modulation == TxEncoding::Lora && max_payload_for(self.radiosf, self.radiobw, self.radiocr) <= chunk_overhead(self.maxhops) {
+            return Err(ConfigError::Message(format!("radiosf {}, radiobw {} and radiocr {} leave no room for a chunk with maxhops {}",
+                self.radiosf, self.radiobw, self.radiocr, self.maxhops)));
+        }
+        if self.tunmtu < MIN_TUN_MTU || self.tunmtu > MAX_PAYLOAD_LEN {
+            return Err(ConfigError::Message(format!("tunmtu must be between {} and {}", MIN_TUN_MTU, MAX_PAYLOAD_LEN)));
+        }
+        if self.txdestdepth == 0 {
+            return Err(ConfigError::Message(String::from("txdestdepth must be at least 1")));
+        }
+        // reservations carry their duration in ms as 16 bits
+        if self.reservation && (self.reservationmax == 0 || self.reservationmax > u16::MAX as u64) {
+            return Err(ConfigError::Message(String::from("reservationmax must be between 1 and 65535")));
+        }
+        if self.partitionthreshold == 0 || self.partitionthreshold > 100 {
+            return Err(ConfigError::Message(String::from("partitionthreshold must be between 1 and 100 percent")));
+        }
+        if let Some(prefix) = self.ipv6prefix {
+            if prefix.segments()[4..].iter().any(|segment| *segment != 0) {
+                return Err(ConfigError::Message(String::from("ipv6prefix must be a /64 prefix")));
+            }
+            // IPv6 packets are never fragmented on the way
+            if self.tunmtu < IPV6_MIN_MTU {
+                return Err(ConfigError::Message(format!("tunmtu must be at least {} with ipv6prefix", IPV6_MIN_MTU)));
+            }
+        }
+        if u32::from(self.ippoolstart) > u32::from(self.ippoolend) {
+            return Err(ConfigError::Message(String::from("ippoolstart must not come after ippoolend")));
+        }
+        if self.dedupfprate <= 0.0 || self.dedupfprate >= 1.0 {
+            return Err(ConfigError::Message(String::from("dedupfprate must be between 0 and 1")));
+        }
+        // a beacon repeated without pause would hog the channel
+        if self.emergencyinterval == 0 {
+            return Err(ConfigError::Message(String::from("emergencyinterval must be greater than 0")));
+        }
+        // gateways would be forgotten as soon as they were heard
+        if self.gatewaymissed == 0 {
+            return Err(ConfigError::Message(String::from("gatewaymissed must be at least 1")));
+        }
+        // frames would be sent again on every pass of the main loop
+        if self.hoptimeout == 0 {
+            return Err(ConfigError::Message(String::from("hoptimeout must be greater than 0")));
+        }
+        // probing before a route even exists would probe it all the time
+        if self.routeprobebefore >= self.routemaxage {
+            return Err(ConfigError::Message(String::from("routeprobebefore must be shorter than routemaxage")));
+        }
+        // every route found would be dropped right away
+        if self.routing == RoutingMode::Dsr && self.dsrcachesize == 0 {
+            return Err(ConfigError::Message(String::from("dsrcachesize must be greater than 0")));
+        }
+        if let Some(key) = &self.configkey {
+            parse_config_key(key).map_err(|e| ConfigError::Message(e.to_string()))?;
+        }
+        if let Some(key) = &self.framekey {
+            parse_frame_key(key).map_err(|e| ConfigError::Message(e.to_string()))?;
+        }
+        // nothing could be checked, every frame would be dropped
+        if self.requiresigned && self.framekey.is_none() {
+            return Err(ConfigError::Message(String::from("requiresigned needs a framekey")));
+        }
+        if self.encryption && self.framekey.is_none() {
+            return Err(ConfigError::Message(String::from("encryption needs a framekey")));
+        }
+        // the others are only read at startup, a change would not take
+        if let Some(key) = self.mutableconfigkeys.iter().find(|key| !REMOTE_SETTINGS.contains(&key.as_str())) {
+            return Err(ConfigError::Message(format!("{} cannot be changed while the node runs", key)));
+        }
+        self.modulation.validate().map_err(|e| ConfigError::Message(e.to_string()))?;
+        if self.adaptivedatarate && (self.adrminsf < 7 || self.adrminsf > self.radiosf) {
+            return Err(ConfigError::Message(String::from("adrminsf must be between 7 and radiosf")));
+        }
+        parse_static_routes(&self.staticroutes)
+            .map_err(|e| ConfigError::Message(e.to_string()))?;
+        TelemetryField::parse_list(&self.telemetryfields)
+            .map_err(|e| ConfigError::Message(e.to_string()))?;
+        PacketFilter::parse(&self.tunfilter)
+            .map_err(|e| ConfigError::Message(e.to_string()))?;
+        Ok(())
     }
 }
 
@@ -70,9 +815,144 @@ fn settings_load() {
     let opt: Settings = Settings::new().expect("Error loading settings");
 
     assert_eq!(&opt.nodeid, &0);
+    assert_eq!(&opt.usehardwareeui, &false);
+    assert_eq!(&opt.resolveidcollisions, &false);
     assert_eq!(&opt.isgateway, &false);
     assert_eq!(&opt.radioport.to_str().unwrap(), &"/dev/ttyUSB0");
     assert_eq!(&opt.maxpacketsize, &200usize);
+    assert_eq!(&opt.tunmtu, &1500usize);
     assert_eq!(&opt.maxhops, &2);
+    assert_eq!(&opt.autoretransmit, &false);
+    assert_eq!(&opt.routing, &RoutingMode::Aodv);
+    assert_eq!((opt.txqueuesize, opt.txdestdepth), (64, 16));
+    assert!(!opt.reservation);
+    assert_eq!((opt.reservationthreshold, opt.reservationmax, opt.reservationwait), (600, 30000, 5000));
+    assert_eq!((opt.partitionwindow, opt.partitionthreshold, opt.partitionboost), (180000, 30, 600000));
+    assert_eq!(&opt.dsrcachesize, &32usize);
     assert_eq!(&opt.radiocfg, &None);
+    assert_eq!(opt.networkid, None);
+    assert!(!opt.dutycycle);
+    assert_eq!((opt.dutycycleperiod, opt.dutycycleawake, opt.dutycycleguard), (120000, 10000, 500));
+    assert_eq!((opt.dutycycleresync, opt.dutycyclequeue), (1800000, 256));
+    assert_eq!(&opt.region, &LoraRegion::EU868);
+    assert_eq!((opt.lbtenabled, opt.lbtthresholddbm), (false, -80));
+    assert_eq!(&opt.gpsport, &None);
+    assert_eq!(&opt.gpsbaud, &9600);
+    assert_eq!((opt.floodrelay, opt.floodthreshold, opt.floodwindow), (true, 3, 500));
+    assert_eq!(opt.flooddelay, FloodDelay::Rssi);
+    assert_eq!(&opt.dedupsize, &256usize);
+    assert_eq!(&opt.dedupfprate, &0.01);
+    assert_eq!(&opt.padding, &false);
+    assert_eq!((opt.ackdelay, opt.acktimeout), (250, 10000));
+    assert_eq!((opt.hoptimeout, opt.hopretries, opt.routewait), (5000, 2, 30000));
+    assert_eq!(&opt.controlsocket.to_str().unwrap(), &"/var/run/loramesh.sock");
+    assert_eq!(&opt.pingtimeout, &30000);
+    assert_eq!(&opt.ippoolstart, &Ipv4Addr::new(172, 16, 0, 2));
+    assert_eq!(opt.ipv6prefix, None);
+    assert!(!opt.icmpechoreply);
+    assert!(opt.headercompression);
+    assert_eq!(opt.tunfilter, DEFAULT_TUN_FILTER);
+    assert_eq!(&opt.leasetime, &86400000);
+    assert_eq!((opt.dnsproxy, opt.dnscachesize, opt.resolvfile.as_ref()), (false, 256, None));
+    assert_eq!((opt.nat, opt.meshsubnet.as_str(), opt.uplink.as_ref()), (true, "172.16.0.0/24", None));
+    assert_eq!((opt.staticip, opt.staticprefix, opt.staticiproutes.as_str()), (None, 24, ""));
+    assert_eq!((opt.addressttl, opt.addresswait, opt.addressqueuelen), (300000, 10000, 8));
+    assert_eq!(&opt.stateinterval, &300000);
+    assert_eq!((opt.routemaxage, opt.staticroutes.as_str()), (600000, ""));
+    assert_eq!(opt.routeprobebefore, 60000);
+    assert_eq!(opt.etxminsamples, 5);
+    assert_eq!(&opt.storeforward, &false);
+    assert_eq!((opt.storemaxmessages, opt.storemaxbytes, opt.storemaxage), (100, 65536, 86400000));
+    assert_eq!(&opt.clockstep, &false);
+    assert_eq!(&opt.gatewayinterval, &60000);
+    assert_eq!((opt.gatewaymissed, opt.gatewayfailures), (3, 3));
+    assert_eq!(opt.networkinterval, 60000);
+    assert_eq!(&opt.hellointerval, &60000);
+    assert_eq!((opt.broadcastinterval, opt.broadcastjitter, opt.broadcastdelay, opt.broadcastmaxinterval), (60000, 20, 20000, 0));
+    assert_eq!((opt.emergencyinterval, opt.emergencyallowance), (30000, 60));
+    assert_eq!(opt.vddminthreshold, 2.7);
+    assert_eq!(&opt.remoteinterval, &5000);
+    assert_eq!((&opt.configkey, opt.mutableconfigkeys.len()), (&None, 0));
+    assert_eq!((&opt.framekey, opt.requiresigned, opt.encryption), (&None, false, false));
+    assert_eq!(&opt.maxfilesize, &65536);
+    assert_eq!((opt.radiosf, opt.radiobw, opt.radiocr), (12, 125, 5));
+    assert_eq!((opt.adaptivedatarate, opt.adrminsf, opt.adrsnrmargin, opt.adrhysteresis), (false, 7, 10, 3));
+    assert_eq!(opt.modulation, TxEncoding::Lora);
+    assert_eq!(TelemetryField::parse_list(&opt.telemetryfields).unwrap(), TelemetryField::ALL.to_vec());
+
+    let mut radio = opt.clone();
+    radio.radiobw = 62;
+    assert!(radio.validate().is_err());
+    radio.radiobw = 125;
+    radio.radiocr = 9;
+    assert!(radio.validate().is_err());
+
+    let mut padded = opt.clone();
+    padded.padding = true;
+    assert!(padded.validate().is_err());
+    padded.encryption = true;
+    assert!(padded.validate().is_err());
+    padded.framekey = Some("5a".repeat(32));
+    assert!(padded.validate().is_ok());
+
+    let mut watchdog = opt.clone();
+    watchdog.watchdogtimeout = watchdog.heartbeatinterval;
+    assert!(watchdog.validate().is_err());
+    watchdog.watchdogtimeout = 0;
+    assert!(watchdog.validate().is_ok());
+
+    let mut heartbeats = opt.clone();
+    assert_eq!((heartbeats.heartbeatinterval, heartbeats.broadcastinterval), (5000, 60000));
+    heartbeats.neighbortimeout = heartbeats.heartbeatinterval * 2;
+    assert!(heartbeats.validate().is_err());
+    heartbeats.neighbortimeout = heartbeats.heartbeatinterval * 3;
+    assert!(heartbeats.validate().is_ok());
+
+    let mut broadcasts = opt.clone();
+    broadcasts.broadcastjitter = 100;
+    assert!(broadcasts.validate().is_err());
+    broadcasts.broadcastjitter = 20;
+    broadcasts.broadcastmaxinterval = 30000;
+    assert!(broadcasts.validate().is_err());
+
+    let mut pool = opt.clone();
+    pool.ippoolend = Ipv4Addr::new(172, 16, 0, 1);
+    assert!(pool.validate().is_err());
+
+    let mut adr = opt.clone();
+    adr.adaptivedatarate = true;
+    adr.adrminsf = 6;
+    assert!(adr.validate().is_err());
+    adr.adrminsf = 7;
+    assert!(adr.validate().is_ok());
+
+    let mut fsk = opt.clone();
+    fsk.modulation = TxEncoding::Fsk { bitrate: 0, frequency_deviation: 25000 };
+    assert!(fsk.validate().is_err());
+
+    let mut signing = opt.clone();
+    signing.requiresigned = true;
+    assert!(signing.validate().is_err());
+    signing.framekey = Some("a1".repeat(32));
+    assert!(signing.validate().is_ok());
+
+    let mut fields = opt.clone();
+    fields.telemetryfields = String::from("vdd,volume");
+    assert!(fields.validate().is_err());
+
+    let mut filtered = opt.clone();
+    filtered.tunfilter = String::from("deny out udp port 5353; drop in");
+    assert!(filtered.validate().is_err());
+
+    let mut pinned = opt.clone();
+    pinned.staticip = Some(Ipv4Addr::new(172, 16, 1, 40));
+    assert!(pinned.validate().is_err());
+    pinned.staticip = Some(Ipv4Addr::new(172, 16, 0, 40));
+    assert!(pinned.validate().is_ok());
+    pinned.staticiproutes = String::from("0.0.0.0/0 via 192.168.1.1");
+    assert!(pinned.validate().is_err());
+
+    let mut resolving = opt.clone();
+    resolving.addressttl = resolving.broadcastinterval;
+    assert!(resolving.validate().is_err());
 }
\ No newline at end of file
@@ -0,0 +1,219 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use crate::control::{ControlCommand, ControlRequest};
+use crate::hardware::{LoopbackMedium, LoopbackRadio, PairedLoopback};
+use crate::node::MeshNode;
+use crate::settings::Settings;
+use crate::stack::{IpPacket, NetworkTunnel, NodeId};
+
+/// How long the mesh waits between passes over its nodes
+const SIMULATION_TICK: Duration = Duration::from_millis(1);
+
+/// Simulated meshes made so far, each gets its own directory
+static SIMULATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// A node of a simulated mesh and what the test holds of it
+struct SimulatedNode {
+    node: MeshNode<LoopbackRadio>,
+    /// IP packets the node wrote to its tunnel
+    tunnel: Receiver<IpPacket>,
+    /// IP packets to the node's tunnel, as if the kernel routed them there
+    inbound: Sender<IpPacket>,
+    control: Sender<ControlRequest>,
+    down: bool,
+}
+
+/// Mesh nodes on loopback radios, run in one thread for tests
+/* Nodes have the IDs 1 to n and are all out of range of each other until
+linked. Their settings are the defaults with every interval cut down to
+fractions of a second, so a test sees minutes of mesh life in a few
+seconds. Nothing touches the host: tunnels are in memory and files go to
+a directory of their own that is removed again. */
+pub struct SimulatedMesh {
+    nodes: Vec<SimulatedNode>,
+    medium: LoopbackMedium,
+    dir: PathBuf,
+}
+
+impl SimulatedMesh {
+    /// `n` nodes with the simulation's settings
+    pub fn new(n: usize) -> Self {
+        SimulatedMesh::with_settings(n, |_, _| {})
+    }
+
+    /// `n` nodes, with `configure` changing each node's settings before it is made
+    pub fn with_settings<F: Fn(NodeId, &mut Settings)>(n: usize, configure: F) -> Self {
+        let dir = std::env::temp_dir().join(format!("loramesh-sim-{}-{}",
+            std::process::id(), SIMULATIONS.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&dir).unwrap();
+        let paired = PairedLoopback::isolated(n).with_seed(n as u64);
+        let medium = paired.medium();
+        let nodes = paired.into_radios().into_iter().enumerate()
+            .map(|(i, radio)| {
+                let id = i as NodeId + 1;
+                let mut opt = SimulatedMesh::settings(id, &dir);
+                configure(id, &mut opt);
+                opt.validate().unwrap();
+                let (networktunnel, tunnel) = NetworkTunnel::in_memory(opt.tunmtu);
+                let inbound = networktunnel.inboundSender.clone();
+                let (control, requests) = unbounded();
+                let mut node = MeshNode::new(id, networktunnel, radio, opt);
+                node.attach_control(requests);
+                node.start();
+                SimulatedNode { node, tunnel, inbound, control, down: false }
+            })
+            .collect();
+        SimulatedMesh { nodes, medium, dir }
+    }
+
+    /// The settings of node `id`, with fast intervals and its files in `dir`
+    fn settings(id: NodeId, dir: &PathBuf) -> Settings {
+        let mut opt = Settings::new().unwrap();
+        opt.nodeid = id;
+        opt.radiosf = 7;
+        opt.maxhops = 4;
+        opt.watchdogtimeout = 0;
+        opt.txslot = 20;
+        opt.heartbeatinterval = 100;
+        opt.neighbortimeout = 400;
+        opt.broadcastinterval = 500;
+        opt.broadcastdelay = 0;
+        opt.partitionwindow = 0;
+        opt.stateinterval = 0;
+        opt.floodwindow = 100;
+        opt.ackdelay = 10;
+        opt.acktimeout = 2000;
+        opt.hoptimeout = 300;
+        opt.routewait = 3000;
+        opt.receipttimeout = 5000;
+        opt.emergencyinterval = 1000;
+        opt.gatewayinterval = 300;
+        opt.networkinterval = 0;
+        opt.iprequestinterval = 300;
+        opt.hellointerval = 500;
+        opt.lsainterval = 500;
+        opt.lsajitter = 100;
+        opt.leasefile = dir.join(format!("{}-leases.json", id));
+        opt.statefile = dir.join(format!("{}-mesh.json", id));
+        opt.routefile = dir.join(format!("{}-routes.json", id));
+        opt.storefile = dir.join(format!("{}-held.json", id));
+        opt.filedir = dir.join(format!("{}-files", id));
+        opt
+    }
+
+    fn index(&self, id: NodeId) -> usize {
+        assert!(id >= 1 && id as usize <= self.nodes.len(), "no node {} in the mesh", id);
+        id as usize - 1
+    }
+
+    /// The medium the nodes' radios share
+    pub fn medium(&self) -> &LoopbackMedium {
+        &self.medium
+    }
+
+    /// put two nodes in range of each other
+    pub fn link(&self, a: NodeId, b: NodeId) {
+        self.medium.connect(self.index(a), self.index(b));
+    }
+
+    /// link each node to the next one
+    pub fn line(&self, ids: &[NodeId]) {
+        for pair in ids.windows(2) {
+            self.link(pair[0], pair[1]);
+        }
+    }
+
+    /// put every node in range of every other one
+    pub fn full(&self) {
+        for a in 1..=self.nodes.len() as NodeId {
+            for b in (a + 1)..=self.nodes.len() as NodeId {
+                self.link(a, b);
+            }
+        }
+    }
+
+    /// chance that a frame between two nodes is lost
+    pub fn set_loss(&self, a: NodeId, b: NodeId, loss: f64) {
+        self.medium.set_loss(self.index(a), self.index(b), loss);
+    }
+
+    /// RSSI two nodes hear each other with
+    pub fn set_rssi(&self, a: NodeId, b: NodeId, rssi: i16) {
+        self.medium.set_quality(self.index(a), self.index(b), Some(rssi), Some(5));
+    }
+
+    pub fn node(&mut self, id: NodeId) -> &mut MeshNode<LoopbackRadio> {
+        let i = self.index(id);
+        &mut self.nodes[i].node
+    }
+
+    /// Switch a node off, it is no longer run and its radio neither sends nor hears
+    pub fn kill(&mut self, id: NodeId) {
+        let i = self.index(id);
+        self.nodes[i].down = true;
+        self.medium.set_down(i, true);
+    }
+
+    /// Switch a node on again, with everything it knew
+    pub fn revive(&mut self, id: NodeId) {
+        let i = self.index(id);
+        self.nodes[i].down = false;
+        self.medium.set_down(i, false);
+    }
+
+    /// frames a node put on the air
+    pub fn transmissions(&self, id: NodeId) -> u64 {
+        self.medium.transmissions(self.index(id))
+    }
+
+    /// IP packets a node wrote to its tunnel
+    pub fn tunnel(&self, id: NodeId) -> &Receiver<IpPacket> {
+        &self.nodes[self.index(id)].tunnel
+    }
+
+    /// Hand a node an IP packet as if the kernel routed it to its tunnel
+    pub fn inject(&self, id: NodeId, packet: IpPacket) {
+        self.nodes[self.index(id)].inbound.send(packet).unwrap();
+    }
+
+    /// Send a command to a node's control socket, returning where its answers arrive
+    pub fn control(&self, id: NodeId, command: ControlCommand) -> Receiver<String> {
+        let (reply, replies) = unbounded();
+        self.nodes[self.index(id)].control.send(ControlRequest { command, reply }).unwrap();
+        replies
+    }
+
+    /// Run the nodes that are up for `duration`
+    pub fn run_for(&mut self, duration: Duration) {
+        self.run_until(duration, |_| false);
+    }
+
+    /// Run the nodes that are up until `done` is true, false if it is not within `timeout`
+    pub fn run_until<F: FnMut(&mut Self) -> bool>(&mut self, timeout: Duration, mut done: F) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            for (i, node) in self.nodes.iter_mut().enumerate().filter(|(_, node)| !node.down) {
+                // a node reads one frame per poll, it catches up before time moves on
+                node.node.poll();
+                while self.medium.unread(i) > 0 {
+                    node.node.poll();
+                }
+            }
+            if done(self) {
+                return true;
+            }
+            thread::sleep(SIMULATION_TICK);
+        }
+        false
+    }
+}
+
+impl Drop for SimulatedMesh {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::hardware::LinkQuality;
+use crate::stack::frame::NodeId;
+use crate::stack::message::{AckEntry, AckMessage};
+
+/// Most frames confirmed by a single ack
+pub const MAX_COALESCED_ACKS: usize = 16;
+
+/// Collects the acks we owe, sending one ack per node for frames that
+/// arrive close together
+/* An ack waits up to `delay` for more frames from the same node, or
+goes out as soon as it holds `MAX_COALESCED_ACKS` of them. */
+#[derive(Clone, Debug)]
+pub struct AckCoalescer {
+    delay: Duration,
+    /// acks owed to each node, and when the first of them was owed
+    pending: HashMap<NodeId, (Instant, Vec<AckEntry>)>,
+}
+
+impl AckCoalescer {
+    pub fn new(delay: Duration) -> Self {
+        AckCoalescer { delay, pending: HashMap::new() }
+    }
+
+    /// a frame asking for an ack arrived from `origin`
+    pub fn push(&mut self, origin: NodeId, frameid: u8, quality: LinkQuality) {
+        self.push_at(origin, frameid, quality, Instant::now())
+    }
+
+    pub(crate) fn push_at(&mut self, origin: NodeId, frameid: u8, quality: LinkQuality, now: Instant) {
+        let (_, acks) = self.pending.entry(origin).or_insert_with(|| (now, Vec::new()));
+        // a repeated frame is acked again, with its latest reading
+        acks.retain(|ack| ack.frameid != frameid);
+        acks.push(AckEntry { frameid, quality });
+    }
+
+    /// Acks that are due, with the node each goes to
+    pub fn poll(&mut self) -> Vec<(NodeId, AckMessage)> {
+        self.poll_at(Instant::now())
+    }
+
+    pub(crate) fn poll_at(&mut self, now: Instant) -> Vec<(NodeId, AckMessage)> {
+        let delay = self.delay;
+        let mut due: Vec<NodeId> = self.pending.iter()
+            .filter(|(_, (first, acks))| now.duration_since(*first) >= delay || acks.len() >= MAX_COALESCED_ACKS)
+            .map(|(origin, _)| *origin)
+            .collect();
+        due.sort();
+        let mut msgs = Vec::new();
+        for origin in due {
+            let (_, mut acks) = self.pending.remove(&origin).expect("Due acks are pending");
+            while !acks.is_empty() {
+                let rest = acks.split_off(acks.len().min(MAX_COALESCED_ACKS));
+                msgs.push((origin, AckMessage::new(origin, acks)));
+                acks = rest;
+            }
+        }
+        msgs
+    }
+}
+
+/// Frames we sent asking for an ack, until the ack arrives or they time out
+#[derive(Clone, Debug)]
+pub struct AckTracker {
+    timeout: Duration,
+    /// by destination and frame ID, when each was sent
+    pending: HashMap<(NodeId, u8), Instant>,
+    /// how well each destination heard the last frame it acked
+    reverse: HashMap<NodeId, LinkQuality>,
+}
+
+impl AckTracker {
+    pub fn new(timeout: Duration) -> Self {
+        AckTracker { timeout, pending: HashMap::new(), reverse: HashMap::new() }
+    }
+
+    /// a frame asking for an ack went out to `dest`
+    pub fn expect(&mut self, dest: NodeId, frameid: u8) {
+        self.expect_at(dest, frameid, Instant::now())
+    }
+
+    pub(crate) fn expect_at(&mut self, dest: NodeId, frameid: u8, now: Instant) {
+        self.pending.insert((dest, frameid), now);
+    }
+
+    /// Take an ack from `from`, returning the frame IDs it confirmed
+    pub fn handle(&mut self, from: NodeId, ack: &AckMessage) -> Vec<u8> {
+        let mut confirmed = Vec::new();
+        for entry in ack.acks.iter() {
+            if self.pending.remove(&(from, entry.frameid)).is_some() {
+                confirmed.push(entry.frameid);
+                self.reverse.insert(from, entry.quality);
+            }
+        }
+        confirmed
+    }
+
+    /// how well a node heard the last of our frames it acked
+    pub fn reverse_quality(&self, nodeid: NodeId) -> Option<LinkQuality> {
+        self.reverse.get(&nodeid).cloned()
+    }
+
+    /// Frames whose ack did not arrive in time, by destination and frame ID
+    pub fn expire(&mut self) -> Vec<(NodeId, u8)> {
+        self.expire_at(Instant::now())
+    }
+
+    pub(crate) fn expire_at(&mut self, now: Instant) -> Vec<(NodeId, u8)> {
+        let timeout = self.timeout;
+        let mut expired: Vec<(NodeId, u8)> = self.pending.iter()
+            .filter(|(_, sent)| now.duration_since(**sent) >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        expired.sort();
+        for key in expired.iter() {
+            self.pending.remove(key);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn ack_round_trip() {
+    use crate::stack::Frame;
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::{MessageType, TextMessage};
+
+    let start = Instant::now();
+    let mut sender = AckTracker::new(Duration::from_secs(10));
+    let mut receiver = AckCoalescer::new(Duration::from_millis(250));
+
+    // node 1 sends three texts to node 3 over node 2, asking for acks
+    for frameid in 5..8u8 {
+        let mut frame = TextMessage::new(frameid as u16, "hello").unwrap()
+            .to_frame(frameid, 1, vec![2, 3]).unwrap()
+            .with_ack_requested(true);
+        sender.expect_at(3, frameid, start);
+        for mut chunk in frame.chunked(&20).into_iter().map(|bytes| Frame::from_bytes(&bytes).unwrap()) {
+            assert!(chunk.ack_requested());
+            assert_eq!(chunk.msgtype(), MessageType::Text);
+        }
+        let mut received = Frame::from_bytes(&frame.to_bytes()).unwrap();
+        assert!(received.ack_requested() && received.header().route() == vec![2, 3]);
+        let quality = LinkQuality { rssi: Some(-90 - frameid as i16), snr: Some(7) };
+        receiver.push_at(received.sender(), received.frameid(), quality, start);
+    }
+
+    // one ack confirms all three once the delay passed
+    assert!(receiver.poll_at(start + Duration::from_millis(100)).is_empty());
+    let acks = receiver.poll_at(start + Duration::from_millis(250));
+    assert_eq!(acks.len(), 1);
+    let (dest, ack) = &acks[0];
+    assert_eq!(*dest, 1);
+    let mut frame = Frame::from_bytes(&ack.to_frame(9, 3, vec![2, 1]).unwrap().to_bytes()).unwrap();
+    assert!(!frame.ack_requested());
+    let ack = AckMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(ack.acks.iter().map(|a| a.frameid).collect::<Vec<_>>(), vec![5, 6, 7]);
+
+    assert_eq!(sender.handle(3, &ack), vec![5, 6, 7]);
+    assert_eq!(sender.reverse_quality(3), Some(LinkQuality { rssi: Some(-97), snr: Some(7) }));
+    // an ack from the wrong node or a repeated one confirms nothing
+    assert!(sender.handle(3, &ack).is_empty());
+    sender.expect_at(4, 1, start);
+    assert!(sender.handle(3, &AckMessage::new(1, vec![AckEntry { frameid: 1, quality: LinkQuality::default() }])).is_empty());
+    assert_eq!(sender.expire_at(start + Duration::from_secs(10)), vec![(4, 1)]);
+}
+
+#[test]
+fn ack_coalescing() {
+    let start = Instant::now();
+    let mut coalescer = AckCoalescer::new(Duration::from_millis(250));
+    let quality = LinkQuality { rssi: None, snr: Some(-3) };
+
+    // a full ack goes out right away, another node's waits
+    for frameid in 0..MAX_COALESCED_ACKS as u8 {
+        coalescer.push_at(4, frameid, quality, start);
+    }
+    coalescer.push_at(6, 0, quality, start);
+    let acks = coalescer.poll_at(start);
+    assert_eq!(acks.len(), 1);
+    assert_eq!((acks[0].0, acks[0].1.acks.len()), (4, MAX_COALESCED_ACKS));
+
+    // a repeated frame is only acked once
+    coalescer.push_at(6, 0, quality, start + Duration::from_millis(100));
+    coalescer.push_at(6, 1, quality, start + Duration::from_millis(100));
+    let acks = coalescer.poll_at(start + Duration::from_millis(250));
+    assert_eq!(acks.len(), 1);
+    assert_eq!(acks[0].1.acks.iter().map(|a| a.frameid).collect::<Vec<_>>(), vec![0, 1]);
+    assert!(coalescer.poll_at(start + Duration::from_secs(1)).is_empty());
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use crate::stack::frame::NodeId;
+
+/// Good readings in a row before the spreading factor is lowered
+pub const ADR_LOWER_AFTER: u8 = 10;
+
+/// Bad readings in a row before it is raised again
+pub const ADR_RAISE_AFTER: u8 = 2;
+
+/// weight of a new reading in a neighbor's average SNR
+const ADR_SMOOTHING: f32 = 0.25;
+
+/// Lowest SNR in dB the RN2483 demodulates at a spreading factor
+pub fn required_snr(sf: u8) -> f32 {
+    -5.0 - 2.5 * (sf.max(6) - 6) as f32
+}
+
+/// What the adaptive data rate asks of the radio
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdrEvent {
+    SetSF(u8),
+}
+
+/// Lowers the spreading factor while every neighbor is heard well, and raises it as soon as one is not
+/* Like LoRaWAN's ADR, a reading is good when the SNR leaves
+`snr_threshold` dB of margin above what the next lower spreading factor
+needs, plus `hysteresis`, and bad when the margin at the current one falls
+below `snr_threshold`. The radio has one spreading factor for all
+neighbors, so the neighbor with the worst average SNR decides. Lowering
+takes `ADR_LOWER_AFTER` good readings in a row, raising only
+`ADR_RAISE_AFTER` bad ones. */
+#[derive(Clone, Debug)]
+pub struct AdaptiveDataRate {
+    pub min_sf: u8,
+    pub max_sf: u8,
+    pub snr_threshold: i8,
+    pub hysteresis: u8,
+    sf: u8,
+    averages: HashMap<NodeId, f32>,
+    good: u8,
+    bad: u8,
+}
+
+impl AdaptiveDataRate {
+    /// starting out at `max_sf`, where every node can be heard
+    pub fn new(min_sf: u8, max_sf: u8, snr_threshold: i8, hysteresis: u8) -> Self {
+        AdaptiveDataRate { min_sf, max_sf, snr_threshold, hysteresis, sf: max_sf, averages: HashMap::new(), good: 0, bad: 0 }
+    }
+
+    /// Spreading factor the radio should be using
+    pub fn sf(&self) -> u8 {
+        self.sf
+    }
+
+    /// The radio is on a spreading factor, whatever we asked for
+    pub fn set_sf(&mut self, sf: u8) {
+        self.sf = sf;
+        self.good = 0;
+        self.bad = 0;
+    }
+
+    /// A neighbor announced the spreading factor it is moving to, Some if we should follow
+    /* A radio only hears its own spreading factor, so the neighbors have to
+    move together. The announced one is kept within our own limits. */
+    pub fn follow(&mut self, sf: u8) -> Option<AdrEvent> {
+        let sf = sf.max(self.min_sf).min(self.max_sf);
+        if sf == self.sf {
+            return None;
+        }
+        self.set_sf(sf);
+        Some(AdrEvent::SetSF(sf))
+    }
+
+    /// Nobody is heard anymore, go back to `max_sf` where every node can be heard
+    /* A node left alone on a low spreading factor, because it missed the
+    change or its neighbors went back, would never hear them again. The
+    averages are of neighbors that are gone. */
+    pub fn fall_back(&mut self) -> Option<AdrEvent> {
+        self.averages.clear();
+        if self.sf == self.max_sf {
+            return None;
+        }
+        self.set_sf(self.max_sf);
+        Some(AdrEvent::SetSF(self.max_sf))
+    }
+
+    /// Stop counting a neighbor that went away
+    pub fn forget(&mut self, nodeid: NodeId) {
+        self.averages.remove(&nodeid);
+    }
+
+    /// A frame was heard from a neighbor with this SNR, Some if the spreading factor should change
+    pub fn record(&mut self, nodeid: NodeId, snr: i8) -> Option<AdrEvent> {
+        let average = self.averages.entry(nodeid).or_insert(snr as f32);
+        *average += (snr as f32 - *average) * ADR_SMOOTHING;
+        let worst = self.averages.values().cloned().fold(f32::INFINITY, f32::min);
+
+        let threshold = self.snr_threshold as f32;
+        if worst - required_snr(self.sf) < threshold {
+            self.good = 0;
+            self.bad = self.bad.saturating_add(1);
+        } else if self.sf > self.min_sf && worst - required_snr(self.sf - 1) >= threshold + self.hysteresis as f32 {
+            self.bad = 0;
+            self.good = self.good.saturating_add(1);
+        } else {
+            self.good = 0;
+            self.bad = 0;
+        }
+
+        if self.bad >= ADR_RAISE_AFTER && self.sf < self.max_sf {
+            self.set_sf(self.sf + 1);
+            return Some(AdrEvent::SetSF(self.sf));
+        }
+        if self.good >= ADR_LOWER_AFTER {
+            self.set_sf(self.sf - 1);
+            return Some(AdrEvent::SetSF(self.sf));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn adaptive_data_rate() {
+    assert_eq!((required_snr(7), required_snr(12)), (-7.5, -20.0));
+
+    // 10 dB of margin, 3 dB of hysteresis, SF12 at the start
+    let mut adr = AdaptiveDataRate::new(7, 12, 10, 3);
+    assert_eq!(adr.sf(), 12);
+
+    // at 0 dB, SF11 would leave 17.5 dB of margin, enough to step down after ten frames
+    for _ in 0..9 {
+        assert_eq!(adr.record(2, 0), None);
+    }
+    assert_eq!(adr.record(2, 0), Some(AdrEvent::SetSF(11)));
+    // down to SF10, where SF9 would leave 12.5 dB, short of the hysteresis
+    for _ in 0..20 {
+        adr.record(2, 0);
+    }
+    assert_eq!(adr.sf(), 10);
+    for _ in 0..20 {
+        assert_eq!(adr.record(2, 0), None);
+    }
+
+    // a second neighbor heard poorly pulls the worst average down, two frames raise it
+    assert_eq!(adr.record(3, -6), None);
+    assert_eq!(adr.record(3, -6), Some(AdrEvent::SetSF(11)));
+    // it is heard well enough at SF11 to stay there, but not to go lower
+    for _ in 0..20 {
+        assert_eq!(adr.record(3, -6), None);
+    }
+    assert_eq!(adr.sf(), 11);
+
+    // once it is gone the first neighbor decides again
+    adr.forget(3);
+    for _ in 0..20 {
+        adr.record(2, 0);
+    }
+    assert_eq!(adr.sf(), 10);
+
+    // never past the limits
+    let mut adr = AdaptiveDataRate::new(11, 12, 10, 3);
+    for _ in 0..50 {
+        adr.record(2, 10);
+    }
+    assert_eq!(adr.sf(), 11);
+    for _ in 0..50 {
+        adr.record(2, -30);
+    }
+    assert_eq!(adr.sf(), 12);
+}
+
+#[test]
+fn adaptive_data_rate_fall_back() {
+    let mut adr = AdaptiveDataRate::new(7, 12, 10, 3);
+    assert_eq!(adr.fall_back(), None);
+    for _ in 0..30 {
+        adr.record(2, 0);
+    }
+    assert_eq!(adr.sf(), 10);
+
+    // alone on SF10, back to SF12 and starting over
+    assert_eq!(adr.fall_back(), Some(AdrEvent::SetSF(12)));
+    assert_eq!(adr.sf(), 12);
+    assert_eq!(adr.fall_back(), None);
+    for _ in 0..9 {
+        assert_eq!(adr.record(3, 0), None);
+    }
+    assert_eq!(adr.record(3, 0), Some(AdrEvent::SetSF(11)));
+
+    // a neighbor's announced spreading factor is followed within our limits
+    assert_eq!(adr.follow(11), None);
+    assert_eq!(adr.follow(9), Some(AdrEvent::SetSF(9)));
+    assert_eq!(adr.follow(5), Some(AdrEvent::SetSF(7)));
+    assert_eq!(adr.follow(14), Some(AdrEvent::SetSF(12)));
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::stack::frame::NodeId;
+use crate::stack::neighbors::frameid_distance;
+
+/// Frame IDs behind the highest one seen that are still told apart, older ones are rejected
+pub const ANTI_REPLAY_WINDOW: u8 = 64;
+
+/// Furthest ahead of the highest ID seen a frame ID is taken as new, rather than old
+pub(crate) const MAX_AHEAD: u8 = 128;
+
+#[derive(Clone, Debug)]
+struct SeqWindow {
+    highest: u8,
+    /// bit n set if the ID n behind `highest` was seen
+    seen: u64,
+    heard: Instant,
+}
+
+/// Frame IDs seen from every sender, so a captured frame can't be sent again
+/* A sender numbers its frames with one counter per destination and one
+for broadcasts, so each counter gets its own window. The counters wrap
+from 255 back to 1, and 0 only ever starts a node's broadcast counter: a
+frame with ID 0 means its sender restarted and drops all of its windows.
+A window silent for `maxage` is forgotten as well, in case we missed
+that frame. A replayed restart frame still clears the windows, past the
+duplicate filter nothing tells it from a real restart. Windows restored
+after our own restart take every ID behind the highest as seen. */
+#[derive(Clone, Debug)]
+pub struct AntiReplayWindow {
+    maxage: Duration,
+    windows: HashMap<(NodeId, Option<NodeId>), SeqWindow>,
+    /// frames rejected as replayed
+    pub replayed: u64,
+}
+
+impl AntiReplayWindow {
+    pub fn new(maxage: Duration) -> Self {
+        AntiReplayWindow { maxage, windows: HashMap::new(), replayed: 0 }
+    }
+
+    /// true if the frame `seq` from `sender` to `dest`, None for a broadcast, is new, recording it
+    pub fn check_and_advance(&mut self, sender: NodeId, dest: Option<NodeId>, seq: u8) -> bool {
+        self.check_and_advance_at(sender, dest, seq, Instant::now())
+    }
+
+    pub(crate) fn check_and_advance_at(&mut self, sender: NodeId, dest: Option<NodeId>, seq: u8, now: Instant) -> bool {
+        if seq == 0 {
+            self.windows.retain(|(from, _), _| *from != sender);
+        }
+        let maxage = self.maxage;
+        let window = match self.windows.get_mut(&(sender, dest)) {
+            Some(window) if now.duration_since(window.heard) <= maxage => window,
+            _ => {
+                self.windows.insert((sender, dest), SeqWindow { highest: seq, seen: 1, heard: now });
+                return true;
+            },
+        };
+        let ahead = frameid_distance(window.highest, seq);
+        if ahead > 0 && ahead <= MAX_AHEAD {
+            window.seen = if ahead >= ANTI_REPLAY_WINDOW { 0 } else { window.seen << ahead };
+            window.seen |= 1;
+            window.highest = seq;
+            window.heard = now;
+            return true;
+        }
+        // the counter skips 0, so 255 IDs make a full turn
+        let behind = if ahead == 0 { 0 } else { u8::MAX - ahead };
+        if behind >= ANTI_REPLAY_WINDOW || window.seen & (1 << behind) != 0 {
+            self.replayed += 1;
+            return false;
+        }
+        window.seen |= 1 << behind;
+        window.heard = now;
+        true
+    }
+
+    /// Highest frame ID seen from every sender, per destination counter
+    pub fn highest(&self) -> Vec<(NodeId, Option<NodeId>, u8)> {
+        let mut highest: Vec<(NodeId, Option<NodeId>, u8)> = self.windows.iter()
+            .map(|((sender, dest), window)| (*sender, *dest, window.highest))
+            .collect();
+        highest.sort();
+        highest
+    }
+
+    /// Take back the highest IDs saved before a restart
+    pub fn restore(&mut self, highest: &[(NodeId, Option<NodeId>, u8)]) {
+        self.restore_at(highest, Instant::now())
+    }
+
+    pub(crate) fn restore_at(&mut self, highest: &[(NodeId, Option<NodeId>, u8)], now: Instant) {
+        for (sender, dest, seq) in highest {
+            self.windows.entry((*sender, *dest)).or_insert(SeqWindow { highest: *seq, seen: u64::MAX, heard: now });
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn anti_replay_window() {
+    let start = Instant::now();
+    let mut window = AntiReplayWindow::new(Duration::from_secs(200));
+    assert!(window.check_and_advance_at(2, Some(1), 250, start));
+    assert!(!window.check_and_advance_at(2, Some(1), 250, start));
+
+    // across the wrap from 255 to 1, out of order within the window once
+    assert!(window.check_and_advance_at(2, Some(1), 3, start));
+    assert!(window.check_and_advance_at(2, Some(1), 252, start));
+    assert!(!window.check_and_advance_at(2, Some(1), 252, start));
+    assert!(window.check_and_advance_at(2, Some(1), 255, start));
+    assert!(!window.check_and_advance_at(2, Some(1), 3, start));
+
+    // a frame too far behind is rejected even if it was never seen
+    for seq in 4..=80 {
+        assert!(window.check_and_advance_at(2, Some(1), seq, start));
+    }
+    assert!(!window.check_and_advance_at(2, Some(1), 254, start));
+    assert!(!window.check_and_advance_at(2, Some(1), 20, start));
+    assert_eq!(window.replayed, 5);
+
+    // every counter of a sender has its own window
+    assert!(window.check_and_advance_at(2, None, 10, start));
+    assert!(window.check_and_advance_at(2, Some(5), 10, start));
+    assert!(window.check_and_advance_at(3, Some(1), 10, start));
+
+    // a restart starts the broadcast counter at 0 and clears the sender's windows
+    assert!(window.check_and_advance_at(2, None, 0, start));
+    assert!(window.check_and_advance_at(2, None, 1, start));
+    assert!(window.check_and_advance_at(2, Some(1), 10, start));
+    assert!(!window.check_and_advance_at(3, Some(1), 10, start));
+
+    // a window silent for too long starts over
+    let later = start + Duration::from_secs(201);
+    assert!(window.check_and_advance_at(3, Some(1), 10, later));
+    assert!(!window.check_and_advance_at(3, Some(1), 10, later));
+
+    // after our restart, frames from before it are still rejected
+    let mut restarted = AntiReplayWindow::new(Duration::from_secs(200));
+    restarted.restore_at(&window.highest(), later);
+    assert!(!restarted.check_and_advance_at(3, Some(1), 9, later));
+    assert!(restarted.check_and_advance_at(3, Some(1), 11, later));
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use crate::hardware::Radio;
+use crate::node::MeshNode;
+use crate::stack::frame::NodeId;
+
+/// Data port probes are sent to and answered on
+pub const BANDWIDTH_PROBE_PORT: u8 = 254;
+
+/// How long to wait for the last answers once the probe stops sending
+pub const BANDWIDTH_PROBE_GRACE: Duration = Duration::from_secs(10);
+
+const PROBE: u8 = 0;
+const ECHO: u8 = 1;
+/// kind and sequence number in front of every probe
+const PROBE_HEADER: usize = 3;
+
+/// What a bandwidth probe measured
+#[derive(Clone, Debug, PartialEq)]
+pub struct BandwidthReport {
+    pub destination: NodeId,
+    /// probes sent
+    pub sent: u64,
+    /// probes the destination answered
+    pub delivered: u64,
+    /// payload bits delivered per second of sending
+    pub throughput_bps: f64,
+    /// share of the probes that were answered
+    pub packet_delivery_ratio: f64,
+    /// mean time from sending a probe to its answer
+    pub average_latency_ms: u64,
+}
+
+/// The answer to a probe, None for anything else sent to the probe port
+pub fn probe_echo(payload: &[u8]) -> Option<Vec<u8>> {
+    if payload.len() < PROBE_HEADER || payload[0] != PROBE {
+        return None;
+    }
+    Some(vec![ECHO, payload[1], payload[2]])
+}
+
+/// Measures the throughput of the mesh to one node
+/* Probes are data sent to `BANDWIDTH_PROBE_PORT` as fast as the radio
+takes them, each one numbered. A node listening on the port answers
+every probe with its number, so delivery and round trip are counted
+per probe. Answers are small and only the probes count towards the
+throughput. */
+#[derive(Clone, Debug)]
+pub struct BandwidthProbe {
+    destination: NodeId,
+    payload_size: usize,
+    nextseq: u16,
+    /// probes not answered yet, by sequence number
+    pending: HashMap<u16, Instant>,
+    latency: Duration,
+    pub sent: u64,
+    pub delivered: u64,
+}
+
+impl BandwidthProbe {
+    /// probes of `payload_size` bytes to `destination`
+    pub fn new(destination: NodeId, payload_size: usize) -> Self {
+        BandwidthProbe {
+            destination,
+            payload_size: payload_size.max(PROBE_HEADER),
+            nextseq: 0,
+            pending: HashMap::new(),
+            latency: Duration::from_secs(0),
+            sent: 0,
+            delivered: 0,
+        }
+    }
+
+    /// Measure the throughput to a node for `duration`, blocking until done
+    /* Keeps the node running while probing, so it must not be called
+    from a handler. The destination must be listening on
+    `BANDWIDTH_PROBE_PORT` to answer. */
+    pub fn run<R: Radio>(mesh: &mut MeshNode<R>, destination: NodeId, duration: Duration, payload_size: usize) -> io::Result<BandwidthReport> {
+        if payload_size < PROBE_HEADER {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("probes carry at least {} bytes", PROBE_HEADER)));
+        }
+        mesh.start();
+        let echoes = mesh.recv_on_port(BANDWIDTH_PROBE_PORT);
+        let mut probe = BandwidthProbe::new(destination, payload_size);
+        let started = Instant::now();
+        let deadline = started + duration;
+        loop {
+            mesh.poll();
+            let now = Instant::now();
+            while let Ok((sender, payload)) = echoes.try_recv() {
+                if sender == destination {
+                    probe.echo_at(&payload, now);
+                }
+            }
+            if now < deadline {
+                // the next probe once the radio took the last one
+                if mesh.tx_backlog() == 0 {
+                    let payload = probe.probe_at(now);
+                    mesh.send_data(destination, BANDWIDTH_PROBE_PORT, payload)?;
+                }
+            } else if probe.pending() == 0 || now >= deadline + BANDWIDTH_PROBE_GRACE {
+                break;
+            }
+        }
+        if probe.delivered == 0 {
+            return Err(Error::new(ErrorKind::TimedOut, format!("no answer from node {}", destination)));
+        }
+        Ok(probe.report(duration))
+    }
+
+    /// The next probe to send
+    pub fn probe(&mut self) -> Vec<u8> {
+        self.probe_at(Instant::now())
+    }
+
+    pub(crate) fn probe_at(&mut self, now: Instant) -> Vec<u8> {
+        let seq = self.nextseq;
+        self.nextseq = self.nextseq.wrapping_add(1);
+        self.pending.insert(seq, now);
+        self.sent += 1;
+        let mut payload = vec![PROBE];
+        payload.extend_from_slice(&seq.to_be_bytes());
+        payload.resize(self.payload_size, 0);
+        payload
+    }
+
+    /// An answer came back, false if it was not for one of our probes
+    pub fn echo(&mut self, payload: &[u8]) -> bool {
+        self.echo_at(payload, Instant::now())
+    }
+
+    pub(crate) fn echo_at(&mut self, payload: &[u8], now: Instant) -> bool {
+        if payload.len() < PROBE_HEADER || payload[0] != ECHO {
+            return false;
+        }
+        match self.pending.remove(&u16::from_be_bytes([payload[1], payload[2]])) {
+            None => false,
+            Some(sent) => {
+                self.delivered += 1;
+                self.latency += now.duration_since(sent);
+                true
+            }
+        }
+    }
+
+    /// Probes still waiting for their answer
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// What was measured, over `elapsed` of sending
+    pub fn report(&self, elapsed: Duration) -> BandwidthReport {
+        let secs = elapsed.as_secs_f64();
+        let bits = (self.delivered * self.payload_size as u64 * 8) as f64;
+        BandwidthReport {
+            destination: self.destination,
+            sent: self.sent,
+            delivered: self.delivered,
+            throughput_bps: if secs > 0.0 { bits / secs } else { 0.0 },
+            packet_delivery_ratio: if self.sent > 0 { self.delivered as f64 / self.sent as f64 } else { 0.0 },
+            average_latency_ms: (self.latency.as_millis() as u64).checked_div(self.delivered).unwrap_or(0),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn bandwidth_probe_report() {
+    // a link taking one 100 byte probe a second, losing every fifth, answering in 800ms
+    let start = Instant::now();
+    let mut probe = BandwidthProbe::new(7, 100);
+    let mut answers = Vec::new();
+    for second in 0..20u64 {
+        let now = start + Duration::from_secs(second);
+        let payload = probe.probe_at(now);
+        assert_eq!(payload.len(), 100);
+        if second % 5 != 4 {
+            answers.push((probe_echo(&payload).unwrap(), now + Duration::from_millis(800)));
+        }
+    }
+    assert_eq!(probe.pending(), 20);
+    for (echo, at) in answers.iter() {
+        assert!(probe.echo_at(echo, *at));
+    }
+    // answers are only counted once, and only for our probes
+    assert!(!probe.echo_at(&answers[0].0, start));
+    assert!(!probe.echo_at(&[ECHO, 0xff, 0xff], start));
+    assert_eq!(probe.pending(), 4);
+
+    let report = probe.report(Duration::from_secs(20));
+    assert_eq!((report.sent, report.delivered), (20, 16));
+    assert_eq!(report.packet_delivery_ratio, 0.8);
+    assert_eq!(report.throughput_bps, 16.0 * 800.0 / 20.0);
+    assert_eq!(report.average_latency_ms, 800);
+
+    // echoes and short payloads are not answered, so two probing nodes do not loop
+    assert_eq!(probe_echo(&answers[0].0), None);
+    assert_eq!(probe_echo(&[PROBE, 1]), None);
+    assert_eq!(probe_echo(&[PROBE, 1, 2, 3]), Some(vec![ECHO, 1, 2]));
+}
@@ -0,0 +1,126 @@
+//! Bytes as base64 strings in human readable formats such as JSON, and as
+//! plain bytes in binary ones such as MessagePack
+use std::convert::TryFrom;
+use std::fmt;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{Error, SeqAccess, Visitor};
+
+pub fn serialize<T: AsRef<[u8]>, S: Serializer>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&STANDARD.encode(bytes.as_ref()))
+    } else {
+        serializer.serialize_bytes(bytes.as_ref())
+    }
+}
+
+pub fn deserialize<'de, T: TryFrom<Vec<u8>>, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    let bytes = if deserializer.is_human_readable() {
+        let text = String::deserialize(deserializer)?;
+        STANDARD.decode(text).map_err(D::Error::custom)?
+    } else {
+        deserializer.deserialize_byte_buf(BytesVisitor)?
+    };
+    let len = bytes.len();
+    T::try_from(bytes).map_err(|_| D::Error::custom(format!("unexpected length of {} bytes", len)))
+}
+
+/// takes bytes however a binary format hands them over
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bytes")
+    }
+
+    fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(Vec::from(bytes))
+    }
+
+    fn visit_byte_buf<E: Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::new();
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+/// bytes inside another value, to serialize
+struct BytesRef<'a>(&'a [u8]);
+
+impl<'a> Serialize for BytesRef<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(&self.0, serializer)
+    }
+}
+
+/// bytes inside another value, deserialized
+struct ByteBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize(deserializer).map(ByteBuf)
+    }
+}
+
+/// Type-length-value entries, the values as bytes
+pub mod tlv {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(tlv: &[(u8, Vec<u8>)], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(tlv.iter().map(|(tlvtype, value)| (tlvtype, BytesRef(value))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(u8, Vec<u8>)>, D::Error> {
+        let tlv = Vec::<(u8, ByteBuf)>::deserialize(deserializer)?;
+        Ok(tlv.into_iter().map(|(tlvtype, value)| (tlvtype, value.0)).collect())
+    }
+}
+
+/// An IP packet, as its bytes
+pub mod ip_packet {
+    use crate::stack::ipv6::IpPacket;
+    use super::*;
+
+    pub fn serialize<S: Serializer>(packet: &IpPacket, serializer: S) -> Result<S::Ok, S::Error> {
+        super::serialize(&packet.as_ref(), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpPacket, D::Error> {
+        let bytes: Vec<u8> = super::deserialize(deserializer)?;
+        IpPacket::new(bytes).map_err(|_| D::Error::custom("invalid IP packet"))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn base64_json() {
+    use crate::stack::{ConfigMessage, FileChunkMessage, Frame, ToFromFrame};
+
+    let chunk = FileChunkMessage { header: None, transferid: 3, seq: 1, data: vec![0, 1, 2, 0xff] };
+    let json = serde_json::to_string(&chunk).unwrap();
+    assert_eq!(json, r#"{"header":null,"transferid":3,"seq":1,"data":"AAEC/w=="}"#);
+    let restored: FileChunkMessage = serde_json::from_str(&json).unwrap();
+    assert_eq!(restored.data, chunk.data);
+
+    // received messages keep their header
+    let mut frame = Frame::from_bytes(&chunk.to_frame(4, 2, vec![6]).unwrap().to_bytes()).unwrap();
+    let received = FileChunkMessage::from_frame(&mut frame).unwrap();
+    let restored: FileChunkMessage = serde_json::from_str(&serde_json::to_string(&received).unwrap()).unwrap();
+    assert_eq!(restored.header.unwrap().route(), vec![6]);
+
+    // fixed size fields take nothing else
+    let config = ConfigMessage { header: None, key: String::from("maxhops"), value: String::from("3"), requester_id: 1, signature: [9; 32] };
+    let json = serde_json::to_string(&config).unwrap();
+    assert!(serde_json::from_str::<ConfigMessage>(&json).is_ok());
+    let short = json.replace(&base64::engine::general_purpose::STANDARD.encode([9u8; 32]), "CQkJ");
+    assert!(serde_json::from_str::<ConfigMessage>(&short).is_err());
+}
@@ -0,0 +1,149 @@
+use std::collections::HashSet;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use rand::{thread_rng, Rng};
+use crate::stack::frame::NodeId;
+use crate::stack::util::crc32;
+
+/// Another node found sending with our ID
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollisionEvent {
+    pub nodeid: NodeId,
+    /// its instance token, and ours
+    pub theirs: u32,
+    pub ours: u32,
+}
+
+/// Node ID derived from a radio's hardware EUI, a different one for every attempt
+/* The first attempt is the lowest byte of the EUI, as `usehardwareeui`
+takes it, later ones a checksum of the EUI and the attempt. 0 is never
+handed out. */
+pub fn derive_node_id(eui: &[u8; 8], attempt: u8) -> NodeId {
+    if attempt == 0 && eui[7] != 0 {
+        return eui[7];
+    }
+    let mut data = eui.to_vec();
+    data.push(attempt);
+    let id = crc32(&data) as u8;
+    if id == 0 { 1 } else { id }
+}
+
+/// Tells us apart from another node using our ID
+/* Every node picks a random instance token when it starts and sends it
+in its heartbeats. We never hear our own frames first hand, so a
+heartbeat with our ID and another token comes from a second node. Each
+token is reported once. Of the two nodes, the one with the larger token
+is the one to give way, comparing them as hex strings of the same length
+that is the larger number. */
+#[derive(Debug)]
+pub struct CollisionDetector {
+    token: u32,
+    reported: HashSet<u32>,
+    subscribers: Vec<Sender<CollisionEvent>>,
+}
+
+impl CollisionDetector {
+    /// with a random token
+    pub fn new() -> Self {
+        CollisionDetector::with_token(thread_rng().gen())
+    }
+
+    pub fn with_token(token: u32) -> Self {
+        CollisionDetector { token, reported: HashSet::new(), subscribers: Vec::new() }
+    }
+
+    /// our instance token, to send in heartbeats
+    pub fn token(&self) -> u32 {
+        self.token
+    }
+
+    /// Receive an event whenever another node turns out to use our ID
+    pub fn subscribe(&mut self) -> Receiver<CollisionEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// A heartbeat with our ID `nodeid` was heard with `token`, Some the first time it shows another node
+    pub fn own_id_heard(&mut self, nodeid: NodeId, token: u32) -> Option<CollisionEvent> {
+        if token == self.token || !self.reported.insert(token) {
+            return None;
+        }
+        let event = CollisionEvent { nodeid, theirs: token, ours: self.token };
+        // subscribers that hung up are dropped
+        self.subscribers.retain(|subscriber| subscriber.send(event).is_ok());
+        Some(event)
+    }
+
+    /// true if we are the one to take another ID
+    pub fn yields(&self, event: &CollisionEvent) -> bool {
+        event.ours > event.theirs
+    }
+
+    /// Another ID was taken, what was heard under the old one no longer matters
+    pub fn reset(&mut self) {
+        self.reported.clear();
+    }
+}
+
+impl Default for CollisionDetector {
+    fn default() -> Self {
+        CollisionDetector::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn node_id_collision() {
+    use std::time::Duration;
+    use crate::hardware::{LinkQuality, Radio, RxPacket};
+    use crate::hardware::loopback::PairedLoopback;
+    use crate::stack::{Frame, NeighborTable};
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::HeartbeatMessage;
+    use crate::stack::qos::{TxPriority, TxQueueSender};
+
+    // two nodes with ID 5 from cloned settings, and node 8 hearing both
+    let radios: Vec<(Receiver<RxPacket>, TxQueueSender)> = PairedLoopback::new(3)
+        .into_radios().iter().map(|radio| radio.run()).collect();
+    let mut nodes = [CollisionDetector::with_token(0x1000), CollisionDetector::with_token(0x2000)];
+    let mut neighbors = NeighborTable::new(Duration::from_secs(60));
+    let heartbeat = |token: u32| {
+        let mut msg = HeartbeatMessage::new(0);
+        msg.token = Some(token);
+        msg.to_frame(1, 5, Vec::new()).unwrap().to_bytes()
+    };
+
+    let timeout = Duration::from_secs(5);
+    for round in 0..2 {
+        for (i, node) in nodes.iter().enumerate() {
+            radios[i].1.send(heartbeat(node.token()), TxPriority::Normal).unwrap();
+        }
+        // each of the pair hears the other
+        for (i, node) in nodes.iter_mut().enumerate() {
+            let mut frame = Frame::from_bytes(&radios[i].0.recv_timeout(timeout).unwrap().data).unwrap();
+            let token = HeartbeatMessage::from_frame(&mut frame).unwrap().token.unwrap();
+            let event = node.own_id_heard(frame.sender(), token);
+            // reported only the first time
+            assert_eq!(event.is_some(), round == 0);
+            if let Some(event) = event {
+                assert_eq!((event.nodeid, event.ours), (5, node.token()));
+                // the larger token gives way
+                assert_eq!(node.yields(&event), i == 1);
+            }
+        }
+        // node 8 hears ID 5 with two tokens taking turns
+        for _ in 0..2 {
+            let mut frame = Frame::from_bytes(&radios[2].0.recv_timeout(timeout).unwrap().data).unwrap();
+            neighbors.heard(frame.sender(), frame.frameid(), LinkQuality::default());
+            let token = HeartbeatMessage::from_frame(&mut frame).unwrap().token.unwrap();
+            neighbors.token_heard(frame.sender(), token);
+        }
+        assert_eq!(neighbors.get(5).unwrap().collision, round == 1);
+    }
+
+    // the first derived ID is the one `usehardwareeui` takes
+    let eui = [0x00, 0x04, 0xA3, 0x0B, 0x00, 0x1A, 0x2B, 0x05];
+    assert_eq!(derive_node_id(&eui, 0), 5);
+    let ids: HashSet<NodeId> = (1..=20).map(|attempt| derive_node_id(&eui, attempt)).collect();
+    assert!(ids.len() > 15 && !ids.contains(&0));
+}
@@ -0,0 +1,88 @@
+use enumn::N;
+use lz4_flex::block::{compress_prepend_size, decompress};
+use std::io;
+use std::io::{Error, ErrorKind};
+
+/// Largest payload a compressed one may claim to expand to
+/* The size comes from the sender, a bogus one must not make us allocate
+gigabytes. */
+pub const MAX_DECOMPRESSED_LEN: usize = 65535;
+
+/// How a payload was compressed, sent in the byte ahead of it
+#[derive(Clone, Copy, PartialEq, Debug, N)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub enum CompressionType {
+    None = 0,
+    /// LZ4 block with the uncompressed size ahead of it, little endian
+    Lz4 = 1,
+}
+
+impl CompressionType {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::Lz4 => 1,
+        }
+    }
+
+    pub fn from_u8(byte: u8) -> io::Result<Self> {
+        CompressionType::n(byte)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("unknown compression type {}", byte)))
+    }
+}
+
+/// Compresses payloads when that makes them smaller
+/* Short payloads such as sensor readings rarely get smaller with LZ4,
+they go as they are. */
+pub struct MeshCompressor;
+
+impl MeshCompressor {
+    /// The smallest form of `data`, and how it was compressed
+    pub fn compress(data: &[u8]) -> (CompressionType, Vec<u8>) {
+        let compressed = compress_prepend_size(data);
+        if compressed.len() < data.len() {
+            (CompressionType::Lz4, compressed)
+        } else {
+            (CompressionType::None, data.to_vec())
+        }
+    }
+
+    /// Undo `compress`
+    pub fn decompress(ctype: CompressionType, data: &[u8]) -> io::Result<Vec<u8>> {
+        match ctype {
+            CompressionType::None => Ok(data.to_vec()),
+            CompressionType::Lz4 => {
+                if data.len() < 4 {
+                    return Err(Error::new(ErrorKind::InvalidData, "compressed payload is too short"));
+                }
+                let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                if size > MAX_DECOMPRESSED_LEN {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("compressed payload claims {} bytes", size)));
+                }
+                decompress(&data[4..], size).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn compressor_picks_smaller() {
+    // a sensor reading stays as it is
+    let reading = [0x01, 0x2c, 0x00, 0x5a];
+    assert_eq!(MeshCompressor::compress(&reading), (CompressionType::None, reading.to_vec()));
+
+    let log = b"temp=21.5 temp=21.5 temp=21.6 temp=21.5 temp=21.5 temp=21.4 temp=21.5".to_vec();
+    let (ctype, compressed) = MeshCompressor::compress(&log);
+    assert_eq!(ctype, CompressionType::Lz4);
+    assert!(compressed.len() < log.len());
+    assert_eq!(MeshCompressor::decompress(ctype, &compressed).unwrap(), log);
+    assert_eq!(MeshCompressor::decompress(CompressionType::None, &reading).unwrap(), reading.to_vec());
+
+    assert_eq!(CompressionType::from_u8(ctype.to_u8()).unwrap(), CompressionType::Lz4);
+    assert!(CompressionType::from_u8(7).is_err());
+    // truncated or oversized claims are errors, not panics
+    assert!(MeshCompressor::decompress(CompressionType::Lz4, &compressed[..compressed.len() - 3]).is_err());
+    assert!(MeshCompressor::decompress(CompressionType::Lz4, &[0xff, 0xff, 0xff, 0x7f, 0]).is_err());
+    assert!(MeshCompressor::decompress(CompressionType::Lz4, &[1]).is_err());
+}
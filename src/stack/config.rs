@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::io;
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use poly1305::Poly1305;
+use poly1305::universal_hash::KeyInit;
+use crate::hardware::lostik::mkerror;
+use crate::stack::frame::NodeId;
+use crate::stack::message::ConfigMessage;
+use crate::stack::remote::ReplayWindow;
+
+/// Shared key config changes are signed with
+pub type ConfigKey = [u8; 32];
+
+/// Read the config key from its 64 hex digits
+pub fn parse_config_key(hex: &str) -> io::Result<ConfigKey> {
+    let bytes = hex::decode(hex.trim()).map_err(|_| mkerror("configkey must be hex"))?;
+    if bytes.len() != 32 {
+        return Err(mkerror("configkey must be 32 bytes, 64 hex digits"));
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+/// Nonce at the start of a signature
+pub fn config_nonce(signature: &[u8; 32]) -> u64 {
+    let mut nonce = [0u8; 8];
+    nonce.copy_from_slice(&signature[..8]);
+    u64::from_be_bytes(nonce)
+}
+
+/// Poly1305 tag of a change, keyed for this nonce only
+/* Poly1305 keys must never be used twice, so like ChaCha20-Poly1305 the
+key for each message is the first ChaCha20 block under the shared key and
+the nonce. The key is prefixed with its length so no key and value can pass
+for another split of the same bytes. */
+fn config_tag(secret: &ConfigKey, nonce: u64, key: &str, value: &str, requester: NodeId) -> [u8; 16] {
+    let mut iv = [0u8; 12];
+    iv[..8].copy_from_slice(&nonce.to_be_bytes());
+    let mut onetime = [0u8; 32];
+    ChaCha20::new(secret.into(), &iv.into()).apply_keystream(&mut onetime);
+
+    let mut data = vec![key.len() as u8];
+    data.extend_from_slice(key.as_bytes());
+    data.extend_from_slice(value.as_bytes());
+    data.push(requester);
+    Poly1305::new((&onetime).into()).compute_unpadded(&data).into()
+}
+
+/// Signature of a change: the nonce, then the tag
+pub fn sign_config(secret: &ConfigKey, nonce: u64, key: &str, value: &str, requester: NodeId) -> [u8; 32] {
+    let mut signature = [0u8; 32];
+    signature[..8].copy_from_slice(&nonce.to_be_bytes());
+    signature[16..].copy_from_slice(&config_tag(secret, nonce, key, value, requester));
+    signature
+}
+
+/// Decides which config changes are applied
+#[derive(Clone, Debug)]
+pub struct ConfigGuard {
+    secret: Option<ConfigKey>,
+    mutable: Vec<String>,
+    replay: HashMap<NodeId, ReplayWindow>,
+}
+
+impl ConfigGuard {
+    /// without a key every change is rejected
+    pub fn new(secret: Option<ConfigKey>, mutable: Vec<String>) -> Self {
+        ConfigGuard { secret, mutable, replay: HashMap::new() }
+    }
+
+    /// Ok if the change from `sender` may be applied, or why not
+    pub fn check(&mut self, sender: NodeId, msg: &ConfigMessage) -> Result<(), String> {
+        let secret = self.secret.as_ref().ok_or(String::from("no configkey is set"))?;
+        if msg.requester_id != sender {
+            return Err(format!("requested by {} but sent by {}", msg.requester_id, sender));
+        }
+        if !self.mutable.contains(&msg.key) {
+            return Err(format!("{} may not be changed over the air", msg.key));
+        }
+        // comparing the whole tag before telling the result keeps its timing the same
+        let nonce = config_nonce(&msg.signature);
+        let tag = config_tag(secret, nonce, &msg.key, &msg.value, msg.requester_id);
+        let diff = tag.iter().zip(&msg.signature[16..]).fold(0, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 || msg.signature[8..16].iter().any(|b| *b != 0) {
+            return Err(String::from("bad signature"));
+        }
+        // only signed nonces count, or anyone could burn them
+        if !self.replay.entry(sender).or_insert_with(ReplayWindow::default).check(nonce) {
+            return Err(format!("nonce {} was already used", nonce));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn config_signature() {
+    let secret = parse_config_key(&"2b".repeat(32)).unwrap();
+    assert!(parse_config_key("2b2b").is_err());
+    assert!(parse_config_key(&"zz".repeat(32)).is_err());
+
+    let change = |nonce: u64, key: &str, value: &str, signer: &ConfigKey| ConfigMessage {
+        header: None,
+        key: String::from(key),
+        value: String::from(value),
+        requester_id: 1,
+        signature: sign_config(signer, nonce, key, value, 1),
+    };
+    let mut guard = ConfigGuard::new(Some(secret), vec![String::from("maxhops"), String::from("hellointerval")]);
+    let msg = change(100, "maxhops", "3", &secret);
+    assert_eq!(config_nonce(&msg.signature), 100);
+    assert_eq!(guard.check(1, &msg), Ok(()));
+    // replays and other senders are rejected
+    assert_eq!(guard.check(1, &msg), Err(String::from("nonce 100 was already used")));
+    assert!(guard.check(2, &change(101, "maxhops", "3", &secret)).is_err());
+
+    // a changed value or another key breaks the signature
+    let mut tampered = change(102, "maxhops", "3", &secret);
+    tampered.value = String::from("9");
+    assert_eq!(guard.check(1, &tampered), Err(String::from("bad signature")));
+    assert!(guard.check(1, &change(103, "maxhops", "3", &[7u8; 32])).is_err());
+    // the same change under another nonce has another tag
+    assert_ne!(change(104, "maxhops", "3", &secret).signature[16..], msg.signature[16..]);
+
+    // only listed settings
+    let err = guard.check(1, &change(105, "stateinterval", "0", &secret)).unwrap_err();
+    assert_eq!(err, "stateinterval may not be changed over the air");
+    assert!(guard.check(1, &change(106, "hellointerval", "30000", &secret)).is_ok());
+
+    // nothing goes without a key
+    let mut guard = ConfigGuard::new(None, vec![String::from("maxhops")]);
+    assert!(guard.check(1, &change(107, "maxhops", "3", &secret)).is_err());
+}
@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use crate::stack::Frame;
+use crate::stack::frame::NodeId;
+
+/// Identifies a frame by sender, destination, frame ID and message type
+/* Senders count frame IDs per destination, None for floods, so frames
+to different destinations may carry the same ID. */
+pub type DedupKey = (NodeId, Option<NodeId>, u8, u8);
+
+/// The key of a frame, floods end their route with their sender and have no destination
+pub(crate) fn dedup_key(frame: &mut Frame) -> DedupKey {
+    let sender = frame.sender();
+    let dest = frame.route().last().cloned().filter(|dest| *dest != sender);
+    (sender, dest, frame.frameid(), frame.msgtype().to_u8())
+}
+
+/// Most recent frames checked exactly, before the Bloom filter
+pub const DEDUP_RECENT: usize = 32;
+
+/// How long a seen frame is remembered unless set with `with_ttl`
+pub const DEDUP_TTL: Duration = Duration::from_secs(30);
+
+/// Fixed size set of keys that may report a key it never saw, never the reverse
+#[derive(Clone, Debug)]
+struct Bloom {
+    bits: Vec<u64>,
+    nbits: usize,
+    hashes: u32,
+    items: usize,
+}
+
+impl Bloom {
+    /// sized for `expected` keys at the given false positive rate
+    fn new(expected: usize, rate: f64) -> Self {
+        let expected = expected.max(1) as f64;
+        let rate = rate.max(1e-9).min(0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let nbits = (-expected * rate.ln() / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let hashes = ((nbits as f64 / expected) * ln2).round().max(1.0) as u32;
+        Bloom { bits: vec![0; nbits / 64 + 1], nbits, hashes, items: 0 }
+    }
+
+    /// bit positions of a key, by double hashing
+    fn positions(&self, key: &DedupKey) -> Vec<usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let first = hasher.finish();
+        0x9E37_79B9u32.hash(&mut hasher);
+        let second = hasher.finish() | 1;
+        (0..self.hashes as u64)
+            .map(|i| (first.wrapping_add(i.wrapping_mul(second)) % self.nbits as u64) as usize)
+            .collect()
+    }
+
+    fn contains(&self, key: &DedupKey) -> bool {
+        self.positions(key).iter().all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn insert(&mut self, key: &DedupKey) {
+        for bit in self.positions(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.items += 1;
+    }
+
+    fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+        self.items = 0;
+    }
+}
+
+/// Recently seen frames, kept in a Bloom filter
+/* Frames flooded through the mesh are heard from several neighbors, the
+cache lets us handle each one once. A Bloom filter takes a few bits per
+frame however dense the mesh is, at the price of false positives: now
+and then a new frame looks seen and is dropped as a duplicate. The
+sender's retransmission or the next broadcast makes up for it, a
+duplicate slipping through is never the result. The last
+`DEDUP_RECENT` frames are also checked exactly.
+
+Bloom filters can't forget single frames, so there are two: new frames
+go into the current one, and every half TTL, or once it holds the
+expected number of frames, the older one is cleared and takes its
+place. A frame is remembered for between half and the whole TTL, so a
+frame ID that wraps around and is reused is not mistaken for a
+duplicate. */
+#[derive(Clone, Debug)]
+pub struct DedupCache {
+    expected: usize,
+    ttl: Duration,
+    current: Bloom,
+    previous: Bloom,
+    /// when `current` started taking frames
+    rotated: Option<Instant>,
+    // most recently seen at the back, with when they were seen
+    recent: VecDeque<(DedupKey, Instant)>,
+    duplicates: u64,
+}
+
+impl DedupCache {
+    /// Remember about `expected_items` frames per half TTL, at most
+    /// `false_positive_rate` of new frames taken for duplicates
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let bloom = Bloom::new(expected_items, false_positive_rate);
+        DedupCache {
+            expected: expected_items.max(1),
+            ttl: DEDUP_TTL,
+            current: bloom.clone(),
+            previous: bloom,
+            rotated: None,
+            recent: VecDeque::with_capacity(DEDUP_RECENT),
+            duplicates: 0,
+        }
+    }
+
+    /// the same cache remembering frames for `ttl`
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Check if a frame was seen recently, recording it if not
+    pub fn is_duplicate(&mut self, sender: NodeId, dest: Option<NodeId>, frameid: u8, msgtype: u8) -> bool {
+        self.check_at((sender, dest, frameid, msgtype), Instant::now())
+    }
+
+    pub(crate) fn check_at(&mut self, key: DedupKey, now: Instant) -> bool {
+        self.expire(now);
+
+        let duplicate = self.recent.iter().any(|(k, _)| k == &key) || self.current.contains(&key) || self.previous.contains(&key);
+        if duplicate {
+            self.duplicates += 1;
+            return true;
+        }
+
+        if self.current.items >= self.expected {
+            self.rotate(now);
+        }
+        self.current.insert(&key);
+        if self.recent.len() >= DEDUP_RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((key, now));
+        false
+    }
+
+    /// start a new generation every half TTL
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        while self.recent.front().map_or(false, |(_, seen)| now.duration_since(*seen) >= ttl) {
+            self.recent.pop_front();
+        }
+        let rotated = *self.rotated.get_or_insert(now);
+        let elapsed = now.duration_since(rotated);
+        if elapsed >= self.ttl {
+            // nothing seen is young enough to keep
+            self.current.clear();
+            self.previous.clear();
+            self.recent.clear();
+            self.rotated = Some(now);
+        } else if elapsed >= self.ttl / 2 {
+            self.rotate(now);
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        std::mem::swap(&mut self.current, &mut self.previous);
+        self.current.clear();
+        self.rotated = Some(now);
+    }
+
+    /// Number of duplicate frames dropped so far
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// frames remembered in both generations
+    pub fn len(&self) -> usize {
+        self.current.items + self.previous.items
+    }
+
+    /// true if no frame is remembered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn dedup_keys() {
+    // the first copy of a frame is new, the others are duplicates
+    let now = Instant::now();
+    let mut cache = DedupCache::new(16, 0.01);
+    let handled = (0..3).filter(|_| !cache.check_at((1, None, 42, 1), now)).count();
+    assert_eq!((handled, cache.duplicates()), (1, 2));
+
+    // a different message type, sender or destination with the same frame ID is new
+    assert!(!cache.check_at((1, None, 42, 9), now));
+    assert!(!cache.check_at((2, None, 42, 1), now));
+    assert!(!cache.check_at((1, Some(3), 42, 1), now));
+    assert!(!cache.check_at((1, Some(4), 42, 1), now));
+    assert!(cache.check_at((1, Some(3), 42, 1), now));
+}
+
+#[test]
+fn dedup_expiry_and_rotation() {
+    let now = Instant::now();
+    let mut cache = DedupCache::new(64, 0.01).with_ttl(Duration::from_secs(10));
+    assert!(cache.is_empty());
+    assert!(!cache.check_at((1, None, 255, 1), now));
+
+    // still remembered in the older generation after half the TTL
+    assert!(cache.check_at((1, None, 255, 1), now + Duration::from_secs(6)));
+    // frame ID wrapped around and was reused after the TTL
+    assert!(!cache.check_at((1, None, 255, 1), now + Duration::from_secs(11)));
+    assert_eq!(cache.len(), 1);
+
+    // a full generation rotates, the frames before it are still known
+    let later = now + Duration::from_secs(12);
+    let handled = (0..128u8).filter(|frameid| !cache.check_at((2, None, *frameid, 1), later)).count();
+    assert!(handled >= 126, "{} of 128 frames handled", handled);
+    assert!(cache.len() <= 128);
+    assert!(cache.check_at((2, None, 64, 1), later));
+    assert!(cache.check_at((2, None, 127, 1), later));
+}
+
+#[test]
+fn dedup_false_positive_rate() {
+    // 200 nodes sending a thousand frames each half TTL
+    let now = Instant::now();
+    let mut cache = DedupCache::new(1000, 0.01);
+    let keys = (1..=200u8).flat_map(|sender| (0..5u8).map(move |frameid| (sender, None, frameid, 1)));
+    let handled = keys.filter(|key| !cache.check_at(*key, now)).count();
+    assert!(handled > 1000 * 97 / 100, "{} of 1000 frames handled", handled);
+    // new frames are rarely taken for duplicates
+    let falsepositives = (1..=200u8)
+        .flat_map(|sender| (100..150u8).map(move |frameid| (sender, None, frameid, 1)))
+        .filter(|key| cache.current.contains(key))
+        .count();
+    assert!(falsepositives < 10_000 * 3 / 100, "{} false positives", falsepositives);
+    // a few kilobytes instead of a map entry per frame
+    assert!(cache.current.bits.len() * 8 * 2 < 4096);
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use crate::stack::Frame;
+use crate::stack::dedup::{dedup_key, DedupKey};
+use crate::stack::frame::NodeId;
+use crate::stack::qos::TxPriority;
+
+/// Application data waiting for a route to its destination
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedPayload {
+    pub dest: NodeId,
+    pub port: u8,
+    pub payload: Vec<u8>,
+    /// whether the destination is asked to ack it
+    pub receipt: bool,
+    queued: Instant,
+}
+
+/// What became of data sent to another node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryEvent {
+    /// the destination acked the frame
+    Delivered { dest: NodeId, frameid: u8 },
+    /// no route was found in time, or the ack never came
+    Failed { dest: NodeId, frameid: Option<u8> },
+}
+
+/// A relay that did not pass a frame on, however often it was sent again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HopFailure {
+    pub origin: NodeId,
+    pub frameid: u8,
+    pub nexthop: NodeId,
+    pub dest: NodeId,
+}
+
+/// A frame handed to the next hop, until we hear it went on
+#[derive(Clone, Debug)]
+struct HopAttempt {
+    chunks: Vec<Vec<u8>>,
+    priority: TxPriority,
+    nexthop: NodeId,
+    dest: NodeId,
+    sent: Instant,
+    tries: u8,
+}
+
+/// Carries unicast frames hop by hop, from the route lookup to the receipt
+/* Nodes don't ack each hop. A frame counts as passed on once we overhear
+the next hop send it on with itself taken off the route, or for the last
+hop once the destination's ack comes back through us. Until then it is
+sent again every `hoptimeout`, and after `hopretries` the hop is given up
+on, which the origin answers with a new route and a relay with a route
+error. A frame for a neighbor that does not ask for an ack can't be
+confirmed and is sent once. */
+#[derive(Debug)]
+pub struct DeliveryTracker {
+    hoptimeout: Duration,
+    hopretries: u8,
+    routewait: Duration,
+    queued: Vec<QueuedPayload>,
+    inflight: HashMap<DedupKey, HopAttempt>,
+    subscribers: Vec<Sender<DeliveryEvent>>,
+    /// frames sent again and hops given up on so far
+    pub retransmitted: u64,
+    pub failed: u64,
+}
+
+impl DeliveryTracker {
+    pub fn new(hoptimeout: Duration, hopretries: u8, routewait: Duration) -> Self {
+        DeliveryTracker {
+            hoptimeout,
+            hopretries,
+            routewait,
+            queued: Vec::new(),
+            inflight: HashMap::new(),
+            subscribers: Vec::new(),
+            retransmitted: 0,
+            failed: 0,
+        }
+    }
+
+    /// Receive an event whenever data we sent is delivered or fails
+    pub fn subscribe(&mut self) -> Receiver<DeliveryEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn publish(&mut self, event: DeliveryEvent) {
+        // subscribers that hung up are dropped
+        self.subscribers.retain(|subscriber| subscriber.send(event).is_ok());
+    }
+
+    /// Keep data for a node we have no route to yet
+    pub fn queue(&mut self, dest: NodeId, port: u8, payload: Vec<u8>, receipt: bool) {
+        self.queue_at(dest, port, payload, receipt, Instant::now())
+    }
+
+    pub(crate) fn queue_at(&mut self, dest: NodeId, port: u8, payload: Vec<u8>, receipt: bool, now: Instant) {
+        self.queued.push(QueuedPayload { dest, port, payload, receipt, queued: now });
+    }
+
+    /// Destinations data is waiting for, each once
+    pub fn waiting(&self) -> Vec<NodeId> {
+        let mut dests: Vec<NodeId> = self.queued.iter().map(|queued| queued.dest).collect();
+        dests.sort();
+        dests.dedup();
+        dests
+    }
+
+    /// Take the data for a node a route was found to, in the order it was queued
+    pub fn take(&mut self, dest: NodeId) -> Vec<QueuedPayload> {
+        let (taken, rest) = self.queued.drain(..).partition(|queued| queued.dest == dest);
+        self.queued = rest;
+        taken
+    }
+
+    /// Data that waited longer than `routewait` for a route
+    pub fn expire_queued(&mut self) -> Vec<QueuedPayload> {
+        self.expire_queued_at(Instant::now())
+    }
+
+    pub(crate) fn expire_queued_at(&mut self, now: Instant) -> Vec<QueuedPayload> {
+        let routewait = self.routewait;
+        let (expired, rest) = self.queued.drain(..)
+            .partition(|queued| now.duration_since(queued.queued) >= routewait);
+        self.queued = rest;
+        expired
+    }
+
+    /// A frame went out to `nexthop` as these chunks, watch for it to go on
+    pub fn sent(&mut self, frame: &mut Frame, nexthop: NodeId, chunks: Vec<Vec<u8>>, priority: TxPriority) {
+        self.sent_at(frame, nexthop, chunks, priority, Instant::now())
+    }
+
+    pub(crate) fn sent_at(&mut self, frame: &mut Frame, nexthop: NodeId, chunks: Vec<Vec<u8>>, priority: TxPriority, now: Instant) {
+        let dest = match frame.route().last() {
+            None => return,
+            Some(dest) => *dest,
+        };
+        if dest == nexthop && !frame.ack_requested() {
+            return;
+        }
+        let key = dedup_key(frame);
+        self.inflight.insert(key, HopAttempt { chunks, priority, nexthop, dest, sent: now, tries: 0 });
+    }
+
+    /// Another copy of a frame was heard, true if it shows the next hop passed ours on
+    pub fn overheard(&mut self, frame: &mut Frame) -> bool {
+        let key = dedup_key(frame);
+        let passed = match self.inflight.get(&key) {
+            None => return false,
+            // the hop sending it on takes itself off the route
+            Some(attempt) => !frame.route().contains(&attempt.nexthop),
+        };
+        if passed {
+            self.inflight.remove(&key);
+        }
+        passed
+    }
+
+    /// An ack from `dest` to `origin` passed, confirming the last hop of the frames it names
+    pub fn acked(&mut self, dest: NodeId, origin: NodeId, frameids: &[u8]) {
+        self.inflight.retain(|(sender, _, frameid, _), attempt| {
+            !(*sender == origin && attempt.dest == dest && frameids.contains(frameid))
+        });
+    }
+
+    /// Frames to send again, and the hops that ran out of retries
+    pub fn due(&mut self) -> (Vec<(Vec<u8>, TxPriority)>, Vec<HopFailure>) {
+        self.due_at(Instant::now())
+    }
+
+    pub(crate) fn due_at(&mut self, now: Instant) -> (Vec<(Vec<u8>, TxPriority)>, Vec<HopFailure>) {
+        let mut keys: Vec<(Instant, DedupKey)> = self.inflight.iter()
+            .filter(|(_, attempt)| now.duration_since(attempt.sent) >= self.hoptimeout)
+            .map(|(key, attempt)| (attempt.sent, *key))
+            .collect();
+        keys.sort();
+        let mut resend = Vec::new();
+        let mut failures = Vec::new();
+        for (_, key) in keys {
+            let attempt = self.inflight.get_mut(&key).expect("Due frames are in flight");
+            if attempt.tries < self.hopretries {
+                attempt.tries += 1;
+                attempt.sent = now;
+                resend.extend(attempt.chunks.iter().map(|chunk| (chunk.clone(), attempt.priority)));
+                self.retransmitted += 1;
+            } else {
+                let attempt = self.inflight.remove(&key).expect("Due frames are in flight");
+                failures.push(HopFailure { origin: key.0, frameid: key.2, nexthop: attempt.nexthop, dest: attempt.dest });
+                self.failed += 1;
+            }
+        }
+        (resend, failures)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn hop_delivery() {
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::DataMessage;
+
+    // data for a node with no route waits, in the order it came
+    let start = Instant::now();
+    let mut delivery = DeliveryTracker::new(Duration::from_secs(2), 2, Duration::from_secs(30));
+    delivery.queue_at(5, 7, b"hello".to_vec(), true, start);
+    delivery.queue_at(6, 7, b"lost".to_vec(), false, start);
+    delivery.queue_at(5, 8, b"again".to_vec(), false, start);
+    assert_eq!(delivery.waiting(), vec![5, 6]);
+    let queued = delivery.take(5);
+    assert_eq!(queued.iter().map(|queued| (queued.port, queued.receipt)).collect::<Vec<_>>(), vec![(7, true), (8, false)]);
+    assert_eq!(delivery.waiting(), vec![6]);
+
+    // data that never found a route is given up on
+    assert!(delivery.expire_queued_at(start + Duration::from_secs(29)).is_empty());
+    assert_eq!(delivery.expire_queued_at(start + Duration::from_secs(30)).len(), 1);
+
+    // a frame goes from 3 to 4 and is sent again until 4 is heard sending it on
+    let mut frame = DataMessage::new(5, 7, b"hello".to_vec()).unwrap().to_frame(10, 1, vec![4, 5]).unwrap();
+    let bytes = frame.to_bytes();
+    delivery.sent_at(&mut frame, 4, vec![bytes.clone()], TxPriority::Low, start);
+    let (resend, failures) = delivery.due_at(start + Duration::from_secs(2));
+    assert_eq!((resend, failures), (vec![(bytes, TxPriority::Low)], vec![]));
+    frame.route_shift();
+    assert!(delivery.overheard(&mut frame));
+    assert!(delivery.due_at(start + Duration::from_secs(10)).0.is_empty());
+
+    // with 5 gone, 4 gives up on the hop after its retries
+    let mut frame = DataMessage::new(5, 7, b"anyone?".to_vec()).unwrap().to_frame(11, 1, vec![5]).unwrap().with_ack_requested(true);
+    let bytes = frame.to_bytes();
+    delivery.sent_at(&mut frame, 5, vec![bytes], TxPriority::Low, start);
+    for retry in 1..=2 {
+        assert_eq!(delivery.due_at(start + Duration::from_secs(2 * retry)).0.len(), 1);
+    }
+    let (resend, failures) = delivery.due_at(start + Duration::from_secs(6));
+    assert!(resend.is_empty());
+    assert_eq!(failures, vec![HopFailure { origin: 1, frameid: 11, nexthop: 5, dest: 5 }]);
+    assert_eq!((delivery.retransmitted, delivery.failed), (3, 1));
+}
@@ -0,0 +1,556 @@
+use log::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use crate::stack::mtu::set_checksum;
+
+/// Port DNS queries are sent to
+pub const DNS_PORT: u16 = 53;
+
+/// File the host's resolvers are read from
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Time to wait for each upstream resolver
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+const DNS_HEADER_LEN: usize = 12;
+const UDP: u8 = 17;
+/// record types with a meaning of their own for the cache
+const TYPE_SOA: u16 = 6;
+const TYPE_OPT: u16 = 41;
+/// response codes
+const RCODE_NOERROR: u8 = 0;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// A node that sent a DNS query through the mesh, and the address it sent it to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DnsClient {
+    pub addr: Ipv4Addr,
+    pub port: u16,
+    pub server: Ipv4Addr,
+}
+
+/// Resolves queries for the proxy, the host's resolvers or a stand-in for tests
+pub trait DnsUpstream: Send {
+    /// the response to a query, as it came
+    fn resolve(&self, query: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// The resolvers the host uses, asked over UDP one after the other
+#[derive(Clone, Debug)]
+pub struct UdpUpstream {
+    servers: Vec<SocketAddr>,
+}
+
+impl UdpUpstream {
+    /// the `nameserver` entries of a resolv.conf
+    pub fn from_resolv_conf(path: &Path) -> io::Result<Self> {
+        let servers: Vec<SocketAddr> = fs::read_to_string(path)?
+            .lines()
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                match (words.next(), words.next()) {
+                    (Some("nameserver"), Some(addr)) => addr.parse::<IpAddr>().ok(),
+                    _ => None,
+                }
+            })
+            .map(|addr| SocketAddr::new(addr, DNS_PORT))
+            .collect();
+        if servers.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no nameserver in {}", path.display())));
+        }
+        Ok(UdpUpstream { servers })
+    }
+}
+
+impl DnsUpstream for UdpUpstream {
+    fn resolve(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut last = Error::new(ErrorKind::NotFound, "no upstream resolver");
+        for server in self.servers.iter() {
+            let bind = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let socket = UdpSocket::bind(bind)?;
+            socket.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+            socket.send_to(query, server)?;
+            let mut buffer = [0u8; 4096];
+            // answers to other queries, or from elsewhere, are not ours
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    Ok((len, from)) if from == *server && len >= 2 && buffer[..2] == query[..2] => return Ok(buffer[..len].to_vec()),
+                    Ok(_) => continue,
+                    Err(e) => { last = e; break; },
+                }
+            }
+            debug!("DNS server {} did not answer: {}", server, last);
+        }
+        Err(last)
+    }
+}
+
+/// A resource record of a DNS message, by where it is
+#[derive(Clone, Copy, Debug)]
+struct Record {
+    /// 0 for the answers, 1 for the authority and 2 for the additional records
+    section: usize,
+    start: usize,
+    end: usize,
+    rtype: u16,
+}
+
+fn be16(msg: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([msg[at], msg[at + 1]])
+}
+
+fn be32(msg: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([msg[at], msg[at + 1], msg[at + 2], msg[at + 3]])
+}
+
+/// Offset after a name starting at `at`
+fn skip_name(msg: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(at)? as usize;
+        match len & 0xc0 {
+            0 if len == 0 => return Some(at + 1),
+            0 => at += 1 + len,
+            // a pointer ends the name
+            0xc0 => return msg.get(at + 1).map(|_| at + 2),
+            _ => return None,
+        }
+    }
+}
+
+/// Where the question ends and the records of a message are, None if it is malformed
+fn parse(msg: &[u8]) -> Option<(usize, Vec<Record>)> {
+    if msg.len() < DNS_HEADER_LEN {
+        return None;
+    }
+    let mut at = DNS_HEADER_LEN;
+    for _ in 0..be16(msg, 4) {
+        at = skip_name(msg, at)? + 4;
+    }
+    if at > msg.len() {
+        return None;
+    }
+    let questionend = at;
+    let mut records = Vec::new();
+    for (section, countat) in [6, 8, 10].iter().enumerate() {
+        for _ in 0..be16(msg, *countat) {
+            let start = at;
+            let fixed = skip_name(msg, at)?;
+            msg.get(fixed..fixed + 10)?;
+            let end = fixed + 10 + be16(msg, fixed + 8) as usize;
+            if end > msg.len() {
+                return None;
+            }
+            records.push(Record { section, start, end, rtype: be16(msg, fixed) });
+            at = end;
+        }
+    }
+    Some((questionend, records))
+}
+
+/// Offset of the TTL of a record
+fn ttl_offset(msg: &[u8], record: &Record) -> usize {
+    skip_name(msg, record.start).unwrap_or(record.start) + 4
+}
+
+/// The question of a standard query with its name in lower case, the key of the cache
+fn cache_key(query: &[u8]) -> Option<Vec<u8>> {
+    // a standard query with a single question
+    if query.len() < DNS_HEADER_LEN || query[2] & 0xf8 != 0 || be16(query, 4) != 1 {
+        return None;
+    }
+    let end = skip_name(query, DNS_HEADER_LEN)? + 4;
+    let question = query.get(DNS_HEADER_LEN..end)?;
+    Some(question.to_ascii_lowercase())
+}
+
+/// How long a response may be cached, None if it may not
+/* Answers last as long as their shortest TTL. Names that don't exist, or
+have no records of the type asked for, last as long as the SOA of their
+zone says, RFC 2308. Failures and truncated responses are not kept. */
+fn cache_ttl(response: &[u8]) -> Option<u32> {
+    let (_, records) = parse(response)?;
+    let rcode = response[3] & 0x0f;
+    if response[2] & 0x02 != 0 || (rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN) {
+        return None;
+    }
+    let answers = records.iter().filter(|r| r.section == 0);
+    if rcode == RCODE_NOERROR && answers.clone().count() > 0 {
+        return answers.map(|r| be32(response, ttl_offset(response, r))).min();
+    }
+    records.iter()
+        .filter(|r| r.section == 1 && r.rtype == TYPE_SOA && r.end >= 4)
+        .map(|r| be32(response, ttl_offset(response, r)).min(be32(response, r.end - 4)))
+        .min()
+}
+
+/// A response that fits in `maxlen` bytes
+/* The additional records go first, then the authority. If the answers
+alone are still too long, only the question is left with TC set, so the
+client knows to ask another way. */
+pub fn fit_response(response: &[u8], maxlen: usize) -> Vec<u8> {
+    if response.len() <= maxlen {
+        return response.to_vec();
+    }
+    let (questionend, records) = match parse(response) {
+        Some(parsed) => parsed,
+        None => return response[..maxlen.min(response.len())].to_vec(),
+    };
+    for keep in [1, 0].iter() {
+        let end = records.iter().filter(|r| r.section <= *keep).map(|r| r.end).max().unwrap_or(questionend);
+        if end <= maxlen {
+            let mut fitted = response[..end].to_vec();
+            fitted[10..12].copy_from_slice(&[0, 0]);
+            if *keep == 0 {
+                fitted[8..10].copy_from_slice(&[0, 0]);
+            }
+            return fitted;
+        }
+    }
+    let mut truncated = response[..questionend.min(maxlen)].to_vec();
+    truncated[2] |= 0x02;
+    truncated[6..12].copy_from_slice(&[0; 6]);
+    truncated
+}
+
+/// The SERVFAIL answer to a query, for when no upstream resolver answered
+fn servfail(query: &[u8]) -> Vec<u8> {
+    let end = skip_name(query, DNS_HEADER_LEN).map_or(DNS_HEADER_LEN, |end| (end + 4).min(query.len()));
+    let mut response = query[..end.max(DNS_HEADER_LEN).min(query.len())].to_vec();
+    response.resize(response.len().max(DNS_HEADER_LEN), 0);
+    response[2] = 0x80 | (response[2] & 0x79);
+    response[3] = 0x80 | RCODE_SERVFAIL;
+    response[4..6].copy_from_slice(if end > DNS_HEADER_LEN { &[0, 1] } else { &[0, 0] });
+    response[6..12].copy_from_slice(&[0; 6]);
+    response
+}
+
+/// The DNS query in an IP packet for one of our addresses, and who sent it
+pub fn dns_query(packet: &[u8]) -> Option<(DnsClient, Vec<u8>)> {
+    let ihl = (*packet.first()? & 0x0f) as usize * 4;
+    if packet[0] >> 4 != 4 || packet.len() < ihl + 8 + DNS_HEADER_LEN || packet[9] != UDP
+        || be16(packet, 6) & 0x3fff != 0 || be16(packet, ihl + 2) != DNS_PORT {
+        return None;
+    }
+    let query = &packet[ihl + 8..];
+    // responses are not for us to answer
+    if query[2] & 0x80 != 0 {
+        return None;
+    }
+    let client = DnsClient {
+        addr: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+        port: be16(packet, ihl),
+        server: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+    };
+    Some((client, query.to_vec()))
+}
+
+/// The IP packet carrying a response back to the client that asked
+pub fn dns_response_packet(client: &DnsClient, response: &[u8]) -> Vec<u8> {
+    let udplen = 8 + response.len();
+    let mut udp = DNS_PORT.to_be_bytes().to_vec();
+    udp.extend_from_slice(&client.port.to_be_bytes());
+    udp.extend_from_slice(&(udplen as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(response);
+    let mut pseudo = client.server.octets().to_vec();
+    pseudo.extend_from_slice(&client.addr.octets());
+    pseudo.extend_from_slice(&[0, UDP]);
+    pseudo.extend_from_slice(&(udplen as u16).to_be_bytes());
+    pseudo.extend_from_slice(&udp);
+    set_checksum(&mut pseudo, 18);
+    udp[6..8].copy_from_slice(&pseudo[18..20]);
+    if udp[6..8] == [0, 0] {
+        udp[6..8].copy_from_slice(&[0xff, 0xff]);
+    }
+
+    let mut packet = vec![0x45, 0];
+    packet.extend_from_slice(&((20 + udplen) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, UDP, 0, 0]);
+    packet.extend_from_slice(&client.server.octets());
+    packet.extend_from_slice(&client.addr.octets());
+    set_checksum(&mut packet, 10);
+    packet.extend(udp);
+    packet
+}
+
+/// A cached response and when it expires
+#[derive(Clone, Debug)]
+struct CachedResponse {
+    response: Vec<u8>,
+    stored: Instant,
+    ttl: u32,
+}
+
+/// Responses kept for as long as their TTL allows
+#[derive(Clone, Debug)]
+pub struct DnsCache {
+    entries: HashMap<Vec<u8>, CachedResponse>,
+    capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> Self {
+        DnsCache { entries: HashMap::new(), capacity, hits: 0, misses: 0 }
+    }
+
+    /// The cached response to a query, with its ID and the TTLs that are left
+    pub fn get(&mut self, query: &[u8]) -> Option<Vec<u8>> {
+        self.get_at(query, Instant::now())
+    }
+
+    pub(crate) fn get_at(&mut self, query: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let key = cache_key(query)?;
+        let elapsed = match self.entries.get(&key) {
+            Some(entry) => now.duration_since(entry.stored).as_secs(),
+            None => {
+                self.misses += 1;
+                return None;
+            },
+        };
+        let entry = &self.entries[&key];
+        if elapsed >= entry.ttl as u64 {
+            self.entries.remove(&key);
+            self.misses += 1;
+            return None;
+        }
+        self.hits += 1;
+        let mut response = entry.response.clone();
+        // the client's ID and question, in the case it was asked in
+        response[..2].copy_from_slice(&query[..2]);
+        response[DNS_HEADER_LEN..DNS_HEADER_LEN + key.len()].copy_from_slice(&query[DNS_HEADER_LEN..DNS_HEADER_LEN + key.len()]);
+        if let Some((_, records)) = parse(&response) {
+            for record in records.iter().filter(|r| r.rtype != TYPE_OPT) {
+                let at = ttl_offset(&response, record);
+                let ttl = be32(&response, at).saturating_sub(elapsed as u32);
+                response[at..at + 4].copy_from_slice(&ttl.to_be_bytes());
+            }
+        }
+        Some(response)
+    }
+
+    /// Keep the response to a query, if it may be kept
+    pub fn store(&mut self, query: &[u8], response: &[u8]) {
+        self.store_at(query, response, Instant::now());
+    }
+
+    pub(crate) fn store_at(&mut self, query: &[u8], response: &[u8], now: Instant) {
+        let (key, ttl) = match (cache_key(query), cache_ttl(response)) {
+            (Some(key), Some(ttl)) if ttl > 0 && self.capacity > 0 => (key, ttl),
+            _ => return,
+        };
+        if response.get(DNS_HEADER_LEN..DNS_HEADER_LEN + key.len()).map(|q| q.to_ascii_lowercase()) != Some(key.clone()) {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            // the one expiring first makes room
+            let first = self.entries.iter()
+                .min_by_key(|(_, entry)| entry.stored + Duration::from_secs(entry.ttl as u64))
+                .map(|(key, _)| key.clone());
+            if let Some(first) = first {
+                self.entries.remove(&first);
+            }
+        }
+        self.entries.insert(key, CachedResponse { response: response.to_vec(), stored: now, ttl });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// DNS proxy on the gateway, for nodes that have no resolver of their own
+/* Nodes send their queries to the gateway's mesh address, which the
+gateway hands out as their resolver. Answers come from the cache, or
+from the host's resolvers on a thread of their own so the mesh does not
+wait for them. Every answer is made to fit the mesh MTU. */
+pub struct DnsProxy {
+    cache: DnsCache,
+    maxlen: usize,
+    requests: Sender<(DnsClient, Vec<u8>)>,
+    results: Receiver<(DnsClient, Vec<u8>, io::Result<Vec<u8>>)>,
+}
+
+impl DnsProxy {
+    /// a proxy asking `upstream`, keeping `cachesize` responses and answering with at most `maxlen` bytes
+    pub fn new(upstream: Box<dyn DnsUpstream>, cachesize: usize, maxlen: usize) -> Self {
+        let (requests, queries) = unbounded::<(DnsClient, Vec<u8>)>();
+        let (answers, results) = unbounded();
+        thread::spawn(move || {
+            for (client, query) in queries.iter() {
+                let result = upstream.resolve(&query);
+                if answers.send((client, query, result)).is_err() {
+                    return;
+                }
+            }
+        });
+        DnsProxy { cache: DnsCache::new(cachesize), maxlen, requests, results }
+    }
+
+    /// Handle a query from a node, returns the answer right away if it is cached
+    pub fn query(&mut self, client: DnsClient, query: Vec<u8>) -> Option<Vec<u8>> {
+        if let Some(response) = self.cache.get(&query) {
+            trace!("DNS cache hit for {}", client.addr);
+            return Some(fit_response(&response, self.maxlen));
+        }
+        if self.requests.send((client, query)).is_err() {
+            error!("DNS proxy thread is gone");
+        }
+        None
+    }
+
+    /// The answers the upstream resolvers sent since last asked
+    /* A query no resolver answered gets SERVFAIL. */
+    pub fn answers(&mut self) -> Vec<(DnsClient, Vec<u8>)> {
+        let results: Vec<_> = self.results.try_iter().collect();
+        results.into_iter().map(|(client, query, result)| {
+            let response = match result {
+                Ok(response) => {
+                    self.cache.store(&query, &response);
+                    response
+                },
+                Err(e) => {
+                    warn!("Could not resolve a query from {}: {}", client.addr, e);
+                    servfail(&query)
+                },
+            };
+            (client, fit_response(&response, self.maxlen))
+        }).collect()
+    }
+
+    pub fn cache(&self) -> &DnsCache {
+        &self.cache
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn dns_proxy_answers() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&[0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.extend_from_slice(&[0, 0, 1, 0, 1]);
+        query
+    }
+    fn a(ttl: u32, last: u8) -> Vec<u8> {
+        let mut record = vec![0xc0, 0x0c, 0, 1, 0, 1];
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&[0, 4, 10, 0, 0, last]);
+        record
+    }
+    fn respond(query: &[u8], rcode: u8, sections: [Vec<Vec<u8>>; 3]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2..4].copy_from_slice(&[0x81, 0x80 | rcode]);
+        for (i, records) in sections.iter().enumerate() {
+            response[6 + 2 * i..8 + 2 * i].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        }
+        sections.iter().flatten().for_each(|record| response.extend_from_slice(record));
+        response
+    }
+
+    struct Mock(Arc<AtomicUsize>);
+    impl DnsUpstream for Mock {
+        fn resolve(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            match query[13] {
+                // example.org
+                b'e' => Ok(respond(query, RCODE_NOERROR, [vec![a(300, 1)], vec![], vec![]])),
+                // missing.org, with the SOA of org: TTL 900, minimum 60
+                b'm' => {
+                    let mut soa = vec![0, 0, TYPE_SOA as u8, 0, 1, 0, 0, 0x03, 0x84, 0, 22, 0, 0];
+                    soa.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 60]);
+                    Ok(respond(query, RCODE_NXDOMAIN, [vec![], vec![soa], vec![]]))
+                },
+                // big.org, with lots of glue
+                b'b' => Ok(respond(query, RCODE_NOERROR, [(0..20).map(|i| a(60, i)).collect(), vec![a(60, 100)], (0..20).map(|i| a(60, i)).collect()])),
+                _ => Err(Error::new(ErrorKind::TimedOut, "no answer")),
+            }
+        }
+    }
+    let wait = |proxy: &mut DnsProxy| {
+        for _ in 0..200 {
+            let answers = proxy.answers();
+            if !answers.is_empty() {
+                return answers;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("no answer from the proxy");
+    };
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut proxy = DnsProxy::new(Box::new(Mock(calls.clone())), 16, 400);
+    let client = DnsClient { addr: Ipv4Addr::new(172, 16, 0, 9), port: 40000, server: Ipv4Addr::new(172, 16, 0, 1) };
+
+    // the first query goes upstream, the second comes from the cache with its own ID
+    assert_eq!(proxy.query(client, query(1, "example.org")), None);
+    let answers = wait(&mut proxy);
+    assert_eq!(answers, vec![(client, respond(&query(1, "example.org"), 0, [vec![a(300, 1)], vec![], vec![]]))]);
+    let cached = proxy.query(client, query(2, "EXAMPLE.org")).unwrap();
+    assert_eq!(cached, respond(&query(2, "EXAMPLE.org"), 0, [vec![a(300, 1)], vec![], vec![]]));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // the TTLs count down, and the answer is gone once they run out
+    let mut cache = DnsCache::new(4);
+    let now = Instant::now();
+    let response = respond(&query(1, "example.org"), 0, [vec![a(300, 1)], vec![], vec![]]);
+    cache.store_at(&query(1, "example.org"), &response, now);
+    let later = cache.get_at(&query(3, "example.org"), now + Duration::from_secs(100)).unwrap();
+    assert_eq!(later, respond(&query(3, "example.org"), 0, [vec![a(200, 1)], vec![], vec![]]));
+    assert_eq!(cache.get_at(&query(3, "example.org"), now + Duration::from_secs(300)), None);
+    assert!(cache.is_empty());
+
+    // NXDOMAIN is cached for the SOA's minimum
+    assert_eq!(proxy.query(client, query(3, "missing.org")), None);
+    let answers = wait(&mut proxy);
+    assert_eq!(answers[0].1[3] & 0x0f, RCODE_NXDOMAIN);
+    assert_eq!(cache_ttl(&answers[0].1), Some(60));
+    assert_eq!(proxy.query(client, query(4, "missing.org")).unwrap()[3] & 0x0f, RCODE_NXDOMAIN);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // too long for the mesh: the additional records go first, then everything but the question
+    let big = respond(&query(5, "big.org"), 0, [(0..20).map(|i| a(60, i)).collect(), vec![a(60, 100)], (0..20).map(|i| a(60, i)).collect()]);
+    assert!(big.len() > 400);
+    assert_eq!(proxy.query(client, query(5, "big.org")), None);
+    let fitted = &wait(&mut proxy)[0].1;
+    assert_eq!((fitted[2] & 0x02, be16(fitted, 6), be16(fitted, 8), be16(fitted, 10)), (0, 20, 1, 0));
+    assert_eq!(&fitted[DNS_HEADER_LEN..], &big[DNS_HEADER_LEN..fitted.len()]);
+    let truncated = fit_response(&big, 100);
+    assert_eq!(truncated.len(), query(5, "big.org").len());
+    assert_eq!((truncated[2] & 0x02, be16(&truncated, 4), be16(&truncated, 6)), (0x02, 1, 0));
+
+    // no answer upstream is SERVFAIL, and not cached
+    assert_eq!(proxy.query(client, query(6, "unreachable.org")), None);
+    let failed = &wait(&mut proxy)[0].1;
+    assert_eq!((be16(failed, 0), failed[3] & 0x0f, failed.len()), (6, RCODE_SERVFAIL, query(6, "unreachable.org").len()));
+    assert_eq!(proxy.query(client, query(7, "unreachable.org")), None);
+
+    // the query comes out of the packet it was sent in, and the response goes back the same way
+    let packet = dns_response_packet(&DnsClient { addr: client.server, port: DNS_PORT, server: client.addr }, &query(8, "example.org"));
+    let mut swapped = packet.clone();
+    swapped[20..24].copy_from_slice(&[0x9c, 0x40, 0, 0x35]);
+    assert_eq!(dns_query(&swapped), Some((client, query(8, "example.org"))));
+    let packet = dns_response_packet(&client, &cached);
+    assert_eq!((&packet[12..16], &packet[16..20], be16(&packet, 20), be16(&packet, 22)), (&[172, 16, 0, 1][..], &[172, 16, 0, 9][..], DNS_PORT, 40000));
+    assert_eq!(dns_query(&packet), None);
+}
@@ -0,0 +1,282 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use crate::stack::frame::NodeId;
+use crate::stack::message::{DsrReplyMessage, DsrRequestMessage};
+
+/// Source routes learned from DSR requests and replies
+/* Every path starts at our next hop and is a route to each node on it,
+the shortest one to a node wins. A path that is the beginning of a
+longer one is not kept separately. Once full the oldest path goes. */
+#[derive(Clone, Debug)]
+pub struct RouteCache {
+    nodeid: NodeId,
+    capacity: usize,
+    paths: VecDeque<Vec<NodeId>>,
+}
+
+impl RouteCache {
+    pub fn new(nodeid: NodeId, capacity: usize) -> Self {
+        RouteCache { nodeid, capacity, paths: VecDeque::new() }
+    }
+
+    /// Keep a path starting at our next hop, false if it visits a node twice or comes back to us
+    pub fn add(&mut self, path: &[NodeId]) -> bool {
+        if path.is_empty() || path.contains(&self.nodeid) {
+            return false;
+        }
+        let mut nodes = path.to_vec();
+        nodes.sort();
+        nodes.dedup();
+        if nodes.len() < path.len() {
+            return false;
+        }
+        if self.paths.iter().any(|known| known.starts_with(path)) {
+            return true;
+        }
+        self.paths.retain(|known| !path.starts_with(known));
+        self.paths.push_front(path.to_vec());
+        self.paths.truncate(self.capacity);
+        true
+    }
+
+    /// Shortest cached route to a node
+    pub fn route(&self, dest: NodeId) -> Option<Vec<NodeId>> {
+        self.paths.iter()
+            .filter_map(|path| path.iter().position(|nodeid| *nodeid == dest).map(|at| path[..=at].to_vec()))
+            .min_by_key(|route| route.len())
+    }
+
+    /// Cut every path at a broken link, returns the nodes no longer reachable
+    pub fn remove_link(&mut self, from: NodeId, to: NodeId) -> Vec<NodeId> {
+        let mut cut = Vec::new();
+        for path in self.paths.iter_mut() {
+            let mut prev = self.nodeid;
+            let broken = path.iter().position(|next| {
+                let link = (prev, *next);
+                prev = *next;
+                link == (from, to) || link == (to, from)
+            });
+            if let Some(at) = broken {
+                cut.extend(path.drain(at..));
+            }
+        }
+        self.paths.retain(|path| !path.is_empty());
+        cut.sort();
+        cut.dedup();
+        cut.retain(|nodeid| self.route(*nodeid).is_none());
+        cut
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
+
+/// What a node does with a DSR request it heard
+pub enum DsrAction {
+    /// already seen by us, or out of hops
+    Ignore,
+    /// flood it on with ourselves added to the record
+    Forward(DsrRequestMessage),
+    /// send the path back to the origin along `route`
+    Reply { reply: DsrReplyMessage, route: Vec<NodeId> },
+}
+
+/// Dynamic Source Routing, on demand like AODV but without per-hop state
+/* A request collects the path it is flooded along, the target answers
+with the whole of it and only the origin needs to remember the route.
+Every node a request or reply passes caches the paths it learns from
+them, and a relay with a route to the target in its cache answers in its
+place. */
+#[derive(Clone, Debug)]
+pub struct DsrRouter {
+    nodeid: NodeId,
+    maxhops: u8,
+    window: Duration,
+    nextid: u16,
+    /// requests handled, by origin and request ID
+    seen: HashMap<(NodeId, u16), Instant>,
+    pub cache: RouteCache,
+    /// requests answered from the cache
+    pub cachereplies: u64,
+}
+
+impl DsrRouter {
+    /// copies of a request are dropped for `window` after the first one
+    pub fn new(nodeid: NodeId, maxhops: u8, capacity: usize, window: Duration) -> Self {
+        DsrRouter {
+            nodeid,
+            maxhops,
+            window,
+            nextid: 0,
+            seen: HashMap::new(),
+            cache: RouteCache::new(nodeid, capacity),
+            cachereplies: 0,
+        }
+    }
+
+    /// A new request for a route to `target`
+    pub fn request(&mut self, target: NodeId) -> DsrRequestMessage {
+        self.request_at(target, Instant::now())
+    }
+
+    pub(crate) fn request_at(&mut self, target: NodeId, now: Instant) -> DsrRequestMessage {
+        self.nextid = self.nextid.wrapping_add(1);
+        self.seen.insert((self.nodeid, self.nextid), now);
+        DsrRequestMessage::new(self.nextid, target, self.nodeid)
+    }
+
+    /// Handle a request heard from anyone
+    pub fn handle_request(&mut self, request: &DsrRequestMessage) -> DsrAction {
+        self.handle_request_at(request, Instant::now())
+    }
+
+    pub(crate) fn handle_request_at(&mut self, request: &DsrRequestMessage, now: Instant) -> DsrAction {
+        let window = self.window;
+        self.seen.retain(|_, first| now.duration_since(*first) < window);
+        let origin = match request.record.first() {
+            Some(origin) => *origin,
+            None => return DsrAction::Ignore,
+        };
+        if request.record.contains(&self.nodeid) || self.seen.contains_key(&(origin, request.id)) {
+            return DsrAction::Ignore;
+        }
+        self.seen.insert((origin, request.id), now);
+
+        // the way back to the origin and everyone on it
+        let back: Vec<NodeId> = request.record.iter().rev().cloned().collect();
+        self.cache.add(&back);
+
+        let mut path = request.record.clone();
+        path.push(self.nodeid);
+        if request.target == self.nodeid {
+            return DsrAction::Reply { reply: DsrReplyMessage::new(path), route: back };
+        }
+        if let Some(cached) = self.cache.route(request.target) {
+            if !cached.iter().any(|nodeid| path.contains(nodeid)) {
+                self.cachereplies += 1;
+                path.extend(cached);
+                return DsrAction::Reply { reply: DsrReplyMessage::new(path), route: back };
+            }
+        }
+        if (request.record.len() as u8) < self.maxhops {
+            let mut next = request.clone();
+            next.header = None;
+            next.record.push(self.nodeid);
+            return DsrAction::Forward(next);
+        }
+        DsrAction::Ignore
+    }
+
+    /// A reply passed us, or reached us as its origin
+    pub fn handle_reply(&mut self, reply: &DsrReplyMessage) {
+        self.learn(&reply.path);
+    }
+
+    /// A link broke, returns the nodes we no longer have a route to
+    pub fn handle_error(&mut self, from: NodeId, to: NodeId) -> Vec<NodeId> {
+        self.cache.remove_link(from, to)
+    }
+
+    /// Cache the routes to both ends of a path we are on
+    pub fn learn(&mut self, path: &[NodeId]) {
+        if let Some(at) = path.iter().position(|nodeid| *nodeid == self.nodeid) {
+            let backward: Vec<NodeId> = path[..at].iter().rev().cloned().collect();
+            self.cache.add(&path[at + 1..]);
+            self.cache.add(&backward);
+        }
+    }
+
+    /// Source route to a node, from the cache
+    pub fn route(&self, dest: NodeId) -> Option<Vec<NodeId>> {
+        self.cache.route(dest)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn dsr_route_discovery() {
+    // a line 1-2-3-4, with 5 hanging off 2
+    let links = [(1u8, 2u8), (2, 3), (3, 4), (2, 5)];
+    let linked = |a: NodeId, b: NodeId| links.iter().any(|link| *link == (a, b) || *link == (b, a));
+    let start = Instant::now();
+    let mut routers: HashMap<NodeId, DsrRouter> = (1..=5)
+        .map(|id| (id, DsrRouter::new(id, 4, 8, Duration::from_secs(30))))
+        .collect();
+
+    // flood a request, the origin learns the route from the replies
+    let discover = |routers: &mut HashMap<NodeId, DsrRouter>, origin: NodeId, target: NodeId| {
+        let mut flood = VecDeque::new();
+        flood.push_back((origin, routers.get_mut(&origin).unwrap().request_at(target, start)));
+        let mut replies = Vec::new();
+        while let Some((transmitter, request)) = flood.pop_front() {
+            for id in 1..=5 {
+                if !linked(transmitter, id) {
+                    continue;
+                }
+                match routers.get_mut(&id).unwrap().handle_request_at(&request, start) {
+                    DsrAction::Ignore => {},
+                    DsrAction::Forward(next) => flood.push_back((id, next)),
+                    DsrAction::Reply { reply, route } => {
+                        // every node on the way back takes in the reply
+                        for nodeid in route.iter() {
+                            routers.get_mut(nodeid).unwrap().handle_reply(&reply);
+                        }
+                        assert_eq!(route.last(), Some(&origin));
+                        replies.push(reply.path);
+                    },
+                }
+            }
+        }
+        replies
+    };
+
+    assert_eq!(discover(&mut routers, 1, 4), vec![vec![1, 2, 3, 4]]);
+    assert_eq!(routers[&1].route(4), Some(vec![2, 3, 4]));
+    // a prefix of the path is a route as well, and the relays learned both ways
+    assert_eq!(routers[&1].route(3), Some(vec![2, 3]));
+    assert_eq!(routers[&2].route(4), Some(vec![3, 4]));
+    assert_eq!(routers[&3].route(1), Some(vec![2, 1]));
+    // the target learned its way back from the request alone
+    assert_eq!(routers[&4].route(1), Some(vec![3, 2, 1]));
+
+    // 2 knows the way to 4 and answers 5 from its cache
+    assert_eq!(discover(&mut routers, 5, 4), vec![vec![5, 2, 3, 4]]);
+    assert_eq!(routers[&2].cachereplies, 1);
+    assert_eq!(routers[&5].route(4), Some(vec![2, 3, 4]));
+
+    // the link 3-4 breaks, 3 notices and 2 cuts its paths there, only losing 4
+    assert_eq!(routers.get_mut(&3).unwrap().handle_error(3, 4), vec![4]);
+    assert_eq!(routers.get_mut(&2).unwrap().handle_error(3, 4), vec![4]);
+    assert_eq!(routers[&2].route(4), None);
+    assert_eq!(routers[&2].route(3), Some(vec![3]));
+    assert_eq!(routers.get_mut(&1).unwrap().handle_error(3, 4), vec![4]);
+    assert_eq!(routers[&1].route(3), Some(vec![2, 3]));
+    // a broken first hop takes everything behind it
+    assert_eq!(routers.get_mut(&5).unwrap().handle_error(5, 2), vec![1, 2, 3, 4]);
+    assert!(routers[&5].cache.is_empty());
+
+    // nobody is left with a cached route past the break, 4 is found again
+    assert_eq!(discover(&mut routers, 1, 4), vec![vec![1, 2, 3, 4]]);
+
+    // a request is only handled once, and never loops back
+    let request = routers.get_mut(&1).unwrap().request_at(9, start);
+    assert!(matches!(routers.get_mut(&1).unwrap().handle_request_at(&request, start), DsrAction::Ignore));
+    assert!(matches!(routers.get_mut(&2).unwrap().handle_request_at(&request, start), DsrAction::Forward(_)));
+    assert!(matches!(routers.get_mut(&2).unwrap().handle_request_at(&request, start), DsrAction::Ignore));
+
+    // the cache keeps whole paths, drops looping ones and the oldest once full
+    let mut cache = RouteCache::new(1, 2);
+    assert!(!cache.add(&[2, 3, 2]));
+    assert!(!cache.add(&[2, 1]));
+    assert!(cache.add(&[2, 3]));
+    assert!(cache.add(&[2, 3, 4]));
+    assert_eq!(cache.len(), 1);
+    assert!(cache.add(&[5]));
+    assert!(cache.add(&[6, 7]));
+    assert_eq!((cache.len(), cache.route(4), cache.route(7)), (2, None, Some(vec![6, 7])));
+}
@@ -0,0 +1,157 @@
+use std::time::{Duration, Instant};
+
+/// Where a duty-cycled node is in the sleep schedule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DutyState {
+    /// no recent time sync, listening all the time until one comes
+    AlwaysOn,
+    /// in the window every node is awake for
+    Awake,
+    /// between windows, the radio sleeps
+    Asleep,
+}
+
+/// Sleep schedule shared by every node of a duty-cycled mesh
+/* Mesh time, the gateway's clock as spread by time syncs, is cut into
+epochs of `period`. Every node is awake for the first `awake` of each
+epoch, plus `guard` on either side for clocks that are a little apart,
+and sleeps for the rest. A node that has not heard a sync for `resync`
+can no longer tell where the windows are and stays awake until it hears
+one. Frames sent while asleep wait in the transmit queue for the next
+window. */
+#[derive(Clone, Debug)]
+pub struct DutyCycle {
+    period: u64,
+    awake: u64,
+    guard: u64,
+    resync: Duration,
+    synced: Option<Instant>,
+    state: DutyState,
+    /// times the radio was put to sleep
+    pub sleeps: u64,
+    /// times sync was lost and the node fell back to listening
+    pub unsynced: u64,
+}
+
+impl DutyCycle {
+    /// windows of `awake` ms every `period` ms, opened `guard` ms early and closed as late
+    pub fn new(period: u64, awake: u64, guard: u64, resync: Duration) -> Self {
+        DutyCycle { period, awake, guard, resync, synced: None, state: DutyState::AlwaysOn, sleeps: 0, unsynced: 0 }
+    }
+
+    /// A time sync was taken, or we are the gateway keeping the time
+    pub fn synced(&mut self) {
+        self.synced_at(Instant::now())
+    }
+
+    pub(crate) fn synced_at(&mut self, now: Instant) {
+        self.synced = Some(now);
+    }
+
+    /// The state as of the last tick
+    pub fn state(&self) -> DutyState {
+        self.state
+    }
+
+    /// false while the radio sleeps, nothing should be sent
+    pub fn is_awake(&self) -> bool {
+        self.state != DutyState::Asleep
+    }
+
+    /// Where `meshtime`, in ms since the unix epoch, falls in the schedule
+    pub fn state_at(&self, meshtime: u64, now: Instant) -> DutyState {
+        if self.synced.map_or(true, |synced| now.duration_since(synced) >= self.resync) {
+            return DutyState::AlwaysOn;
+        }
+        let phase = meshtime % self.period;
+        if phase < self.awake + self.guard || phase + self.guard >= self.period {
+            DutyState::Awake
+        } else {
+            DutyState::Asleep
+        }
+    }
+
+    /// Time until the next window opens, guard included
+    pub fn wake_in(&self, meshtime: u64) -> Duration {
+        let phase = meshtime % self.period;
+        Duration::from_millis((self.period - self.guard).saturating_sub(phase))
+    }
+
+    /// Move along the schedule, returns the new state when it changed
+    pub fn tick(&mut self, meshtime: u64) -> Option<DutyState> {
+        self.tick_at(meshtime, Instant::now())
+    }
+
+    pub(crate) fn tick_at(&mut self, meshtime: u64, now: Instant) -> Option<DutyState> {
+        let state = self.state_at(meshtime, now);
+        if state == self.state {
+            return None;
+        }
+        match state {
+            DutyState::Asleep => self.sleeps += 1,
+            DutyState::AlwaysOn => self.unsynced += 1,
+            DutyState::Awake => {},
+        }
+        self.state = state;
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn duty_cycle_windows() {
+    use crate::stack::qos::{tx_queue, TxPriority};
+
+    // 10s awake every 2 minutes, windows opened half a second early and closed as late
+    let (period, awake, guard) = (120_000u64, 10_000u64, 500u64);
+    let resync = Duration::from_secs(600);
+    let start = Instant::now();
+    let epoch = 1_700_000_040_000u64 / period * period;
+    let mut duty = DutyCycle::new(period, awake, guard, resync);
+
+    // before the first sync the node listens all the time
+    assert_eq!(duty.tick_at(epoch + 60_000, start), None);
+    assert_eq!(duty.state(), DutyState::AlwaysOn);
+
+    // the radio only sends while awake, frames queued in between wait for it
+    let (sender, receiver) = tx_queue(8);
+    duty.synced_at(start);
+    let mut delivered = Vec::new();
+    let mut events = Vec::new();
+    for ms in (0..period * 2).step_by(100) {
+        let meshtime = epoch + 30_000 + ms;
+        let now = start + Duration::from_millis(ms);
+        if let Some(state) = duty.tick_at(meshtime, now) {
+            events.push((meshtime - epoch, state));
+        }
+        // something to send in the middle of the sleep
+        if meshtime == epoch + 60_000 {
+            assert_eq!(duty.state(), DutyState::Asleep);
+            assert_eq!(duty.wake_in(meshtime), Duration::from_millis(59_500));
+            sender.send(vec![1], TxPriority::Low).unwrap();
+        }
+        if duty.is_awake() {
+            while let Ok(data) = receiver.try_recv() {
+                delivered.push((meshtime - epoch, data));
+            }
+        }
+    }
+    assert_eq!(events, vec![
+        (30_000, DutyState::Asleep),
+        (period - guard, DutyState::Awake),
+        (period + awake + guard, DutyState::Asleep),
+        (2 * period - guard, DutyState::Awake),
+        (2 * period + awake + guard, DutyState::Asleep),
+    ]);
+    // delivered as soon as the next window opened
+    assert_eq!(delivered, vec![(period - guard, vec![1])]);
+    assert_eq!(duty.sleeps, 3);
+
+    // without syncs the node falls back to listening, and back on the schedule with the next one
+    let late = start + resync + Duration::from_secs(1);
+    assert_eq!(duty.tick_at(epoch + 60_000, late), Some(DutyState::AlwaysOn));
+    assert!(duty.is_awake() && duty.unsynced == 1);
+    duty.synced_at(late);
+    assert_eq!(duty.tick_at(epoch + 60_000, late), Some(DutyState::Asleep));
+    assert_eq!(duty.tick_at(epoch + period + 1_000, late), Some(DutyState::Awake));
+}
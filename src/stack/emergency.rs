@@ -0,0 +1,172 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use crate::stack::frame::NodeId;
+use crate::stack::message::EmergencyBeaconMessage;
+
+/// Window the emergency allowance is counted over
+pub const EMERGENCY_WINDOW: Duration = Duration::from_secs(3600);
+
+/// true if a beacon should be sent on, whatever routes there are
+/* Every node relays every beacon once, duplicates are caught by the dedup
+cache before this is asked. */
+pub fn should_relay(route: &[NodeId], id: NodeId) -> bool {
+    !route.contains(&id) && route.len() < u8::MAX as usize
+}
+
+/// Our own emergency, repeated until it is cancelled
+#[derive(Clone, Debug)]
+pub struct Beacon {
+    pub text: String,
+    seq: u16,
+    interval: Duration,
+    next: Instant,
+}
+
+impl Beacon {
+    /// a beacon due right away
+    pub fn new(text: &str, interval: Duration) -> Self {
+        Beacon::new_at(text, interval, Instant::now())
+    }
+
+    pub(crate) fn new_at(text: &str, interval: Duration, now: Instant) -> Self {
+        Beacon { text: String::from(text), seq: 0, interval, next: now }
+    }
+
+    /// sequence number of the repeat due now, if any
+    pub fn due(&mut self) -> Option<u16> {
+        self.due_at(Instant::now())
+    }
+
+    pub(crate) fn due_at(&mut self, now: Instant) -> Option<u16> {
+        if now < self.next {
+            return None;
+        }
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        self.next = now + self.interval;
+        Some(seq)
+    }
+
+    /// sequence number for the all-clear ending this beacon
+    pub fn clear_seq(&self) -> u16 {
+        self.seq
+    }
+}
+
+/// What a received beacon means to the application
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmergencyEvent {
+    /// a node we had no emergency for raised one
+    Raised,
+    /// a repeat of a known emergency
+    Repeated,
+    /// the originator gave the all-clear
+    Cleared,
+}
+
+/// Emergencies raised by other nodes that were not cleared yet
+#[derive(Clone, Debug, Default)]
+pub struct EmergencyTable {
+    active: HashMap<NodeId, (EmergencyBeaconMessage, Instant)>,
+}
+
+impl EmergencyTable {
+    pub fn new() -> Self {
+        EmergencyTable { active: HashMap::new() }
+    }
+
+    /// record a beacon from its originator, None for an all-clear of an emergency never heard
+    pub fn handle(&mut self, nodeid: NodeId, beacon: EmergencyBeaconMessage) -> Option<EmergencyEvent> {
+        if beacon.allclear {
+            return self.active.remove(&nodeid).map(|_| EmergencyEvent::Cleared);
+        }
+        match self.active.insert(nodeid, (beacon, Instant::now())) {
+            None => Some(EmergencyEvent::Raised),
+            Some(_) => Some(EmergencyEvent::Repeated),
+        }
+    }
+
+    /// every active emergency with the time its last beacon arrived, by node ID
+    pub fn all(&self) -> Vec<(NodeId, &EmergencyBeaconMessage, Instant)> {
+        let mut all: Vec<_> = self.active.iter()
+            .map(|(id, (beacon, received))| (*id, beacon, *received))
+            .collect();
+        all.sort_by_key(|(id, _, _)| *id);
+        all
+    }
+}
+
+/// Number of beacon frames that may jump the transmit queue within an hour
+/* There is no duty-cycle accounting in the radio, this keeps a stuck panic
+button or a storm of beacons from taking the channel over. Beacons past the
+allowance still go out, at high priority behind the queued ones. */
+#[derive(Clone, Debug)]
+pub struct EmergencyAllowance {
+    max: usize,
+    sent: VecDeque<Instant>,
+}
+
+impl EmergencyAllowance {
+    pub fn new(max: usize) -> Self {
+        EmergencyAllowance { max, sent: VecDeque::new() }
+    }
+
+    /// true if another frame may be sent as an emergency, recording it if so
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    pub(crate) fn allow_at(&mut self, now: Instant) -> bool {
+        while self.sent.front().map_or(false, |sent| now.duration_since(*sent) >= EMERGENCY_WINDOW) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.max {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn emergency_beacon_repeats() {
+    let start = Instant::now();
+    let mut beacon = Beacon::new_at("help", Duration::from_secs(30), start);
+    assert_eq!(beacon.due_at(start), Some(0));
+    assert_eq!(beacon.due_at(start + Duration::from_secs(10)), None);
+    assert_eq!(beacon.due_at(start + Duration::from_secs(30)), Some(1));
+    assert_eq!(beacon.clear_seq(), 2);
+
+    let mut table = EmergencyTable::new();
+    assert_eq!(table.handle(5, EmergencyBeaconMessage::all_clear(0)), None);
+    assert_eq!(table.handle(5, EmergencyBeaconMessage::new(0, None, "help")), Some(EmergencyEvent::Raised));
+    assert_eq!(table.handle(5, EmergencyBeaconMessage::new(1, None, "help")), Some(EmergencyEvent::Repeated));
+    assert_eq!(table.all().len(), 1);
+    assert_eq!(table.handle(5, EmergencyBeaconMessage::all_clear(2)), Some(EmergencyEvent::Cleared));
+    assert!(table.all().is_empty());
+
+    let mut allowance = EmergencyAllowance::new(2);
+    assert!(allowance.allow_at(start));
+    assert!(allowance.allow_at(start + Duration::from_secs(1)));
+    assert!(!allowance.allow_at(start + Duration::from_secs(2)));
+    assert!(allowance.allow_at(start + EMERGENCY_WINDOW));
+}
+
+#[test]
+fn emergency_events() {
+    let mut table = EmergencyTable::new();
+    assert_eq!(table.handle(1, EmergencyBeaconMessage::new(0, Some((46.5, 7.8)), "avalanche")), Some(EmergencyEvent::Raised));
+    assert_eq!(table.handle(1, EmergencyBeaconMessage::new(1, Some((46.5, 7.8)), "avalanche")), Some(EmergencyEvent::Repeated));
+    assert_eq!(table.handle(2, EmergencyBeaconMessage::new(0, None, "flood")), Some(EmergencyEvent::Raised));
+    assert_eq!(table.all().iter().map(|(id, beacon, _)| (*id, beacon.seq)).collect::<Vec<_>>(), vec![(1, 1), (2, 0)]);
+
+    // only a raised emergency is cleared
+    assert_eq!(table.handle(1, EmergencyBeaconMessage::all_clear(2)), Some(EmergencyEvent::Cleared));
+    assert_eq!(table.handle(3, EmergencyBeaconMessage::all_clear(0)), None);
+    assert_eq!(table.all().len(), 1);
+
+    // beacons are relayed once by every node, whatever the route
+    assert!(should_relay(&[1, 3, 4, 6], 7));
+    assert!(!should_relay(&[1, 3, 4], 3));
+}
@@ -0,0 +1,184 @@
+use log::*;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use crate::hardware::{Radio, RxPacket};
+use crate::hardware::lostik::mkerror;
+use crate::stack::{route_step, Frame, FrameIdAllocator, FrameKey, NodeId, RouteStep};
+use crate::stack::frame::ToFromFrame;
+use crate::stack::message::{DatagramMessage, MessageType};
+use crate::stack::qos::{TxPriority, TxQueueSender};
+
+/// Receivers of datagrams, by port
+type DatagramPorts = Arc<Mutex<HashMap<u16, Vec<Sender<(NodeId, Vec<u8>)>>>>>;
+
+/// Datagrams to and from nodes in range, straight over a radio
+/* For applications that only exchange small datagrams, without a tun
+device and so without root. Nothing is routed, relayed or acknowledged:
+datagrams go to their destination directly and may be lost. A node
+running a full `MeshNode` receives them on its datagram ports like any
+others. With the mesh's frame key frames are signed, and only signed ones
+whose tag checks out are taken, decrypted if they are encrypted. Without
+it frames are sent unsigned, and signed or encrypted ones are dropped as
+there is nothing to check them with. */
+pub struct DatagramEndpoint {
+    id: NodeId,
+    txsender: TxQueueSender,
+    frameids: FrameIdAllocator,
+    ports: DatagramPorts,
+}
+
+impl DatagramEndpoint {
+    /// Start the radio and a thread passing the datagrams it receives for `id` on
+    pub fn new<R: Radio>(id: NodeId, radio: &R, framekey: Option<FrameKey>) -> Self {
+        let (rxreader, txsender) = radio.run();
+        let txsender = txsender.with_frame_key(framekey);
+        let ports: DatagramPorts = Arc::new(Mutex::new(HashMap::new()));
+        let receivers = ports.clone();
+        thread::spawn(move || datagram_loop(id, framekey, rxreader, receivers));
+        DatagramEndpoint { id, txsender, frameids: FrameIdAllocator::new(), ports }
+    }
+
+    /// Send a datagram to a port on a node in range
+    pub fn send_datagram(&self, dest: NodeId, port: u16, payload: Vec<u8>) -> io::Result<()> {
+        let mut frame = DatagramMessage::new(dest, port, payload)?
+            .to_frame(self.frameids.allocate(Some(dest)), self.id, vec![dest])?;
+        self.txsender.send(frame.to_bytes(), TxPriority::Low)
+            .map_err(|e| mkerror(&format!("Could not queue datagram: {}", e)))
+    }
+
+    /// Receive the sender and payload of datagrams sent to a port of ours
+    pub fn recv_datagrams(&self, port: u16) -> Receiver<(NodeId, Vec<u8>)> {
+        let (sender, receiver) = unbounded();
+        self.ports.lock().unwrap().entry(port).or_insert_with(Vec::new).push(sender);
+        receiver
+    }
+}
+
+/// The frame as it was sent, if `framekey` allows taking it
+fn authenticate(frame: Frame, framekey: &Option<FrameKey>) -> io::Result<Frame> {
+    match (framekey, frame.signed()) {
+        (Some(key), true) => {
+            frame.verify(key)?;
+            let frame = frame.without_signature();
+            if frame.encrypted() { frame.decrypt(key) } else { Ok(frame) }
+        },
+        (Some(_), false) => Err(mkerror("frame is not signed")),
+        (None, true) => Err(mkerror("frame is signed and we have no framekey")),
+        (None, false) if frame.encrypted() => Err(mkerror("frame is encrypted and we have no framekey")),
+        (None, false) => Ok(frame),
+    }
+}
+
+fn datagram_loop(id: NodeId, framekey: Option<FrameKey>, rxreader: Receiver<RxPacket>, ports: DatagramPorts) {
+    while let Ok(packet) = rxreader.recv() {
+        let mut frame = match Frame::from_bytes(&packet.data).and_then(|frame| authenticate(frame, &framekey)) {
+            Ok(frame) => frame,
+            Err(e) => {
+                debug!("Dropping radio frame {}", e);
+                continue;
+            },
+        };
+        // datagrams are never chunked, and only those for us are taken
+        if frame.msgtype() != MessageType::Datagram || frame.txflag().more_chunks() {
+            continue;
+        }
+        match route_step(&mut frame, id) {
+            RouteStep::Deliver => {},
+            _ => continue,
+        }
+        let datagram = match DatagramMessage::from_frame(&mut frame) {
+            Ok(datagram) if datagram.destination == id => datagram,
+            Ok(datagram) => {
+                debug!("Dropping datagram for {} delivered to us", datagram.destination);
+                continue;
+            },
+            Err(e) => {
+                error!("Could not parse DatagramMessage: {}", e);
+                continue;
+            },
+        };
+        let mut ports = ports.lock().unwrap();
+        match ports.get_mut(&datagram.port) {
+            None => trace!("Dropping datagram from {} for closed port {}", frame.sender(), datagram.port),
+            Some(receivers) => {
+                // receivers that hung up are dropped
+                receivers.retain(|receiver| receiver.send((frame.sender(), datagram.payload.clone())).is_ok());
+                if receivers.is_empty() {
+                    ports.remove(&datagram.port);
+                }
+            }
+        }
+    }
+    debug!("Datagram endpoint stopped");
+}
+
+#[cfg(test)]
+#[test]
+fn datagram_endpoints() {
+    use std::time::Duration;
+    use crate::hardware::PairedLoopback;
+    use crate::stack::message::MAX_DATAGRAM_LEN;
+
+    let radios = PairedLoopback::new(3).into_radios();
+    let (a, b) = (DatagramEndpoint::new(1, &radios[0], None), DatagramEndpoint::new(2, &radios[1], None));
+    let timeout = Duration::from_secs(5);
+    let (chat, sensors) = (b.recv_datagrams(5000), b.recv_datagrams(5683));
+    let replies = a.recv_datagrams(5000);
+
+    // each port gets its own datagrams, in order
+    a.send_datagram(2, 5683, vec![21, 5]).unwrap();
+    a.send_datagram(2, 5000, b"hello".to_vec()).unwrap();
+    a.send_datagram(2, 5683, vec![21, 6]).unwrap();
+    assert_eq!(chat.recv_timeout(timeout).unwrap(), (1, b"hello".to_vec()));
+    assert_eq!(sensors.recv_timeout(timeout).unwrap(), (1, vec![21, 5]));
+    assert_eq!(sensors.recv_timeout(timeout).unwrap(), (1, vec![21, 6]));
+
+    // replies come back the same way, a third node in range hears them but they are not for it
+    b.send_datagram(1, 5000, b"hi".to_vec()).unwrap();
+    assert_eq!(replies.recv_timeout(timeout).unwrap(), (2, b"hi".to_vec()));
+    let c = DatagramEndpoint::new(3, &radios[2], None);
+    let overheard = c.recv_datagrams(5000);
+    b.send_datagram(1, 5000, b"again".to_vec()).unwrap();
+    assert_eq!(replies.recv_timeout(timeout).unwrap(), (2, b"again".to_vec()));
+    assert!(overheard.recv_timeout(Duration::from_millis(100)).is_err());
+
+    // closed ports drop datagrams, too large ones are refused
+    a.send_datagram(2, 9, vec![1]).unwrap();
+    assert!(a.send_datagram(2, 5000, vec![0; MAX_DATAGRAM_LEN + 1]).is_err());
+    a.send_datagram(2, 5000, vec![0; MAX_DATAGRAM_LEN]).unwrap();
+    assert_eq!(chat.recv_timeout(timeout).unwrap().1.len(), MAX_DATAGRAM_LEN);
+    assert!(chat.try_recv().is_err() && sensors.try_recv().is_err());
+}
+
+#[test]
+fn datagram_endpoints_signed() {
+    use std::time::Duration;
+    use crate::hardware::PairedLoopback;
+    use crate::stack::frame::parse_frame_key;
+
+    let key = parse_frame_key(&"5a".repeat(32)).unwrap();
+    let radios = PairedLoopback::new(4).into_radios();
+    let a = DatagramEndpoint::new(1, &radios[0], Some(key));
+    let b = DatagramEndpoint::new(2, &radios[1], Some(key));
+    let c = DatagramEndpoint::new(3, &radios[2], None);
+    let timeout = Duration::from_millis(500);
+    let (tob, toc) = (b.recv_datagrams(5000), c.recv_datagrams(5000));
+
+    // nodes sharing the key hear each other
+    a.send_datagram(2, 5000, b"signed".to_vec()).unwrap();
+    assert_eq!(tob.recv_timeout(timeout).unwrap(), (1, b"signed".to_vec()));
+
+    // an unsigned datagram is dropped by a node with the key, a signed one by a node without it
+    c.send_datagram(2, 5000, b"unsigned".to_vec()).unwrap();
+    assert!(tob.recv_timeout(timeout).is_err());
+    a.send_datagram(3, 5000, b"signed".to_vec()).unwrap();
+    assert!(toc.recv_timeout(timeout).is_err());
+
+    // and so is one whose tag does not check out
+    let forger = DatagramEndpoint::new(4, &radios[3], Some([0x5b; 32]));
+    forger.send_datagram(2, 5000, b"forged".to_vec()).unwrap();
+    assert!(tob.recv_timeout(timeout).is_err());
+}
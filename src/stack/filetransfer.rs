@@ -0,0 +1,492 @@
+use log::*;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::hardware::lostik::mkerror;
+use crate::stack::frame::NodeId;
+use crate::stack::message::{FileAckMessage, FileChunkMessage, FileCompleteMessage, FileMessage, FileOfferMessage, MAX_FILENAME_LEN};
+use crate::stack::util::crc32;
+
+/// Most chunks asked for by a single ack
+pub const FILE_WINDOW: usize = 16;
+
+/// Timeouts in a row before a transfer is given up
+pub const FILE_RETRIES: u32 = 5;
+
+/// A transfer that ended, for the user
+#[derive(Clone, Debug, PartialEq)]
+pub enum FileEvent {
+    /// the receiver confirmed our file arrived intact
+    Sent { dest: NodeId, transferid: u16 },
+    /// a file arrived intact and was saved
+    Received { sender: NodeId, path: PathBuf },
+    /// a transfer to or from `peer` was given up
+    Failed { peer: NodeId, transferid: u16, reason: String },
+}
+
+/// A file we offered, kept in memory until the receiver confirms it
+struct Outgoing {
+    name: String,
+    data: Vec<u8>,
+    chunksize: usize,
+    checksum: u32,
+    last: Instant,
+    retries: u32,
+}
+
+impl Outgoing {
+    fn offer(&self, transferid: u16) -> FileMessage {
+        FileMessage::Offer(FileOfferMessage {
+            header: None,
+            transferid,
+            name: self.name.clone(),
+            size: self.data.len() as u32,
+            checksum: self.checksum,
+            chunksize: self.chunksize as u16,
+        })
+    }
+
+    fn chunk(&self, transferid: u16, seq: u16) -> Option<FileMessage> {
+        let start = seq as usize * self.chunksize;
+        let data = self.data.get(start..(start + self.chunksize).min(self.data.len()))?;
+        Some(FileMessage::Chunk(FileChunkMessage { header: None, transferid, seq, data: Vec::from(data) }))
+    }
+}
+
+/// Progress of a file being received, saved next to the partial file so
+/// the transfer can resume after a restart
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct IncomingState {
+    name: String,
+    size: u32,
+    checksum: u32,
+    chunksize: u16,
+    received: Vec<bool>,
+}
+
+impl IncomingState {
+    /// the first `limit` chunks still missing
+    fn missing(&self, limit: usize) -> Vec<u16> {
+        self.received.iter().enumerate()
+            .filter(|(_, received)| !**received)
+            .map(|(seq, _)| seq as u16)
+            .take(limit)
+            .collect()
+    }
+
+    fn chunk_len(&self, seq: u16) -> usize {
+        let start = seq as usize * self.chunksize as usize;
+        (self.size as usize - start).min(self.chunksize as usize)
+    }
+}
+
+struct Incoming {
+    state: IncomingState,
+    /// chunks asked for in our last ack
+    window: Vec<u16>,
+    last: Instant,
+    retries: u32,
+}
+
+/// Sends and receives files in chunks, with selective acks of the missing ones
+/* The receiver drives a transfer: it answers an offer with the chunks it
+is missing, a window at a time, and asks again when chunks stop coming.
+The sender only repeats its offer if the receiver goes quiet. Nothing
+here touches the radio, messages to send are returned along with the
+node they go to. */
+pub struct FileTransfers {
+    /// where received files are saved
+    dir: PathBuf,
+    maxsize: u64,
+    chunksize: usize,
+    timeout: Duration,
+    nextid: u16,
+    /// by destination and transfer ID
+    outgoing: HashMap<(NodeId, u16), Outgoing>,
+    /// by sender and transfer ID
+    incoming: HashMap<(NodeId, u16), Incoming>,
+    /// how recent incoming transfers ended, repeated if the sender missed it
+    finished: HashMap<(NodeId, u16), (FileCompleteMessage, Instant)>,
+    events: Vec<FileEvent>,
+}
+
+fn complete(transferid: u16, ok: bool, reason: &str) -> FileCompleteMessage {
+    FileCompleteMessage { header: None, transferid, ok, reason: String::from(reason) }
+}
+
+/// the file name alone, None if it would reach outside the receive directory
+fn plain_name(name: &str) -> Option<&str> {
+    match Path::new(name).file_name().and_then(|n| n.to_str()) {
+        Some(plain) if plain == name && name.len() <= MAX_FILENAME_LEN => Some(name),
+        _ => None,
+    }
+}
+
+impl FileTransfers {
+    pub fn new(dir: PathBuf, maxsize: u64, chunksize: usize, timeout: Duration) -> Self {
+        FileTransfers {
+            dir,
+            maxsize,
+            chunksize: chunksize.max(1).min(u16::MAX as usize),
+            timeout,
+            nextid: 0,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            finished: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    fn part_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", name))
+    }
+
+    fn state_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.part.json", name))
+    }
+
+    /// Offer a file to a node, returning the transfer ID and the offer to send
+    pub fn send(&mut self, dest: NodeId, path: &Path, now: Instant) -> io::Result<(u16, FileMessage)> {
+        let name = path.file_name().and_then(|n| n.to_str()).and_then(plain_name)
+            .ok_or(mkerror(&format!("invalid file name, at most {} bytes of UTF-8", MAX_FILENAME_LEN)))?;
+        let size = fs::metadata(path)?.len();
+        let chunks = (size as usize).div_ceil(self.chunksize);
+        if size > self.maxsize || size > u32::MAX as u64 || chunks > u16::MAX as usize + 1 {
+            return Err(mkerror(&format!("file is {} bytes, the maximum is {}", size, self.maxsize)));
+        }
+        let data = fs::read(path)?;
+
+        self.nextid = self.nextid.wrapping_add(1);
+        let outgoing = Outgoing {
+            name: String::from(name),
+            checksum: crc32(&data),
+            data,
+            chunksize: self.chunksize,
+            last: now,
+            retries: 0,
+        };
+        let offer = outgoing.offer(self.nextid);
+        self.outgoing.insert((dest, self.nextid), outgoing);
+        Ok((self.nextid, offer))
+    }
+
+    /// Handle a message from another node, returning the messages to send
+    pub fn handle(&mut self, from: NodeId, msg: FileMessage, now: Instant) -> Vec<(NodeId, FileMessage)> {
+        match msg {
+            FileMessage::Offer(offer) => self.handle_offer(from, offer, now),
+            FileMessage::Chunk(chunk) => self.handle_chunk(from, chunk, now),
+            FileMessage::Ack(ack) => self.handle_ack(from, ack, now),
+            FileMessage::Complete(complete) => self.handle_complete(from, complete),
+        }
+    }
+
+    /// Transfers that ended since the last call
+    pub fn take_events(&mut self) -> Vec<FileEvent> {
+        std::mem::replace(&mut self.events, Vec::new())
+    }
+
+    fn handle_offer(&mut self, from: NodeId, offer: FileOfferMessage, now: Instant) -> Vec<(NodeId, FileMessage)> {
+        let key = (from, offer.transferid);
+        if let Some((complete, _)) = self.finished.get(&key) {
+            return vec![(from, FileMessage::Complete(complete.clone()))];
+        }
+        if !self.incoming.contains_key(&key) {
+            match self.accept(&offer) {
+                Err(reason) => {
+                    warn!("Refusing file {:?} from node {}: {}", &offer.name, &from, &reason);
+                    return self.finish(key, complete(offer.transferid, false, &reason), now);
+                },
+                Ok(state) => {
+                    info!("Receiving file {:?} of {} bytes from node {}", &state.name, &state.size, &from);
+                    self.incoming.insert(key, Incoming { state, window: Vec::new(), last: now, retries: 0 });
+                }
+            }
+        }
+        self.request(key, now)
+    }
+
+    /// Set up the partial file for an offer, resuming one left by an earlier transfer
+    fn accept(&self, offer: &FileOfferMessage) -> Result<IncomingState, String> {
+        let name = plain_name(&offer.name).ok_or(String::from("invalid file name"))?;
+        if offer.size as u64 > self.maxsize {
+            return Err(format!("file is {} bytes, the maximum is {}", offer.size, self.maxsize));
+        }
+        let chunks = (offer.size as usize + offer.chunksize as usize - 1) / (offer.chunksize.max(1) as usize);
+        if offer.chunksize == 0 || chunks > u16::MAX as usize + 1 {
+            return Err(format!("invalid chunk size {}", offer.chunksize));
+        }
+        let fresh = IncomingState {
+            name: String::from(name),
+            size: offer.size,
+            checksum: offer.checksum,
+            chunksize: offer.chunksize,
+            received: vec![false; chunks],
+        };
+        let saved = fs::read_to_string(self.state_path(name)).ok()
+            .and_then(|json| serde_json::from_str::<IncomingState>(&json).ok());
+        match saved {
+            Some(saved) if (saved.size, saved.checksum, saved.chunksize) == (fresh.size, fresh.checksum, fresh.chunksize) => {
+                info!("Resuming file {:?}, {} of {} chunks already received", name, chunks - saved.missing(chunks).len(), chunks);
+                Ok(saved)
+            },
+            _ => {
+                fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+                let part = fs::File::create(self.part_path(name)).map_err(|e| e.to_string())?;
+                part.set_len(offer.size as u64).map_err(|e| e.to_string())?;
+                self.save(&fresh).map_err(|e| e.to_string())?;
+                Ok(fresh)
+            }
+        }
+    }
+
+    fn save(&self, state: &IncomingState) -> io::Result<()> {
+        let json = serde_json::to_string(state).expect("Transfer state is always serializable");
+        fs::write(self.state_path(&state.name), json)
+    }
+
+    /// Ask for the next missing chunks, or end the transfer once all are in
+    fn request(&mut self, key: (NodeId, u16), now: Instant) -> Vec<(NodeId, FileMessage)> {
+        let incoming = match self.incoming.get_mut(&key) {
+            None => return Vec::new(),
+            Some(incoming) => incoming,
+        };
+        let missing = incoming.state.missing(FILE_WINDOW);
+        if missing.is_empty() {
+            let result = self.verify(key);
+            return self.finish(key, result, now);
+        }
+        incoming.window = missing.clone();
+        vec![(key.0, FileMessage::Ack(FileAckMessage { header: None, transferid: key.1, missing }))]
+    }
+
+    /// Check the received file against its checksum and move it into place
+    fn verify(&mut self, key: (NodeId, u16)) -> FileCompleteMessage {
+        let state = match self.incoming.remove(&key) {
+            None => return complete(key.1, false, "unknown transfer"),
+            Some(incoming) => incoming.state,
+        };
+        let part = self.part_path(&state.name);
+        let intact = fs::read(&part).map(|data| crc32(&data) == state.checksum).unwrap_or(false);
+        fs::remove_file(self.state_path(&state.name)).ok();
+        if !intact {
+            fs::remove_file(&part).ok();
+            error!("File {:?} from node {} does not match its checksum", &state.name, &key.0);
+            self.events.push(FileEvent::Failed { peer: key.0, transferid: key.1, reason: String::from("checksum mismatch") });
+            return complete(key.1, false, "checksum mismatch");
+        }
+        let path = self.dir.join(&state.name);
+        if let Err(e) = fs::rename(&part, &path) {
+            error!("Could not save file {:?}: {}", &state.name, e);
+            self.events.push(FileEvent::Failed { peer: key.0, transferid: key.1, reason: e.to_string() });
+            return complete(key.1, false, &e.to_string());
+        }
+        info!("Received file {:?} from node {}", &path, &key.0);
+        self.events.push(FileEvent::Received { sender: key.0, path });
+        complete(key.1, true, "")
+    }
+
+    fn finish(&mut self, key: (NodeId, u16), result: FileCompleteMessage, now: Instant) -> Vec<(NodeId, FileMessage)> {
+        self.finished.insert(key, (result.clone(), now));
+        vec![(key.0, FileMessage::Complete(result))]
+    }
+
+    fn handle_chunk(&mut self, from: NodeId, chunk: FileChunkMessage, now: Instant) -> Vec<(NodeId, FileMessage)> {
+        let key = (from, chunk.transferid);
+        let partpath = match self.incoming.get(&key) {
+            None => {
+                trace!("Dropping chunk {} of unknown transfer {} from {}", &chunk.seq, &chunk.transferid, &from);
+                return Vec::new();
+            },
+            Some(incoming) => self.part_path(&incoming.state.name),
+        };
+        let incoming = self.incoming.get_mut(&key).expect("Transfer was just found");
+        let state = &mut incoming.state;
+        if chunk.seq as usize >= state.received.len() || chunk.data.len() != state.chunk_len(chunk.seq) {
+            warn!("Dropping invalid chunk {} of file {:?} from {}", &chunk.seq, &state.name, &from);
+            return Vec::new();
+        }
+        incoming.last = now;
+        incoming.retries = 0;
+        if !state.received[chunk.seq as usize] {
+            let offset = chunk.seq as u64 * state.chunksize as u64;
+            let written = OpenOptions::new().write(true).open(&partpath)
+                .and_then(|part| part.write_all_at(&chunk.data, offset));
+            if let Err(e) = written {
+                error!("Could not write file {:?}: {}", &state.name, e);
+                let reason = format!("could not write file: {}", e);
+                self.incoming.remove(&key);
+                self.events.push(FileEvent::Failed { peer: from, transferid: key.1, reason: reason.clone() });
+                return self.finish(key, complete(key.1, false, &reason), now);
+            }
+            state.received[chunk.seq as usize] = true;
+            let state = state.clone();
+            if let Err(e) = self.save(&state) {
+                warn!("Could not save progress of file {:?}: {}", &state.name, e);
+            }
+        }
+
+        // ask for more once the whole window is in
+        let incoming = &self.incoming[&key];
+        if incoming.window.iter().all(|seq| incoming.state.received[*seq as usize]) {
+            return self.request(key, now);
+        }
+        Vec::new()
+    }
+
+    fn handle_ack(&mut self, from: NodeId, ack: FileAckMessage, now: Instant) -> Vec<(NodeId, FileMessage)> {
+        match self.outgoing.get_mut(&(from, ack.transferid)) {
+            None => {
+                trace!("Dropping ack of unknown transfer {} from {}", &ack.transferid, &from);
+                Vec::new()
+            },
+            Some(outgoing) => {
+                outgoing.last = now;
+                outgoing.retries = 0;
+                ack.missing.iter()
+                    .filter_map(|seq| outgoing.chunk(ack.transferid, *seq))
+                    .map(|chunk| (from, chunk))
+                    .collect()
+            }
+        }
+    }
+
+    fn handle_complete(&mut self, from: NodeId, complete: FileCompleteMessage) -> Vec<(NodeId, FileMessage)> {
+        match self.outgoing.remove(&(from, complete.transferid)) {
+            None => trace!("Dropping completion of unknown transfer {} from {}", &complete.transferid, &from),
+            Some(outgoing) if complete.ok => {
+                info!("File {:?} delivered to node {}", &outgoing.name, &from);
+                self.events.push(FileEvent::Sent { dest: from, transferid: complete.transferid });
+            },
+            Some(outgoing) => {
+                error!("Node {} did not accept file {:?}: {}", &from, &outgoing.name, &complete.reason);
+                self.events.push(FileEvent::Failed { peer: from, transferid: complete.transferid, reason: complete.reason });
+            }
+        }
+        Vec::new()
+    }
+
+    /// Repeat offers and acks that went unanswered, and give up on
+    /// transfers that timed out too often
+    /* Senders wait twice as long as receivers, so the receiver asks again
+    for missing chunks before the sender repeats its offer. */
+    pub fn poll(&mut self, now: Instant) -> Vec<(NodeId, FileMessage)> {
+        let mut send = Vec::new();
+        let mut failed = Vec::new();
+
+        for ((dest, transferid), outgoing) in self.outgoing.iter_mut() {
+            if now.duration_since(outgoing.last) < self.timeout * 2 {
+                continue;
+            }
+            outgoing.last = now;
+            outgoing.retries += 1;
+            if outgoing.retries > FILE_RETRIES {
+                warn!("Giving up on file {:?} to node {}", &outgoing.name, &dest);
+                failed.push((*dest, *transferid, "receiver stopped answering"));
+            } else {
+                send.push((*dest, outgoing.offer(*transferid)));
+            }
+        }
+        for (dest, transferid, reason) in failed.drain(..) {
+            self.outgoing.remove(&(dest, transferid));
+            self.events.push(FileEvent::Failed { peer: dest, transferid, reason: String::from(reason) });
+        }
+
+        let mut stalled = Vec::new();
+        for (key, incoming) in self.incoming.iter_mut() {
+            if now.duration_since(incoming.last) < self.timeout {
+                continue;
+            }
+            incoming.last = now;
+            incoming.retries += 1;
+            if incoming.retries > FILE_RETRIES {
+                // the partial file stays, a new offer resumes it
+                warn!("Giving up on file {:?} from node {}", &incoming.state.name, &key.0);
+                failed.push((key.0, key.1, "sender stopped answering"));
+            } else {
+                stalled.push(*key);
+            }
+        }
+        for (sender, transferid, reason) in failed {
+            self.incoming.remove(&(sender, transferid));
+            self.events.push(FileEvent::Failed { peer: sender, transferid, reason: String::from(reason) });
+        }
+        for key in stalled {
+            send.extend(self.request(key, now));
+        }
+
+        let keep = self.timeout * FILE_RETRIES * 2;
+        self.finished.retain(|_, (_, at)| now.duration_since(*at) < keep);
+        send
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn file_transfer_lossy_link() {
+    use std::collections::{HashSet, VecDeque};
+    use crate::stack::Frame;
+
+    let base = std::env::temp_dir().join(format!("loramesh-files-{}", std::process::id()));
+    fs::create_dir_all(&base).unwrap();
+    let contents: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(base.join("sensor.log"), &contents).unwrap();
+
+    let timeout = Duration::from_secs(10);
+    let mut now = Instant::now();
+    let mut sender = FileTransfers::new(base.join("sender"), 65536, 180, timeout);
+    let mut receiver = FileTransfers::new(base.join("received"), 65536, 180, timeout);
+    let (transferid, offer) = sender.send(2, &base.join("sensor.log"), now).unwrap();
+
+    // every fourth frame between node 1 and node 2 is lost
+    let mut air: VecDeque<(NodeId, NodeId, FileMessage)> = VecDeque::new();
+    air.push_back((1, 2, offer));
+    let mut frames = 0;
+    let mut chunks = HashSet::new();
+    let mut events = Vec::new();
+    let mut restarted = false;
+    while events.is_empty() && frames < 2000 {
+        // the receiver restarts partway through, keeping what it has on disk
+        if frames == 40 && !restarted {
+            receiver = FileTransfers::new(base.join("received"), 65536, 180, timeout);
+            chunks.clear();
+            restarted = true;
+        }
+        match air.pop_front() {
+            None => {
+                now += Duration::from_secs(5);
+                air.extend(sender.poll(now).into_iter().map(|(to, msg)| (1, to, msg)));
+                air.extend(receiver.poll(now).into_iter().map(|(to, msg)| (2, to, msg)));
+            },
+            Some((from, to, msg)) => {
+                frames += 1;
+                if frames % 4 == 0 {
+                    continue;
+                }
+                let mut frame = Frame::from_bytes(&msg.to_frame(frames as u8, from, vec![to]).unwrap().to_bytes()).unwrap();
+                let msg = FileMessage::from_frame(&mut frame).unwrap();
+                if let FileMessage::Chunk(chunk) = &msg {
+                    chunks.insert(chunk.seq);
+                }
+                let replies = if to == 2 { receiver.handle(from, msg, now) } else { sender.handle(from, msg, now) };
+                air.extend(replies.into_iter().map(|(dest, reply)| (to, dest, reply)));
+            }
+        }
+        events.extend(sender.take_events());
+    }
+
+    assert_eq!(events, vec![FileEvent::Sent { dest: 2, transferid }]);
+    assert_eq!(fs::read(base.join("received").join("sensor.log")).unwrap(), contents);
+    assert!(!base.join("received").join("sensor.log.part").exists());
+    // the chunks received before the restart were not sent again
+    assert!(chunks.len() < 56, "{} chunks sent after the restart", chunks.len());
+    match receiver.take_events().as_slice() {
+        [FileEvent::Received { sender: 1, .. }] => {},
+        other => panic!("unexpected receiver events {:?}", other),
+    }
+    fs::remove_dir_all(&base).unwrap();
+}
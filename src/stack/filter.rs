@@ -0,0 +1,305 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use crate::stack::ipv6::{IpPacket, IPV6_HEADER_LEN};
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ICMP: u8 = 1;
+const ICMPV6: u8 = 58;
+
+/// IPv6 extension headers skipped to find the protocol of the payload
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_DEST_OPTIONS: u8 = 60;
+
+/// Rules that keep LAN multicast and broadcast chatter off the air
+/* mDNS, SSDP, LLMNR, NetBIOS and syncthing discovery, along with anything
+else sent to a multicast or broadcast address. Unicast goes through. */
+pub const DEFAULT_TUN_FILTER: &str = "deny out to 224.0.0.0/4; deny out to 255.255.255.255/32; deny out to ff00::/8; \
+    deny out udp port 137-138; deny out udp port 1900; deny out udp port 5353; deny out udp port 5355; deny out udp port 21027";
+
+/// Which way a packet crosses the tunnel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterDirection {
+    /// from the tunnel to the radio
+    Out,
+    /// from the radio to the tunnel
+    In,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterAction {
+    Allow,
+    Deny,
+}
+
+/// Protocol a rule matches, ICMP being ICMPv6 for IPv6
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Number(u8),
+}
+
+impl FilterProtocol {
+    fn matches(&self, protocol: u8, ipv6: bool) -> bool {
+        match self {
+            FilterProtocol::Tcp => protocol == TCP,
+            FilterProtocol::Udp => protocol == UDP,
+            FilterProtocol::Icmp => protocol == if ipv6 { ICMPV6 } else { ICMP },
+            FilterProtocol::Number(number) => protocol == *number,
+        }
+    }
+}
+
+/// A rule written as `allow|deny [in|out] [tcp|udp|icmp|proto N] [to addr/len] [port N[-M]]`
+/* A rule without a direction applies both ways. `port` is the destination
+port, packets without one, such as later IP fragments, never match a rule
+with ports. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterRule {
+    pub action: FilterAction,
+    pub direction: Option<FilterDirection>,
+    pub protocol: Option<FilterProtocol>,
+    pub destination: Option<(IpAddr, u8)>,
+    pub ports: Option<(u16, u16)>,
+    /// the rule as written, for status output
+    pub text: String,
+}
+
+impl FilterRule {
+    pub fn parse(rule: &str) -> io::Result<Self> {
+        let invalid = |why: &str| Error::new(ErrorKind::InvalidInput, format!("invalid filter rule {}: {}", rule, why));
+        let mut words = rule.split_whitespace();
+        let action = match words.next() {
+            Some("allow") => FilterAction::Allow,
+            Some("deny") => FilterAction::Deny,
+            _ => return Err(invalid("must start with allow or deny")),
+        };
+        let mut parsed = FilterRule { action, direction: None, protocol: None, destination: None, ports: None, text: rule.trim().to_string() };
+        while let Some(word) = words.next() {
+            match word {
+                "in" => parsed.direction = Some(FilterDirection::In),
+                "out" => parsed.direction = Some(FilterDirection::Out),
+                "tcp" => parsed.protocol = Some(FilterProtocol::Tcp),
+                "udp" => parsed.protocol = Some(FilterProtocol::Udp),
+                "icmp" => parsed.protocol = Some(FilterProtocol::Icmp),
+                "proto" => {
+                    let number = words.next().and_then(|n| n.parse().ok()).ok_or_else(|| invalid("proto needs a protocol number"))?;
+                    parsed.protocol = Some(FilterProtocol::Number(number));
+                },
+                "to" => {
+                    let prefix = words.next().and_then(parse_prefix).ok_or_else(|| invalid("to needs an address/prefix"))?;
+                    parsed.destination = Some(prefix);
+                },
+                "port" => {
+                    let ports = words.next().and_then(parse_ports).ok_or_else(|| invalid("port needs a port or a range such as 137-138"))?;
+                    parsed.ports = Some(ports);
+                },
+                other => return Err(invalid(&format!("unknown word {}", other))),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn matches(&self, direction: FilterDirection, packet: &PacketSummary) -> bool {
+        self.direction.is_none_or(|d| d == direction)
+            && self.protocol.is_none_or(|protocol| packet.protocol.is_some_and(|p| protocol.matches(p, packet.destination.is_ipv6())))
+            && self.destination.is_none_or(|prefix| in_prefix(packet.destination, prefix))
+            && self.ports.is_none_or(|(first, last)| packet.port.is_some_and(|port| (first..=last).contains(&port)))
+    }
+}
+
+fn parse_prefix(prefix: &str) -> Option<(IpAddr, u8)> {
+    let mut parts = prefix.splitn(2, '/');
+    let addr: IpAddr = parts.next()?.parse().ok()?;
+    let maxlen = if addr.is_ipv4() { 32 } else { 128 };
+    let len = match parts.next() {
+        None => maxlen,
+        Some(len) => len.parse().ok().filter(|len| *len <= maxlen)?,
+    };
+    Some((addr, len))
+}
+
+fn parse_ports(ports: &str) -> Option<(u16, u16)> {
+    let mut parts = ports.splitn(2, '-');
+    let first: u16 = parts.next()?.parse().ok()?;
+    let last: u16 = match parts.next() {
+        None => first,
+        Some(last) => last.parse().ok()?,
+    };
+    Some((first, last)).filter(|(first, last)| first <= last)
+}
+
+fn in_prefix(addr: IpAddr, (prefix, len): (IpAddr, u8)) -> bool {
+    match (addr, prefix) {
+        (IpAddr::V4(addr), IpAddr::V4(prefix)) => {
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(prefix) & mask
+        },
+        (IpAddr::V6(addr), IpAddr::V6(prefix)) => {
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(prefix) & mask
+        },
+        _ => false,
+    }
+}
+
+/// What the rules look at in a packet
+struct PacketSummary {
+    destination: IpAddr,
+    /// None if the extension headers could not be followed
+    protocol: Option<u8>,
+    /// destination port of TCP and UDP, when the packet has it
+    port: Option<u16>,
+}
+
+impl PacketSummary {
+    fn new(packet: &IpPacket) -> Self {
+        let bytes = packet.as_ref();
+        let (protocol, transport) = match packet {
+            IpPacket::V4(_) => {
+                let headerlen = (bytes[0] & 0x0f) as usize * 4;
+                // later fragments carry no transport header
+                let offset = u16::from_be_bytes([bytes[6], bytes[7]]) & 0x1fff;
+                (Some(bytes[9]), Some(headerlen).filter(|_| offset == 0))
+            },
+            IpPacket::V6(_) => ipv6_transport(bytes),
+        };
+        let port = match (protocol, transport) {
+            (Some(TCP), Some(at)) | (Some(UDP), Some(at)) if bytes.len() >= at + 4 => Some(u16::from_be_bytes([bytes[at + 2], bytes[at + 3]])),
+            _ => None,
+        };
+        PacketSummary { destination: packet.destination(), protocol, port }
+    }
+}
+
+/// Protocol of an IPv6 packet's payload past its extension headers, and where its header starts
+fn ipv6_transport(bytes: &[u8]) -> (Option<u8>, Option<usize>) {
+    let mut next = bytes[6];
+    let mut at = IPV6_HEADER_LEN;
+    loop {
+        match next {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTIONS if bytes.len() >= at + 2 => {
+                next = bytes[at];
+                at += (bytes[at + 1] as usize + 1) * 8;
+            },
+            IPV6_FRAGMENT if bytes.len() >= at + 8 => {
+                let offset = u16::from_be_bytes([bytes[at + 2], bytes[at + 3]]) >> 3;
+                next = bytes[at];
+                at += 8;
+                if offset != 0 {
+                    return (Some(next), None);
+                }
+            },
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTIONS | IPV6_FRAGMENT => return (None, None),
+            protocol => return (Some(protocol), Some(at)),
+        }
+    }
+}
+
+/// Allow and deny rules for packets crossing the tunnel, the first matching rule decides
+/* Packets no rule matches are allowed. Every rule counts the packets it
+dropped. */
+#[derive(Clone, Debug)]
+pub struct PacketFilter {
+    rules: Vec<FilterRule>,
+    drops: Vec<u64>,
+}
+
+impl PacketFilter {
+    /// Parse rules separated by `;`
+    pub fn parse(rules: &str) -> io::Result<Self> {
+        let rules = rules.split(';')
+            .map(|rule| rule.trim())
+            .filter(|rule| !rule.is_empty())
+            .map(FilterRule::parse)
+            .collect::<io::Result<Vec<_>>>()?;
+        let drops = vec![0; rules.len()];
+        Ok(PacketFilter { rules, drops })
+    }
+
+    /// true if the packet may cross the tunnel in this direction
+    pub fn allow(&mut self, direction: FilterDirection, packet: &IpPacket) -> bool {
+        let summary = PacketSummary::new(packet);
+        match self.rules.iter().position(|rule| rule.matches(direction, &summary)) {
+            Some(i) if self.rules[i].action == FilterAction::Deny => {
+                self.drops[i] += 1;
+                false
+            },
+            _ => true,
+        }
+    }
+
+    /// Every rule with the packets it dropped
+    pub fn rules(&self) -> Vec<(&FilterRule, u64)> {
+        self.rules.iter().zip(self.drops.iter().cloned()).collect()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn filter_rules_match() {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let ipv4 = |protocol: u8, dest: Ipv4Addr, port: u16, fragment: u16| {
+        let mut bytes = vec![0x45, 0, 0, 28, 0, 1, (fragment >> 8) as u8, fragment as u8, 64, protocol, 0, 0, 172, 16, 0, 2];
+        bytes.extend_from_slice(&dest.octets());
+        bytes.extend_from_slice(&[0xc0, 0x01]);
+        bytes.extend_from_slice(&port.to_be_bytes());
+        bytes.extend_from_slice(&[0, 8, 0, 0]);
+        IpPacket::new(bytes).unwrap()
+    };
+    let ipv6 = |protocol: u8, dest: Ipv6Addr, port: u16, extension: bool| {
+        let payload = if extension { 16 } else { 8 };
+        let mut bytes = vec![0x60, 0, 0, 0, 0, payload, if extension { IPV6_HOP_BY_HOP } else { protocol }, 64];
+        bytes.extend_from_slice(&"fd6c:6d00::2".parse::<Ipv6Addr>().unwrap().octets());
+        bytes.extend_from_slice(&dest.octets());
+        if extension {
+            bytes.extend_from_slice(&[protocol, 0, 1, 4, 0, 0, 0, 0]);
+        }
+        bytes.extend_from_slice(&[0xc0, 0x01]);
+        bytes.extend_from_slice(&port.to_be_bytes());
+        bytes.extend_from_slice(&[0, 8, 0, 0]);
+        IpPacket::new(bytes).unwrap()
+    };
+    let unicast = Ipv4Addr::new(172, 16, 0, 9);
+
+    let mut filter = PacketFilter::parse(DEFAULT_TUN_FILTER).unwrap();
+    // mDNS, SSDP and syncthing stay off the air, unicast goes
+    assert!(!filter.allow(FilterDirection::Out, &ipv4(UDP, Ipv4Addr::new(224, 0, 0, 251), 5353, 0)));
+    assert!(!filter.allow(FilterDirection::Out, &ipv4(UDP, Ipv4Addr::new(239, 255, 255, 250), 1900, 0)));
+    assert!(!filter.allow(FilterDirection::Out, &ipv4(UDP, Ipv4Addr::new(255, 255, 255, 255), 21027, 0)));
+    assert!(!filter.allow(FilterDirection::Out, &ipv4(UDP, Ipv4Addr::new(172, 16, 0, 255), 138, 0)));
+    assert!(!filter.allow(FilterDirection::Out, &ipv6(UDP, "ff02::fb".parse().unwrap(), 5353, false)));
+    assert!(filter.allow(FilterDirection::Out, &ipv4(TCP, unicast, 22, 0)));
+    assert!(filter.allow(FilterDirection::Out, &ipv4(UDP, unicast, 53, 0)));
+    assert!(filter.allow(FilterDirection::Out, &ipv6(TCP, "fd6c:6d00::9".parse().unwrap(), 443, false)));
+    // the defaults only look at what leaves
+    assert!(filter.allow(FilterDirection::In, &ipv4(UDP, Ipv4Addr::new(224, 0, 0, 251), 5353, 0)));
+    let drops: Vec<u64> = filter.rules().iter().map(|(_, drops)| *drops).collect();
+    assert_eq!(drops, vec![2, 1, 1, 1, 0, 0, 0, 0]);
+
+    // a gateway letting only SSH and ICMP in, ports found past extension headers
+    let mut gateway = PacketFilter::parse("allow in tcp port 22; allow in icmp; deny in; allow").unwrap();
+    assert!(gateway.allow(FilterDirection::In, &ipv4(TCP, unicast, 22, 0)));
+    assert!(gateway.allow(FilterDirection::In, &ipv6(TCP, "fd6c:6d00::9".parse().unwrap(), 22, true)));
+    assert!(!gateway.allow(FilterDirection::In, &ipv6(TCP, "fd6c:6d00::9".parse().unwrap(), 80, true)));
+    assert!(gateway.allow(FilterDirection::In, &ipv4(ICMP, unicast, 0, 0)));
+    assert!(gateway.allow(FilterDirection::In, &ipv6(ICMPV6, "fd6c:6d00::9".parse().unwrap(), 0, false)));
+    assert!(!gateway.allow(FilterDirection::In, &ipv4(UDP, unicast, 22, 0)));
+    // a later fragment has no port to match
+    assert!(!gateway.allow(FilterDirection::In, &ipv4(TCP, unicast, 22, 0x00b9)));
+    assert!(gateway.allow(FilterDirection::Out, &ipv4(UDP, unicast, 5353, 0)));
+    assert_eq!(gateway.rules()[2].1, 3);
+
+    assert!(PacketFilter::parse("").unwrap().allow(FilterDirection::Out, &ipv4(UDP, Ipv4Addr::new(224, 0, 0, 251), 5353, 0)));
+    assert!(FilterRule::parse("drop udp").is_err());
+    assert!(FilterRule::parse("deny port 5353-53").is_err());
+    assert!(FilterRule::parse("deny to 10.0.0.0/33").is_err());
+    assert!(FilterRule::parse("deny proto tcp").is_err());
+    assert_eq!(FilterRule::parse("deny out to 10.1.0.0/16 port 80").unwrap().destination, Some((IpAddr::V4(Ipv4Addr::new(10, 1, 0, 0)), 16)));
+}
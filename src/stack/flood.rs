@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use crate::stack::dedup::DedupKey;
+use crate::stack::qos::TxPriority;
+
+/// Signal strength (dBm) of a flood heard from as far away as a node can be
+pub const FLOOD_RSSI_FAR: i16 = -130;
+
+/// Signal strength (dBm) of a flood heard from right next to its sender
+pub const FLOOD_RSSI_NEAR: i16 = -50;
+
+/// Share of the flood window kept random when the delay follows the signal
+pub const FLOOD_RSSI_JITTER: f32 = 0.25;
+
+/// How long a node waits before sending a flood on
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize))]
+pub enum FloodDelay {
+    /// anywhere in the window
+    #[serde(rename = "random")]
+    Random,
+    /// the weaker the signal the flood came in on, the sooner
+    #[serde(rename = "rssi")]
+    Rssi,
+}
+
+impl FloodDelay {
+    /// Delay within `window` for a flood heard at `rssi`, `random` is between 0 and 1
+    /* A node far from the sender hears it weakly, and its rebroadcast
+    reaches the most nodes that did not have the flood yet. It goes first,
+    so the nodes between overhear it and stay quiet. Nodes at about the
+    same distance still need to be spread out, a part of the window stays
+    random. Without an RSSI the whole window is. */
+    pub fn delay(&self, window: Duration, rssi: Option<i16>, random: f32) -> Duration {
+        let share = match (self, rssi) {
+            (FloodDelay::Rssi, Some(rssi)) => {
+                let near = (rssi - FLOOD_RSSI_FAR) as f32 / (FLOOD_RSSI_NEAR - FLOOD_RSSI_FAR) as f32;
+                near.clamp(0.0, 1.0) * (1.0 - FLOOD_RSSI_JITTER) + random * FLOOD_RSSI_JITTER
+            },
+            _ => random,
+        };
+        Duration::from_millis((window.as_millis() as f32 * share).round() as u64)
+    }
+}
+
+/// A rebroadcast waiting out its delay
+#[derive(Clone, Debug)]
+struct PendingRelay {
+    bytes: Vec<u8>,
+    priority: TxPriority,
+    due: Instant,
+    /// copies overheard from other nodes since it was scheduled
+    overheard: usize,
+}
+
+/// Rebroadcasts of flooded frames, delayed and dropped once neighbors covered them
+/* Each node waits a delay set by `policy` before sending a flood on.
+Whoever hears the same frame from more than `threshold` other nodes in the
+meantime can assume its neighbors already have it and stays quiet. The hop
+limit still bounds how far a flood goes, that is up to the caller. */
+#[derive(Clone, Debug)]
+pub struct FloodControl {
+    threshold: usize,
+    window: Duration,
+    policy: FloodDelay,
+    pending: HashMap<DedupKey, PendingRelay>,
+    /// rebroadcasts sent and cancelled so far
+    pub sent: u64,
+    pub suppressed: u64,
+}
+
+impl FloodControl {
+    /// with random delays
+    pub fn new(threshold: usize, window: Duration) -> Self {
+        FloodControl { threshold, window, policy: FloodDelay::Random, pending: HashMap::new(), sent: 0, suppressed: 0 }
+    }
+
+    /// the same, choosing the delays with `policy`
+    pub fn with_delay(mut self, policy: FloodDelay) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Schedule the rebroadcast of a flood heard for the first time, at `rssi` if the radio reported it
+    /// a flood scheduled again while it waits replaces the frame, keeping its delay
+    pub fn schedule(&mut self, key: DedupKey, bytes: Vec<u8>, priority: TxPriority, rssi: Option<i16>) {
+        let delay = self.policy.delay(self.window, rssi, thread_rng().gen());
+        self.schedule_at(key, bytes, priority, Instant::now() + delay)
+    }
+
+    pub(crate) fn schedule_at(&mut self, key: DedupKey, bytes: Vec<u8>, priority: TxPriority, due: Instant) {
+        match self.pending.get_mut(&key) {
+            Some(relay) => relay.bytes = bytes,
+            None => { self.pending.insert(key, PendingRelay { bytes, priority, due, overheard: 0 }); },
+        }
+    }
+
+    /// Another copy of a flood was heard, true if its rebroadcast was cancelled
+    pub fn overheard(&mut self, key: &DedupKey) -> bool {
+        let cancel = match self.pending.get_mut(key) {
+            None => return false,
+            Some(relay) => {
+                relay.overheard += 1;
+                relay.overheard > self.threshold
+            }
+        };
+        if cancel {
+            self.pending.remove(key);
+            self.suppressed += 1;
+        }
+        cancel
+    }
+
+    /// Rebroadcasts whose delay is over, in the order they came due
+    pub fn due(&mut self) -> Vec<(Vec<u8>, TxPriority)> {
+        self.due_at(Instant::now())
+    }
+
+    pub(crate) fn due_at(&mut self, now: Instant) -> Vec<(Vec<u8>, TxPriority)> {
+        let mut keys: Vec<(Instant, DedupKey)> = self.pending.iter()
+            .filter(|(_, relay)| relay.due <= now)
+            .map(|(key, relay)| (relay.due, *key))
+            .collect();
+        keys.sort();
+        self.sent += keys.len() as u64;
+        keys.into_iter()
+            .filter_map(|(_, key)| self.pending.remove(&key))
+            .map(|relay| (relay.bytes, relay.priority))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn flood_control() {
+    // a copy overheard too often cancels the rebroadcast, until then it stays
+    let key: DedupKey = (0, None, 1, 3);
+    let start = Instant::now();
+    let mut control = FloodControl::new(1, Duration::from_millis(500));
+    control.schedule_at(key, vec![1], TxPriority::Normal, start + Duration::from_millis(100));
+    control.schedule_at((0, None, 2, 3), vec![2], TxPriority::Low, start + Duration::from_millis(50));
+    assert!(!control.overheard(&key));
+    // a better copy of the frame takes the place of the waiting one
+    control.schedule_at(key, vec![3], TxPriority::Normal, start + Duration::from_millis(10));
+    assert_eq!(control.pending[&key].bytes, vec![3]);
+    assert!(control.due_at(start).is_empty());
+    assert_eq!(control.due_at(start + Duration::from_millis(60)), vec![(vec![2], TxPriority::Low)]);
+    assert!(control.overheard(&key));
+    assert!(control.due_at(start + Duration::from_millis(200)).is_empty());
+    assert_eq!((control.sent, control.suppressed, control.pending.len()), (1, 1, 0));
+}
+
+#[test]
+fn flood_delays() {
+    // strong signals wait longer, without one the delay is random
+    let window = Duration::from_millis(1000);
+    let far = FloodDelay::Rssi.delay(window, Some(FLOOD_RSSI_FAR - 10), 0.5);
+    let near = FloodDelay::Rssi.delay(window, Some(FLOOD_RSSI_NEAR), 0.5);
+    assert_eq!((far, near), (Duration::from_millis(125), Duration::from_millis(875)));
+    assert_eq!(FloodDelay::Rssi.delay(window, None, 0.3), Duration::from_millis(300));
+    assert_eq!(FloodDelay::Random.delay(window, Some(FLOOD_RSSI_NEAR), 0.3), Duration::from_millis(300));
+}
@@ -0,0 +1,214 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use crate::stack::Frame;
+use crate::stack::frame::NodeId;
+use crate::stack::routes::path_loops;
+
+/// frames remembered per destination by `LoopGuard`
+const MAX_FORWARDED: usize = 64;
+
+/// What a node does with a source-routed frame it heard
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RouteStep {
+    /// the route was already used up
+    Empty,
+    /// we are the destination
+    Deliver,
+    /// we are a relay, the frame goes on to this hop
+    Forward(NodeId),
+    /// the route does not name us, the frame is left unchanged
+    Overheard,
+}
+
+/// Take our hop off the front of a frame route
+/* The frame route holds the hops still ahead of the frame. A node named
+further down the route than the next hop skips the hops before it, which
+is how frames passed on by a promiscuous relay find their way back onto
+the route. */
+pub fn route_step(frame: &mut Frame, nodeid: NodeId) -> RouteStep {
+    let route = frame.route();
+    let pos = match route.iter().position(|hop| *hop == nodeid) {
+        None if route.is_empty() => return RouteStep::Empty,
+        None => return RouteStep::Overheard,
+        Some(pos) => pos,
+    };
+    for _ in 0..=pos {
+        frame.route_shift();
+    }
+    match frame.route().first() {
+        None => RouteStep::Deliver,
+        Some(next) => RouteStep::Forward(*next),
+    }
+}
+
+/// Pass on a frame we overheard, adding ourselves in front of its route
+/// returns false if it was ours to begin with or would exceed `maxhops`
+/* The route grows by one with every promiscuous relay, so like a flood
+it only travels until the route is `maxhops` long. Each node passes a
+frame on once at most, the duplicate cache drops the copies. */
+pub fn overheard_forward(frame: &mut Frame, nodeid: NodeId, maxhops: u8) -> bool {
+    if frame.sender() == nodeid || frame.route().len() >= maxhops as usize {
+        return false;
+    }
+    frame.route_unshift(nodeid).is_ok()
+}
+
+/// Loop defenses of the forwarding engine, each with its own counter
+/* Passive route learning, routes restored after a restart and source
+routing can disagree about the way to a node. A frame caught between them
+would use airtime on every lap until its route ran out, so the node that
+notices drops it. */
+#[derive(Clone, Debug)]
+pub struct LoopGuard {
+    window: Duration,
+    /// frames forwarded lately by destination, with their sender and frame ID
+    forwarded: HashMap<NodeId, VecDeque<(NodeId, u8, Instant)>>,
+    /// frames dropped because their route leads back to us
+    pub routeloops: u64,
+    /// paths not installed because they visit a node twice
+    pub pathloops: u64,
+    /// frames dropped because we already forwarded them towards their destination
+    pub pingpongs: u64,
+}
+
+impl LoopGuard {
+    /// Remember forwarded frames for `window`
+    pub fn new(window: Duration) -> Self {
+        LoopGuard { window, forwarded: HashMap::new(), routeloops: 0, pathloops: 0, pingpongs: 0 }
+    }
+
+    /// true if a frame we are about to forward went round a loop and must be dropped
+    /* Checked after our hop was taken off the route. Our own frames, routes
+    naming us again and frames we forwarded to the same destination before
+    all mean it came back. */
+    pub fn looping(&mut self, frame: &mut Frame, nodeid: NodeId) -> bool {
+        self.looping_at(frame, nodeid, Instant::now())
+    }
+
+    pub(crate) fn looping_at(&mut self, frame: &mut Frame, nodeid: NodeId, now: Instant) -> bool {
+        let route = frame.route();
+        if frame.sender() == nodeid || route.contains(&nodeid) {
+            self.routeloops += 1;
+            return true;
+        }
+        let dest = match route.last() {
+            None => return false,
+            Some(dest) => *dest,
+        };
+        let window = self.window;
+        let recent = self.forwarded.entry(dest).or_insert_with(VecDeque::new);
+        recent.retain(|(_, _, at)| now.duration_since(*at) < window);
+        if recent.iter().any(|(sender, frameid, _)| (*sender, *frameid) == (frame.sender(), frame.frameid())) {
+            self.pingpongs += 1;
+            return true;
+        }
+        if recent.len() >= MAX_FORWARDED {
+            recent.pop_front();
+        }
+        recent.push_back((frame.sender(), frame.frameid(), now));
+        false
+    }
+
+    /// true if a path may be installed as a route, it names no node twice and not us
+    pub fn path_ok(&mut self, path: &[NodeId], nodeid: NodeId) -> bool {
+        if path_loops(path) || path.contains(&nodeid) {
+            self.pathloops += 1;
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn route_step_shortcut() {
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::PingMessage;
+
+    let frame = PingMessage::new(1, 0).to_frame(9, 1, vec![2, 3, 4]).unwrap();
+    assert_eq!(route_step(&mut frame.clone(), 2), RouteStep::Forward(3));
+    assert_eq!(route_step(&mut frame.clone(), 5), RouteStep::Overheard);
+
+    // node 3 heard it directly from node 1 and skips node 2
+    let mut shortcut = frame.clone();
+    assert_eq!(route_step(&mut shortcut, 3), RouteStep::Forward(4));
+    assert_eq!(shortcut.route(), vec![4]);
+    assert_eq!(route_step(&mut frame.clone(), 4), RouteStep::Deliver);
+
+    let mut relayed = frame.clone();
+    assert!(overheard_forward(&mut relayed, 5, 4));
+    assert_eq!(relayed.route(), vec![5, 2, 3, 4]);
+    assert!(!overheard_forward(&mut relayed, 6, 4));
+    assert!(!overheard_forward(&mut frame.clone(), 1, 4));
+}
+
+#[test]
+fn promiscuous_no_storm() {
+    use crate::stack::DedupCache;
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::PingMessage;
+
+    // five nodes that all hear each other, node 1 pings node 5 through node 2
+    let mut caches: Vec<DedupCache> = (0..=5).map(|_| DedupCache::new(16, 0.01)).collect();
+    let mut air = vec![(1u8, PingMessage::new(1, 0).to_frame(9, 1, vec![2, 5]).unwrap().to_bytes())];
+    let mut transmissions = 0;
+    let mut delivered = 0;
+    while let Some((transmitter, bytes)) = air.pop() {
+        transmissions += 1;
+        for id in (1..=5u8).filter(|id| *id != transmitter) {
+            let mut frame = Frame::from_bytes(&bytes).unwrap();
+            let dest = frame.route().last().cloned().filter(|dest| *dest != frame.sender());
+            if caches[id as usize].is_duplicate(frame.sender(), dest, frame.frameid(), frame.msgtype().to_u8()) {
+                continue;
+            }
+            match route_step(&mut frame, id) {
+                RouteStep::Empty => panic!("route ran out"),
+                RouteStep::Deliver => delivered += 1,
+                RouteStep::Forward(_) => air.push((id, frame.to_bytes())),
+                RouteStep::Overheard => {
+                    if overheard_forward(&mut frame, id, 3) {
+                        air.push((id, frame.to_bytes()));
+                    }
+                },
+            }
+        }
+    }
+    assert_eq!(delivered, 1);
+    // the original plus at most one copy from each other node
+    assert!(transmissions <= 5, "{} transmissions", transmissions);
+}
+
+#[test]
+fn loop_guard() {
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::PingMessage;
+
+    let now = Instant::now();
+    let mut guard = LoopGuard::new(Duration::from_secs(30));
+
+    // a frame forwarded once is dropped when it comes back towards the same destination
+    let mut frame = PingMessage::new(1, 0).to_frame(8, 1, vec![3, 5]).unwrap();
+    assert!(!guard.looping_at(&mut frame.clone(), 2, now));
+    assert!(guard.looping_at(&mut frame, 2, now + Duration::from_secs(2)));
+    assert_eq!(guard.pingpongs, 1);
+
+    // a route naming us again is caught before any lap
+    let mut back = PingMessage::new(1, 0).to_frame(9, 1, vec![2, 3, 2, 5]).unwrap();
+    assert_eq!(route_step(&mut back, 2), RouteStep::Forward(3));
+    assert!(guard.looping_at(&mut back, 2, now));
+    assert_eq!(guard.routeloops, 1);
+
+    // and so are our own frames coming back
+    let mut own = PingMessage::new(1, 0).to_frame(10, 2, vec![5]).unwrap();
+    assert!(guard.looping_at(&mut own, 2, now));
+    assert_eq!(guard.routeloops, 2);
+
+    // retries after the window are forwarded again
+    let mut later = PingMessage::new(1, 0).to_frame(8, 1, vec![3, 5]).unwrap();
+    assert!(!guard.looping_at(&mut later, 2, now + Duration::from_secs(31)));
+
+    assert!(guard.path_ok(&[3, 4, 5], 2));
+    assert!(!guard.path_ok(&[3, 4, 3, 5], 2));
+    assert!(!guard.path_ok(&[3, 2, 5], 2));
+    assert_eq!(guard.pathloops, 2);
+}
@@ -2,10 +2,115 @@ use crate::stack::message::*;
 use enumn::N;
 use crate::stack::chunk::chunk_data;
 use std::io::ErrorKind;
+use std::fmt;
+use std::mem::size_of;
 use packet::ip::v4::Packet;
+use zerocopy::{AsBytes, FromBytes, FromZeroes, Ref, Unaligned};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use crate::hardware::lostik::mkerror;
+use crate::stack::padding::{pad_payload, unpad_payload, PADDING_HEADER_LEN};
+
+/// ID of a node in the mesh
+pub type NodeId = u8;
+
+/// size of the fixed part of a frame header, before the route
+pub const FRAME_HEADER_LEN: usize = size_of::<WireHeader>();
+
+/// largest frame the radio can transmit at once
+pub const MAX_FRAME_LEN: usize = 255;
+
+/// largest payload a frame may carry before chunking, an IP packet from the tunnel
+pub const MAX_PAYLOAD_LEN: usize = 1500;
+
+/// most hops a route may list, longer ones would crowd the payload out of a frame
+pub const MAX_ROUTE_LEN: usize = 10;
+
+/// Largest payload the radio can send in one packet with the given spreading
+/// factor, bandwidth in kHz and coding rate numerator (4/5 to 4/8)
+/* These are the maximum MAC payload sizes (M) of the Semtech/LoRaWAN regional
+tables. 500 kHz follows the US915 data rates, 250 kHz the 125 kHz row one
+spreading factor lower as that has the same airtime. The coding rate only
+stretches the airtime, the table limits are the same for all of them.
+Invalid settings allow nothing. */
+pub const fn max_payload_for(sf: u8, bw: u32, cr_numerator: u8) -> usize {
+    if sf < 7 || sf > 12 || cr_numerator < 5 || cr_numerator > 8 {
+        return 0;
+    }
+    match bw {
+        500 => match sf {
+            12 => 61,
+            11 => 137,
+            _ => 250,
+        },
+        250 => match sf {
+            11..=12 => 59,
+            10 => 123,
+            _ => 250,
+        },
+        125 => match sf {
+            10..=12 => 59,
+            9 => 123,
+            _ => 250,
+        },
+        _ => 0,
+    }
+}
+
+/// bit of the transmission flags asking the destination for an ack
+pub const TXFLAG_ACK: u8 = 0x80;
+
+/// bit of the transmission flags telling the frame ends in an HMAC tag
+pub const TXFLAG_SIGNED: u8 = 0x40;
+
+/// bit of the transmission flags telling the payload is encrypted
+pub const TXFLAG_ENCRYPTED: u8 = 0x20;
+
+/// bits of the transmission flags holding the `TransmissionState`
+const TXFLAG_STATE: u8 = 0x1F;
+
+/// length of the truncated HMAC-SHA256 tag at the end of a signed frame
+pub const FRAME_TAG_LEN: usize = 8;
+
+/// length of the nonce ahead of an encrypted payload
+pub const FRAME_NONCE_LEN: usize = 8;
+
+/// Most a frame grows on its way to the radio, encrypted and signed
+pub const FRAME_SEAL_LEN: usize = FRAME_NONCE_LEN + PADDING_HEADER_LEN + FRAME_TAG_LEN;
+
+/// Key shared by the nodes of a mesh to sign their frames
+pub type FrameKey = [u8; 32];
+
+/// Read a frame key from its 64 hex digits
+pub fn parse_frame_key(hex: &str) -> std::io::Result<FrameKey> {
+    let bytes = hex::decode(hex.trim()).map_err(|_| mkerror("framekey must be hex"))?;
+    if bytes.len() != 32 {
+        return Err(mkerror("framekey must be 32 bytes, 64 hex digits"));
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn frame_mac(key: &FrameKey, bytes: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(bytes);
+    mac
+}
+
+/// ChaCha20 under a key derived from the frame key, so the HMAC key is not used twice
+fn frame_cipher(key: &FrameKey, nonce: &[u8]) -> ChaCha20 {
+    let cipherkey = frame_mac(key, b"loramesh frame encryption").finalize().into_bytes();
+    let mut iv = [0u8; 12];
+    iv[12 - FRAME_NONCE_LEN..].copy_from_slice(nonce);
+    ChaCha20::new(&cipherkey, &iv.into())
+}
 
 /// Defines continuity in current transmission
 #[derive(Clone, PartialEq, Debug, N)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
 pub enum TransmissionState {
     FinalChunk = 0,
     MoreChunks = 1,
@@ -32,8 +137,48 @@ impl TransmissionState {
     }
 }
 
+/// fixed part of the frame header as it is laid out on the air,
+/// the route and payload follow it
+#[derive(Clone, Copy, Debug, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub struct WireHeader {
+    pub txflag: u8,
+    pub frameid: u8,
+    pub msgtype: u8,
+    pub sender: u8,
+    pub routeoffset: u8,
+}
+
+/// split raw bytes into the fixed header, route and remaining payload
+/// without copying
+fn split_wire(bytes: &[u8]) -> std::io::Result<(Ref<&[u8], WireHeader>, &[u8], &[u8])> {
+    let (wire, rest) = Ref::<_, WireHeader>::new_from_prefix(bytes).ok_or(ErrorKind::InvalidData)?;
+    let routelen = wire.routeoffset as usize;
+    // nothing could be relayed or answered over a longer route
+    if routelen > MAX_ROUTE_LEN || rest.len() < routelen {
+        return Err(ErrorKind::InvalidData.into());
+    }
+    let (route, payload) = rest.split_at(routelen);
+    Ok((wire, route, payload))
+}
+
+/// Why the bytes from the radio are refused, with the sender and frame ID they name
+/* Bytes too short for a header do not say who sent them, and are only
+dropped. */
+pub fn frame_error(bytes: &[u8]) -> Option<(NodeId, u8, MeshError)> {
+    let (wire, _, _) = split_wire(bytes).ok()?;
+    if bytes.len() > MAX_FRAME_LEN {
+        return Some((wire.sender, wire.frameid, MeshError::FrameTooLong));
+    }
+    if MessageType::n(wire.msgtype).is_none() && TransmissionState::n(wire.txflag & TXFLAG_STATE).is_some() {
+        return Some((wire.sender, wire.frameid, MeshError::UnknownMessageType(wire.msgtype)));
+    }
+    None
+}
+
 /// header of a frame
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
 pub struct FrameHeader {
     txflag: TransmissionState,
     frameid: u8,
@@ -41,27 +186,49 @@ pub struct FrameHeader {
     sender: u8,
     routeoffset: usize,
     route: Vec<u8>,
+    ackrequested: bool,
 }
 
 impl FrameHeader {
     /// constructor
     pub fn new(txflag: TransmissionState, frameid: u8, msgtype: MessageType, sender: u8, route: Vec<u8>) -> Self {
-        FrameHeader{txflag, frameid, msgtype, sender, routeoffset: route.len(), route}
+        FrameHeader{txflag, frameid, msgtype, sender, routeoffset: route.len(), route, ackrequested: false}
+    }
+
+    /// the transmission flags byte, with the ack bit
+    fn txflag_byte(&self) -> u8 {
+        self.txflag.to_u8() | if self.ackrequested { TXFLAG_ACK } else { 0 }
     }
 
     /// convert a packet to bytes
     pub fn bytes(&mut self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.push(self.txflag.to_u8());
-        bytes.push(self.frameid);
-        bytes.push(self.msgtype.to_u8());
-        bytes.push(self.sender.clone());
-        bytes.push(self.routeoffset.clone() as u8);
-        self.route.iter().for_each(|n| bytes.push(n.clone() as u8));
+        let wire = WireHeader {
+            txflag: self.txflag_byte(),
+            frameid: self.frameid,
+            msgtype: self.msgtype.to_u8(),
+            sender: self.sender,
+            routeoffset: self.routeoffset as u8,
+        };
+        let mut bytes = Vec::from(wire.as_bytes());
+        bytes.extend_from_slice(&self.route);
 
         return bytes;
     }
 
+    /// parse the header at the start of a raw frame
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+        let (wire, route, _payload) = split_wire(bytes)?;
+        Ok(FrameHeader {
+            txflag: TransmissionState::n(wire.txflag & TXFLAG_STATE).ok_or(ErrorKind::InvalidData)?,
+            frameid: wire.frameid,
+            msgtype: MessageType::n(wire.msgtype).ok_or(ErrorKind::InvalidData)?,
+            sender: wire.sender,
+            routeoffset: route.len(),
+            route: Vec::from(route),
+            ackrequested: wire.txflag & TXFLAG_ACK != 0,
+        })
+    }
+
     pub fn sender(&mut self) -> u8 {
         return self.sender;
     }
@@ -77,6 +244,7 @@ impl FrameHeader {
 
 /// A simple packet indicating the sender, message type, and transmission state
 #[derive(Clone)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
 pub struct Frame {
     txflag: u8, // indicates if chunked
     frameid: u8, // prevent collisions on chunking
@@ -84,11 +252,13 @@ pub struct Frame {
     sender: u8, // which node ID sent this frame?
     routeoffset: u8, // size of array of route for frame
     route: Vec<u8>, // a list of node IDs that frame should pass
+    #[cfg_attr(feature = "serde-support", serde(with = "crate::stack::base64_serde"))]
     payload: Vec<u8>, // payload data
 }
 
 impl Frame {
     /// public construct for Frame
+    #[deprecated(note = "use FrameBuilder, which validates the route and payload")]
     pub fn new(txflag: u8, frameid: u8, msgtype: u8, sender: u8, routeoffset: u8, route: Vec<u8>, payload: Vec<u8>) -> Self {
         Frame {txflag, frameid, msgtype, sender, routeoffset, route, payload }
     }
@@ -96,7 +266,7 @@ impl Frame {
     /// construct a frame from a header and payload
     pub fn from_header(mut header: FrameHeader, payload: Vec<u8>) -> Self {
         Frame{
-            txflag: header.txflag.to_u8(),
+            txflag: header.txflag_byte(),
             frameid: header.frameid,
             msgtype: header.msgtype.to_u8(),
             sender: header.sender,
@@ -108,38 +278,39 @@ impl Frame {
 
     /// convert a frame to bytes
     pub fn to_bytes(&mut self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.push(self.txflag);
-        bytes.push(self.frameid);
-        bytes.push(self.msgtype);
-        bytes.push(self.sender);
-        bytes.push(self.routeoffset);
-        self.route.iter().for_each(|n| bytes.push(n.clone()));
+        let wire = WireHeader {
+            txflag: self.txflag,
+            frameid: self.frameid,
+            msgtype: self.msgtype,
+            sender: self.sender,
+            routeoffset: self.routeoffset,
+        };
+        let mut bytes = Vec::with_capacity(FRAME_HEADER_LEN + self.route.len() + self.payload.len());
+        bytes.extend_from_slice(wire.as_bytes());
+        bytes.extend_from_slice(&self.route);
 
         // push data, if any
-        self.payload.iter().for_each(|d| bytes.push(d.clone()));
+        bytes.extend_from_slice(&self.payload);
 
         return bytes;
     }
 
     /// parse from raw bytes
+    /* Unknown message types and states are rejected here, as the header
+    accessors rely on them being valid. */
     pub fn from_bytes(bytes: &Vec<u8>) -> std::io::Result<Self> {
-        let txflag = bytes.get(0).ok_or(ErrorKind::InvalidData)?.clone();
-        let frameid = bytes.get(1).ok_or(ErrorKind::InvalidData)?.clone();
-        let msgtype = bytes.get(2).ok_or(ErrorKind::InvalidData)?.clone();
-        let sender = bytes.get(3).ok_or(ErrorKind::InvalidData)?.clone();
-        let routeoffset = bytes.get(4).ok_or(ErrorKind::InvalidData)?.clone();
-        let routes = bytes.get(5..(5+routeoffset as usize)).ok_or(ErrorKind::InvalidData)?;
-        let (_left, right) = bytes.split_at(5+routeoffset as usize);
+        let (wire, route, payload) = split_wire(bytes)?;
+        TransmissionState::n(wire.txflag & TXFLAG_STATE).ok_or(ErrorKind::InvalidData)?;
+        MessageType::n(wire.msgtype).ok_or(ErrorKind::InvalidData)?;
 
         Ok(Frame {
-            txflag,
-            frameid,
-            msgtype,
-            sender,
-            routeoffset,
-            route: Vec::from(routes),
-            payload: Vec::from(right)
+            txflag: wire.txflag,
+            frameid: wire.frameid,
+            msgtype: wire.msgtype,
+            sender: wire.sender,
+            routeoffset: wire.routeoffset,
+            route: Vec::from(route),
+            payload: Vec::from(payload)
         })
     }
 
@@ -153,9 +324,48 @@ impl Frame {
 
     /// insert a hop at the beginning of the route
     /// useful for when a message is rebroadcasted
-    pub fn route_unshift(&mut self, nodeid: u8) {
+    /// fails if the route already holds `MAX_ROUTE_LEN` hops
+    pub fn route_unshift(&mut self, nodeid: u8) -> std::io::Result<()> {
+        if self.route.len() >= MAX_ROUTE_LEN {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, "route too long"));
+        }
         self.route.insert(0, nodeid);
         self.routeoffset += 1;
+        Ok(())
+    }
+
+    /// add a hop at the end of the route
+    /// fails if the route already holds `MAX_ROUTE_LEN` hops
+    pub fn append_to_route(&mut self, hop_id: u8) -> std::io::Result<()> {
+        if self.route.len() >= MAX_ROUTE_LEN {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, "route too long"));
+        }
+        self.route.push(hop_id);
+        self.routeoffset += 1;
+        Ok(())
+    }
+
+    /// the same frame with a new route, for forwarding
+    /// fails if the route is longer than `MAX_ROUTE_LEN` hops
+    pub fn with_route(mut self, route: Vec<u8>) -> std::io::Result<Frame> {
+        if route.len() > MAX_ROUTE_LEN {
+            return Err(FrameError::RouteTooLong(route.len()).into());
+        }
+        self.routeoffset = route.len() as u8;
+        self.route = route;
+        Ok(self)
+    }
+
+    /// the same frame with a new payload
+    pub fn with_payload(mut self, payload: Vec<u8>) -> Frame {
+        self.payload = payload;
+        self
+    }
+
+    /// the same frame with new transmission flags, see `TransmissionState`
+    pub fn with_flags(mut self, flags: u8) -> Frame {
+        self.txflag = flags;
+        self
     }
 
     /// chunk a frame into multiple frames
@@ -169,7 +379,7 @@ impl Frame {
             chunk.extend(datachunk.iter());
             // set tx flag
             if i < (payloadchunks.len()-1) {
-                chunk[0] = 1 as u8;
+                chunk[0] |= TransmissionState::MoreChunks.to_u8();
             }
             chunks.push(chunk);
         }
@@ -177,6 +387,35 @@ impl Frame {
         return chunks;
     }
 
+    /// Chunk a frame into frames the radio can send in one packet each
+    /* A chunk carries at most `maxchunk` bytes of the payload, less when
+    the radio settings leave less room after the header, the route and
+    `FRAME_SEAL_LEN`. Fails when they leave none. */
+    pub fn radio_chunked(&mut self, maxchunk: usize, sf: u8, bw: u32, cr_numerator: u8) -> Result<Vec<Vec<u8>>, FrameError> {
+        let room = max_payload_for(sf, bw, cr_numerator).saturating_sub(FRAME_HEADER_LEN + self.route.len() + FRAME_SEAL_LEN);
+        if room == 0 {
+            return Err(FrameError::PayloadTooLarge { size: self.payload.len(), max: 0 });
+        }
+        let payloadchunks = chunk_data(self.payload.clone(), &maxchunk.min(room));
+        let last = payloadchunks.len() - 1;
+        let mut chunks = Vec::with_capacity(payloadchunks.len());
+        for (i, datachunk) in payloadchunks.into_iter().enumerate() {
+            let txflag = if i < last { TransmissionState::MoreChunks } else { self.txflag() };
+            let mut chunk = FrameBuilder::new()
+                .txflag(txflag)
+                .frameid(self.frameid)
+                .message_type(self.msgtype())
+                .sender(self.sender)
+                .route(&self.route)
+                .payload(datachunk)
+                .radio(sf, bw, cr_numerator)
+                .build()?
+                .with_ack_requested(self.ack_requested());
+            chunks.push(chunk.to_bytes());
+        }
+        Ok(chunks)
+    }
+
     pub fn header(&mut self) -> FrameHeader {
         return FrameHeader{
             txflag: self.txflag(),
@@ -184,12 +423,102 @@ impl Frame {
             msgtype: self.msgtype(),
             sender: self.sender(),
             routeoffset: self.route().len(),
-            route: self.route()
+            route: self.route(),
+            ackrequested: self.ack_requested(),
         };
     }
 
     pub fn txflag(&mut self) -> TransmissionState {
-        return TransmissionState::n(self.txflag as u8).unwrap();
+        return TransmissionState::n(self.txflag & TXFLAG_STATE).unwrap();
+    }
+
+    /// true if the sender wants an `AckMessage` once the frame arrives
+    pub fn ack_requested(&self) -> bool {
+        self.txflag & TXFLAG_ACK != 0
+    }
+
+    /// the same frame asking, or not, for an ack from its destination
+    pub fn with_ack_requested(mut self, requested: bool) -> Frame {
+        if requested {
+            self.txflag |= TXFLAG_ACK;
+        } else {
+            self.txflag &= !TXFLAG_ACK;
+        }
+        self
+    }
+
+    /// true if the frame ends in an HMAC tag
+    pub fn signed(&self) -> bool {
+        self.txflag & TXFLAG_SIGNED != 0
+    }
+
+    /// The same frame with a tag proving it was sent by a holder of `key`
+    /* The tag is the first 8 bytes of the HMAC-SHA256 of the serialized
+    frame, with the signed flag set, and is appended to the payload.
+    Relays change the route, so every hop signs the frame again. */
+    pub fn sign(&self, key: &FrameKey) -> Frame {
+        let mut signed = self.clone().without_signature();
+        signed.txflag |= TXFLAG_SIGNED;
+        let tag = frame_mac(key, &signed.to_bytes()).finalize().into_bytes();
+        signed.payload.extend_from_slice(&tag[..FRAME_TAG_LEN]);
+        signed
+    }
+
+    /// Check the tag of a signed frame against `key`
+    pub fn verify(&self, key: &FrameKey) -> std::io::Result<()> {
+        if !self.signed() || self.payload.len() < FRAME_TAG_LEN {
+            return Err(mkerror("frame is not signed"));
+        }
+        let mut unsigned = self.clone();
+        let tag = unsigned.payload.split_off(self.payload.len() - FRAME_TAG_LEN);
+        // the comparison takes the same time wherever the tags differ
+        frame_mac(key, &unsigned.to_bytes()).verify_truncated_left(&tag)
+            .map_err(|_| mkerror("frame signature does not match"))
+    }
+
+    /// true if the payload is encrypted
+    pub fn encrypted(&self) -> bool {
+        self.txflag & TXFLAG_ENCRYPTED != 0
+    }
+
+    /// The same frame with its payload padded and encrypted under `key`
+    /* The payload, its true length ahead of it, is padded to a multiple
+    of `bucket` bytes but no further than `max`, see `pad_payload`, and
+    goes behind `nonce`, which must never be used twice with a key. Only
+    the tag of `sign` authenticates it, so sign it afterwards. Relays
+    decrypt and encrypt the frame again, like they sign it. */
+    pub fn encrypt(&self, key: &FrameKey, nonce: [u8; FRAME_NONCE_LEN], bucket: usize, max: usize) -> Frame {
+        let mut encrypted = self.clone().without_signature();
+        let mut data = pad_payload(&encrypted.payload, bucket, max);
+        frame_cipher(key, &nonce).apply_keystream(&mut data);
+        encrypted.payload = nonce.to_vec();
+        encrypted.payload.extend_from_slice(&data);
+        encrypted.txflag |= TXFLAG_ENCRYPTED;
+        encrypted
+    }
+
+    /// The same frame with its payload decrypted under `key` and the padding taken off
+    pub fn decrypt(&self, key: &FrameKey) -> std::io::Result<Frame> {
+        if !self.encrypted() || self.payload.len() < FRAME_NONCE_LEN {
+            return Err(mkerror("frame is not encrypted"));
+        }
+        let (nonce, data) = self.payload.split_at(FRAME_NONCE_LEN);
+        let mut data = data.to_vec();
+        frame_cipher(key, nonce).apply_keystream(&mut data);
+        let mut decrypted = self.clone();
+        decrypted.payload = unpad_payload(&data)?;
+        decrypted.txflag &= !TXFLAG_ENCRYPTED;
+        Ok(decrypted)
+    }
+
+    /// The same frame without its tag, once it was checked
+    pub fn without_signature(mut self) -> Frame {
+        if self.signed() {
+            let len = self.payload.len().saturating_sub(FRAME_TAG_LEN);
+            self.payload.truncate(len);
+            self.txflag &= !TXFLAG_SIGNED;
+        }
+        self
     }
 
     pub fn frameid(&mut self) -> u8 {
@@ -221,6 +550,134 @@ impl Frame {
     }
 }
 
+/// Reasons a frame could not be built
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrameError {
+    /// a required field was never set
+    MissingField(&'static str),
+    /// the route has more than `MAX_ROUTE_LEN` hops
+    RouteTooLong(usize),
+    /// the payload exceeds the allowed size
+    PayloadTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::MissingField(field) => write!(f, "frame is missing {}", field),
+            FrameError::RouteTooLong(len) => write!(f, "route of {} hops is too long", len),
+            FrameError::PayloadTooLarge { size, max } => write!(f, "payload of {} bytes exceeds maximum of {} bytes", size, max),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for std::io::Error {
+    fn from(e: FrameError) -> Self {
+        std::io::Error::new(ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Builds a frame with typed fields, computing the route offset
+/// and validating sizes
+#[derive(Clone, Debug)]
+pub struct FrameBuilder {
+    txflag: TransmissionState,
+    frameid: u8,
+    msgtype: Option<MessageType>,
+    sender: Option<NodeId>,
+    route: Vec<NodeId>,
+    payload: Vec<u8>,
+    maxpayload: usize,
+    radiolimit: Option<usize>,
+}
+
+impl FrameBuilder {
+    pub fn new() -> Self {
+        FrameBuilder {
+            txflag: TransmissionState::FinalChunk,
+            frameid: 0,
+            msgtype: None,
+            sender: None,
+            route: Vec::new(),
+            payload: Vec::new(),
+            maxpayload: MAX_PAYLOAD_LEN,
+            radiolimit: None,
+        }
+    }
+
+    pub fn txflag(mut self, txflag: TransmissionState) -> Self {
+        self.txflag = txflag;
+        self
+    }
+
+    pub fn frameid(mut self, frameid: u8) -> Self {
+        self.frameid = frameid;
+        self
+    }
+
+    pub fn message_type(mut self, msgtype: MessageType) -> Self {
+        self.msgtype = Some(msgtype);
+        self
+    }
+
+    pub fn sender(mut self, sender: NodeId) -> Self {
+        self.sender = Some(sender);
+        self
+    }
+
+    pub fn route(mut self, route: &[NodeId]) -> Self {
+        self.route = Vec::from(route);
+        self
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// override the maximum payload size, defaults to [`MAX_PAYLOAD_LEN`]
+    pub fn max_payload(mut self, max: usize) -> Self {
+        self.maxpayload = max;
+        self
+    }
+
+    /// limit the frame to what the radio can send in one packet with these settings
+    /* The header, the route and room to encrypt and sign the frame count
+    against the packet, the payload gets what is left. */
+    pub fn radio(mut self, sf: u8, bw: u32, cr_numerator: u8) -> Self {
+        self.radiolimit = Some(max_payload_for(sf, bw, cr_numerator));
+        self
+    }
+
+    /// validate the fields and assemble the frame
+    pub fn build(self) -> Result<Frame, FrameError> {
+        let msgtype = self.msgtype.ok_or(FrameError::MissingField("message type"))?;
+        let sender = self.sender.ok_or(FrameError::MissingField("sender"))?;
+        if self.route.len() > MAX_ROUTE_LEN {
+            return Err(FrameError::RouteTooLong(self.route.len()));
+        }
+        let max = match self.radiolimit {
+            Some(limit) => self.maxpayload.min(limit.saturating_sub(FRAME_HEADER_LEN + self.route.len() + FRAME_SEAL_LEN)),
+            None => self.maxpayload,
+        };
+        if self.payload.len() > max {
+            return Err(FrameError::PayloadTooLarge { size: self.payload.len(), max });
+        }
+
+        Ok(Frame {
+            txflag: self.txflag.to_u8(),
+            frameid: self.frameid,
+            msgtype: msgtype.to_u8(),
+            sender,
+            routeoffset: self.route.len() as u8,
+            route: self.route,
+            payload: self.payload,
+        })
+    }
+}
+
 /// take a list of received chunked frames and recombine their payload
 pub fn recombine_chunks(chunks: Vec<Frame>, header: FrameHeader) -> Frame {
     let mut combinedbytes = Vec::new();
@@ -238,7 +695,8 @@ pub fn recombine_chunks(chunks: Vec<Frame>, header: FrameHeader) -> Frame {
 pub trait ToFromFrame {
     fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>>;
 
-    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> Frame;
+    /// fails when the route or payload do not fit a frame
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> std::io::Result<Frame>;
 }
 
 #[cfg(test)]
@@ -257,7 +715,7 @@ fn frame_chunking() {
     assert_eq!(&hex1, &hex::encode(&raw));
 
     let msg = IPPacketMessage::new(packet);
-    let mut frame = msg.to_frame(1u8, sender, Vec::new());
+    let mut frame = msg.to_frame(1u8, sender, Vec::new()).unwrap();
 
     let chunksize = 45usize;
     let framesize = chunksize.clone()+5usize;
@@ -290,4 +748,268 @@ fn frame_chunking() {
 
     assert_eq!(&raw3[0], &raw[0]);
     assert_eq!(&raw3[50], &raw[50]);
-}
\ No newline at end of file
+}
+
+#[test]
+fn frame_builder() {
+    let mut frame = FrameBuilder::new()
+        .frameid(7)
+        .message_type(MessageType::Broadcast)
+        .sender(3)
+        .route(&[3, 4])
+        .payload(vec![1, 2, 3])
+        .build()
+        .expect("Valid frame");
+
+    assert_eq!(frame.routeoffset(), 2);
+    assert_eq!(frame.to_bytes(), vec![0, 7, 1, 3, 2, 3, 4, 1, 2, 3]);
+
+    // missing fields and oversize data are rejected
+    let err = FrameBuilder::new().sender(3).build().err().unwrap();
+    assert_eq!(err, FrameError::MissingField("message type"));
+
+    let err = FrameBuilder::new()
+        .message_type(MessageType::IPPacket)
+        .sender(3)
+        .route(&vec![1u8; 256])
+        .build()
+        .err().unwrap();
+    assert_eq!(err, FrameError::RouteTooLong(256));
+
+    let err = FrameBuilder::new()
+        .message_type(MessageType::IPPacket)
+        .sender(3)
+        .payload(vec![0u8; 11])
+        .max_payload(10)
+        .build()
+        .err().unwrap();
+    assert_eq!(err, FrameError::PayloadTooLarge { size: 11, max: 10 });
+
+    // the radio settings bound the payload
+    let err = FrameBuilder::new()
+        .message_type(MessageType::IPPacket)
+        .sender(3)
+        .route(&[1, 2])
+        .payload(vec![0u8; 35])
+        .radio(12, 125, 5)
+        .build()
+        .err().unwrap();
+    // 59 bytes less the header, route, nonce, length and tag
+    assert_eq!(err.to_string(), "payload of 35 bytes exceeds maximum of 34 bytes");
+    assert!(FrameBuilder::new()
+        .message_type(MessageType::IPPacket)
+        .sender(3)
+        .route(&[1, 2])
+        .payload(vec![0u8; 35])
+        .radio(9, 125, 5)
+        .build()
+        .is_ok());
+}
+
+#[test]
+fn frame_radio_chunking() {
+    let mut frame = FrameBuilder::new()
+        .frameid(9)
+        .message_type(MessageType::IPPacket)
+        .sender(3)
+        .route(&[3, 4])
+        .payload((0..150).collect())
+        .build()
+        .unwrap()
+        .with_ack_requested(true);
+
+    // SF12 at 125 kHz leaves 34 bytes a chunk, fewer than the 200 asked for
+    let chunks = frame.radio_chunked(200, 12, 125, 5).unwrap();
+    assert_eq!(chunks.len(), 5);
+    for chunk in &chunks {
+        assert!(chunk.len() + FRAME_SEAL_LEN <= max_payload_for(12, 125, 5));
+    }
+    let mut parsed: Vec<Frame> = chunks.iter().map(|bytes| Frame::from_bytes(bytes).unwrap()).collect();
+    assert!(parsed[..4].iter_mut().all(|chunk| chunk.txflag().more_chunks() && chunk.ack_requested()));
+    assert!(!parsed[4].txflag().more_chunks());
+    let header = parsed[0].header();
+    let mut whole = recombine_chunks(parsed, header);
+    assert_eq!((whole.frameid(), whole.route(), whole.payload()), (9, vec![3, 4], (0..150).collect::<Vec<u8>>()));
+
+    // a faster setting leaves more room than asked for, and settings the radio lacks none
+    assert_eq!(frame.radio_chunked(100, 7, 125, 5).unwrap().len(), 2);
+    assert!(frame.radio_chunked(100, 6, 125, 5).is_err());
+}
+
+#[test]
+fn radio_payload_limits() {
+    assert_eq!(max_payload_for(12, 125, 5), 59);
+    assert_eq!(max_payload_for(10, 125, 8), 59);
+    assert_eq!(max_payload_for(9, 125, 5), 123);
+    assert_eq!(max_payload_for(7, 125, 5), 250);
+    assert_eq!(max_payload_for(10, 250, 5), 123);
+    assert_eq!(max_payload_for(12, 500, 5), 61);
+    assert_eq!(max_payload_for(11, 500, 6), 137);
+    assert_eq!(max_payload_for(8, 500, 7), 250);
+    // out of range settings allow nothing
+    assert_eq!(max_payload_for(6, 125, 5), 0);
+    assert_eq!(max_payload_for(7, 125, 4), 0);
+    assert_eq!(max_payload_for(7, 62, 5), 0);
+    // usable in constants
+    const LIMIT: usize = max_payload_for(12, 125, 5);
+    assert_eq!(LIMIT, 59);
+}
+
+#[test]
+fn frame_with_fields() {
+    let original = FrameBuilder::new()
+        .frameid(7)
+        .message_type(MessageType::Position)
+        .sender(3)
+        .route(&[3])
+        .payload(vec![1, 2, 3])
+        .build()
+        .expect("Valid frame");
+
+    // forwarding node adds itself to the route
+    let mut forwarded = original.clone().with_route(vec![5, 3]).unwrap();
+    assert_eq!(forwarded.routeoffset(), 2);
+    assert_eq!(forwarded.to_bytes(), vec![0, 7, 17, 3, 2, 5, 3, 1, 2, 3]);
+
+    let mut changed = original.clone().with_payload(vec![9]).with_flags(TransmissionState::MoreChunks.to_u8());
+    assert_eq!(changed.payload(), vec![9]);
+    assert_eq!(changed.txflag(), TransmissionState::MoreChunks);
+    let mut parsed = Frame::from_bytes(&changed.to_bytes()).unwrap();
+    assert_eq!((parsed.sender(), parsed.route(), parsed.payload()), (3, vec![3], vec![9]));
+
+    // the original is untouched
+    let mut original = original;
+    assert_eq!(original.to_bytes(), vec![0, 7, 17, 3, 1, 3, 1, 2, 3]);
+
+    // hops go on the end of the route until it is full
+    original.append_to_route(8).unwrap();
+    assert_eq!(original.to_bytes(), vec![0, 7, 17, 3, 2, 3, 8, 1, 2, 3]);
+    for hop in 2..MAX_ROUTE_LEN {
+        original.append_to_route(hop as u8).unwrap();
+    }
+    assert_eq!(original.append_to_route(99).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!((original.routeoffset() as usize, original.route().len()), (MAX_ROUTE_LEN, MAX_ROUTE_LEN));
+    // nor can a relay put itself in front, or a route that long be set or built
+    assert!(original.route_unshift(99).is_err());
+    assert!(original.clone().with_route(vec![1; MAX_ROUTE_LEN + 1]).is_err());
+    assert!(FrameBuilder::new().message_type(MessageType::Ping).sender(1).route(&[1; MAX_ROUTE_LEN + 1]).build().is_err());
+}
+
+#[test]
+fn frame_header_wire() {
+    assert_eq!(FRAME_HEADER_LEN, 5);
+
+    let bytes = vec![1u8, 9, 2, 4, 2, 4, 6, 0xff];
+    let mut header = FrameHeader::from_bytes(&bytes).expect("Valid header");
+    assert_eq!(header.txflag, TransmissionState::MoreChunks);
+    assert_eq!(header.msgtype, MessageType::IPAssignSuccess);
+    assert_eq!(header.sender(), 4);
+    assert_eq!(header.route(), vec![4, 6]);
+    assert_eq!(header.bytes(), &bytes[..7]);
+
+    // truncated routes and unknown enum values are rejected
+    assert!(FrameHeader::from_bytes(&[0, 1, 1, 4, 3, 4]).is_err());
+    let mut toolong = vec![0, 1, 1, 4, MAX_ROUTE_LEN as u8 + 1];
+    toolong.resize(toolong.len() + MAX_ROUTE_LEN + 1, 2);
+    assert!(Frame::from_bytes(&toolong).is_err());
+    assert!(FrameHeader::from_bytes(&[0, 1, 200, 4, 0]).is_err());
+    assert!(Frame::from_bytes(&vec![0, 1, 1]).is_err());
+    assert!(Frame::from_bytes(&vec![0, 1, 200, 4, 0]).is_err());
+
+    // the sender hears why, if the header names it
+    assert_eq!(frame_error(&[0, 1, 200, 4, 0]), Some((4, 1, MeshError::UnknownMessageType(200))));
+    assert_eq!(frame_error(&[0, 1, 1]), None);
+    let mut long = vec![0, 7, 21, 4, 0];
+    assert_eq!(frame_error(&long), None);
+    long.resize(MAX_FRAME_LEN + 1, 0);
+    assert_eq!(frame_error(&long), Some((4, 7, MeshError::FrameTooLong)));
+}
+
+#[test]
+fn frame_signing() {
+    let key = parse_frame_key(&"5a".repeat(32)).unwrap();
+    assert!(parse_frame_key("5a5a").is_err());
+    let frame = FrameBuilder::new()
+        .frameid(3)
+        .message_type(MessageType::Data)
+        .sender(1)
+        .route(&[2, 4])
+        .payload(vec![9, 8, 7])
+        .build()
+        .unwrap()
+        .with_ack_requested(true);
+    assert!(!frame.signed());
+    assert!(frame.verify(&key).is_err());
+
+    // the tag is the last bytes on the air, the flags tell it is there
+    let mut signed = frame.sign(&key);
+    let bytes = signed.to_bytes();
+    assert_eq!(bytes.len(), frame.clone().to_bytes().len() + FRAME_TAG_LEN);
+    assert_eq!(bytes[0], TXFLAG_ACK | TXFLAG_SIGNED);
+    let mut received = Frame::from_bytes(&bytes).unwrap();
+    assert!(received.signed() && received.ack_requested());
+    assert_eq!(received.txflag(), TransmissionState::FinalChunk);
+    assert!(received.verify(&key).is_ok());
+    assert!(received.verify(&[0x5b; 32]).is_err());
+    assert_eq!(received.clone().without_signature().to_bytes(), frame.clone().to_bytes());
+
+    // any changed byte breaks it, the flag included
+    for i in 0..bytes.len() {
+        let mut tampered = bytes.clone();
+        tampered[i] ^= 0x04;
+        if let Ok(tampered) = Frame::from_bytes(&tampered) {
+            assert!(tampered.verify(&key).is_err(), "byte {} changed", i);
+        }
+    }
+    let mut stripped = bytes.clone();
+    stripped[0] &= !TXFLAG_SIGNED;
+    assert!(Frame::from_bytes(&stripped).unwrap().verify(&key).is_err());
+
+    // a relay signs the frame again once it took itself off the route
+    received.route_shift();
+    assert!(received.verify(&key).is_err());
+    assert!(received.sign(&key).verify(&key).is_ok());
+    assert_eq!(received.sign(&key).payload().len(), 3 + FRAME_TAG_LEN);
+}
+
+#[test]
+fn frame_encryption() {
+    let key = parse_frame_key(&"5a".repeat(32)).unwrap();
+    let frame = FrameBuilder::new()
+        .frameid(3)
+        .message_type(MessageType::Data)
+        .sender(1)
+        .route(&[2, 4])
+        .payload(b"meet at the north gate".to_vec())
+        .build()
+        .unwrap()
+        .with_ack_requested(true);
+    assert!(!frame.encrypted() && frame.decrypt(&key).is_err());
+
+    // the payload is hidden behind the nonce, the header is not
+    let encrypted = frame.encrypt(&key, [1; FRAME_NONCE_LEN], 1, 200).sign(&key);
+    let bytes = encrypted.clone().to_bytes();
+    assert_eq!(bytes[0], TXFLAG_ACK | TXFLAG_SIGNED | TXFLAG_ENCRYPTED);
+    assert_eq!(bytes.len(), frame.clone().to_bytes().len() + FRAME_SEAL_LEN);
+    assert!(!bytes.windows(5).any(|w| w == b"north"));
+    let mut received = Frame::from_bytes(&bytes).unwrap();
+    assert!(received.encrypted() && received.ack_requested());
+    assert_eq!((received.frameid(), received.route()), (3, vec![2, 4]));
+    received.verify(&key).unwrap();
+    let mut decrypted = received.without_signature().decrypt(&key).unwrap();
+    assert!(!decrypted.encrypted());
+    assert_eq!(decrypted.to_bytes(), frame.clone().to_bytes());
+
+    // another nonce, another ciphertext, and another key reads nothing useful
+    let again = frame.encrypt(&key, [2; FRAME_NONCE_LEN], 1, 200);
+    assert_ne!(again.clone().payload(), encrypted.without_signature().payload());
+    assert_ne!(again.decrypt(&[0x5b; 32]).map(|mut f| f.payload()).ok(), Some(frame.clone().payload()));
+
+    // padded to the bucket, the true length comes back out
+    for size in [0usize, 1, 29, 30, 31, 100].iter().cloned() {
+        let frame = frame.clone().with_payload(vec![7; size]);
+        let mut padded = frame.encrypt(&key, [3; FRAME_NONCE_LEN], 32, 200);
+        assert_eq!((padded.payload().len() - FRAME_NONCE_LEN) % 32, 0);
+        assert_eq!(padded.decrypt(&key).unwrap().payload(), vec![7; size]);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use rand::{thread_rng, Rng};
+use crate::stack::antireplay::MAX_AHEAD;
+use crate::stack::frame::NodeId;
+
+/// Hands out frame IDs from an independent counter per destination
+/* IDs run from 1 to 255 and wrap back to 1. Each counter starts at a
+random offset so frames to different destinations rarely share an ID,
+except the broadcast counter which starts at 0, the only time 0 is handed
+out, so nodes hearing it know we restarted. An ID still waiting on an ack is skipped until it is
+acknowledged or its timer runs out. Clones share the same counters, so
+the allocator can be handed to every thread that submits frames.
+Counters saved before a restart resume `MAX_AHEAD` IDs on, past those
+sent after the save as long as there were fewer, and still ahead of what
+the destination last saw. */
+#[derive(Clone, Debug, Default)]
+pub struct FrameIdAllocator {
+    state: Arc<Mutex<AllocatorState>>,
+}
+
+#[derive(Debug, Default)]
+struct AllocatorState {
+    // last ID handed out per destination, None is broadcast
+    last: HashMap<Option<NodeId>, u8>,
+    // IDs waiting on an ack, with their deadline
+    outstanding: HashMap<Option<NodeId>, HashMap<u8, Instant>>,
+}
+
+/// the ID after `id`, wrapping around past 255
+fn next_id(id: u8) -> u8 {
+    if id == u8::MAX { 1 } else { id + 1 }
+}
+
+impl FrameIdAllocator {
+    pub fn new() -> Self {
+        FrameIdAllocator::default()
+    }
+
+    /// Next frame ID for a destination, `None` for broadcasts
+    pub fn allocate(&self, dest: Option<NodeId>) -> u8 {
+        self.allocate_at(dest, None, Instant::now())
+            .expect("IDs without an ack timer are always available")
+    }
+
+    /// Next frame ID for a frame that expects an ack within `timeout`
+    /// None if every ID for the destination is still waiting on an ack
+    pub fn allocate_with_ack(&self, dest: Option<NodeId>, timeout: Duration) -> Option<u8> {
+        let now = Instant::now();
+        self.allocate_at(dest, Some(now + timeout), now)
+    }
+
+    fn allocate_at(&self, dest: Option<NodeId>, deadline: Option<Instant>, now: Instant) -> Option<u8> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let outstanding = state.outstanding.entry(dest).or_insert_with(HashMap::new);
+        outstanding.retain(|_, expires| *expires > now);
+
+        if dest.is_none() && !state.last.contains_key(&dest) {
+            state.last.insert(dest, 0);
+            return Some(0);
+        }
+        let last = state.last.entry(dest).or_insert_with(|| thread_rng().gen_range(1u8, 255u8));
+        let mut candidate = next_id(*last);
+        for _ in 0..u8::MAX {
+            if !outstanding.contains_key(&candidate) {
+                *last = candidate;
+                if let Some(expires) = deadline {
+                    outstanding.insert(candidate, expires);
+                }
+                return Some(candidate);
+            }
+            candidate = next_id(candidate);
+        }
+        None
+    }
+
+    /// Release an ID once its ack arrives or is given up on
+    pub fn acknowledge(&self, dest: Option<NodeId>, frameid: u8) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(outstanding) = state.outstanding.get_mut(&dest) {
+            outstanding.remove(&frameid);
+        }
+    }
+
+    /// Last ID handed out for every destination, broadcasts excluded
+    pub fn last(&self) -> Vec<(NodeId, u8)> {
+        let state = self.state.lock().unwrap();
+        let mut last: Vec<(NodeId, u8)> = state.last.iter()
+            .filter_map(|(dest, id)| dest.map(|dest| (dest, *id)))
+            .collect();
+        last.sort();
+        last
+    }
+
+    /// Continue the counters saved before a restart
+    pub fn resume(&self, last: &[(NodeId, u8)]) {
+        let mut state = self.state.lock().unwrap();
+        for (dest, id) in last {
+            let mut resumed = *id;
+            for _ in 1..MAX_AHEAD {
+                resumed = next_id(resumed);
+            }
+            state.last.insert(Some(*dest), resumed);
+        }
+    }
+
+    /// Number of IDs waiting on an ack for a destination
+    pub fn outstanding(&self, dest: Option<NodeId>) -> usize {
+        let state = self.state.lock().unwrap();
+        state.outstanding.get(&dest).map_or(0, |o| o.len())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn frameid_wraparound() {
+    let ids = FrameIdAllocator::new();
+    let first = ids.allocate(Some(2));
+    let mut prev = first;
+    for _ in 0..300 {
+        let id = ids.allocate(Some(2));
+        assert_ne!(id, 0);
+        assert_eq!(id, next_id(prev));
+        prev = id;
+    }
+    assert_eq!(next_id(255), 1);
+    assert_eq!((ids.allocate(None), ids.allocate(None)), (0, 1));
+
+    // after a restart the counters move on, and broadcasts start over to tell
+    let restarted = FrameIdAllocator::new();
+    restarted.resume(&ids.last());
+    assert_eq!(crate::stack::neighbors::frameid_distance(prev, restarted.allocate(Some(2))), MAX_AHEAD);
+    assert_eq!(restarted.allocate(None), 0);
+
+    // outstanding IDs are skipped until acknowledged or expired
+    let now = Instant::now();
+    let later = now + Duration::from_secs(5);
+    let mut held = Vec::new();
+    for _ in 0..255 {
+        held.push(ids.allocate_at(Some(3), Some(later), now).expect("ID available"));
+    }
+    assert_eq!(ids.outstanding(Some(3)), 255);
+    assert_eq!(ids.allocate_at(Some(3), Some(later), now), None);
+
+    ids.acknowledge(Some(3), held[10]);
+    assert_eq!(ids.allocate_at(Some(3), Some(later), now), Some(held[10]));
+    assert!(ids.allocate_at(Some(3), None, later).is_some());
+    assert_eq!(ids.outstanding(Some(3)), 0);
+}
+
+#[test]
+fn frameid_concurrent() {
+    use std::thread;
+    use std::collections::HashSet;
+
+    let ids = FrameIdAllocator::new();
+    let workers: Vec<_> = (0..2).map(|_| {
+        let ids = ids.clone();
+        thread::spawn(move || {
+            (0..120).map(|_| ids.allocate_with_ack(Some(9), Duration::from_secs(60)).unwrap()).collect::<Vec<u8>>()
+        })
+    }).collect();
+
+    let mut seen = HashSet::new();
+    for worker in workers {
+        for id in worker.join().unwrap() {
+            assert!(seen.insert(id), "ID {} handed out twice", id);
+        }
+    }
+    assert_eq!(ids.outstanding(Some(9)), 240);
+}
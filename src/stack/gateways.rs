@@ -0,0 +1,338 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use crate::stack::frame::NodeId;
+
+/// Highest ETX of a single hop, for links we barely hear
+pub(crate) const MAX_HOP_COST: f32 = 10.0;
+
+/// ETX of the hop a frame came in on, from the share of the neighbor's
+/// frames we receive, taken to be the same both ways
+/* A neighbor we have no estimate for yet counts as a perfect hop. */
+pub fn hop_cost(quality: f32) -> f32 {
+    if quality <= 0.0 {
+        return 1.0;
+    }
+    (1.0 / (quality * quality)).min(MAX_HOP_COST)
+}
+
+/// What we last heard from a gateway
+#[derive(Clone, Debug, PartialEq)]
+pub struct GatewayEntry {
+    pub uplink: bool,
+    /// transmit queue use, in percent
+    pub load: u8,
+    /// ETX of our path to the gateway
+    pub cost: f32,
+    pub heard: Instant,
+    /// frames to it that failed on the way since it was last demoted
+    pub failures: u32,
+    /// when too many failures put it behind the other gateways
+    pub demoted: Option<Instant>,
+    /// known from before a restart and not heard since
+    pub stale: bool,
+}
+
+impl GatewayEntry {
+    /// path cost weighed by how busy the gateway is, lower is better
+    pub fn score(&self) -> f32 {
+        self.cost * (1.0 + self.load as f32 / 100.0)
+    }
+
+    /// whether it was demoted less than `timeout` ago
+    pub fn demoted_at(&self, now: Instant, timeout: Duration) -> bool {
+        self.demoted.map_or(false, |demoted| now.duration_since(demoted) < timeout)
+    }
+}
+
+/// A gateway that announced it reaches the internet
+#[derive(Clone, Debug, PartialEq)]
+pub struct DefaultGateway {
+    pub gateway_id: NodeId,
+    /// ETX of our path to it, in hundredths
+    pub metric: u16,
+    pub last_announced: Instant,
+}
+
+/// Every gateway announcing itself, best first
+/* Gateways with a working uplink always come before those without, a
+gateway that stops announcing drops out once `timeout` passes. One that
+keeps announcing while our frames to it fail is demoted behind the others
+for the same time, it is still used when there is no other. Gateways
+restored after a restart come after those heard since. */
+#[derive(Clone, Debug)]
+pub struct GatewayTable {
+    timeout: Duration,
+    /// failures that demote a gateway, 0 never does
+    maxfailures: u32,
+    gateways: HashMap<NodeId, GatewayEntry>,
+}
+
+impl GatewayTable {
+    pub fn new(timeout: Duration) -> Self {
+        GatewayTable { timeout, maxfailures: 0, gateways: HashMap::new() }
+    }
+
+    /// Demote a gateway after this many failed frames
+    pub fn with_max_failures(mut self, maxfailures: u32) -> Self {
+        self.maxfailures = maxfailures;
+        self
+    }
+
+    /// record an announcement
+    pub fn update(&mut self, nodeid: NodeId, uplink: bool, load: u8, cost: f32) {
+        self.update_at(nodeid, uplink, load, cost, Instant::now())
+    }
+
+    pub(crate) fn update_at(&mut self, nodeid: NodeId, uplink: bool, load: u8, cost: f32, now: Instant) {
+        // announcements get through even when our frames don't, they don't undo a demotion
+        let (failures, demoted) = self.gateways.get(&nodeid)
+            .map_or((0, None), |entry| (entry.failures, entry.demoted));
+        self.gateways.insert(nodeid, GatewayEntry { uplink, load, cost, heard: now, failures, demoted, stale: false });
+    }
+
+    /// A gateway known from before a restart, ranked behind every gateway heard since until it announces itself
+    pub fn restore(&mut self, nodeid: NodeId, uplink: bool) {
+        self.restore_at(nodeid, uplink, Instant::now())
+    }
+
+    pub(crate) fn restore_at(&mut self, nodeid: NodeId, uplink: bool, now: Instant) {
+        self.gateways.entry(nodeid).or_insert(GatewayEntry {
+            uplink,
+            load: 0,
+            cost: MAX_HOP_COST,
+            heard: now,
+            failures: 0,
+            demoted: None,
+            stale: true,
+        });
+    }
+
+    /// A frame to a gateway failed on the way, true if that demoted it
+    pub fn failed(&mut self, nodeid: NodeId) -> bool {
+        self.failed_at(nodeid, Instant::now())
+    }
+
+    pub(crate) fn failed_at(&mut self, nodeid: NodeId, now: Instant) -> bool {
+        let (timeout, maxfailures) = (self.timeout, self.maxfailures);
+        let entry = match self.gateways.get_mut(&nodeid) {
+            Some(entry) if maxfailures > 0 => entry,
+            _ => return false,
+        };
+        if entry.demoted_at(now, timeout) {
+            return false;
+        }
+        entry.failures += 1;
+        if entry.failures < maxfailures {
+            return false;
+        }
+        entry.failures = 0;
+        entry.demoted = Some(now);
+        true
+    }
+
+    /// gateways heard within the timeout, best first
+    pub fn ranked(&self) -> Vec<(NodeId, &GatewayEntry)> {
+        self.ranked_at(Instant::now())
+    }
+
+    pub(crate) fn ranked_at(&self, now: Instant) -> Vec<(NodeId, &GatewayEntry)> {
+        let mut ranked: Vec<(NodeId, &GatewayEntry)> = self.gateways.iter()
+            .filter(|(_, entry)| now.duration_since(entry.heard) <= self.timeout)
+            .map(|(id, entry)| (*id, entry))
+            .collect();
+        let timeout = self.timeout;
+        ranked.sort_by(|(a, x), (b, y)| {
+            x.demoted_at(now, timeout).cmp(&y.demoted_at(now, timeout))
+                .then(x.stale.cmp(&y.stale))
+                .then(y.uplink.cmp(&x.uplink))
+                .then(x.score().partial_cmp(&y.score()).unwrap_or(std::cmp::Ordering::Equal))
+                .then(a.cmp(b))
+        });
+        ranked
+    }
+
+    /// the gateway to send gateway-bound traffic to
+    pub fn best(&self) -> Option<NodeId> {
+        self.best_at(Instant::now())
+    }
+
+    pub(crate) fn best_at(&self, now: Instant) -> Option<NodeId> {
+        self.ranked_at(now).first().map(|(id, _)| *id)
+    }
+}
+
+/// Most packets kept to send again through another gateway
+pub const MAX_GATEWAY_BACKLOG: usize = 32;
+
+/// IP packets recently sent out through a gateway
+/* A failed gateway is only noticed a while after our packets to it were
+lost, those sent within `window` are sent again through the gateway
+that takes over. Packets that did arrive are then duplicated, which IP
+copes with. */
+#[derive(Clone, Debug)]
+pub struct GatewayBacklog {
+    window: Duration,
+    packets: VecDeque<(Instant, NodeId, Vec<u8>)>,
+}
+
+impl GatewayBacklog {
+    pub fn new(window: Duration) -> Self {
+        GatewayBacklog { window, packets: VecDeque::new() }
+    }
+
+    /// a packet went out through `gateway`
+    pub fn push(&mut self, gateway: NodeId, packet: Vec<u8>) {
+        self.push_at(gateway, packet, Instant::now())
+    }
+
+    pub(crate) fn push_at(&mut self, gateway: NodeId, packet: Vec<u8>, now: Instant) {
+        self.prune(now);
+        if self.packets.len() >= MAX_GATEWAY_BACKLOG {
+            self.packets.pop_front();
+        }
+        self.packets.push_back((now, gateway, packet));
+    }
+
+    /// Take the packets sent through a gateway that failed, oldest first
+    pub fn requeue(&mut self, gateway: NodeId) -> Vec<Vec<u8>> {
+        self.requeue_at(gateway, Instant::now())
+    }
+
+    pub(crate) fn requeue_at(&mut self, gateway: NodeId, now: Instant) -> Vec<Vec<u8>> {
+        self.prune(now);
+        let (failed, rest): (VecDeque<_>, VecDeque<_>) = self.packets.drain(..).partition(|(_, via, _)| *via == gateway);
+        self.packets = rest;
+        failed.into_iter().map(|(_, _, packet)| packet).collect()
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self.packets.front().map_or(false, |(sent, _, _)| now.duration_since(*sent) >= self.window) {
+            self.packets.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn gateway_failover() {
+    assert_eq!(hop_cost(1.0), 1.0);
+    assert_eq!(hop_cost(0.5), 4.0);
+    assert_eq!(hop_cost(0.01), MAX_HOP_COST);
+    assert_eq!(hop_cost(0.0), 1.0);
+
+    let start = Instant::now();
+    let minute = Duration::from_secs(60);
+    let mut table = GatewayTable::new(minute * 3);
+    assert_eq!(table.best_at(start), None);
+
+    // gateway 1 is closer, gateway 9 further away
+    table.update_at(1, true, 10, 1.0, start);
+    table.update_at(9, true, 10, 3.0, start);
+    assert_eq!(table.best_at(start), Some(1));
+    // a busy gateway loses to a slightly longer path
+    table.update_at(1, true, 100, 2.0, start);
+    assert_eq!(table.best_at(start), Some(9));
+    table.update_at(1, true, 0, 1.0, start);
+
+    // gateway 9 keeps announcing, gateway 1 goes silent
+    for i in 1..=4 {
+        table.update_at(9, true, 10, 3.0, start + minute * i);
+    }
+    assert_eq!(table.best_at(start + minute * 3), Some(1));
+    assert_eq!(table.best_at(start + minute * 4), Some(9));
+    assert_eq!(table.ranked_at(start + minute * 4).len(), 1);
+
+    // it comes back, but without its uplink
+    table.update_at(1, false, 0, 1.0, start + minute * 5);
+    assert_eq!(table.ranked_at(start + minute * 5).iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![9, 1]);
+    table.update_at(1, true, 0, 1.0, start + minute * 6);
+    assert_eq!(table.best_at(start + minute * 6), Some(1));
+
+    // a gateway from before a restart comes last until it announces itself
+    table.restore_at(4, true, start + minute * 6);
+    assert_eq!(table.ranked_at(start + minute * 6).last().map(|(id, _)| *id), Some(4));
+    table.update_at(4, true, 0, 0.5, start + minute * 6);
+    assert_eq!(table.best_at(start + minute * 6), Some(4));
+}
+
+#[test]
+fn gateway_demotion() {
+    let start = Instant::now();
+    let interval = Duration::from_secs(60);
+    let second = Duration::from_secs(1);
+
+    // both gateways announce every minute until gateway 1 dies after two,
+    // we send a packet every 10 seconds and learn of each lost one 15 seconds later
+    // returns how long traffic took to resume through gateway 9, and how many packets were sent again
+    let run = |maxfailures: u32| -> (Duration, usize) {
+        let died = start + interval * 2;
+        let mut table = GatewayTable::new(interval * 3).with_max_failures(maxfailures);
+        let mut backlog = GatewayBacklog::new(second * 30);
+        let mut failures: Vec<Instant> = Vec::new();
+        let mut current = None;
+        let mut requeued = 0;
+        for tick in 0..1200u32 {
+            let now = start + second * tick;
+            if tick % 60 == 0 {
+                if now < died {
+                    table.update_at(1, true, 0, 1.0, now);
+                }
+                table.update_at(9, true, 0, 2.0, now);
+            }
+            for _ in 0..failures.iter().filter(|shows| **shows == now).count() {
+                table.failed_at(1, now);
+            }
+            let best = table.best_at(now);
+            if best != current {
+                if let Some(old) = current {
+                    requeued += backlog.requeue_at(old, now).len();
+                }
+                current = best;
+            }
+            if tick % 10 == 0 {
+                let gateway = current.unwrap();
+                if gateway == 9 {
+                    return (now - died, requeued);
+                }
+                backlog.push_at(gateway, vec![tick as u8], now);
+                if now >= died {
+                    failures.push(now + second * 15);
+                }
+            }
+        }
+        panic!("traffic never resumed");
+    };
+
+    // three failures demote gateway 1 within one announcement period
+    let (resumed, requeued) = run(3);
+    assert!(resumed < interval, "resumed after {:?}", resumed);
+    assert_eq!(requeued, 3);
+    // without them it takes the three missed announcements
+    let (resumed, requeued) = run(0);
+    assert!(resumed > interval * 2 && resumed <= interval * 3, "resumed after {:?}", resumed);
+    assert_eq!(requeued, 3);
+
+    // a demoted gateway is still used when it is the only one, and stays demoted while announcing
+    let mut table = GatewayTable::new(interval * 3).with_max_failures(2);
+    table.update_at(1, true, 0, 1.0, start);
+    assert!(!table.failed_at(1, start));
+    assert!(table.failed_at(1, start));
+    assert_eq!(table.best_at(start), Some(1));
+    table.update_at(9, true, 0, 5.0, start + interval);
+    table.update_at(1, true, 0, 1.0, start + interval);
+    assert_eq!(table.best_at(start + interval), Some(9));
+    assert!(!table.failed_at(1, start + interval));
+    table.update_at(9, true, 0, 5.0, start + interval * 3);
+    table.update_at(1, true, 0, 1.0, start + interval * 3);
+    assert_eq!(table.best_at(start + interval * 3), Some(1));
+
+    // the backlog only keeps the most recent packets
+    let mut backlog = GatewayBacklog::new(interval);
+    for i in 0..MAX_GATEWAY_BACKLOG + 2 {
+        backlog.push_at(1, vec![i as u8], start);
+    }
+    backlog.push_at(9, vec![0], start);
+    let packets = backlog.requeue_at(1, start);
+    assert_eq!((packets.len(), packets[0].clone()), (MAX_GATEWAY_BACKLOG - 1, vec![3]));
+    assert_eq!(backlog.requeue_at(9, start + interval), Vec::<Vec<u8>>::new());
+}
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::frame::NodeId;
+use crate::stack::mtu::set_checksum;
+
+/// Most flows compressed towards one peer at a time, the least recently used makes room
+pub const MAX_HEADER_CONTEXTS: usize = 16;
+
+/// Packets of a flow sent compressed before its full headers are sent again
+pub const HEADER_REFRESH: u32 = 32;
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+
+/// bits of the change mask leading compressed data
+/* A packet with full headers sets up or refreshes its context. The others
+carry only what changed since the previous packet of the flow, in the
+order of the bits, and the transport checksum last. */
+const COMP_FULL: u8 = 0x80;
+const COMP_IPID: u8 = 0x01;
+const COMP_SEQ: u8 = 0x02;
+const COMP_ACK: u8 = 0x04;
+const COMP_WINDOW: u8 = 0x08;
+const COMP_OPTIONS: u8 = 0x10;
+const COMP_FLAGS: u8 = 0x20;
+
+/// Addresses, protocol and ports, which stay the same for all packets of a flow
+type FlowKey = ([u8; 8], u8, [u8; 4]);
+
+/// Headers of the last packet sent in a flow
+#[derive(Clone, Debug)]
+struct FlowContext {
+    id: u8,
+    key: FlowKey,
+    header: Vec<u8>,
+    /// packets sent compressed since the last full headers
+    compressed: u32,
+    /// when it was last used, to find the least recently used
+    used: u64,
+}
+
+/// Length of the IPv4 and TCP or UDP headers of a packet we can compress
+/* Fragments, IP options and TCP's urgent data are left alone, they are
+rare enough not to be worth a context. */
+fn header_len(packet: &[u8]) -> Option<usize> {
+    if packet.len() < 28 || packet[0] != 0x45 || u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0 {
+        return None;
+    }
+    if u16::from_be_bytes([packet[2], packet[3]]) as usize != packet.len() {
+        return None;
+    }
+    match packet[9] {
+        UDP => Some(28),
+        TCP => {
+            let len = 20 + (packet.get(32)? >> 4) as usize * 4;
+            if len < 40 || packet.len() < len || packet[33] & 0x20 != 0 {
+                return None;
+            }
+            Some(len)
+        },
+        _ => None,
+    }
+}
+
+fn flow_key(packet: &[u8]) -> FlowKey {
+    let mut addrs = [0; 8];
+    addrs.copy_from_slice(&packet[12..20]);
+    let mut ports = [0; 4];
+    ports.copy_from_slice(&packet[20..24]);
+    (addrs, packet[9], ports)
+}
+
+/// true if the TCP or UDP checksum of a packet is right
+fn transport_checksum_ok(packet: &[u8]) -> bool {
+    let at = if packet[9] == TCP { 16 } else { 6 };
+    let sent = [packet[20 + at], packet[21 + at]];
+    // UDP senders may leave the checksum out
+    if packet[9] == UDP && sent == [0, 0] {
+        return true;
+    }
+    let segment = &packet[20..];
+    let mut data = packet[12..20].to_vec();
+    data.extend_from_slice(&[0, packet[9]]);
+    data.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    data.extend_from_slice(segment);
+    set_checksum(&mut data, 12 + at);
+    let computed = [data[12 + at], data[13 + at]];
+    computed == sent || (packet[9] == UDP && computed == [0, 0] && sent == [0xff, 0xff])
+}
+
+fn be16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn be32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// What changed from the previous headers of the flow, None if too much did
+fn encode_changes(prev: &[u8], packet: &[u8], hlen: usize) -> Option<Vec<u8>> {
+    // version, TOS, fragment flags, TTL, protocol, addresses and ports stay the same
+    if prev.len() != hlen || prev[..2] != packet[..2] || prev[6..10] != packet[6..10] || prev[12..24] != packet[12..24] {
+        return None;
+    }
+    let mut mask = 0;
+    let mut fields = Vec::new();
+    let ipid = be16(packet, 4);
+    if ipid != be16(prev, 4).wrapping_add(1) {
+        mask |= COMP_IPID;
+        fields.extend_from_slice(&ipid.to_be_bytes());
+    }
+    if packet[9] == TCP {
+        if prev[32] != packet[32] {
+            return None;
+        }
+        for &(at, bit) in [(24, COMP_SEQ), (28, COMP_ACK)].iter() {
+            let delta = be32(packet, at).wrapping_sub(be32(prev, at));
+            if delta > 0xffff {
+                return None;
+            }
+            if delta != 0 {
+                mask |= bit;
+                fields.extend_from_slice(&(delta as u16).to_be_bytes());
+            }
+        }
+        if prev[33] != packet[33] {
+            mask |= COMP_FLAGS;
+            fields.push(packet[33]);
+        }
+        if prev[34..36] != packet[34..36] {
+            mask |= COMP_WINDOW;
+            fields.extend_from_slice(&packet[34..36]);
+        }
+        if prev[40..hlen] != packet[40..hlen] {
+            mask |= COMP_OPTIONS;
+            fields.extend_from_slice(&packet[40..hlen]);
+        }
+        fields.extend_from_slice(&packet[36..38]);
+    } else {
+        fields.extend_from_slice(&packet[26..28]);
+    }
+    let mut data = vec![mask];
+    data.extend(fields);
+    Some(data)
+}
+
+/// TCP/IP header compression between mesh nodes
+/* A simplified Van Jacobson scheme. Each flow of IPv4 TCP or UDP packets
+to a peer gets a context, named by a byte. The first packet of a flow
+goes with its full headers, which the peer keeps. The following ones
+carry only the fields that changed since, as deltas where they can, so a
+bare TCP ACK shrinks from 40 bytes of headers to a handful. The transport
+checksum is carried as it is: a peer whose context went out of step,
+after a lost packet, finds it does not match and tells us, and the next
+packet of the flow goes with full headers again. They are also sent
+every HEADER_REFRESH packets. */
+#[derive(Clone, Debug, Default)]
+pub struct HeaderCompression {
+    /// contexts of the flows we send, by peer
+    tx: HashMap<NodeId, Vec<FlowContext>>,
+    /// next context ID to hand out, by peer
+    nextid: HashMap<NodeId, u8>,
+    /// headers of the last packet received in each context, by peer and context ID
+    rx: HashMap<(NodeId, u8), Vec<u8>>,
+    tick: u64,
+    /// header bytes left out of the packets we sent
+    pub saved: u64,
+    /// packets received that could not be put back together
+    pub failures: u64,
+}
+
+impl HeaderCompression {
+    pub fn new() -> Self {
+        HeaderCompression::default()
+    }
+
+    /// Compress a packet for a peer, returns the context ID and the compressed data
+    /// None if the packet is not TCP or UDP over IPv4 and must be sent as it is
+    pub fn compress(&mut self, peer: NodeId, packet: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let hlen = header_len(packet)?;
+        let key = flow_key(packet);
+        self.tick += 1;
+        let tick = self.tick;
+        let contexts = self.tx.entry(peer).or_default();
+        let at = match contexts.iter().position(|ctx| ctx.key == key) {
+            Some(at) => at,
+            None => {
+                if contexts.len() >= MAX_HEADER_CONTEXTS {
+                    let oldest = contexts.iter().enumerate().min_by_key(|(_, ctx)| ctx.used).map(|(i, _)| i)?;
+                    contexts.remove(oldest);
+                }
+                // IDs are not reused right away, a peer could still have the old flow under it
+                let nextid = self.nextid.entry(peer).or_insert(0);
+                while contexts.iter().any(|ctx| ctx.id == *nextid) {
+                    *nextid = nextid.wrapping_add(1);
+                }
+                let id = *nextid;
+                *nextid = nextid.wrapping_add(1);
+                contexts.push(FlowContext { id, key, header: Vec::new(), compressed: HEADER_REFRESH, used: tick });
+                contexts.len() - 1
+            },
+        };
+        let ctx = &mut contexts[at];
+        ctx.used = tick;
+        let changes = if ctx.compressed < HEADER_REFRESH { encode_changes(&ctx.header, packet, hlen) } else { None };
+        ctx.header = packet[..hlen].to_vec();
+        let mut data = match changes {
+            Some(changes) => {
+                ctx.compressed += 1;
+                self.saved += (hlen - changes.len()) as u64;
+                changes
+            },
+            None => {
+                ctx.compressed = 0;
+                vec![COMP_FULL]
+            },
+        };
+        data.extend_from_slice(&packet[if data[0] == COMP_FULL { 0 } else { hlen }..]);
+        Some((ctx.id, data))
+    }
+
+    /// Put a packet from a peer back together
+    /* Fails for a context we don't know or one out of step, the peer is
+    to be told so with `MeshError::HeaderContextLost`. */
+    pub fn decompress(&mut self, peer: NodeId, context: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        let result = self.decompress_packet(peer, context, data);
+        match &result {
+            Ok(packet) => {
+                let hlen = header_len(packet).unwrap_or(0);
+                self.rx.insert((peer, context), packet[..hlen].to_vec());
+            },
+            Err(_) => {
+                self.failures += 1;
+                self.rx.remove(&(peer, context));
+            },
+        }
+        result
+    }
+
+    fn decompress_packet(&self, peer: NodeId, context: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("header context {} of {}: {}", context, peer, msg));
+        let mask = *data.first().ok_or_else(|| invalid("no data"))?;
+        if mask & COMP_FULL != 0 {
+            let packet = data[1..].to_vec();
+            header_len(&packet).ok_or_else(|| invalid("full headers are not TCP or UDP over IPv4"))?;
+            return Ok(packet);
+        }
+        let prev = self.rx.get(&(peer, context)).ok_or_else(|| invalid("unknown context"))?;
+        let mut pos = 1;
+        let mut take = |n: usize| {
+            let field = data.get(pos..pos + n).ok_or_else(|| invalid("compressed data is too short"));
+            pos += n;
+            field
+        };
+        let mut header = prev.clone();
+        let hlen = header.len();
+        let ipid = if mask & COMP_IPID != 0 { be16(take(2)?, 0) } else { be16(prev, 4).wrapping_add(1) };
+        header[4..6].copy_from_slice(&ipid.to_be_bytes());
+        if header[9] == TCP {
+            for &(at, bit) in [(24, COMP_SEQ), (28, COMP_ACK)].iter() {
+                if mask & bit != 0 {
+                    let value = be32(prev, at).wrapping_add(be16(take(2)?, 0) as u32);
+                    header[at..at + 4].copy_from_slice(&value.to_be_bytes());
+                }
+            }
+            if mask & COMP_FLAGS != 0 {
+                header[33] = take(1)?[0];
+            }
+            if mask & COMP_WINDOW != 0 {
+                header[34..36].copy_from_slice(take(2)?);
+            }
+            if mask & COMP_OPTIONS != 0 {
+                header[40..hlen].copy_from_slice(take(hlen - 40)?);
+            }
+            header[36..38].copy_from_slice(take(2)?);
+        } else {
+            header[26..28].copy_from_slice(take(2)?);
+        }
+        let payload = &data[pos..];
+        let total = hlen + payload.len();
+        header[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+        if header[9] == UDP {
+            header[24..26].copy_from_slice(&((total - 20) as u16).to_be_bytes());
+        }
+        set_checksum(&mut header[..20], 10);
+        let mut packet = header;
+        packet.extend_from_slice(payload);
+        if !transport_checksum_ok(&packet) {
+            return Err(invalid("checksum does not match, out of step"));
+        }
+        Ok(packet)
+    }
+
+    /// A peer could not put a packet of ours back together
+    /* The flow's context is dropped, its next packet goes with full headers. */
+    pub fn context_lost(&mut self, peer: NodeId, context: u8) {
+        if let Some(contexts) = self.tx.get_mut(&peer) {
+            contexts.retain(|ctx| ctx.id != context);
+        }
+    }
+
+    /// Forget the contexts shared with a peer, after it restarted
+    pub fn forget(&mut self, peer: NodeId) {
+        self.tx.remove(&peer);
+        self.rx.retain(|(from, _), _| *from != peer);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn header_compression_tcp_session() {
+    // an HTTP request from 10.107.1.3 on node 3 to 10.107.1.9 on node 9, captured on the tunnel:
+    // handshake, request, response and close, with the timestamps Linux puts in every segment
+    let session: Vec<(NodeId, Vec<u8>)> = [
+        (3, "4500003cb24f40004006718b0a6b01030a6b01099d7800502a1c4c8600000000a002faf033160000020405b40402080a0042ee6a0000000001030307"),
+        (9, "4500003c00004000400623db0a6b01090a6b010300509d78c47e52182a1c4c87a012fe88fd190000020405b40402080a5b18c0a30042ee6a01030307"),
+        (3, "45000034b2504000400671920a6b01030a6b01099d7800502a1c4c87c47e5219801001f6287000000101080a0042ee735b18c0a3"),
+        (3, concat!(
+            "4500007eb2514000400671470a6b01030a6b01099d7800502a1c4c87c47e5219801801f6221100000101080a0042ee735b18c0a3474554202f204854",
+            "54502f312e310d0a486f73743a2031302e3130372e312e390d0a557365722d4167656e743a206375726c2f372e38382e310d0a4163636570743a202a",
+            "2f2a0d0a0d0a",
+        )),
+        (9, "45000034e7c8400040063c1a0a6b01090a6b010300509d78c47e52192a1c4cd1801001fd280900000101080a5b18c0b90042ee73"),
+        (9, concat!(
+            "4500012fe7c9400040063b1e0a6b01090a6b010300509d78c47e52192a1c4cd1801801fdf96c00000101080a5b18c0bb0042ee73485454502f312e31",
+            "20323030204f4b0d0a5365727665723a2053696d706c65485454502f302e3620507974686f6e2f332e31312e320d0a436f6e74656e742d747970653a",
+            "20746578742f68746d6c0d0a436f6e74656e742d4c656e6774683a203134380d0a0d0a3c21444f43545950452068746d6c3e0a3c68746d6c3e3c6865",
+            "61643e3c7469746c653e6d6573683c2f7469746c653e3c2f686561643e3c626f64793e3c68313e4974207475726e73206f757420746865206d657368",
+            "206973206d6f73746c7920686561646572733c2f68313e3c703e5365727665642066726f6d206e6f646520393c2f703e3c2f626f64793e3c2f68746d",
+            "6c3e0a",
+        )),
+        (3, "45000034b2524000400671900a6b01030a6b01099d7800502a1c4cd1c47e5314801001f526a500000101080a0042eee25b18c0bb"),
+        (9, "45000034e7ca400040063c180a6b01090a6b010300509d78c47e53142a1c4cd1801101fd269b00000101080a5b18c0bc0042eee2"),
+        (3, "45000034b25340004006718f0a6b01030a6b01099d7800502a1c4cd1c47e5315801101f5266000000101080a0042ef245b18c0bc"),
+        (9, "45000034e7cb400040063c170a6b01090a6b010300509d78c47e53152a1c4cd2801001fd261600000101080a5b18c0fe0042ef24"),
+    ].iter().map(|(node, packet)| (*node, hex::decode(packet).unwrap())).collect();
+    assert!(session.iter().all(|(_, packet)| transport_checksum_ok(packet)));
+
+    let mut node3 = HeaderCompression::new();
+    let mut node9 = HeaderCompression::new();
+    let (mut raw, mut air) = (0, 0);
+    let mut headers = Vec::new();
+    for (sender, packet) in session.iter() {
+        let (compressor, decompressor, peer) = if *sender == 3 { (&mut node3, &mut node9, 9) } else { (&mut node9, &mut node3, 3) };
+        let (context, data) = compressor.compress(peer, packet).unwrap();
+        let received = decompressor.decompress(*sender, context, &data).unwrap();
+        assert_eq!(&received, packet);
+        raw += packet.len();
+        // the context ID goes on the air too
+        air += 1 + data.len();
+        headers.push(1 + data.len() - (packet.len() - header_len(packet).unwrap()));
+    }
+    // the SYNs and the first segment after each carry full headers, the options changed length
+    assert_eq!(&headers[..4], &[62, 62, 54, 5]);
+    // bare ACKs keep the changed timestamps and little else
+    assert!(headers[4..].iter().all(|&len| len == 54 || len <= 23), "{:?}", headers);
+    assert_eq!(node3.saved + node9.saved, 214);
+    assert_eq!((raw, air), (861, 661));
+    assert_eq!(node3.failures + node9.failures, 0);
+
+    // a segment lost on the way puts the contexts out of step, the checksum gives it away
+    let mut sender = HeaderCompression::new();
+    let mut receiver = HeaderCompression::new();
+    let (context, data) = sender.compress(9, &session[2].1).unwrap();
+    receiver.decompress(3, context, &data).unwrap();
+    sender.compress(9, &session[6].1).unwrap();
+    let (context, data) = sender.compress(9, &session[8].1).unwrap();
+    assert!(receiver.decompress(3, context, &data).is_err());
+    assert_eq!(receiver.failures, 1);
+    // told so, the sender starts the flow over with full headers
+    sender.context_lost(9, context);
+    let (context, data) = sender.compress(9, &session[8].1).unwrap();
+    assert_eq!(data[0], COMP_FULL);
+    assert_eq!(receiver.decompress(3, context, &data).unwrap(), session[8].1);
+    assert!(receiver.decompress(3, 200, &[0, 0, 0]).is_err());
+
+    // DNS queries shrink to the checksum
+    let queries = [
+        hex::decode("4500003a1f404000401104920a6b01030a6b0109cf0800350026bab15c21010000010000000000000767617465776179046d6573680000010001").unwrap(),
+        hex::decode("450000381f414000401104930a6b01030a6b0109cf080035002410755c2201000001000000000000056e6f646539046d6573680000010001").unwrap(),
+    ];
+    let (first, data) = sender.compress(9, &queries[0]).unwrap();
+    assert_ne!(first, context);
+    assert_eq!(receiver.decompress(3, first, &data).unwrap(), queries[0]);
+    let (second, data) = sender.compress(9, &queries[1]).unwrap();
+    assert_eq!((second, data.len()), (first, 3 + queries[1].len() - 28));
+    assert_eq!(receiver.decompress(3, second, &data).unwrap(), queries[1]);
+
+    // anything but TCP or UDP over IPv4 goes as it is
+    let mut ping = queries[0].clone();
+    ping[9] = 1;
+    assert!(sender.compress(9, &ping).is_none());
+
+    // full headers are sent again now and then, the second query was the first compressed
+    for _ in 1..HEADER_REFRESH {
+        let (_, data) = sender.compress(9, &queries[1]).unwrap();
+        assert_ne!(data[0], COMP_FULL);
+    }
+    assert_eq!(sender.compress(9, &queries[1]).unwrap().1[0], COMP_FULL);
+}
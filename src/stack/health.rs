@@ -0,0 +1,105 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use serde::Serialize;
+use crate::stack::frame::NodeId;
+use crate::stack::message::TelemetrySample;
+
+/// Read the memory available to new processes from /proc/meminfo, in kB
+pub fn mem_available_kb() -> Option<u64> {
+    fs::read_to_string("/proc/meminfo").ok().and_then(|info| parse_meminfo(&info))
+}
+
+fn parse_meminfo(info: &str) -> Option<u64> {
+    info.lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+}
+
+/// true if the host has a default route that doesn't go through `tunname`
+/* A gateway with no way out besides the mesh itself has no uplink. */
+pub fn uplink_up(tunname: &str) -> bool {
+    fs::read_to_string("/proc/net/route").map_or(false, |routes| parse_default_route(&routes, tunname))
+}
+
+fn parse_default_route(routes: &str, tunname: &str) -> bool {
+    routes.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // interface, destination, gateway, flags, with RTF_UP set
+        match fields.as_slice() {
+            [iface, "00000000", _, flags, ..] => *iface != tunname
+                && u16::from_str_radix(flags, 16).map_or(false, |flags| flags & 0x1 != 0),
+            _ => false,
+        }
+    })
+}
+
+#[derive(Serialize)]
+struct TelemetryEntry<'a> {
+    /// ms since the unix epoch when the sample arrived
+    received: u64,
+    #[serde(flatten)]
+    sample: &'a TelemetrySample,
+}
+
+/// Latest telemetry sample of every node that reported one
+#[derive(Clone, Debug, Default)]
+pub struct TelemetryTable {
+    samples: HashMap<NodeId, (TelemetrySample, u64)>,
+}
+
+impl TelemetryTable {
+    pub fn new() -> Self {
+        TelemetryTable { samples: HashMap::new() }
+    }
+
+    /// record a sample received at `timestamp` ms since the unix epoch
+    pub fn update(&mut self, nodeid: NodeId, sample: TelemetrySample, timestamp: u64) {
+        self.samples.insert(nodeid, (sample, timestamp));
+    }
+
+    pub fn get(&self, nodeid: NodeId) -> Option<&(TelemetrySample, u64)> {
+        self.samples.get(&nodeid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// The whole table as a JSON object keyed by node ID
+    pub fn to_json(&self) -> String {
+        let entries: BTreeMap<NodeId, TelemetryEntry> = self.samples.iter()
+            .map(|(id, (sample, received))| (*id, TelemetryEntry { received: *received, sample }))
+            .collect();
+        serde_json::to_string(&entries).expect("Telemetry table is always serializable")
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn telemetry_table_json() {
+    let info = "MemTotal:        8054880 kB\nMemFree:          312516 kB\nMemAvailable:    4021764 kB\n";
+    assert_eq!(parse_meminfo(info), Some(4021764));
+    assert_eq!(parse_meminfo("MemTotal: 1 kB\n"), None);
+
+    let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+        eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\n\
+        eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\n";
+    assert!(parse_default_route(routes, "tun0"));
+    assert!(!parse_default_route(&routes.replace("eth0", "tun0"), "tun0"));
+    assert!(!parse_default_route(&routes.replace("\t0003\t", "\t0002\t"), "tun0"));
+
+    let mut table = TelemetryTable::new();
+    assert!(table.is_empty());
+    assert_eq!(table.to_json(), "{}");
+    table.update(7, TelemetrySample { vdd_mv: Some(3300), ..Default::default() }, 1000);
+    table.update(2, TelemetrySample { uptime_s: Some(60), queue_depth: Some(0), ..Default::default() }, 2000);
+    table.update(7, TelemetrySample { vdd_mv: Some(3290), ..Default::default() }, 3000);
+    assert_eq!(table.len(), 2);
+    assert_eq!(table.to_json(),
+        r#"{"2":{"received":2000,"uptime_s":60,"queue_depth":0},"7":{"received":3000,"vdd_mv":3290}}"#);
+}
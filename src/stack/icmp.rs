@@ -0,0 +1,130 @@
+use packet::ip::v4::Packet;
+use crate::stack::ipv6::{icmpv6_packet, IpPacket};
+use crate::stack::mtu::set_checksum;
+
+const ICMP: u8 = 1;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6: u8 = 58;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// true for ICMP and ICMPv6 packets
+pub fn is_icmp(packet: &IpPacket) -> bool {
+    match packet {
+        IpPacket::V4(packet) => packet.as_ref()[9] == ICMP,
+        IpPacket::V6(packet) => packet.next_header() == ICMPV6,
+    }
+}
+
+/// The reply to an echo request, None for any other packet
+/* Built by the node rather than the kernel, so a node answers pings as
+long as the mesh reaches it, however the network above its tunnel is set
+up. The reply carries the request's identifier, sequence and data. */
+pub fn echo_reply(packet: &IpPacket) -> Option<IpPacket> {
+    match packet {
+        IpPacket::V4(packet) => {
+            let bytes = packet.as_ref();
+            let ihl = (bytes[0] & 0x0f) as usize * 4;
+            let fragment = u16::from_be_bytes([bytes[6], bytes[7]]) & 0x3fff;
+            if bytes[9] != ICMP || fragment != 0 || bytes.len() < ihl + 8 || bytes[ihl] != ICMP_ECHO_REQUEST {
+                return None;
+            }
+            let mut reply = bytes.to_vec();
+            reply[12..16].copy_from_slice(&bytes[16..20]);
+            reply[16..20].copy_from_slice(&bytes[12..16]);
+            reply[8] = 64;
+            reply[ihl] = ICMP_ECHO_REPLY;
+            set_checksum(&mut reply[ihl..], 2);
+            set_checksum(&mut reply[..ihl], 10);
+            Packet::new(reply).ok().map(IpPacket::V4)
+        },
+        IpPacket::V6(packet) => {
+            let icmp = packet.payload();
+            if packet.next_header() != ICMPV6 || icmp.len() < 8 || icmp[0] != ICMPV6_ECHO_REQUEST {
+                return None;
+            }
+            let mut reply = icmp.to_vec();
+            reply[0] = ICMPV6_ECHO_REPLY;
+            Some(IpPacket::V6(icmpv6_packet(packet.destination(), packet.source(), 64, reply)))
+        },
+    }
+}
+
+/// IP packets carried over the mesh, with ICMP counted apart from the rest
+/* Pings and their replies are probes, counting them as application
+traffic would make the mesh look busier than it is. */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IpTraffic {
+    pub packets: u64,
+    pub bytes: u64,
+    pub icmp_packets: u64,
+    pub icmp_bytes: u64,
+}
+
+impl IpTraffic {
+    /// Count a packet sent or received over the mesh
+    pub fn record(&mut self, packet: &IpPacket) {
+        let len = packet.as_ref().len() as u64;
+        if is_icmp(packet) {
+            self.icmp_packets += 1;
+            self.icmp_bytes += len;
+        } else {
+            self.packets += 1;
+            self.bytes += len;
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn icmp_echo_reply() {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use crate::stack::ipv6::Ipv6Packet;
+
+    let checksum_ok = |data: &[u8], at: usize| {
+        let mut copy = data.to_vec();
+        set_checksum(&mut copy, at);
+        copy == data
+    };
+
+    // a ping from the gateway's network to node 3
+    let mut ping = vec![0x45, 0, 0, 32, 0x12, 0x34, 0x40, 0, 63, ICMP, 0, 0, 192, 168, 1, 20, 172, 16, 0, 3];
+    ping.extend_from_slice(&[ICMP_ECHO_REQUEST, 0, 0, 0, 0x0b, 0xad, 0, 7, 1, 2, 3, 4]);
+    set_checksum(&mut ping[20..], 2);
+    set_checksum(&mut ping[..20], 10);
+    let ping = IpPacket::new(ping).unwrap();
+    assert!(is_icmp(&ping));
+
+    let reply = echo_reply(&ping).unwrap();
+    assert_eq!((reply.source(), reply.destination()), (IpAddr::V4(Ipv4Addr::new(172, 16, 0, 3)), IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))));
+    let bytes = reply.as_ref();
+    assert_eq!(bytes[20], ICMP_ECHO_REPLY);
+    assert_eq!(&bytes[24..], &ping.as_ref()[24..]);
+    assert!(checksum_ok(&bytes[..20], 10) && checksum_ok(&bytes[20..], 2));
+    // replies and other ICMP are not answered, or two nodes would ping pong
+    assert!(echo_reply(&reply).is_none());
+
+    // the same over IPv6
+    let ours: Ipv6Addr = "fd6c:6d00::3".parse().unwrap();
+    let theirs: Ipv6Addr = "fd6c:6d00::1".parse().unwrap();
+    let ping = IpPacket::V6(icmpv6_packet(theirs, ours, 64, vec![ICMPV6_ECHO_REQUEST, 0, 0, 0, 0x0b, 0xad, 0, 7, 1, 2, 3, 4]));
+    let reply = echo_reply(&ping).unwrap();
+    assert_eq!((reply.source(), reply.destination()), (IpAddr::V6(ours), IpAddr::V6(theirs)));
+    let expected = icmpv6_packet(ours, theirs, 64, vec![ICMPV6_ECHO_REPLY, 0, 0, 0, 0x0b, 0xad, 0, 7, 1, 2, 3, 4]);
+    assert_eq!(reply.as_ref(), expected.as_ref());
+    assert!(echo_reply(&reply).is_none());
+
+    // pings are counted apart from the traffic they share the mesh with
+    let mut udp = vec![0x60, 0, 0, 0, 0, 8, 17, 64];
+    udp.extend_from_slice(&theirs.octets());
+    udp.extend_from_slice(&ours.octets());
+    udp.extend_from_slice(&[0x30, 0x39, 0, 0x35, 0, 8, 0, 0]);
+    let udp = IpPacket::V6(Ipv6Packet::new(udp).unwrap());
+    assert!(!is_icmp(&udp));
+    let mut traffic = IpTraffic::default();
+    for packet in [&ping, &reply, &udp].iter() {
+        traffic.record(packet);
+    }
+    assert_eq!(traffic, IpTraffic { packets: 1, bytes: 48, icmp_packets: 2, icmp_bytes: 104 });
+}
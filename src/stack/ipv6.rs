@@ -0,0 +1,272 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv6Addr};
+use packet::ip::v4::Packet;
+use crate::stack::mtu::set_checksum;
+
+/// Length of the fixed IPv6 header
+pub const IPV6_HEADER_LEN: usize = 40;
+
+/// Smallest MTU an IPv6 link may have, nothing on the way fragments for it
+pub const IPV6_MIN_MTU: usize = 1280;
+
+const ICMPV6: u8 = 58;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// An IPv6 packet, as its bytes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ipv6Packet {
+    buffer: Vec<u8>,
+}
+
+impl Ipv6Packet {
+    /// checks the version and that the packet is as long as its header says
+    pub fn new(mut buffer: Vec<u8>) -> io::Result<Self> {
+        if buffer.len() < IPV6_HEADER_LEN || buffer[0] >> 4 != 6 {
+            return Err(Error::new(ErrorKind::InvalidData, "not an IPv6 packet"));
+        }
+        let len = IPV6_HEADER_LEN + u16::from_be_bytes([buffer[4], buffer[5]]) as usize;
+        if buffer.len() < len {
+            return Err(Error::new(ErrorKind::InvalidData, "IPv6 packet is truncated"));
+        }
+        buffer.truncate(len);
+        Ok(Ipv6Packet { buffer })
+    }
+
+    pub fn source(&self) -> Ipv6Addr {
+        parse_ipv6(&self.buffer[8..24])
+    }
+
+    pub fn destination(&self) -> Ipv6Addr {
+        parse_ipv6(&self.buffer[24..40])
+    }
+
+    /// protocol of the payload, extension headers are not skipped
+    pub fn next_header(&self) -> u8 {
+        self.buffer[6]
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer[IPV6_HEADER_LEN..]
+    }
+}
+
+impl AsRef<[u8]> for Ipv6Packet {
+    fn as_ref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+/// An IP packet of either version
+#[derive(Clone, Debug)]
+pub enum IpPacket {
+    V4(Packet<Vec<u8>>),
+    V6(Ipv6Packet),
+}
+
+impl IpPacket {
+    /// the packet in `buffer`, parsed by its version
+    pub fn new(buffer: Vec<u8>) -> io::Result<Self> {
+        match buffer.first().map(|byte| byte >> 4) {
+            Some(4) => Packet::new(buffer)
+                .map(IpPacket::V4)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string())),
+            Some(6) => Ipv6Packet::new(buffer).map(IpPacket::V6),
+            _ => Err(Error::new(ErrorKind::InvalidData, "unsupported IP version")),
+        }
+    }
+
+    pub fn source(&self) -> IpAddr {
+        match self {
+            IpPacket::V4(packet) => IpAddr::V4(packet.source()),
+            IpPacket::V6(packet) => IpAddr::V6(packet.source()),
+        }
+    }
+
+    pub fn destination(&self) -> IpAddr {
+        match self {
+            IpPacket::V4(packet) => IpAddr::V4(packet.destination()),
+            IpPacket::V6(packet) => IpAddr::V6(packet.destination()),
+        }
+    }
+}
+
+impl AsRef<[u8]> for IpPacket {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            IpPacket::V4(packet) => packet.as_ref(),
+            IpPacket::V6(packet) => packet.as_ref(),
+        }
+    }
+}
+
+impl From<Packet<Vec<u8>>> for IpPacket {
+    fn from(packet: Packet<Vec<u8>>) -> Self {
+        IpPacket::V4(packet)
+    }
+}
+
+impl From<Ipv6Packet> for IpPacket {
+    fn from(packet: Ipv6Packet) -> Self {
+        IpPacket::V6(packet)
+    }
+}
+
+/// What an IPv6 packet from the tunnel is, and so where it goes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ipv6Traffic {
+    /// to a single node, carried over the mesh
+    Unicast(Ipv6Addr),
+    /// the kernel asking who has an address, answered by the node itself
+    NeighborSolicitation(Ipv6Addr),
+    /// only meant for the link, such as router solicitations and MLD reports
+    LinkLocal,
+    /// to a wider group, the mesh does not route multicast
+    Multicast(Ipv6Addr),
+}
+
+/// Sort an IPv6 packet from the tunnel
+/* Neighbor discovery and everything else scoped to the link stays on the
+node, flooding it would cost airtime for packets only the kernel on the
+other end of the tunnel cares about. */
+pub fn classify_ipv6(packet: &Ipv6Packet) -> Ipv6Traffic {
+    let payload = packet.payload();
+    if packet.next_header() == ICMPV6 && payload.len() >= 24 && payload[0] == ICMPV6_NEIGHBOR_SOLICITATION {
+        return Ipv6Traffic::NeighborSolicitation(parse_ipv6(&payload[8..24]));
+    }
+    let dest = packet.destination();
+    let first = dest.segments()[0];
+    if dest.is_multicast() {
+        // interface and link scope
+        if first & 0x000f <= 2 { Ipv6Traffic::LinkLocal } else { Ipv6Traffic::Multicast(dest) }
+    } else if first & 0xffc0 == 0xfe80 {
+        Ipv6Traffic::LinkLocal
+    } else {
+        Ipv6Traffic::Unicast(dest)
+    }
+}
+
+/// Address of a node in a /64 mesh prefix, its ID being the interface ID
+pub fn ipv6_node_addr(prefix: Ipv6Addr, nodeid: u8) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[8..].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, nodeid]);
+    Ipv6Addr::from(octets)
+}
+
+/// Answer to a neighbor solicitation, on behalf of the node with `target`
+/* A solicitation from the unspecified address checks whether an address
+is taken, the answer goes to all nodes and tells the kernel it is. */
+pub fn neighbor_advertisement(solicitation: &Ipv6Packet, target: Ipv6Addr) -> Ipv6Packet {
+    // solicited and override, only override for a duplicate check
+    let (dest, flags) = match solicitation.source() {
+        source if source.is_unspecified() => (Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1), 0x20),
+        source => (source, 0x60),
+    };
+    let mut icmp = vec![ICMPV6_NEIGHBOR_ADVERTISEMENT, 0, 0, 0, flags, 0, 0, 0];
+    icmp.extend_from_slice(&target.octets());
+    icmpv6_packet(target, dest, 255, icmp)
+}
+
+/// ICMPv6 Packet Too Big from `source`, telling the sender of `packet` to keep to `mtu`
+pub fn icmpv6_packet_too_big(packet: &Ipv6Packet, mtu: usize, source: Ipv6Addr) -> Ipv6Packet {
+    // as much of the packet as the answer fits in the minimum MTU
+    let quoted = &packet.as_ref()[..packet.as_ref().len().min(IPV6_MIN_MTU - IPV6_HEADER_LEN - 8)];
+    let mut icmp = vec![ICMPV6_PACKET_TOO_BIG, 0, 0, 0];
+    icmp.extend_from_slice(&(mtu as u32).to_be_bytes());
+    icmp.extend_from_slice(quoted);
+    icmpv6_packet(source, packet.source(), 64, icmp)
+}
+
+/// An ICMPv6 message in a packet, its checksum covering the addresses
+pub(crate) fn icmpv6_packet(source: Ipv6Addr, dest: Ipv6Addr, hoplimit: u8, mut icmp: Vec<u8>) -> Ipv6Packet {
+    let mut header = vec![0x60, 0, 0, 0];
+    header.extend_from_slice(&(icmp.len() as u16).to_be_bytes());
+    header.extend_from_slice(&[ICMPV6, hoplimit]);
+    header.extend_from_slice(&source.octets());
+    header.extend_from_slice(&dest.octets());
+
+    let mut summed = Vec::from(&header[8..]);
+    summed.extend_from_slice(&(icmp.len() as u32).to_be_bytes());
+    summed.extend_from_slice(&[0, 0, 0, ICMPV6]);
+    summed.extend_from_slice(&icmp);
+    set_checksum(&mut summed, IPV6_HEADER_LEN + 2);
+    icmp[2..4].copy_from_slice(&summed[IPV6_HEADER_LEN + 2..IPV6_HEADER_LEN + 4]);
+
+    header.extend(icmp);
+    Ipv6Packet { buffer: header }
+}
+
+/// Parse 16 bytes into an IPv6 address
+pub fn parse_ipv6(arr: &[u8]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&arr[..16]);
+    Ipv6Addr::from(octets)
+}
+
+#[cfg(test)]
+#[test]
+fn ipv6_classification() {
+    let ours: Ipv6Addr = "fd6c:6d00::3".parse().unwrap();
+    let packet = |dest: &str, next: u8, payload: Vec<u8>| {
+        let mut bytes = vec![0x60, 0, 0, 0];
+        bytes.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&[next, 64]);
+        bytes.extend_from_slice(&ours.octets());
+        bytes.extend_from_slice(&dest.parse::<Ipv6Addr>().unwrap().octets());
+        bytes.extend(payload);
+        Ipv6Packet::new(bytes).unwrap()
+    };
+    let udp = vec![0x30, 0x39, 0x00, 0x35, 0, 12, 0, 0, 1, 2, 3, 4];
+
+    // mesh and global addresses go over the air
+    let unicast = packet("fd6c:6d00::7", 17, udp.clone());
+    assert_eq!(classify_ipv6(&unicast), Ipv6Traffic::Unicast("fd6c:6d00::7".parse().unwrap()));
+    assert_eq!(classify_ipv6(&packet("2001:db8::1", 17, udp.clone())), Ipv6Traffic::Unicast("2001:db8::1".parse().unwrap()));
+    assert_eq!(unicast.payload(), &udp[..]);
+
+    // the kernel talking to the link stays local
+    let mut solicit = vec![ICMPV6_NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+    solicit.extend_from_slice(&"fd6c:6d00::7".parse::<Ipv6Addr>().unwrap().octets());
+    let solicitation = packet("ff02::1:ff00:7", ICMPV6, solicit);
+    assert_eq!(classify_ipv6(&solicitation), Ipv6Traffic::NeighborSolicitation("fd6c:6d00::7".parse().unwrap()));
+    assert_eq!(classify_ipv6(&packet("ff02::2", ICMPV6, vec![133, 0, 0, 0, 0, 0, 0, 0])), Ipv6Traffic::LinkLocal);
+    assert_eq!(classify_ipv6(&packet("ff02::16", 0, vec![58, 0, 5, 2, 0, 0, 1, 0])), Ipv6Traffic::LinkLocal);
+    assert_eq!(classify_ipv6(&packet("fe80::7", 17, udp.clone())), Ipv6Traffic::LinkLocal);
+    assert_eq!(classify_ipv6(&packet("ff05::fb", 17, udp.clone())), Ipv6Traffic::Multicast("ff05::fb".parse().unwrap()));
+
+    // either version parses by its first nibble, anything else is refused
+    assert!(matches!(IpPacket::new(unicast.as_ref().to_vec()), Ok(IpPacket::V6(_))));
+    assert!(IpPacket::new(unicast.as_ref()[..IPV6_HEADER_LEN + 4].to_vec()).is_err());
+    assert!(IpPacket::new(vec![0x50; 40]).is_err());
+
+    // the answer comes from the solicited address, with a checksum the kernel accepts
+    let checksum_ok = |packet: &Ipv6Packet| {
+        let mut summed = Vec::from(&packet.as_ref()[8..IPV6_HEADER_LEN]);
+        summed.extend_from_slice(&(packet.payload().len() as u32).to_be_bytes());
+        summed.extend_from_slice(&[0, 0, 0, ICMPV6]);
+        summed.extend_from_slice(packet.payload());
+        let sent = summed.clone();
+        set_checksum(&mut summed, IPV6_HEADER_LEN + 2);
+        summed == sent
+    };
+    let answer = neighbor_advertisement(&solicitation, "fd6c:6d00::7".parse().unwrap());
+    assert_eq!((answer.source(), answer.destination()), ("fd6c:6d00::7".parse().unwrap(), ours));
+    assert_eq!((answer.payload()[0], answer.payload()[4]), (ICMPV6_NEIGHBOR_ADVERTISEMENT, 0x60));
+    assert_eq!(&answer.as_ref()[7..8], &[255]);
+    assert!(checksum_ok(&answer));
+
+    // a packet too big for the tunnel is answered with its MTU
+    let mut big = vec![0u8; 1452];
+    big[..8].copy_from_slice(&udp[..8]);
+    let big = packet("fd6c:6d00::7", 17, big);
+    let reply = icmpv6_packet_too_big(&big, 1280, "fd6c:6d00::1".parse().unwrap());
+    assert_eq!(reply.destination(), ours);
+    assert_eq!(reply.as_ref().len(), IPV6_MIN_MTU);
+    assert_eq!(u32::from_be_bytes([reply.payload()[4], reply.payload()[5], reply.payload()[6], reply.payload()[7]]), 1280);
+    assert!(checksum_ok(&reply));
+
+    assert_eq!(ipv6_node_addr("fd6c:6d00::".parse().unwrap(), 7), "fd6c:6d00::7".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(ipv6_node_addr("fd6c:6d00:0:1:ffff::".parse().unwrap(), 9), "fd6c:6d00:0:1::9".parse::<Ipv6Addr>().unwrap());
+}
@@ -0,0 +1,156 @@
+use log::*;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::stack::frame::NodeId;
+use crate::stack::message::IPAssignFailureMessage;
+
+/// An address handed out to a node until it expires
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    pub ipaddr: Ipv4Addr,
+    /// ms since the unix epoch, so leases survive a reboot
+    pub expires: u64,
+}
+
+/// Addresses the gateway assigns to nodes, by node ID
+/* A node keeps its address across renewals, and an expired lease is only
+reclaimed once the pool has no never-used address left. Leases are saved
+to disk after every change so a gateway reboot doesn't reshuffle them. */
+#[derive(Clone, Debug)]
+pub struct LeaseTable {
+    first: u32,
+    last: u32,
+    leasetime: Duration,
+    /// addresses in the pool that are never handed out, like our own
+    reserved: HashSet<Ipv4Addr>,
+    leases: BTreeMap<NodeId, Lease>,
+    path: Option<PathBuf>,
+}
+
+impl LeaseTable {
+    pub fn new(first: Ipv4Addr, last: Ipv4Addr, leasetime: Duration) -> Self {
+        LeaseTable {
+            first: u32::from(first),
+            last: u32::from(last),
+            leasetime,
+            reserved: HashSet::new(),
+            leases: BTreeMap::new(),
+            path: None,
+        }
+    }
+
+    /// Keep the table in a file, reading the leases already in it
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        self.path = Some(path.to_path_buf());
+        match fs::read_to_string(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+            Ok(json) => {
+                self.leases = serde_json::from_str(&json)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the table to its file, if it has one
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            None => return Ok(()),
+            Some(path) => path,
+        };
+        let json = serde_json::to_string(&self.leases).expect("Lease table is always serializable");
+        // a crash while writing leaves the old table intact
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Never hand out this address
+    pub fn reserve(&mut self, ipaddr: Ipv4Addr) {
+        self.reserved.insert(ipaddr);
+    }
+
+    pub fn leasetime(&self) -> Duration {
+        self.leasetime
+    }
+
+    pub fn get(&self, nodeid: NodeId) -> Option<&Lease> {
+        self.leases.get(&nodeid)
+    }
+
+    /// every lease, expired or not, by node ID
+    pub fn iter(&self) -> impl Iterator<Item = (&NodeId, &Lease)> {
+        self.leases.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leases.is_empty()
+    }
+
+    /// true if the address is in the pool and nobody else holds it
+    fn available(&self, ipaddr: Ipv4Addr, nodeid: NodeId, now: u64) -> bool {
+        let ip = u32::from(ipaddr);
+        ip >= self.first && ip <= self.last && !self.reserved.contains(&ipaddr)
+            && !self.leases.iter().any(|(id, lease)| *id != nodeid && lease.ipaddr == ipaddr && lease.expires > now)
+    }
+
+    /// Assign or renew the address of a node, preferring the one it asked for
+    /// returns the address and whether it is new to the node
+    pub fn assign(&mut self, nodeid: NodeId, requested: Option<Ipv4Addr>, now: u64) -> Result<(Ipv4Addr, bool), IPAssignFailureMessage> {
+        let current = self.leases.get(&nodeid).map(|lease| lease.ipaddr);
+        let pool = (self.first..=self.last).map(Ipv4Addr::from);
+        // never leased, then expired
+        let free = pool.clone().find(|ip| self.available(*ip, nodeid, now) && !self.leases.values().any(|l| l.ipaddr == *ip))
+            .or_else(|| pool.clone().find(|ip| self.available(*ip, nodeid, now)));
+        let ipaddr = current.into_iter()
+            .chain(requested)
+            .find(|ip| self.available(*ip, nodeid, now))
+            .or(free)
+            .ok_or_else(|| IPAssignFailureMessage::new(String::from("address pool exhausted")))?;
+
+        // the expired lease of another node on this address is reclaimed
+        self.leases.retain(|id, lease| *id == nodeid || lease.ipaddr != ipaddr);
+        let expires = now + self.leasetime.as_millis() as u64;
+        self.leases.insert(nodeid, Lease { ipaddr, expires });
+        if let Err(e) = self.save() {
+            error!("Could not save IP leases: {}", e);
+        }
+        Ok((ipaddr, current != Some(ipaddr)))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn lease_table_assign() {
+    let hour = 3_600_000;
+    let mut table = LeaseTable::new(Ipv4Addr::new(172, 16, 0, 1), Ipv4Addr::new(172, 16, 0, 3), Duration::from_millis(hour));
+    table.reserve(Ipv4Addr::new(172, 16, 0, 1));
+    let path = std::env::temp_dir().join(format!("loramesh-leases-{}.json", std::process::id()));
+    table.load(&path).unwrap();
+
+    assert_eq!(table.assign(5, None, 0).unwrap(), (Ipv4Addr::new(172, 16, 0, 2), true));
+    // renewal keeps the address, another node asking for it doesn't get it
+    assert_eq!(table.assign(5, None, hour / 2).unwrap(), (Ipv4Addr::new(172, 16, 0, 2), false));
+    assert_eq!(table.assign(6, Some(Ipv4Addr::new(172, 16, 0, 2)), hour).unwrap(), (Ipv4Addr::new(172, 16, 0, 3), true));
+    assert!(table.assign(7, None, hour).is_err());
+
+    // leases survive a reboot, expired ones are reclaimed once the pool runs out
+    let mut reloaded = LeaseTable::new(Ipv4Addr::new(172, 16, 0, 1), Ipv4Addr::new(172, 16, 0, 3), Duration::from_millis(hour));
+    reloaded.reserve(Ipv4Addr::new(172, 16, 0, 1));
+    reloaded.load(&path).unwrap();
+    assert_eq!(reloaded.get(6).unwrap().ipaddr, Ipv4Addr::new(172, 16, 0, 3));
+    assert_eq!(reloaded.assign(7, None, 3 * hour).unwrap(), (Ipv4Addr::new(172, 16, 0, 2), true));
+    assert_eq!(reloaded.len(), 2);
+    assert!(!reloaded.is_empty());
+    std::fs::remove_file(&path).unwrap();
+}
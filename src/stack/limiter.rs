@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Allows one event per key within each interval
+/* Used to keep a single flapping link or unreachable node from flooding
+the mesh with repeated error reports and route discoveries. */
+#[derive(Clone, Debug)]
+pub struct KeyedLimiter<K: Hash + Eq> {
+    interval: Duration,
+    last: HashMap<K, Instant>,
+}
+
+impl<K: Hash + Eq> KeyedLimiter<K> {
+    pub fn new(interval: Duration) -> Self {
+        KeyedLimiter { interval, last: HashMap::new() }
+    }
+
+    /// true if the event may happen now, recording it if so
+    pub fn allow(&mut self, key: K) -> bool {
+        self.allow_at(key, Instant::now())
+    }
+
+    pub(crate) fn allow_at(&mut self, key: K, now: Instant) -> bool {
+        let interval = self.interval;
+        self.last.retain(|_, last| now.duration_since(*last) < interval);
+        if self.last.contains_key(&key) {
+            return false;
+        }
+        self.last.insert(key, now);
+        true
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn keyed_limiter_interval() {
+    let now = Instant::now();
+    let mut limiter = KeyedLimiter::new(Duration::from_secs(30));
+    assert!(limiter.allow_at((2, 3), now));
+    assert!(!limiter.allow_at((2, 3), now + Duration::from_secs(10)));
+    assert!(limiter.allow_at((3, 2), now + Duration::from_secs(10)));
+    assert!(limiter.allow_at((2, 3), now + Duration::from_secs(30)));
+}
@@ -0,0 +1,424 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use petgraph::algo::astar;
+use petgraph::graphmap::DiGraphMap;
+use crate::stack::frame::NodeId;
+use crate::stack::gateways::{hop_cost, MAX_HOP_COST};
+use crate::stack::message::{HelloNeighbor, LsaLink, MAX_HELLO_NEIGHBORS};
+
+/// weight of a new RSSI reading in a neighbor's average
+const RSSI_SMOOTHING: f32 = 0.25;
+
+/// A link to a neighbor, as we hear it and as it told us it hears us
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NeighborLink {
+    /// share of the neighbor's frames we receive
+    pub inbound: f32,
+    /// share of our frames the neighbor receives, once it sent a hello
+    pub outbound: Option<f32>,
+    /// average RSSI of the neighbor's frames here, and of ours there
+    pub rssiin: Option<f32>,
+    pub rssiout: Option<i16>,
+    /// periodic frames heard from the neighbor within the window
+    pub samples: usize,
+}
+
+impl NeighborLink {
+    /// Expected transmissions to get a frame across and its ack back
+    pub fn etx(&self) -> Option<f32> {
+        let outbound = self.outbound?;
+        if self.inbound <= 0.0 || outbound <= 0.0 {
+            return None;
+        }
+        Some(1.0 / (self.inbound * outbound))
+    }
+
+    /// routing cost of the link, taken to be symmetric until the neighbor's hello arrives
+    pub fn cost(&self) -> f32 {
+        match self.outbound {
+            None => hop_cost(self.inbound),
+            Some(_) => self.etx().map_or(MAX_HOP_COST, |etx| etx.min(MAX_HOP_COST)),
+        }
+    }
+
+    /// Fold in the share of the neighbor's frames missed, from the gaps in their frame IDs
+    /* Both estimate the same delivery ratio, the heartbeat count over the
+    window and the frame IDs over every frame since the neighbor appeared. */
+    pub fn with_loss(mut self, loss: Option<f32>) -> Self {
+        if let Some(loss) = loss {
+            self.inbound = (self.inbound + (1.0 - loss).max(0.0)) / 2.0;
+        }
+        self
+    }
+
+    /// Cost of the link for routing, a single hop until `minsamples` frames were heard
+    pub fn route_cost(&self, minsamples: usize) -> f32 {
+        if self.samples < minsamples {
+            return hop_cost(1.0);
+        }
+        self.cost()
+    }
+}
+
+/// Estimates the share of a neighbor's periodic frames we receive
+/* Every node sends a heartbeat or broadcast each heartbeat interval, so
+the number heard within the window against the number sent gives the
+delivery ratio of the link towards us. */
+#[derive(Clone, Debug)]
+pub struct LinkEstimator {
+    interval: Duration,
+    window: Duration,
+    // first time heard, and the frames heard within the window
+    heard: HashMap<NodeId, (Instant, VecDeque<Instant>)>,
+    /// average RSSI of every neighbor
+    rssi: HashMap<NodeId, f32>,
+    /// how each neighbor hears us, from its last hello
+    reported: HashMap<NodeId, (f32, Option<i16>, Instant)>,
+}
+
+impl LinkEstimator {
+    pub fn new(interval: Duration, window: Duration) -> Self {
+        LinkEstimator { interval, window, heard: HashMap::new(), rssi: HashMap::new(), reported: HashMap::new() }
+    }
+
+    /// a frame was heard directly from a neighbor with this RSSI
+    pub fn record_rssi(&mut self, nodeid: NodeId, rssi: i16) {
+        let average = self.rssi.entry(nodeid).or_insert(rssi as f32);
+        *average += (rssi as f32 - *average) * RSSI_SMOOTHING;
+    }
+
+    /// a periodic frame was heard directly from a neighbor
+    pub fn record(&mut self, nodeid: NodeId) {
+        self.record_at(nodeid, Instant::now())
+    }
+
+    pub(crate) fn record_at(&mut self, nodeid: NodeId, now: Instant) {
+        let (_, times) = self.heard.entry(nodeid).or_insert_with(|| (now, VecDeque::new()));
+        times.push_back(now);
+    }
+
+    /// delivery ratio from a neighbor, between 0 and 1
+    pub fn quality_at(&mut self, nodeid: NodeId, now: Instant) -> f32 {
+        let window = self.window;
+        let interval = self.interval.as_secs_f32();
+        match self.heard.get_mut(&nodeid) {
+            None => 0.0,
+            Some((first, times)) => {
+                while times.front().map_or(false, |t| now.duration_since(*t) > window) {
+                    times.pop_front();
+                }
+                // a neighbor heard only recently hasn't had a full window to send in
+                let span = now.duration_since(*first).min(window).as_secs_f32();
+                let expected = (span / interval).floor().max(1.0);
+                (times.len() as f32 / expected).min(1.0)
+            }
+        }
+    }
+
+    /// every neighbor heard within the window, for our advertisement
+    pub fn links_at(&mut self, now: Instant) -> Vec<LsaLink> {
+        let ids: Vec<NodeId> = self.heard.keys().cloned().collect();
+        let mut links = Vec::new();
+        for nodeid in ids {
+            let quality = self.quality_at(nodeid, now);
+            if quality > 0.0 {
+                links.push(LsaLink { nodeid, quality: (quality * 255.0).round() as u8 });
+            } else {
+                self.heard.remove(&nodeid);
+                self.rssi.remove(&nodeid);
+                self.reported.remove(&nodeid);
+            }
+        }
+        links
+    }
+
+    /// The neighbors for our hello, strongest first
+    pub fn hello_at(&mut self, now: Instant) -> Vec<HelloNeighbor> {
+        let mut neighbors: Vec<HelloNeighbor> = self.links_at(now).into_iter()
+            .map(|link| HelloNeighbor {
+                nodeid: link.nodeid,
+                quality: link.quality,
+                rssi: self.rssi.get(&link.nodeid).map(|rssi| rssi.round() as i16),
+            })
+            .collect();
+        // neighbors without a reading go last
+        neighbors.sort_by_key(|n| (std::cmp::Reverse(n.rssi.unwrap_or(i16::MIN)), std::cmp::Reverse(n.quality), n.nodeid));
+        neighbors.truncate(MAX_HELLO_NEIGHBORS);
+        neighbors
+    }
+
+    /// Take note of how a neighbor hears us from its hello
+    pub fn handle_hello(&mut self, from: NodeId, ourid: NodeId, neighbors: &[HelloNeighbor]) {
+        self.handle_hello_at(from, ourid, neighbors, Instant::now())
+    }
+
+    pub(crate) fn handle_hello_at(&mut self, from: NodeId, ourid: NodeId, neighbors: &[HelloNeighbor], now: Instant) {
+        match neighbors.iter().find(|n| n.nodeid == ourid) {
+            Some(us) => { self.reported.insert(from, (us.quality as f32 / 255.0, us.rssi, now)); },
+            // a full hello may have left us out for stronger neighbors
+            None if neighbors.len() >= MAX_HELLO_NEIGHBORS => { self.reported.remove(&from); },
+            None => { self.reported.insert(from, (0.0, None, now)); },
+        }
+    }
+
+    /// Both directions of the link to a neighbor
+    pub fn neighbor_at(&mut self, nodeid: NodeId, now: Instant) -> NeighborLink {
+        let inbound = self.quality_at(nodeid, now);
+        let samples = self.heard.get(&nodeid).map_or(0, |(_, times)| times.len());
+        let window = self.window;
+        let (outbound, rssiout) = match self.reported.get(&nodeid) {
+            Some((quality, rssi, received)) if now.duration_since(*received) <= window => (Some(*quality), *rssi),
+            _ => (None, None),
+        };
+        NeighborLink { inbound, outbound, rssiin: self.rssi.get(&nodeid).cloned(), rssiout, samples }
+    }
+
+    /// Every neighbor heard within the window with both directions of its link, by node ID
+    pub fn neighbors_at(&mut self, now: Instant) -> Vec<(NodeId, NeighborLink)> {
+        let mut ids: Vec<NodeId> = self.links_at(now).iter().map(|link| link.nodeid).collect();
+        ids.sort();
+        ids.into_iter().map(|nodeid| (nodeid, self.neighbor_at(nodeid, now))).collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct LsaEntry {
+    seq: u16,
+    received: Instant,
+    links: Vec<LsaLink>,
+}
+
+/// Latest advertisement from every node, and the topology built from them
+#[derive(Clone, Debug)]
+pub struct LinkStateDb {
+    maxage: Duration,
+    entries: HashMap<NodeId, LsaEntry>,
+}
+
+impl LinkStateDb {
+    pub fn new(maxage: Duration) -> Self {
+        LinkStateDb { maxage, entries: HashMap::new() }
+    }
+
+    /// Store an advertisement, false if it is not newer than the one we have
+    pub fn update(&mut self, origin: NodeId, seq: u16, links: Vec<LsaLink>) -> bool {
+        self.update_at(origin, seq, links, Instant::now())
+    }
+
+    pub(crate) fn update_at(&mut self, origin: NodeId, seq: u16, links: Vec<LsaLink>, now: Instant) -> bool {
+        self.expire_at(now);
+        if let Some(entry) = self.entries.get(&origin) {
+            // sequence numbers wrap around
+            if (seq.wrapping_sub(entry.seq) as i16) <= 0 {
+                return false;
+            }
+        }
+        self.entries.insert(origin, LsaEntry { seq, received: now, links });
+        true
+    }
+
+    /// Drop advertisements older than the max age, returning their origins
+    pub fn expire(&mut self) -> Vec<NodeId> {
+        self.expire_at(Instant::now())
+    }
+
+    pub(crate) fn expire_at(&mut self, now: Instant) -> Vec<NodeId> {
+        let maxage = self.maxage;
+        let stale: Vec<NodeId> = self.entries.iter()
+            .filter(|(_, entry)| now.duration_since(entry.received) > maxage)
+            .map(|(id, _)| *id)
+            .collect();
+        stale.iter().for_each(|id| { self.entries.remove(id); });
+        stale
+    }
+
+    /// share of frames `to` receives from `from`, as advertised by `to`
+    fn reported(&self, from: NodeId, to: NodeId) -> Option<f32> {
+        self.entries.get(&to)
+            .and_then(|entry| entry.links.iter().find(|link| link.nodeid == from))
+            .map(|link| link.quality as f32 / 255.0)
+    }
+
+    /// Expected transmissions to get a frame across a link and its ack back
+    /* When only one end advertised the link it is taken to be symmetric. */
+    pub fn etx(&self, from: NodeId, to: NodeId) -> Option<f32> {
+        let (forward, reverse) = match (self.reported(from, to), self.reported(to, from)) {
+            (None, None) => return None,
+            (Some(f), None) => (f, f),
+            (None, Some(r)) => (r, r),
+            (Some(f), Some(r)) => (f, r),
+        };
+        if forward <= 0.0 || reverse <= 0.0 {
+            return None;
+        }
+        Some(1.0 / (forward * reverse))
+    }
+
+    /// Every advertised link with its ETX, sorted by node IDs
+    pub fn edges(&self) -> Vec<(NodeId, NodeId, f32)> {
+        let mut edges = Vec::new();
+        for (origin, entry) in self.entries.iter() {
+            for link in entry.links.iter() {
+                // each link in both directions, once
+                for (from, to) in [(link.nodeid, *origin), (*origin, link.nodeid)].iter() {
+                    if let Some(etx) = self.etx(*from, *to) {
+                        if !edges.iter().any(|(f, t, _)| f == from && t == to) {
+                            edges.push((*from, *to, etx));
+                        }
+                    }
+                }
+            }
+        }
+        edges.sort_by_key(|(from, to, _)| (*from, *to));
+        edges
+    }
+
+    /// Cheapest path by ETX, excluding `from`
+    pub fn route(&self, from: NodeId, dest: NodeId) -> Option<Vec<NodeId>> {
+        let mut graph: DiGraphMap<NodeId, f32> = DiGraphMap::new();
+        for (a, b, etx) in self.edges() {
+            graph.add_edge(a, b, etx);
+        }
+        if !graph.contains_node(from) || !graph.contains_node(dest) {
+            return None;
+        }
+        astar(&graph, from, |node| node == dest, |e| *e.2, |_| 0.0)
+            .map(|(_, path)| path.into_iter().skip(1).collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn link_estimator_quality() {
+    let start = Instant::now();
+    let mut estimator = LinkEstimator::new(Duration::from_secs(10), Duration::from_secs(100));
+    let at = |s: u64| start + Duration::from_secs(s);
+
+    // node 2 is heard every interval, node 3 every other one
+    for i in 0..20u64 {
+        estimator.record_at(2, at(i * 10));
+        if i % 2 == 0 {
+            estimator.record_at(3, at(i * 10));
+        }
+    }
+    assert!((estimator.quality_at(2, at(195)) - 1.0).abs() < 0.01);
+    assert!((estimator.quality_at(3, at(195)) - 0.5).abs() < 0.01);
+
+    // silent neighbors drop out of the advertisement
+    assert_eq!(estimator.links_at(at(400)), vec![]);
+}
+
+#[test]
+fn neighbor_hello_asymmetric() {
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::NeighborHelloMessage;
+    use crate::stack::Frame;
+
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+    let mut node1 = LinkEstimator::new(Duration::from_secs(10), Duration::from_secs(100));
+    let mut node2 = LinkEstimator::new(Duration::from_secs(10), Duration::from_secs(100));
+
+    // node 1 has the better antenna: it hears every frame of node 2,
+    // node 2 only every other one of node 1
+    for i in 0..20u64 {
+        node1.record_at(2, at(i * 10));
+        node1.record_rssi(2, -95);
+        if i % 2 == 0 {
+            node2.record_at(1, at(i * 10));
+            node2.record_rssi(1, -118);
+        }
+    }
+    // before any hello both take the link to be symmetric
+    assert_eq!(node1.neighbor_at(2, at(195)).outbound, None);
+    assert!((node1.neighbor_at(2, at(195)).cost() - 1.0).abs() < 0.01);
+
+    let hello = NeighborHelloMessage::new(node2.hello_at(at(195)));
+    let mut frame = Frame::from_bytes(&hello.to_frame(1, 2, vec![2]).unwrap().to_bytes()).unwrap();
+    let received = NeighborHelloMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(received.neighbors, vec![HelloNeighbor { nodeid: 1, quality: 128, rssi: Some(-118) }]);
+    node1.handle_hello_at(2, 1, &received.neighbors, at(195));
+
+    // the link is good one way and poor the other, ETX covers both
+    let link = node1.neighbor_at(2, at(195));
+    assert!((link.inbound - 1.0).abs() < 0.01);
+    assert!((link.outbound.unwrap() - 0.5).abs() < 0.01);
+    assert_eq!((link.rssiin, link.rssiout), (Some(-95.0), Some(-118)));
+    assert!((link.etx().unwrap() - 2.0).abs() < 0.1);
+    assert!((link.cost() - 2.0).abs() < 0.1);
+
+    // ten heartbeats within the window, too few to go by for a stricter node
+    assert_eq!(link.samples, 10);
+    assert!((link.route_cost(5) - 2.0).abs() < 0.1);
+    assert_eq!(link.route_cost(20), 1.0);
+    // frame ID gaps showing half the frames lost pull the inbound estimate down
+    assert!((link.with_loss(Some(0.5)).inbound - 0.75).abs() < 0.01);
+    assert_eq!(link.with_loss(None), link);
+
+    // a neighbor that doesn't list us can't hear us
+    node1.handle_hello_at(2, 1, &[], at(196));
+    assert_eq!(node1.neighbor_at(2, at(196)).etx(), None);
+    assert_eq!(node1.neighbor_at(2, at(196)).cost(), MAX_HOP_COST);
+}
+
+#[test]
+fn neighbor_hello_bounded() {
+    let start = Instant::now();
+    let mut estimator = LinkEstimator::new(Duration::from_secs(10), Duration::from_secs(100));
+    for nodeid in 1..=40u8 {
+        estimator.record_at(nodeid, start);
+        estimator.record_rssi(nodeid, -130 + nodeid as i16);
+    }
+    estimator.record_at(41, start);
+
+    // only the strongest neighbors are reported
+    let hello = estimator.hello_at(start);
+    assert_eq!(hello.len(), MAX_HELLO_NEIGHBORS);
+    assert_eq!(hello.first().map(|n| n.nodeid), Some(40));
+    assert!(hello.iter().all(|n| n.nodeid > 40 - MAX_HELLO_NEIGHBORS as u8));
+    assert_eq!(estimator.neighbors_at(start).len(), 41);
+}
+
+#[test]
+fn linkstate_etx_routing() {
+    let start = Instant::now();
+    let q = |quality: f32| (quality * 255.0) as u8;
+    let link = |nodeid: u8, quality: f32| LsaLink { nodeid, quality: q(quality) };
+    let mut db = LinkStateDb::new(Duration::from_secs(300));
+
+    // 1 - 2 - 5 is short but lossy, 1 - 3 - 4 - 5 is clean
+    db.update_at(1, 1, vec![link(2, 0.5), link(3, 1.0)], start);
+    db.update_at(2, 1, vec![link(1, 0.5), link(5, 0.5)], start);
+    db.update_at(3, 1, vec![link(1, 1.0), link(4, 1.0)], start);
+    db.update_at(4, 1, vec![link(3, 1.0), link(5, 1.0)], start);
+    db.update_at(5, 1, vec![link(2, 0.5), link(4, 1.0)], start);
+    assert!((db.etx(1, 2).unwrap() - 4.0).abs() < 0.1);
+    assert_eq!(db.route(1, 5), Some(vec![3, 4, 5]));
+
+    // older or repeated advertisements are ignored, wraparound is newer
+    assert!(!db.update_at(3, 1, vec![], start));
+    // node 3 barely hears node 4 any more
+    assert!(db.update_at(3, 2, vec![link(1, 1.0), link(4, 0.1)], start));
+    assert_eq!(db.route(1, 5), Some(vec![2, 5]));
+    assert!(db.update_at(6, u16::MAX, vec![], start));
+    assert!(db.update_at(6, 0, vec![], start));
+
+    // stale advertisements age out
+    let later = start + Duration::from_secs(200);
+    db.update_at(1, 2, vec![link(2, 0.5)], later);
+    db.update_at(2, 2, vec![link(1, 0.5)], later);
+    let mut expired = db.expire_at(start + Duration::from_secs(301));
+    expired.sort();
+    assert_eq!(expired, vec![3, 4, 5, 6]);
+    assert_eq!(db.route(1, 5), None);
+    assert_eq!(db.len(), 2);
+    assert!(!db.is_empty());
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::stack::frame::NodeId;
+use crate::stack::message::PositionMessage;
+
+/// mean radius of the earth in meters
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// Great circle distance in meters between two points given in degrees
+pub fn distance_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// Decides when our own position is announced
+/* Positions are sent at a fixed interval, or sooner once the node moved
+further than the distance threshold since the last announcement. */
+#[derive(Clone, Debug)]
+pub struct PositionTracker {
+    interval: Duration,
+    distance: f64,
+    // time and coordinates of the last announcement
+    last: Option<(Instant, f64, f64)>,
+}
+
+impl PositionTracker {
+    pub fn new(interval: Duration, distance: f64) -> Self {
+        PositionTracker { interval, distance, last: None }
+    }
+
+    /// true if a position should be announced now, recording it if so
+    pub fn should_send(&mut self, lat: f64, lon: f64) -> bool {
+        self.should_send_at(Instant::now(), lat, lon)
+    }
+
+    pub(crate) fn should_send_at(&mut self, now: Instant, lat: f64, lon: f64) -> bool {
+        let due = match self.last {
+            None => true,
+            Some((sent, lastlat, lastlon)) => {
+                now.duration_since(sent) >= self.interval
+                    || distance_m(lastlat, lastlon, lat, lon) >= self.distance
+            }
+        };
+        if due {
+            self.last = Some((now, lat, lon));
+        }
+        due
+    }
+}
+
+/// Last known position of every node we heard from
+#[derive(Clone, Debug, Default)]
+pub struct PositionTable {
+    positions: HashMap<NodeId, (PositionMessage, Instant)>,
+}
+
+impl PositionTable {
+    pub fn new() -> Self {
+        PositionTable { positions: HashMap::new() }
+    }
+
+    /// record a node's position, older reports are ignored
+    pub fn update(&mut self, nodeid: NodeId, position: PositionMessage) {
+        if let Some((known, _)) = self.positions.get(&nodeid) {
+            if known.timestamp > position.timestamp {
+                return;
+            }
+        }
+        self.positions.insert(nodeid, (position, Instant::now()));
+    }
+
+    pub fn get(&self, nodeid: NodeId) -> Option<&PositionMessage> {
+        self.positions.get(&nodeid).map(|(position, _)| position)
+    }
+
+    /// every known position with the time it was received, by node ID
+    pub fn all(&self) -> Vec<(NodeId, &PositionMessage, Instant)> {
+        let mut all: Vec<_> = self.positions.iter()
+            .map(|(id, (position, received))| (id.clone(), position, received.clone()))
+            .collect();
+        all.sort_by_key(|(id, _, _)| *id);
+        all
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn position_tracking() {
+    // one degree of latitude is about 111km
+    assert!((distance_m(0.0, 0.0, 1.0, 0.0) - 111_195.0).abs() < 10.0);
+    assert!(distance_m(48.1173, 11.5166, 48.1173, 11.5166) < 1e-6);
+
+    let start = Instant::now();
+    let mut tracker = PositionTracker::new(Duration::from_secs(300), 50.0);
+    assert!(tracker.should_send_at(start, 48.0, 11.0));
+    // ~11m of drift is not enough
+    assert!(!tracker.should_send_at(start + Duration::from_secs(10), 48.0001, 11.0));
+    // ~111m is
+    assert!(tracker.should_send_at(start + Duration::from_secs(20), 48.001, 11.0));
+    assert!(!tracker.should_send_at(start + Duration::from_secs(300), 48.001, 11.0));
+    assert!(tracker.should_send_at(start + Duration::from_secs(320), 48.001, 11.0));
+
+    let mut table = PositionTable::new();
+    table.update(3, PositionMessage::new(1.0, 2.0, 0.0, 0.0, 1, 200));
+    table.update(3, PositionMessage::new(5.0, 5.0, 0.0, 0.0, 1, 100));
+    table.update(2, PositionMessage::new(0.0, 0.0, 0.0, 0.0, 1, 100));
+    assert_eq!(table.get(3).unwrap().lat_degrees(), 1.0);
+    assert_eq!(table.all().iter().map(|(id, _, _)| *id).collect::<Vec<u8>>(), vec![2, 3]);
+}
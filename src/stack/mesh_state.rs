@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::stack::frame::NodeId;
+
+/// How long routes read from disk may wait for a ping to confirm them
+pub const STALE_ROUTE_TTL: Duration = Duration::from_secs(60);
+
+/// Format of the state file, a file of another version is discarded
+pub const MESH_STATE_VERSION: u32 = 2;
+
+/// What the node knew about the mesh, kept across restarts
+/* Only the nodes, their addresses and the links between them are kept,
+anything with a timestamp would be meaningless after a reboot. What is
+read back is stale until the mesh confirms it: routes until a ping gets
+through, neighbors until they are heard, gateways until they announce
+themselves. */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MeshState {
+    /// files written before versioning read as 0
+    #[serde(default)]
+    pub version: u32,
+    /// every known node with its IP address, if it has one
+    pub nodes: BTreeMap<NodeId, Option<Ipv4Addr>>,
+    /// links of the mesh graph
+    pub links: Vec<(NodeId, NodeId)>,
+    /// IP address of the internet gateway
+    pub gateway: Option<Ipv4Addr>,
+    /// gateways heard announcing themselves, and whether they had an uplink
+    #[serde(default)]
+    pub gateways: Vec<(NodeId, bool)>,
+    /// nodes heard directly
+    #[serde(default)]
+    pub neighbors: Vec<NodeId>,
+    /// IP address the gateway leased us
+    #[serde(default)]
+    pub ipaddr: Option<Ipv4Addr>,
+    /// last frame ID we sent to each destination
+    #[serde(default)]
+    pub frameids: Vec<(NodeId, u8)>,
+    /// highest frame ID seen from each sender, per destination counter, None for broadcasts
+    #[serde(default)]
+    pub replay: Vec<(NodeId, Option<NodeId>, u8)>,
+}
+
+impl MeshState {
+    /// Write the state to a file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string(self).expect("Mesh state is always serializable");
+        // a crash while writing leaves the old state intact
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Read the state saved to a file, an error if it is corrupt or of another version
+    pub fn load(path: &Path) -> io::Result<MeshState> {
+        let json = fs::read_to_string(path)?;
+        let state: MeshState = serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if state.version != MESH_STATE_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("state file version {} is not {}", state.version, MESH_STATE_VERSION)));
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn mesh_state_restart() {
+    use std::time::Instant;
+    use crate::stack::router::MeshRouter;
+
+    let mut router = MeshRouter::new(1, None, 3, Duration::from_secs(10), false);
+    router.route_add(vec![(1, 2), (2, 3), (3, 4)]);
+    router.ip_add(4, Ipv4Addr::new(172, 16, 0, 4));
+    router.handle_gateway_assignment(&Ipv4Addr::new(172, 16, 0, 4));
+    let mut state = router.state();
+    assert_eq!(state.links, vec![(1, 2), (2, 3), (3, 4)]);
+    // the node adds what it knows itself
+    state.neighbors = vec![2];
+    state.ipaddr = Some(Ipv4Addr::new(172, 16, 0, 9));
+    state.frameids = vec![(4, 200)];
+    state.replay = vec![(2, None, 17), (4, Some(1), 3)];
+
+    let path = std::env::temp_dir().join(format!("loramesh-state-{}.json", std::process::id()));
+    state.save(&path).unwrap();
+    let loaded = MeshState::load(&path).unwrap();
+    assert_eq!(loaded, state);
+
+    // a corrupt file, or one written by another version, is refused
+    fs::write(&path, "{\"nodes\":").unwrap();
+    assert_eq!(MeshState::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    MeshState { version: 1, ..state.clone() }.save(&path).unwrap();
+    assert_eq!(MeshState::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    fs::remove_file(&path).unwrap();
+
+    // restored routes are not used until a ping confirms them
+    let start = Instant::now();
+    let mut restarted = MeshRouter::new(1, None, 3, Duration::from_secs(10), false);
+    let mut stale = restarted.restore_at(loaded, start);
+    stale.sort();
+    assert_eq!(stale, vec![(2, vec![2]), (3, vec![2, 3]), (4, vec![2, 3, 4])]);
+    assert_eq!(restarted.node_route(4), None);
+    assert_eq!(restarted.gateway_id(), Some(4));
+    // a confirmed route confirms the ones along it
+    assert!(restarted.confirm_stale(3));
+    assert!(!restarted.confirm_stale(2));
+    assert_eq!(restarted.node_route(3), Some(vec![2, 3]));
+    assert_eq!(restarted.node_route(4), None);
+
+    // unconfirmed ones are dropped once their time is up
+    assert_eq!(restarted.expire_stale_at(start + STALE_ROUTE_TTL + Duration::from_secs(1)), vec![4]);
+    assert!(!restarted.confirm_stale(4));
+}
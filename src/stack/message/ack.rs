@@ -0,0 +1,79 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::hardware::LinkQuality;
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, NodeId, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// bytes of every acknowledged frame: frame ID, RSSI and SNR
+const ACK_ENTRY_LEN: usize = 4;
+
+/// stands in for a reading the radio did not report
+const NO_RSSI: i16 = i16::MIN;
+const NO_SNR: i8 = i8::MIN;
+
+/// A frame that arrived, with the signal it arrived with
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct AckEntry {
+    pub frameid: u8,
+    pub quality: LinkQuality,
+}
+
+/// Confirms frames that asked for an ack, several from the same node at once
+/* Acks never ask for an ack themselves. The signal quality of each acked
+frame tells its sender how well we hear it, for free. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct AckMessage {
+    pub header: Option<FrameHeader>,
+    /// the node that sent the acknowledged frames
+    pub origin: NodeId,
+    pub acks: Vec<AckEntry>,
+}
+
+impl AckMessage {
+    pub fn new(origin: NodeId, acks: Vec<AckEntry>) -> Self {
+        AckMessage { header: None, origin, acks }
+    }
+}
+
+impl ToFromFrame for AckMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 1 + ACK_ENTRY_LEN || (data.len() - 1) % ACK_ENTRY_LEN != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "ack payload has an invalid length"));
+        }
+        let acks = data[1..].chunks(ACK_ENTRY_LEN).map(|entry| {
+            let rssi = i16::from_be_bytes([entry[1], entry[2]]);
+            let snr = entry[3] as i8;
+            AckEntry {
+                frameid: entry[0],
+                quality: LinkQuality {
+                    rssi: if rssi == NO_RSSI { None } else { Some(rssi) },
+                    snr: if snr == NO_SNR { None } else { Some(snr) },
+                },
+            }
+        }).collect();
+        Ok(Box::new(AckMessage { header: Some(f.header()), origin: data[0], acks }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(1 + self.acks.len() * ACK_ENTRY_LEN);
+        data.push(self.origin);
+        for ack in self.acks.iter() {
+            data.push(ack.frameid);
+            data.extend_from_slice(&ack.quality.rssi.unwrap_or(NO_RSSI).to_be_bytes());
+            data.push(ack.quality.snr.unwrap_or(NO_SNR) as u8);
+        }
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Ack)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
@@ -0,0 +1,114 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+use crate::stack::util::parse_ipv4;
+
+/// Flooded to find the node holding a mesh address
+/* The TTL is the number of hops the query may still be relayed, a node
+receiving it with 0 left answers it but does not send it on. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct AddressQueryMessage {
+    pub header: Option<FrameHeader>,
+    pub ipaddr: Ipv4Addr,
+    pub ttl: u8,
+}
+
+impl AddressQueryMessage {
+    pub fn new(ipaddr: Ipv4Addr, ttl: u8) -> Self {
+        AddressQueryMessage { header: None, ipaddr, ttl }
+    }
+}
+
+impl ToFromFrame for AddressQueryMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 5 {
+            return Err(Error::new(ErrorKind::InvalidData, "address query payload is too short"));
+        }
+        Ok(Box::new(AddressQueryMessage {
+            header: Some(f.header()),
+            ipaddr: parse_ipv4(&data[0..4]),
+            ttl: data[4],
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut payload = self.ipaddr.octets().to_vec();
+        payload.push(self.ttl);
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::AddressQuery)
+            .sender(sender)
+            .route(&route)
+            .payload(payload)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+/// Sent back by the node holding an address, the sender of the frame is its owner
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct AddressAnswerMessage {
+    pub header: Option<FrameHeader>,
+    pub ipaddr: Ipv4Addr,
+}
+
+impl AddressAnswerMessage {
+    pub fn new(ipaddr: Ipv4Addr) -> Self {
+        AddressAnswerMessage { header: None, ipaddr }
+    }
+}
+
+impl ToFromFrame for AddressAnswerMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidData, "address answer payload is too short"));
+        }
+        Ok(Box::new(AddressAnswerMessage {
+            header: Some(f.header()),
+            ipaddr: parse_ipv4(&data[0..4]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::AddressAnswer)
+            .sender(sender)
+            .route(&route)
+            .payload(self.ipaddr.octets().to_vec())
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn address_tofrom_frame() {
+    let ipaddr = Ipv4Addr::new(172, 16, 0, 9);
+    let mut frame = Frame::from_bytes(&AddressQueryMessage::new(ipaddr, 3).to_frame(5, 2, vec![2]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::AddressQuery);
+    let query = AddressQueryMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((query.ipaddr, query.ttl), (ipaddr, 3));
+
+    let mut frame = Frame::from_bytes(&AddressAnswerMessage::new(ipaddr).to_frame(6, 9, vec![4, 2]).unwrap().to_bytes()).unwrap();
+    assert_eq!((frame.msgtype(), frame.sender()), (MessageType::AddressAnswer, 9));
+    assert_eq!(AddressAnswerMessage::from_frame(&mut frame).unwrap().ipaddr, ipaddr);
+
+    let mut short = FrameBuilder::new()
+        .frameid(7)
+        .message_type(MessageType::AddressQuery)
+        .sender(2)
+        .route(&[2])
+        .payload(ipaddr.octets().to_vec())
+        .build()
+        .unwrap();
+    assert!(AddressQueryMessage::from_frame(&mut short).is_err());
+}
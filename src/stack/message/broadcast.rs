@@ -1,42 +1,69 @@
 use lz4_flex::block::{compress_prepend_size, decompress_size_prepended};
-use std::net::Ipv4Addr;
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use crate::stack::Frame;
-use crate::stack::frame::{FrameHeader, ToFromFrame};
-use crate::stack::util::{parse_bool, parse_ipv4, parse_byte};
-use crate::message::MessageType;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame, FRAME_HEADER_LEN, MAX_FRAME_LEN};
+use crate::stack::ipv6::parse_ipv6;
+use crate::stack::util::{parse_bool, parse_ipv4, parse_byte, parse_tlv, push_tlv};
+use crate::stack::message::MessageType;
 use lz4::{Decoder, EncoderBuilder};
 
+/// Extension entry a node's IPv6 address is sent in, it never shows up in `custom_tlv`
+/* Nodes that don't know it keep it as application metadata. */
+pub const BROADCAST_TLV_IPV6: u8 = 0xf6;
 
 /// Broadcast this node to nearby devices.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
 pub struct BroadcastMessage {
     pub header: Option<FrameHeader>,
     pub isgateway: bool,
     pub ipOffset: usize,
-    pub ipaddr: Option<Ipv4Addr>
+    pub ipaddr: Option<Ipv4Addr>,
+    /// the node's address in the mesh's IPv6 prefix
+    pub ipv6addr: Option<Ipv6Addr>,
+    /// application metadata as (type, value) pairs, sent after the IP address
+    #[cfg_attr(feature = "serde-support", serde(with = "crate::stack::base64_serde::tlv"))]
+    pub custom_tlv: Vec<(u8, Vec<u8>)>
 }
 
 impl ToFromFrame for BroadcastMessage {
     fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
         let header = f.header();
         let data = f.payload();
-        let isgateway = parse_bool(data[0]).unwrap();
+        if data.len() < 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "broadcast payload is too short"));
+        }
+        if data[0] > 1 {
+            return Err(Error::new(ErrorKind::InvalidData, "broadcast gateway flag is not a boolean"));
+        }
+        let isgateway = parse_bool(data[0])?;
         let offset = data[1] as usize;
         let mut ipaddr: Option<Ipv4Addr> = None;
         if offset > 0 as usize {
+            if data.len() < 6 {
+                return Err(Error::new(ErrorKind::InvalidData, "broadcast IP address is truncated"));
+            }
             let octets = &data[2..6];
             ipaddr = Some(parse_ipv4(octets));
         }
+        let mut ipv6addr = None;
+        let mut custom_tlv = parse_tlv(data.get(2+offset..).unwrap_or(&[]));
+        if let Some(at) = custom_tlv.iter().position(|(tlvtype, value)| *tlvtype == BROADCAST_TLV_IPV6 && value.len() == 16) {
+            ipv6addr = Some(parse_ipv6(&custom_tlv.remove(at).1));
+        }
 
         Ok(Box::new(BroadcastMessage {
             header: Some(header),
             isgateway,
             ipOffset: offset,
-            ipaddr
+            ipaddr,
+            ipv6addr,
+            custom_tlv
         }))
     }
 
-    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> Frame {
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> std::io::Result<Frame> {
         // write the payload
         let mut payload: Vec<u8> = Vec::new();
         payload.push(parse_byte(self.isgateway));
@@ -51,19 +78,29 @@ impl ToFromFrame for BroadcastMessage {
             payload.push(0usize as u8);
         }
 
-        // cast the route
-        let route: Vec<u8> = route.clone().iter().map(|i| i.clone() as u8).collect();
-        let routeoffset = route.len() as u8;
-
-        Frame::new(
-            0i8 as u8,
-            frameid,
-            MessageType::Broadcast as u8,
-            sender as u8,
-            routeoffset as u8,
-            route,
-            payload
-        )
+        // write as many extension entries as fit in the frame
+        let mut budget = MAX_FRAME_LEN.saturating_sub(FRAME_HEADER_LEN + route.len() + payload.len());
+        // the address goes first, it is what other nodes route by
+        if let Some(ipv6addr) = self.ipv6addr {
+            push_tlv(&mut payload, BROADCAST_TLV_IPV6, &ipv6addr.octets());
+            budget = budget.saturating_sub(18);
+        }
+        for (tlvtype, value) in self.custom_tlv.iter() {
+            if value.len() > u8::MAX as usize || value.len() + 2 > budget {
+                break;
+            }
+            push_tlv(&mut payload, tlvtype.clone(), value);
+            budget -= value.len() + 2;
+        }
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Broadcast)
+            .sender(sender)
+            .route(&route)
+            .payload(payload)
+            .build()
+            .map_err(Into::into)
     }
 }
 
@@ -76,13 +113,15 @@ fn broadcast_tofrom_frame() {
         header: None,
         isgateway,
         ipOffset: 4,
-        ipaddr: Some(Ipv4Addr::new(172,16,0,id.clone() as u8))
+        ipaddr: Some(Ipv4Addr::new(172,16,0,id.clone() as u8)),
+        ipv6addr: None,
+        custom_tlv: Vec::new()
     };
     let mut route: Vec<u8> = Vec::new();
     route.push(id.clone());
 
     // check tofrom frame
-    let mut frame = msg.to_frame(1u8, id, route);
+    let mut frame = msg.to_frame(1u8, id, route).unwrap();
 
     assert_eq!(frame.sender(), id);
     assert_eq!(frame.payload().get(0).unwrap().clone() as i8, 0i8);
@@ -111,6 +150,47 @@ fn broadcast_tofrom_frame() {
     assert_eq!(msg2.ipaddr.unwrap(), msg.ipaddr.unwrap());
 }
 
+#[test]
+fn broadcast_custom_tlv() {
+    let mut msg = BroadcastMessage {
+        header: None,
+        isgateway: true,
+        ipOffset: 0,
+        ipaddr: None,
+        ipv6addr: None,
+        custom_tlv: vec![(1, vec![0x0c, 0xe4]), (200, Vec::from("v0.1.1"))]
+    };
+
+    let mut frame = msg.to_frame(1u8, 2, vec![2]).unwrap();
+    let msg2 = BroadcastMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(msg2.ipaddr, None);
+    assert_eq!(msg2.custom_tlv, msg.custom_tlv);
+
+    // an IPv6 address rides along as an extension of its own
+    let mut v6 = msg.clone();
+    v6.ipv6addr = Some("fd6c:6d00::2".parse().unwrap());
+    let mut frame = v6.to_frame(1u8, 2, vec![2]).unwrap();
+    assert_eq!(&frame.payload()[2..4], &[BROADCAST_TLV_IPV6, 16]);
+    let msg2 = BroadcastMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((msg2.ipv6addr, msg2.custom_tlv), (v6.ipv6addr, msg.custom_tlv.clone()));
+
+    // entries beyond the frame budget are dropped instead of panicking
+    msg.custom_tlv.push((2, vec![0u8; 255]));
+    let mut frame = msg.to_frame(1u8, 2, vec![2]).unwrap();
+    assert!(frame.to_bytes().len() <= MAX_FRAME_LEN);
+    let msg3 = BroadcastMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(msg3.custom_tlv.len(), 2);
+
+    // a truncated trailing entry is ignored
+    assert_eq!(parse_tlv(&[7, 1, 9, 8, 5, 1]), vec![(7, vec![9])]);
+
+    // malformed payloads are errors, not panics
+    for payload in [vec![], vec![1], vec![2, 0], vec![0, 4, 10, 0]].iter() {
+        let mut frame = frame.clone().with_payload(payload.clone());
+        assert!(BroadcastMessage::from_frame(&mut frame).is_err());
+    }
+}
+
 #[test]
 fn lz4test(){
     let input: &[u8] = b"Hello people, what's up?";
@@ -0,0 +1,71 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// An IP packet with its headers compressed, see `HeaderCompression`
+/* The context ID comes first, the compressed headers and the rest of
+the packet after it. Only sent to nodes whose info says they take it. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompressedIPPacketMessage {
+    pub header: Option<FrameHeader>,
+    pub context: u8,
+    #[cfg_attr(feature = "serde-support", serde(with = "crate::stack::base64_serde"))]
+    pub data: Vec<u8>,
+}
+
+impl CompressedIPPacketMessage {
+    pub fn new(context: u8, data: Vec<u8>) -> Self {
+        CompressedIPPacketMessage { header: None, context, data }
+    }
+}
+
+impl ToFromFrame for CompressedIPPacketMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "compressed IP packet is too short"));
+        }
+        Ok(Box::new(CompressedIPPacketMessage {
+            header: Some(f.header()),
+            context: data[0],
+            data: Vec::from(&data[1..]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut payload = vec![self.context];
+        payload.extend_from_slice(&self.data);
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::CompressedIPPacket)
+            .sender(sender)
+            .route(&route)
+            .payload(payload)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn compressed_ippacket_tofrom_frame() {
+    let msg = CompressedIPPacketMessage::new(7, vec![0x20, 0x18, 0xab, 0xcd, b'h', b'i']);
+    let mut frame = Frame::from_bytes(&msg.to_frame(5, 3, vec![9]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::CompressedIPPacket);
+    let received = CompressedIPPacketMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.context, received.data), (7, msg.data));
+
+    let mut short = FrameBuilder::new()
+        .frameid(6)
+        .message_type(MessageType::CompressedIPPacket)
+        .sender(3)
+        .route(&[9])
+        .payload(vec![7])
+        .build()
+        .unwrap();
+    assert!(CompressedIPPacketMessage::from_frame(&mut short).is_err());
+}
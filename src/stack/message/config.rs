@@ -0,0 +1,143 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+use crate::stack::util::{parse_tlv, push_tlv};
+
+/// TLV types of the strings
+const CONFIG_KEY: u8 = 1;
+const CONFIG_VALUE: u8 = 2;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// A setting to change on another node, signed with the shared config key
+/* The first 16 bytes of the signature are a nonce, the last 16 the
+Poly1305 tag, see `stack::config`. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConfigMessage {
+    pub header: Option<FrameHeader>,
+    pub key: String,
+    pub value: String,
+    /// node the change comes from
+    pub requester_id: u8,
+    #[cfg_attr(feature = "serde-support", serde(with = "crate::stack::base64_serde"))]
+    pub signature: [u8; 32],
+}
+
+/// Whether a config change was applied, sent back to the requester
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConfigAckMessage {
+    pub header: Option<FrameHeader>,
+    /// nonce of the change
+    pub nonce: u64,
+    pub ok: bool,
+    /// the new value, or why the change was rejected
+    pub output: String,
+}
+
+impl ToFromFrame for ConfigMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 33 {
+            return Err(invalid("config payload is too short"));
+        }
+        let mut signature = [0u8; 32];
+        signature.copy_from_slice(&data[1..33]);
+        let mut key = None;
+        let mut value = None;
+        for (tlvtype, bytes) in parse_tlv(&data[33..]) {
+            let text = String::from_utf8(bytes).map_err(|_| invalid("config is not UTF-8"))?;
+            match tlvtype {
+                CONFIG_KEY => key = Some(text),
+                CONFIG_VALUE => value = Some(text),
+                _ => {}
+            }
+        }
+        Ok(Box::new(ConfigMessage {
+            header: Some(f.header()),
+            key: key.ok_or(invalid("config is missing the key"))?,
+            value: value.ok_or(invalid("config is missing the value"))?,
+            requester_id: data[0],
+            signature,
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = vec![self.requester_id];
+        data.extend_from_slice(&self.signature);
+        push_tlv(&mut data, CONFIG_KEY, self.key.as_bytes());
+        push_tlv(&mut data, CONFIG_VALUE, self.value.as_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Config)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+impl ToFromFrame for ConfigAckMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 9 {
+            return Err(invalid("config ack payload is too short"));
+        }
+        let mut nonce = [0u8; 8];
+        nonce.copy_from_slice(&data[..8]);
+        Ok(Box::new(ConfigAckMessage {
+            header: Some(f.header()),
+            nonce: u64::from_be_bytes(nonce),
+            ok: data[8] != 0,
+            output: String::from_utf8_lossy(&data[9..]).into_owned(),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::from(&self.nonce.to_be_bytes()[..]);
+        data.push(self.ok as u8);
+        data.extend_from_slice(self.output.as_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::ConfigAck)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn config_tofrom_frame() {
+    let mut signature = [0u8; 32];
+    signature[0] = 0x17;
+    signature[31] = 0xa5;
+    let msg = ConfigMessage {
+        header: None,
+        key: String::from("hellointerval"),
+        value: String::from("30000"),
+        requester_id: 1,
+        signature,
+    };
+    let mut frame = Frame::from_bytes(&msg.to_frame(5, 1, vec![4, 6]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::Config);
+    let received = ConfigMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.key.as_str(), received.value.as_str()), ("hellointerval", "30000"));
+    assert_eq!((received.requester_id, received.signature), (1, signature));
+
+    let ack = ConfigAckMessage { header: None, nonce: 1_600_000_000_123, ok: false, output: String::from("bad signature") };
+    let mut frame = Frame::from_bytes(&ack.to_frame(6, 4, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::ConfigAck);
+    let received = ConfigAckMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.nonce, received.ok, received.output.as_str()), (1_600_000_000_123, false, "bad signature"));
+}
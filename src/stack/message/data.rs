@@ -0,0 +1,99 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::compression::{CompressionType, MeshCompressor};
+use crate::stack::frame::{FrameBuilder, FrameHeader, NodeId, ToFromFrame, MAX_PAYLOAD_LEN};
+use crate::stack::message::MessageType;
+
+/// destination, port and compression type ahead of the payload
+const DATA_HEADER_LEN: usize = 3;
+
+/// Largest data payload, it fits a frame even where it does not compress
+pub const MAX_DATA_LEN: usize = MAX_PAYLOAD_LEN - DATA_HEADER_LEN;
+
+/// Raw application payload for a port on another node
+/* Ports multiplex application streams over the mesh, much like UDP
+ports. The payload is opaque to the mesh, larger ones are chunked. It is
+sent LZ4 compressed when that is smaller, the compression type goes in
+the byte after the port. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct DataMessage {
+    pub header: Option<FrameHeader>,
+    pub destination: NodeId,
+    pub port: u8,
+    #[cfg_attr(feature = "serde-support", serde(with = "crate::stack::base64_serde"))]
+    pub payload: Vec<u8>,
+}
+
+impl DataMessage {
+    pub fn new(destination: NodeId, port: u8, payload: Vec<u8>) -> io::Result<Self> {
+        if payload.len() > MAX_DATA_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("data is {} bytes, the maximum is {}", payload.len(), MAX_DATA_LEN)));
+        }
+        Ok(DataMessage { header: None, destination, port, payload })
+    }
+}
+
+impl ToFromFrame for DataMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < DATA_HEADER_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "data payload is too short"));
+        }
+        let ctype = CompressionType::from_u8(data[2])?;
+        Ok(Box::new(DataMessage {
+            header: Some(f.header()),
+            destination: data[0],
+            port: data[1],
+            payload: MeshCompressor::decompress(ctype, &data[DATA_HEADER_LEN..])?,
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let (ctype, payload) = MeshCompressor::compress(&self.payload);
+        let mut data = Vec::with_capacity(DATA_HEADER_LEN + payload.len());
+        data.push(self.destination);
+        data.push(self.port);
+        data.push(ctype.to_u8());
+        data.extend_from_slice(&payload);
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Data)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn data_tofrom_frame() {
+    let msg = DataMessage::new(4, 80, vec![0, 1, 2, 255]).unwrap();
+    let mut frame = Frame::from_bytes(&msg.to_frame(3, 1, vec![2, 4]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::Data);
+    let received = DataMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.destination, received.port), (4, 80));
+    assert_eq!(received.payload, vec![0, 1, 2, 255]);
+
+    let mut empty = Frame::from_bytes(&DataMessage::new(4, 0, vec![]).unwrap().to_frame(3, 1, vec![4]).unwrap().to_bytes()).unwrap();
+    assert!(DataMessage::from_frame(&mut empty).unwrap().payload.is_empty());
+
+    // a repetitive payload travels compressed
+    let log = b"ok ok ok ok ok ok ok ok ok ok ok ok ok ok ok ok".to_vec();
+    let mut frame = DataMessage::new(4, 80, log.clone()).unwrap().to_frame(4, 1, vec![4]).unwrap();
+    assert!(frame.payload().len() < 2 + log.len());
+    assert_eq!(DataMessage::from_frame(&mut frame).unwrap().payload, log);
+
+    // the largest payload fits even when it does not compress, a larger one is refused
+    use rand::{thread_rng, Rng};
+    let mut noise = vec![0u8; MAX_DATA_LEN];
+    thread_rng().fill(&mut noise[..]);
+    let mut frame = DataMessage::new(4, 80, noise.clone()).unwrap().to_frame(5, 1, vec![4]).unwrap();
+    assert_eq!(DataMessage::from_frame(&mut frame).unwrap().payload, noise);
+    noise.push(0);
+    assert!(DataMessage::new(4, 80, noise).is_err());
+}
@@ -0,0 +1,81 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, NodeId, ToFromFrame, FRAME_HEADER_LEN, FRAME_SEAL_LEN, MAX_FRAME_LEN, MAX_ROUTE_LEN};
+use crate::stack::message::MessageType;
+
+/// destination and port ahead of a datagram's payload
+const DATAGRAM_HEADER_LEN: usize = 3;
+
+/// Largest datagram payload, it fits a single frame with the longest route, encrypted and signed
+pub const MAX_DATAGRAM_LEN: usize = MAX_FRAME_LEN - FRAME_HEADER_LEN - MAX_ROUTE_LEN - FRAME_SEAL_LEN - DATAGRAM_HEADER_LEN;
+
+/// A small payload for a 16 bit port on another node, never chunked nor acknowledged
+/* Like UDP it may be lost on the way, it is up to the application to
+send it again. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct DatagramMessage {
+    pub header: Option<FrameHeader>,
+    pub destination: NodeId,
+    pub port: u16,
+    #[cfg_attr(feature = "serde-support", serde(with = "crate::stack::base64_serde"))]
+    pub payload: Vec<u8>,
+}
+
+impl DatagramMessage {
+    pub fn new(destination: NodeId, port: u16, payload: Vec<u8>) -> io::Result<Self> {
+        if payload.len() > MAX_DATAGRAM_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("datagram is {} bytes, the maximum is {}", payload.len(), MAX_DATAGRAM_LEN)));
+        }
+        Ok(DatagramMessage { header: None, destination, port, payload })
+    }
+}
+
+impl ToFromFrame for DatagramMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < DATAGRAM_HEADER_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "datagram payload is too short"));
+        }
+        Ok(Box::new(DatagramMessage {
+            header: Some(f.header()),
+            destination: data[0],
+            port: u16::from_be_bytes([data[1], data[2]]),
+            payload: data[DATAGRAM_HEADER_LEN..].to_vec(),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(DATAGRAM_HEADER_LEN + self.payload.len());
+        data.push(self.destination);
+        data.extend_from_slice(&self.port.to_be_bytes());
+        data.extend_from_slice(&self.payload);
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Datagram)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn datagram_tofrom_frame() {
+    let msg = DatagramMessage::new(4, 5683, vec![0, 1, 2, 255]).unwrap();
+    let mut frame = Frame::from_bytes(&msg.to_frame(3, 1, vec![2, 4]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::Datagram);
+    let received = DatagramMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.destination, received.port), (4, 5683));
+    assert_eq!(received.payload, vec![0, 1, 2, 255]);
+
+    // the largest one still fits a frame over the longest route
+    let route: Vec<u8> = (1..=MAX_ROUTE_LEN as u8).collect();
+    let frame = DatagramMessage::new(4, 1, vec![7; MAX_DATAGRAM_LEN]).unwrap().to_frame(3, 1, route).unwrap().to_bytes();
+    assert!(frame.len() + FRAME_SEAL_LEN <= MAX_FRAME_LEN);
+    assert!(DatagramMessage::new(4, 1, vec![7; MAX_DATAGRAM_LEN + 1]).is_err());
+}
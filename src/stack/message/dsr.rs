@@ -0,0 +1,153 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, NodeId, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// Looks for a source route to a node, flooded through the mesh
+/* Each relay appends itself to `record`, so the target receives the
+whole path from the origin. The frame route only holds the origin, as
+for any other flood. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct DsrRequestMessage {
+    pub header: Option<FrameHeader>,
+    /// numbered by the origin, copies with the same one are the same request
+    pub id: u16,
+    pub target: NodeId,
+    /// the origin and every relay so far
+    pub record: Vec<NodeId>,
+}
+
+impl DsrRequestMessage {
+    pub fn new(id: u16, target: NodeId, origin: NodeId) -> Self {
+        DsrRequestMessage { header: None, id, target, record: vec![origin] }
+    }
+}
+
+impl ToFromFrame for DsrRequestMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidData, "DSR request payload is too short"));
+        }
+        Ok(Box::new(DsrRequestMessage {
+            header: Some(f.header()),
+            id: u16::from_be_bytes([data[0], data[1]]),
+            target: data[2],
+            record: data[3..].to_vec(),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = self.id.to_be_bytes().to_vec();
+        data.push(self.target);
+        data.extend_from_slice(&self.record);
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::DsrRequest)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+/// Answer to a DSR request, the recorded path from origin to target
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct DsrReplyMessage {
+    pub header: Option<FrameHeader>,
+    pub path: Vec<NodeId>,
+}
+
+impl DsrReplyMessage {
+    pub fn new(path: Vec<NodeId>) -> Self {
+        DsrReplyMessage { header: None, path }
+    }
+}
+
+impl ToFromFrame for DsrReplyMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "DSR reply payload is too short"));
+        }
+        Ok(Box::new(DsrReplyMessage { header: Some(f.header()), path: data.to_vec() }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::DsrReply)
+            .sender(sender)
+            .route(&route)
+            .payload(self.path.clone())
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+/// A relay could not reach the next hop of a source route
+/* Sent back to the origin of the failed frame, every node it passes
+drops the routes over the link from its cache. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct DsrErrorMessage {
+    pub header: Option<FrameHeader>,
+    /// the node that detected the failure
+    pub from: NodeId,
+    /// the next hop it could not reach
+    pub to: NodeId,
+}
+
+impl DsrErrorMessage {
+    pub fn new(from: NodeId, to: NodeId) -> Self {
+        DsrErrorMessage { header: None, from, to }
+    }
+}
+
+impl ToFromFrame for DsrErrorMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "DSR error payload is too short"));
+        }
+        Ok(Box::new(DsrErrorMessage { header: Some(f.header()), from: data[0], to: data[1] }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::DsrError)
+            .sender(sender)
+            .route(&route)
+            .payload(vec![self.from, self.to])
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn dsr_messages_tofrom_frame() {
+    let mut request = DsrRequestMessage::new(0x1234, 9, 1);
+    request.record.push(2);
+    let mut frame = Frame::from_bytes(&request.to_frame(5, 1, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::DsrRequest);
+    let request = DsrRequestMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((request.id, request.target, request.record), (0x1234, 9, vec![1, 2]));
+
+    let mut frame = Frame::from_bytes(&DsrReplyMessage::new(vec![1, 2, 9]).to_frame(6, 9, vec![2, 1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(DsrReplyMessage::from_frame(&mut frame).unwrap().path, vec![1, 2, 9]);
+
+    let mut frame = Frame::from_bytes(&DsrErrorMessage::new(2, 9).to_frame(7, 2, vec![1]).unwrap().to_bytes()).unwrap();
+    let error = DsrErrorMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((error.from, error.to), (2, 9));
+
+    // a request without its origin is malformed
+    let mut frame = Frame::from_bytes(&DsrErrorMessage::new(2, 9).to_frame(8, 2, vec![1]).unwrap().to_bytes()).unwrap();
+    assert!(DsrRequestMessage::from_frame(&mut frame).is_err());
+}
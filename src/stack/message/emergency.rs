@@ -0,0 +1,124 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::{MessageType, POSITION_SCALE};
+
+/// Longest emergency text, in bytes of UTF-8
+pub const MAX_EMERGENCY_TEXT: usize = 100;
+
+/// flag set when the originator cancels its emergency
+const EMERGENCY_ALLCLEAR: u8 = 0x01;
+/// flag set when a position follows the sequence number
+const EMERGENCY_POSITION: u8 = 0x02;
+
+/// A call for help flooded to every node, repeated until the originator sends an all-clear
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct EmergencyBeaconMessage {
+    pub header: Option<FrameHeader>,
+    /// counts the repeats of a beacon, starting at 0
+    pub seq: u16,
+    /// cancels the originator's emergency
+    pub allclear: bool,
+    /// latitude and longitude in 1e-7 degrees
+    pub position: Option<(i32, i32)>,
+    pub text: String,
+}
+
+impl EmergencyBeaconMessage {
+    pub fn new(seq: u16, position: Option<(f64, f64)>, text: &str) -> Self {
+        // cut at a character boundary
+        let mut end = text.len().min(MAX_EMERGENCY_TEXT);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        EmergencyBeaconMessage {
+            header: None,
+            seq,
+            allclear: false,
+            position: position.map(|(lat, lon)| ((lat * POSITION_SCALE).round() as i32, (lon * POSITION_SCALE).round() as i32)),
+            text: String::from(&text[..end]),
+        }
+    }
+
+    pub fn all_clear(seq: u16) -> Self {
+        EmergencyBeaconMessage { header: None, seq, allclear: true, position: None, text: String::new() }
+    }
+
+    /// position in degrees
+    pub fn position_degrees(&self) -> Option<(f64, f64)> {
+        self.position.map(|(lat, lon)| (lat as f64 / POSITION_SCALE, lon as f64 / POSITION_SCALE))
+    }
+}
+
+impl ToFromFrame for EmergencyBeaconMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 3 {
+            return Err(Error::new(ErrorKind::InvalidData, "emergency beacon payload is too short"));
+        }
+        let flags = data[0];
+        let mut at = 3;
+        let position = if flags & EMERGENCY_POSITION != 0 {
+            if data.len() < at + 8 {
+                return Err(Error::new(ErrorKind::InvalidData, "emergency beacon position is truncated"));
+            }
+            at += 8;
+            Some((i32::from_be_bytes([data[3], data[4], data[5], data[6]]),
+                  i32::from_be_bytes([data[7], data[8], data[9], data[10]])))
+        } else {
+            None
+        };
+        Ok(Box::new(EmergencyBeaconMessage {
+            header: Some(f.header()),
+            seq: u16::from_be_bytes([data[1], data[2]]),
+            allclear: flags & EMERGENCY_ALLCLEAR != 0,
+            position,
+            text: String::from_utf8_lossy(&data[at..]).into_owned(),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut flags = 0;
+        if self.allclear { flags |= EMERGENCY_ALLCLEAR; }
+        if self.position.is_some() { flags |= EMERGENCY_POSITION; }
+
+        let mut data = Vec::with_capacity(11 + self.text.len());
+        data.push(flags);
+        data.extend_from_slice(&self.seq.to_be_bytes());
+        if let Some((lat, lon)) = self.position {
+            data.extend_from_slice(&lat.to_be_bytes());
+            data.extend_from_slice(&lon.to_be_bytes());
+        }
+        data.extend_from_slice(self.text.as_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::EmergencyBeacon)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn emergency_tofrom_frame() {
+    let msg = EmergencyBeaconMessage::new(2, Some((46.558_6, 7.835_2)), "fell into crevasse, leg broken");
+    let mut frame = Frame::from_bytes(&msg.to_frame(9, 5, vec![5]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::EmergencyBeacon);
+    let received = EmergencyBeaconMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.seq, received.allclear), (2, false));
+    assert_eq!(received.position, Some((465_586_000, 78_352_000)));
+    assert_eq!(received.text, "fell into crevasse, leg broken");
+
+    let clear = EmergencyBeaconMessage::all_clear(3);
+    let mut frame = Frame::from_bytes(&clear.to_frame(10, 5, vec![5]).unwrap().to_bytes()).unwrap();
+    let received = EmergencyBeaconMessage::from_frame(&mut frame).unwrap();
+    assert!(received.allclear && received.position.is_none() && received.text.is_empty());
+
+    assert_eq!(EmergencyBeaconMessage::new(0, None, &"ä".repeat(60)).text.len(), 100);
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// Why a node could not handle a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub enum MeshError {
+    /// the message type is not known to the receiver, which runs older firmware
+    UnknownMessageType(u8),
+    /// longer than the radio can send in one frame
+    FrameTooLong,
+    /// the frame was not signed, or not with the receiver's frame key
+    DecryptionFailed,
+    /// a relay had no way on to the destination and dropped the frame
+    RouteNotFound,
+    /// more than one node is sending with the sender's ID
+    DuplicateNodeId,
+    /// the receiver could not put the headers of a compressed IP packet back together
+    HeaderContextLost(u8),
+}
+
+impl MeshError {
+    /// the error code on the wire
+    pub fn code(&self) -> u8 {
+        match self {
+            MeshError::UnknownMessageType(_) => 1,
+            MeshError::FrameTooLong => 2,
+            MeshError::DecryptionFailed => 3,
+            MeshError::RouteNotFound => 4,
+            MeshError::DuplicateNodeId => 5,
+            MeshError::HeaderContextLost(_) => 6,
+        }
+    }
+
+    /// the error for a code and the byte after it, None if the code is unknown
+    pub fn from_code(code: u8, arg: u8) -> Option<Self> {
+        match code {
+            1 => Some(MeshError::UnknownMessageType(arg)),
+            2 => Some(MeshError::FrameTooLong),
+            3 => Some(MeshError::DecryptionFailed),
+            4 => Some(MeshError::RouteNotFound),
+            5 => Some(MeshError::DuplicateNodeId),
+            6 => Some(MeshError::HeaderContextLost(arg)),
+            _ => None,
+        }
+    }
+
+    fn arg(&self) -> u8 {
+        match self {
+            MeshError::UnknownMessageType(msgtype) => *msgtype,
+            MeshError::HeaderContextLost(context) => *context,
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MeshError::UnknownMessageType(msgtype) => write!(f, "unknown message type {}", msgtype),
+            MeshError::FrameTooLong => write!(f, "frame too long"),
+            MeshError::DecryptionFailed => write!(f, "frame not authenticated"),
+            MeshError::RouteNotFound => write!(f, "no route to the destination"),
+            MeshError::DuplicateNodeId => write!(f, "node ID used by more than one node"),
+            MeshError::HeaderContextLost(context) => write!(f, "header compression context {} lost", context),
+        }
+    }
+}
+
+/// Tells the sender of a frame why it was not handled
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorMessage {
+    pub header: Option<FrameHeader>,
+    pub error_code: MeshError,
+    /// ID of the frame the error is about
+    pub original_frame_id: u8,
+}
+
+impl ErrorMessage {
+    pub fn new(error_code: MeshError, original_frame_id: u8) -> Self {
+        ErrorMessage { header: None, error_code, original_frame_id }
+    }
+}
+
+impl ToFromFrame for ErrorMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 3 {
+            return Err(Error::new(ErrorKind::InvalidData, "error payload is too short"));
+        }
+        let error_code = MeshError::from_code(data[0], data[1])
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("unknown error code {}", data[0])))?;
+        Ok(Box::new(ErrorMessage { header: Some(f.header()), error_code, original_frame_id: data[2] }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Error)
+            .sender(sender)
+            .route(&route)
+            .payload(vec![self.error_code.code(), self.error_code.arg(), self.original_frame_id])
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn error_message_tofrom_frame() {
+    let errors = [
+        MeshError::UnknownMessageType(200),
+        MeshError::FrameTooLong,
+        MeshError::DecryptionFailed,
+        MeshError::RouteNotFound,
+        MeshError::DuplicateNodeId,
+        MeshError::HeaderContextLost(3),
+    ];
+    for error in errors.iter() {
+        let mut frame = Frame::from_bytes(&ErrorMessage::new(*error, 17).to_frame(3, 2, vec![2, 1]).unwrap().to_bytes()).unwrap();
+        assert_eq!(frame.msgtype(), MessageType::Error);
+        let msg = ErrorMessage::from_frame(&mut frame).unwrap();
+        assert_eq!((msg.error_code, msg.original_frame_id), (*error, 17));
+    }
+
+    // codes from newer firmware are refused
+    let mut frame = FrameBuilder::new()
+        .frameid(4)
+        .message_type(MessageType::Error)
+        .sender(2)
+        .route(&[1])
+        .payload(vec![99, 0, 17])
+        .build()
+        .unwrap();
+    assert!(ErrorMessage::from_frame(&mut frame).is_err());
+}
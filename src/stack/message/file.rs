@@ -0,0 +1,231 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// Longest file name offered, in bytes of UTF-8
+pub const MAX_FILENAME_LEN: usize = 64;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn build(msgtype: MessageType, frameid: u8, sender: u8, route: Vec<u8>, data: Vec<u8>) -> io::Result<Frame> {
+    FrameBuilder::new()
+        .frameid(frameid)
+        .message_type(msgtype)
+        .sender(sender)
+        .route(&route)
+        .payload(data)
+        .build()
+        .map_err(Into::into)
+}
+
+/// Announces a file to another node, which answers with the chunks it is missing
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileOfferMessage {
+    pub header: Option<FrameHeader>,
+    pub transferid: u16,
+    pub name: String,
+    /// file size in bytes
+    pub size: u32,
+    /// CRC-32 of the whole file
+    pub checksum: u32,
+    /// bytes in every chunk but the last
+    pub chunksize: u16,
+}
+
+/// One piece of an offered file
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileChunkMessage {
+    pub header: Option<FrameHeader>,
+    pub transferid: u16,
+    pub seq: u16,
+    #[cfg_attr(feature = "serde-support", serde(with = "crate::stack::base64_serde"))]
+    pub data: Vec<u8>,
+}
+
+/// Chunks the receiver of a file is still missing, the sender sends them next
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileAckMessage {
+    pub header: Option<FrameHeader>,
+    pub transferid: u16,
+    pub missing: Vec<u16>,
+}
+
+/// Ends a transfer, `ok` if the file arrived with the offered checksum
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileCompleteMessage {
+    pub header: Option<FrameHeader>,
+    pub transferid: u16,
+    pub ok: bool,
+    /// why the transfer failed
+    pub reason: String,
+}
+
+impl ToFromFrame for FileOfferMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 12 {
+            return Err(invalid("file offer payload is too short"));
+        }
+        let name = String::from_utf8(Vec::from(&data[12..])).map_err(|_| invalid("file name is not UTF-8"))?;
+        if name.len() > MAX_FILENAME_LEN {
+            return Err(invalid("file name is too long"));
+        }
+        Ok(Box::new(FileOfferMessage {
+            header: Some(f.header()),
+            transferid: read_u16(&data, 0),
+            size: read_u32(&data, 2),
+            checksum: read_u32(&data, 6),
+            chunksize: read_u16(&data, 10),
+            name,
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(12 + self.name.len());
+        data.extend_from_slice(&self.transferid.to_be_bytes());
+        data.extend_from_slice(&self.size.to_be_bytes());
+        data.extend_from_slice(&self.checksum.to_be_bytes());
+        data.extend_from_slice(&self.chunksize.to_be_bytes());
+        data.extend_from_slice(self.name.as_bytes());
+        build(MessageType::FileOffer, frameid, sender, route, data)
+    }
+}
+
+impl ToFromFrame for FileChunkMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 4 {
+            return Err(invalid("file chunk payload is too short"));
+        }
+        Ok(Box::new(FileChunkMessage {
+            header: Some(f.header()),
+            transferid: read_u16(&data, 0),
+            seq: read_u16(&data, 2),
+            data: Vec::from(&data[4..]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(4 + self.data.len());
+        data.extend_from_slice(&self.transferid.to_be_bytes());
+        data.extend_from_slice(&self.seq.to_be_bytes());
+        data.extend_from_slice(&self.data);
+        build(MessageType::FileChunk, frameid, sender, route, data)
+    }
+}
+
+impl ToFromFrame for FileAckMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 2 || data.len() % 2 != 0 {
+            return Err(invalid("file ack payload has an invalid length"));
+        }
+        Ok(Box::new(FileAckMessage {
+            header: Some(f.header()),
+            transferid: read_u16(&data, 0),
+            missing: data[2..].chunks(2).map(|seq| read_u16(seq, 0)).collect(),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(2 + self.missing.len() * 2);
+        data.extend_from_slice(&self.transferid.to_be_bytes());
+        self.missing.iter().for_each(|seq| data.extend_from_slice(&seq.to_be_bytes()));
+        build(MessageType::FileAck, frameid, sender, route, data)
+    }
+}
+
+impl ToFromFrame for FileCompleteMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 3 {
+            return Err(invalid("file complete payload is too short"));
+        }
+        Ok(Box::new(FileCompleteMessage {
+            header: Some(f.header()),
+            transferid: read_u16(&data, 0),
+            ok: data[2] != 0,
+            reason: String::from_utf8_lossy(&data[3..]).into_owned(),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(3 + self.reason.len());
+        data.extend_from_slice(&self.transferid.to_be_bytes());
+        data.push(self.ok as u8);
+        data.extend_from_slice(self.reason.as_bytes());
+        build(MessageType::FileComplete, frameid, sender, route, data)
+    }
+}
+
+/// Any of the file transfer messages
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub enum FileMessage {
+    Offer(FileOfferMessage),
+    Chunk(FileChunkMessage),
+    Ack(FileAckMessage),
+    Complete(FileCompleteMessage),
+}
+
+impl FileMessage {
+    /// Parse a frame of one of the file transfer message types
+    pub fn from_frame(f: &mut Frame) -> io::Result<Self> {
+        match f.msgtype() {
+            MessageType::FileOffer => Ok(FileMessage::Offer(*FileOfferMessage::from_frame(f)?)),
+            MessageType::FileChunk => Ok(FileMessage::Chunk(*FileChunkMessage::from_frame(f)?)),
+            MessageType::FileAck => Ok(FileMessage::Ack(*FileAckMessage::from_frame(f)?)),
+            MessageType::FileComplete => Ok(FileMessage::Complete(*FileCompleteMessage::from_frame(f)?)),
+            other => Err(invalid(&format!("{:?} is not a file transfer message", other))),
+        }
+    }
+
+    pub fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        match self {
+            FileMessage::Offer(msg) => msg.to_frame(frameid, sender, route),
+            FileMessage::Chunk(msg) => msg.to_frame(frameid, sender, route),
+            FileMessage::Ack(msg) => msg.to_frame(frameid, sender, route),
+            FileMessage::Complete(msg) => msg.to_frame(frameid, sender, route),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn file_messages_tofrom_frame() {
+    let offer = FileOfferMessage { header: None, transferid: 7, name: String::from("sensor.log"), size: 10_000, checksum: 0xDEAD_BEEF, chunksize: 180 };
+    let mut frame = Frame::from_bytes(&offer.to_frame(1, 2, vec![3]).unwrap().to_bytes()).unwrap();
+    match FileMessage::from_frame(&mut frame).unwrap() {
+        FileMessage::Offer(msg) => {
+            assert_eq!((msg.transferid, msg.size, msg.checksum, msg.chunksize), (7, 10_000, 0xDEAD_BEEF, 180));
+            assert_eq!(msg.name, "sensor.log");
+        },
+        _ => panic!("expected an offer"),
+    }
+
+    let ack = FileAckMessage { header: None, transferid: 7, missing: vec![0, 3, 300] };
+    let mut frame = Frame::from_bytes(&ack.to_frame(1, 3, vec![2]).unwrap().to_bytes()).unwrap();
+    assert_eq!(FileAckMessage::from_frame(&mut frame).unwrap().missing, vec![0, 3, 300]);
+
+    let complete = FileCompleteMessage { header: None, transferid: 7, ok: false, reason: String::from("checksum mismatch") };
+    let mut frame = Frame::from_bytes(&complete.to_frame(1, 3, vec![2]).unwrap().to_bytes()).unwrap();
+    let received = FileCompleteMessage::from_frame(&mut frame).unwrap();
+    assert!(!received.ok);
+    assert_eq!(received.reason, "checksum mismatch");
+}
@@ -0,0 +1,75 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+const GATEWAY_PAYLOAD_LEN: usize = 4;
+
+/// flag set while the gateway's own uplink works
+const GATEWAY_UPLINK: u8 = 0x01;
+
+/// A gateway telling the mesh it is there, flooded periodically
+/* Every relay adds the cost of the hop it heard the frame on to `cost`,
+so a receiver only has to add the cost of the last hop to know its own
+cost of reaching the gateway. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct GatewayAnnounceMessage {
+    pub header: Option<FrameHeader>,
+    /// the gateway reaches the internet or whatever backhaul it serves
+    pub uplink: bool,
+    /// how busy the gateway's transmit queue is, in percent
+    pub load: u8,
+    /// ETX of the path before the last hop, in hundredths
+    pub cost: u16,
+}
+
+impl GatewayAnnounceMessage {
+    pub fn new(uplink: bool, load: u8) -> Self {
+        GatewayAnnounceMessage { header: None, uplink, load: load.min(100), cost: 0 }
+    }
+}
+
+impl ToFromFrame for GatewayAnnounceMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < GATEWAY_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "gateway announcement payload is too short"));
+        }
+        Ok(Box::new(GatewayAnnounceMessage {
+            header: Some(f.header()),
+            uplink: data[0] & GATEWAY_UPLINK != 0,
+            load: data[1].min(100),
+            cost: u16::from_be_bytes([data[2], data[3]]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(GATEWAY_PAYLOAD_LEN);
+        data.push(if self.uplink { GATEWAY_UPLINK } else { 0 });
+        data.push(self.load);
+        data.extend_from_slice(&self.cost.to_be_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::GatewayAnnounce)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn gateway_announce_tofrom_frame() {
+    let mut msg = GatewayAnnounceMessage::new(true, 250);
+    assert_eq!(msg.load, 100);
+    msg.cost = 345;
+    let mut frame = Frame::from_bytes(&msg.to_frame(9, 1, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::GatewayAnnounce);
+    let received = GatewayAnnounceMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.uplink, received.load, received.cost), (true, 100, 345));
+}
@@ -0,0 +1,136 @@
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// status flag set when the sender is a gateway
+pub const HEARTBEAT_GATEWAY: u8 = 0x01;
+
+/// Heartbeats in a row a node may miss before it is considered dead
+pub const HEARTBEAT_MISSES: u32 = 3;
+
+/// Minimal proof of life sent to direct neighbors between broadcasts.
+/* Carries no routing, unlike a broadcast, so it is cheap to send every
+few seconds. The health readings go last, after the token, so older
+receivers still find the token where they expect it. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeartbeatMessage {
+    pub header: Option<FrameHeader>,
+    pub status: u8,
+    /// random number the sender picked when it started, two nodes sharing an ID send different ones
+    pub token: Option<u32>,
+    /// counts up with every heartbeat, gaps are heartbeats that were lost
+    pub seq: u16,
+    /// radio supply voltage in mV, 0 if the sender has not read it
+    pub battery_mv: u16,
+    /// seconds since the sender started
+    pub uptime_s: u32,
+    /// spreading factor the sender's adaptive data rate is on or moving to, 0 if it has none
+    pub sf: u8,
+}
+
+impl HeartbeatMessage {
+    pub fn new(status: u8) -> Self {
+        HeartbeatMessage { header: None, status, token: None, seq: 0, battery_mv: 0, uptime_s: 0, sf: 0 }
+    }
+}
+
+impl ToFromFrame for HeartbeatMessage {
+    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+        let header = f.header();
+        // older senders may leave the status, token, health readings and spreading factor out
+        let payload = f.payload();
+        let status = payload.get(0).cloned().unwrap_or(0);
+        let (token, health, sf) = match payload.len() {
+            5 => (Some(&payload[1..5]), None, None),
+            9 | 10 => (None, Some(&payload[1..9]), payload.get(9)),
+            n if n >= 13 => (Some(&payload[1..5]), Some(&payload[5..13]), payload.get(13)),
+            _ => (None, None, None),
+        };
+        let token = token.map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+        let (seq, battery_mv, uptime_s) = health.map_or((0, 0, 0), |b| (
+            u16::from_be_bytes([b[0], b[1]]),
+            u16::from_be_bytes([b[2], b[3]]),
+            u32::from_be_bytes([b[4], b[5], b[6], b[7]]),
+        ));
+
+        Ok(Box::new(HeartbeatMessage {
+            header: Some(header),
+            status,
+            token,
+            seq,
+            battery_mv,
+            uptime_s,
+            sf: sf.cloned().unwrap_or(0),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> std::io::Result<Frame> {
+        let mut payload = vec![self.status];
+        if let Some(token) = self.token {
+            payload.extend_from_slice(&token.to_be_bytes());
+        }
+        payload.extend_from_slice(&self.seq.to_be_bytes());
+        payload.extend_from_slice(&self.battery_mv.to_be_bytes());
+        payload.extend_from_slice(&self.uptime_s.to_be_bytes());
+        if self.sf != 0 {
+            payload.push(self.sf);
+        }
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Heartbeat)
+            .sender(sender)
+            .route(&route)
+            .payload(payload)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn heartbeat_tofrom_frame() {
+    let mut frame = HeartbeatMessage::new(HEARTBEAT_GATEWAY).to_frame(3u8, 7, vec![7]).unwrap();
+    let bytes = frame.to_bytes();
+    assert_eq!(bytes.len(), 15);
+
+    let mut frame2 = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame2.msgtype(), MessageType::Heartbeat);
+    let msg = HeartbeatMessage::from_frame(&mut frame2).unwrap();
+    assert_eq!(msg.status, HEARTBEAT_GATEWAY);
+    assert_eq!(msg.token, None);
+    assert_eq!(msg.header.unwrap().sender(), 7);
+
+    let mut msg = HeartbeatMessage::new(0);
+    msg.token = Some(0xDEADBEEF);
+    msg.seq = 513;
+    msg.battery_mv = 3312;
+    msg.uptime_s = 86_400;
+    let mut frame = msg.to_frame(4u8, 7, vec![7]).unwrap();
+    let msg = HeartbeatMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((msg.token, msg.seq, msg.battery_mv, msg.uptime_s, msg.sf), (Some(0xDEADBEEF), 513, 3312, 86_400, 0));
+
+    // the spreading factor goes last, with or without a token
+    let mut msg = HeartbeatMessage::new(0);
+    msg.sf = 9;
+    msg.uptime_s = 60;
+    let mut frame = msg.to_frame(5u8, 7, vec![7]).unwrap();
+    let msg2 = HeartbeatMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((msg2.token, msg2.uptime_s, msg2.sf), (None, 60, 9));
+    msg.token = Some(1);
+    let mut frame = msg.to_frame(6u8, 7, vec![7]).unwrap();
+    let msg2 = HeartbeatMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((msg2.token, msg2.uptime_s, msg2.sf), (Some(1), 60, 9));
+
+    // a heartbeat from before the health readings
+    let mut old = FrameBuilder::new()
+        .frameid(5)
+        .message_type(MessageType::Heartbeat)
+        .sender(7)
+        .route(&[7])
+        .payload(vec![0, 0xDE, 0xAD, 0xBE, 0xEF])
+        .build()
+        .unwrap();
+    let msg = HeartbeatMessage::from_frame(&mut old).unwrap();
+    assert_eq!((msg.token, msg.seq, msg.battery_mv, msg.uptime_s), (Some(0xDEADBEEF), 0, 0, 0));
+}
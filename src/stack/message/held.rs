@@ -0,0 +1,70 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// Tells a node that came back in range that messages for it are held
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeldNoticeMessage {
+    pub header: Option<FrameHeader>,
+    /// number of messages waiting
+    pub count: u16,
+}
+
+/// Asks the node that sent a `HeldNoticeMessage` for the held messages
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeldRequestMessage {
+    pub header: Option<FrameHeader>,
+}
+
+impl HeldNoticeMessage {
+    pub fn new(count: u16) -> Self {
+        HeldNoticeMessage { header: None, count }
+    }
+}
+
+impl HeldRequestMessage {
+    pub fn new() -> Self {
+        HeldRequestMessage { header: None }
+    }
+}
+
+impl ToFromFrame for HeldNoticeMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "held notice payload is too short"));
+        }
+        Ok(Box::new(HeldNoticeMessage { header: Some(f.header()), count: u16::from_be_bytes([data[0], data[1]]) }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::HeldNotice)
+            .sender(sender)
+            .route(&route)
+            .payload(self.count.to_be_bytes().to_vec())
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+impl ToFromFrame for HeldRequestMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        Ok(Box::new(HeldRequestMessage { header: Some(f.header()) }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::HeldRequest)
+            .sender(sender)
+            .route(&route)
+            .build()
+            .map_err(Into::into)
+    }
+}
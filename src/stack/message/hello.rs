@@ -0,0 +1,78 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, NodeId, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// Most neighbors reported in one hello, the strongest are kept
+pub const MAX_HELLO_NEIGHBORS: usize = 16;
+
+/// bytes of every reported neighbor: node ID, delivery ratio and RSSI
+const HELLO_ENTRY_LEN: usize = 4;
+
+/// stands in for a neighbor we have no RSSI reading of
+const NO_RSSI: i16 = i16::MIN;
+
+/// How well the sender of a hello hears one of its neighbors
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct HelloNeighbor {
+    pub nodeid: NodeId,
+    /// share of the neighbor's periodic frames received, scaled to 0-255
+    pub quality: u8,
+    /// average RSSI of its frames
+    pub rssi: Option<i16>,
+}
+
+/// Sent to direct neighbors, telling each how well we hear it
+/* Our own measurements only cover the links towards us, the hellos of
+our neighbors fill in the links away from us. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct NeighborHelloMessage {
+    pub header: Option<FrameHeader>,
+    pub neighbors: Vec<HelloNeighbor>,
+}
+
+impl NeighborHelloMessage {
+    pub fn new(mut neighbors: Vec<HelloNeighbor>) -> Self {
+        neighbors.truncate(MAX_HELLO_NEIGHBORS);
+        NeighborHelloMessage { header: None, neighbors }
+    }
+}
+
+impl ToFromFrame for NeighborHelloMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() % HELLO_ENTRY_LEN != 0 || data.len() > MAX_HELLO_NEIGHBORS * HELLO_ENTRY_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "neighbor hello payload has an invalid length"));
+        }
+        let neighbors = data.chunks(HELLO_ENTRY_LEN).map(|entry| {
+            let rssi = i16::from_be_bytes([entry[2], entry[3]]);
+            HelloNeighbor {
+                nodeid: entry[0],
+                quality: entry[1],
+                rssi: if rssi == NO_RSSI { None } else { Some(rssi) },
+            }
+        }).collect();
+        Ok(Box::new(NeighborHelloMessage { header: Some(f.header()), neighbors }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(self.neighbors.len() * HELLO_ENTRY_LEN);
+        for neighbor in self.neighbors.iter().take(MAX_HELLO_NEIGHBORS) {
+            data.push(neighbor.nodeid);
+            data.push(neighbor.quality);
+            data.extend_from_slice(&neighbor.rssi.unwrap_or(NO_RSSI).to_be_bytes());
+        }
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::NeighborHello)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
@@ -1,19 +1,72 @@
 
 
+use std::io::{Error, ErrorKind};
 use std::net::Ipv4Addr;
+use std::time::Duration;
 use crate::stack::{Frame, MessageType};
-use crate::stack::frame::{FrameHeader, ToFromFrame};
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
 use crate::stack::util::{parse_ipv4};
 
+/// Ask the gateway for an IP address, or to renew the one we have.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct IPRequestMessage {
+    pub header: Option<FrameHeader>,
+    /// address we held before, the gateway hands it out again if it can
+    pub ipaddr: Option<Ipv4Addr>
+}
+
+impl IPRequestMessage {
+    pub fn new(ipaddr: Option<Ipv4Addr>) -> Self {
+        return IPRequestMessage{ header: None, ipaddr}
+    }
+}
+
+impl ToFromFrame for IPRequestMessage {
+    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+        let header = f.header();
+        let data = f.payload();
+        let ipaddr = match data.len() {
+            0 => None,
+            4 => Some(parse_ipv4(&data[0..4])),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "IP request payload has an invalid length")),
+        };
+
+        Ok(Box::new(IPRequestMessage {
+            header: Some(header),
+            ipaddr
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> std::io::Result<Frame> {
+        let data = self.ipaddr.map(|ip| ip.octets().to_vec()).unwrap_or(Vec::new());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::IPRequest)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
 /// Notify node of their new IP address.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
 pub struct IPAssignSuccessMessage {
     pub header: Option<FrameHeader>,
-    pub ipaddr: Ipv4Addr
+    pub ipaddr: Ipv4Addr,
+    /// how long the address is ours, None if it never expires
+    pub leasetime: Option<Duration>,
+    /// DNS server to use, the gateway when it proxies queries
+    pub resolver: Option<Ipv4Addr>
 }
 
 impl IPAssignSuccessMessage {
-    pub fn new(ipaddr: Ipv4Addr) -> Self {
-        return IPAssignSuccessMessage{ header: None, ipaddr}
+    pub fn new(ipaddr: Ipv4Addr, leasetime: Option<Duration>) -> Self {
+        return IPAssignSuccessMessage{ header: None, ipaddr, leasetime, resolver: None}
     }
 }
 
@@ -21,38 +74,54 @@ impl ToFromFrame for IPAssignSuccessMessage {
     fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
         let header = f.header();
         let data = f.payload();
-        let octets = &data[0..data.len()];
-        let ipaddr = parse_ipv4(octets);
+        if data.len() != 4 && data.len() != 8 && data.len() != 12 {
+            return Err(Error::new(ErrorKind::InvalidData, "IP assignment payload has an invalid length"));
+        }
+        let ipaddr = parse_ipv4(&data[0..4]);
+        // the lease time in seconds follows the address, if it expires
+        let leasetime = data.get(4..8)
+            .map(|secs| u32::from_be_bytes([secs[0], secs[1], secs[2], secs[3]]))
+            .filter(|secs| *secs != 0 || data.len() == 8)
+            .map(|secs| Duration::from_secs(secs as u64));
+        // then the resolver, with a lease time of zero if it never expires
+        let resolver = data.get(8..12).map(parse_ipv4);
 
         Ok(Box::new(IPAssignSuccessMessage {
             header: Some(header),
-            ipaddr
+            ipaddr,
+            leasetime,
+            resolver
         }))
     }
 
-    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> Frame {
-        // cast the route
-        let route: Vec<u8> = route.clone().iter().map(|i| i.clone() as u8).collect();
-        let routeoffset = route.len() as u8;
-
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> std::io::Result<Frame> {
         // write the payload
         let mut data: Vec<u8> = Vec::new();
         let octets = self.ipaddr.octets();
         octets.iter().for_each(|oct| data.push(oct.clone()));
-
-        Frame::new(
-            0i8 as u8,
-            frameid,
-            MessageType::IPAssignSuccess as u8,
-            sender as u8,
-            routeoffset as u8,
-            route,
-            data
-        )
+        if let Some(leasetime) = self.leasetime {
+            let secs = leasetime.as_secs().min(u32::MAX as u64) as u32;
+            data.extend_from_slice(&secs.to_be_bytes());
+        }
+        if let Some(resolver) = self.resolver {
+            data.resize(8, 0);
+            data.extend_from_slice(&resolver.octets());
+        }
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::IPAssignSuccess)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
     }
 }
 
 /// Assigning IP to node failed, tell them.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
 pub struct IPAssignFailureMessage {
     pub header: Option<FrameHeader>,
     pub reason: String
@@ -75,21 +144,40 @@ impl ToFromFrame for IPAssignFailureMessage {
         }))
     }
 
-    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> Frame {
-        // cast the route
-        let route: Vec<u8> = route.clone().iter().map(|i| i.clone() as u8).collect();
-        let routeoffset = route.len() as u8;
-
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> std::io::Result<Frame> {
         let payload = &self.reason;
 
-        Frame::new(
-            0u8,
-            frameid,
-            MessageType::IPAssignFailure as u8,
-            sender as u8,
-            routeoffset,
-            route,
-            payload.clone().into_bytes()
-        )
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::IPAssignFailure)
+            .sender(sender)
+            .route(&route)
+            .payload(payload.clone().into_bytes())
+            .build()
+            .map_err(Into::into)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+#[test]
+fn ipassign_tofrom_frame() {
+    let ipaddr = Ipv4Addr::new(172, 16, 0, 9);
+    let mut frame = Frame::from_bytes(&IPRequestMessage::new(Some(ipaddr)).to_frame(1, 9, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::IPRequest);
+    assert_eq!(IPRequestMessage::from_frame(&mut frame).unwrap().ipaddr, Some(ipaddr));
+    let mut frame = Frame::from_bytes(&IPRequestMessage::new(None).to_frame(1, 9, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(IPRequestMessage::from_frame(&mut frame).unwrap().ipaddr, None);
+
+    let assign = IPAssignSuccessMessage::new(ipaddr, Some(Duration::from_secs(86400)));
+    let mut frame = Frame::from_bytes(&assign.to_frame(2, 1, vec![9]).unwrap().to_bytes()).unwrap();
+    let received = IPAssignSuccessMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.ipaddr, received.leasetime), (ipaddr, Some(Duration::from_secs(86400))));
+    let mut frame = Frame::from_bytes(&IPAssignSuccessMessage::new(ipaddr, None).to_frame(2, 1, vec![9]).unwrap().to_bytes()).unwrap();
+    assert_eq!(IPAssignSuccessMessage::from_frame(&mut frame).unwrap().leasetime, None);
+
+    let mut assign = IPAssignSuccessMessage::new(ipaddr, None);
+    assign.resolver = Some(Ipv4Addr::new(172, 16, 0, 1));
+    let mut frame = Frame::from_bytes(&assign.to_frame(2, 1, vec![9]).unwrap().to_bytes()).unwrap();
+    let received = IPAssignSuccessMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.leasetime, received.resolver), (None, assign.resolver));
+}
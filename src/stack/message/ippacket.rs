@@ -1,22 +1,24 @@
-use packet::ip::v4::Packet;
 use crate::stack::Frame;
-use crate::stack::frame::{FrameHeader, ToFromFrame};
-use crate::message::MessageType;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::ipv6::IpPacket;
+use crate::stack::message::MessageType;
 use std::io::ErrorKind;
 
-/// Container for IP-level packets
+/// Container for IP-level packets, of either version
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
 pub struct IPPacketMessage {
     header: Option<FrameHeader>,
-    packet: Packet<Vec<u8>>
+    #[cfg_attr(feature = "serde-support", serde(with = "crate::stack::base64_serde::ip_packet"))]
+    packet: IpPacket
 }
 
 impl IPPacketMessage {
-    pub fn new(packet: Packet<Vec<u8>>) -> Self {
-        IPPacketMessage{header: None, packet}
+    pub fn new<P: Into<IpPacket>>(packet: P) -> Self {
+        IPPacketMessage{header: None, packet: packet.into()}
     }
 
-    pub fn packet(&self) -> Packet<Vec<u8>> {
+    pub fn packet(&self) -> IpPacket {
         return self.packet.clone();
     }
 }
@@ -25,7 +27,7 @@ impl ToFromFrame for IPPacketMessage {
     fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
         let header = f.header();
         let data = f.payload();
-        let packet = Packet::new(data).ok().ok_or(ErrorKind::InvalidData)?;
+        let packet = IpPacket::new(data).ok().ok_or(ErrorKind::InvalidData)?;
 
         Ok(Box::new(IPPacketMessage {
             header: Some(header),
@@ -33,23 +35,18 @@ impl ToFromFrame for IPPacketMessage {
         }))
     }
 
-    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> Frame {
-        // cast the route
-        let route: Vec<u8> = route.clone().iter().map(|i| i.clone() as u8).collect();
-        let routeoffset = route.len() as u8;
-
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> std::io::Result<Frame> {
         // write the payload
         let payload: Vec<u8> = Vec::from(self.packet.as_ref());
 
-        Frame::new(
-            0i8 as u8,
-            frameid,
-            MessageType::IPPacket as u8,
-            sender as u8,
-            routeoffset as u8,
-            route,
-            payload
-        )
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::IPPacket)
+            .sender(sender)
+            .route(&route)
+            .payload(payload)
+            .build()
+            .map_err(Into::into)
     }
 }
 
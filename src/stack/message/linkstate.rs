@@ -0,0 +1,88 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, NodeId, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// most neighbors listed in one advertisement, the strongest are kept
+pub const MAX_LSA_LINKS: usize = 64;
+
+/// A neighbor as listed in a link-state advertisement
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct LsaLink {
+    pub nodeid: NodeId,
+    /// share of the neighbor's frames we receive, scaled to 0..=255
+    pub quality: u8,
+}
+
+/// Every neighbor a node hears, flooded through the mesh in link-state mode
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct LinkStateMessage {
+    pub header: Option<FrameHeader>,
+    /// newer advertisements from the same node replace older ones
+    pub seq: u16,
+    pub links: Vec<LsaLink>,
+}
+
+impl LinkStateMessage {
+    /// advertise up to `MAX_LSA_LINKS` links, preferring the best ones
+    pub fn new(seq: u16, mut links: Vec<LsaLink>) -> Self {
+        links.sort_by(|a, b| b.quality.cmp(&a.quality).then(a.nodeid.cmp(&b.nodeid)));
+        links.truncate(MAX_LSA_LINKS);
+        LinkStateMessage { header: None, seq, links }
+    }
+}
+
+impl ToFromFrame for LinkStateMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 2 || data.len() % 2 != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "link-state payload has an invalid length"));
+        }
+        let links = data[2..].chunks(2)
+            .map(|link| LsaLink { nodeid: link[0], quality: link[1] })
+            .collect();
+
+        Ok(Box::new(LinkStateMessage {
+            header: Some(f.header()),
+            seq: u16::from_be_bytes([data[0], data[1]]),
+            links,
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(2 + self.links.len() * 2);
+        data.extend_from_slice(&self.seq.to_be_bytes());
+        for link in self.links.iter() {
+            data.push(link.nodeid);
+            data.push(link.quality);
+        }
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::LinkState)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn linkstate_tofrom_frame() {
+    let links: Vec<LsaLink> = (1..=100u8).map(|id| LsaLink { nodeid: id, quality: id }).collect();
+    let msg = LinkStateMessage::new(513, links);
+    assert_eq!(msg.links.len(), MAX_LSA_LINKS);
+    assert_eq!(msg.links[0], LsaLink { nodeid: 100, quality: 100 });
+
+    let mut frame = Frame::from_bytes(&msg.to_frame(1, 7, vec![7]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::LinkState);
+    let received = LinkStateMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(received.seq, 513);
+    assert_eq!(received.links, msg.links);
+    assert!(frame.payload().len() < 200);
+}
@@ -7,6 +7,7 @@ use crate::stack::frame::{FrameHeader};
 
 /// Defines the type of message in the protocol.
 #[derive(Clone, PartialEq, Debug, N)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
 pub enum MessageType {
     Broadcast = 1,
     IPAssignSuccess = 2,
@@ -17,6 +18,44 @@ pub enum MessageType {
     TransmitRequest = 7,
     TransmitConfirm = 8,
     IPPacket = 9,
+    Heartbeat = 10,
+    Ping = 11,
+    Pong = 12,
+    Traceroute = 13,
+    TracerouteReply = 14,
+    Text = 15,
+    TextReceipt = 16,
+    Position = 17,
+    Telemetry = 18,
+    RouteError = 19,
+    LinkState = 20,
+    Data = 21,
+    IPRequest = 22,
+    TimeSync = 23,
+    FileOffer = 24,
+    FileChunk = 25,
+    FileAck = 26,
+    FileComplete = 27,
+    NodeInfo = 28,
+    GatewayAnnounce = 29,
+    Ack = 30,
+    NeighborHello = 31,
+    RemoteCommand = 32,
+    RemoteCommandResult = 33,
+    HeldNotice = 34,
+    HeldRequest = 35,
+    EmergencyBeacon = 36,
+    Config = 37,
+    ConfigAck = 38,
+    DsrRequest = 39,
+    DsrReply = 40,
+    DsrError = 41,
+    Error = 42,
+    CompressedIPPacket = 43,
+    AddressQuery = 44,
+    AddressAnswer = 45,
+    NetworkAnnounce = 46,
+    Datagram = 47,
 }
 
 impl MessageType {
@@ -31,24 +70,51 @@ impl MessageType {
             MessageType::TransmitRequest => 7 as u8,
             MessageType::TransmitConfirm => 8 as u8,
             MessageType::IPPacket => 9 as u8,
+            MessageType::Heartbeat => 10 as u8,
+            MessageType::Ping => 11 as u8,
+            MessageType::Pong => 12 as u8,
+            MessageType::Traceroute => 13 as u8,
+            MessageType::TracerouteReply => 14 as u8,
+            MessageType::Text => 15 as u8,
+            MessageType::TextReceipt => 16 as u8,
+            MessageType::Position => 17 as u8,
+            MessageType::Telemetry => 18 as u8,
+            MessageType::RouteError => 19 as u8,
+            MessageType::LinkState => 20 as u8,
+            MessageType::Data => 21 as u8,
+            MessageType::IPRequest => 22 as u8,
+            MessageType::TimeSync => 23 as u8,
+            MessageType::FileOffer => 24 as u8,
+            MessageType::FileChunk => 25 as u8,
+            MessageType::FileAck => 26 as u8,
+            MessageType::FileComplete => 27 as u8,
+            MessageType::NodeInfo => 28 as u8,
+            MessageType::GatewayAnnounce => 29 as u8,
+            MessageType::Ack => 30 as u8,
+            MessageType::NeighborHello => 31 as u8,
+            MessageType::RemoteCommand => 32 as u8,
+            MessageType::RemoteCommandResult => 33 as u8,
+            MessageType::HeldNotice => 34 as u8,
+            MessageType::HeldRequest => 35 as u8,
+            MessageType::EmergencyBeacon => 36 as u8,
+            MessageType::Config => 37 as u8,
+            MessageType::ConfigAck => 38 as u8,
+            MessageType::DsrRequest => 39 as u8,
+            MessageType::DsrReply => 40 as u8,
+            MessageType::DsrError => 41 as u8,
+            MessageType::Error => 42 as u8,
+            MessageType::CompressedIPPacket => 43 as u8,
+            MessageType::AddressQuery => 44 as u8,
+            MessageType::AddressAnswer => 45 as u8,
+            MessageType::NetworkAnnounce => 46 as u8,
+            MessageType::Datagram => 47 as u8,
         }
     }
 }
 
 /// A node is no longer reachable from the sender.
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
 pub struct RouteFailureMessage {
     pub header: Option<FrameHeader>,
     pub failednodeid: u8
-}
-
-/// Request destination node if okay to transmit.
-pub struct TransmitRequestMessage {
-    pub header: Option<FrameHeader>,
-    pub dest: u8 // the intended receiver
-}
-
-/// Confirm to original requester that it is okay to transmit.
-pub struct TransmitConfirmMessage {
-    pub header: Option<FrameHeader>,
-    pub requester: u8 // the original requester
 }
\ No newline at end of file
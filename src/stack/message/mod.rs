@@ -1,11 +1,89 @@
 pub(crate) mod message;
-pub(crate) use message::*;
+pub use message::*;
+
+pub(crate) mod ack;
+pub use ack::*;
+
+pub(crate) mod address;
+pub use address::*;
 
 pub(crate) mod broadcast;
-pub(crate) use broadcast::*;
+pub use broadcast::*;
+
+pub(crate) mod compressed;
+pub use compressed::*;
+
+pub(crate) mod config;
+pub use config::*;
+
+pub(crate) mod dsr;
+pub use dsr::*;
 
 pub(crate) mod ippacket;
-pub(crate) use ippacket::*;
+pub use ippacket::*;
 
 pub(crate) mod ipassign;
-pub(crate) use ipassign::*;
+pub use ipassign::*;
+
+pub(crate) mod error;
+pub use error::*;
+
+pub(crate) mod emergency;
+pub use emergency::*;
+
+pub(crate) mod gateway;
+pub use gateway::*;
+
+pub(crate) mod heartbeat;
+pub use heartbeat::*;
+
+pub(crate) mod held;
+pub use held::*;
+
+pub(crate) mod hello;
+pub use hello::*;
+
+pub(crate) mod network;
+pub use network::*;
+
+pub(crate) mod nodeinfo;
+pub use nodeinfo::*;
+
+pub(crate) mod ping;
+pub use ping::*;
+
+pub(crate) mod traceroute;
+pub use traceroute::*;
+
+pub(crate) mod text;
+pub use text::*;
+
+pub(crate) mod data;
+pub use data::*;
+
+pub(crate) mod datagram;
+pub use datagram::*;
+
+pub(crate) mod file;
+pub use file::*;
+
+pub(crate) mod linkstate;
+pub use linkstate::*;
+
+pub(crate) mod position;
+pub use position::*;
+
+pub(crate) mod remote;
+pub use remote::*;
+
+pub(crate) mod reservation;
+pub use reservation::*;
+
+pub(crate) mod route;
+pub use route::*;
+
+pub(crate) mod telemetry;
+pub use telemetry::*;
+
+pub(crate) mod timesync;
+pub use timesync::*;
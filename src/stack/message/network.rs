@@ -0,0 +1,73 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+const NETWORK_PAYLOAD_LEN: usize = 4;
+
+/// flag set while the gateway reaches the internet
+const NETWORK_REACHABLE: u8 = 0x01;
+
+/// A gateway telling the mesh whether it reaches the internet, flooded when that changes and periodically
+/* Like a gateway announcement every relay adds the cost of the hop it
+heard the frame on to `metric`, ETX in hundredths. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct NetworkAnnounceMessage {
+    pub header: Option<FrameHeader>,
+    pub internet_reachable: bool,
+    pub gateway_id: u8,
+    pub metric: u16,
+}
+
+impl NetworkAnnounceMessage {
+    pub fn new(internet_reachable: bool, gateway_id: u8) -> Self {
+        NetworkAnnounceMessage { header: None, internet_reachable, gateway_id, metric: 0 }
+    }
+}
+
+impl ToFromFrame for NetworkAnnounceMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < NETWORK_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "network announcement payload is too short"));
+        }
+        Ok(Box::new(NetworkAnnounceMessage {
+            header: Some(f.header()),
+            internet_reachable: data[0] & NETWORK_REACHABLE != 0,
+            gateway_id: data[1],
+            metric: u16::from_be_bytes([data[2], data[3]]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(NETWORK_PAYLOAD_LEN);
+        data.push(if self.internet_reachable { NETWORK_REACHABLE } else { 0 });
+        data.push(self.gateway_id);
+        data.extend_from_slice(&self.metric.to_be_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::NetworkAnnounce)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn network_announce_tofrom_frame() {
+    let mut msg = NetworkAnnounceMessage::new(true, 1);
+    msg.metric = 310;
+    let mut frame = Frame::from_bytes(&msg.to_frame(9, 1, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::NetworkAnnounce);
+    let received = NetworkAnnounceMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.internet_reachable, received.gateway_id, received.metric), (true, 1, 310));
+
+    let mut frame = Frame::from_bytes(&NetworkAnnounceMessage::new(false, 4).to_frame(10, 4, vec![4]).unwrap().to_bytes()).unwrap();
+    assert!(!NetworkAnnounceMessage::from_frame(&mut frame).unwrap().internet_reachable);
+}
@@ -0,0 +1,203 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use enumn::N;
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+use crate::stack::router::RoutingMode;
+use crate::stack::util::{parse_tlv, push_tlv};
+
+/// flag asking the destination to answer with its own info
+const NODEINFO_REQUEST: u8 = 0x01;
+/// flag set on the announcement flooded after boot
+const NODEINFO_ANNOUNCE: u8 = 0x02;
+/// flag set by nodes that take compressed IP headers
+const NODEINFO_HEADERCOMP: u8 = 0x04;
+/// capability flags
+const NODEINFO_GATEWAY: u8 = 0x10;
+const NODEINFO_ENCRYPTION: u8 = 0x20;
+const NODEINFO_LINKSTATE: u8 = 0x40;
+const NODEINFO_DSR: u8 = 0x80;
+
+/// TLV types of the strings
+const NODEINFO_NAME: u8 = 1;
+const NODEINFO_FIRMWARE: u8 = 2;
+const NODEINFO_VERSION: u8 = 3;
+
+/// Radio hardware a node runs on
+/* The discriminant is the code on the wire. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, N)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub enum HardwareModel {
+    Unknown = 0,
+    LoStik = 1,
+    RN2483 = 2,
+    SX127x = 3,
+}
+
+impl HardwareModel {
+    /// Tell the model from the response of `sys get ver`
+    /* The LoStik is built on the RN2903, a bare RN2483 is the EU module. */
+    pub fn from_version(version: &str) -> Self {
+        if version.starts_with("RN2903") {
+            HardwareModel::LoStik
+        } else if version.starts_with("RN2483") {
+            HardwareModel::RN2483
+        } else {
+            HardwareModel::Unknown
+        }
+    }
+}
+
+/// What a node is and how it is set up
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct NodeInfo {
+    /// display name from the settings
+    pub name: Option<String>,
+    pub hardware: HardwareModel,
+    /// radio firmware, as reported by `sys get ver`
+    pub firmware: String,
+    /// version of loramesh the node runs
+    pub version: String,
+    pub gateway: bool,
+    pub encryption: bool,
+    pub routing: RoutingMode,
+    /// takes IP packets with compressed headers
+    pub headercompression: bool,
+}
+
+/// A node's info, flooded after boot or sent to a node that asked for it
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct NodeInfoMessage {
+    pub header: Option<FrameHeader>,
+    /// ask the destination for its info in return
+    pub request: bool,
+    /// flooded through the mesh rather than routed
+    pub announce: bool,
+    pub info: NodeInfo,
+}
+
+impl NodeInfoMessage {
+    pub fn new(info: NodeInfo) -> Self {
+        NodeInfoMessage { header: None, request: false, announce: false, info }
+    }
+}
+
+/// longest string that fits a TLV entry, cut at a character boundary
+fn truncated(s: &str) -> &str {
+    let mut end = s.len().min(u8::MAX as usize);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+impl ToFromFrame for NodeInfoMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "node info payload is too short"));
+        }
+        let flags = data[0];
+        let mut info = NodeInfo {
+            name: None,
+            hardware: HardwareModel::n(data[1]).unwrap_or(HardwareModel::Unknown),
+            firmware: String::new(),
+            version: String::new(),
+            gateway: flags & NODEINFO_GATEWAY != 0,
+            encryption: flags & NODEINFO_ENCRYPTION != 0,
+            routing: if flags & NODEINFO_LINKSTATE != 0 {
+                RoutingMode::LinkState
+            } else if flags & NODEINFO_DSR != 0 {
+                RoutingMode::Dsr
+            } else {
+                RoutingMode::Aodv
+            },
+            headercompression: flags & NODEINFO_HEADERCOMP != 0,
+        };
+        // unknown types are skipped so newer nodes can add fields
+        for (tlvtype, value) in parse_tlv(&data[2..]) {
+            let value = String::from_utf8_lossy(&value).into_owned();
+            match tlvtype {
+                NODEINFO_NAME => info.name = Some(value),
+                NODEINFO_FIRMWARE => info.firmware = value,
+                NODEINFO_VERSION => info.version = value,
+                _ => {}
+            }
+        }
+        Ok(Box::new(NodeInfoMessage {
+            header: Some(f.header()),
+            request: flags & NODEINFO_REQUEST != 0,
+            announce: flags & NODEINFO_ANNOUNCE != 0,
+            info,
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let info = &self.info;
+        let mut flags = 0;
+        if self.request { flags |= NODEINFO_REQUEST; }
+        if self.announce { flags |= NODEINFO_ANNOUNCE; }
+        if info.gateway { flags |= NODEINFO_GATEWAY; }
+        if info.encryption { flags |= NODEINFO_ENCRYPTION; }
+        if info.routing == RoutingMode::LinkState { flags |= NODEINFO_LINKSTATE; }
+        if info.routing == RoutingMode::Dsr { flags |= NODEINFO_DSR; }
+        if info.headercompression { flags |= NODEINFO_HEADERCOMP; }
+
+        let mut data = vec![flags, info.hardware as u8];
+        if let Some(name) = &info.name {
+            push_tlv(&mut data, NODEINFO_NAME, truncated(name).as_bytes());
+        }
+        push_tlv(&mut data, NODEINFO_FIRMWARE, truncated(&info.firmware).as_bytes());
+        push_tlv(&mut data, NODEINFO_VERSION, truncated(&info.version).as_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::NodeInfo)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn nodeinfo_tofrom_frame() {
+    assert_eq!(HardwareModel::from_version("RN2903 1.0.5 Nov 06 2018 10:45:27"), HardwareModel::LoStik);
+    assert_eq!(HardwareModel::from_version("RN2483 1.0.5 Oct 31 2018 15:06:52"), HardwareModel::RN2483);
+    assert_eq!(HardwareModel::from_version(""), HardwareModel::Unknown);
+
+    let info = NodeInfo {
+        name: Some(String::from("roof")),
+        hardware: HardwareModel::LoStik,
+        firmware: String::from("RN2903 1.0.5 Nov 06 2018 10:45:27"),
+        version: String::from("0.1.0"),
+        gateway: true,
+        encryption: false,
+        routing: RoutingMode::LinkState,
+        headercompression: true,
+    };
+    let mut msg = NodeInfoMessage::new(info.clone());
+    msg.request = true;
+    let mut frame = Frame::from_bytes(&msg.to_frame(3, 1, vec![4]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::NodeInfo);
+    let received = NodeInfoMessage::from_frame(&mut frame).unwrap();
+    assert!(received.request && !received.announce);
+    assert_eq!(received.info, info);
+
+    let anonymous = NodeInfo { name: None, gateway: false, routing: RoutingMode::Aodv, headercompression: false, ..info };
+    let mut msg = NodeInfoMessage::new(anonymous.clone());
+    msg.announce = true;
+    let mut frame = Frame::from_bytes(&msg.to_frame(4, 1, vec![1]).unwrap().to_bytes()).unwrap();
+    let received = NodeInfoMessage::from_frame(&mut frame).unwrap();
+    assert!(!received.request && received.announce);
+    assert_eq!(received.info, anonymous);
+
+    let dsr = NodeInfo { routing: RoutingMode::Dsr, ..anonymous };
+    let mut frame = Frame::from_bytes(&NodeInfoMessage::new(dsr.clone()).to_frame(5, 1, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(NodeInfoMessage::from_frame(&mut frame).unwrap().info, dsr);
+}
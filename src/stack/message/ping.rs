@@ -0,0 +1,111 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// sequence number plus sender timestamp
+const PING_PAYLOAD_LEN: usize = 10;
+
+/// Asks a node to answer with a `PongMessage`
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct PingMessage {
+    pub header: Option<FrameHeader>,
+    pub seq: u16,
+    /// sender clock in milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// Answer to a `PingMessage`, echoing its sequence number and timestamp
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct PongMessage {
+    pub header: Option<FrameHeader>,
+    pub seq: u16,
+    pub timestamp: u64,
+}
+
+impl PingMessage {
+    pub fn new(seq: u16, timestamp: u64) -> Self {
+        PingMessage { header: None, seq, timestamp }
+    }
+
+    /// the pong answering this ping
+    pub fn pong(&self) -> PongMessage {
+        PongMessage { header: None, seq: self.seq, timestamp: self.timestamp }
+    }
+}
+
+fn ping_payload(seq: u16, timestamp: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(PING_PAYLOAD_LEN);
+    data.extend_from_slice(&seq.to_be_bytes());
+    data.extend_from_slice(&timestamp.to_be_bytes());
+    return data;
+}
+
+fn parse_ping_payload(data: &[u8]) -> io::Result<(u16, u64)> {
+    if data.len() < PING_PAYLOAD_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "ping payload is too short"));
+    }
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&data[2..PING_PAYLOAD_LEN]);
+    Ok((u16::from_be_bytes([data[0], data[1]]), u64::from_be_bytes(timestamp)))
+}
+
+impl ToFromFrame for PingMessage {
+    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+        let (seq, timestamp) = parse_ping_payload(&f.payload())?;
+        Ok(Box::new(PingMessage { header: Some(f.header()), seq, timestamp }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Ping)
+            .sender(sender)
+            .route(&route)
+            .payload(ping_payload(self.seq, self.timestamp))
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+impl ToFromFrame for PongMessage {
+    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+        let (seq, timestamp) = parse_ping_payload(&f.payload())?;
+        Ok(Box::new(PongMessage { header: Some(f.header()), seq, timestamp }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Pong)
+            .sender(sender)
+            .route(&route)
+            .payload(ping_payload(self.seq, self.timestamp))
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn ping_pong_tofrom_frame() {
+    let ping = PingMessage::new(513, 1_600_000_000_123);
+    let bytes = ping.to_frame(4, 1, vec![2, 3]).unwrap().to_bytes();
+
+    let mut frame = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::Ping);
+    let ping2 = PingMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((ping2.seq, ping2.timestamp), (513, 1_600_000_000_123));
+
+    let bytes = ping2.pong().to_frame(9, 3, vec![2, 1]).unwrap().to_bytes();
+    let mut frame = Frame::from_bytes(&bytes).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::Pong);
+    let pong = PongMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((pong.seq, pong.timestamp), (513, 1_600_000_000_123));
+    assert_eq!(pong.header.unwrap().sender(), 3);
+
+    assert!(parse_ping_payload(&[0, 1, 2]).is_err());
+}
@@ -0,0 +1,105 @@
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// coordinates are sent in units of 1e-7 degrees
+pub const POSITION_SCALE: f64 = 1e7;
+
+const POSITION_PAYLOAD_LEN: usize = 21;
+
+/// Where a node is, announced to the whole mesh
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct PositionMessage {
+    pub header: Option<FrameHeader>,
+    /// latitude in 1e-7 degrees
+    pub lat: i32,
+    /// longitude in 1e-7 degrees
+    pub lon: i32,
+    /// meters above mean sea level
+    pub altitude: i16,
+    /// ground speed in cm/s
+    pub speed: u16,
+    /// GGA fix quality
+    pub fix: u8,
+    /// ms since the unix epoch
+    pub timestamp: u64,
+}
+
+impl PositionMessage {
+    pub fn new(lat: f64, lon: f64, altitude: f32, speed: f32, fix: u8, timestamp: u64) -> Self {
+        PositionMessage {
+            header: None,
+            lat: (lat * POSITION_SCALE).round() as i32,
+            lon: (lon * POSITION_SCALE).round() as i32,
+            altitude: altitude.round() as i16,
+            speed: (speed * 100.0).round() as u16,
+            fix,
+            timestamp,
+        }
+    }
+
+    pub fn lat_degrees(&self) -> f64 {
+        self.lat as f64 / POSITION_SCALE
+    }
+
+    pub fn lon_degrees(&self) -> f64 {
+        self.lon as f64 / POSITION_SCALE
+    }
+}
+
+impl ToFromFrame for PositionMessage {
+    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < POSITION_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "position payload is too short"));
+        }
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&data[13..21]);
+
+        Ok(Box::new(PositionMessage {
+            header: Some(f.header()),
+            lat: i32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            lon: i32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            altitude: i16::from_be_bytes([data[8], data[9]]),
+            speed: u16::from_be_bytes([data[10], data[11]]),
+            fix: data[12],
+            timestamp: u64::from_be_bytes(timestamp),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> std::io::Result<Frame> {
+        let mut data = Vec::with_capacity(POSITION_PAYLOAD_LEN);
+        data.extend_from_slice(&self.lat.to_be_bytes());
+        data.extend_from_slice(&self.lon.to_be_bytes());
+        data.extend_from_slice(&self.altitude.to_be_bytes());
+        data.extend_from_slice(&self.speed.to_be_bytes());
+        data.push(self.fix);
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Position)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn position_tofrom_frame() {
+    let msg = PositionMessage::new(-33.868_82, 151.209_29, 58.4, 1.25, 2, 1_600_000_000_000);
+    let mut frame = Frame::from_bytes(&msg.to_frame(1, 6, vec![6]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::Position);
+
+    let received = PositionMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(received.lat, -338_688_200);
+    assert_eq!(received.lon, 1_512_092_900);
+    assert!((received.lat_degrees() + 33.868_82).abs() < 1e-7);
+    assert_eq!((received.altitude, received.speed, received.fix), (58, 125, 2));
+    assert_eq!(received.timestamp, 1_600_000_000_000);
+}
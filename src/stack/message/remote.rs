@@ -0,0 +1,207 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// Longest output a remote command sends back, in bytes of UTF-8
+pub const MAX_REMOTE_OUTPUT: usize = 1000;
+
+/// verb codes on the wire
+const VERB_GET: u8 = 1;
+const VERB_SET: u8 = 2;
+const VERB_RESTART_RADIO: u8 = 3;
+const VERB_SELFTEST: u8 = 4;
+const VERB_STATS: u8 = 5;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut nonce = [0u8; 8];
+    nonce.copy_from_slice(&data[..8]);
+    u64::from_be_bytes(nonce)
+}
+
+/// What a remote command asks a node to do
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub enum RemoteCommand {
+    /// read a setting
+    Get(String),
+    /// change a setting until the node restarts
+    Set(String, String),
+    /// restart and reinitialize the radio
+    RestartRadio,
+    /// check that the radio answers
+    SelfTest,
+    /// report the node's health readings
+    Stats,
+}
+
+impl RemoteCommand {
+    /// Parse a command line such as `set maxhops 3`
+    pub fn parse(line: &str) -> io::Result<Self> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some("get") => RemoteCommand::Get(String::from(words.next().ok_or(invalid("usage: get <setting>"))?)),
+            Some("set") => {
+                let key = words.next().ok_or(invalid("usage: set <setting> <value>"))?;
+                let value = words.next().ok_or(invalid("usage: set <setting> <value>"))?;
+                RemoteCommand::Set(String::from(key), String::from(value))
+            },
+            Some("restart-radio") => RemoteCommand::RestartRadio,
+            Some("selftest") => RemoteCommand::SelfTest,
+            Some("stats") => RemoteCommand::Stats,
+            Some(verb) => return Err(invalid(&format!("unknown remote command: {}", verb))),
+            None => return Err(invalid("missing remote command")),
+        };
+        match words.next() {
+            Some(extra) => Err(invalid(&format!("unexpected argument: {}", extra))),
+            None => Ok(command),
+        }
+    }
+
+    /// The command line for this command
+    pub fn to_line(&self) -> String {
+        match self {
+            RemoteCommand::Get(key) => format!("get {}", key),
+            RemoteCommand::Set(key, value) => format!("set {} {}", key, value),
+            RemoteCommand::RestartRadio => String::from("restart-radio"),
+            RemoteCommand::SelfTest => String::from("selftest"),
+            RemoteCommand::Stats => String::from("stats"),
+        }
+    }
+}
+
+/// A command for another node to run, only accepted with frame authentication
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct RemoteCommandMessage {
+    pub header: Option<FrameHeader>,
+    /// only ever increases per sender, repeats are rejected as replays
+    pub nonce: u64,
+    pub command: RemoteCommand,
+}
+
+/// The outcome of a remote command, sent back to the node that sent it
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct RemoteCommandResultMessage {
+    pub header: Option<FrameHeader>,
+    /// nonce of the command
+    pub nonce: u64,
+    pub ok: bool,
+    /// output of the command, or why it was rejected
+    pub output: String,
+}
+
+impl RemoteCommandResultMessage {
+    pub fn new(nonce: u64, ok: bool, output: &str) -> Self {
+        let mut end = output.len().min(MAX_REMOTE_OUTPUT);
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        RemoteCommandResultMessage { header: None, nonce, ok, output: String::from(&output[..end]) }
+    }
+}
+
+impl ToFromFrame for RemoteCommandMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 9 {
+            return Err(invalid("remote command payload is too short"));
+        }
+        let args = String::from_utf8(Vec::from(&data[9..])).map_err(|_| invalid("remote command is not UTF-8"))?;
+        let mut args = args.splitn(2, ' ');
+        let mut arg = || args.next().map(String::from).ok_or(invalid("remote command is missing an argument"));
+        let command = match data[8] {
+            VERB_GET => RemoteCommand::Get(arg()?),
+            VERB_SET => RemoteCommand::Set(arg()?, arg()?),
+            VERB_RESTART_RADIO => RemoteCommand::RestartRadio,
+            VERB_SELFTEST => RemoteCommand::SelfTest,
+            VERB_STATS => RemoteCommand::Stats,
+            verb => return Err(invalid(&format!("unknown remote command verb {}", verb))),
+        };
+        Ok(Box::new(RemoteCommandMessage { header: Some(f.header()), nonce: read_u64(&data), command }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::from(&self.nonce.to_be_bytes()[..]);
+        match &self.command {
+            RemoteCommand::Get(key) => {
+                data.push(VERB_GET);
+                data.extend_from_slice(key.as_bytes());
+            },
+            RemoteCommand::Set(key, value) => {
+                data.push(VERB_SET);
+                data.extend_from_slice(format!("{} {}", key, value).as_bytes());
+            },
+            RemoteCommand::RestartRadio => data.push(VERB_RESTART_RADIO),
+            RemoteCommand::SelfTest => data.push(VERB_SELFTEST),
+            RemoteCommand::Stats => data.push(VERB_STATS),
+        }
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::RemoteCommand)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+impl ToFromFrame for RemoteCommandResultMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 9 {
+            return Err(invalid("remote command result payload is too short"));
+        }
+        Ok(Box::new(RemoteCommandResultMessage {
+            header: Some(f.header()),
+            nonce: read_u64(&data),
+            ok: data[8] != 0,
+            output: String::from_utf8_lossy(&data[9..]).into_owned(),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::from(&self.nonce.to_be_bytes()[..]);
+        data.push(self.ok as u8);
+        data.extend_from_slice(self.output.as_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::RemoteCommandResult)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn remote_command_tofrom_frame() {
+    for line in &["get maxhops", "set autoretransmit true", "restart-radio", "selftest", "stats"] {
+        let command = RemoteCommand::parse(line).unwrap();
+        assert_eq!(&command.to_line(), line);
+        let msg = RemoteCommandMessage { header: None, nonce: 1_600_000_000_123, command: command.clone() };
+        let mut frame = Frame::from_bytes(&msg.to_frame(1, 2, vec![3]).unwrap().to_bytes()).unwrap();
+        let received = RemoteCommandMessage::from_frame(&mut frame).unwrap();
+        assert_eq!((received.nonce, received.command), (1_600_000_000_123, command));
+    }
+    assert!(RemoteCommand::parse("set maxhops").is_err());
+    assert!(RemoteCommand::parse("reboot").is_err());
+    assert!(RemoteCommand::parse("stats now").is_err());
+
+    let result = RemoteCommandResultMessage::new(7, false, &"x".repeat(2000));
+    assert_eq!(result.output.len(), MAX_REMOTE_OUTPUT);
+    let mut frame = Frame::from_bytes(&result.to_frame(1, 3, vec![2]).unwrap().to_bytes()).unwrap();
+    let received = RemoteCommandResultMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.nonce, received.ok, received.output.len()), (7, false, MAX_REMOTE_OUTPUT));
+}
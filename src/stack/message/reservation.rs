@@ -0,0 +1,122 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, NodeId, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// Asks a neighbor to reserve the channel for a burst we are about to send it
+/* Sent a single hop. Nodes that overhear it hold back for the burst as
+well, it is the receiver's confirm that reaches the nodes we cannot hear. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransmitRequestMessage {
+    pub header: Option<FrameHeader>,
+    /// the intended receiver
+    pub dest: NodeId,
+    /// ms the burst takes
+    pub duration: u16,
+}
+
+impl TransmitRequestMessage {
+    pub fn new(dest: NodeId, duration: u16) -> Self {
+        TransmitRequestMessage { header: None, dest, duration }
+    }
+}
+
+impl ToFromFrame for TransmitRequestMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 3 {
+            return Err(Error::new(ErrorKind::InvalidData, "transmit request payload is too short"));
+        }
+        Ok(Box::new(TransmitRequestMessage {
+            header: Some(f.header()),
+            dest: data[0],
+            duration: u16::from_be_bytes([data[1], data[2]]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = vec![self.dest];
+        data.extend_from_slice(&self.duration.to_be_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::TransmitRequest)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+/// Grants a transmit request, everyone hearing it holds back for the burst
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransmitConfirmMessage {
+    pub header: Option<FrameHeader>,
+    /// the original requester
+    pub requester: NodeId,
+    /// ms the channel is reserved for
+    pub duration: u16,
+}
+
+impl TransmitConfirmMessage {
+    pub fn new(requester: NodeId, duration: u16) -> Self {
+        TransmitConfirmMessage { header: None, requester, duration }
+    }
+}
+
+impl ToFromFrame for TransmitConfirmMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 3 {
+            return Err(Error::new(ErrorKind::InvalidData, "transmit confirm payload is too short"));
+        }
+        Ok(Box::new(TransmitConfirmMessage {
+            header: Some(f.header()),
+            requester: data[0],
+            duration: u16::from_be_bytes([data[1], data[2]]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = vec![self.requester];
+        data.extend_from_slice(&self.duration.to_be_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::TransmitConfirm)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn reservation_messages_tofrom_frame() {
+    let mut frame = Frame::from_bytes(&TransmitRequestMessage::new(2, 4500).to_frame(5, 1, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::TransmitRequest);
+    let request = TransmitRequestMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((request.dest, request.duration), (2, 4500));
+
+    let mut frame = Frame::from_bytes(&TransmitConfirmMessage::new(1, 4500).to_frame(6, 2, vec![2]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::TransmitConfirm);
+    let confirm = TransmitConfirmMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((confirm.requester, confirm.duration), (1, 4500));
+
+    // a confirm without its duration is malformed
+    let mut frame = FrameBuilder::new()
+        .frameid(7)
+        .message_type(MessageType::TransmitConfirm)
+        .sender(2)
+        .route(&[2])
+        .payload(vec![1])
+        .build()
+        .unwrap();
+    assert!(TransmitConfirmMessage::from_frame(&mut frame).is_err());
+}
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, NodeId, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// A relay could not reach the next hop of a source route
+/* Sent back to the origin of the failed frame. Every node relaying it
+drops the broken link from its graph, the origin then looks for a new
+route to `dest`. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteErrorMessage {
+    pub header: Option<FrameHeader>,
+    /// the node that detected the failure
+    pub from: NodeId,
+    /// the next hop it could not reach
+    pub to: NodeId,
+    /// final destination of the failed frame
+    pub dest: NodeId,
+}
+
+impl RouteErrorMessage {
+    pub fn new(from: NodeId, to: NodeId, dest: NodeId) -> Self {
+        RouteErrorMessage { header: None, from, to, dest }
+    }
+}
+
+impl ToFromFrame for RouteErrorMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 3 {
+            return Err(Error::new(ErrorKind::InvalidData, "route error payload is too short"));
+        }
+        Ok(Box::new(RouteErrorMessage {
+            header: Some(f.header()),
+            from: data[0],
+            to: data[1],
+            dest: data[2],
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::RouteError)
+            .sender(sender)
+            .route(&route)
+            .payload(vec![self.from, self.to, self.dest])
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+/// A route cost in hundredths of a transmission, as carried in frames
+pub fn cost_hundredths(cost: f32) -> u16 {
+    (cost * 100.0).round().max(0.0).min(u16::MAX as f32) as u16
+}
+
+/// Looks for a route to a node, flooded through the mesh
+/* Each relay adds itself to the front of the frame route, so the
+destination receives the path back to the origin, and adds the ETX of the
+hop it heard the frame on to `cost`. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteDiscoveryMessage {
+    pub header: Option<FrameHeader>,
+    pub dest: NodeId,
+    /// ETX of the path so far, in hundredths
+    pub cost: u16,
+}
+
+impl RouteDiscoveryMessage {
+    pub fn new(dest: NodeId) -> Self {
+        RouteDiscoveryMessage { header: None, dest, cost: 0 }
+    }
+}
+
+impl ToFromFrame for RouteDiscoveryMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 3 {
+            return Err(Error::new(ErrorKind::InvalidData, "route discovery payload is too short"));
+        }
+        Ok(Box::new(RouteDiscoveryMessage {
+            header: Some(f.header()),
+            dest: data[0],
+            cost: u16::from_be_bytes([data[1], data[2]]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = vec![self.dest];
+        data.extend_from_slice(&self.cost.to_be_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::RouteDiscovery)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+/// Answer to a route discovery, carrying the path from origin to destination
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct RouteSuccessMessage {
+    pub header: Option<FrameHeader>,
+    pub path: Vec<NodeId>,
+    /// ETX of the whole path, in hundredths
+    pub cost: u16,
+}
+
+impl RouteSuccessMessage {
+    pub fn new(path: Vec<NodeId>, cost: u16) -> Self {
+        RouteSuccessMessage { header: None, path, cost }
+    }
+}
+
+impl ToFromFrame for RouteSuccessMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "route success payload is too short"));
+        }
+        Ok(Box::new(RouteSuccessMessage {
+            header: Some(f.header()),
+            path: data[2..].to_vec(),
+            cost: u16::from_be_bytes([data[0], data[1]]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = self.cost.to_be_bytes().to_vec();
+        data.extend_from_slice(&self.path);
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::RouteSuccess)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+/// Lowest cost each route discovery reached us with
+/* The first copy of a discovery to arrive came over the fewest hops, not
+necessarily the cheapest path. Later copies are forwarded or answered
+again only if they came a cheaper way. */
+#[derive(Clone, Debug)]
+pub struct DiscoveryCosts {
+    window: Duration,
+    best: HashMap<(NodeId, u8), (u16, Instant)>,
+}
+
+impl DiscoveryCosts {
+    /// copies of a discovery are compared for `window` after the first one
+    pub fn new(window: Duration) -> Self {
+        DiscoveryCosts { window, best: HashMap::new() }
+    }
+
+    /// true if a copy of a discovery from `origin` is the first or cheapest so far
+    pub fn improves(&mut self, origin: NodeId, frameid: u8, cost: u16) -> bool {
+        self.improves_at(origin, frameid, cost, Instant::now())
+    }
+
+    pub(crate) fn improves_at(&mut self, origin: NodeId, frameid: u8, cost: u16, now: Instant) -> bool {
+        let window = self.window;
+        self.best.retain(|_, (_, first)| now.duration_since(*first) < window);
+        match self.best.get_mut(&(origin, frameid)) {
+            Some((best, _)) if *best <= cost => false,
+            Some((best, _)) => {
+                *best = cost;
+                true
+            },
+            None => {
+                self.best.insert((origin, frameid), (cost, now));
+                true
+            },
+        }
+    }
+}
+
+/// What a node does with a route discovery it heard
+pub enum DiscoveryAction {
+    /// already seen by us, or out of hops
+    Ignore,
+    /// flood it on with ourselves added to the route
+    Forward(Frame),
+    /// we are the destination, send the path back to the origin along `route`
+    Reply { reply: RouteSuccessMessage, route: Vec<u8> },
+}
+
+/// Handle a route discovery frame heard by `nodeid` over a hop costing `hop`
+pub fn discovery_hop(frame: &mut Frame, nodeid: NodeId, maxhops: u8, hop: f32, seen: &mut DiscoveryCosts) -> io::Result<DiscoveryAction> {
+    let route = frame.route();
+    if frame.sender() == nodeid || route.contains(&nodeid) {
+        return Ok(DiscoveryAction::Ignore);
+    }
+    let mut msg = RouteDiscoveryMessage::from_frame(frame)?;
+    let cost = msg.cost.saturating_add(cost_hundredths(hop));
+    if !seen.improves(frame.sender(), frame.frameid(), cost) {
+        return Ok(DiscoveryAction::Ignore);
+    }
+
+    if msg.dest == nodeid {
+        let mut path: Vec<NodeId> = route.iter().rev().cloned().collect();
+        path.push(nodeid);
+        return Ok(DiscoveryAction::Reply { reply: RouteSuccessMessage::new(path, cost), route });
+    }
+    if (route.len() as u8) < maxhops {
+        msg.cost = cost;
+        let mut next = msg.to_frame(frame.frameid(), frame.sender(), route)?;
+        if next.route_unshift(nodeid).is_ok() {
+            return Ok(DiscoveryAction::Forward(next));
+        }
+    }
+    Ok(DiscoveryAction::Ignore)
+}
+
+#[cfg(test)]
+#[test]
+fn route_messages_tofrom_frame() {
+    let mut frame = Frame::from_bytes(&RouteErrorMessage::new(2, 3, 4).to_frame(1, 2, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::RouteError);
+    let error = RouteErrorMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((error.from, error.to, error.dest), (2, 3, 4));
+
+    let mut frame = Frame::from_bytes(&RouteSuccessMessage::new(vec![1, 2, 4], 345).to_frame(1, 4, vec![2, 1]).unwrap().to_bytes()).unwrap();
+    let success = RouteSuccessMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((success.path, success.cost), (vec![1, 2, 4], 345));
+
+    // two hops allowed, the second relay gives up
+    let window = Duration::from_secs(30);
+    let mut seen: HashMap<NodeId, DiscoveryCosts> = [1, 2, 3, 9].iter().map(|id| (*id, DiscoveryCosts::new(window))).collect();
+    let mut frame = RouteDiscoveryMessage::new(9).to_frame(5, 1, vec![1]).unwrap();
+    let mut frame = match discovery_hop(&mut frame, 2, 2, 1.5, seen.get_mut(&2).unwrap()).unwrap() {
+        DiscoveryAction::Forward(next) => next,
+        _ => panic!("first relay should forward"),
+    };
+    assert_eq!(frame.route(), vec![2, 1]);
+    assert_eq!(RouteDiscoveryMessage::from_frame(&mut frame.clone()).unwrap().cost, 150);
+    assert!(match discovery_hop(&mut frame.clone(), 1, 2, 1.0, seen.get_mut(&1).unwrap()).unwrap() { DiscoveryAction::Ignore => true, _ => false });
+    assert!(match discovery_hop(&mut frame.clone(), 3, 2, 1.0, seen.get_mut(&3).unwrap()).unwrap() { DiscoveryAction::Ignore => true, _ => false });
+    match discovery_hop(&mut frame.clone(), 9, 2, 1.0, seen.get_mut(&9).unwrap()).unwrap() {
+        DiscoveryAction::Reply { reply, route } => {
+            assert_eq!((reply.path, reply.cost), (vec![1, 2, 9], 250));
+            assert_eq!(route, vec![2, 1]);
+        },
+        _ => panic!("destination should reply"),
+    }
+    // the same copy again is no cheaper
+    assert!(match discovery_hop(&mut frame, 9, 2, 1.0, seen.get_mut(&9).unwrap()).unwrap() { DiscoveryAction::Ignore => true, _ => false });
+}
+
+#[test]
+fn discovery_cheaper_copy() {
+    use crate::stack::linkstate::NeighborLink;
+
+    // a link heard twice counts as a single hop, one heard ten times by its ETX
+    let lossy = |samples: usize| NeighborLink { inbound: 0.5, outbound: Some(0.5), rssiin: None, rssiout: None, samples }.route_cost(5);
+    assert_eq!((lossy(2), lossy(10)), (1.0, 4.0));
+
+    // node 9 hears the short lossy copy first and answers it, then a cheaper one over three clean hops
+    let mut seen = DiscoveryCosts::new(Duration::from_secs(30));
+    let copy = |route: Vec<NodeId>, cost: u16| {
+        let mut msg = RouteDiscoveryMessage::new(9);
+        msg.cost = cost;
+        msg.to_frame(7, 1, route).unwrap()
+    };
+    let reply = |action: DiscoveryAction| match action {
+        DiscoveryAction::Reply { reply, .. } => Some((reply.path, reply.cost)),
+        _ => None,
+    };
+    assert_eq!(reply(discovery_hop(&mut copy(vec![2, 1], 400), 9, 4, 4.0, &mut seen).unwrap()), Some((vec![1, 2, 9], 800)));
+    assert_eq!(reply(discovery_hop(&mut copy(vec![4, 3, 1], 200), 9, 4, 1.0, &mut seen).unwrap()), Some((vec![1, 3, 4, 9], 300)));
+    // copies no cheaper than the best so far are left alone
+    assert_eq!(reply(discovery_hop(&mut copy(vec![2, 1], 400), 9, 4, 1.0, &mut seen).unwrap()), None);
+}
@@ -0,0 +1,207 @@
+use std::io;
+use std::convert::TryInto;
+use serde::Serialize;
+use crate::hardware::lostik::mkerror;
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+use crate::stack::util::{parse_tlv, push_tlv};
+
+/// A health metric a node can report
+/* The discriminant is the TLV type code on the wire. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TelemetryField {
+    Vdd = 1,
+    Uptime = 2,
+    TxFrames = 3,
+    RxFrames = 4,
+    Dropped = 5,
+    QueueDepth = 6,
+    FreeMemory = 7,
+    Icmp = 8,
+}
+
+impl TelemetryField {
+    pub const ALL: [TelemetryField; 8] = [
+        TelemetryField::Vdd,
+        TelemetryField::Uptime,
+        TelemetryField::TxFrames,
+        TelemetryField::RxFrames,
+        TelemetryField::Dropped,
+        TelemetryField::QueueDepth,
+        TelemetryField::FreeMemory,
+        TelemetryField::Icmp,
+    ];
+
+    /// the name used for this field in the settings
+    pub fn name(&self) -> &'static str {
+        match self {
+            TelemetryField::Vdd => "vdd",
+            TelemetryField::Uptime => "uptime",
+            TelemetryField::TxFrames => "tx",
+            TelemetryField::RxFrames => "rx",
+            TelemetryField::Dropped => "dropped",
+            TelemetryField::QueueDepth => "queue",
+            TelemetryField::FreeMemory => "memory",
+            TelemetryField::Icmp => "icmp",
+        }
+    }
+
+    /// Parse a comma separated list of field names
+    pub fn parse_list(list: &str) -> io::Result<Vec<TelemetryField>> {
+        list.split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                TelemetryField::ALL.iter()
+                    .find(|field| field.name() == name)
+                    .cloned()
+                    .ok_or(mkerror(&format!("unknown telemetry field: {}", name)))
+            })
+            .collect()
+    }
+}
+
+/// One set of health readings from a node, fields that are not reported are None
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "serde-support", derive(serde::Deserialize))]
+pub struct TelemetrySample {
+    /// radio supply voltage in mV
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vdd_mv: Option<u16>,
+    /// seconds since the node started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_s: Option<u32>,
+    /// frames handed to the radio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_frames: Option<u32>,
+    /// frames received from the radio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rx_frames: Option<u32>,
+    /// frames dropped because a queue was full or they could not be parsed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped: Option<u32>,
+    /// frames waiting in the transmit queue
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_depth: Option<u16>,
+    /// memory available to the host in kB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_memory_kb: Option<u32>,
+    /// ICMP packets sent and received over the mesh, not counted in the frames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icmp_packets: Option<u32>,
+}
+
+impl TelemetrySample {
+    /// clear every field that is not in the list
+    pub fn retain(&mut self, fields: &[TelemetryField]) {
+        let keep = |field| fields.contains(&field);
+        if !keep(TelemetryField::Vdd) { self.vdd_mv = None; }
+        if !keep(TelemetryField::Uptime) { self.uptime_s = None; }
+        if !keep(TelemetryField::TxFrames) { self.tx_frames = None; }
+        if !keep(TelemetryField::RxFrames) { self.rx_frames = None; }
+        if !keep(TelemetryField::Dropped) { self.dropped = None; }
+        if !keep(TelemetryField::QueueDepth) { self.queue_depth = None; }
+        if !keep(TelemetryField::FreeMemory) { self.free_memory_kb = None; }
+        if !keep(TelemetryField::Icmp) { self.icmp_packets = None; }
+    }
+}
+
+/// Periodic health report sent to the gateway
+/* Each reported field is a type-length-value entry, fields that are not
+reported take no space at all. Unknown types are skipped so newer nodes can
+add fields. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct TelemetryMessage {
+    pub header: Option<FrameHeader>,
+    pub sample: TelemetrySample,
+}
+
+impl TelemetryMessage {
+    pub fn new(sample: TelemetrySample) -> Self {
+        TelemetryMessage { header: None, sample }
+    }
+}
+
+impl ToFromFrame for TelemetryMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let mut sample = TelemetrySample::default();
+        for (tlvtype, value) in parse_tlv(&f.payload()) {
+            let value = value.as_slice();
+            let u16value = value.try_into().ok().map(u16::from_be_bytes);
+            let u32value = value.try_into().ok().map(u32::from_be_bytes);
+            match tlvtype {
+                t if t == TelemetryField::Vdd as u8 => sample.vdd_mv = u16value,
+                t if t == TelemetryField::Uptime as u8 => sample.uptime_s = u32value,
+                t if t == TelemetryField::TxFrames as u8 => sample.tx_frames = u32value,
+                t if t == TelemetryField::RxFrames as u8 => sample.rx_frames = u32value,
+                t if t == TelemetryField::Dropped as u8 => sample.dropped = u32value,
+                t if t == TelemetryField::QueueDepth as u8 => sample.queue_depth = u16value,
+                t if t == TelemetryField::FreeMemory as u8 => sample.free_memory_kb = u32value,
+                t if t == TelemetryField::Icmp as u8 => sample.icmp_packets = u32value,
+                _ => {},
+            }
+        }
+
+        Ok(Box::new(TelemetryMessage {
+            header: Some(f.header()),
+            sample,
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let sample = &self.sample;
+        let mut data = Vec::new();
+        if let Some(v) = sample.vdd_mv { push_tlv(&mut data, TelemetryField::Vdd as u8, &v.to_be_bytes()); }
+        if let Some(v) = sample.uptime_s { push_tlv(&mut data, TelemetryField::Uptime as u8, &v.to_be_bytes()); }
+        if let Some(v) = sample.tx_frames { push_tlv(&mut data, TelemetryField::TxFrames as u8, &v.to_be_bytes()); }
+        if let Some(v) = sample.rx_frames { push_tlv(&mut data, TelemetryField::RxFrames as u8, &v.to_be_bytes()); }
+        if let Some(v) = sample.dropped { push_tlv(&mut data, TelemetryField::Dropped as u8, &v.to_be_bytes()); }
+        if let Some(v) = sample.queue_depth { push_tlv(&mut data, TelemetryField::QueueDepth as u8, &v.to_be_bytes()); }
+        if let Some(v) = sample.free_memory_kb { push_tlv(&mut data, TelemetryField::FreeMemory as u8, &v.to_be_bytes()); }
+        if let Some(v) = sample.icmp_packets { push_tlv(&mut data, TelemetryField::Icmp as u8, &v.to_be_bytes()); }
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Telemetry)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn telemetry_tofrom_frame() {
+    let full = TelemetrySample {
+        vdd_mv: Some(3312),
+        uptime_s: Some(86_400),
+        tx_frames: Some(1200),
+        rx_frames: Some(4800),
+        dropped: Some(3),
+        queue_depth: Some(2),
+        free_memory_kb: Some(512_000),
+        icmp_packets: Some(42),
+    };
+    let mut frame = Frame::from_bytes(&TelemetryMessage::new(full.clone()).to_frame(1, 4, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::Telemetry);
+    assert_eq!(TelemetryMessage::from_frame(&mut frame).unwrap().sample, full);
+    let fulllen = frame.payload().len();
+
+    // omitted fields take no payload bytes
+    let fields = TelemetryField::parse_list("vdd, uptime").unwrap();
+    let mut partial = full.clone();
+    partial.retain(&fields);
+    let mut frame = Frame::from_bytes(&TelemetryMessage::new(partial.clone()).to_frame(2, 4, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.payload().len(), 4 + 6);
+    assert!(frame.payload().len() < fulllen);
+    let received = TelemetryMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(received.sample, partial);
+    assert_eq!(received.sample.rx_frames, None);
+
+    assert!(TelemetryField::parse_list("vdd,temperature").is_err());
+    assert_eq!(TelemetryField::parse_list("").unwrap(), vec![]);
+}
@@ -0,0 +1,199 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// Longest text accepted, in bytes of UTF-8
+/* Texts longer than a radio frame are chunked like IP packets, the cap
+keeps a single text from holding the channel for too long. */
+pub const MAX_TEXT_LEN: usize = 1024;
+
+/// Longest sender display name, in bytes of UTF-8
+pub const MAX_NAME_LEN: usize = 32;
+
+/// flag asking the destination to send a receipt
+const TEXT_RECEIPT: u8 = 0x01;
+/// flag set when a display name follows the message ID
+const TEXT_NAME: u8 = 0x02;
+
+/// A UTF-8 text for the user of another node
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct TextMessage {
+    pub header: Option<FrameHeader>,
+    /// identifies the text in its delivery receipt
+    pub msgid: u16,
+    /// ask the destination to confirm delivery
+    pub receipt: bool,
+    /// display name of the sender
+    pub name: Option<String>,
+    pub text: String,
+}
+
+/// Confirms a text with a receipt request was delivered
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct TextReceiptMessage {
+    pub header: Option<FrameHeader>,
+    pub msgid: u16,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+impl TextMessage {
+    pub fn new(msgid: u16, text: &str) -> io::Result<Self> {
+        if text.len() > MAX_TEXT_LEN {
+            return Err(invalid(&format!("text is {} bytes, the maximum is {}", text.len(), MAX_TEXT_LEN)));
+        }
+        Ok(TextMessage { header: None, msgid, receipt: false, name: None, text: String::from(text) })
+    }
+}
+
+impl TextReceiptMessage {
+    pub fn new(msgid: u16) -> Self {
+        TextReceiptMessage { header: None, msgid }
+    }
+}
+
+impl ToFromFrame for TextMessage {
+    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 3 {
+            return Err(invalid("text payload is too short"));
+        }
+        let flags = data[0];
+        let msgid = u16::from_be_bytes([data[1], data[2]]);
+        let mut rest = &data[3..];
+
+        let mut name = None;
+        if flags & TEXT_NAME != 0 {
+            let len = *rest.get(0).ok_or(invalid("text is missing its name length"))? as usize;
+            let bytes = rest.get(1..1+len).ok_or(invalid("text name is truncated"))?;
+            name = Some(String::from_utf8(Vec::from(bytes)).map_err(|_| invalid("text name is not UTF-8"))?);
+            rest = &rest[1+len..];
+        }
+        if rest.len() > MAX_TEXT_LEN {
+            return Err(invalid("text is too long"));
+        }
+        let text = String::from_utf8(Vec::from(rest)).map_err(|_| invalid("text is not UTF-8"))?;
+
+        Ok(Box::new(TextMessage {
+            header: Some(f.header()),
+            msgid,
+            receipt: flags & TEXT_RECEIPT != 0,
+            name,
+            text,
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut flags = 0u8;
+        if self.receipt {
+            flags |= TEXT_RECEIPT;
+        }
+        let mut data = vec![0u8];
+        data.extend_from_slice(&self.msgid.to_be_bytes());
+        if let Some(name) = &self.name {
+            // names are cut at the limit, on a character boundary
+            let mut len = name.len().min(MAX_NAME_LEN);
+            while !name.is_char_boundary(len) {
+                len -= 1;
+            }
+            flags |= TEXT_NAME;
+            data.push(len as u8);
+            data.extend_from_slice(&name.as_bytes()[..len]);
+        }
+        data[0] = flags;
+        data.extend_from_slice(self.text.as_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Text)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+impl ToFromFrame for TextReceiptMessage {
+    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < 2 {
+            return Err(invalid("text receipt payload is too short"));
+        }
+        Ok(Box::new(TextReceiptMessage {
+            header: Some(f.header()),
+            msgid: u16::from_be_bytes([data[0], data[1]]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::TextReceipt)
+            .sender(sender)
+            .route(&route)
+            .payload(self.msgid.to_be_bytes().to_vec())
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn text_max_len() {
+    let text = "é".repeat(MAX_TEXT_LEN / 2);
+    assert!(TextMessage::new(1, &text).is_ok());
+    assert!(TextMessage::new(1, &format!("{}x", text)).is_err());
+
+    // an oversized name is cut without splitting a character
+    let mut msg = TextMessage::new(2, "hi").unwrap();
+    msg.name = Some("ö".repeat(MAX_NAME_LEN));
+    let mut frame = Frame::from_bytes(&msg.to_frame(1, 2, vec![3]).unwrap().to_bytes()).unwrap();
+    let msg = TextMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(msg.name.unwrap(), "ö".repeat(MAX_NAME_LEN / 2));
+    assert_eq!(msg.text, "hi");
+}
+
+#[test]
+fn text_chunked() {
+    use crate::stack::frame::recombine_chunks;
+
+    let mut msg = TextMessage::new(7, &"mesh ".repeat(150)).unwrap();
+    msg.name = Some(String::from("alice"));
+    let mut frame = msg.to_frame(9, 4, vec![2, 3]).unwrap();
+    let chunks = frame.chunked(&200);
+    assert_eq!(chunks.len(), 4);
+
+    let mut frames: Vec<Frame> = chunks.iter().map(|c| Frame::from_bytes(c).unwrap()).collect();
+    let header = frames[3].header();
+    let mut frame = recombine_chunks(frames, header);
+    let received = TextMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(received.text, msg.text);
+    assert_eq!(received.name, msg.name);
+    assert_eq!(received.msgid, 7);
+}
+
+#[test]
+fn text_receipt_roundtrip() {
+    let mut msg = TextMessage::new(300, "are you there?").unwrap();
+    msg.receipt = true;
+    let mut frame = Frame::from_bytes(&msg.to_frame(1, 2, vec![5]).unwrap().to_bytes()).unwrap();
+    let received = TextMessage::from_frame(&mut frame).unwrap();
+    assert!(received.receipt);
+    assert_eq!(received.name, None);
+
+    let receipt = TextReceiptMessage::new(received.msgid);
+    let mut frame = Frame::from_bytes(&receipt.to_frame(1, 5, vec![2]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::TextReceipt);
+    let receipt = TextReceiptMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(receipt.msgid, 300);
+    assert_eq!(receipt.header.unwrap().sender(), 5);
+
+    assert!(TextMessage::from_frame(&mut Frame::from_bytes(&vec![0, 1, 15, 1, 0, 0, 0, 0, 0xff]).unwrap()).is_err());
+}
@@ -0,0 +1,83 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, ToFromFrame};
+use crate::stack::message::MessageType;
+
+const TIMESYNC_PAYLOAD_LEN: usize = 13;
+
+/// flag set when a node asks the gateway to announce its time
+const TIMESYNC_REQUEST: u8 = 0x01;
+
+/// Wall-clock time of the gateway, flooded through the mesh
+/* Every relay adds the airtime of the hop it heard the frame on to
+`delay`, so a receiver only has to add the airtime of the last hop to
+know the gateway time at the moment it received the frame. */
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimeSyncMessage {
+    pub header: Option<FrameHeader>,
+    /// asks the gateway for an announcement, `time` is unused
+    pub request: bool,
+    /// ms since the unix epoch when the gateway sent it
+    pub time: u64,
+    /// ms the frame spent on the air and in relays before the last hop
+    pub delay: u32,
+}
+
+impl TimeSyncMessage {
+    pub fn new(time: u64) -> Self {
+        TimeSyncMessage { header: None, request: false, time, delay: 0 }
+    }
+
+    pub fn request() -> Self {
+        TimeSyncMessage { header: None, request: true, time: 0, delay: 0 }
+    }
+}
+
+impl ToFromFrame for TimeSyncMessage {
+    fn from_frame(f: &mut Frame) -> io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < TIMESYNC_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "time sync payload is too short"));
+        }
+        let mut time = [0u8; 8];
+        time.copy_from_slice(&data[1..9]);
+        Ok(Box::new(TimeSyncMessage {
+            header: Some(f.header()),
+            request: data[0] & TIMESYNC_REQUEST != 0,
+            time: u64::from_be_bytes(time),
+            delay: u32::from_be_bytes([data[9], data[10], data[11], data[12]]),
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = Vec::with_capacity(TIMESYNC_PAYLOAD_LEN);
+        data.push(if self.request { TIMESYNC_REQUEST } else { 0 });
+        data.extend_from_slice(&self.time.to_be_bytes());
+        data.extend_from_slice(&self.delay.to_be_bytes());
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::TimeSync)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn timesync_tofrom_frame() {
+    let mut msg = TimeSyncMessage::new(1_600_000_000_123);
+    msg.delay = 2500;
+    let mut frame = Frame::from_bytes(&msg.to_frame(4, 1, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::TimeSync);
+    let received = TimeSyncMessage::from_frame(&mut frame).unwrap();
+    assert_eq!((received.request, received.time, received.delay), (false, 1_600_000_000_123, 2500));
+
+    let mut frame = Frame::from_bytes(&TimeSyncMessage::request().to_frame(5, 3, vec![1]).unwrap().to_bytes()).unwrap();
+    assert!(TimeSyncMessage::from_frame(&mut frame).unwrap().request);
+}
@@ -0,0 +1,307 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, FrameHeader, NodeId, ToFromFrame};
+use crate::stack::message::MessageType;
+
+/// written in place of the RSSI when the radio did not report one
+pub const RSSI_UNKNOWN: i16 = i16::MIN;
+
+/// bytes per recorded hop: node ID, RSSI and timestamp
+const TRACE_HOP_LEN: usize = 11;
+
+/// bytes in front of the hops: destination, TTL or completion flag,
+/// sequence and timestamp
+const TRACE_HEADER_LEN: usize = 12;
+
+/// A node a traceroute passed through
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceHop {
+    pub nodeid: NodeId,
+    /// signal strength (dBm) the node heard the previous hop with
+    pub rssi: Option<i16>,
+    /// node clock when it handled the traceroute, in ms since the unix epoch
+    pub timestamp: u64,
+}
+
+/// Records the path to a node, each forwarding node appends itself
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct TracerouteMessage {
+    pub header: Option<FrameHeader>,
+    /// matches the reply to the request
+    pub sequence: u16,
+    /// node being traced
+    pub dest: NodeId,
+    /// hops that may still be recorded
+    pub ttl: u8,
+    /// origin clock when the traceroute was sent
+    pub timestamp: u64,
+    pub hops: Vec<TraceHop>,
+}
+
+/// Path recorded by a traceroute, sent back to its origin
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize, serde::Deserialize))]
+pub struct TracerouteReplyMessage {
+    pub header: Option<FrameHeader>,
+    pub sequence: u16,
+    pub dest: NodeId,
+    /// origin clock when the traceroute was sent, echoed back
+    pub timestamp: u64,
+    /// false if the TTL ran out before reaching the destination
+    pub complete: bool,
+    pub hops: Vec<TraceHop>,
+}
+
+/// What a node does with a traceroute it received
+pub enum TraceAction {
+    /// the frame is not addressed to us
+    Ignore,
+    /// pass the request on to the next hop
+    Forward(Frame),
+    /// send the recorded path back to the origin along `route`
+    Reply { reply: TracerouteReplyMessage, route: Vec<u8> },
+}
+
+/// Outcome of a traceroute, as seen by its origin
+#[derive(Clone, Debug, PartialEq)]
+pub struct TracerouteResult {
+    pub dest: NodeId,
+    /// false if the hop limit was reached before the destination
+    pub complete: bool,
+    pub hops: Vec<TraceHop>,
+    /// time between each hop and the one before it, the first hop is
+    /// measured from the origin
+    /* Relies on node clocks being in sync, a clock behind the previous
+    hop shows up as zero. */
+    pub hop_latency: Vec<Duration>,
+    /// round trip time measured by the origin
+    pub rtt: Duration,
+}
+
+impl TracerouteMessage {
+    pub fn new(sequence: u16, dest: NodeId, ttl: u8, timestamp: u64) -> Self {
+        TracerouteMessage { header: None, sequence, dest, ttl, timestamp, hops: Vec::new() }
+    }
+
+    /// IDs of the nodes recorded so far
+    pub fn path_so_far(&self) -> Vec<NodeId> {
+        self.hops.iter().map(|hop| hop.nodeid).collect()
+    }
+
+    /// timestamps of the nodes recorded so far
+    pub fn timestamps(&self) -> Vec<u64> {
+        self.hops.iter().map(|hop| hop.timestamp).collect()
+    }
+}
+
+impl TracerouteResult {
+    pub fn new(reply: &TracerouteReplyMessage, rtt: Duration) -> Self {
+        let mut last = reply.timestamp;
+        let hop_latency = reply.hops.iter().map(|hop| {
+            let latency = Duration::from_millis(hop.timestamp.saturating_sub(last));
+            last = hop.timestamp;
+            latency
+        }).collect();
+        TracerouteResult {
+            dest: reply.dest,
+            complete: reply.complete,
+            hops: reply.hops.clone(),
+            hop_latency,
+            rtt,
+        }
+    }
+
+    /// IDs of the nodes on the path, in order
+    pub fn path(&self) -> Vec<NodeId> {
+        self.hops.iter().map(|hop| hop.nodeid).collect()
+    }
+}
+
+fn write_hops(data: &mut Vec<u8>, hops: &[TraceHop]) {
+    data.push(hops.len() as u8);
+    hops.iter().for_each(|hop| {
+        data.push(hop.nodeid);
+        data.extend_from_slice(&hop.rssi.unwrap_or(RSSI_UNKNOWN).to_be_bytes());
+        data.extend_from_slice(&hop.timestamp.to_be_bytes());
+    });
+}
+
+fn read_hops(data: &[u8]) -> io::Result<Vec<TraceHop>> {
+    let count = *data.get(0).ok_or(Error::new(ErrorKind::InvalidData, "traceroute is missing its hop count"))? as usize;
+    let hops = &data[1..];
+    if hops.len() < count * TRACE_HOP_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "traceroute is missing hops"));
+    }
+    Ok(hops.chunks(TRACE_HOP_LEN).take(count).map(|hop| {
+        let rssi = i16::from_be_bytes([hop[1], hop[2]]);
+        TraceHop {
+            nodeid: hop[0],
+            rssi: if rssi == RSSI_UNKNOWN { None } else { Some(rssi) },
+            timestamp: read_u64(&hop[3..]),
+        }
+    }).collect())
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+impl ToFromFrame for TracerouteMessage {
+    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < TRACE_HEADER_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "traceroute payload is too short"));
+        }
+        Ok(Box::new(TracerouteMessage {
+            header: Some(f.header()),
+            dest: data[0],
+            ttl: data[1],
+            sequence: u16::from_be_bytes([data[2], data[3]]),
+            timestamp: read_u64(&data[4..]),
+            hops: read_hops(&data[TRACE_HEADER_LEN..])?,
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = vec![self.dest, self.ttl];
+        data.extend_from_slice(&self.sequence.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        write_hops(&mut data, &self.hops);
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::Traceroute)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+impl ToFromFrame for TracerouteReplyMessage {
+    fn from_frame(f: &mut Frame) -> std::io::Result<Box<Self>> {
+        let data = f.payload();
+        if data.len() < TRACE_HEADER_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "traceroute reply payload is too short"));
+        }
+        Ok(Box::new(TracerouteReplyMessage {
+            header: Some(f.header()),
+            dest: data[0],
+            complete: data[1] != 0,
+            sequence: u16::from_be_bytes([data[2], data[3]]),
+            timestamp: read_u64(&data[4..]),
+            hops: read_hops(&data[TRACE_HEADER_LEN..])?,
+        }))
+    }
+
+    fn to_frame(&self, frameid: u8, sender: u8, route: Vec<u8>) -> io::Result<Frame> {
+        let mut data = vec![self.dest, self.complete as u8];
+        data.extend_from_slice(&self.sequence.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        write_hops(&mut data, &self.hops);
+
+        FrameBuilder::new()
+            .frameid(frameid)
+            .message_type(MessageType::TracerouteReply)
+            .sender(sender)
+            .route(&route)
+            .payload(data)
+            .build()
+            .map_err(Into::into)
+    }
+}
+
+/// Handle a traceroute frame heard by `nodeid` at time `now` (ms since the unix epoch)
+/* The frame route holds the hops still ahead of the request. The node it
+is addressed to records itself, along with the RSSI it heard the frame at,
+then either forwards it or answers the origin if it is the destination or
+the TTL ran out. */
+pub fn trace_hop(frame: &mut Frame, nodeid: NodeId, rssi: Option<i16>, now: u64) -> io::Result<TraceAction> {
+    match frame.route_shift() {
+        Some(nexthop) if nexthop == nodeid => {},
+        _ => return Ok(TraceAction::Ignore),
+    }
+    let mut msg = TracerouteMessage::from_frame(frame)?;
+    msg.hops.push(TraceHop { nodeid, rssi, timestamp: now });
+    msg.ttl = msg.ttl.saturating_sub(1);
+
+    let route = frame.route();
+    if route.len() > 0 && msg.ttl > 0 {
+        return Ok(TraceAction::Forward(msg.to_frame(frame.frameid(), frame.sender(), route)?));
+    }
+
+    // back the way it came, ending at the origin
+    let mut route: Vec<u8> = msg.hops.iter().rev().skip(1).map(|hop| hop.nodeid).collect();
+    route.push(frame.sender());
+    let reply = TracerouteReplyMessage {
+        header: None,
+        sequence: msg.sequence,
+        dest: msg.dest,
+        timestamp: msg.timestamp,
+        complete: nodeid == msg.dest,
+        hops: msg.hops,
+    };
+    Ok(TraceAction::Reply { reply, route })
+}
+
+#[cfg(test)]
+#[test]
+fn traceroute_chain() {
+    // 1 - 2 - 3 - 4, each node only hears its neighbors
+    let rssi = |id: u8| Some(-60 - id as i16);
+    let time = |id: u8| 1_000_000 + id as u64 * 1500;
+    let mut request = TracerouteMessage::new(42, 4, 8, time(1)).to_frame(7, 1, vec![2, 3, 4]).unwrap();
+    let mut bytes = request.to_bytes();
+
+    let mut reply = None;
+    for id in 2..=4u8 {
+        // the previous hop's transmission is also heard by the node before it
+        let mut overheard = Frame::from_bytes(&bytes).unwrap();
+        assert!(match trace_hop(&mut overheard, id - 1, None, 0).unwrap() { TraceAction::Ignore => true, _ => false });
+
+        let mut frame = Frame::from_bytes(&bytes).unwrap();
+        match trace_hop(&mut frame, id, rssi(id), time(id)).unwrap() {
+            TraceAction::Forward(mut next) => bytes = next.to_bytes(),
+            TraceAction::Reply { reply: r, route } => reply = Some((r, route)),
+            TraceAction::Ignore => panic!("node {} ignored the traceroute", id),
+        }
+    }
+
+    let (reply, route) = reply.expect("destination did not reply");
+    assert_eq!(route, vec![3, 2, 1]);
+    assert!(reply.complete);
+    assert_eq!(reply.sequence, 42);
+    let path: Vec<TraceHop> = (2..=4u8).map(|id| TraceHop { nodeid: id, rssi: rssi(id), timestamp: time(id) }).collect();
+    assert_eq!(reply.hops, path);
+
+    let mut frame = Frame::from_bytes(&reply.to_frame(1, 4, route).unwrap().to_bytes()).unwrap();
+    assert_eq!(frame.msgtype(), MessageType::TracerouteReply);
+    let reply = TracerouteReplyMessage::from_frame(&mut frame).unwrap();
+    assert_eq!(reply.hops, path);
+
+    let result = TracerouteResult::new(&reply, Duration::from_secs(9));
+    assert_eq!(result.path(), vec![2, 3, 4]);
+    assert_eq!(result.hop_latency, vec![Duration::from_millis(1500); 3]);
+
+    // a TTL of two stops at node 3, which answers with the partial path
+    let mut frame = TracerouteMessage::new(43, 4, 2, 0).to_frame(8, 1, vec![2, 3, 4]).unwrap();
+    let mut frame = match trace_hop(&mut frame, 2, None, 0).unwrap() {
+        TraceAction::Forward(next) => next,
+        _ => panic!("node 2 should forward"),
+    };
+    match trace_hop(&mut frame, 3, None, 0).unwrap() {
+        TraceAction::Reply { reply, route } => {
+            assert!(!reply.complete);
+            assert_eq!(reply.hops.len(), 2);
+            assert_eq!(route, vec![2, 1]);
+        },
+        _ => panic!("node 3 should reply"),
+    }
+}
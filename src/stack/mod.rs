@@ -1,15 +1,156 @@
+pub(crate) mod ack;
+pub use ack::{AckCoalescer, AckTracker, MAX_COALESCED_ACKS};
+
+pub(crate) mod adr;
+pub use adr::{required_snr, AdaptiveDataRate, AdrEvent, ADR_LOWER_AFTER, ADR_RAISE_AFTER};
+
+pub(crate) mod antireplay;
+pub use antireplay::{AntiReplayWindow, ANTI_REPLAY_WINDOW};
+
+pub(crate) mod bandwidth_probe;
+pub use bandwidth_probe::{probe_echo, BandwidthProbe, BandwidthReport, BANDWIDTH_PROBE_GRACE, BANDWIDTH_PROBE_PORT};
+
+#[cfg(feature = "serde-support")]
+pub(crate) mod base64_serde;
+
 pub(crate) mod chunk;
 
+pub(crate) mod collision;
+pub use collision::{derive_node_id, CollisionDetector, CollisionEvent};
+
+pub(crate) mod compression;
+pub use compression::{CompressionType, MeshCompressor, MAX_DECOMPRESSED_LEN};
+
+pub(crate) mod config;
+pub use config::{config_nonce, parse_config_key, sign_config, ConfigGuard, ConfigKey};
+
+pub(crate) mod dedup;
+pub use dedup::DedupCache;
+
+pub(crate) mod delivery;
+pub use delivery::{DeliveryEvent, DeliveryTracker, HopFailure, QueuedPayload};
+
+pub(crate) mod dns;
+pub use dns::{dns_query, dns_response_packet, fit_response, DnsCache, DnsClient, DnsProxy, DnsUpstream, UdpUpstream, DNS_PORT, RESOLV_CONF};
+
+pub(crate) mod dsr;
+pub use dsr::{DsrAction, DsrRouter, RouteCache};
+
+pub(crate) mod dutycycle;
+pub use dutycycle::{DutyCycle, DutyState};
+
+pub(crate) mod emergency;
+pub use emergency::{should_relay, Beacon, EmergencyAllowance, EmergencyEvent, EmergencyTable};
+
+pub(crate) mod endpoint;
+pub use endpoint::DatagramEndpoint;
+
+pub(crate) mod filter;
+pub use filter::{FilterAction, FilterDirection, FilterProtocol, FilterRule, PacketFilter, DEFAULT_TUN_FILTER};
+
+pub(crate) mod flood;
+pub use flood::{FloodControl, FloodDelay, FLOOD_RSSI_FAR, FLOOD_RSSI_JITTER, FLOOD_RSSI_NEAR};
+
+pub(crate) mod frameid;
+pub use frameid::FrameIdAllocator;
+
+pub(crate) mod filetransfer;
+pub use filetransfer::{FileEvent, FileTransfers};
+
+pub(crate) mod forward;
+pub use forward::{route_step, overheard_forward, LoopGuard, RouteStep};
+
 pub(crate) mod frame;
-pub(crate) use frame::*;
+pub use frame::*;
+
+pub(crate) mod gateways;
+pub use gateways::{hop_cost, DefaultGateway, GatewayBacklog, GatewayEntry, GatewayTable, MAX_GATEWAY_BACKLOG};
+
+pub(crate) mod headercomp;
+pub use headercomp::{HeaderCompression, HEADER_REFRESH, MAX_HEADER_CONTEXTS};
+
+pub(crate) mod health;
+pub use health::TelemetryTable;
+
+pub(crate) mod icmp;
+pub use icmp::{echo_reply, is_icmp, IpTraffic};
+
+pub(crate) mod ipv6;
+pub use ipv6::{classify_ipv6, icmpv6_packet_too_big, ipv6_node_addr, neighbor_advertisement, IpPacket, Ipv6Packet, Ipv6Traffic, IPV6_HEADER_LEN, IPV6_MIN_MTU};
+
+pub(crate) mod limiter;
+pub use limiter::KeyedLimiter;
+
+pub(crate) mod lease;
+pub use lease::{Lease, LeaseTable};
+
+pub(crate) mod linkstate;
+pub use linkstate::{LinkEstimator, LinkStateDb, NeighborLink};
+
+pub(crate) mod location;
+pub use location::{PositionTable, PositionTracker};
+
+pub(crate) mod mesh_state;
+pub use mesh_state::{MeshState, MESH_STATE_VERSION, STALE_ROUTE_TTL};
 
 pub(crate) mod message;
-pub(crate) use message::*;
+pub use message::*;
+
+pub(crate) mod mtu;
+pub use mtu::{chunk_overhead, dont_fragment, fragment_ipv4, frames_per_packet, icmp_frag_needed, MIN_TUN_MTU};
+
+pub(crate) mod neighbors;
+pub use neighbors::{NeighborEvent, NeighborStats, NeighborTable};
+
+pub(crate) mod nodeinfo;
+pub use nodeinfo::{mismatches, NodeHeartbeat, NodeInfoTable};
+
+pub(crate) mod padding;
+pub use padding::{pad_payload, unpad_payload, PaddingStats, PADDING_HEADER_LEN};
+
+pub(crate) mod nat;
+pub use nat::{masquerade_conflicts, parse_subnet, Masquerade, NatRule, NAT_TABLE};
+
+pub(crate) mod partition;
+pub use partition::{PartitionDetector, PartitionEvent, PARTITION_MIN_NODES, PARTITION_SPEEDUP};
+
+pub(crate) mod pinger;
+pub use pinger::{PingSession, PingSummary};
+
+pub(crate) mod qos;
+pub use qos::{TxPriority, TxQueueSender};
+
+pub(crate) mod remote;
+pub use remote::{remote_get, remote_set, RemoteGuard, ReplayWindow, REMOTE_SETTINGS};
+
+pub(crate) mod resolve;
+pub use resolve::{relay_query, resolvable, AddressResolver, Held, ADDRESS_QUERIES};
+
+pub(crate) mod reservation;
+pub use reservation::Reservations;
+
+pub(crate) mod routes;
+pub use routes::{parse_static_routes, RouteEntry, RouteSource, RoutingTable};
 
 pub(crate) mod router;
-pub(crate) use router::MeshRouter;
+pub use router::{MeshRouter, RoutingMode};
+
+pub(crate) mod scheduler;
+pub use scheduler::{PeriodicScheduler, PeriodicTask};
+
+pub(crate) mod staticip;
+pub use staticip::{assignment, in_subnet, parse_ip_routes, AddressClaims, Assignment};
+
+pub(crate) mod store;
+pub use store::{holdable, HeldMessage, MessageStore};
+
+pub(crate) mod timesync;
+pub use timesync::{airtime, sync_offset, MeshClock, CLOCK_STEP_THRESHOLD};
+
+pub(crate) mod topology;
+pub use topology::{GraphEdge, GraphNode, MeshGraph, TopologyReport};
 
 pub(crate) mod tun;
-pub(crate) use tun::NetworkTunnel;
+pub use tun::NetworkTunnel;
 
 pub(crate) mod util;
\ No newline at end of file
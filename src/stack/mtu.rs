@@ -0,0 +1,178 @@
+use std::net::Ipv4Addr;
+use crate::stack::frame::{FRAME_HEADER_LEN, FRAME_SEAL_LEN};
+
+/// Smallest MTU an IPv4 link may have
+pub const MIN_TUN_MTU: usize = 68;
+
+/// IPv4 flag asking routers not to fragment the packet
+const IPV4_DONT_FRAGMENT: u16 = 0x4000;
+/// IPv4 flag for fragments that are not the last one
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+
+/// Bytes of every chunk that are not payload, at worst
+/* The fixed header, a route of `maxhops` nodes and what encrypting and
+signing the frame adds. What is left of a frame after these is the most
+a chunk may carry. */
+pub fn chunk_overhead(maxhops: u8) -> usize {
+    FRAME_HEADER_LEN + maxhops as usize + FRAME_SEAL_LEN
+}
+
+/// Number of frames an IP packet of `mtu` bytes is sent in
+pub fn frames_per_packet(mtu: usize, maxpacketsize: usize) -> usize {
+    mtu.div_ceil(maxpacketsize)
+}
+
+/// true if an IPv4 packet may not be fragmented
+pub fn dont_fragment(packet: &[u8]) -> bool {
+    packet.len() >= 20 && u16::from_be_bytes([packet[6], packet[7]]) & IPV4_DONT_FRAGMENT != 0
+}
+
+/// Split an IPv4 packet into fragments of at most `mtu` bytes
+/* Every fragment carries the whole header, options included. A packet
+that fits, or is not IPv4, is returned as it is. */
+pub fn fragment_ipv4(packet: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+    let ihl = packet.first().map_or(0, |byte| (byte & 0x0f) as usize * 4);
+    if packet.len() <= mtu || ihl < 20 || packet.len() < ihl || packet[0] >> 4 != 4 {
+        return vec![packet.to_vec()];
+    }
+    let (header, payload) = packet.split_at(ihl);
+    let flags = u16::from_be_bytes([header[6], header[7]]);
+    let offset = (flags & 0x1fff) as usize;
+    // fragment offsets count in 8 byte blocks
+    let step = (mtu - ihl) / 8 * 8;
+    payload.chunks(step).enumerate().map(|(i, part)| {
+        let last = (i + 1) * step >= payload.len();
+        let more = if last { flags & IPV4_MORE_FRAGMENTS } else { IPV4_MORE_FRAGMENTS };
+        let mut fragment = header.to_vec();
+        fragment.extend_from_slice(part);
+        let total = fragment.len() as u16;
+        fragment[2..4].copy_from_slice(&total.to_be_bytes());
+        fragment[6..8].copy_from_slice(&(more | (offset + i * step / 8) as u16).to_be_bytes());
+        set_checksum(&mut fragment[..ihl], 10);
+        fragment
+    }).collect()
+}
+
+/// ICMP Fragmentation Needed from `source`, telling the sender of `packet` to keep to `mtu`
+pub fn icmp_frag_needed(packet: &[u8], mtu: usize, source: Ipv4Addr) -> Vec<u8> {
+    let ihl = packet.first().map_or(20, |byte| (byte & 0x0f) as usize * 4);
+    // the original header and the first 8 bytes after it
+    let quoted = &packet[..packet.len().min(ihl + 8)];
+    let mut icmp = vec![3, 4, 0, 0, 0, 0];
+    icmp.extend_from_slice(&(mtu as u16).to_be_bytes());
+    icmp.extend_from_slice(quoted);
+    set_checksum(&mut icmp, 2);
+
+    let mut reply = vec![0x45, 0];
+    reply.extend_from_slice(&((20 + icmp.len()) as u16).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0, 64, 1, 0, 0]);
+    reply.extend_from_slice(&source.octets());
+    reply.extend_from_slice(packet.get(12..16).unwrap_or(&[0; 4]));
+    set_checksum(&mut reply, 10);
+    reply.extend(icmp);
+    reply
+}
+
+/// Internet checksum of `data`, written at `at` where it is left out
+pub(crate) fn set_checksum(data: &mut [u8], at: usize) {
+    data[at] = 0;
+    data[at + 1] = 0;
+    let mut sum: u32 = data.chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    data[at..at + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+#[cfg(test)]
+#[test]
+fn mtu_udp_through_mesh() {
+    use packet::ip::v4::Packet;
+    use crate::stack::Frame;
+    use crate::stack::frame::{parse_frame_key, recombine_chunks, ToFromFrame, MAX_FRAME_LEN};
+    use crate::stack::message::{IPPacketMessage, MessageType};
+    use crate::stack::qos::{tx_queue, TxPriority};
+
+    // a UDP datagram filling the whole MTU, don't fragment set as Linux does
+    let udp = |len: usize, df: bool| {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&(len as u16).to_be_bytes());
+        packet.extend_from_slice(&[0x12, 0x34]);
+        packet.extend_from_slice(&(if df { IPV4_DONT_FRAGMENT } else { 0 }).to_be_bytes());
+        packet.extend_from_slice(&[64, 17, 0, 0, 10, 107, 1, 3, 10, 107, 1, 9]);
+        set_checksum(&mut packet, 10);
+        packet.extend_from_slice(&[0x30, 0x39, 0x00, 0x35]);
+        packet.extend_from_slice(&((len - 20) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend((0..len - 28).map(|i| i as u8));
+        packet
+    };
+    let checksum_ok = |data: &[u8]| {
+        let mut copy = data.to_vec();
+        let at = if data[0] == 0x45 { 10 } else { 2 };
+        set_checksum(&mut copy, at);
+        copy == data
+    };
+    let key = parse_frame_key(&"5a".repeat(32)).unwrap();
+    let (maxhops, maxpacketsize) = (4u8, 200usize);
+    assert!(chunk_overhead(maxhops) + maxpacketsize <= MAX_FRAME_LEN);
+
+    for mtu in [MIN_TUN_MTU, 576, 1280, 1500].iter().cloned() {
+        // node 1 sends over the longest route, padding, encrypting and signing every chunk on its way to the radio
+        let datagram = udp(mtu, true);
+        let route: Vec<u8> = (2..2 + maxhops).collect();
+        let mut frame = IPPacketMessage::new(Packet::new(datagram.clone()).unwrap()).to_frame(9, 1, route).unwrap();
+        let (sender, receiver) = tx_queue(64);
+        let sender = sender.with_frame_key(Some(key)).with_encryption(0, MAX_FRAME_LEN);
+        for chunk in frame.chunked(&maxpacketsize) {
+            sender.send(chunk, TxPriority::Low).unwrap();
+        }
+        let mut chunks = Vec::new();
+        while let Ok(bytes) = receiver.try_recv() {
+            assert_eq!(bytes.len(), MAX_FRAME_LEN, "chunk for MTU {}", mtu);
+            chunks.push(bytes);
+        }
+        assert_eq!(chunks.len(), frames_per_packet(mtu, maxpacketsize));
+
+        // node 2 checks each chunk and puts the datagram back together
+        let mut frames: Vec<Frame> = chunks.iter().map(|bytes| {
+            let frame = Frame::from_bytes(bytes).unwrap();
+            frame.verify(&key).unwrap();
+            frame.without_signature().decrypt(&key).unwrap()
+        }).collect();
+        let header = frames.last_mut().unwrap().header();
+        let mut whole = recombine_chunks(frames, header);
+        assert_eq!(whole.msgtype(), MessageType::IPPacket);
+        let received = IPPacketMessage::from_frame(&mut whole).unwrap().packet();
+        assert_eq!(received.as_ref(), &datagram[..]);
+    }
+
+    // too big for the tunnel with don't fragment set, the sender hears why
+    let big = udp(1500, true);
+    assert!(dont_fragment(&big));
+    let icmp = icmp_frag_needed(&big, 576, Ipv4Addr::new(10, 107, 1, 9));
+    let reply = Packet::new(icmp.clone()).unwrap();
+    assert_eq!((reply.source(), reply.destination()), (Ipv4Addr::new(10, 107, 1, 9), Ipv4Addr::new(10, 107, 1, 3)));
+    assert_eq!((icmp[9], icmp[20], icmp[21]), (1, 3, 4));
+    assert_eq!(u16::from_be_bytes([icmp[26], icmp[27]]), 576);
+    assert_eq!(&icmp[28..], &big[..28]);
+    assert!(checksum_ok(&icmp[..20]) && checksum_ok(&icmp[20..]));
+
+    // without it the packet goes in fragments that fit, which put back together are the datagram
+    let big = udp(1500, false);
+    let fragments = fragment_ipv4(&big, 576);
+    assert_eq!(fragments.len(), 3);
+    let mut payload = Vec::new();
+    for (i, fragment) in fragments.iter().enumerate() {
+        assert!(fragment.len() <= 576 && checksum_ok(&fragment[..20]));
+        assert_eq!(u16::from_be_bytes([fragment[2], fragment[3]]) as usize, fragment.len());
+        let flags = u16::from_be_bytes([fragment[6], fragment[7]]);
+        assert_eq!((flags & 0x1fff) as usize * 8, payload.len());
+        assert_eq!(flags & IPV4_MORE_FRAGMENTS != 0, i < 2);
+        payload.extend_from_slice(&fragment[20..]);
+    }
+    assert_eq!(&payload[..], &big[20..]);
+    assert_eq!(fragment_ipv4(&big, 1500), vec![big.clone()]);
+}
@@ -0,0 +1,547 @@
+use log::*;
+use netlink_sys::{protocols::NETLINK_NETFILTER, Socket, SocketAddr};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+
+/// nftables table our rules live in, removed as a whole on shutdown
+pub const NAT_TABLE: &str = "loramesh";
+const NAT_CHAIN: &str = "postrouting";
+/// room for the replies to a dump of the ruleset
+const NLMSG_BUFFER_LEN: usize = 65536;
+
+const IP_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+
+/// Parse an IPv4 subnet such as 172.16.0.0/24
+pub fn parse_subnet(subnet: &str) -> io::Result<(Ipv4Addr, u8)> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid subnet {}, expected address/prefix", subnet));
+    let mut parts = subnet.splitn(2, '/');
+    let addr: Ipv4Addr = parts.next().and_then(|addr| addr.parse().ok()).ok_or_else(invalid)?;
+    let len: u8 = parts.next().and_then(|len| len.parse().ok()).filter(|len| *len <= 32).ok_or_else(invalid)?;
+    Ok((addr, len))
+}
+
+/// true if the two subnets share any address
+pub(crate) fn overlaps(a: (Ipv4Addr, u8), b: (Ipv4Addr, u8)) -> bool {
+    let len = a.1.min(b.1) as u32;
+    let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+    u32::from(a.0) & mask == u32::from(b.0) & mask
+}
+
+/// A masquerade or SNAT rule as far as clashes with ours go
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NatRule {
+    pub family: String,
+    pub table: String,
+    pub chain: String,
+    pub handle: u64,
+    /// the source prefix it matches, None for any
+    pub saddr: Option<(Ipv4Addr, u8)>,
+    /// the output interface it matches, None for any
+    pub oifname: Option<String>,
+    /// true if it masquerades or does SNAT
+    pub translates: bool,
+}
+
+/// Rules that already translate traffic from `subnet` out of `uplink`
+/* A masquerade or SNAT rule clashes with ours unless it matches another
+source or another output interface. Ours, left over from a crash, is not
+counted. Rules made with iptables-legacy are not in nftables and are not
+seen here. */
+pub fn masquerade_conflicts(rules: &[NatRule], subnet: (Ipv4Addr, u8), uplink: &str) -> Vec<String> {
+    rules.iter()
+        .filter(|rule| rule.table != NAT_TABLE && rule.translates)
+        .filter(|rule| rule.saddr.iter().all(|theirs| overlaps(*theirs, subnet)))
+        .filter(|rule| rule.oifname.iter().all(|oif| oif == uplink))
+        .map(|rule| format!("{} {} chain {} handle {}", rule.family, rule.table, rule.chain, rule.handle))
+        .collect()
+}
+
+// from linux/netlink.h, linux/netfilter.h, linux/netfilter/nfnetlink.h and nf_tables.h
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_APPEND: u16 = 0x800;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+const NLA_F_NESTED: u16 = 0x8000;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFT_MSG_GETRULE: u16 = 7;
+const NFPROTO_UNSPEC: u8 = 0;
+const NFPROTO_IPV4: u8 = 2;
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_HANDLE: u16 = 3;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16 = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16 = 5;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_NAT_TYPE: u16 = 1;
+const NFT_REG_1: u32 = 1;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_NAT_SNAT: u32 = 0;
+const NF_INET_POST_ROUTING: u32 = 4;
+const NF_ACCEPT: u32 = 1;
+/// srcnat, where masquerading goes
+const NF_IP_PRI_NAT_SRC: i32 = 100;
+/// offset of the source address in an IPv4 header
+const IPV4_SADDR_OFFSET: u32 = 12;
+const IFNAMSIZ: usize = 16;
+
+/// Round a netlink length up to the 4 byte alignment
+fn nlalign(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A netlink attribute, padded
+fn attr(kind: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(nlalign(4 + data.len()));
+    buf.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(nlalign(buf.len()), 0);
+    buf
+}
+
+/// A NUL terminated string attribute
+fn attr_str(kind: u16, value: &str) -> Vec<u8> {
+    let mut data = value.as_bytes().to_vec();
+    data.push(0);
+    attr(kind, &data)
+}
+
+/// A u32 attribute, nf_tables wants them big endian
+fn attr_u32(kind: u16, value: u32) -> Vec<u8> {
+    attr(kind, &value.to_be_bytes())
+}
+
+/// An attribute holding other attributes
+fn attr_nested(kind: u16, attrs: &[Vec<u8>]) -> Vec<u8> {
+    attr(kind | NLA_F_NESTED, &attrs.concat())
+}
+
+/// A netlink message with an nfgenmsg header, `kind` includes the subsystem
+fn nlmsg(kind: u16, flags: u16, seq: u32, family: u8, resid: u16, attrs: &[Vec<u8>]) -> Vec<u8> {
+    let body = attrs.concat();
+    let len = NLMSG_HDRLEN + 4 + body.len();
+    let mut buf = Vec::with_capacity(len);
+    buf.extend_from_slice(&(len as u32).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(&(NLM_F_REQUEST | flags).to_ne_bytes());
+    buf.extend_from_slice(&seq.to_ne_bytes());
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    buf.push(family);
+    buf.push(0);
+    buf.extend_from_slice(&resid.to_be_bytes());
+    buf.extend_from_slice(&body);
+    buf
+}
+
+/// An nf_tables message asking for an ack, as it goes in a batch
+fn nft_msg(kind: u16, flags: u16, seq: u32, attrs: &[Vec<u8>]) -> Vec<u8> {
+    nlmsg(NFNL_SUBSYS_NFTABLES << 8 | kind, flags | NLM_F_ACK, seq, NFPROTO_IPV4, 0, attrs)
+}
+
+/// An nf_tables expression of a rule
+fn expr(name: &str, data: &[Vec<u8>]) -> Vec<u8> {
+    attr_nested(NFTA_LIST_ELEM, &[attr_str(NFTA_EXPR_NAME, name), attr_nested(NFTA_EXPR_DATA, data)])
+}
+
+/// Compare register 1 with `value`
+fn expr_cmp_eq(value: &[u8]) -> Vec<u8> {
+    expr("cmp", &[
+        attr_u32(NFTA_CMP_SREG, NFT_REG_1),
+        attr_u32(NFTA_CMP_OP, NFT_CMP_EQ),
+        attr_nested(NFTA_CMP_DATA, &[attr(NFTA_DATA_VALUE, value)]),
+    ])
+}
+
+/// The messages that create our table, its postrouting chain and the rule masquerading `subnet` out of `uplink`
+/* The same as `ip saddr <subnet> oifname "<uplink>" masquerade` in a
+chain of type nat hooked at postrouting with priority srcnat. */
+fn masquerade_messages(subnet: (Ipv4Addr, u8), uplink: &str) -> Vec<Vec<u8>> {
+    let mask = u32::MAX.checked_shl(32 - subnet.1 as u32).unwrap_or(0);
+    let mut ifname = [0u8; IFNAMSIZ];
+    ifname[..uplink.len()].copy_from_slice(uplink.as_bytes());
+
+    let mut exprs = vec![
+        expr("meta", &[attr_u32(NFTA_META_DREG, NFT_REG_1), attr_u32(NFTA_META_KEY, NFT_META_OIFNAME)]),
+        expr_cmp_eq(&ifname),
+        expr("payload", &[
+            attr_u32(NFTA_PAYLOAD_DREG, NFT_REG_1),
+            attr_u32(NFTA_PAYLOAD_BASE, NFT_PAYLOAD_NETWORK_HEADER),
+            attr_u32(NFTA_PAYLOAD_OFFSET, IPV4_SADDR_OFFSET),
+            attr_u32(NFTA_PAYLOAD_LEN, 4),
+        ]),
+    ];
+    if mask != u32::MAX {
+        exprs.push(expr("bitwise", &[
+            attr_u32(NFTA_BITWISE_SREG, NFT_REG_1),
+            attr_u32(NFTA_BITWISE_DREG, NFT_REG_1),
+            attr_u32(NFTA_BITWISE_LEN, 4),
+            attr_nested(NFTA_BITWISE_MASK, &[attr(NFTA_DATA_VALUE, &mask.to_be_bytes())]),
+            attr_nested(NFTA_BITWISE_XOR, &[attr(NFTA_DATA_VALUE, &[0; 4])]),
+        ]));
+    }
+    exprs.push(expr_cmp_eq(&(u32::from(subnet.0) & mask).to_be_bytes()));
+    exprs.push(expr("masq", &[]));
+
+    vec![
+        nft_msg(NFT_MSG_NEWTABLE, NLM_F_CREATE, 1, &[attr_str(NFTA_TABLE_NAME, NAT_TABLE)]),
+        nft_msg(NFT_MSG_NEWCHAIN, NLM_F_CREATE, 2, &[
+            attr_str(NFTA_CHAIN_TABLE, NAT_TABLE),
+            attr_str(NFTA_CHAIN_NAME, NAT_CHAIN),
+            attr_nested(NFTA_CHAIN_HOOK, &[
+                attr_u32(NFTA_HOOK_HOOKNUM, NF_INET_POST_ROUTING),
+                attr(NFTA_HOOK_PRIORITY, &NF_IP_PRI_NAT_SRC.to_be_bytes()),
+            ]),
+            attr_u32(NFTA_CHAIN_POLICY, NF_ACCEPT),
+            attr_str(NFTA_CHAIN_TYPE, "nat"),
+        ]),
+        nft_msg(NFT_MSG_NEWRULE, NLM_F_CREATE | NLM_F_APPEND, 3, &[
+            attr_str(NFTA_RULE_TABLE, NAT_TABLE),
+            attr_str(NFTA_RULE_CHAIN, NAT_CHAIN),
+            attr_nested(NFTA_RULE_EXPRESSIONS, &exprs),
+        ]),
+    ]
+}
+
+/// The message deleting our table and everything in it
+fn delete_table_message() -> Vec<u8> {
+    nft_msg(NFT_MSG_DELTABLE, 0, 1, &[attr_str(NFTA_TABLE_NAME, NAT_TABLE)])
+}
+
+/// Type and payload of each attribute in `data`
+fn parse_attrs(mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        if len < 4 || len > data.len() {
+            break;
+        }
+        attrs.push((u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK, &data[4..len]));
+        data = &data[nlalign(len).min(data.len())..];
+    }
+    attrs
+}
+
+fn find_attr<'a>(attrs: &[(u16, &'a [u8])], kind: u16) -> Option<&'a [u8]> {
+    attrs.iter().find(|(k, _)| *k == kind).map(|(_, data)| *data)
+}
+
+fn attr_as_u32(data: &[u8]) -> Option<u32> {
+    data.get(..4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn attr_as_str(data: &[u8]) -> String {
+    String::from_utf8_lossy(data.split(|b| *b == 0).next().unwrap_or_default()).into_owned()
+}
+
+/// What a register holds while the expressions of a rule are read
+#[derive(Clone, Copy)]
+enum Register {
+    OutputInterface,
+    /// the IPv4 source address, with the mask applied to it so far
+    SourceAddress(u32),
+}
+
+/// The rule in the payload of an NFT_MSG_NEWRULE message, as far as NAT goes
+/* Only the matches nft makes of `ip saddr` and `oifname` are read, a rule
+that matches the source some other way, with a set say, counts as
+matching any. */
+fn parse_rule(payload: &[u8]) -> Option<NatRule> {
+    let family = match payload.first()? {
+        1 => "inet",
+        2 => "ip",
+        3 => "arp",
+        5 => "netdev",
+        7 => "bridge",
+        10 => "ip6",
+        _ => "?",
+    };
+    let attrs = parse_attrs(payload.get(4..)?);
+    let mut rule = NatRule {
+        family: String::from(family),
+        table: attr_as_str(find_attr(&attrs, NFTA_RULE_TABLE)?),
+        chain: attr_as_str(find_attr(&attrs, NFTA_RULE_CHAIN)?),
+        handle: find_attr(&attrs, NFTA_RULE_HANDLE)
+            .and_then(|b| b.get(..8))
+            .map_or(0, |b| u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])),
+        ..NatRule::default()
+    };
+    let mut registers: HashMap<u32, Register> = HashMap::new();
+    let exprs = find_attr(&attrs, NFTA_RULE_EXPRESSIONS).unwrap_or_default();
+    for (_, elem) in parse_attrs(exprs).into_iter().filter(|(kind, _)| *kind == NFTA_LIST_ELEM) {
+        let elem = parse_attrs(elem);
+        let name = find_attr(&elem, NFTA_EXPR_NAME).map(attr_as_str).unwrap_or_default();
+        let data = parse_attrs(find_attr(&elem, NFTA_EXPR_DATA).unwrap_or_default());
+        let u32_of = |kind| find_attr(&data, kind).and_then(attr_as_u32);
+        let value_of = |kind| find_attr(&data, kind).and_then(|nested| find_attr(&parse_attrs(nested), NFTA_DATA_VALUE));
+        match name.as_str() {
+            "meta" | "payload" => {
+                let (dreg, loaded) = if name == "meta" {
+                    (u32_of(NFTA_META_DREG), Some(Register::OutputInterface).filter(|_| u32_of(NFTA_META_KEY) == Some(NFT_META_OIFNAME)))
+                } else {
+                    let field = (u32_of(NFTA_PAYLOAD_BASE), u32_of(NFTA_PAYLOAD_OFFSET), u32_of(NFTA_PAYLOAD_LEN));
+                    (u32_of(NFTA_PAYLOAD_DREG), Some(Register::SourceAddress(u32::MAX))
+                        .filter(|_| field == (Some(NFT_PAYLOAD_NETWORK_HEADER), Some(IPV4_SADDR_OFFSET), Some(4))))
+                };
+                match (dreg, loaded) {
+                    (Some(dreg), Some(loaded)) => { registers.insert(dreg, loaded); },
+                    (Some(dreg), None) => { registers.remove(&dreg); },
+                    (None, _) => {},
+                }
+            },
+            "bitwise" => {
+                let source = u32_of(NFTA_BITWISE_SREG).and_then(|sreg| registers.get(&sreg).copied());
+                let mask = value_of(NFTA_BITWISE_MASK).and_then(attr_as_u32);
+                if let (Some(Register::SourceAddress(applied)), Some(mask), Some(dreg)) = (source, mask, u32_of(NFTA_BITWISE_DREG)) {
+                    registers.insert(dreg, Register::SourceAddress(applied & mask));
+                }
+            },
+            "cmp" if u32_of(NFTA_CMP_OP) == Some(NFT_CMP_EQ) => {
+                let value = value_of(NFTA_CMP_DATA).unwrap_or_default();
+                match u32_of(NFTA_CMP_SREG).and_then(|sreg| registers.get(&sreg)) {
+                    Some(Register::OutputInterface) => rule.oifname = Some(attr_as_str(value)),
+                    Some(Register::SourceAddress(mask)) => if let Some(addr) = attr_as_u32(value) {
+                        rule.saddr = Some((Ipv4Addr::from(addr & mask), mask.count_ones() as u8));
+                    },
+                    None => {},
+                }
+            },
+            "masq" => rule.translates = true,
+            "nat" => rule.translates |= u32_of(NFTA_NAT_TYPE) == Some(NFT_NAT_SNAT),
+            _ => {},
+        }
+    }
+    Some(rule)
+}
+
+/// A netlink socket talking to nf_tables in the kernel
+fn nftables_socket() -> io::Result<Socket> {
+    let mut socket = Socket::new(NETLINK_NETFILTER)?;
+    socket.bind_auto()?;
+    socket.connect(&SocketAddr::new(0, 0))?;
+    Ok(socket)
+}
+
+/// Hand the type and payload of each message received to `handle` until it returns false
+fn read_replies<F: FnMut(u16, &[u8]) -> io::Result<bool>>(socket: &Socket, mut handle: F) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(NLMSG_BUFFER_LEN);
+    loop {
+        buf.clear();
+        socket.recv(&mut buf, 0)?;
+        let mut rest = &buf[..];
+        while rest.len() >= NLMSG_HDRLEN {
+            let len = u32::from_ne_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            if len < NLMSG_HDRLEN || len > rest.len() {
+                return Err(Error::new(ErrorKind::InvalidData, "truncated netlink message"));
+            }
+            if !handle(u16::from_ne_bytes([rest[4], rest[5]]), &rest[NLMSG_HDRLEN..len])? {
+                return Ok(());
+            }
+            rest = &rest[nlalign(len).min(rest.len())..];
+        }
+    }
+}
+
+/// The error in the payload of an NLMSG_ERROR message, Ok for an ack
+fn ack_result(payload: &[u8]) -> io::Result<()> {
+    match payload.get(..4).map(|b| i32::from_ne_bytes([b[0], b[1], b[2], b[3]])) {
+        Some(0) => Ok(()),
+        Some(errno) => Err(Error::from_raw_os_error(-errno)),
+        None => Err(Error::new(ErrorKind::InvalidData, "truncated netlink error")),
+    }
+}
+
+/// Send nf_tables messages as one batch, which the kernel applies whole or not at all
+fn transact(messages: &[Vec<u8>]) -> io::Result<()> {
+    let socket = nftables_socket()?;
+    let mut batch = nlmsg(NFNL_MSG_BATCH_BEGIN, 0, 0, NFPROTO_UNSPEC, NFNL_SUBSYS_NFTABLES, &[]);
+    batch.extend(messages.concat());
+    batch.extend(nlmsg(NFNL_MSG_BATCH_END, 0, messages.len() as u32 + 1, NFPROTO_UNSPEC, NFNL_SUBSYS_NFTABLES, &[]));
+    socket.send(&batch, 0)?;
+
+    // each message is acked, the first error ends the batch
+    let mut acks = 0;
+    read_replies(&socket, |kind, payload| {
+        if kind == NLMSG_ERROR {
+            ack_result(payload)?;
+            acks += 1;
+        }
+        Ok(acks < messages.len())
+    })
+}
+
+/// The masquerade and SNAT rules of every table in nftables
+fn list_nat_rules() -> io::Result<Vec<NatRule>> {
+    let socket = nftables_socket()?;
+    socket.send(&nlmsg(NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_GETRULE, NLM_F_DUMP, 0, NFPROTO_UNSPEC, 0, &[]), 0)?;
+    let mut rules = Vec::new();
+    read_replies(&socket, |kind, payload| match kind {
+        NLMSG_DONE => Ok(false),
+        NLMSG_ERROR => ack_result(payload).map(|_| false),
+        _ => {
+            rules.extend(parse_rule(payload).filter(|rule| rule.translates));
+            Ok(true)
+        },
+    })?;
+    Ok(rules)
+}
+
+/// Masquerading of the mesh out of the gateway's uplink, undone when dropped
+/* IPv4 forwarding is switched on if it was off, and back off again
+afterwards. The rule goes in a table of our own so it is removed without
+touching anyone else's. */
+pub struct Masquerade {
+    subnet: (Ipv4Addr, u8),
+    uplink: String,
+    restoreforwarding: bool,
+    installed: bool,
+}
+
+impl Masquerade {
+    /// Masquerade `subnet` out of `uplink`, unless other rules already translate it
+    pub fn install(subnet: (Ipv4Addr, u8), uplink: &str) -> io::Result<Self> {
+        if uplink.is_empty() || uplink.len() >= IFNAMSIZ {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid uplink interface name {}", uplink)));
+        }
+        let conflicts = masquerade_conflicts(&list_nat_rules()?, subnet, uplink);
+        if !conflicts.is_empty() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{}/{} is already translated out of {} by {}",
+                subnet.0, subnet.1, uplink, conflicts.join(", "))));
+        }
+        match transact(&[delete_table_message()]) {
+            Ok(()) => warn!("Replaced nftables table {} left from an earlier run", NAT_TABLE),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {},
+            Err(e) => return Err(e),
+        }
+        transact(&masquerade_messages(subnet, uplink))?;
+        let mut masquerade = Masquerade { subnet, uplink: String::from(uplink), restoreforwarding: false, installed: true };
+
+        if fs::read_to_string(IP_FORWARD)?.trim() == "0" {
+            fs::write(IP_FORWARD, "1")?;
+            masquerade.restoreforwarding = true;
+        }
+        info!("Masquerading {}/{} out of {}", subnet.0, subnet.1, uplink);
+        Ok(masquerade)
+    }
+
+    /// Remove our rules and restore IPv4 forwarding, does nothing the second time
+    pub fn remove(&mut self) {
+        if !self.installed {
+            return;
+        }
+        self.installed = false;
+        match transact(&[delete_table_message()]) {
+            Ok(()) => info!("Stopped masquerading {}/{} out of {}", self.subnet.0, self.subnet.1, self.uplink),
+            Err(e) => error!("Could not remove nftables table {}: {}", NAT_TABLE, e),
+        }
+        if self.restoreforwarding {
+            if let Err(e) = fs::write(IP_FORWARD, "0") {
+                error!("Could not switch IPv4 forwarding back off: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Masquerade {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn masquerade_conflicts_found() {
+    let subnet = parse_subnet("172.16.0.0/24").unwrap();
+    assert_eq!(subnet, (Ipv4Addr::new(172, 16, 0, 0), 24));
+    assert!(parse_subnet("172.16.0.0").is_err() && parse_subnet("172.16.0.0/33").is_err());
+
+    let rule = |table: &str, handle: u64, saddr: Option<&str>, oifname: Option<&str>, translates: bool| NatRule {
+        family: String::from("ip"),
+        table: String::from(table),
+        chain: String::from("postrouting"),
+        handle,
+        saddr: saddr.map(|saddr| parse_subnet(saddr).unwrap()),
+        oifname: oifname.map(String::from),
+        translates,
+    };
+    let rules = vec![
+        // docker's own network, another interface, ours from a crash and a filter rule pass
+        rule("nat", 4, Some("172.17.0.0/16"), None, true),
+        rule("nat", 5, Some("172.16.0.0/16"), Some("wlan0"), true),
+        rule(NAT_TABLE, 2, Some("172.16.0.0/24"), Some("eth0"), true),
+        rule("filter", 9, Some("172.16.0.0/24"), None, false),
+        // everything out of eth0, and a wider SNAT of ours
+        rule("nat", 6, None, Some("eth0"), true),
+        rule("nat", 7, Some("172.16.0.0/12"), None, true),
+    ];
+    assert_eq!(masquerade_conflicts(&rules, subnet, "eth0"), vec!["ip nat chain postrouting handle 6", "ip nat chain postrouting handle 7"]);
+    assert_eq!(masquerade_conflicts(&rules, subnet, "wlan0"), vec!["ip nat chain postrouting handle 5", "ip nat chain postrouting handle 7"]);
+    assert!(masquerade_conflicts(&[], subnet, "eth0").is_empty());
+}
+
+#[test]
+fn masquerade_rule_messages() {
+    let subnet = parse_subnet("172.16.0.9/24").unwrap();
+    let messages = masquerade_messages(subnet, "eth0");
+    assert_eq!(messages.len(), 3);
+    for message in &messages {
+        assert_eq!(u32::from_ne_bytes([message[0], message[1], message[2], message[3]]) as usize, message.len());
+        assert_eq!(message.len() % 4, 0);
+    }
+
+    // our own rule reads back as the masquerade it is
+    let rule = parse_rule(&messages[2][NLMSG_HDRLEN..]).unwrap();
+    assert_eq!(rule, NatRule {
+        family: String::from("ip"),
+        table: String::from(NAT_TABLE),
+        chain: String::from(NAT_CHAIN),
+        handle: 0,
+        saddr: Some((Ipv4Addr::new(172, 16, 0, 0), 24)),
+        oifname: Some(String::from("eth0")),
+        translates: true,
+    });
+    let host = parse_rule(&masquerade_messages(parse_subnet("10.0.0.1/32").unwrap(), "wlan0")[2][NLMSG_HDRLEN..]).unwrap();
+    assert_eq!((host.saddr, host.oifname.as_deref()), (Some((Ipv4Addr::new(10, 0, 0, 1), 32)), Some("wlan0")));
+
+    assert_eq!(ack_result(&0i32.to_ne_bytes()).unwrap(), ());
+    assert_eq!(ack_result(&(-libc::ENOENT).to_ne_bytes()).unwrap_err().raw_os_error(), Some(libc::ENOENT));
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use crate::hardware::LinkQuality;
+use crate::stack::frame::NodeId;
+
+/// weight of a new reading in the rolling RSSI and SNR averages
+const SIGNAL_SMOOTHING: f32 = 0.25;
+
+/// frame ID gaps this large are taken for a restart rather than lost frames
+const MAX_FRAMEID_GAP: u8 = 64;
+
+/// A neighbor showing up or going silent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeighborEvent {
+    Appeared(NodeId),
+    Expired(NodeId),
+    /// two nodes send with this ID
+    Collision(NodeId),
+}
+
+/// What is known about a neighbor, as returned by `NeighborTable::neighbors`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NeighborStats {
+    pub nodeid: NodeId,
+    pub lastseen: Instant,
+    /// frames heard from it directly
+    pub packets: u64,
+    /// rolling averages of the RSSI in dBm and SNR in dB
+    pub rssi: Option<f32>,
+    pub snr: Option<f32>,
+    /// share of its broadcasts we missed, from the gaps in their frame IDs
+    pub loss: Option<f32>,
+    /// heard with two instance tokens taking turns, more than one node uses its ID
+    pub collision: bool,
+}
+
+#[derive(Clone, Debug)]
+struct NeighborEntry {
+    stats: NeighborStats,
+    lastid: Option<u8>,
+    expected: u64,
+    received: u64,
+    /// instance token heard last, and the one before it
+    token: Option<u32>,
+    previous: Option<u32>,
+}
+
+/// frames sent between `last` and `id` by a counter that runs from 1 to 255 and skips 0
+pub(crate) fn frameid_distance(last: u8, id: u8) -> u8 {
+    if id >= last { id - last } else { id + (u8::MAX - last) }
+}
+
+/// Every node heard directly, with its link statistics
+/* Only frames a neighbor sent itself without a route, or flooded with
+only itself in the route, count as heard directly, anything routed may
+have been relayed by another node. Those frames all take their ID from
+the sender's broadcast counter, which counts up by one per frame, so a
+gap in the IDs is the number of frames we missed. Neighbors known from
+before a restart are kept apart as stale, they only become neighbors
+once heard again. A neighbor that restarts sends a new instance token,
+one whose old token comes back is two nodes sharing an ID. */
+#[derive(Debug)]
+pub struct NeighborTable {
+    timeout: Duration,
+    entries: HashMap<NodeId, NeighborEntry>,
+    /// restored neighbors not heard since, with when they were restored
+    stale: HashMap<NodeId, Instant>,
+    subscribers: Vec<Sender<NeighborEvent>>,
+}
+
+impl NeighborTable {
+    /// neighbors expire after `timeout` without a frame
+    pub fn new(timeout: Duration) -> Self {
+        NeighborTable { timeout, entries: HashMap::new(), stale: HashMap::new(), subscribers: Vec::new() }
+    }
+
+    /// Receive an event whenever a neighbor appears or expires
+    pub fn subscribe(&mut self) -> Receiver<NeighborEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn publish(&mut self, event: NeighborEvent) {
+        // subscribers that hung up are dropped
+        self.subscribers.retain(|subscriber| subscriber.send(event).is_ok());
+    }
+
+    /// a frame with this ID was heard directly from a neighbor, true if the neighbor is new
+    pub fn heard(&mut self, nodeid: NodeId, frameid: u8, quality: LinkQuality) -> bool {
+        self.heard_at(nodeid, frameid, quality, Instant::now())
+    }
+
+    pub(crate) fn heard_at(&mut self, nodeid: NodeId, frameid: u8, quality: LinkQuality, now: Instant) -> bool {
+        self.stale.remove(&nodeid);
+        let mut appeared = false;
+        let entry = self.entries.entry(nodeid).or_insert_with(|| {
+            appeared = true;
+            NeighborEntry {
+                stats: NeighborStats { nodeid, lastseen: now, packets: 0, rssi: None, snr: None, loss: None, collision: false },
+                lastid: None,
+                expected: 0,
+                received: 0,
+                token: None,
+                previous: None,
+            }
+        });
+        let stats = &mut entry.stats;
+        stats.lastseen = now;
+        stats.packets += 1;
+        if let Some(rssi) = quality.rssi {
+            let average = stats.rssi.get_or_insert(rssi as f32);
+            *average += (rssi as f32 - *average) * SIGNAL_SMOOTHING;
+        }
+        if let Some(snr) = quality.snr {
+            let average = stats.snr.get_or_insert(snr as f32);
+            *average += (snr as f32 - *average) * SIGNAL_SMOOTHING;
+        }
+        match entry.lastid.map(|last| frameid_distance(last, frameid)) {
+            // the same frame heard again
+            Some(0) => {},
+            Some(gap) if gap <= MAX_FRAMEID_GAP => {
+                entry.expected += gap as u64;
+                entry.received += 1;
+                entry.lastid = Some(frameid);
+            },
+            _ => entry.lastid = Some(frameid),
+        }
+        if entry.expected > 0 {
+            stats.loss = Some(1.0 - entry.received as f32 / entry.expected as f32);
+        }
+        if appeared {
+            self.publish(NeighborEvent::Appeared(nodeid));
+        }
+        appeared
+    }
+
+    /// A neighbor sent the instance token it started with, true if that shows a second node with its ID
+    pub fn token_heard(&mut self, nodeid: NodeId, token: u32) -> bool {
+        let entry = match self.entries.get_mut(&nodeid) {
+            None => return false,
+            Some(entry) => entry,
+        };
+        if entry.token == Some(token) {
+            return false;
+        }
+        let returned = entry.previous == Some(token);
+        entry.previous = entry.token;
+        entry.token = Some(token);
+        if !returned || entry.stats.collision {
+            return false;
+        }
+        entry.stats.collision = true;
+        self.publish(NeighborEvent::Collision(nodeid));
+        true
+    }
+
+    /// Forget neighbors silent for longer than the timeout, returning their IDs
+    pub fn expire(&mut self) -> Vec<NodeId> {
+        self.expire_at(Instant::now())
+    }
+
+    pub(crate) fn expire_at(&mut self, now: Instant) -> Vec<NodeId> {
+        let timeout = self.timeout;
+        self.stale.retain(|_, restored| now.duration_since(*restored) <= timeout);
+        let mut expired: Vec<NodeId> = self.entries.values()
+            .filter(|entry| now.duration_since(entry.stats.lastseen) > timeout)
+            .map(|entry| entry.stats.nodeid)
+            .collect();
+        expired.sort();
+        for nodeid in &expired {
+            self.entries.remove(nodeid);
+            self.publish(NeighborEvent::Expired(*nodeid));
+        }
+        expired
+    }
+
+    /// A neighbor known from before a restart, stale until it is heard
+    pub fn restore(&mut self, nodeid: NodeId) {
+        self.restore_at(nodeid, Instant::now())
+    }
+
+    pub(crate) fn restore_at(&mut self, nodeid: NodeId, now: Instant) {
+        if !self.entries.contains_key(&nodeid) {
+            self.stale.insert(nodeid, now);
+        }
+    }
+
+    /// Restored neighbors not heard since, by node ID
+    pub fn stale(&self) -> Vec<NodeId> {
+        let mut stale: Vec<NodeId> = self.stale.keys().cloned().collect();
+        stale.sort();
+        stale
+    }
+
+    pub fn get(&self, nodeid: NodeId) -> Option<NeighborStats> {
+        self.entries.get(&nodeid).map(|entry| entry.stats)
+    }
+
+    /// Snapshot of every neighbor, by node ID
+    pub fn neighbors(&self) -> Vec<NeighborStats> {
+        let mut all: Vec<NeighborStats> = self.entries.values().map(|entry| entry.stats).collect();
+        all.sort_by_key(|stats| stats.nodeid);
+        all
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn neighbor_table_stats() {
+    let start = Instant::now();
+    let mut table = NeighborTable::new(Duration::from_secs(60));
+    let events = table.subscribe();
+    let quality = |rssi, snr| LinkQuality { rssi: Some(rssi), snr: Some(snr) };
+
+    assert!(table.heard_at(4, 10, quality(-80, 8), start));
+    assert!(!table.heard_at(4, 11, quality(-100, 0), start + Duration::from_secs(1)));
+    let stats = table.get(4).unwrap();
+    assert_eq!(stats.packets, 2);
+    assert_eq!((stats.rssi, stats.snr), (Some(-85.0), Some(6.0)));
+    assert_eq!(stats.loss, Some(0.0));
+
+    // frames 12 and 13 were missed, a duplicate does not count
+    table.heard_at(4, 14, LinkQuality::default(), start + Duration::from_secs(2));
+    table.heard_at(4, 14, LinkQuality::default(), start + Duration::from_secs(2));
+    assert_eq!(table.get(4).unwrap().loss, Some(0.5));
+    assert_eq!(table.get(4).unwrap().rssi, Some(-85.0));
+    // the counter wraps from 255 to 1
+    assert_eq!(frameid_distance(254, 1), 2);
+
+    table.heard_at(7, 200, LinkQuality::default(), start + Duration::from_secs(30));
+    assert_eq!(table.get(7).unwrap().loss, None);
+    assert_eq!(table.neighbors().iter().map(|n| n.nodeid).collect::<Vec<_>>(), vec![4, 7]);
+
+    assert!(table.expire_at(start + Duration::from_secs(62)).is_empty());
+    assert_eq!(table.expire_at(start + Duration::from_secs(63)), vec![4]);
+    assert_eq!(table.expire_at(start + Duration::from_secs(91)), vec![7]);
+    assert!(table.neighbors().is_empty());
+    assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+        NeighborEvent::Appeared(4),
+        NeighborEvent::Appeared(7),
+        NeighborEvent::Expired(4),
+        NeighborEvent::Expired(7),
+    ]);
+
+    // a neighbor heard again after expiring appears anew
+    assert!(table.heard_at(4, 20, LinkQuality::default(), start + Duration::from_secs(100)));
+    assert_eq!(table.get(4).unwrap().packets, 1);
+
+    // a neighbor that restarted sends a new token, two taking turns share its ID
+    assert!(!table.token_heard(4, 0xAAAA));
+    assert!(!table.token_heard(4, 0xBBBB));
+    assert!(!table.get(4).unwrap().collision);
+    assert!(table.token_heard(4, 0xAAAA));
+    assert!(!table.token_heard(4, 0xBBBB));
+    assert!(table.get(4).unwrap().collision);
+    assert_eq!(events.try_iter().last(), Some(NeighborEvent::Collision(4)));
+
+    // neighbors from before a restart stay stale until heard, or go once the timeout passes
+    table.restore_at(4, start + Duration::from_secs(100));
+    table.restore_at(5, start + Duration::from_secs(100));
+    table.restore_at(6, start + Duration::from_secs(100));
+    assert_eq!(table.stale(), vec![5, 6]);
+    assert_eq!(table.get(5), None);
+    assert!(table.heard_at(5, 1, LinkQuality::default(), start + Duration::from_secs(110)));
+    assert_eq!(table.stale(), vec![6]);
+    table.expire_at(start + Duration::from_secs(161));
+    assert!(table.stale().is_empty());
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::stack::frame::NodeId;
+use crate::stack::message::{HeartbeatMessage, NodeInfo, HEARTBEAT_MISSES};
+
+/// Settings two nodes disagree on that keep them from working together
+pub fn mismatches(ours: &NodeInfo, theirs: &NodeInfo) -> Vec<String> {
+    let mut found = Vec::new();
+    if ours.encryption != theirs.encryption {
+        found.push(format!("encryption is {} here and {} there",
+            if ours.encryption { "on" } else { "off" }, if theirs.encryption { "on" } else { "off" }));
+    }
+    if ours.routing != theirs.routing {
+        found.push(format!("routing is {:?} here and {:?} there", ours.routing, theirs.routing));
+    }
+    found
+}
+
+/// What the latest heartbeat of a neighbor told us
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeHeartbeat {
+    pub last_seen: Instant,
+    pub seq: u16,
+    /// radio supply voltage in mV, None if the node does not read it
+    pub battery_mv: Option<u16>,
+    /// seconds since the node started
+    pub uptime_s: u32,
+}
+
+/// Latest info of every node that sent one, and the latest heartbeat of every neighbor
+#[derive(Clone, Debug, Default)]
+pub struct NodeInfoTable {
+    nodes: HashMap<NodeId, (NodeInfo, Instant)>,
+    heartbeats: HashMap<NodeId, NodeHeartbeat>,
+}
+
+impl NodeInfoTable {
+    pub fn new() -> Self {
+        NodeInfoTable { nodes: HashMap::new(), heartbeats: HashMap::new() }
+    }
+
+    /// record a heartbeat heard first hand from a neighbor
+    pub fn heartbeat(&mut self, nodeid: NodeId, msg: &HeartbeatMessage) {
+        self.heartbeat_at(nodeid, msg, Instant::now());
+    }
+
+    pub(crate) fn heartbeat_at(&mut self, nodeid: NodeId, msg: &HeartbeatMessage, now: Instant) {
+        self.heartbeats.insert(nodeid, NodeHeartbeat {
+            last_seen: now,
+            seq: msg.seq,
+            battery_mv: if msg.battery_mv == 0 { None } else { Some(msg.battery_mv) },
+            uptime_s: msg.uptime_s,
+        });
+    }
+
+    pub fn last_heartbeat(&self, nodeid: NodeId) -> Option<&NodeHeartbeat> {
+        self.heartbeats.get(&nodeid)
+    }
+
+    /// when we last heard of a node, by its info or a heartbeat
+    pub fn last_seen(&self, nodeid: NodeId) -> Option<Instant> {
+        let info = self.nodes.get(&nodeid).map(|(_, received)| *received);
+        let heartbeat = self.heartbeats.get(&nodeid).map(|hb| hb.last_seen);
+        info.max(heartbeat)
+    }
+
+    /// true once a neighbor heartbeating every `interval` missed HEARTBEAT_MISSES of them in a row
+    /* Nodes we never had a heartbeat from are not dead, only not neighbors. */
+    pub fn dead(&self, nodeid: NodeId, interval: Duration) -> bool {
+        self.dead_at(nodeid, interval, Instant::now())
+    }
+
+    pub(crate) fn dead_at(&self, nodeid: NodeId, interval: Duration, now: Instant) -> bool {
+        self.heartbeats.get(&nodeid)
+            .map_or(false, |hb| now.duration_since(hb.last_seen) > interval * HEARTBEAT_MISSES)
+    }
+
+    /// record a node's info, true if it is new or changed
+    pub fn update(&mut self, nodeid: NodeId, info: NodeInfo) -> bool {
+        let changed = self.nodes.get(&nodeid).map_or(true, |(known, _)| *known != info);
+        self.nodes.insert(nodeid, (info, Instant::now()));
+        changed
+    }
+
+    pub fn get(&self, nodeid: NodeId) -> Option<&NodeInfo> {
+        self.nodes.get(&nodeid).map(|(info, _)| info)
+    }
+
+    pub fn contains(&self, nodeid: NodeId) -> bool {
+        self.nodes.contains_key(&nodeid)
+    }
+
+    /// every known node with the time its info was received, by node ID
+    pub fn all(&self) -> Vec<(NodeId, &NodeInfo, Instant)> {
+        let mut all: Vec<_> = self.nodes.iter()
+            .map(|(id, (info, received))| (*id, info, *received))
+            .collect();
+        all.sort_by_key(|(id, _, _)| *id);
+        all
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn nodeinfo_table() {
+    use crate::stack::message::HardwareModel;
+    use crate::stack::router::RoutingMode;
+
+    let ours = NodeInfo {
+        name: None,
+        hardware: HardwareModel::LoStik,
+        firmware: String::from("RN2903 1.0.5 Nov 06 2018 10:45:27"),
+        version: String::from("0.1.0"),
+        gateway: false,
+        encryption: false,
+        routing: RoutingMode::Aodv,
+        headercompression: false,
+    };
+    let gateway = NodeInfo { name: Some(String::from("gw")), gateway: true, ..ours.clone() };
+    assert!(mismatches(&ours, &gateway).is_empty());
+    let other = NodeInfo { encryption: true, routing: RoutingMode::LinkState, ..ours.clone() };
+    assert_eq!(mismatches(&ours, &other), vec![
+        String::from("encryption is off here and on there"),
+        String::from("routing is Aodv here and LinkState there"),
+    ]);
+
+    let mut table = NodeInfoTable::new();
+    assert!(table.update(7, gateway.clone()));
+    assert!(!table.update(7, gateway.clone()));
+    assert!(table.update(2, other));
+    assert!(table.update(7, ours));
+    assert!(table.contains(2) && !table.contains(3));
+    assert_eq!(table.all().iter().map(|(id, _, _)| *id).collect::<Vec<_>>(), vec![2, 7]);
+    assert_eq!(table.get(7).unwrap().name, None);
+
+    // neighbor 7 heartbeats every 5s, it is dead once three went missing
+    let interval = Duration::from_secs(5);
+    let start = Instant::now();
+    let mut msg = HeartbeatMessage::new(0);
+    msg.seq = 41;
+    msg.uptime_s = 3600;
+    table.heartbeat_at(7, &msg, start);
+    let hb = *table.last_heartbeat(7).unwrap();
+    assert_eq!((hb.seq, hb.battery_mv, hb.uptime_s), (41, None, 3600));
+    assert_eq!(table.last_seen(7), Some(start));
+    msg.seq = 42;
+    msg.battery_mv = 3300;
+    table.heartbeat_at(7, &msg, start + interval);
+    assert_eq!(table.last_heartbeat(7).unwrap().battery_mv, Some(3300));
+    assert!(!table.dead_at(7, interval, start + interval * 3));
+    assert!(!table.dead_at(7, interval, start + interval * 4));
+    assert!(table.dead_at(7, interval, start + interval * 4 + Duration::from_millis(1)));
+    assert!(!table.dead_at(2, interval, start + interval * 100));
+}
@@ -0,0 +1,86 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+
+/// Bytes used to record the true payload length inside a padded payload
+pub const PADDING_HEADER_LEN: usize = 2;
+
+/// Pad a payload to the next multiple of `bucket`, or to `max` if `bucket` is zero
+/* The true length is written in front of the payload so the receiver can strip
+the padding. Padding is only useful under encryption, otherwise the length
+prefix gives it away. */
+pub fn pad_payload(payload: &[u8], bucket: usize, max: usize) -> Vec<u8> {
+    let len = PADDING_HEADER_LEN + payload.len();
+    let mut target = if bucket == 0 {
+        max
+    } else {
+        len.div_ceil(bucket) * bucket
+    };
+    if target > max {
+        target = max;
+    }
+    if target < len {
+        target = len;
+    }
+
+    let mut padded = Vec::with_capacity(target);
+    padded.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    padded.extend_from_slice(payload);
+    padded.resize(target, 0u8);
+    return padded;
+}
+
+/// Recover the original payload from a padded one
+pub fn unpad_payload(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < PADDING_HEADER_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "padded payload is missing its length"));
+    }
+    let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    data.get(PADDING_HEADER_LEN..PADDING_HEADER_LEN+len)
+        .map(Vec::from)
+        .ok_or(Error::new(ErrorKind::InvalidData, "padded payload is shorter than its length"))
+}
+
+/// Airtime spent on padding, so the cost of the privacy setting is visible
+#[derive(Clone, Debug, Default)]
+pub struct PaddingStats {
+    pub frames: u64,
+    pub payloadbytes: u64,
+    pub paddingbytes: u64,
+}
+
+impl PaddingStats {
+    /// record a payload before and after padding
+    pub fn record(&mut self, original: usize, padded: usize) {
+        self.frames += 1;
+        self.payloadbytes += original as u64;
+        self.paddingbytes += padded.saturating_sub(original) as u64;
+    }
+
+    /// fraction of transmitted bytes that were padding
+    pub fn overhead(&self) -> f64 {
+        let total = self.payloadbytes + self.paddingbytes;
+        if total == 0 { 0.0 } else { self.paddingbytes as f64 / total as f64 }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn padding_roundtrip() {
+    let mut stats = PaddingStats::default();
+    for size in 0..300usize {
+        let payload: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let padded = pad_payload(&payload, 32, 400);
+        assert_eq!(padded.len() % 32, 0);
+        assert_eq!(unpad_payload(&padded).unwrap(), payload);
+        stats.record(payload.len(), padded.len());
+    }
+    assert!(stats.overhead() > 0.0 && stats.overhead() < 1.0);
+
+    // padding to the frame maximum, never beyond it
+    assert_eq!(pad_payload(&[1, 2, 3], 0, 200).len(), 200);
+    assert_eq!(pad_payload(&[0u8; 197], 32, 200).len(), 200);
+    assert_eq!(pad_payload(&[0u8; 250], 32, 200).len(), 252);
+
+    assert!(unpad_payload(&[0]).is_err());
+    assert!(unpad_payload(&[0, 9, 1]).is_err());
+}
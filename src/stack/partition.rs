@@ -0,0 +1,190 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use crate::stack::frame::NodeId;
+
+/// Fewest nodes that must go silent together for a partition to be suspected
+pub const PARTITION_MIN_NODES: usize = 2;
+
+/// How many times faster broadcasts and hellos are sent while the mesh settles
+pub const PARTITION_SPEEDUP: u32 = 4;
+
+/// The mesh split or came back together
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionEvent {
+    /// these nodes went silent together, a relay between us and them is probably gone
+    Suspected(Vec<NodeId>),
+    /// nodes lost in a partition were heard again
+    Rejoined(Vec<NodeId>),
+}
+
+/// Notices the mesh splitting and coming back together
+/* A node is reachable while anything it sent, first hand or flooded,
+was heard within `window`. When at least `threshold` percent of the
+nodes that were reachable, and no fewer than `PARTITION_MIN_NODES`, go
+silent within one window of each other, they are reported lost together.
+Their last frames reached us at different times, so nodes going silent
+within a window after that are lost in the same partition. Nodes that go
+silent one at a time just drop out. Hearing a lost node again reports it
+rejoined. Either event asks for faster broadcasts for `boost`, so both
+sides learn about each other soon. */
+#[derive(Debug)]
+pub struct PartitionDetector {
+    window: Duration,
+    threshold: u8,
+    boost: Duration,
+    heard: HashMap<NodeId, Instant>,
+    /// nodes that went silent lately, and when it was noticed
+    silent: HashMap<NodeId, Instant>,
+    /// nodes lost in a partition and not heard since
+    lost: HashSet<NodeId>,
+    rejoined: Vec<NodeId>,
+    /// when the last partition was suspected
+    suspected: Option<Instant>,
+    boosted: Option<Instant>,
+    subscribers: Vec<Sender<PartitionEvent>>,
+    /// partitions suspected
+    pub partitions: u64,
+    /// times lost nodes came back
+    pub rejoins: u64,
+}
+
+impl PartitionDetector {
+    pub fn new(window: Duration, threshold: u8, boost: Duration) -> Self {
+        PartitionDetector {
+            window,
+            threshold,
+            boost,
+            heard: HashMap::new(),
+            silent: HashMap::new(),
+            lost: HashSet::new(),
+            rejoined: Vec::new(),
+            suspected: None,
+            boosted: None,
+            subscribers: Vec::new(),
+            partitions: 0,
+            rejoins: 0,
+        }
+    }
+
+    /// Receive an event whenever a partition is suspected or lost nodes come back
+    pub fn subscribe(&mut self) -> Receiver<PartitionEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Something sent by a node reached us
+    pub fn heard(&mut self, nodeid: NodeId) {
+        self.heard_at(nodeid, Instant::now())
+    }
+
+    pub(crate) fn heard_at(&mut self, nodeid: NodeId, now: Instant) {
+        self.heard.insert(nodeid, now);
+        self.silent.remove(&nodeid);
+        if self.lost.remove(&nodeid) {
+            self.rejoined.push(nodeid);
+        }
+    }
+
+    /// Nodes heard within the window
+    pub fn reachable(&self) -> Vec<NodeId> {
+        let mut reachable: Vec<NodeId> = self.heard.keys().cloned().collect();
+        reachable.sort();
+        reachable
+    }
+
+    /// Nodes lost in a partition and not heard since
+    pub fn lost(&self) -> Vec<NodeId> {
+        let mut lost: Vec<NodeId> = self.lost.iter().cloned().collect();
+        lost.sort();
+        lost
+    }
+
+    /// true while broadcasts should go faster
+    pub fn boosted(&self) -> bool {
+        self.boosted_at(Instant::now())
+    }
+
+    pub(crate) fn boosted_at(&self, now: Instant) -> bool {
+        self.boosted.map_or(false, |until| now < until)
+    }
+
+    /// Look for nodes that went silent together or came back
+    pub fn tick(&mut self) -> Vec<PartitionEvent> {
+        self.tick_at(Instant::now())
+    }
+
+    pub(crate) fn tick_at(&mut self, now: Instant) -> Vec<PartitionEvent> {
+        let window = self.window;
+        let quiet: Vec<NodeId> = self.heard.iter()
+            .filter(|(_, heard)| now.duration_since(**heard) >= window)
+            .map(|(nodeid, _)| *nodeid)
+            .collect();
+        for nodeid in quiet {
+            self.heard.remove(&nodeid);
+            self.silent.insert(nodeid, now);
+        }
+        self.silent.retain(|_, noticed| now.duration_since(*noticed) < window);
+
+        let mut events = Vec::new();
+        let silent = self.silent.len();
+        let ongoing = self.suspected.map_or(false, |suspected| now.duration_since(suspected) < window);
+        if (ongoing && silent > 0)
+            || (silent >= PARTITION_MIN_NODES && silent * 100 >= self.threshold as usize * (self.heard.len() + silent)) {
+            let mut lost: Vec<NodeId> = self.silent.drain().map(|(nodeid, _)| nodeid).collect();
+            lost.sort();
+            self.lost.extend(lost.iter());
+            if !ongoing {
+                self.partitions += 1;
+                self.suspected = Some(now);
+            }
+            events.push(PartitionEvent::Suspected(lost));
+        }
+        if !self.rejoined.is_empty() {
+            let mut rejoined = std::mem::take(&mut self.rejoined);
+            rejoined.sort();
+            self.rejoins += 1;
+            events.push(PartitionEvent::Rejoined(rejoined));
+        }
+        if !events.is_empty() {
+            self.boosted = Some(now + self.boost);
+        }
+        for event in events.iter() {
+            // subscribers that hung up are dropped
+            self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn partition_detection() {
+    let window = Duration::from_secs(1200);
+    let boost = Duration::from_secs(1800);
+    let start = Instant::now();
+
+    // nodes going silent one at a time are not a partition
+    let mut detector = PartitionDetector::new(window, 30, boost);
+    let events = detector.subscribe();
+    for nodeid in 1..=6 {
+        detector.heard_at(nodeid, start);
+    }
+    for nodeid in 1..=5 {
+        detector.heard_at(nodeid, start + window / 2);
+    }
+    assert!(detector.tick_at(start + window).is_empty());
+    for nodeid in 1..=4 {
+        detector.heard_at(nodeid, start + window * 2);
+    }
+    assert!(detector.tick_at(start + window * 2).is_empty() && !detector.boosted_at(start + window * 2));
+    assert_eq!(detector.reachable(), vec![1, 2, 3, 4]);
+    // then the rest go silent at once
+    detector.tick_at(start + window * 3);
+    assert_eq!(events.try_recv(), Ok(PartitionEvent::Suspected(vec![1, 2, 3, 4])));
+    assert!(detector.boosted_at(start + window * 3));
+    detector.heard_at(2, start + window * 4);
+    assert_eq!(detector.tick_at(start + window * 4), vec![PartitionEvent::Rejoined(vec![2])]);
+    assert_eq!(detector.lost(), vec![1, 3, 4]);
+}
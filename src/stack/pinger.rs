@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::stack::frame::NodeId;
+
+/// Tracks the pings sent to one node and the pongs that come back
+/* Round trip times are measured with the local monotonic clock, the
+timestamp carried in the ping is only echoed for the benefit of the sender. */
+#[derive(Clone, Debug)]
+pub struct PingSession {
+    pub dest: NodeId,
+    count: u16,
+    interval: Duration,
+    timeout: Duration,
+    nextseq: u16,
+    lastsent: Option<Instant>,
+    // sequence numbers waiting on a pong, with the time they were sent
+    pending: HashMap<u16, Instant>,
+    rtts: Vec<Duration>,
+}
+
+/// Results of a finished or running ping session
+#[derive(Clone, Debug, PartialEq)]
+pub struct PingSummary {
+    pub sent: u16,
+    pub received: u16,
+    /// lost pings in percent of those sent
+    pub loss: f32,
+    pub min: Option<Duration>,
+    pub avg: Option<Duration>,
+    pub max: Option<Duration>,
+}
+
+impl PingSession {
+    pub fn new(dest: NodeId, count: u16, interval: Duration, timeout: Duration) -> Self {
+        PingSession {
+            dest,
+            count,
+            interval,
+            timeout,
+            nextseq: 0,
+            lastsent: None,
+            pending: HashMap::new(),
+            rtts: Vec::new(),
+        }
+    }
+
+    /// Sequence number of the ping to send now, if one is due
+    pub fn poll_send(&mut self, now: Instant) -> Option<u16> {
+        if self.nextseq >= self.count {
+            return None;
+        }
+        if let Some(last) = self.lastsent {
+            if now.duration_since(last) < self.interval {
+                return None;
+            }
+        }
+        let seq = self.nextseq;
+        self.nextseq += 1;
+        self.lastsent = Some(now);
+        self.pending.insert(seq, now);
+        Some(seq)
+    }
+
+    /// Record a pong, returning the round trip time if it answers one of our pings
+    pub fn on_pong(&mut self, seq: u16, now: Instant) -> Option<Duration> {
+        let sent = self.pending.remove(&seq)?;
+        let rtt = now.duration_since(sent);
+        self.rtts.push(rtt);
+        Some(rtt)
+    }
+
+    /// Give up on pings older than the timeout, returning their sequence numbers
+    pub fn expire(&mut self, now: Instant) -> Vec<u16> {
+        let timeout = self.timeout;
+        let mut expired: Vec<u16> = self.pending.iter()
+            .filter(|(_, sent)| now.duration_since(**sent) >= timeout)
+            .map(|(seq, _)| seq.clone())
+            .collect();
+        expired.sort();
+        expired.iter().for_each(|seq| { self.pending.remove(seq); });
+        return expired;
+    }
+
+    /// true once every ping was sent and answered or timed out
+    pub fn is_done(&self) -> bool {
+        self.nextseq >= self.count && self.pending.is_empty()
+    }
+
+    pub fn summary(&self) -> PingSummary {
+        let sent = self.nextseq;
+        let received = self.rtts.len() as u16;
+        let loss = if sent == 0 { 0.0 } else { (sent - received) as f32 * 100.0 / sent as f32 };
+        let avg = if received == 0 {
+            None
+        } else {
+            Some(self.rtts.iter().sum::<Duration>() / received as u32)
+        };
+        PingSummary {
+            sent,
+            received,
+            loss,
+            min: self.rtts.iter().min().cloned(),
+            avg,
+            max: self.rtts.iter().max().cloned(),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn ping_session_loss() {
+    let start = Instant::now();
+    let secs = |s: u64| start + Duration::from_secs(s);
+    let mut session = PingSession::new(5, 3, Duration::from_secs(2), Duration::from_secs(5));
+
+    assert_eq!(session.poll_send(start), Some(0));
+    assert_eq!(session.poll_send(secs(1)), None);
+    assert_eq!(session.poll_send(secs(2)), Some(1));
+    assert_eq!(session.on_pong(0, secs(3)), Some(Duration::from_secs(3)));
+    // duplicate or unknown pongs are ignored
+    assert_eq!(session.on_pong(0, secs(3)), None);
+    assert_eq!(session.on_pong(7, secs(3)), None);
+    assert_eq!(session.poll_send(secs(4)), Some(2));
+    assert_eq!(session.poll_send(secs(6)), None);
+    assert_eq!(session.on_pong(2, secs(5)), Some(Duration::from_secs(1)));
+    assert!(!session.is_done());
+
+    // seq 1 never came back
+    assert!(session.expire(secs(6)).is_empty());
+    assert_eq!(session.expire(secs(7)), vec![1]);
+    assert!(session.is_done());
+
+    let summary = session.summary();
+    assert_eq!((summary.sent, summary.received), (3, 2));
+    assert!((summary.loss - 33.333).abs() < 0.01);
+    assert_eq!(summary.min, Some(Duration::from_secs(1)));
+    assert_eq!(summary.avg, Some(Duration::from_secs(2)));
+    assert_eq!(summary.max, Some(Duration::from_secs(3)));
+}
@@ -0,0 +1,515 @@
+use log::*;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+use crossbeam_channel;
+use crossbeam_channel::{Sender, Receiver, TryRecvError, TrySendError};
+use rand::{thread_rng, Rng};
+use crate::stack::frame::{Frame, FrameKey, NodeId, FRAME_HEADER_LEN, FRAME_NONCE_LEN, FRAME_TAG_LEN, MAX_FRAME_LEN};
+use crate::stack::message::MessageType;
+use crate::stack::padding::PaddingStats;
+
+/// Transmission priority of a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TxPriority {
+    /// emergency beacons, ahead of everything else
+    Emergency,
+    /// error reports, acknowledgements and diagnostics
+    High,
+    /// routing protocol traffic
+    Normal,
+    /// application data
+    Low,
+}
+
+impl TxPriority {
+    /// the priority a message type is sent with
+    pub fn for_message(msgtype: &MessageType) -> Self {
+        match msgtype {
+            MessageType::EmergencyBeacon => TxPriority::Emergency,
+            MessageType::RouteFailure | MessageType::RouteError | MessageType::DsrError => TxPriority::High,
+            MessageType::Error => TxPriority::High,
+            MessageType::Ping | MessageType::Pong => TxPriority::High,
+            MessageType::Traceroute | MessageType::TracerouteReply => TxPriority::High,
+            MessageType::TextReceipt | MessageType::Ack => TxPriority::High,
+            MessageType::TransmitRequest | MessageType::TransmitConfirm => TxPriority::High,
+            MessageType::Text => TxPriority::Low,
+            MessageType::Telemetry => TxPriority::Low,
+            MessageType::Data | MessageType::Datagram | MessageType::FileChunk => TxPriority::Low,
+            MessageType::IPPacket | MessageType::CompressedIPPacket => TxPriority::Low,
+            _ => TxPriority::Normal,
+        }
+    }
+}
+
+/// Frames taken off and dropped from a transmit queue, shared by both halves
+#[derive(Debug, Default)]
+struct TxCounters {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    padding: Mutex<PaddingStats>,
+}
+
+/// Queue depth and traffic for one destination
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TxDestStats {
+    /// frames waiting in its fair queue
+    pub depth: usize,
+    /// frames and bytes taken off the queue for it, in any tier
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+/// One destination's frames and what it may still send this round
+#[derive(Debug, Default)]
+struct DestQueue {
+    frames: VecDeque<Vec<u8>>,
+    deficit: usize,
+}
+
+/// Application frames queued by destination, drained with deficit round robin
+/* Each destination with frames waiting takes its turn, and may send up
+to `quantum` bytes plus whatever it did not use on earlier turns. A
+destination's unused allowance is forgotten once its queue empties, so an
+idle one cannot save up for a burst. A frame is queued for the last node
+on its route, the destination of a unicast frame and the origin of a
+flood, anything that does not parse under None. */
+#[derive(Debug)]
+struct FairQueue {
+    quantum: usize,
+    /// most frames waiting for one destination
+    depth: usize,
+    /// most frames waiting for all of them
+    capacity: usize,
+    len: usize,
+    queues: HashMap<Option<NodeId>, DestQueue>,
+    /// destinations with frames waiting, in the order of their turns
+    active: VecDeque<Option<NodeId>>,
+    /// the destination at the front got its quantum for this turn
+    granted: bool,
+    stats: BTreeMap<Option<NodeId>, TxDestStats>,
+}
+
+impl FairQueue {
+    fn new(depth: usize, capacity: usize) -> Self {
+        FairQueue {
+            quantum: MAX_FRAME_LEN,
+            depth,
+            capacity,
+            len: 0,
+            queues: HashMap::new(),
+            active: VecDeque::new(),
+            granted: false,
+            stats: BTreeMap::new(),
+        }
+    }
+
+    /// Queue a frame, giving it back if its destination or the whole queue is full
+    fn push(&mut self, dest: Option<NodeId>, data: Vec<u8>) -> Result<(), Vec<u8>> {
+        let queue = self.queues.entry(dest).or_default();
+        if queue.frames.len() >= self.depth || self.len >= self.capacity {
+            return Err(data);
+        }
+        if queue.frames.is_empty() {
+            self.active.push_back(dest);
+        }
+        queue.frames.push_back(data);
+        self.len += 1;
+        self.stats.entry(dest).or_default().depth += 1;
+        Ok(())
+    }
+
+    /// The next frame and the destination it was queued for
+    fn pop(&mut self) -> Option<(Option<NodeId>, Vec<u8>)> {
+        loop {
+            let dest = *self.active.front()?;
+            let queue = self.queues.get_mut(&dest).expect("active destination without a queue");
+            if !self.granted {
+                queue.deficit += self.quantum;
+                self.granted = true;
+            }
+            let len = queue.frames.front().map_or(0, |data| data.len());
+            if len > queue.deficit {
+                // its turn is over, the rest waits for the next round
+                self.active.rotate_left(1);
+                self.granted = false;
+                continue;
+            }
+            queue.deficit -= len;
+            let data = queue.frames.pop_front()?;
+            if queue.frames.is_empty() {
+                self.queues.remove(&dest);
+                self.active.pop_front();
+                self.granted = false;
+            }
+            self.len -= 1;
+            self.stats.entry(dest).or_default().depth -= 1;
+            return Some((dest, data));
+        }
+    }
+
+    fn sent(&mut self, dest: Option<NodeId>, len: usize) {
+        let stats = self.stats.entry(dest).or_default();
+        stats.frames += 1;
+        stats.bytes += len as u64;
+    }
+}
+
+/// The destination a frame is queued for
+fn tx_destination(data: &Vec<u8>) -> Option<NodeId> {
+    Frame::from_bytes(data).ok().and_then(|mut frame| frame.route().last().cloned())
+}
+
+/// Sending half of the prioritized transmit queue
+#[derive(Clone)]
+pub struct TxQueueSender {
+    emergency: Sender<Vec<u8>>,
+    high: Sender<Vec<u8>>,
+    normal: Sender<Vec<u8>>,
+    low: Arc<Mutex<FairQueue>>,
+    /// gone once every receiver was dropped
+    receivers: Weak<()>,
+    counters: Arc<TxCounters>,
+    deferred: Arc<Mutex<Option<Instant>>>,
+    /// set while the radio is silenced, nothing is queued or sent
+    halted: Arc<AtomicBool>,
+    /// key every frame is signed with on its way to the radio
+    framekey: Option<FrameKey>,
+    /// padding bucket and largest frame, if frames are encrypted before they are signed
+    encryption: Option<(usize, usize)>,
+}
+
+/// Receiving half of the prioritized transmit queue
+#[derive(Clone)]
+pub struct TxQueueReceiver {
+    emergency: Receiver<Vec<u8>>,
+    high: Receiver<Vec<u8>>,
+    normal: Receiver<Vec<u8>>,
+    low: Arc<Mutex<FairQueue>>,
+    /// only held, so senders notice once every receiver is gone
+    _alive: Arc<()>,
+    counters: Arc<TxCounters>,
+    /// until when the channel is reserved by others
+    deferred: Arc<Mutex<Option<Instant>>>,
+    halted: Arc<AtomicBool>,
+}
+
+/// Create a transmit queue with a bounded channel for each priority tier
+/* Low priority frames go to one destination's share of `capacity`, which
+can take all of it. */
+pub fn tx_queue(capacity: usize) -> (TxQueueSender, TxQueueReceiver) {
+    tx_queue_fair(capacity, capacity)
+}
+
+/// Create a transmit queue holding at most `depth` low priority frames for any one destination
+/* The emergency, high and normal tiers are channels of `capacity` frames
+each. Low priority frames are shared out fairly between destinations, up
+to `capacity` of them in all, so a bulk transfer to one node cannot hold
+up the traffic to the others. */
+pub fn tx_queue_fair(capacity: usize, depth: usize) -> (TxQueueSender, TxQueueReceiver) {
+    let (emergencytx, emergencyrx) = crossbeam_channel::bounded(capacity);
+    let (hightx, highrx) = crossbeam_channel::bounded(capacity);
+    let (normaltx, normalrx) = crossbeam_channel::bounded(capacity);
+    let low = Arc::new(Mutex::new(FairQueue::new(depth, capacity)));
+    let alive = Arc::new(());
+
+    let counters = Arc::new(TxCounters::default());
+    let deferred = Arc::new(Mutex::new(None));
+    let halted = Arc::new(AtomicBool::new(false));
+
+    (TxQueueSender { emergency: emergencytx, high: hightx, normal: normaltx, low: low.clone(),
+        receivers: Arc::downgrade(&alive), counters: counters.clone(), deferred: deferred.clone(), halted: halted.clone(), framekey: None, encryption: None },
+     TxQueueReceiver { emergency: emergencyrx, high: highrx, normal: normalrx, low, _alive: alive, counters, deferred, halted })
+}
+
+impl TxQueueSender {
+    /// the same queue, signing the frames sent through it with `key`
+    /* All frames leave through here, relayed and resent ones included, so
+    none can go out unsigned. */
+    pub fn with_frame_key(mut self, key: Option<FrameKey>) -> Self {
+        self.framekey = key;
+        self
+    }
+
+    /// the same queue, encrypting the frames sent through it before they are signed
+    /* Payloads are padded to a multiple of `padbucket` bytes, 1 pads
+    nothing and 0 as far as a frame of `maxframe` bytes goes. Takes effect
+    with a frame key only. */
+    pub fn with_encryption(mut self, padbucket: usize, maxframe: usize) -> Self {
+        self.encryption = Some((padbucket, maxframe));
+        self
+    }
+
+    /// Queue a frame, dropping it if its tier, or the queue for its destination, is full
+    /* While the queue is halted every frame is refused as if the radio
+    was gone. */
+    pub fn send(&self, data: Vec<u8>, priority: TxPriority) -> Result<(), TrySendError<Vec<u8>>> {
+        if self.is_halted() {
+            return Err(TrySendError::Disconnected(data));
+        }
+        let data = match (&self.framekey, Frame::from_bytes(&data)) {
+            (Some(key), Ok(frame)) => self.encrypt(frame, key).sign(key).to_bytes(),
+            _ => data,
+        };
+        let res = match priority {
+            TxPriority::Emergency => self.emergency.try_send(data),
+            TxPriority::High => self.high.try_send(data),
+            TxPriority::Normal => self.normal.try_send(data),
+            TxPriority::Low => self.send_fair(data),
+        };
+        if let Err(TrySendError::Full(_)) = &res {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            warn!("Transmit queue for {:?} priority is full, dropping frame", priority);
+        }
+        res
+    }
+
+    /// the frame encrypted under `key` if the queue encrypts, counting the padding
+    fn encrypt(&self, mut frame: Frame, key: &FrameKey) -> Frame {
+        let (bucket, maxframe) = match self.encryption {
+            Some(encryption) => encryption,
+            None => return frame,
+        };
+        let max = maxframe.saturating_sub(FRAME_HEADER_LEN + frame.route().len() + FRAME_NONCE_LEN + FRAME_TAG_LEN);
+        let mut encrypted = frame.encrypt(key, thread_rng().gen(), bucket, max);
+        self.counters.padding.lock().unwrap().record(frame.payload().len(), encrypted.payload().len() - FRAME_NONCE_LEN);
+        encrypted
+    }
+
+    /// Queue a frame like `send`, for frames nobody waits on, logging it if it was refused
+    pub fn send_logged(&self, data: Vec<u8>, priority: TxPriority) {
+        if let Err(e) = self.send(data, priority) {
+            debug!("Could not queue frame for transmission: {}", e);
+        }
+    }
+
+    fn send_fair(&self, data: Vec<u8>) -> Result<(), TrySendError<Vec<u8>>> {
+        if self.receivers.upgrade().is_none() {
+            return Err(TrySendError::Disconnected(data));
+        }
+        let dest = tx_destination(&data);
+        self.low.lock().unwrap().push(dest, data).map_err(TrySendError::Full)
+    }
+
+    /// true if no frames are waiting in any tier
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.emergency.len() + self.high.len() + self.normal.len() + self.low.lock().unwrap().len
+    }
+
+    /// Queue depth and traffic of every destination frames were queued for
+    pub fn destinations(&self) -> Vec<(Option<NodeId>, TxDestStats)> {
+        self.low.lock().unwrap().stats.iter()
+            .map(|(dest, stats)| (*dest, stats.clone()))
+            .collect()
+    }
+
+    /// Number of frames taken off the queue for transmission so far
+    pub fn sent(&self) -> u64 {
+        self.counters.sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames dropped because their tier was full
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Payload and padding bytes of the frames encrypted so far
+    pub fn padding(&self) -> PaddingStats {
+        self.counters.padding.lock().unwrap().clone()
+    }
+
+    /// Hold back everything but emergency beacons until `until`, while others have the channel
+    /* A later reservation only ever extends the wait. */
+    pub fn defer_until(&self, until: Instant) {
+        let mut deferred = self.deferred.lock().unwrap();
+        if deferred.map_or(true, |current| current < until) {
+            *deferred = Some(until);
+        }
+    }
+
+    /// Refuse every frame until `resume`, on all clones of either half
+    pub fn halt(&self) {
+        self.halted.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.halted.store(false, Ordering::SeqCst);
+    }
+
+    /// true between `halt` and `resume`
+    pub fn is_halted(&self) -> bool {
+        self.halted.load(Ordering::SeqCst)
+    }
+}
+
+impl TxQueueReceiver {
+    /// Take the next frame, always draining higher tiers first
+    /* The queue counts as disconnected once the senders are gone, which
+    drops the channels, and nothing is left for the fair queues. */
+    pub fn try_recv(&self) -> Result<Vec<u8>, TryRecvError> {
+        self.try_recv_at(Instant::now())
+    }
+
+    pub(crate) fn try_recv_at(&self, now: Instant) -> Result<Vec<u8>, TryRecvError> {
+        // a halted queue gives nothing out, not even what was queued before
+        if self.halted.load(Ordering::SeqCst) {
+            return Err(TryRecvError::Empty);
+        }
+        let deferred = self.deferred.lock().unwrap().map_or(false, |until| now < until);
+        let mut disconnected = true;
+        for (tier, channel) in [&self.emergency, &self.high, &self.normal].iter().enumerate() {
+            // while the channel is reserved by others only emergency beacons go out
+            if deferred && tier > 0 {
+                return Err(TryRecvError::Empty);
+            }
+            match channel.try_recv() {
+                Ok(data) => {
+                    self.counters.sent.fetch_add(1, Ordering::Relaxed);
+                    self.low.lock().unwrap().sent(tx_destination(&data), data.len());
+                    return Ok(data);
+                },
+                Err(TryRecvError::Empty) => disconnected = false,
+                Err(TryRecvError::Disconnected) => {},
+            }
+        }
+        let mut low = self.low.lock().unwrap();
+        if let Some((dest, data)) = low.pop() {
+            self.counters.sent.fetch_add(1, Ordering::Relaxed);
+            low.sent(dest, data.len());
+            return Ok(data);
+        }
+        if disconnected { Err(TryRecvError::Disconnected) } else { Err(TryRecvError::Empty) }
+    }
+
+    /// Drop every frame waiting in any tier, returning how many there were
+    /* They count as dropped, not sent. */
+    pub fn drain(&self) -> usize {
+        let mut drained = 0;
+        for channel in [&self.emergency, &self.high, &self.normal].iter() {
+            drained += channel.try_iter().count();
+        }
+        let mut low = self.low.lock().unwrap();
+        while low.pop().is_some() {
+            drained += 1;
+        }
+        self.counters.dropped.fetch_add(drained as u64, Ordering::Relaxed);
+        drained
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn qos_drain_order() {
+    let (sender, receiver) = tx_queue(4);
+    sender.send(vec![3], TxPriority::Low).unwrap();
+    sender.send(vec![2], TxPriority::Normal).unwrap();
+    sender.send(vec![1], TxPriority::High).unwrap();
+    sender.send(vec![4], TxPriority::Low).unwrap();
+    sender.send(vec![5], TxPriority::High).unwrap();
+    sender.send(vec![0], TxPriority::Emergency).unwrap();
+    assert_eq!(sender.len(), 6);
+
+    let mut order = Vec::new();
+    while let Ok(data) = receiver.try_recv() {
+        order.push(data[0]);
+    }
+    assert_eq!(order, vec![0, 1, 5, 2, 3, 4]);
+    assert!(sender.is_empty());
+
+    // tiers are bounded
+    for _ in 0..4 { sender.send(vec![0], TxPriority::Low).unwrap(); }
+    assert!(sender.send(vec![0], TxPriority::Low).is_err());
+    assert!(sender.send(vec![0], TxPriority::High).is_ok());
+    assert_eq!((sender.sent(), sender.dropped()), (6, 1));
+
+    // a halted queue refuses frames and gives none out, draining drops what waits
+    sender.halt();
+    assert!(sender.send(vec![0], TxPriority::Emergency).is_err());
+    assert!(receiver.try_recv().is_err());
+    assert_eq!(receiver.drain(), 5);
+    sender.resume();
+    assert!(sender.is_empty() && !sender.is_halted());
+    assert_eq!((sender.sent(), sender.dropped()), (6, 6));
+    sender.send(vec![7], TxPriority::Normal).unwrap();
+    assert_eq!(receiver.try_recv().unwrap(), vec![7]);
+}
+
+#[test]
+fn qos_signs_frames() {
+    use crate::stack::frame::FrameBuilder;
+
+    let key = [7u8; 32];
+    let (sender, receiver) = tx_queue(4);
+    let sender = sender.with_frame_key(Some(key));
+    let mut frame = FrameBuilder::new()
+        .message_type(MessageType::Data)
+        .sender(1)
+        .route(&[2])
+        .payload(vec![1, 2, 3])
+        .build()
+        .unwrap();
+    sender.send(frame.to_bytes(), TxPriority::Low).unwrap();
+    let signed = Frame::from_bytes(&receiver.try_recv().unwrap()).unwrap();
+    assert!(signed.signed() && signed.verify(&key).is_ok());
+    assert_eq!(signed.without_signature().to_bytes(), frame.to_bytes());
+    assert_eq!(sender.padding().frames, 0);
+
+    // encrypted before it is signed, padded to the bucket, never past the largest frame
+    let sender = sender.with_encryption(32, 40);
+    sender.send(frame.to_bytes(), TxPriority::Low).unwrap();
+    let bytes = receiver.try_recv().unwrap();
+    let encrypted = Frame::from_bytes(&bytes).unwrap();
+    assert!(encrypted.signed() && encrypted.verify(&key).is_ok() && encrypted.encrypted());
+    let mut decrypted = encrypted.without_signature().decrypt(&key).unwrap();
+    assert_eq!(decrypted.to_bytes(), frame.to_bytes());
+    assert_eq!(bytes.len(), 40);
+    let padding = sender.padding();
+    assert_eq!((padding.frames, padding.payloadbytes), (1, 3));
+    assert!(padding.overhead() > 0.5);
+}
+
+#[test]
+fn qos_fair_destinations() {
+    use crate::stack::frame::FrameBuilder;
+
+    let frame = |msgtype: MessageType, dest: NodeId, len: usize| FrameBuilder::new()
+        .message_type(msgtype)
+        .sender(1)
+        .route(&[dest])
+        .payload(vec![0; len])
+        .build()
+        .unwrap()
+        .to_bytes();
+    let a = 2;
+    let (sender, receiver) = tx_queue_fair(32, 8);
+
+    // no destination can take more than its depth, nor all of them more than the capacity
+    while sender.send(frame(MessageType::FileChunk, a, 200), TxPriority::Low).is_ok() {}
+    for dest in 4..=6 {
+        for _ in 0..8 {
+            sender.send(frame(MessageType::Data, dest, 200), TxPriority::Low).unwrap();
+        }
+        assert!(sender.send(frame(MessageType::Data, dest, 200), TxPriority::Low).is_err());
+    }
+    assert_eq!(sender.len(), 32);
+    assert!(sender.send(frame(MessageType::Data, 7, 10), TxPriority::Low).is_err());
+    assert!(sender.send(frame(MessageType::Ping, 7, 10), TxPriority::High).is_ok());
+
+    // frames drain in turns, one destination after the other
+    let mut order = Vec::new();
+    while let Ok(data) = receiver.try_recv() {
+        order.push(Frame::from_bytes(&data).unwrap().route()[0]);
+    }
+    assert_eq!(&order[..6], &[7, a, 4, 5, 6, a]);
+    assert_eq!(order.len(), 33);
+    let stats: HashMap<Option<NodeId>, TxDestStats> = sender.destinations().into_iter().collect();
+    assert_eq!((stats[&Some(a)].depth, stats[&Some(a)].frames, stats[&Some(a)].bytes), (0, 8, 8 * frame(MessageType::FileChunk, a, 200).len() as u64));
+    assert_eq!((stats[&Some(7)].depth, stats[&Some(7)].frames), (0, 1));
+    drop(receiver);
+    assert!(matches!(sender.send(frame(MessageType::Data, 4, 10), TxPriority::Low), Err(TrySendError::Disconnected(_))));
+}
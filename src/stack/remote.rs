@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::settings::Settings;
+use crate::stack::frame::NodeId;
+use crate::stack::limiter::KeyedLimiter;
+use crate::stack::message::{RemoteCommand, RemoteCommandMessage};
+
+/// Settings remote commands may read and change
+/* Only settings read afresh on every use are listed, changing the
+others would take a restart, which would undo the change. */
+pub const REMOTE_SETTINGS: &[&str] = &[
+    "maxhops",
+    "autoretransmit",
+    "telemetryinterval",
+    "gatewayinterval",
+    "hellointerval",
+    "timesyncinterval",
+    "stateinterval",
+];
+
+/// nonces behind the highest one that are still accepted once
+const REPLAY_WINDOW: u64 = 64;
+
+/// Read a whitelisted setting
+pub fn remote_get(opt: &Settings, key: &str) -> Result<String, String> {
+    match key {
+        "maxhops" => Ok(opt.maxhops.to_string()),
+        "autoretransmit" => Ok(opt.autoretransmit.to_string()),
+        "telemetryinterval" => Ok(opt.telemetryinterval.to_string()),
+        "gatewayinterval" => Ok(opt.gatewayinterval.to_string()),
+        "hellointerval" => Ok(opt.hellointerval.to_string()),
+        "timesyncinterval" => Ok(opt.timesyncinterval.to_string()),
+        "stateinterval" => Ok(opt.stateinterval.to_string()),
+        _ => Err(format!("{} is not a remote setting", key)),
+    }
+}
+
+/// Change a whitelisted setting
+pub fn remote_set(opt: &mut Settings, key: &str, value: &str) -> Result<(), String> {
+    let invalid = |_| format!("invalid value for {}: {}", key, value);
+    match key {
+        "maxhops" => opt.maxhops = value.parse().map_err(invalid)?,
+        "autoretransmit" => opt.autoretransmit = value.parse().map_err(|_| format!("invalid value for {}: {}", key, value))?,
+        "telemetryinterval" => opt.telemetryinterval = value.parse().map_err(invalid)?,
+        "gatewayinterval" => opt.gatewayinterval = value.parse().map_err(invalid)?,
+        "hellointerval" => opt.hellointerval = value.parse().map_err(invalid)?,
+        "timesyncinterval" => opt.timesyncinterval = value.parse().map_err(invalid)?,
+        "stateinterval" => opt.stateinterval = value.parse().map_err(invalid)?,
+        _ => return Err(format!("{} is not a remote setting", key)),
+    }
+    Ok(())
+}
+
+/// Nonces seen from one sender, so a recorded command can't be sent again
+/* Nonces may arrive out of order by up to `REPLAY_WINDOW`, anything
+older is rejected. */
+#[derive(Clone, Debug, Default)]
+pub struct ReplayWindow {
+    highest: Option<u64>,
+    /// bit n set if `highest - n` was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// true if the nonce is new, recording it
+    pub fn check(&mut self, nonce: u64) -> bool {
+        let highest = match self.highest {
+            None => {
+                self.highest = Some(nonce);
+                self.seen = 1;
+                return true;
+            },
+            Some(highest) => highest,
+        };
+        if nonce > highest {
+            let shift = nonce - highest;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = Some(nonce);
+            return true;
+        }
+        let age = highest - nonce;
+        if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+/// Decides which remote commands are run
+#[derive(Clone, Debug)]
+pub struct RemoteGuard {
+    authenticated: bool,
+    replay: HashMap<NodeId, ReplayWindow>,
+    limiter: KeyedLimiter<NodeId>,
+}
+
+impl RemoteGuard {
+    /// `authenticated` tells if frames are authenticated, without it every command is rejected
+    pub fn new(authenticated: bool, interval: Duration) -> Self {
+        RemoteGuard { authenticated, replay: HashMap::new(), limiter: KeyedLimiter::new(interval) }
+    }
+
+    /// Ok if the command from `sender` may run, or why not
+    pub fn check(&mut self, sender: NodeId, msg: &RemoteCommandMessage) -> Result<(), String> {
+        self.check_at(sender, msg, Instant::now())
+    }
+
+    pub(crate) fn check_at(&mut self, sender: NodeId, msg: &RemoteCommandMessage, now: Instant) -> Result<(), String> {
+        // anyone could forge a command in a plain frame
+        if !self.authenticated {
+            return Err(String::from("remote commands need frame authentication, which is not enabled"));
+        }
+        match &msg.command {
+            RemoteCommand::Get(key) | RemoteCommand::Set(key, _) if !REMOTE_SETTINGS.contains(&key.as_str()) => {
+                return Err(format!("{} is not a remote setting", key));
+            },
+            _ => {},
+        }
+        if !self.replay.entry(sender).or_insert_with(ReplayWindow::default).check(msg.nonce) {
+            return Err(format!("nonce {} was already used", msg.nonce));
+        }
+        if !self.limiter.allow_at(sender, now) {
+            return Err(String::from("too many remote commands, try again later"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn remote_guard() {
+    let start = Instant::now();
+    let command = |nonce: u64, line: &str| RemoteCommandMessage { header: None, nonce, command: RemoteCommand::parse(line).unwrap() };
+
+    // without frame authentication nothing runs
+    let mut guard = RemoteGuard::new(false, Duration::from_secs(5));
+    assert!(guard.check_at(2, &command(1, "stats"), start).is_err());
+
+    let mut guard = RemoteGuard::new(true, Duration::from_secs(5));
+    assert!(guard.check_at(2, &command(1, "set maxhops 3"), start).is_ok());
+    // only whitelisted settings
+    let err = guard.check_at(2, &command(2, "set radioport /dev/ttyS0"), start + Duration::from_secs(10)).unwrap_err();
+    assert_eq!(err, "radioport is not a remote setting");
+    // a replayed command is rejected
+    assert!(guard.check_at(2, &command(1, "set maxhops 3"), start + Duration::from_secs(20)).is_err());
+    // and commands are rate limited per sender
+    assert!(guard.check_at(2, &command(3, "stats"), start + Duration::from_secs(30)).is_ok());
+    assert!(guard.check_at(2, &command(4, "stats"), start + Duration::from_secs(31)).is_err());
+    assert!(guard.check_at(3, &command(4, "stats"), start + Duration::from_secs(31)).is_ok());
+
+    let mut opt = Settings::new().unwrap();
+    assert!(remote_set(&mut opt, "maxhops", "3").is_ok());
+    assert_eq!(remote_get(&opt, "maxhops"), Ok(String::from("3")));
+    assert!(remote_set(&mut opt, "maxhops", "many").is_err());
+    assert!(remote_set(&mut opt, "autoretransmit", "true").is_ok());
+    assert!(opt.autoretransmit);
+    assert!(remote_get(&opt, "controlsocket").is_err());
+}
+
+#[test]
+fn replay_window() {
+    let mut window = ReplayWindow::default();
+    assert!(window.check(100));
+    assert!(!window.check(100));
+    // out of order within the window is fine, once
+    assert!(window.check(105));
+    assert!(window.check(101));
+    assert!(!window.check(101));
+    // too far behind
+    assert!(window.check(300));
+    assert!(!window.check(200));
+    assert!(window.check(299));
+}
@@ -0,0 +1,192 @@
+use std::time::{Duration, Instant};
+use crate::stack::frame::NodeId;
+use crate::stack::message::{TransmitConfirmMessage, TransmitRequestMessage};
+
+/// A burst held back until its receiver grants the channel
+#[derive(Clone, Debug)]
+struct PendingBurst {
+    dest: NodeId,
+    frames: Vec<Vec<u8>>,
+    /// when the request went out, or the channel was free to send it
+    asked: Instant,
+}
+
+/// Channel reservations for large bursts, against hidden terminals
+/* Two nodes that cannot hear each other but send to the same receiver
+collide there without ever noticing. Before a burst of more than
+`threshold` bytes the sender asks its receiver for the channel with a
+transmit request naming the burst's duration, and holds the burst back.
+The receiver answers with a transmit confirm, which the nodes hidden
+from the sender overhear. Everyone hearing either one holds back their
+own frames for the duration. Reservations are only advice: a burst
+whose grant does not come within `wait` is sent anyway, and no
+reservation holds the channel for longer than `max`. */
+#[derive(Clone, Debug)]
+pub struct Reservations {
+    nodeid: NodeId,
+    threshold: usize,
+    max: Duration,
+    wait: Duration,
+    pending: Vec<PendingBurst>,
+    /// until when others have the channel
+    deferred: Option<Instant>,
+    /// reservations we asked for
+    pub requests: u64,
+    /// reservations we granted
+    pub grants: u64,
+    /// bursts sent without a grant
+    pub ungranted: u64,
+    /// times we held back for others
+    pub deferrals: u64,
+}
+
+impl Reservations {
+    /// bursts over `threshold` bytes are reserved for, waiting `wait` for a grant and `max` at most for others
+    pub fn new(nodeid: NodeId, threshold: usize, max: Duration, wait: Duration) -> Self {
+        Reservations {
+            nodeid,
+            threshold,
+            max,
+            wait,
+            pending: Vec::new(),
+            deferred: None,
+            requests: 0,
+            grants: 0,
+            ungranted: 0,
+            deferrals: 0,
+        }
+    }
+
+    /// true if a burst of this many bytes should reserve the channel first
+    pub fn needed(&self, bytes: usize) -> bool {
+        bytes > self.threshold
+    }
+
+    /// Until when others have the channel
+    pub fn deferred_until(&self) -> Option<Instant> {
+        self.deferred
+    }
+
+    /// Hold a burst for a neighbor back and ask it for the channel
+    /// returns None when a burst for it already waits, the frames join that one
+    pub fn request(&mut self, dest: NodeId, frames: Vec<Vec<u8>>, duration: Duration) -> Option<TransmitRequestMessage> {
+        self.request_at(dest, frames, duration, Instant::now())
+    }
+
+    pub(crate) fn request_at(&mut self, dest: NodeId, frames: Vec<Vec<u8>>, duration: Duration, now: Instant) -> Option<TransmitRequestMessage> {
+        if let Some(burst) = self.pending.iter_mut().find(|burst| burst.dest == dest) {
+            burst.frames.extend(frames);
+            return None;
+        }
+        // the request itself waits for a reservation of others to end
+        let asked = self.deferred.map_or(now, |until| until.max(now));
+        self.pending.push(PendingBurst { dest, frames, asked });
+        self.requests += 1;
+        Some(TransmitRequestMessage::new(dest, self.millis(duration)))
+    }
+
+    /// A transmit request from a neighbor was heard, returns our grant if it was for us
+    /* A receiver that is itself held back by another reservation does not
+    grant, the requester sends once its wait is over. */
+    pub fn handle_request(&mut self, sender: NodeId, request: &TransmitRequestMessage) -> Option<TransmitConfirmMessage> {
+        self.handle_request_at(sender, request, Instant::now())
+    }
+
+    pub(crate) fn handle_request_at(&mut self, sender: NodeId, request: &TransmitRequestMessage, now: Instant) -> Option<TransmitConfirmMessage> {
+        if request.dest != self.nodeid {
+            self.defer_at(request.duration, now);
+            return None;
+        }
+        if self.deferred.map_or(false, |until| now < until) {
+            return None;
+        }
+        self.grants += 1;
+        Some(TransmitConfirmMessage::new(sender, self.millis(Duration::from_millis(request.duration as u64))))
+    }
+
+    /// A transmit confirm from a neighbor was heard, returns the burst it releases if it was for us
+    pub fn handle_confirm(&mut self, sender: NodeId, confirm: &TransmitConfirmMessage) -> Vec<Vec<u8>> {
+        self.handle_confirm_at(sender, confirm, Instant::now())
+    }
+
+    pub(crate) fn handle_confirm_at(&mut self, sender: NodeId, confirm: &TransmitConfirmMessage, now: Instant) -> Vec<Vec<u8>> {
+        if confirm.requester != self.nodeid {
+            self.defer_at(confirm.duration, now);
+            return Vec::new();
+        }
+        match self.pending.iter().position(|burst| burst.dest == sender) {
+            Some(at) => self.pending.remove(at).frames,
+            None => Vec::new(),
+        }
+    }
+
+    /// Bursts whose grant never came, to be sent anyway
+    pub fn expired(&mut self) -> Vec<Vec<u8>> {
+        self.expired_at(Instant::now())
+    }
+
+    pub(crate) fn expired_at(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let wait = self.wait;
+        let mut frames = Vec::new();
+        let mut ungranted = 0;
+        self.pending.retain(|burst| {
+            if now < burst.asked + wait {
+                return true;
+            }
+            ungranted += 1;
+            frames.extend(burst.frames.iter().cloned());
+            false
+        });
+        self.ungranted += ungranted;
+        frames
+    }
+
+    fn defer_at(&mut self, duration: u16, now: Instant) {
+        let until = now + Duration::from_millis(duration as u64).min(self.max);
+        if self.deferred.map_or(true, |current| current < until) {
+            self.deferred = Some(until);
+            self.deferrals += 1;
+        }
+    }
+
+    fn millis(&self, duration: Duration) -> u16 {
+        duration.min(self.max).as_millis().min(u16::MAX as u128) as u16
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn reservation_handshake() {
+    let start = Instant::now();
+    let reservations = |id: NodeId| Reservations::new(id, 400, Duration::from_secs(10), Duration::from_secs(2));
+    let (mut sender, mut receiver, mut hidden) = (reservations(1), reservations(2), reservations(3));
+
+    // the burst is held until 2 grants the channel, 3 overhears the grant and holds back
+    let request = sender.request_at(2, vec![vec![1], vec![2]], Duration::from_millis(500), start).unwrap();
+    assert!(sender.expired_at(start).is_empty());
+    let confirm = receiver.handle_request_at(1, &request, start).unwrap();
+    assert_eq!((confirm.requester, confirm.duration), (1, 500));
+    assert!(hidden.handle_confirm_at(2, &confirm, start).is_empty());
+    assert_eq!(hidden.deferred_until(), Some(start + Duration::from_millis(500)));
+    assert_eq!(sender.handle_confirm_at(2, &confirm, start), vec![vec![1], vec![2]]);
+    assert_eq!((sender.requests, receiver.grants, sender.ungranted, hidden.deferrals), (1, 1, 0, 1));
+
+    // the request of 3 waits for the reservation to end, and 2 does not grant while held back itself
+    let request = hidden.request_at(2, vec![vec![3]], Duration::from_millis(500), start).unwrap();
+    assert!(hidden.expired_at(start + Duration::from_millis(2499)).is_empty());
+    receiver.handle_request_at(3, &TransmitRequestMessage::new(4, 1000), start);
+    assert!(receiver.handle_request_at(3, &request, start).is_none());
+
+    // reservations are bounded, and a lost grant only delays the burst
+    let mut res = reservations(1);
+    assert!(!res.needed(400) && res.needed(401));
+    let request = res.request_at(2, vec![vec![1]], Duration::from_secs(60), start).unwrap();
+    assert_eq!(request.duration, 10_000);
+    assert!(res.request_at(2, vec![vec![2]], Duration::from_secs(1), start).is_none());
+    assert!(res.expired_at(start + Duration::from_millis(1999)).is_empty());
+    assert_eq!(res.expired_at(start + Duration::from_secs(2)), vec![vec![1], vec![2]]);
+    assert_eq!(res.ungranted, 1);
+    let overheard = TransmitConfirmMessage::new(3, 60_000);
+    res.handle_confirm_at(2, &overheard, start);
+    assert_eq!(res.deferred_until(), Some(start + Duration::from_secs(10)));
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use crate::stack::{IpPacket, NodeId};
+use crate::stack::message::AddressQueryMessage;
+use crate::stack::staticip::in_subnet;
+
+/// Queries sent for an address before its packets are given up on, spread over the wait
+pub const ADDRESS_QUERIES: u32 = 3;
+
+/// true if `ipaddr` is a single node's address in `subnet`, not its network or broadcast address
+pub fn resolvable(ipaddr: Ipv4Addr, subnet: (Ipv4Addr, u8)) -> bool {
+    if !in_subnet(ipaddr, subnet) {
+        return false;
+    }
+    // a /31 or /32 has no network or broadcast address
+    let host = u32::from(ipaddr) & u32::MAX.checked_shr(subnet.1 as u32).unwrap_or(0);
+    subnet.1 >= 31 || (host != 0 && host != u32::MAX >> subnet.1)
+}
+
+/// The query to send on, one hop further, or None if it may go no further
+pub fn relay_query(query: &AddressQueryMessage) -> Option<AddressQueryMessage> {
+    query.ttl.checked_sub(1).map(|ttl| AddressQueryMessage::new(query.ipaddr, ttl))
+}
+
+/// What became of a packet to an address we could not resolve
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Held {
+    /// the first one waiting, the address needs a query
+    Query,
+    /// a query is out already
+    Queued,
+    /// too many wait already, it was dropped
+    Full,
+}
+
+/// Packets waiting for the node holding their address
+struct Pending {
+    since: Instant,
+    queried: Instant,
+    packets: Vec<IpPacket>,
+}
+
+/// Node IDs of the mesh's addresses, and the packets waiting for one to be found
+/* Entries are learned from broadcasts, from the source of packets nodes
+send us and from answers to our queries, and are forgotten once nothing
+confirmed them for `ttl`. A node holds a single address, learning a new
+one replaces the old. */
+pub struct AddressResolver {
+    cache: HashMap<Ipv4Addr, (NodeId, Instant)>,
+    pending: HashMap<Ipv4Addr, Pending>,
+    ttl: Duration,
+    wait: Duration,
+    maxqueued: usize,
+}
+
+impl AddressResolver {
+    pub fn new(ttl: Duration, wait: Duration, maxqueued: usize) -> Self {
+        AddressResolver { cache: HashMap::new(), pending: HashMap::new(), ttl, wait, maxqueued }
+    }
+
+    /// Note that `nodeid` holds `ipaddr`, returns the packets that waited for it
+    pub fn learn(&mut self, ipaddr: Ipv4Addr, nodeid: NodeId) -> Vec<IpPacket> {
+        self.learn_at(ipaddr, nodeid, Instant::now())
+    }
+
+    pub(crate) fn learn_at(&mut self, ipaddr: Ipv4Addr, nodeid: NodeId, now: Instant) -> Vec<IpPacket> {
+        self.cache.retain(|ip, (holder, _)| *holder != nodeid || *ip == ipaddr);
+        self.cache.insert(ipaddr, (nodeid, now));
+        self.pending.remove(&ipaddr).map_or(Vec::new(), |pending| pending.packets)
+    }
+
+    /// Node holding `ipaddr`, if it was confirmed within the TTL
+    pub fn lookup(&self, ipaddr: Ipv4Addr) -> Option<NodeId> {
+        self.lookup_at(ipaddr, Instant::now())
+    }
+
+    pub(crate) fn lookup_at(&self, ipaddr: Ipv4Addr, now: Instant) -> Option<NodeId> {
+        self.cache.get(&ipaddr)
+            .filter(|(_, learned)| now.duration_since(*learned) < self.ttl)
+            .map(|(nodeid, _)| *nodeid)
+    }
+
+    /// Keep a packet until the node holding `ipaddr` is known
+    pub fn hold(&mut self, ipaddr: Ipv4Addr, packet: IpPacket) -> Held {
+        self.hold_at(ipaddr, packet, Instant::now())
+    }
+
+    pub(crate) fn hold_at(&mut self, ipaddr: Ipv4Addr, packet: IpPacket, now: Instant) -> Held {
+        match self.pending.get_mut(&ipaddr) {
+            Some(pending) if pending.packets.len() >= self.maxqueued => Held::Full,
+            Some(pending) => {
+                pending.packets.push(packet);
+                Held::Queued
+            },
+            None => {
+                self.pending.insert(ipaddr, Pending { since: now, queried: now, packets: vec![packet] });
+                Held::Query
+            },
+        }
+    }
+
+    /// Addresses to query again, and those given up on with the number of packets dropped
+    pub fn tick(&mut self) -> (Vec<Ipv4Addr>, Vec<(Ipv4Addr, usize)>) {
+        self.tick_at(Instant::now())
+    }
+
+    pub(crate) fn tick_at(&mut self, now: Instant) -> (Vec<Ipv4Addr>, Vec<(Ipv4Addr, usize)>) {
+        let ttl = self.ttl;
+        self.cache.retain(|_, (_, learned)| now.duration_since(*learned) < ttl);
+        let wait = self.wait;
+        let mut expired = Vec::new();
+        self.pending.retain(|ipaddr, pending| {
+            let keep = now.duration_since(pending.since) < wait;
+            if !keep {
+                expired.push((*ipaddr, pending.packets.len()));
+            }
+            keep
+        });
+        let retry = wait / ADDRESS_QUERIES;
+        let mut requery = Vec::new();
+        for (ipaddr, pending) in self.pending.iter_mut() {
+            if now.duration_since(pending.queried) >= retry {
+                pending.queried = now;
+                requery.push(*ipaddr);
+            }
+        }
+        (requery, expired)
+    }
+
+    /// Every address known, with its node and how long ago it was confirmed
+    pub fn entries(&self) -> Vec<(Ipv4Addr, NodeId, Duration)> {
+        self.entries_at(Instant::now())
+    }
+
+    pub(crate) fn entries_at(&self, now: Instant) -> Vec<(Ipv4Addr, NodeId, Duration)> {
+        let mut entries: Vec<_> = self.cache.iter()
+            .filter(|(_, (_, learned))| now.duration_since(*learned) < self.ttl)
+            .map(|(ipaddr, (nodeid, learned))| (*ipaddr, *nodeid, now.duration_since(*learned)))
+            .collect();
+        entries.sort_by_key(|(ipaddr, _, _)| *ipaddr);
+        entries
+    }
+
+    /// Addresses with packets waiting, and how many
+    pub fn pending(&self) -> Vec<(Ipv4Addr, usize)> {
+        let mut pending: Vec<_> = self.pending.iter().map(|(ipaddr, pending)| (*ipaddr, pending.packets.len())).collect();
+        pending.sort();
+        pending
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn address_query_round_trip() {
+    use crate::stack::Frame;
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::AddressAnswerMessage;
+
+    let mesh = (Ipv4Addr::new(172, 16, 0, 0), 24);
+    let target = Ipv4Addr::new(172, 16, 0, 9);
+    assert!(resolvable(target, mesh));
+    assert!(!resolvable(Ipv4Addr::new(172, 16, 0, 255), mesh) && !resolvable(Ipv4Addr::new(172, 16, 0, 0), mesh));
+    assert!(!resolvable(Ipv4Addr::new(8, 8, 8, 8), mesh));
+
+    let packet = |id: u8| IpPacket::new(vec![0x45, 0, 0, 20, 0, id, 0x40, 0, 64, 17, 0, 0, 172, 16, 0, 2, 172, 16, 0, 9]).unwrap();
+    let now = Instant::now();
+    let mut resolver = AddressResolver::new(Duration::from_secs(300), Duration::from_secs(9), 2);
+    assert_eq!(resolver.lookup_at(target, now), None);
+    // the first packet sends a query, the next waits with it, then there's no room
+    assert_eq!(resolver.hold_at(target, packet(1), now), Held::Query);
+    assert_eq!(resolver.hold_at(target, packet(2), now), Held::Queued);
+    assert_eq!(resolver.hold_at(target, packet(3), now), Held::Full);
+    assert_eq!(resolver.pending(), vec![(target, 2)]);
+
+    // node 2 floods the query, node 4 sends it on with one hop less
+    let mut frame = Frame::from_bytes(&AddressQueryMessage::new(target, 1).to_frame(5, 2, vec![2]).unwrap().to_bytes()).unwrap();
+    let query = AddressQueryMessage::from_frame(&mut frame).unwrap();
+    let relayed = relay_query(&query).unwrap();
+    assert_eq!((relayed.ipaddr, relayed.ttl), (target, 0));
+    assert!(relay_query(&relayed).is_none());
+    let mut frame = relayed.to_frame(5, 2, vec![2]).unwrap();
+    frame.route_unshift(4).unwrap();
+    assert_eq!(frame.route(), vec![4, 2]);
+
+    // node 9 holds the address and answers the way the query came
+    let mut answer = Frame::from_bytes(&AddressAnswerMessage::new(target).to_frame(7, 9, vec![4, 2]).unwrap().to_bytes()).unwrap();
+    let answered = AddressAnswerMessage::from_frame(&mut answer).unwrap();
+    let released = resolver.learn_at(answered.ipaddr, answer.sender(), now + Duration::from_secs(1));
+    assert_eq!(released.iter().map(|p| p.as_ref()[5]).collect::<Vec<_>>(), vec![1, 2]);
+    assert_eq!(resolver.lookup_at(target, now + Duration::from_secs(1)), Some(9));
+    assert!(resolver.pending().is_empty());
+
+    // a node taking another address forgets the old, entries lapse after the TTL
+    resolver.learn_at(Ipv4Addr::new(172, 16, 0, 19), 9, now + Duration::from_secs(2));
+    assert_eq!(resolver.lookup_at(target, now + Duration::from_secs(2)), None);
+    assert_eq!(resolver.entries_at(now + Duration::from_secs(2)), vec![(Ipv4Addr::new(172, 16, 0, 19), 9, Duration::from_secs(0))]);
+    assert_eq!(resolver.lookup_at(Ipv4Addr::new(172, 16, 0, 19), now + Duration::from_secs(302)), None);
+
+    // unanswered queries go out again, their packets are dropped after the wait
+    let other = Ipv4Addr::new(172, 16, 0, 30);
+    assert_eq!(resolver.hold_at(other, packet(4), now), Held::Query);
+    assert_eq!(resolver.tick_at(now + Duration::from_secs(2)), (vec![], vec![]));
+    assert_eq!(resolver.tick_at(now + Duration::from_secs(3)), (vec![other], vec![]));
+    assert_eq!(resolver.tick_at(now + Duration::from_secs(9)), (vec![], vec![(other, 1)]));
+    assert!(resolver.pending().is_empty());
+}
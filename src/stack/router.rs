@@ -1,15 +1,33 @@
 use log::*;
-use std::net::Ipv4Addr;
-use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
-use std::result::Result;
-use packet::ip::v4::Packet;
 use petgraph::graphmap::UnGraphMap;
 use petgraph::algo::{astar, min_spanning_tree};
 use petgraph::data::FromElements;
 use std::cell::{RefCell};
 use std::borrow::{BorrowMut};
-use crate::stack::message::{BroadcastMessage, IPAssignFailureMessage};
+use serde::Deserialize;
+use crate::stack::gateways::{DefaultGateway, GatewayTable};
+use crate::stack::ipv6::IpPacket;
+use crate::stack::linkstate::LinkStateDb;
+use crate::stack::mesh_state::{MeshState, MESH_STATE_VERSION, STALE_ROUTE_TTL};
+use crate::stack::message::BroadcastMessage;
+
+/// How routes to other nodes are found
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[cfg_attr(feature = "serde-support", derive(serde::Serialize))]
+pub enum RoutingMode {
+    /// on demand, from broadcasts and route discovery
+    #[serde(rename = "aodv")]
+    Aodv,
+    /// proactive, every node floods its links and routes by ETX
+    #[serde(rename = "linkstate")]
+    LinkState,
+    /// on demand, requests record the whole path and its origin keeps it as a source route
+    #[serde(rename = "dsr")]
+    Dsr,
+}
 
 #[derive(Clone)]
 pub struct MeshRouter {
@@ -20,9 +38,28 @@ pub struct MeshRouter {
     timeout: Duration,
     retries: u8,
     observations: RefCell<HashMap<u8, Instant>>,
+    /// last time each direct neighbor was heard
+    neighbors: HashMap<u8, Instant>,
+    /// neighbors that expired and have not been heard from since
+    lost: HashSet<u8>,
     graph: UnGraphMap<u8, u8>,
     id2ip: RefCell<HashMap<u8, Ipv4Addr>>,
     ip2id: RefCell<HashMap<Ipv4Addr, u8>>,
+    /// addresses in the mesh's IPv6 prefix, learned from broadcasts
+    id2ip6: HashMap<u8, Ipv6Addr>,
+    ip62id: HashMap<Ipv6Addr, u8>,
+    /// advertisements from every node, only kept in link-state mode
+    linkstate: Option<LinkStateDb>,
+    /// gateways announcing themselves, when there may be more than one
+    gateways: Option<GatewayTable>,
+    /// gateways announcing they reach the internet, by ID
+    defaultgateways: HashMap<u8, DefaultGateway>,
+    /// how long a network announcement holds, None while they are not tracked
+    networktimeout: Option<Duration>,
+    /// routes read from disk waiting to be confirmed, and when they expire
+    stale: HashMap<u8, (Vec<u8>, Instant)>,
+    /// IDs we gave up, and until when paths through them are ignored
+    tombstones: HashMap<u8, Instant>,
     isgateway: bool
 
 }
@@ -37,13 +74,209 @@ impl MeshRouter {
             timeout,
             retries: 1, // TODO
             observations: RefCell::new(HashMap::new()),
+            neighbors: HashMap::new(),
+            lost: HashSet::new(),
             graph: UnGraphMap::new(),
             id2ip: RefCell::new(HashMap::new()),
             ip2id: RefCell::new(HashMap::new()),
+            id2ip6: HashMap::new(),
+            ip62id: HashMap::new(),
+            linkstate: None,
+            gateways: None,
+            defaultgateways: HashMap::new(),
+            networktimeout: None,
+            stale: HashMap::new(),
+            tombstones: HashMap::new(),
             isgateway
         }
     }
 
+    /// Route by link-state advertisements instead of the observed graph
+    pub fn enable_linkstate(&mut self, maxage: Duration) {
+        self.linkstate = Some(LinkStateDb::new(maxage));
+    }
+
+    /// The link-state database, if running in link-state mode
+    pub fn linkstate(&mut self) -> Option<&mut LinkStateDb> {
+        self.linkstate.as_mut()
+    }
+
+    /// Pick the gateway by announcements, forgetting those silent for `timeout`
+    /// and demoting those our frames failed to reach `maxfailures` times
+    pub fn track_gateways(&mut self, timeout: Duration, maxfailures: u32) {
+        self.gateways = Some(GatewayTable::new(timeout).with_max_failures(maxfailures));
+    }
+
+    /// The gateways heard from, if tracking them
+    pub fn gateways(&mut self) -> Option<&mut GatewayTable> {
+        self.gateways.as_mut()
+    }
+
+    /// Send traffic with no route of its own to the gateways announcing they reach the internet,
+    /// forgetting those silent for `timeout`
+    pub fn track_networks(&mut self, timeout: Duration) {
+        self.networktimeout = Some(timeout);
+    }
+
+    /// Record a gateway's network announcement, reaching us at `metric`
+    pub fn handle_network_announce(&mut self, gateway_id: u8, reachable: bool, metric: u16) {
+        self.handle_network_announce_at(gateway_id, reachable, metric, Instant::now())
+    }
+
+    pub(crate) fn handle_network_announce_at(&mut self, gateway_id: u8, reachable: bool, metric: u16, now: Instant) {
+        if !reachable {
+            self.defaultgateways.remove(&gateway_id);
+            return;
+        }
+        self.defaultgateways.insert(gateway_id, DefaultGateway { gateway_id, metric, last_announced: now });
+    }
+
+    /// A frame to a default gateway failed on the way, it is not used again until it announces itself
+    pub fn default_gateway_failed(&mut self, gateway_id: u8) {
+        self.defaultgateways.remove(&gateway_id);
+    }
+
+    /// The gateway reaching the internet at the lowest metric, if any announced it lately
+    pub fn default_gateway(&self) -> Option<&DefaultGateway> {
+        self.default_gateway_at(Instant::now())
+    }
+
+    pub(crate) fn default_gateway_at(&self, now: Instant) -> Option<&DefaultGateway> {
+        let timeout = self.networktimeout?;
+        self.defaultgateways.values()
+            .filter(|gateway| gateway.gateway_id != self.nodeid && now.duration_since(gateway.last_announced) <= timeout)
+            .min_by_key(|gateway| (gateway.metric, gateway.gateway_id))
+    }
+
+    /// Every link we know of with its cost, ETX in link-state mode and
+    /// hop count otherwise
+    pub fn topology(&self) -> Vec<(u8, u8, f32)> {
+        match &self.linkstate {
+            Some(db) => db.edges(),
+            None => {
+                let mut edges: Vec<(u8, u8, f32)> = self.graph.all_edges()
+                    .map(|(a, b, weight)| (a.min(b), a.max(b), *weight as f32))
+                    .collect();
+                edges.sort_by_key(|(a, b, _)| (*a, *b));
+                edges
+            }
+        }
+    }
+
+    /// What we know about the mesh, to be restored after a restart
+    pub fn state(&self) -> MeshState {
+        let mut state = MeshState { version: MESH_STATE_VERSION, ..MeshState::default() };
+        for nodeid in self.graph.nodes().filter(|id| *id != self.nodeid) {
+            state.nodes.insert(nodeid, None);
+        }
+        for (nodeid, ipaddr) in self.id2ip.borrow().iter().filter(|(id, _)| **id != self.nodeid) {
+            state.nodes.insert(*nodeid, Some(*ipaddr));
+        }
+        state.links = self.graph.all_edges().map(|(a, b, _)| (a.min(b), a.max(b))).collect();
+        state.links.sort();
+        state.gateway = self.gatewayipaddr;
+        if let Some(gateways) = &self.gateways {
+            state.gateways = gateways.ranked().into_iter().map(|(nodeid, entry)| (nodeid, entry.uplink)).collect();
+        }
+        state
+    }
+
+    /// Take back the state saved before a restart, returning the stale
+    /// route to every node, none of them are used until confirmed
+    pub fn restore(&mut self, state: MeshState) -> Vec<(u8, Vec<u8>)> {
+        self.restore_at(state, Instant::now())
+    }
+
+    pub(crate) fn restore_at(&mut self, state: MeshState, now: Instant) -> Vec<(u8, Vec<u8>)> {
+        for (nodeid, ipaddr) in state.nodes.iter() {
+            if let Some(ipaddr) = ipaddr {
+                self.ip_add(*nodeid, *ipaddr);
+            }
+        }
+        if self.gatewayipaddr.is_none() && !self.isgateway {
+            self.gatewayipaddr = state.gateway;
+        }
+        if let Some(gateways) = self.gateways.as_mut() {
+            for (nodeid, uplink) in state.gateways.iter() {
+                gateways.restore_at(*nodeid, *uplink, now);
+            }
+        }
+        let mut graph: UnGraphMap<u8, u8> = UnGraphMap::new();
+        for (a, b) in state.links.iter() {
+            graph.add_edge(*a, *b, 1);
+        }
+        let ourid = self.nodeid;
+        let mut routes = Vec::new();
+        for nodeid in state.nodes.keys().filter(|id| **id != ourid) {
+            if let Some((_, path)) = astar(&graph, ourid, |finish| finish == *nodeid, |e| *e.2, |_| 0) {
+                let route: Vec<u8> = path.into_iter().skip(1).collect();
+                self.stale.insert(*nodeid, (route.clone(), now + STALE_ROUTE_TTL));
+                routes.push((*nodeid, route));
+            }
+        }
+        routes
+    }
+
+    /// Start using the stale route to a node that answered over it,
+    /// along with the routes to the nodes on the way
+    pub fn confirm_stale(&mut self, nodeid: u8) -> bool {
+        let route = match self.stale.remove(&nodeid) {
+            None => return false,
+            Some((route, _)) => route,
+        };
+        self.stale.retain(|_, (other, _)| !route.starts_with(other));
+        let mut path = vec![self.nodeid];
+        path.extend(route);
+        self.path_add(&path);
+        true
+    }
+
+    /// Forget stale routes nobody confirmed in time, returning their destinations
+    pub fn expire_stale(&mut self) -> Vec<u8> {
+        self.expire_stale_at(Instant::now())
+    }
+
+    pub(crate) fn expire_stale_at(&mut self, now: Instant) -> Vec<u8> {
+        let mut expired: Vec<u8> = self.stale.iter()
+            .filter(|(_, (_, expires))| now >= *expires)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.sort();
+        for id in expired.iter() {
+            self.stale.remove(id);
+        }
+        expired
+    }
+
+    /// Take a new node ID, keeping paths through the old one out of the graph for `ttl`
+    /* Other nodes keep routes to the old ID until theirs expire, the frames
+    they send over them would add it back as a node nobody answers for. */
+    pub fn set_nodeid(&mut self, nodeid: u8, ttl: Duration) {
+        self.set_nodeid_at(nodeid, ttl, Instant::now())
+    }
+
+    pub(crate) fn set_nodeid_at(&mut self, nodeid: u8, ttl: Duration, now: Instant) {
+        let old = self.nodeid;
+        self.nodeid = nodeid;
+        self.graph.remove_node(old);
+        self.observations.get_mut().remove(&old);
+        self.tombstones.retain(|_, until| *until > now);
+        self.tombstones.remove(&nodeid);
+        self.tombstones.insert(old, now + ttl);
+        if let Some(ipaddr) = self.id2ip.get_mut().remove(&old) {
+            self.ip_add(nodeid, ipaddr);
+        }
+        if let Some(ipaddr) = self.id2ip6.remove(&old) {
+            self.ip6_add(nodeid, ipaddr);
+        }
+        self.node_add(nodeid);
+    }
+
+    /// true if `nodeid` is one we gave up and paths through it are ignored
+    fn tombstoned(&self, nodeid: u8) -> bool {
+        self.tombstones.get(&nodeid).map_or(false, |until| *until > Instant::now())
+    }
+
     /// Applies a spanning tree algorithm to the mesh graph
     pub fn min_spanning_tree(&mut self) {
         let graph = UnGraphMap::from_elements(min_spanning_tree(&self.graph));
@@ -52,6 +285,9 @@ impl MeshRouter {
 
     /// Adds a new route to the mesh, fail if route does not exist
     pub fn route_add(&mut self, route: Vec<(u8, u8)>) {
+        if route.iter().any(|(src, dest)| self.tombstoned(*src) || self.tombstoned(*dest)) {
+            return;
+        }
         route.iter().for_each( |(src, dest)| {
             // we track each observation of every node
             self.node_observe_put(src.clone());
@@ -69,21 +305,23 @@ impl MeshRouter {
     /// Update our router with new IP
     pub fn handle_ip_assignment(&mut self, ipaddr: &Ipv4Addr) {
         self.node_add(self.nodeid.clone());
-        self.id2ip.borrow_mut().insert(self.nodeid.clone(), ipaddr.clone());
-        self.ip2id.borrow_mut().insert(ipaddr.clone(), self.nodeid.clone());
+        self.ip_add(self.nodeid, ipaddr.clone());
     }
 
     pub fn handle_gateway_assignment(&mut self, gatewayip: &Ipv4Addr) {
         self.gatewayipaddr = Some(gatewayip.clone());
     }
 
-    /// Handle a network broadcast, maybe node needs an IP?
-    pub fn handle_broadcast(&mut self, broadcast: Box<BroadcastMessage>, route: Vec<u8>) -> Result<Option<(Ipv4Addr, bool)>, IPAssignFailureMessage> {
+    /// Handle a network broadcast, learning the sender's IP and route
+    pub fn handle_broadcast(&mut self, broadcast: Box<BroadcastMessage>, route: Vec<u8>) {
         let srcid = broadcast.header.expect("Broadcast did not have a frame header.").sender();
         if broadcast.isgateway && srcid != self.nodeid {
             info!("Gateway {} observed with IP {}", &srcid, &broadcast.ipaddr.expect("Gateways must broadcast their IP"));
             self.handle_gateway_assignment(&broadcast.ipaddr.unwrap());
         }
+        if route.iter().any(|nodeid| self.tombstoned(*nodeid)) {
+            return;
+        }
 
         // observe our latest sighting
         route.iter().for_each(|nodeid| {
@@ -93,8 +331,10 @@ impl MeshRouter {
 
         // add IP to graph
         if broadcast.ipaddr.is_some() {
-            self.id2ip.borrow_mut().insert(srcid.clone(), broadcast.ipaddr.unwrap());
-            self.ip2id.borrow_mut().insert(broadcast.ipaddr.unwrap(), srcid.clone());
+            self.ip_add(srcid, broadcast.ipaddr.unwrap());
+        }
+        if let Some(ipaddr) = broadcast.ipv6addr {
+            self.ip6_add(srcid, ipaddr);
         }
 
         // add edges for each node in the route
@@ -102,28 +342,31 @@ impl MeshRouter {
 
         // add edge for ourself
         self.edge_add(self.nodeid, route.last().expect("Received broadcast with empty route").clone());
+    }
 
-        let mut ipaddrtup = None;
-        if broadcast.ipOffset == 0 && self.isgateway {
-            ipaddrtup = Some(self.ip_assign(srcid)?);
+    /// Map an IP address to a node, replacing the node's previous address
+    pub fn ip_add(&mut self, nodeid: u8, ipaddr: Ipv4Addr) {
+        if let Some(old) = self.id2ip.get_mut().insert(nodeid, ipaddr) {
+            if old != ipaddr {
+                self.ip2id.get_mut().remove(&old);
+            }
         }
-        return Ok(ipaddrtup);
+        self.ip2id.get_mut().insert(ipaddr, nodeid);
     }
 
-    /// Assign IP address to node
-    // TODO implement proper DHCP later
-    fn ip_assign(&mut self, nodeid: u8) -> Result<(Ipv4Addr, bool), IPAssignFailureMessage> {
-        match self.id2ip.get_mut().get(&nodeid) {
-            None => {
-                let ipaddr = Ipv4Addr::new(172,16,0, nodeid);
-                self.id2ip.get_mut().insert(nodeid, ipaddr);
-                self.ip2id.get_mut().insert(ipaddr, nodeid);
-                return Ok((ipaddr, true));
-            },
-            Some(ip) => {
-                return Ok((ip.clone(), false));
+    /// Map an IPv6 address to a node, replacing the node's previous address
+    pub fn ip6_add(&mut self, nodeid: u8, ipaddr: Ipv6Addr) {
+        if let Some(old) = self.id2ip6.insert(nodeid, ipaddr) {
+            if old != ipaddr {
+                self.ip62id.remove(&old);
             }
         }
+        self.ip62id.insert(ipaddr, nodeid);
+    }
+
+    /// The node with an IPv6 address, if it announced one
+    pub fn ip6_node(&self, ipaddr: &Ipv6Addr) -> Option<u8> {
+        self.ip62id.get(ipaddr).cloned()
     }
 
     /// Track each node observation for routing purposes
@@ -135,6 +378,58 @@ impl MeshRouter {
         self.observations.get_mut().get(nodeid)
     }
 
+    /// Record proof of life from a direct neighbor
+    /* Both broadcasts and heartbeats count */
+    pub fn neighbor_seen(&mut self, nodeid: u8) {
+        self.neighbor_seen_at(nodeid, Instant::now());
+    }
+
+    fn neighbor_seen_at(&mut self, nodeid: u8, now: Instant) {
+        if nodeid != self.nodeid {
+            self.neighbors.insert(nodeid, now);
+            self.lost.remove(&nodeid);
+        }
+    }
+
+    /// Forget neighbors not heard from within `maxage`, returning their IDs
+    pub fn expire_neighbors(&mut self, maxage: Duration) -> Vec<u8> {
+        self.expire_neighbors_at(maxage, Instant::now())
+    }
+
+    fn expire_neighbors_at(&mut self, maxage: Duration, now: Instant) -> Vec<u8> {
+        let expired: Vec<u8> = self.neighbors.iter()
+            .filter(|(_, seen)| now.duration_since(**seen) > maxage)
+            .map(|(id, _)| id.clone())
+            .collect();
+        // routes through an expired neighbor are dead
+        for id in expired.iter() {
+            self.neighbors.remove(id);
+            self.lost.insert(id.clone());
+            self.graph.remove_edge(self.nodeid, id.clone());
+        }
+        return expired;
+    }
+
+    /// true if a neighbor expired and was not heard from since
+    pub fn neighbor_lost(&self, nodeid: u8) -> bool {
+        self.lost.contains(&nodeid)
+    }
+
+    /// Drop a broken link from the mesh graph
+    pub fn link_remove(&mut self, src: u8, dest: u8) {
+        self.graph.remove_edge(src, dest);
+    }
+
+    /// Add every link along a path that was found to work
+    pub fn path_add(&mut self, path: &[u8]) {
+        self.route_add(path.windows(2).map(|pair| (pair[0], pair[1])).collect());
+    }
+
+    /// IDs of neighbors currently considered alive
+    pub fn neighbors(&self) -> Vec<u8> {
+        self.neighbors.keys().cloned().collect()
+    }
+
     fn edge_add(&mut self, src: u8, dest: u8) {
         self.graph.add_edge(src.clone(), dest.clone(), 1);
     }
@@ -149,25 +444,208 @@ impl MeshRouter {
         self.graph.borrow_mut().remove_node(nodeid);
     }
 
-    /// Routes an IP packet to a node in the mesh, if it's possible
-    pub fn packet_route(&mut self, packet: &Packet<Vec<u8>>) -> Option<Vec<u8>> {
+    /// The node an IP packet goes to, the one with its address or else the gateway
+    /// returns None if it leaves the mesh through us or there is no gateway
+    pub fn packet_dest(&self, packet: &IpPacket) -> Option<u8> {
         trace!("Routing packet from {} to {}", &packet.source(), &packet.destination());
+        let dest = match packet {
+            IpPacket::V4(packet) => self.ip2id.borrow().get(&packet.destination()).cloned(),
+            IpPacket::V6(packet) => self.ip6_node(&packet.destination()),
+        };
+        match dest.or_else(|| self.gateway_id()) {
+            Some(dest) if dest != self.nodeid => Some(dest),
+            _ => None,
+        }
+    }
 
-        // look up ip and ensure it's in our mesh
-        let ip2id = self.ip2id.borrow_mut();
-        let src = ip2id.get(&packet.source())?;
-        let dest = ip2id.get(&packet.destination())?;
-        trace!("Found node route source {:?} destination {:?}", &src, &dest);
+    /// Node ID of the internet gateway, if we know of one
+    pub fn gateway_id(&self) -> Option<u8> {
+        if self.isgateway {
+            return Some(self.nodeid);
+        }
+        if let Some(gateway) = self.default_gateway() {
+            return Some(gateway.gateway_id);
+        }
+        if let Some(best) = self.gateways.as_ref().and_then(|gateways| gateways.best()) {
+            return Some(best);
+        }
+        self.gatewayipaddr.and_then(|ip| self.ip2id.borrow().get(&ip).cloned())
+    }
 
+    /// Hops to reach a node, excluding ourselves, if it's in our mesh
+    pub fn node_route(&self, dest: u8) -> Option<Vec<u8>> {
+        if let Some(db) = &self.linkstate {
+            return db.route(self.nodeid, dest);
+        }
+        if !self.graph.contains_node(self.nodeid) || !self.graph.contains_node(dest) {
+            return None;
+        }
         match astar(
             &self.graph,
-            src.clone(),
-            |finish| finish == dest.clone(),
+            self.nodeid.clone(),
+            |finish| finish == dest,
             |e| e.1,
             |_e| 0,
         ) {
             None => None,
-            Some(aresult) => Some(aresult.1)
+            Some(aresult) => Some(aresult.1.into_iter().skip(1).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn neighbor_expiry() {
+    let start = Instant::now();
+    let timeout = Duration::from_secs(45);
+    let mut router = MeshRouter::new(1, None, 2, Duration::from_secs(10), false);
+
+    // node 2 heartbeats every 15 seconds, node 3 goes silent after its broadcast
+    router.neighbor_seen_at(3, start);
+    for i in 0..8u64 {
+        let now = start + Duration::from_secs(i * 15);
+        router.neighbor_seen_at(2, now);
+        let expired = router.expire_neighbors_at(timeout, now);
+        if i == 4 {
+            assert_eq!(expired, vec![3]);
+        } else {
+            assert!(expired.is_empty());
         }
     }
-}
\ No newline at end of file
+    assert_eq!(router.neighbors(), vec![2]);
+
+    // our own frames don't make us a neighbor
+    router.neighbor_seen_at(1, start);
+    assert_eq!(router.neighbors(), vec![2]);
+}
+
+#[test]
+fn node_route_hops() {
+    let mut router = MeshRouter::new(1, None, 2, Duration::from_secs(10), false);
+    router.route_add(vec![(1, 2), (2, 3)]);
+    assert_eq!(router.node_route(3), Some(vec![2, 3]));
+    assert_eq!(router.node_route(2), Some(vec![2]));
+    assert_eq!(router.node_route(9), None);
+}
+
+#[test]
+fn nodeid_change() {
+    let start = Instant::now();
+    let ttl = Duration::from_secs(300);
+    let mut router = MeshRouter::new(1, None, 2, Duration::from_secs(10), false);
+    router.handle_ip_assignment(&Ipv4Addr::new(172, 16, 0, 5));
+    router.route_add(vec![(1, 2), (2, 3)]);
+    router.set_nodeid_at(9, ttl, start);
+
+    // the graph only knows the old ID, paths have to be learned again
+    assert_eq!(router.node_route(3), None);
+    assert!(router.state().nodes.get(&1).is_none());
+    assert_eq!(router.id2ip.borrow().get(&9), Some(&Ipv4Addr::new(172, 16, 0, 5)));
+
+    // paths through the old ID are ignored, new ones are taken
+    router.route_add(vec![(2, 1), (1, 3)]);
+    assert_eq!(router.node_route(3), None);
+    router.route_add(vec![(9, 2), (2, 3)]);
+    assert_eq!(router.node_route(3), Some(vec![2, 3]));
+
+    // until another node takes the old ID once the tombstone is gone
+    router.tombstones.insert(1, start);
+    router.route_add(vec![(2, 1)]);
+    assert_eq!(router.node_route(1), Some(vec![2, 1]));
+}
+
+#[test]
+fn route_error_link() {
+    use crate::stack::limiter::KeyedLimiter;
+
+    let start = Instant::now();
+    let timeout = Duration::from_secs(45);
+    let mut origin = MeshRouter::new(1, None, 3, Duration::from_secs(10), false);
+    origin.route_add(vec![(1, 2), (2, 3), (3, 4)]);
+    assert_eq!(origin.node_route(4), Some(vec![2, 3, 4]));
+
+    // node 3 dies and node 2 stops hearing it
+    let mut relay = MeshRouter::new(2, None, 3, Duration::from_secs(10), false);
+    relay.neighbor_seen_at(3, start);
+    let later = start + timeout * 2;
+    assert_eq!(relay.expire_neighbors_at(timeout, later), vec![3]);
+    assert!(relay.neighbor_lost(3));
+
+    // repeated failures send a single error
+    let mut errors = KeyedLimiter::new(Duration::from_secs(30));
+    let sent = (0..3u64).filter(|i| errors.allow_at((2u8, 3u8), later + Duration::from_secs(*i))).count();
+    assert_eq!(sent, 1);
+
+    // which makes the origin drop the link
+    origin.link_remove(2, 3);
+    assert_eq!(origin.node_route(4), None);
+}
+
+#[test]
+fn ipv6_packet_dest() {
+    use crate::stack::Frame;
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::ipv6::{ipv6_node_addr, Ipv6Packet};
+
+    let prefix: Ipv6Addr = "fd6c:6d00::".parse().unwrap();
+    let packet = |dest: Ipv6Addr| {
+        let mut bytes = vec![0x60, 0, 0, 0, 0, 4, 17, 64];
+        bytes.extend_from_slice(&ipv6_node_addr(prefix, 1).octets());
+        bytes.extend_from_slice(&dest.octets());
+        bytes.extend_from_slice(&[1, 2, 3, 4]);
+        IpPacket::V6(Ipv6Packet::new(bytes).unwrap())
+    };
+    let mut router = MeshRouter::new(1, None, 3, Duration::from_secs(10), false);
+    router.ip6_add(1, ipv6_node_addr(prefix, 1));
+
+    // node 3 announces its address in a broadcast relayed by 2
+    let broadcast = BroadcastMessage {
+        header: None,
+        isgateway: false,
+        ipOffset: 0,
+        ipaddr: None,
+        ipv6addr: Some(ipv6_node_addr(prefix, 3)),
+        custom_tlv: Vec::new()
+    };
+    let mut frame = Frame::from_bytes(&broadcast.to_frame(4, 3, vec![2, 3]).unwrap().to_bytes()).unwrap();
+    let route = frame.route();
+    router.handle_broadcast(BroadcastMessage::from_frame(&mut frame).unwrap(), route);
+    assert_eq!(router.ip6_node(&ipv6_node_addr(prefix, 3)), Some(3));
+    assert_eq!(router.packet_dest(&packet(ipv6_node_addr(prefix, 3))), Some(3));
+
+    // unknown addresses go to the gateway, there is none yet, and ours stay here
+    assert_eq!(router.packet_dest(&packet(ipv6_node_addr(prefix, 9))), None);
+    assert_eq!(router.packet_dest(&packet(ipv6_node_addr(prefix, 1))), None);
+    router.track_gateways(Duration::from_secs(60), 3);
+    router.gateways().unwrap().update(2, true, 0, 1.0);
+    assert_eq!(router.packet_dest(&packet("2001:db8::1".parse().unwrap())), Some(2));
+
+    // the address follows its node to a new ID
+    router.set_nodeid_at(5, Duration::from_secs(300), Instant::now());
+    assert_eq!(router.ip6_node(&ipv6_node_addr(prefix, 1)), Some(5));
+}
+
+#[test]
+fn default_gateway_metric() {
+    let now = Instant::now();
+    let mut router = MeshRouter::new(5, None, 4, Duration::from_secs(10), false);
+    router.handle_network_announce_at(1, true, 200, now);
+    // announcements only count once tracked
+    assert_eq!(router.default_gateway_at(now), None);
+    router.track_networks(Duration::from_secs(180));
+    assert_eq!(router.default_gateway_at(now).map(|gateway| gateway.gateway_id), Some(1));
+
+    // the lowest metric wins, a gateway losing its uplink drops out at once
+    router.handle_network_announce_at(2, true, 120, now);
+    assert_eq!(router.default_gateway_at(now).map(|gateway| (gateway.gateway_id, gateway.metric)), Some((2, 120)));
+    router.handle_network_announce_at(2, false, 120, now + Duration::from_secs(5));
+    assert_eq!(router.default_gateway_at(now + Duration::from_secs(5)).map(|gateway| gateway.gateway_id), Some(1));
+    assert_eq!(router.gateway_id(), Some(1));
+
+    // one no longer announcing is forgotten
+    router.handle_network_announce_at(2, true, 120, now + Duration::from_secs(100));
+    assert_eq!(router.default_gateway_at(now + Duration::from_secs(200)).map(|gateway| gateway.gateway_id), Some(2));
+    assert_eq!(router.default_gateway_at(now + Duration::from_secs(300)), None);
+    router.default_gateway_failed(2);
+    assert_eq!(router.default_gateway_at(now + Duration::from_secs(200)), None);
+}
@@ -0,0 +1,535 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::hardware::lostik::mkerror;
+use crate::stack::frame::NodeId;
+use crate::stack::mesh_state::STALE_ROUTE_TTL;
+use crate::stack::util::unix_millis;
+
+/// Where a route came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RouteSource {
+    /// answer to a route discovery
+    Discovered,
+    /// overheard from the path a flood took
+    Learned,
+    /// from the settings, never expires
+    Static,
+}
+
+impl RouteSource {
+    /// rank among routes of equal cost and length, lowest first
+    fn rank(&self) -> u8 {
+        match self {
+            RouteSource::Static => 0,
+            RouteSource::Discovered => 1,
+            RouteSource::Learned => 2,
+        }
+    }
+}
+
+/// One way of reaching a node
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteEntry {
+    /// hops excluding ourselves, ending at the destination
+    pub path: Vec<NodeId>,
+    /// sum of the ETX of the links along the path
+    pub cost: f32,
+    pub source: RouteSource,
+    pub lastused: Option<Instant>,
+    /// None for a route read from disk that nothing has confirmed since
+    pub confirmed: Option<Instant>,
+    /// last time the route was added, confirmed or restored
+    updated: Instant,
+    /// last time a probe was sent along the route
+    probed: Option<Instant>,
+    /// when a route still valid before a restart was read back, until it is confirmed again
+    stale: Option<Instant>,
+}
+
+impl RouteEntry {
+    pub fn hops(&self) -> usize {
+        self.path.len()
+    }
+
+    /// true if the route may be used: static, or confirmed and not expired
+    fn valid_at(&self, now: Instant, maxage: Duration) -> bool {
+        self.source == RouteSource::Static
+            || (self.confirmed.is_some() && now.duration_since(self.updated) < maxage)
+    }
+
+    /// true if traffic went over the route within `maxage`
+    fn active_at(&self, now: Instant, maxage: Duration) -> bool {
+        self.lastused.map_or(false, |used| now.duration_since(used) < maxage)
+    }
+
+    /// lower is better: cost, then hops, then source, then the most recently confirmed
+    fn preference(&self, other: &RouteEntry) -> Ordering {
+        self.cost.partial_cmp(&other.cost).unwrap_or(Ordering::Equal)
+            .then(self.hops().cmp(&other.hops()))
+            .then(self.source.rank().cmp(&other.source.rank()))
+            .then(other.confirmed.cmp(&self.confirmed))
+    }
+}
+
+/// A route as kept on disk
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct SavedRoute {
+    path: Vec<NodeId>,
+    cost: f32,
+    source: RouteSource,
+    /// unix time in ms the route stops being valid, None if it was not confirmed
+    #[serde(default)]
+    validuntil: Option<u64>,
+}
+
+/// Parse static routes written as `dest:hop,hop,...` separated by `;`
+/* The path excludes ourselves and ends at the destination, `7:3,7` sends
+frames for node 7 through node 3. */
+pub fn parse_static_routes(list: &str) -> io::Result<Vec<(NodeId, Vec<NodeId>)>> {
+    list.split(';')
+        .map(|route| route.trim())
+        .filter(|route| !route.is_empty())
+        .map(|route| {
+            let invalid = || mkerror(&format!("invalid static route: {}", route));
+            let mut parts = route.splitn(2, ':');
+            let dest: NodeId = parts.next().unwrap_or("").trim().parse().map_err(|_| invalid())?;
+            let path = parts.next().ok_or_else(invalid)?
+                .split(',')
+                .map(|hop| hop.trim().parse::<NodeId>().map_err(|_| invalid()))
+                .collect::<io::Result<Vec<NodeId>>>()?;
+            if path.last() != Some(&dest) {
+                return Err(mkerror(&format!("static route to {} must end at {}", dest, dest)));
+            }
+            Ok((dest, path))
+        })
+        .collect()
+}
+
+/// true if a path names a node more than once
+pub fn path_loops(path: &[NodeId]) -> bool {
+    path.iter().enumerate().any(|(i, nodeid)| path[i + 1..].contains(nodeid))
+}
+
+/// Candidate routes to every destination with their metrics
+/* Routes that are neither static nor confirmed within `maxage` are
+never used and dropped on the next expiry. Routes read back from disk are
+kept but not used until something confirms them, or dropped once `maxage`
+passes without that. Those that were still valid when saved are used
+right away until they would have expired, but are dropped unless
+confirmed within `STALE_ROUTE_TTL`, so a restart needs no rediscovery. */
+#[derive(Clone, Debug)]
+pub struct RoutingTable {
+    maxage: Duration,
+    routes: BTreeMap<NodeId, Vec<RouteEntry>>,
+    path: Option<PathBuf>,
+}
+
+impl RoutingTable {
+    pub fn new(maxage: Duration) -> Self {
+        RoutingTable { maxage, routes: BTreeMap::new(), path: None }
+    }
+
+    /// Add a route, or refresh it and its cost if it is already known
+    pub fn add(&mut self, dest: NodeId, path: Vec<NodeId>, cost: f32, source: RouteSource) {
+        self.add_at(dest, path, cost, source, Instant::now())
+    }
+
+    pub(crate) fn add_at(&mut self, dest: NodeId, path: Vec<NodeId>, cost: f32, source: RouteSource, now: Instant) {
+        // a path visiting a node twice would send frames round in a loop
+        if path.last() != Some(&dest) || path_loops(&path) {
+            return;
+        }
+        let entries = self.routes.entry(dest).or_insert_with(Vec::new);
+        match entries.iter_mut().find(|entry| entry.path == path) {
+            // a static route keeps its source
+            Some(entry) => {
+                entry.cost = cost;
+                entry.confirmed = Some(now);
+                entry.updated = now;
+                entry.stale = None;
+                if entry.source != RouteSource::Static {
+                    entry.source = source;
+                }
+            },
+            None => entries.push(RouteEntry { path, cost, source, lastused: None, confirmed: Some(now), updated: now, probed: None, stale: None }),
+        }
+    }
+
+    /// Add a route from the settings, its cost is its hop count
+    pub fn add_static(&mut self, dest: NodeId, path: Vec<NodeId>) {
+        let cost = path.len() as f32;
+        self.add(dest, path, cost, RouteSource::Static);
+    }
+
+    /// A node answered, so the routes to it work
+    pub fn confirm(&mut self, dest: NodeId) {
+        self.confirm_at(dest, Instant::now())
+    }
+
+    pub(crate) fn confirm_at(&mut self, dest: NodeId, now: Instant) {
+        for entry in self.routes.get_mut(&dest).into_iter().flatten() {
+            entry.confirmed = Some(now);
+            entry.updated = now;
+            entry.stale = None;
+        }
+    }
+
+    /// Traffic over the route last used to a node got through, so it lives on
+    pub fn refresh(&mut self, dest: NodeId) {
+        self.refresh_at(dest, Instant::now())
+    }
+
+    pub(crate) fn refresh_at(&mut self, dest: NodeId, now: Instant) {
+        let maxage = self.maxage;
+        let used = self.routes.get_mut(&dest).into_iter().flatten()
+            .filter(|entry| entry.valid_at(now, maxage) && entry.lastused.is_some())
+            .max_by_key(|entry| entry.lastused);
+        if let Some(entry) = used {
+            entry.updated = now;
+            entry.confirmed = Some(now);
+            entry.stale = None;
+        }
+    }
+
+    /// The best valid route to a node
+    pub fn best(&self, dest: NodeId) -> Option<&RouteEntry> {
+        self.best_at(dest, Instant::now())
+    }
+
+    pub(crate) fn best_at(&self, dest: NodeId, now: Instant) -> Option<&RouteEntry> {
+        self.routes.get(&dest)?.iter()
+            .filter(|entry| entry.valid_at(now, self.maxage))
+            .min_by(|a, b| a.preference(b))
+    }
+
+    /// The path of the best route to a node, noting that it was used
+    pub fn route(&mut self, dest: NodeId) -> Option<Vec<NodeId>> {
+        self.route_at(dest, Instant::now())
+    }
+
+    pub(crate) fn route_at(&mut self, dest: NodeId, now: Instant) -> Option<Vec<NodeId>> {
+        let best = self.best_at(dest, now)?.path.clone();
+        let entry = self.routes.get_mut(&dest)?.iter_mut().find(|entry| entry.path == best)?;
+        entry.lastused = Some(now);
+        Some(best)
+    }
+
+    /// Drop the routes leaving through a neighbor that is gone, static ones stay
+    /// Returns the destinations in use that have no route left, to be rediscovered
+    pub fn remove_via(&mut self, nexthop: NodeId) -> Vec<NodeId> {
+        self.remove_via_at(nexthop, Instant::now())
+    }
+
+    pub(crate) fn remove_via_at(&mut self, nexthop: NodeId, now: Instant) -> Vec<NodeId> {
+        self.purge(now, |entry| entry.source != RouteSource::Static && entry.path.first() == Some(&nexthop))
+    }
+
+    /// Drop the routes over a link further down their path that a relay found broken
+    /// Returns the destinations in use that have no route left, to be rediscovered
+    pub fn remove_link(&mut self, from: NodeId, to: NodeId) -> Vec<NodeId> {
+        self.remove_link_at(from, to, Instant::now())
+    }
+
+    pub(crate) fn remove_link_at(&mut self, from: NodeId, to: NodeId, now: Instant) -> Vec<NodeId> {
+        self.purge(now, |entry| entry.source != RouteSource::Static && entry.path.windows(2).any(|link| link == [from, to]))
+    }
+
+    /// Drop the routes to or through nodes that went silent, static ones stay
+    /// Returns the destinations in use that have no route left
+    pub fn remove_through(&mut self, nodes: &[NodeId]) -> Vec<NodeId> {
+        self.remove_through_at(nodes, Instant::now())
+    }
+
+    pub(crate) fn remove_through_at(&mut self, nodes: &[NodeId], now: Instant) -> Vec<NodeId> {
+        self.purge(now, |entry| entry.source != RouteSource::Static && entry.path.iter().any(|nodeid| nodes.contains(nodeid)))
+    }
+
+    /// Drop every route that is not static, the paths no longer hold
+    pub fn clear(&mut self) {
+        for entries in self.routes.values_mut() {
+            entries.retain(|entry| entry.source == RouteSource::Static);
+        }
+        self.routes.retain(|_, entries| !entries.is_empty());
+    }
+
+    /// Drop routes that were not confirmed within the maximum age
+    /// Returns the destinations in use that have no route left, to be rediscovered
+    pub fn expire(&mut self) -> Vec<NodeId> {
+        self.expire_at(Instant::now())
+    }
+
+    pub(crate) fn expire_at(&mut self, now: Instant) -> Vec<NodeId> {
+        let maxage = self.maxage;
+        self.purge(now, |entry| entry.source != RouteSource::Static
+            && (now.duration_since(entry.updated) >= maxage
+                || entry.stale.map_or(false, |restored| now.duration_since(restored) >= STALE_ROUTE_TTL)))
+    }
+
+    fn purge<F: Fn(&RouteEntry) -> bool>(&mut self, now: Instant, dead: F) -> Vec<NodeId> {
+        let maxage = self.maxage;
+        let mut orphaned = Vec::new();
+        for (dest, entries) in self.routes.iter_mut() {
+            let active = entries.iter().any(|entry| dead(entry) && entry.active_at(now, maxage));
+            entries.retain(|entry| !dead(entry));
+            if active && !entries.iter().any(|entry| entry.valid_at(now, maxage)) {
+                orphaned.push(*dest);
+            }
+        }
+        self.routes.retain(|_, entries| !entries.is_empty());
+        orphaned
+    }
+
+    /// Routes in use that expire within `before`, each returned once until it is confirmed again
+    /* Probing them while they still work confirms them before traffic over
+    them starts failing. */
+    pub fn probes_due(&mut self, before: Duration) -> Vec<(NodeId, Vec<NodeId>)> {
+        self.probes_due_at(before, Instant::now())
+    }
+
+    pub(crate) fn probes_due_at(&mut self, before: Duration, now: Instant) -> Vec<(NodeId, Vec<NodeId>)> {
+        let maxage = self.maxage;
+        let mut due = Vec::new();
+        for (dest, entries) in self.routes.iter_mut() {
+            for entry in entries.iter_mut() {
+                if entry.source == RouteSource::Static || !entry.valid_at(now, maxage) || !entry.active_at(now, maxage) {
+                    continue;
+                }
+                let expires = entry.updated + maxage;
+                if expires.saturating_duration_since(now) <= before && entry.probed.map_or(true, |probed| probed < entry.updated) {
+                    entry.probed = Some(now);
+                    due.push((*dest, entry.path.clone()));
+                }
+            }
+        }
+        due
+    }
+
+    /// Destinations with routes that wait for confirmation, and the best of them
+    pub fn unconfirmed(&self) -> Vec<(NodeId, Vec<NodeId>)> {
+        self.routes.iter()
+            .filter_map(|(dest, entries)| {
+                entries.iter()
+                    .filter(|entry| entry.confirmed.is_none() || entry.stale.is_some())
+                    .min_by(|a, b| a.preference(b))
+                    .map(|entry| (*dest, entry.path.clone()))
+            })
+            .collect()
+    }
+
+    /// Every route, by destination and then preference
+    pub fn entries(&self) -> Vec<(NodeId, &RouteEntry)> {
+        let mut all = Vec::new();
+        for (dest, entries) in self.routes.iter() {
+            let mut sorted: Vec<&RouteEntry> = entries.iter().collect();
+            sorted.sort_by(|a, b| b.confirmed.is_some().cmp(&a.confirmed.is_some()).then(a.preference(b)));
+            all.extend(sorted.into_iter().map(|entry| (*dest, entry)));
+        }
+        all
+    }
+
+    /// Keep the table in a file, reading the routes already in it as unconfirmed
+    pub fn load(&mut self, path: &Path) -> io::Result<usize> {
+        self.load_at(path, Instant::now(), unix_millis())
+    }
+
+    pub(crate) fn load_at(&mut self, path: &Path, now: Instant, unixnow: u64) -> io::Result<usize> {
+        self.path = Some(path.to_path_buf());
+        let saved: BTreeMap<NodeId, Vec<SavedRoute>> = match fs::read_to_string(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+            Ok(json) => serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        };
+        let mut restored = 0;
+        for (dest, routes) in saved {
+            let entries = self.routes.entry(dest).or_insert_with(Vec::new);
+            // static routes come from the settings, not the file
+            for route in routes.into_iter().filter(|route| route.source != RouteSource::Static) {
+                if route.path.last() == Some(&dest) && !entries.iter().any(|entry| entry.path == route.path) {
+                    // a route still valid keeps the time it had left
+                    let (confirmed, updated, stale) = match route.validuntil.filter(|until| *until > unixnow) {
+                        None => (None, now, None),
+                        Some(until) => {
+                            let left = Duration::from_millis(until - unixnow).min(self.maxage);
+                            let updated = now.checked_sub(self.maxage - left).unwrap_or(now);
+                            (Some(updated), updated, Some(now))
+                        },
+                    };
+                    entries.push(RouteEntry {
+                        path: route.path,
+                        cost: route.cost,
+                        source: route.source,
+                        lastused: None,
+                        confirmed,
+                        updated,
+                        probed: None,
+                        stale,
+                    });
+                    restored += 1;
+                }
+            }
+        }
+        self.routes.retain(|_, entries| !entries.is_empty());
+        Ok(restored)
+    }
+
+    /// Write the table to its file, if it has one
+    pub fn save(&self) -> io::Result<()> {
+        self.save_at(Instant::now(), unix_millis())
+    }
+
+    pub(crate) fn save_at(&self, now: Instant, unixnow: u64) -> io::Result<()> {
+        let maxage = self.maxage;
+        let path = match &self.path {
+            None => return Ok(()),
+            Some(path) => path,
+        };
+        let saved: BTreeMap<NodeId, Vec<SavedRoute>> = self.routes.iter()
+            .map(|(dest, entries)| (*dest, entries.iter()
+                .map(|entry| SavedRoute {
+                    path: entry.path.clone(),
+                    cost: entry.cost,
+                    source: entry.source,
+                    validuntil: match entry.confirmed {
+                        Some(_) if entry.valid_at(now, maxage) => {
+                            Some(unixnow + (entry.updated + maxage).saturating_duration_since(now).as_millis() as u64)
+                        },
+                        _ => None,
+                    },
+                })
+                .collect()))
+            .collect();
+        let json = serde_json::to_string(&saved).expect("Routing table is always serializable");
+        // a crash while writing leaves the old table intact
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn routing_table_preference() {
+    let start = Instant::now();
+    let mut table = RoutingTable::new(Duration::from_secs(600));
+
+    // a direct link losing half the frames each way against two clean hops
+    table.add_at(7, vec![7], 4.0, RouteSource::Learned, start);
+    table.add_at(7, vec![3, 7], 2.2, RouteSource::Discovered, start);
+    assert_eq!(table.route_at(7, start), Some(vec![3, 7]));
+    assert_eq!(table.best(7).unwrap().lastused, Some(start));
+
+    // the link recovers, the short route wins
+    table.add_at(7, vec![7], 1.1, RouteSource::Learned, start);
+    assert_eq!(table.best(7).unwrap().path, vec![7]);
+
+    // equal cost goes to fewer hops, then to the better source
+    table.add_at(9, vec![3, 5, 9], 3.0, RouteSource::Discovered, start);
+    table.add_at(9, vec![4, 9], 3.0, RouteSource::Learned, start);
+    assert_eq!(table.best(9).unwrap().path, vec![4, 9]);
+    table.add_at(9, vec![5, 9], 3.0, RouteSource::Discovered, start);
+    assert_eq!(table.best(9).unwrap().path, vec![5, 9]);
+
+    // routes through a lost neighbor go, paths must end at the destination
+    assert!(table.remove_via_at(5, start).is_empty());
+    assert_eq!(table.best(9).unwrap().path, vec![4, 9]);
+    table.add_at(9, vec![4], 1.0, RouteSource::Learned, start);
+    assert_eq!(table.entries().iter().filter(|(dest, _)| *dest == 9).count(), 2);
+    // so do routes over nodes that went silent
+    assert!(table.remove_through_at(&[5], start).is_empty());
+    assert_eq!(table.entries().iter().filter(|(dest, _)| *dest == 9).count(), 1);
+
+    // static routes outlive the others
+    table.add_static(11, vec![3, 11]);
+    // 7 is still in use, so it is to be rediscovered
+    table.route_at(7, start + Duration::from_secs(300));
+    assert_eq!(table.expire_at(start + Duration::from_secs(600)), vec![7]);
+    assert_eq!(table.best(7), None);
+    assert_eq!(table.best(11).unwrap().source, RouteSource::Static);
+}
+
+#[test]
+fn routing_table_persistence() {
+    let start = Instant::now();
+    let unixstart = 1_600_000_000_000;
+    let path = std::env::temp_dir().join(format!("loramesh-routes-{}.json", std::process::id()));
+    let mut table = RoutingTable::new(Duration::from_secs(600));
+    table.load_at(&path, start, unixstart).unwrap();
+    table.add_at(7, vec![3, 7], 2.2, RouteSource::Discovered, start);
+    table.add_at(9, vec![3, 9], 2.0, RouteSource::Discovered, start + Duration::from_secs(400));
+    table.add_static(11, vec![11]);
+    let saved = start + Duration::from_secs(500);
+    table.save_at(saved, unixstart + 500_000).unwrap();
+
+    // after a reboot 200s later, the route with time left is used without rediscovering it
+    let later = saved + Duration::from_secs(200);
+    let mut restarted = RoutingTable::new(Duration::from_secs(600));
+    assert_eq!(restarted.load_at(&path, later, unixstart + 700_000).unwrap(), 2);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(restarted.route_at(9, later), Some(vec![3, 9]));
+    assert_eq!(restarted.best_at(9, later + Duration::from_secs(300)), None);
+    // the expired one is known but not used until confirmed, both are to be checked
+    assert_eq!(restarted.best_at(7, later), None);
+    assert_eq!(restarted.best_at(11, later), None);
+    assert_eq!(restarted.unconfirmed(), vec![(7, vec![3, 7]), (9, vec![3, 9])]);
+    restarted.confirm_at(7, later + Duration::from_secs(5));
+    assert_eq!(restarted.route_at(7, later + Duration::from_secs(5)), Some(vec![3, 7]));
+
+    // a restored route nothing confirmed in time is dropped, 9 was used so it is to be rediscovered
+    assert_eq!(restarted.expire_at(later + STALE_ROUTE_TTL), vec![9]);
+    assert!(restarted.unconfirmed().is_empty());
+
+    assert_eq!(parse_static_routes(" 7:3,7; 9:9 ").unwrap(), vec![(7, vec![3, 7]), (9, vec![9])]);
+    assert!(parse_static_routes("").unwrap().is_empty());
+    assert!(parse_static_routes("7:3,5").is_err());
+    assert!(parse_static_routes("7").is_err());
+}
+
+#[test]
+fn route_maintenance() {
+    use crate::hardware::LinkQuality;
+    use crate::stack::NeighborTable;
+
+    let start = Instant::now();
+    let maxage = Duration::from_secs(300);
+    let mut table = RoutingTable::new(maxage);
+    let mut neighbors = NeighborTable::new(Duration::from_secs(60));
+    neighbors.heard_at(3, 1, LinkQuality::default(), start);
+    neighbors.heard_at(4, 1, LinkQuality::default(), start);
+    table.add_at(7, vec![3, 7], 2.0, RouteSource::Discovered, start);
+    table.add_at(8, vec![3, 5, 8], 3.0, RouteSource::Learned, start);
+    table.add_at(8, vec![4, 8], 4.0, RouteSource::Learned, start);
+    table.add_at(9, vec![3, 9], 2.0, RouteSource::Learned, start);
+    table.route_at(7, start);
+    table.route_at(8, start);
+
+    // an expired route is never picked, even before it is purged
+    assert_eq!(table.best_at(9, start + maxage), None);
+
+    // acked traffic keeps the route it used alive
+    table.refresh_at(7, start + Duration::from_secs(200));
+    assert!(table.probes_due_at(Duration::from_secs(60), start + Duration::from_secs(220)).is_empty());
+    // routes in use are probed once as they near expiry, unused ones are not
+    let due = table.probes_due_at(Duration::from_secs(60), start + Duration::from_secs(250));
+    assert_eq!(due, vec![(8, vec![3, 5, 8])]);
+    assert!(table.probes_due_at(Duration::from_secs(60), start + Duration::from_secs(260)).is_empty());
+    table.confirm_at(8, start + Duration::from_secs(260));
+
+    // 3 goes silent, only 7 was in use and has no other route
+    neighbors.heard_at(4, 2, LinkQuality::default(), start + Duration::from_secs(100));
+    let silent = neighbors.expire_at(start + Duration::from_secs(130));
+    assert_eq!(silent, vec![3]);
+    let rediscover = table.remove_via_at(3, start + Duration::from_secs(270));
+    assert_eq!(rediscover, vec![7]);
+    assert_eq!(table.best_at(7, start + Duration::from_secs(270)), None);
+    assert_eq!(table.best_at(8, start + Duration::from_secs(270)).unwrap().path, vec![4, 8]);
+    assert_eq!(table.best_at(9, start + Duration::from_secs(270)), None);
+
+    // the rediscovered route takes over
+    table.add_at(7, vec![4, 7], 2.5, RouteSource::Discovered, start + Duration::from_secs(275));
+    assert_eq!(table.route_at(7, start + Duration::from_secs(275)), Some(vec![4, 7]));
+}
@@ -0,0 +1,274 @@
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+/// Frames a node sends on its own schedule
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeriodicTask {
+    Broadcast,
+    Heartbeat,
+}
+
+/// Decides when periodic frames are due
+/* Broadcasts and heartbeats are never sent within the same transmission
+slot, and a broadcast also counts as a heartbeat since it proves the
+node is alive.
+
+Nodes that boot together, after a power cut for one, would broadcast in
+the same slot forever on a fixed interval. Each broadcast is moved by a
+random jitter and the first one waits a random delay, so they drift apart. */
+#[derive(Clone, Debug)]
+pub struct PeriodicScheduler {
+    broadcastinterval: Duration,
+    heartbeatinterval: Duration,
+    txslot: Duration,
+    /// percentage of the interval each broadcast is moved by, either way
+    jitter: u32,
+    /// longest random delay before the first broadcast
+    startdelay: Duration,
+    /// longest the interval stretches to while the neighbors stay the same
+    maxinterval: Duration,
+    /// interval the next broadcast was scheduled with
+    interval: Duration,
+    /// true if the neighbors changed since the last broadcast
+    changed: bool,
+    /// shorter interval to broadcast with for a while, and until when
+    fast: Option<(Duration, Instant)>,
+    nextbroadcast: Option<Instant>,
+    lastheartbeat: Option<Instant>,
+    lastsent: Option<Instant>,
+    rng: StdRng,
+}
+
+impl PeriodicScheduler {
+    pub fn new(broadcastinterval: Duration, heartbeatinterval: Duration, txslot: Duration) -> Self {
+        PeriodicScheduler {
+            broadcastinterval,
+            heartbeatinterval,
+            txslot,
+            jitter: 0,
+            startdelay: Duration::from_secs(0),
+            maxinterval: broadcastinterval,
+            interval: broadcastinterval,
+            changed: false,
+            fast: None,
+            nextbroadcast: None,
+            lastheartbeat: None,
+            lastsent: None,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// move each broadcast by up to this percentage of the interval, either way
+    pub fn with_jitter(mut self, percent: u32) -> Self {
+        self.jitter = percent.min(100);
+        self
+    }
+
+    /// wait a random time of up to `delay` before the first broadcast
+    pub fn with_start_delay(mut self, delay: Duration) -> Self {
+        self.startdelay = delay;
+        self
+    }
+
+    /// double the interval after each broadcast while the neighbors stay
+    /// the same, up to `max`
+    pub fn with_max_interval(mut self, max: Duration) -> Self {
+        self.maxinterval = max.max(self.broadcastinterval);
+        self
+    }
+
+    /// use a fixed seed for the jitter, so tests can be repeated
+    #[cfg(test)]
+    pub(crate) fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Neighbors appeared or went away, go back to the shortest interval
+    pub fn topology_changed(&mut self) {
+        self.topology_changed_at(Instant::now())
+    }
+
+    pub(crate) fn topology_changed_at(&mut self, now: Instant) {
+        self.changed = true;
+        if self.interval <= self.broadcastinterval {
+            return;
+        }
+        // tell the neighbors soon rather than at the end of a long interval
+        self.interval = self.broadcastinterval;
+        let sooner = now + self.jittered(self.interval);
+        if let Some(next) = self.nextbroadcast {
+            self.nextbroadcast = Some(next.min(sooner));
+        }
+    }
+
+    /// Broadcast every `interval` for `duration`, then go back to the usual interval
+    pub fn accelerate(&mut self, interval: Duration, duration: Duration) {
+        let now = Instant::now();
+        self.accelerate_at(interval, now + duration, now)
+    }
+
+    pub(crate) fn accelerate_at(&mut self, interval: Duration, until: Instant, now: Instant) {
+        self.fast = Some((interval, until));
+        self.interval = self.broadcastinterval;
+        let sooner = now + self.jittered(interval);
+        if let Some(next) = self.nextbroadcast {
+            self.nextbroadcast = Some(next.min(sooner));
+        }
+    }
+
+    /// The interval the next broadcast was scheduled with
+    pub fn broadcast_interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// `interval` moved by a random part of the jitter
+    fn jittered(&mut self, interval: Duration) -> Duration {
+        let ms = interval.as_millis() as u64;
+        let spread = ms * self.jitter as u64 / 100;
+        Duration::from_millis(ms - spread + self.rng.gen_range(0, 2 * spread + 1))
+    }
+
+    /// The periodic task to run now, if any
+    pub fn next_task(&mut self) -> Option<PeriodicTask> {
+        self.next_task_at(Instant::now())
+    }
+
+    pub(crate) fn next_task_at(&mut self, now: Instant) -> Option<PeriodicTask> {
+        let due = |last: Option<Instant>, interval: Duration| {
+            last.map_or(true, |t| now.duration_since(t) >= interval)
+        };
+
+        if self.nextbroadcast.is_none() {
+            let delay = self.rng.gen_range(0, self.startdelay.as_millis() as u64 + 1);
+            self.nextbroadcast = Some(now + Duration::from_millis(delay));
+        }
+
+        // one periodic frame per transmission slot
+        if !due(self.lastsent, self.txslot) {
+            return None;
+        }
+
+        let task = if self.nextbroadcast.map_or(false, |next| now >= next) {
+            let fast = self.fast.filter(|(_, until)| now < *until).map(|(interval, _)| interval);
+            if fast.is_none() {
+                self.fast = None;
+                if !self.changed {
+                    self.interval = (self.interval * 2).min(self.maxinterval);
+                }
+            }
+            self.changed = false;
+            let interval = fast.unwrap_or(self.interval);
+            self.nextbroadcast = Some(now + self.jittered(interval));
+            PeriodicTask::Broadcast
+        } else if due(self.lastheartbeat, self.heartbeatinterval) {
+            PeriodicTask::Heartbeat
+        } else {
+            return None;
+        };
+        self.lastheartbeat = Some(now);
+        self.lastsent = Some(now);
+        Some(task)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn scheduler_interleaves() {
+    let start = Instant::now();
+    let mut sched = PeriodicScheduler::new(Duration::from_secs(60), Duration::from_secs(10), Duration::from_secs(1));
+
+    // simulate two minutes at 100ms resolution
+    let mut tasks = Vec::new();
+    for tick in 0..1200u64 {
+        let now = start + Duration::from_millis(tick * 100);
+        if let Some(task) = sched.next_task_at(now) {
+            tasks.push((now, task));
+        }
+    }
+
+    let broadcasts = tasks.iter().filter(|(_, t)| t == &PeriodicTask::Broadcast).count();
+    let heartbeats = tasks.iter().filter(|(_, t)| t == &PeriodicTask::Heartbeat).count();
+    assert_eq!(broadcasts, 2);
+    assert_eq!(heartbeats, 10);
+
+    // no two periodic frames in the same slot
+    for pair in tasks.windows(2) {
+        assert!(pair[1].0.duration_since(pair[0].0) >= Duration::from_secs(1));
+    }
+}
+
+#[test]
+fn scheduler_jitter() {
+    let start = Instant::now();
+    let node = |seed| PeriodicScheduler::new(Duration::from_secs(60), Duration::from_secs(10), Duration::from_secs(1))
+        .with_jitter(20)
+        .with_start_delay(Duration::from_secs(20))
+        .with_seed(seed);
+
+    // two nodes booting together, broadcasts in the same slot collide on the medium
+    let collisions = |mut a: PeriodicScheduler, mut b: PeriodicScheduler| {
+        let (mut sent, mut collided, mut streak, mut longest) = (0, 0, 0, 0);
+        for tick in 0..36000u64 {
+            let now = start + Duration::from_secs(tick);
+            let first = a.next_task_at(now) == Some(PeriodicTask::Broadcast);
+            let second = b.next_task_at(now) == Some(PeriodicTask::Broadcast);
+            if first {
+                sent += 1;
+                if second {
+                    collided += 1;
+                    streak += 1;
+                    longest = longest.max(streak);
+                } else {
+                    streak = 0;
+                }
+            }
+        }
+        (sent, collided, longest)
+    };
+
+    // without jitter they stay locked together
+    let fixed = || PeriodicScheduler::new(Duration::from_secs(60), Duration::from_secs(10), Duration::from_secs(1));
+    let (sent, collided, _) = collisions(fixed(), fixed());
+    assert_eq!(sent, collided);
+
+    // with it they meet by chance, about once every 30 broadcasts, and drift apart again
+    for seed in 0..5 {
+        let (sent, collided, longest) = collisions(node(2 * seed), node(2 * seed + 1));
+        assert!(sent > 450 && sent < 750, "{} broadcasts", sent);
+        assert!(collided * 10 < sent, "{} of {} broadcasts collided", collided, sent);
+        assert!(longest < 3, "{} collisions in a row", longest);
+    }
+
+    // the first broadcast waits up to the start delay
+    let mut sched = node(42);
+    let first = (0..60u64).find(|secs| sched.next_task_at(start + Duration::from_secs(*secs)) == Some(PeriodicTask::Broadcast));
+    assert!(first.map_or(false, |secs| secs <= 20));
+}
+
+#[test]
+fn scheduler_adaptive() {
+    let start = Instant::now();
+    let mut sched = PeriodicScheduler::new(Duration::from_secs(60), Duration::from_secs(10), Duration::from_secs(1))
+        .with_max_interval(Duration::from_secs(240))
+        .with_seed(7);
+    // the neighbors only show up with the first broadcast
+    sched.topology_changed_at(start);
+    let mut broadcasts = Vec::new();
+    for tick in 0..1200u64 {
+        let now = start + Duration::from_secs(tick);
+        if tick == 1000 {
+            sched.topology_changed_at(now);
+        }
+        if sched.next_task_at(now) == Some(PeriodicTask::Broadcast) {
+            broadcasts.push(tick);
+        }
+    }
+
+    // the interval doubles while nothing changes, up to the maximum
+    assert_eq!(&broadcasts[..5], &[0, 60, 180, 420, 660]);
+    assert_eq!(sched.broadcast_interval(), Duration::from_secs(120));
+    // and shrinks back once a neighbor comes or goes
+    assert_eq!(&broadcasts[5..], &[900, 1060, 1120]);
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use crate::stack::NodeId;
+use crate::stack::nat::{overlaps, parse_subnet};
+
+/// true if `ipaddr` is in `subnet`
+pub fn in_subnet(ipaddr: Ipv4Addr, subnet: (Ipv4Addr, u8)) -> bool {
+    overlaps((ipaddr, 32), subnet)
+}
+
+/// Parse routes through the tunnel written as `dest/len via addr` separated by `;`
+/* `0.0.0.0/0 via 172.16.0.1` sends everything through the gateway at
+172.16.0.1. */
+pub fn parse_ip_routes(list: &str) -> io::Result<Vec<((Ipv4Addr, u8), Ipv4Addr)>> {
+    list.split(';')
+        .map(|route| route.trim())
+        .filter(|route| !route.is_empty())
+        .map(|route| {
+            let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid IP route: {}", route));
+            let words: Vec<&str> = route.split_whitespace().collect();
+            match words.as_slice() {
+                [dest, "via", via] => Ok((parse_subnet(dest)?, via.parse().map_err(|_| invalid())?)),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+/// What becomes of an address the gateway assigns
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Assignment {
+    /// use it, it is new to us
+    Take,
+    /// it is the one we have, only the lease is renewed
+    Renew,
+    /// our static address stays, the assignment is ignored
+    KeepStatic(Ipv4Addr),
+}
+
+/// How to take an address assigned while we hold `current`
+/* A configured address always wins, whatever a gateway or the state
+saved before a restart says. */
+pub fn assignment(staticip: Option<Ipv4Addr>, current: Option<Ipv4Addr>, assigned: Ipv4Addr) -> Assignment {
+    match staticip {
+        Some(staticip) => Assignment::KeepStatic(staticip),
+        None if current == Some(assigned) => Assignment::Renew,
+        None => Assignment::Take,
+    }
+}
+
+/// The addresses nodes announce in their broadcasts, to catch two announcing the same
+/* A claim lapses once its node has not announced it for `window`, so an
+address the gateway moved to another node is not taken for a conflict.
+Each conflict is reported once until one of the two goes quiet. */
+#[derive(Clone, Debug)]
+pub struct AddressClaims {
+    claims: HashMap<Ipv4Addr, Vec<(NodeId, Instant)>>,
+    window: Duration,
+}
+
+impl AddressClaims {
+    pub fn new(window: Duration) -> Self {
+        AddressClaims { claims: HashMap::new(), window }
+    }
+
+    /// Note that `nodeid` announced `ipaddr`, returns the other node holding it if this is a new conflict
+    pub fn claim(&mut self, nodeid: NodeId, ipaddr: Ipv4Addr) -> Option<NodeId> {
+        self.claim_at(nodeid, ipaddr, Instant::now())
+    }
+
+    pub(crate) fn claim_at(&mut self, nodeid: NodeId, ipaddr: Ipv4Addr, now: Instant) -> Option<NodeId> {
+        let window = self.window;
+        let holders = self.claims.entry(ipaddr).or_default();
+        holders.retain(|(_, seen)| now.duration_since(*seen) < window);
+        let known = holders.iter().any(|(holder, _)| *holder == nodeid);
+        holders.retain(|(holder, _)| *holder != nodeid);
+        holders.push((nodeid, now));
+        if !known && holders.len() > 1 {
+            Some(holders[0].0)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn static_address_precedence() {
+    let staticip = Ipv4Addr::new(172, 16, 0, 40);
+    let assigned = Ipv4Addr::new(172, 16, 0, 2);
+    let mesh = parse_subnet("172.16.0.0/24").unwrap();
+    assert!(in_subnet(staticip, mesh) && !in_subnet(Ipv4Addr::new(172, 16, 1, 40), mesh));
+
+    // the configured address beats the gateway, before and after one is held
+    assert_eq!(assignment(Some(staticip), None, assigned), Assignment::KeepStatic(staticip));
+    assert_eq!(assignment(Some(staticip), Some(staticip), assigned), Assignment::KeepStatic(staticip));
+    assert_eq!(assignment(Some(staticip), Some(staticip), staticip), Assignment::KeepStatic(staticip));
+    // without one the gateway decides
+    assert_eq!(assignment(None, None, assigned), Assignment::Take);
+    assert_eq!(assignment(None, Some(assigned), assigned), Assignment::Renew);
+    assert_eq!(assignment(None, Some(staticip), assigned), Assignment::Take);
+
+    let routes = parse_ip_routes("0.0.0.0/0 via 172.16.0.1; 10.1.0.0/16 via 172.16.0.7").unwrap();
+    assert_eq!(routes, vec![((Ipv4Addr::new(0, 0, 0, 0), 0), Ipv4Addr::new(172, 16, 0, 1)), ((Ipv4Addr::new(10, 1, 0, 0), 16), Ipv4Addr::new(172, 16, 0, 7))]);
+    assert!(parse_ip_routes("").unwrap().is_empty());
+    assert!(parse_ip_routes("10.1.0.0/16 172.16.0.7").is_err() && parse_ip_routes("10.1.0.0 via 172.16.0.7").is_err());
+
+    // a second node announcing the address is reported once, until the first goes quiet
+    let now = Instant::now();
+    let mut claims = AddressClaims::new(Duration::from_secs(60));
+    assert_eq!(claims.claim_at(5, staticip, now), None);
+    assert_eq!(claims.claim_at(5, staticip, now + Duration::from_secs(10)), None);
+    assert_eq!(claims.claim_at(9, staticip, now + Duration::from_secs(20)), Some(5));
+    assert_eq!(claims.claim_at(9, staticip, now + Duration::from_secs(30)), None);
+    assert_eq!(claims.claim_at(5, staticip, now + Duration::from_secs(40)), None);
+    // an address whose node went quiet is free for another
+    assert_eq!(claims.claim_at(9, assigned, now), None);
+    assert_eq!(claims.claim_at(7, assigned, now + Duration::from_secs(61)), None);
+}
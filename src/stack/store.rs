@@ -0,0 +1,240 @@
+use log::*;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::stack::Frame;
+use crate::stack::frame::{FrameBuilder, NodeId};
+use crate::stack::message::MessageType;
+
+/// Message types worth holding for a node that is out of range
+/* Routing and diagnostic traffic is stale by the time the node is back. */
+pub fn holdable(msgtype: &MessageType) -> bool {
+    matches!(msgtype, MessageType::Text | MessageType::TextReceipt | MessageType::Data)
+}
+
+/// A message held for a node that could not be reached
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HeldMessage {
+    pub dest: NodeId,
+    /// node the message came from, and the frame ID it gave it
+    pub sender: NodeId,
+    pub frameid: u8,
+    pub msgtype: u8,
+    pub payload: Vec<u8>,
+    /// ms since the unix epoch, so age survives a reboot
+    pub stored: u64,
+}
+
+impl HeldMessage {
+    /// Hold a frame for its destination
+    pub fn from_frame(frame: &mut Frame, dest: NodeId, now: u64) -> Self {
+        HeldMessage {
+            dest,
+            sender: frame.sender(),
+            frameid: frame.frameid(),
+            msgtype: frame.msgtype().to_u8(),
+            payload: frame.payload(),
+            stored: now,
+        }
+    }
+
+    /// The message as a frame again, on a route to its destination
+    pub fn to_frame(&self, route: Vec<NodeId>) -> io::Result<Frame> {
+        let msgtype = MessageType::n(self.msgtype)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "held message has an unknown type"))?;
+        FrameBuilder::new()
+            .frameid(self.frameid)
+            .message_type(msgtype)
+            .sender(self.sender)
+            .route(&route)
+            .payload(self.payload.clone())
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+    }
+}
+
+/// Messages held for unreachable nodes by a store-and-forward node
+/* Bounded by count, payload bytes and age. When full, the oldest message
+of any node makes room. Messages are saved to disk after every change,
+like address leases, so a restart does not lose them. */
+#[derive(Clone, Debug)]
+pub struct MessageStore {
+    maxcount: usize,
+    maxbytes: usize,
+    maxage: Duration,
+    /// oldest first
+    held: VecDeque<HeldMessage>,
+    path: Option<PathBuf>,
+}
+
+impl MessageStore {
+    pub fn new(maxcount: usize, maxbytes: usize, maxage: Duration) -> Self {
+        MessageStore { maxcount, maxbytes, maxage, held: VecDeque::new(), path: None }
+    }
+
+    /// Keep the store in a file, reading the messages already in it
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        self.path = Some(path.to_path_buf());
+        match fs::read_to_string(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+            Ok(json) => {
+                self.held = serde_json::from_str(&json)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the store to its file, if it has one
+    fn save(&self) {
+        let path = match &self.path {
+            None => return,
+            Some(path) => path,
+        };
+        let json = serde_json::to_string(&self.held).expect("Held messages are always serializable");
+        // a crash while writing leaves the old store intact
+        let tmp = path.with_extension("tmp");
+        if let Err(e) = fs::write(&tmp, json).and_then(|_| fs::rename(&tmp, path)) {
+            error!("Could not save held messages to {}: {}", path.display(), e);
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        self.held.iter().map(|msg| msg.payload.len()).sum()
+    }
+
+    /// Hold a message, false if it can never fit
+    pub fn hold(&mut self, msg: HeldMessage) -> bool {
+        if self.maxcount == 0 || msg.payload.len() > self.maxbytes {
+            return false;
+        }
+        // a repeated frame is held once
+        if self.held.iter().any(|m| m.sender == msg.sender && m.frameid == msg.frameid && m.dest == msg.dest) {
+            return true;
+        }
+        while self.held.len() >= self.maxcount || self.bytes() + msg.payload.len() > self.maxbytes {
+            if let Some(dropped) = self.held.pop_front() {
+                debug!("Store full, dropping held message from {} for {}", dropped.sender, dropped.dest);
+            }
+        }
+        self.held.push_back(msg);
+        self.save();
+        true
+    }
+
+    /// number of messages held for a node
+    pub fn count(&self, dest: NodeId) -> usize {
+        self.held.iter().filter(|msg| msg.dest == dest).count()
+    }
+
+    /// Take every message held for a node, oldest first
+    pub fn take(&mut self, dest: NodeId) -> Vec<HeldMessage> {
+        let (taken, kept): (VecDeque<HeldMessage>, VecDeque<HeldMessage>) = self.held.drain(..).partition(|msg| msg.dest == dest);
+        self.held = kept;
+        if !taken.is_empty() {
+            self.save();
+        }
+        taken.into_iter().collect()
+    }
+
+    /// Drop messages older than the max age, returning how many
+    pub fn expire(&mut self, now: u64) -> usize {
+        let maxage = self.maxage.as_millis() as u64;
+        let before = self.held.len();
+        self.held.retain(|msg| now.saturating_sub(msg.stored) < maxage);
+        let expired = before - self.held.len();
+        if expired > 0 {
+            self.save();
+        }
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn store_and_forward() {
+    use std::collections::HashSet;
+    use crate::stack::frame::ToFromFrame;
+    use crate::stack::message::{HeldNoticeMessage, HeldRequestMessage, TextMessage};
+
+    // the gateway 1 holds texts from node 2 for node 3, which drives out of range
+    let mut store = MessageStore::new(10, 1000, Duration::from_secs(3600));
+    let mut inrange: HashSet<NodeId> = vec![2, 3].into_iter().collect();
+    let mut delivered = Vec::new();
+    let mut send = |store: &mut MessageStore, inrange: &HashSet<NodeId>, msgid: u16, now: u64| {
+        let mut frame = TextMessage::new(msgid, &format!("text {}", msgid)).unwrap().to_frame(msgid as u8, 2, vec![1, 3]).unwrap();
+        let bytes = frame.to_bytes();
+        if inrange.contains(&3) {
+            delivered.push(bytes);
+        } else {
+            let mut frame = Frame::from_bytes(&bytes).unwrap();
+            assert!(holdable(&frame.msgtype()));
+            assert!(store.hold(HeldMessage::from_frame(&mut frame, 3, now)));
+        }
+    };
+    send(&mut store, &inrange, 1, 0);
+    inrange.remove(&3);
+    for msgid in 2..6 {
+        send(&mut store, &inrange, msgid, msgid as u64 * 1000);
+    }
+    assert_eq!(store.count(3), 4);
+
+    // node 3 is back, its broadcast gets it a notice and it asks for its messages
+    inrange.insert(3);
+    let mut notice = Frame::from_bytes(&HeldNoticeMessage::new(store.count(3) as u16).to_frame(9, 1, vec![3]).unwrap().to_bytes()).unwrap();
+    assert_eq!(HeldNoticeMessage::from_frame(&mut notice).unwrap().count, 4);
+    let mut request = Frame::from_bytes(&HeldRequestMessage::new().to_frame(1, 3, vec![1]).unwrap().to_bytes()).unwrap();
+    assert_eq!(request.msgtype(), MessageType::HeldRequest);
+    for held in store.take(request.sender()) {
+        delivered.push(held.to_frame(vec![3]).unwrap().to_bytes());
+    }
+    assert_eq!(store.len(), 0);
+
+    // the backlog arrives in order, as if sent by node 2
+    let texts: Vec<String> = delivered.iter().map(|bytes| {
+        let mut frame = Frame::from_bytes(bytes).unwrap();
+        assert_eq!(frame.sender(), 2);
+        TextMessage::from_frame(&mut frame).unwrap().text
+    }).collect();
+    assert_eq!(texts, vec!["text 1", "text 2", "text 3", "text 4", "text 5"]);
+}
+
+#[test]
+fn store_limits() {
+    let held = |dest: NodeId, frameid: u8, len: usize, stored: u64| HeldMessage {
+        dest, sender: 2, frameid, msgtype: MessageType::Data.to_u8(), payload: vec![0; len], stored,
+    };
+    let mut store = MessageStore::new(3, 100, Duration::from_secs(60));
+    assert!(!store.hold(held(3, 0, 101, 0)));
+    for frameid in 0..4 {
+        assert!(store.hold(held(3 + frameid % 2, frameid, 10, frameid as u64)));
+    }
+    // the oldest made room, a repeat is held once
+    assert!(store.hold(held(4, 3, 10, 3)));
+    assert_eq!((store.len(), store.count(3), store.count(4)), (3, 1, 2));
+    // bytes are bounded too
+    assert!(store.hold(held(5, 9, 90, 10)));
+    assert_eq!((store.len(), store.count(5)), (2, 1));
+
+    assert_eq!(store.expire(60_002), 0);
+    assert_eq!(store.expire(60_003), 1);
+    assert_eq!(store.count(5), 1);
+
+    // held messages survive a restart
+    let path = std::env::temp_dir().join(format!("loramesh-held-{}.json", std::process::id()));
+    let mut store = MessageStore::new(3, 100, Duration::from_secs(60));
+    store.load(&path).unwrap();
+    store.hold(held(3, 1, 10, 0));
+    let mut restarted = MessageStore::new(3, 100, Duration::from_secs(60));
+    restarted.load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(restarted.take(3), vec![held(3, 1, 10, 0)]);
+}
@@ -0,0 +1,131 @@
+use log::*;
+use std::io;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use crate::stack::util::unix_millis;
+
+/// Offset (ms) beyond which the system clock is stepped, when enabled
+pub const CLOCK_STEP_THRESHOLD: i64 = 1000;
+
+/// LoRa time on air of a frame, with an explicit header, CRC and 8 symbol preamble at 4/5 coding rate
+pub fn airtime(len: usize, sf: u8, bwkhz: u32) -> Duration {
+    let sf = sf as f64;
+    let symbol = 2f64.powf(sf) / (bwkhz as f64 * 1000.0);
+    // low data rate optimization is on when a symbol lasts over 16ms
+    let lowrate = if symbol > 0.016 { 1.0 } else { 0.0 };
+    let preamble = (8.0 + 4.25) * symbol;
+    let bits = 8.0 * len as f64 - 4.0 * sf + 28.0 + 16.0;
+    let symbols = 8.0 + ((bits / (4.0 * (sf - 2.0 * lowrate))).ceil() * 5.0).max(0.0);
+    Duration::from_secs_f64(preamble + symbols * symbol)
+}
+
+/// Offset (ms) to add to our clock to get the gateway's
+/// `delay` is the time the sync took to reach us, including the last hop
+pub fn sync_offset(time: u64, delay: u64, received: u64) -> i64 {
+    (time + delay) as i64 - received as i64
+}
+
+/// Mesh time, our clock corrected by the offset to the gateway's
+/* Syncs that travelled fewer hops are more accurate, so a sync from
+further away only replaces the current one once that has aged out. */
+#[derive(Clone, Debug)]
+pub struct MeshClock {
+    offset: i64,
+    /// hops of the sync the offset came from, and when it arrived
+    synced: Option<(u8, Instant)>,
+    maxage: Duration,
+}
+
+impl MeshClock {
+    pub fn new(maxage: Duration) -> Self {
+        MeshClock { offset: 0, synced: None, maxage }
+    }
+
+    /// ms since the unix epoch in mesh time
+    pub fn now(&self) -> u64 {
+        (unix_millis() as i64 + self.offset).max(0) as u64
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced.is_some()
+    }
+
+    /// Take an offset from a sync that travelled `hops`, false if a better one is current
+    pub fn update(&mut self, offset: i64, hops: u8) -> bool {
+        self.update_at(offset, hops, Instant::now())
+    }
+
+    pub(crate) fn update_at(&mut self, offset: i64, hops: u8, now: Instant) -> bool {
+        if let Some((besthops, at)) = self.synced {
+            if hops > besthops && now.duration_since(at) < self.maxage {
+                return false;
+            }
+        }
+        self.offset = offset;
+        self.synced = Some((hops, now));
+        true
+    }
+
+    /// Step the system clock to mesh time, leaving no offset
+    /* Needs the privileges to set the time. */
+    pub fn step_system_clock(&mut self) -> io::Result<()> {
+        let target = self.now();
+        let status = Command::new("date")
+            .args(&["-u", "-s", &format!("@{}.{:03}", target / 1000, target % 1000)])
+            .output()?
+            .status;
+        if !status.success() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("date exited with {}", status)));
+        }
+        info!("Stepped system clock by {}ms", self.offset);
+        self.offset = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn timesync_offset() {
+    // 10 byte frame at SF12 and 125kHz, as given by the Semtech calculator
+    assert_eq!(airtime(10, 12, 125).as_micros() / 100, 9912);
+    assert!(airtime(10, 7, 125) < Duration::from_millis(50));
+
+    // the gateway sent at 1000000 and the frame took 500ms on the air,
+    // we received it at 400000 on our bogus clock
+    assert_eq!(sync_offset(1_000_000, 500, 400_000), 600_500);
+    // two hops of 500ms, one added by the relay and one by us
+    let relayed = 500 + 500;
+    assert_eq!(sync_offset(1_000_000, relayed, 1_000_000), 1_000);
+    // our clock runs ahead
+    assert_eq!(sync_offset(1_000_000, 500, 1_000_700), -200);
+
+    let start = Instant::now();
+    let mut clock = MeshClock::new(Duration::from_secs(600));
+    assert!(!clock.is_synced());
+    assert!(clock.update_at(1_000, 2, start));
+    // a sync from further away only counts once the closer one is stale
+    assert!(!clock.update_at(5_000, 3, start + Duration::from_secs(60)));
+    assert!(clock.update_at(1_200, 1, start + Duration::from_secs(60)));
+    assert_eq!(clock.offset(), 1_200);
+    assert!(clock.update_at(5_000, 3, start + Duration::from_secs(700)));
+    assert!((clock.now() as i64 - unix_millis() as i64 - 5_000).abs() < 1_000);
+}
+
+#[test]
+fn airtime_all_rates() {
+    // 10 byte frames in microseconds, SF7 to SF12, as given by the Semtech calculator
+    let expected = [
+        (125, [41216, 72192, 144384, 288768, 577536, 991232]),
+        (250, [20608, 36096, 72192, 144384, 247808, 495616]),
+        (500, [10304, 18048, 36096, 72192, 123904, 247808]),
+    ];
+    for (bw, times) in expected.iter() {
+        for (sf, micros) in (7..=12).zip(times.iter()) {
+            assert_eq!(airtime(10, sf, *bw).as_micros(), *micros, "SF{} at {}kHz", sf, bw);
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::SystemTime;
+use serde::Serialize;
+use crate::stack::frame::NodeId;
+use crate::stack::message::{TracerouteResult, RSSI_UNKNOWN};
+
+/// The mesh as seen by tracing a route to every known node
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TopologyReport {
+    /// every node on a traced path, ourselves included
+    pub nodes: Vec<NodeId>,
+    /// links along the traced paths, with the RSSI (dBm) the second node
+    /// heard the first with, `RSSI_UNKNOWN` if it did not report one
+    pub links: Vec<(NodeId, NodeId, i16)>,
+    /// known nodes that did not answer in time
+    pub unreachable: Vec<NodeId>,
+    pub timestamp: SystemTime,
+}
+
+impl TopologyReport {
+    /// Combine the traceroutes sent from `origin`
+    /* A link on several paths keeps the RSSI of the first one traced. */
+    pub fn from_traces(origin: NodeId, traces: &[TracerouteResult], unreachable: Vec<NodeId>) -> Self {
+        let mut nodes = BTreeSet::new();
+        nodes.insert(origin);
+        let mut links: Vec<(NodeId, NodeId, i16)> = Vec::new();
+        for trace in traces {
+            let mut previous = origin;
+            for hop in &trace.hops {
+                nodes.insert(hop.nodeid);
+                if !links.iter().any(|(from, to, _)| *from == previous && *to == hop.nodeid) {
+                    links.push((previous, hop.nodeid, hop.rssi.unwrap_or(RSSI_UNKNOWN)));
+                }
+                previous = hop.nodeid;
+            }
+        }
+        links.sort_by_key(|(from, to, _)| (*from, *to));
+        let mut unreachable = unreachable;
+        unreachable.sort();
+        TopologyReport { nodes: nodes.into_iter().collect(), links, unreachable, timestamp: SystemTime::now() }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Topology report is always serializable")
+    }
+}
+
+/// A node in a `MeshGraph`
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct GraphNode {
+    pub nodeid: NodeId,
+    /// display name from its node info
+    pub name: Option<String>,
+    pub gateway: bool,
+    /// seconds since it was last heard from, if it ever was
+    pub lastseen: Option<u64>,
+}
+
+/// A link in a `MeshGraph`
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct GraphEdge {
+    pub from: NodeId,
+    pub to: NodeId,
+    /// expected transmissions, known for our own links and in link-state mode
+    pub etx: Option<f32>,
+    /// average RSSI (dBm) `from` hears `to` with, known for our own links
+    pub rssi: Option<i16>,
+}
+
+/// Snapshot of the mesh as one node knows it, to be drawn
+/* In link-state mode the links are directed and cover the whole mesh,
+otherwise they are the undirected links this node observed. */
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MeshGraph {
+    /// the node the snapshot was taken on
+    pub origin: NodeId,
+    pub directed: bool,
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+    #[serde(skip)]
+    nodeindex: BTreeMap<NodeId, usize>,
+    #[serde(skip)]
+    edgeindex: BTreeMap<(NodeId, NodeId), usize>,
+}
+
+impl MeshGraph {
+    pub fn new(origin: NodeId, directed: bool) -> Self {
+        let mut graph = MeshGraph { origin, directed, nodes: Vec::new(), edges: Vec::new(), nodeindex: BTreeMap::new(), edgeindex: BTreeMap::new() };
+        graph.node(origin);
+        graph
+    }
+
+    /// The node with this ID, added if it is not known yet
+    pub fn node(&mut self, nodeid: NodeId) -> &mut GraphNode {
+        let nodes = &mut self.nodes;
+        let index = *self.nodeindex.entry(nodeid).or_insert_with(|| {
+            nodes.push(GraphNode { nodeid, ..GraphNode::default() });
+            nodes.len() - 1
+        });
+        &mut self.nodes[index]
+    }
+
+    /// The link between two nodes, added along with them if it is not known yet
+    pub fn edge(&mut self, from: NodeId, to: NodeId) -> &mut GraphEdge {
+        let (from, to) = if self.directed { (from, to) } else { (from.min(to), from.max(to)) };
+        self.node(from);
+        self.node(to);
+        let edges = &mut self.edges;
+        let index = *self.edgeindex.entry((from, to)).or_insert_with(|| {
+            edges.push(GraphEdge { from, to, ..GraphEdge::default() });
+            edges.len() - 1
+        });
+        &mut self.edges[index]
+    }
+
+    /// Nodes and links in ID order
+    pub fn sorted(mut self) -> Self {
+        self.nodes.sort_by_key(|node| node.nodeid);
+        self.edges.sort_by_key(|edge| (edge.from, edge.to));
+        self.nodeindex = self.nodes.iter().enumerate().map(|(i, node)| (node.nodeid, i)).collect();
+        self.edgeindex = self.edges.iter().enumerate().map(|(i, edge)| ((edge.from, edge.to), i)).collect();
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Mesh graph is always serializable")
+    }
+
+    /// The graph in Graphviz DOT, gateways drawn as boxes and the origin in bold
+    pub fn to_dot(&self) -> String {
+        let mut dot = format!("{} mesh {{\n", if self.directed { "digraph" } else { "graph" });
+        for node in &self.nodes {
+            let mut label = node.nodeid.to_string();
+            if let Some(name) = &node.name {
+                label.push_str(&format!("\\n{}", dot_escape(name)));
+            }
+            if let Some(secs) = node.lastseen {
+                label.push_str(&format!("\\n{}s ago", secs));
+            }
+            let mut attrs = vec![format!("label=\"{}\"", label)];
+            if node.gateway {
+                attrs.push(String::from("shape=box"));
+            }
+            if node.nodeid == self.origin {
+                attrs.push(String::from("style=bold"));
+            }
+            dot.push_str(&format!("    {} [{}];\n", node.nodeid, attrs.join(", ")));
+        }
+        let arrow = if self.directed { "->" } else { "--" };
+        for edge in &self.edges {
+            let mut label = Vec::new();
+            if let Some(etx) = edge.etx {
+                label.push(format!("etx {:.2}", etx));
+            }
+            if let Some(rssi) = edge.rssi {
+                label.push(format!("{} dBm", rssi));
+            }
+            if label.is_empty() {
+                dot.push_str(&format!("    {} {} {};\n", edge.from, arrow, edge.to));
+            } else {
+                dot.push_str(&format!("    {} {} {} [label=\"{}\"];\n", edge.from, arrow, edge.to, label.join(", ")));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Text inside a quoted DOT string
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', " ")
+}
+
+#[cfg(test)]
+#[test]
+fn topology_from_traces() {
+    use std::time::Duration;
+    use crate::stack::message::TraceHop;
+
+    let hop = |nodeid, rssi| TraceHop { nodeid, rssi, timestamp: 0 };
+    let trace = |dest, hops| TracerouteResult { dest, complete: true, hops, hop_latency: Vec::new(), rtt: Duration::from_millis(400) };
+    let traces = vec![
+        trace(2, vec![hop(2, Some(-70))]),
+        trace(4, vec![hop(2, Some(-72)), hop(4, None)]),
+        trace(3, vec![hop(3, Some(-101))]),
+    ];
+    let report = TopologyReport::from_traces(1, &traces, vec![9, 6]);
+    assert_eq!(report.nodes, vec![1, 2, 3, 4]);
+    assert_eq!(report.links, vec![(1, 2, -70), (1, 3, -101), (2, 4, RSSI_UNKNOWN)]);
+    assert_eq!(report.unreachable, vec![6, 9]);
+
+    let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+    assert_eq!(json["links"][0], serde_json::json!([1, 2, -70]));
+    assert_eq!(json["unreachable"], serde_json::json!([6, 9]));
+}
+
+#[test]
+fn mesh_graph_export() {
+    // 1 is a gateway, 2 relays for 3, 4 was never heard from first hand
+    let graph = |directed| {
+        let mut graph = MeshGraph::new(1, directed);
+        graph.node(1).name = Some(String::from("base \"north\""));
+        graph.node(1).gateway = true;
+        {
+            let link = graph.edge(2, 1);
+            link.etx = Some(1.25);
+            link.rssi = Some(-71);
+        }
+        graph.edge(2, 3).etx = if directed { Some(2.5) } else { None };
+        graph.edge(3, 4);
+        let relay = graph.node(2);
+        relay.name = Some(String::from("relay"));
+        relay.lastseen = Some(12);
+        graph.node(3).lastseen = Some(340);
+        graph.sorted()
+    };
+
+    let observed = graph(false);
+    assert_eq!(observed.edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>(), vec![(1, 2), (2, 3), (3, 4)]);
+    assert_eq!(observed.to_dot(), include_str!("../../testdata/topology.dot"));
+    assert_eq!(observed.to_json(), include_str!("../../testdata/topology.json").trim_end());
+
+    let learned = graph(true);
+    assert_eq!(learned.to_dot(), include_str!("../../testdata/topology-linkstate.dot"));
+    let json: serde_json::Value = serde_json::from_str(&learned.to_json()).unwrap();
+    assert_eq!(json["edges"][0], serde_json::json!({"from": 2, "to": 1, "etx": 1.25, "rssi": -71}));
+}
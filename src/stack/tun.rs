@@ -4,33 +4,39 @@ use std::thread;
 extern crate tun_tap;
 use tun_tap::{Iface};
 
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use crossbeam_channel;
 use crossbeam_channel::{Receiver, Sender};
-use packet::ip::v4::Packet;
+use crate::stack::ipv6::IpPacket;
 use std::sync::Arc;
 
 pub struct NetworkTunnel {
     pub tunname: String,
-    pub interface: Arc<Iface>,
+    /// the kernel interface, none for a tunnel in memory
+    pub interface: Option<Arc<Iface>>,
+    /// where packets sent on a tunnel in memory go
+    outbound: Option<Sender<IpPacket>>,
     pub tunip: Option<Ipv4Addr>,
+    /// largest IP packet the kernel hands us
+    pub mtu: usize,
     /// receiver for packets coming from tun
-    pub inboundSender: Sender<Packet<Vec<u8>>>,
-    pub inboundReceiver: Receiver<Packet<Vec<u8>>>
+    pub inboundSender: Sender<IpPacket>,
+    pub inboundReceiver: Receiver<IpPacket>
 }
 
-fn tunloop(iface: Arc<Iface>, sender: Sender<Packet<Vec<u8>>>) {
+fn tunloop(iface: Arc<Iface>, mtu: usize, sender: Sender<IpPacket>) {
     info!("Network tunnel started...");
 
     loop {
-        let mut buffer = vec![0; 1504];
+        // the packet and the 4 byte tun header in front of it
+        let mut buffer = vec![0; mtu + 4];
         // Read next packet from network tunnel
         let size = iface.recv(&mut buffer).unwrap();
         assert!(size >= 4);
         trace!("Network packet of size {}", size);
 
         // Forward packet to node/radio
-        match Packet::new(Vec::from(&buffer[4..size])) {
+        match IpPacket::new(Vec::from(&buffer[4..size])) {
             Err(e) => error!("Received invalid IP packet {}", e), // unsupported protocol
             Ok(ippacket) => { sender.send(ippacket); }
         }
@@ -38,7 +44,7 @@ fn tunloop(iface: Arc<Iface>, sender: Sender<Packet<Vec<u8>>>) {
 }
 
 impl NetworkTunnel {
-    pub fn new(iface: Arc<Iface>) -> Self {
+    pub fn new(iface: Arc<Iface>, mtu: usize) -> Self {
         trace!("Iface: {:?}", iface);
 
         let tunname = String::from(iface.name());
@@ -47,49 +53,126 @@ impl NetworkTunnel {
         // IP and we will route and capture traffic through it
         let iaddr = Ipv4Addr::new(10,107,1,3);
         ipassign(tunname.as_str(), &iaddr);
+        ipcmd("ip", &["link", "set", "dev", tunname.as_str(), "mtu", &mtu.to_string()]);
         ipcmd("ip", &["link", "set", "dev", tunname.as_str(), "up"]);
-        info!("Created interface {} with IP addr {}", tunname, iaddr.to_string());
+        info!("Created interface {} with IP addr {} and MTU {}", tunname, iaddr.to_string(), mtu);
 
         // set up channels for sending and receiving packets
         let (inboundSender, inboundReceiver) = crossbeam_channel::unbounded();
 
         NetworkTunnel {
             tunname: tunname,
-            interface: iface,
+            interface: Some(iface),
+            outbound: None,
             tunip: Some(iaddr),
+            mtu,
             inboundSender,
             inboundReceiver
         }
     }
 
+    /// A tunnel without a kernel interface, for tests
+    /* Packets sent on it arrive on the returned receiver, and packets
+    sent through `inboundSender` are read from it as if the kernel routed
+    them to the tunnel. No addresses or routes are set up, so it needs no
+    root. */
+    pub fn in_memory(mtu: usize) -> (Self, Receiver<IpPacket>) {
+        let (inbound, received) = crossbeam_channel::unbounded();
+        let (outbound, sent) = crossbeam_channel::unbounded();
+        let tunnel = NetworkTunnel {
+            tunname: String::from("memory"),
+            interface: None,
+            outbound: Some(outbound),
+            tunip: Some(Ipv4Addr::new(10,107,1,3)),
+            mtu,
+            inboundSender: inbound,
+            inboundReceiver: received
+        };
+        (tunnel, sent)
+    }
+
     /// Start the network tunnel thread
-    pub fn run(&self) -> Receiver<Packet<Vec<u8>>> {
-        let sender = self.inboundSender.clone();
-        let iface = Arc::clone(&self.interface);
-        thread::spawn(move || tunloop(iface, sender) );
+    pub fn run(&self) -> Receiver<IpPacket> {
+        if let Some(iface) = &self.interface {
+            let sender = self.inboundSender.clone();
+            let iface = Arc::clone(iface);
+            let mtu = self.mtu;
+            thread::spawn(move || tunloop(iface, mtu, sender) );
+        }
         return self.inboundReceiver.clone();
     }
 
     /// Send packet on tunnel
-    pub fn send(&mut self, packet: Packet<Vec<u8>>) {
-        let mut data = vec![0x00u8, 0x00, 0x08, 0x00];
+    pub fn send<P: Into<IpPacket>>(&mut self, packet: P) {
+        let packet = packet.into();
+        let iface = match (&self.interface, &self.outbound) {
+            (Some(iface), _) => iface,
+            (None, Some(outbound)) => {
+                // whoever reads the tunnel in memory may be gone
+                outbound.send(packet).ok();
+                return;
+            },
+            (None, None) => return,
+        };
+        // the tun header carries the ethertype
+        let mut data = match packet {
+            IpPacket::V4(_) => vec![0x00u8, 0x00, 0x08, 0x00],
+            IpPacket::V6(_) => vec![0x00u8, 0x00, 0x86, 0xdd],
+        };
         data.extend(packet.as_ref().iter());
-        self.interface.send(&data).map(|res| trace!("Network tunnel sent {} bytes", &res) );
+        iface.send(&data).map(|res| trace!("Network tunnel sent {} bytes", &res) );
     }
 
     /// Add IP address to this tunnel's interface
     /* This performs a kernel ip route which allows us to capture
     traffic from local interface. */
     pub fn assignipaddr(&mut self, ipaddr: &Ipv4Addr) {
+        if self.interface.is_none() {
+            return;
+        }
         ipassign(self.tunname.as_str(), ipaddr);
     }
 
+    /// Add an IPv6 address to this tunnel's interface, with its /64
+    /* The whole prefix is routed through the tunnel, other nodes need no
+    route of their own. The mesh answers for its addresses, so the
+    duplicate check is skipped. */
+    pub fn assignip6addr(&mut self, ipaddr: &Ipv6Addr) {
+        let addr = format!("{}/64", ipaddr);
+        self.ipcmd("ip", &["-6", "addr", "add", &addr, "dev", self.tunname.as_str(), "nodad"]);
+    }
+
+    /// Add an IP address with its prefix length to this tunnel's interface
+    /* The kernel then sends the whole subnet to the tunnel, no route per
+    node is needed. */
+    pub fn assignipnet(&mut self, ipaddr: &Ipv4Addr, prefix: u8) {
+        let addr = format!("{}/{}", ipaddr, prefix);
+        self.ipcmd("ip", &["addr", "add", &addr, "dev", self.tunname.as_str()]);
+    }
+
+    /// Route a subnet through the tunnel via a mesh address
+    pub fn routesubnet(&mut self, dest: &Ipv4Addr, prefix: u8, via: &Ipv4Addr) {
+        let dest = format!("{}/{}", dest, prefix);
+        trace!("Adding tunnel ip route dest {} via {}", &dest, &via.to_string());
+        self.ipcmd("ip", &["route", "add", &dest, "via", &via.to_string(), "dev", self.tunname.as_str()]);
+    }
+
     /// Set up a route to an IP through this node
     /* This performs a kernel ip route which allows us to capture
     traffic from local interface. */
     pub fn routeipaddr(&mut self, dest: &Ipv4Addr, via: &Ipv4Addr) {
+        if self.interface.is_none() {
+            return;
+        }
         iproute(self.tunname.as_str(), dest, via);
     }
+
+    /// Run `ip` for the kernel interface, a tunnel in memory has nothing to set up
+    fn ipcmd(&self, cmd: &str, args: &[&str]) {
+        if self.interface.is_some() {
+            ipcmd(cmd, args);
+        }
+    }
 }
 
 /// Run a shell command and panic if it fails
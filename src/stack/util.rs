@@ -1,5 +1,6 @@
 use std::convert::TryInto;
 use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn parse_bool(byte: u8) -> std::io::Result<bool> {
     if byte as i8 == 0i8 { return Ok(false); }
@@ -26,4 +27,64 @@ pub fn parse_string(arr: &[u8]) -> Vec<u8> {
 
 pub fn composite_key(id1: &u8, id2: &u8) -> String {
     format!("{}-{}", id1, id2)
-}
\ No newline at end of file
+}
+
+/// Parse type-length-value entries until the end of the data
+/* Unknown type codes are kept as-is, a truncated entry ends parsing. */
+pub fn parse_tlv(mut data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut tlv = Vec::new();
+    while data.len() >= 2 {
+        let len = data[1] as usize;
+        match data.get(2..2+len) {
+            None => break,
+            Some(value) => tlv.push((data[0], Vec::from(value))),
+        }
+        data = &data[2+len..];
+    }
+    return tlv;
+}
+
+/// Append a type-length-value entry, the value must be at most 255 bytes
+pub fn push_tlv(payload: &mut Vec<u8>, tlvtype: u8, value: &[u8]) {
+    payload.push(tlvtype);
+    payload.push(value.len() as u8);
+    payload.extend_from_slice(value);
+}
+
+/// Milliseconds since the unix epoch, used for timestamps sent over the air
+pub fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+/// 32 bit FNV-1a hash
+pub fn fnv1a(data: &[u8]) -> u32 {
+    let mut hash = 0x811C_9DC5u32;
+    for byte in data {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// CRC-32 (IEEE) checksum, as used by zip and ethernet
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+#[test]
+fn crc32_check() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(fnv1a(b""), 0x811C_9DC5);
+    assert_eq!(fnv1a(b"foobar"), 0xBF9C_F968);
+}